@@ -16,22 +16,32 @@ export const language = {
   ],
 
   lispKeywords: [
-    'def', 'fn', 'quote', 'unquote', 'if', 'begin', 'focus', 'let',
-    'letrec', 'match', 'match-fn', 'match-fn*'
+    'def', 'fn', 'quote', 'unquote', 'if', 'begin', 'focus', 'try', 'reset', 'shift', 'let',
+    'letrec', 'match', 'match-fn', 'match-fn*', 'defmacro'
   ],
 
   lispBuiltins: [
-    'display', 'error', 'print', 'report-at', 'begin', 'apply', '+', '*', '^',
-    'max', 'min', '-', '//', '%', '<', '<=', '>', '>=', '=', 'shl', 'shr',
-    'band', 'bor', 'bxor', 'bnot', '==', '->string', 'string->atom',
+    'display', 'error', 'raise', 'print', 'report-at', 'begin', 'apply', 'macro', 'help', '+', '*', '^',
+    'max', 'min', '-', '//', '/', '%', 'mod', 'divmod', 'pow-mod', '<', '<=', '>', '>=', '=', 'numerator',
+    'denominator', 'floor', 'ceil', 'round', 'shl', 'shr',
+    'band', 'bor', 'bxor', 'bnot', '==', '->string', 'string->atom', 'gensym',
     'string-append', 'string-len', 'string-nth', 'substr', 'string->list',
-    'list->string', 'not', 'and', 'or', 'list', 'cons', 'hd', 'tl', 'nth',
-    'map', 'bool?', 'atom?', 'pair?', 'null?', 'number?', 'string?', 'fn?',
+    'list->string', 'string-split', 'string-index', 'string-upcase',
+    'string-downcase', 'string->number', 'number->string', 'format',
+    'open-output-string', 'port?', 'port-write', 'get-output-string',
+    'read-file', 'write-file', 'file-exists?',
+    'not', 'and', 'or', 'list', 'cons', 'hd', 'tl', 'nth',
+    'map', 'bool?', 'atom?', 'pair?', 'null?', 'number?', 'integer?', 'string?', 'fn?',
     'def?', 'ref?', 'ref!', 'get!', 'set!', 'set-weak!', 'copy-span', 'stack-span',
-    'async', 'atom-map?', 'atom-map!', 'lookup', 'insert!', 'insert', 'set-timeout',
-    'set-stack-limit', 'mvar?', 'goal?', 'mvar!', 'pp', 'goal', 'goal-type',
+    'async', 'atom-map?', 'atom-map!', 'lookup', 'insert!', 'insert', 'hash?',
+    'hash-new', 'hash-ref', 'hash-set!', 'hash-keys', 'vector?', 'vector', 'vector-ref',
+    'vector-set!', 'vector-push!', 'vector-length', 'vector->list', 'set-timeout',
+    'set-stack-limit', 'set-step-limit', 'set-alloc-limit', 'profile-start',
+    'profile-report', 'spawn', 'await', 'mvar?', 'goal?', 'mvar!', 'pp', 'goal', 'goal-type',
     'infer-type', 'infer-sort', 'get-mvars', 'get-goals', 'set-goals',
-    'set-close-fn', 'local-ctx', 'to-expr', 'refine', 'have', 'stat', 'get-decl',
+    'set-close-fn', 'sorry', 'local-ctx', 'to-expr', 'refine', 'have', 'stat', 'get-decl',
+    'get-proof', 'decls', 'search', 'congruence-closure', 'simp', 'add-simp!', 'lia',
+    'sat-tauto', 'smt', 'auto', 'instance', 'add-instance!',
     'add-decl!', 'add-term!', 'add-thm!', 'dummy!', 'check-proofs', 'set-reporting',
     'refine-extra-args', 'eval-string', 'mmc-init'
   ],
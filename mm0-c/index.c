@@ -4,7 +4,7 @@
 #include "types.c"
 
 u8* g_file; u8* g_end;
-u8 g_num_sorts;  u8*   g_sorts;
+u32 g_num_sorts;  u8*   g_sorts;
 u32 g_num_terms; term* g_terms;
 u32 g_num_thms;  thm*  g_thms;
 
@@ -40,7 +40,7 @@ bool init_index() {
     switch (ir->id) {
       case 0x656d614eU: { // "Name"
         gi_sort_names = (name_entry*)&g_file[ir->ptr];
-        gi_term_names = &gi_sort_names[gi_header->num_sorts];
+        gi_term_names = &gi_sort_names[HEADER_NUM_SORTS(gi_header)];
         gi_thm_names = &gi_term_names[gi_header->num_terms];
         if ((u8*)&gi_thm_names[gi_header->num_thms] > g_end) return false;
       } break;
@@ -65,9 +65,9 @@ char* name_value(name_entry* p) {
   return 0;
 }
 
-char* lookup_sort(u8 sort) {
+char* lookup_sort(u32 sort) {
   if (!init_index()) return 0;
-  if (gi_sort_names && sort < gi_header->num_sorts) {
+  if (gi_sort_names && sort < HEADER_NUM_SORTS(gi_header)) {
     return name_value(&gi_sort_names[sort]);
   }
   return 0;
@@ -269,7 +269,7 @@ token_result token() {
 }
 
 typedef struct {u32 expr; u8 sort;} expr_sort;
-u32 coerce(expr_sort es, u8 tgt) {
+u32 coerce(expr_sort es, u16 tgt) {
   if (es.sort == tgt) return es.expr;
   coe* c = &g_coes[es.sort][tgt];
   switch (c->tag) {
@@ -320,7 +320,7 @@ expr_sort prefix_expr(u16 prec) {
             coerce(expr(lit->var.prec), TYPE_SORT(targs[lit->var.var]));
         }
       }
-      return (expr_sort){e, t->sort & 0x7f};
+      return (expr_sort){e, TERM_SORT(t)};
     }
   }
   u32 var = gt_terms[t.var->next[0]].data;
@@ -338,7 +338,7 @@ expr_sort prefix_expr(u16 prec) {
     for (int i = 0; i < nargs; i++)
       args[i] = coerce(expr(-1), TYPE_SORT(targs[i]));
   }
-  return (expr_sort){e, tm->sort & 0x7f};
+  return (expr_sort){e, TERM_SORT(tm)};
 }
 
 expr_sort lhs_expr(u16 prec, expr_sort lhs) {
@@ -370,7 +370,7 @@ expr_sort lhs_expr(u16 prec, expr_sort lhs) {
     u64* targs = (u64*)&g_file[tm->p_args];
     args[0] = coerce(lhs, TYPE_SORT(targs[0]));
     args[1] = coerce(rhs, TYPE_SORT(targs[1]));
-    lhs = (expr_sort){e, tm->sort & 0x7f};
+    lhs = (expr_sort){e, TERM_SORT(tm)};
   }
   return lhs;
 }
@@ -433,7 +433,7 @@ u32 binders(int kind, u16 num_args, u64* args, u64 ret) {
       trie x = lookup_ident(gt_sorts);
       ENSURE("expecting sort", x->data);
       u8 sort = (u8)~x->data;
-      u64 type = ((u64)sort << 56) | ((u64)curly << 63);
+      u64 type = TYPE_OF_SORT(sort) | ((u64)curly << 63);
       if (!ch(close)) {
         ENSURE("bound variables should not have dependencies", !curly);
         ENSURE("dummies should not have dependencies", dummy_group_start == g_num_vars);
@@ -467,7 +467,7 @@ u32 binders(int kind, u16 num_args, u64* args, u64 ret) {
       trie x = lookup_ident(gt_sorts);
       ENSURE("expecting sort", x->data);
       u8 sort = (u8)~x->data;
-      u64 type = ((u64)sort << 56);
+      u64 type = TYPE_OF_SORT(sort);
       while (ident_start(CUR())) {
         u32 var = lookup_var();
         ENSURE("expecting variable", var < arg_idx);
@@ -644,7 +644,7 @@ void parse_until(u8 stmt_type) {
         if (k == KW_DEF && ch('=')) {
           ENSURE("expecting '$'", ch('$'));
           check_expr(t->num_args, (u8*)&args[t->num_args+1],
-            coerce(expr(0), t->sort & 0x7f), -1);
+            coerce(expr(0), TERM_SORT(t)), -1);
           ENSURE("expecting '$'", ch('$'));
         }
         ENSURE("duplicate term/def name", x->data == 0);
@@ -41,15 +41,20 @@ void fail(char* err, int e);
 #define SORT_FREE     8
 
 #define MM0B_MAGIC (u32)0x42304D4D // = "MM0B"
-#define MM0B_VERSION (u8)1
-#define MAX_SORTS 128
+#define MM0B_VERSION_V1 (u8)1
+// Version 2 is the same as version 1, except that it supports up to 256 sorts
+#define MM0B_VERSION_WIDE_SORTS (u8)2
+#define MM0B_VERSION MM0B_VERSION_WIDE_SORTS
+#define MAX_SORTS_V1 128
+#define MAX_SORTS 256
 
 typedef struct {
   u32 magic;         // = MM0B_MAGIC
-  u8 version;        // = MM0B_VERSION
+  u8 version;        // = MM0B_VERSION_V1 or MM0B_VERSION_WIDE_SORTS
 
-  u8 num_sorts;      // number of declared sorts ( <= MAX_SORTS )
-  u16 reserved;
+  u8 num_sorts;      // number of declared sorts ( <= MAX_SORTS_V1 in version 1 )
+  u8 num_sorts_hi;   // the high byte of the number of sorts in version 2, 0 in version 1
+  u8 reserved;
   u32 num_terms;     // number of terms and defs
   u32 num_thms;      // number of axioms and theorems
 
@@ -61,20 +66,27 @@ typedef struct {
   /* index_header* */ u64 p_index; // pointer to start of index, or 0
 
   // The list of all sorts. The number of sorts is
-  // limited to 128 because of the data layout.
+  // limited to 128 (256 in version 2) because of the data layout.
   // So don't monomorphize too much.
   u8 sorts[];
 } PACKED header;
 
+// The number of sorts in the file.
+#define HEADER_NUM_SORTS(p) ((u32)(p)->num_sorts | \
+  ((p)->version >= MM0B_VERSION_WIDE_SORTS ? (u32)(p)->num_sorts_hi << 8 : 0))
+
 // The term arguments table is composed of 64 bit values, where:
-// * the low 56 bits (7 bytes) are used for encoding the list of bound variables
+// * the low 55 bits are used for encoding the list of bound variables
 //   this variable depends on. bit i is 1 if this is a regular var and
 //   depends on the ith *bound* variable
-// * Bits 57-62 (the 7 low bits of the high byte) encode the sort of the expression
+// * Bit 55 is the high bit of the sort of the expression (always 0 in version 1)
+// * Bits 56-62 (the 7 low bits of the high byte) encode the low 7 bits of
+//   the sort of the expression
 // * Bit 63 (the MSB / sign bit) is 1 if this is a bound var
-#define TYPE_DEPS_MASK (((u64)1<<56)-1)
+#define TYPE_DEPS_MASK (((u64)1<<55)-1)
 #define TYPE_BOUND_MASK ((u64)1<<63)
-#define TYPE_SORT(type) (((type) >> 56) & 0x7F)
+#define TYPE_SORT(type) ((((type) >> 56) & 0x7F) | (((type) >> 48) & 0x80))
+#define TYPE_OF_SORT(sort) ((((u64)(sort) & 0x7F) << 56) | (((u64)(sort) & 0x80) << 48))
 
 // This struct contains data on symbol names used by the verfifier to ensure
 // that there are no name collisions. It has similar data but a different
@@ -91,7 +103,8 @@ typedef struct {
   u16 num_args;          // number of arguments
   u8 sort;               // sort of the return value, 1 in high bit means this
                          // is a definition
-  u8 reserved;
+  u8 sort_hi;            // the high bit of the sort of the return value
+                         // in version 2, 0 in version 1
   // /* name* */ u32 p_name; // pointer to the name of the term, or 0
   /* u64* */ u32 p_args; // pointer to list of binders
   // The list of binders has n+1 elements, with the last element being
@@ -99,6 +112,9 @@ typedef struct {
   // for definitions.
 } PACKED term;
 
+// The sort of the return value of a term.
+#define TERM_SORT(t) (((u32)(t)->sort & 0x7F) | (((u32)(t)->sort_hi & 1) << 7))
+
 // An entry in the theorem table (4 byte aligned)
 typedef struct {
   u16 num_args;           // number of arguments (expression assumptions)
@@ -277,7 +277,8 @@ void run_unify(unify_mode mode, u8* cmd, u32 tgt) {
         u32 p = pop_ustack();
         store_var* e = get_var(p);
         u64 type = e->type;
-        ENSURE("unify failure at dummy", (type >> 56) == (0x80 | data));
+        ENSURE("unify failure at dummy", data < MAX_SORTS &&
+          (type & ~TYPE_DEPS_MASK) == (TYPE_BOUND_MASK | TYPE_OF_SORT(data)));
         type &= TYPE_DEPS_MASK;
         for (int i = 0; i < g_uheap_size; i++) {
           ENSURE("dummy disjoint variable violation",
@@ -394,8 +395,8 @@ u8* run_proof(proof_mode mode, u8* cmd) {
         ENSURE("dummy variable in strict sort",
           (g_sorts[data] & SORT_STRICT) == 0);
         ENSURE("too many bound variables, please rewrite the verifier",
-          (g_next_bv >> 56) == 0);
-        u64 type = TYPE_BOUND_MASK | ((u64)data << 56) | g_next_bv;
+          (g_next_bv & ~TYPE_DEPS_MASK) == 0);
+        u64 type = TYPE_BOUND_MASK | TYPE_OF_SORT(data) | g_next_bv;
         g_next_bv *= 2;
         u32 e = STACK_TYPE_EXPR |
           ALLOC(((store_var){type, EXPR_VAR, (u16)g_heap_size}), sizeof(store_var));
@@ -423,7 +424,7 @@ u8* run_proof(proof_mode mode, u8* cmd) {
         g_stack_top -= t->num_args;
         // alloc g_deps;
         u8 bound = 0;
-        u64 accum = (u64)(t->sort & 0x7F) << 56;
+        u64 accum = TYPE_OF_SORT(TERM_SORT(t));
         u32 p = ALLOC(((store_term){0, EXPR_TERM, t->num_args, data}),
           sizeof(store_term) + 4 * t->num_args);
         store_term* result = get_term(p);
@@ -663,9 +664,12 @@ void verify(u8* file, u64 len) {
   ENSURE("header not long enough", len >= sizeof(header));
   header* p = (header*)file;
   ENSURE("Not a MM0B file", p->magic == MM0B_MAGIC);
-  ENSURE("Wrong version", p->version == MM0B_VERSION);
-  ENSURE("Too many sorts", p->num_sorts <= MAX_SORTS);
-  ENSURE("header not long enough", len >= sizeof(header) + p->num_sorts);
+  ENSURE("Wrong version",
+    p->version >= MM0B_VERSION_V1 && p->version <= MM0B_VERSION);
+  u32 num_sorts = HEADER_NUM_SORTS(p);
+  ENSURE("Too many sorts", num_sorts <=
+    (p->version >= MM0B_VERSION_WIDE_SORTS ? MAX_SORTS : MAX_SORTS_V1));
+  ENSURE("header not long enough", len >= sizeof(header) + num_sorts);
   ENSURE("Term table out of range",
     len >= p->p_terms + p->num_terms * sizeof(term));
   ENSURE("Theorem table out of range",
@@ -698,7 +702,7 @@ void verify(u8* file, u64 len) {
       // sort counter (after checking that a sort is available in the table).
       case CMD_STMT_SORT: {
         ENSURE("Next statement incorrect", data == sz);
-        ENSURE("Step sort overflow", g_num_sorts < p->num_sorts);
+        ENSURE("Step sort overflow", g_num_sorts < num_sorts);
         parse_until(CMD_STMT_SORT);
         g_num_sorts++;
       } break;
@@ -712,7 +716,7 @@ void verify(u8* file, u64 len) {
       case CMD_STMT_LOCAL_DEF: {
         term* t = &g_terms[g_num_terms];
         ENSURE("Step term overflow", g_num_terms < p->num_terms);
-        u8 sort = t->sort & 0x7F;
+        u32 sort = TERM_SORT(t);
         ENSURE("bad sort", sort < g_num_sorts);
         ENSURE("term in pure sort", (g_sorts[sort] & SORT_PURE) == 0);
         u64* args = (u64*)&file[t->p_args];
@@ -722,7 +726,7 @@ void verify(u8* file, u64 len) {
         g_stack_top = g_stack;
         load_args(args, t->num_args + 1);
         u64 ret = *args_ret;
-        ENSURE("bad return type", (ret >> 56) == sort);
+        ENSURE("bad return type", (ret & ~TYPE_DEPS_MASK) == TYPE_OF_SORT(sort));
         g_heap_size--;
 
         if (t->sort & 0x80) {
@@ -787,7 +791,7 @@ void verify(u8* file, u64 len) {
     stmt = next_stmt;
   }
 
-  ENSURE("not all sorts proved", g_num_sorts == p->num_sorts);
+  ENSURE("not all sorts proved", g_num_sorts == num_sorts);
   ENSURE("not all terms proved", g_num_terms == p->num_terms);
   ENSURE("not all theorems proved", g_num_thms == p->num_thms);
 #ifndef BARE
@@ -32,7 +32,7 @@ void debug_print_expr(u32 n, u32 max, bool type) {
     default: fprintf(stderr, "?"); break;
   }
   char* name;
-  if (type && (name = lookup_sort((p->type >> 56) & 0x7F))) {
+  if (type && (name = lookup_sort(TYPE_SORT(p->type)))) {
     fprintf(stderr, ":%s", name);
   }
   if (type && bound) fprintf(stderr, "}");
@@ -101,7 +101,7 @@ typedef struct {
 } trie_node;
 typedef trie_node* trie;
 
-#define SORT_TRIE_SIZE 1024
+#define SORT_TRIE_SIZE (1 << 13)
 trie_node gt_sorts[SORT_TRIE_SIZE];
 trie_node* gt_sorts_end = gt_sorts + 2;
 
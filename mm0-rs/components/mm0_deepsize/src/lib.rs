@@ -348,6 +348,13 @@ impl DeepSizeOf for num::BigInt {
     }
 }
 
+#[cfg(feature = "num")]
+impl DeepSizeOf for num::BigRational {
+    fn deep_size_of_children(&self, context: &mut Context) -> usize {
+        self.numer().deep_size_of_children(context) + self.denom().deep_size_of_children(context)
+    }
+}
+
 #[cfg(feature = "lsp-types")]
 impl DeepSizeOf for lsp_types::Url {
     fn deep_size_of_children(&self, _: &mut Context) -> usize {
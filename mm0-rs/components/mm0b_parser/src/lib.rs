@@ -52,6 +52,7 @@
   clippy::use_self
 )]
 
+mod opt;
 mod parser;
 mod ty;
 mod write;
@@ -64,17 +65,49 @@ use mm0_util::{Modifiers, SortId, TermId, ThmId};
 use zerocopy::{AsBytes, FromBytes, Unaligned, U16, U32, U64};
 
 pub use mm0_util::u32_as_usize;
-pub use {parser::*, ty::*, write::*};
+pub use {opt::*, parser::*, ty::*, write::*};
 
 /// The maximum number of bound variables supported by the MMB format.
 pub const MAX_BOUND_VARS: usize = 55;
 
+/// The maximum number of sorts supported by version 1 of the MMB format.
+pub const MAX_SORTS_V1: usize = 128;
+
+/// The maximum number of sorts supported by the MMB format, using the
+/// [`MM0B_VERSION_WIDE_SORTS`](cmd::MM0B_VERSION_WIDE_SORTS) extension.
+pub const MAX_SORTS: usize = 256;
+
+/// Get the lowest MMB version that can represent a file with `num_sorts` sorts,
+/// or `None` if there are too many sorts for any version.
+///
+/// Files are written using version 1 whenever possible, so that they remain readable
+/// by verifiers that do not know about the wide sorts extension.
+#[must_use]
+pub fn version_for_sorts(num_sorts: usize) -> Option<u8> {
+  if num_sorts <= MAX_SORTS_V1 {
+    Some(cmd::MM0B_VERSION_V1)
+  } else if num_sorts <= MAX_SORTS {
+    Some(cmd::MM0B_VERSION_WIDE_SORTS)
+  } else {
+    None
+  }
+}
+
 /// Constants used in the MMB specification.
 pub mod cmd {
   /// `MM0B_MAGIC = "MM0B"`: Magic number signalling the MM0B format is in use.
   pub const MM0B_MAGIC: [u8; 4] = *b"MM0B";
-  /// `MM0B_VERSION = 1`, maximum supported MMB version
-  pub const MM0B_VERSION: u8 = 1;
+  /// `MM0B_VERSION_V1 = 1`, the original MMB version, which supports at most 128 sorts
+  pub const MM0B_VERSION_V1: u8 = 1;
+  /// `MM0B_VERSION_WIDE_SORTS = 2`, an extension of version 1 supporting up to 256 sorts.
+  ///
+  /// The sort count uses the first reserved byte of the header as its high byte,
+  /// the high bit of a binder's sort is stored in bit 55 of the [`Type`](super::Type),
+  /// and the high bit of a term's return sort is stored in the
+  /// [`TermEntry::sort_hi`](super::TermEntry::sort_hi) field.
+  pub const MM0B_VERSION_WIDE_SORTS: u8 = 2;
+  /// `MM0B_VERSION = 2`, maximum supported MMB version
+  pub const MM0B_VERSION: u8 = MM0B_VERSION_WIDE_SORTS;
 
   /// `DATA_8 = 0x40`, used as a command mask for an 8 bit data field
   pub const DATA_8: u8 = 0x40;
@@ -155,6 +188,8 @@ pub mod cmd {
   pub const INDEX_VAR_NAME: [u8; 4] = *b"VarN";
   /// `"HypN"` is the magic number for the hypothesis name table.
   pub const INDEX_HYP_NAME: [u8; 4] = *b"HypN";
+  /// `"SrcM"` is the magic number for the source map table.
+  pub const INDEX_SOURCE_MAP: [u8; 4] = *b"SrcM";
 }
 
 #[inline]
@@ -289,7 +324,7 @@ impl TryFrom<u8> for StmtCmd {
 /// * `S: Stack<StackEl>`: The main stack, which most operations push and pop from.
 /// * `HS: Vec<Expr>`: The hypothesis list, which grows only on [`Hyp`](ProofCmd::Hyp)
 ///   operations and collects the hypotheses of the theorem.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ProofCmd {
   /// ```text
   /// Term t: H; S, e1, ..., en --> H; S, (t e1 .. en)
@@ -517,11 +552,12 @@ pub struct Header {
   /// The magic number, which is used to identify this as an mmb file. Must be
   /// equal to [`MM0B_MAGIC`](cmd::MM0B_MAGIC) = `"MM0B"`.
   pub magic: [u8; 4],
-  /// The MMB format version number. Must equal [`MM0B_VERSION`](cmd::MM0B_VERSION) = 1.
+  /// The MMB format version number. Must be at most [`MM0B_VERSION`](cmd::MM0B_VERSION) = 2.
   pub version: u8,
-  /// The number of sorts in the file. This is limited to 128.
+  /// The number of sorts in the file (the low byte, in version 2).
+  /// Use [`Header::sort_count`] to get the actual number of sorts.
   pub num_sorts: u8,
-  /// Padding.
+  /// Padding. In version 2, `reserved[0]` is the high byte of the number of sorts.
   pub reserved: [u8; 2],
   /// The number of terms and defs in the file.
   pub num_terms: U32<LE>,
@@ -541,6 +577,16 @@ pub struct Header {
 }
 
 impl Header {
+  /// The number of sorts in the file. This is limited to 128 in version 1 and 256 in version 2.
+  #[must_use]
+  pub fn sort_count(&self) -> usize {
+    if self.version >= cmd::MM0B_VERSION_WIDE_SORTS {
+      usize::from(u16::from_le_bytes([self.num_sorts, self.reserved[0]]))
+    } else {
+      self.num_sorts.into()
+    }
+  }
+
   /// On top of the magic number and version checks, perform a non-exhaustive list of
   /// miscellaneous checks to see whether there are issues with
   /// the header that won't be caught by the type system or the integer parsers.
@@ -548,12 +594,12 @@ impl Header {
   /// For example, none of the pointers in the header should be greater than the length
   /// of the file, the terms pointer should be less than the theorems pointer, etc.
   pub fn check(&self, mmb: &[u8]) -> Result<(), ParseError> {
-    use crate::cmd::{MM0B_MAGIC, MM0B_VERSION};
+    use crate::cmd::{MM0B_MAGIC, MM0B_VERSION, MM0B_VERSION_V1};
 
     if self.magic != MM0B_MAGIC {
       return Err(ParseError::BadMagic { parsed_magic: self.magic })
     }
-    if self.version != MM0B_VERSION {
+    if !(MM0B_VERSION_V1..=MM0B_VERSION).contains(&self.version) {
       return Err(ParseError::BadVersion { parsed_version: self.version })
     }
 
@@ -562,10 +608,12 @@ impl Header {
     let p_proof = u32_as_usize(self.p_proof.get());
     let p_index = u64_as_usize(self.p_index);
     let headerspace = size_of::<Header>();
-    let sortspace = self.num_sorts as usize;
+    let sortspace = self.sort_count();
     let termspace = size_of::<u32>() * u32_as_usize(self.num_terms.get());
     let thmspace = size_of::<u32>() * u32_as_usize(self.num_thms.get());
-    if headerspace + sortspace <= p_terms
+    if (self.version > MM0B_VERSION_V1 || sortspace <= MAX_SORTS_V1)
+      && sortspace <= MAX_SORTS
+      && headerspace + sortspace <= p_terms
       && p_terms + termspace <= p_thms
       && p_thms + thmspace <= p_proof
       && p_proof <= mmb.len()
@@ -608,8 +656,9 @@ pub struct TermEntry {
   /// The high bit is set if this is a `def`. The low 7 bits give the
   /// return sort of the term.
   pub sort: u8,
-  /// Padding.
-  pub reserved: u8,
+  /// Padding in version 1. In version 2, this is the high bit of the return sort
+  /// of the term (so it is 1 for sorts 128 to 255 and 0 otherwise).
+  pub sort_hi: u8,
   /// The pointer to an `args: [`[`Arg`]`; num_args + 1]` array, followed by the
   /// term's unify command sequence. `args[num_args]` is the return type and dependencies,
   /// and `args[..num_args]` are the actual arguments.
@@ -654,3 +703,22 @@ pub struct NameEntry {
   /// A pointer to the entity's name as a UTF-8 C string.
   pub p_name: U64<LE>,
 }
+
+/// An entry in the source map, which links a range of the proof stream to a span in the
+/// source file that generated it. The source map table consists of a `num_entries: u64`
+/// followed by a `[SourceMapEntry; num_entries]` array, sorted by `p_start`.
+/// Ranges can be nested, in which case the inner ranges refine the outer ones.
+#[repr(C, align(8))]
+#[derive(Debug, Clone, Copy, FromBytes, AsBytes)]
+pub struct SourceMapEntry {
+  /// A pointer to the start of the range in the proof stream.
+  pub p_start: U64<LE>,
+  /// A pointer to the end of the range in the proof stream (exclusive).
+  pub p_end: U64<LE>,
+  /// A pointer to the source file name as a UTF-8 C string.
+  pub p_file: U64<LE>,
+  /// The byte offset of the start of the span in the source file.
+  pub start: U64<LE>,
+  /// The byte offset of the end of the span in the source file (exclusive).
+  pub end: U64<LE>,
+}
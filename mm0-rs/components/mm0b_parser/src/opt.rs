@@ -0,0 +1,94 @@
+//! A peephole optimizer for proof streams.
+use crate::ProofCmd;
+use mm0_util::u32_as_usize;
+
+/// Returns true if this command allocates a new heap element.
+fn allocates(cmd: ProofCmd) -> bool {
+  matches!(
+    cmd,
+    ProofCmd::Term { save: true, .. }
+      | ProofCmd::Thm { save: true, .. }
+      | ProofCmd::Dummy(_)
+      | ProofCmd::Hyp
+      | ProofCmd::Save
+      | ProofCmd::ConvSave
+  )
+}
+
+/// Optimize the proof stream `cmds` of a declaration with `nargs` arguments, which occupy the
+/// first `nargs` heap slots.
+///
+/// Every heap element that is saved but never referenced by a [`Ref`](ProofCmd::Ref) is
+/// not saved: `TermSave` and `ThmSave` become `Term` and `Thm`, a bare [`Save`](ProofCmd::Save)
+/// is removed, and a [`ConvCut`](ProofCmd::ConvCut) / [`ConvSave`](ProofCmd::ConvSave) pair
+/// is removed, leaving the conversion proof in between to discharge the obligation directly.
+/// The remaining references are renumbered to account for the smaller heap.
+///
+/// [`Dummy`](ProofCmd::Dummy) and [`Hyp`](ProofCmd::Hyp) always allocate, and the order of
+/// commands is never changed, since the verifier relies on both. The input is expected to be
+/// a well formed proof stream, as produced by an exporter; this function panics on
+/// references to heap elements that have not been allocated yet.
+#[must_use]
+pub fn optimize_proof(nargs: u32, cmds: &[ProofCmd]) -> Vec<ProofCmd> {
+  // The arguments are always kept, so they are marked as used from the start
+  let mut used = vec![true; u32_as_usize(nargs)];
+  // For each command, the heap slot it allocates, if any
+  let mut slot = vec![None; cmds.len()];
+  // For each `ConvSave`, the index of the matching `ConvCut`
+  let mut cut_of_save = vec![None; cmds.len()];
+  let mut cuts = vec![];
+  for (i, &cmd) in cmds.iter().enumerate() {
+    match cmd {
+      ProofCmd::Ref(n) => {
+        let n = u32_as_usize(n);
+        assert!(n < used.len(), "reference to unallocated heap element");
+        used[n] = true
+      }
+      ProofCmd::ConvCut => cuts.push(i),
+      ProofCmd::ConvSave => cut_of_save[i] = cuts.pop(),
+      _ => {}
+    }
+    if allocates(cmd) {
+      slot[i] = Some(used.len());
+      used.push(false)
+    }
+  }
+  let mut drop = vec![false; cmds.len()];
+  for (i, &cmd) in cmds.iter().enumerate() {
+    let k = match slot[i] {
+      Some(k) if !used[k] => k,
+      _ => continue,
+    };
+    match cmd {
+      ProofCmd::Term { .. } | ProofCmd::Thm { .. } => {}
+      ProofCmd::Save => drop[i] = true,
+      ProofCmd::ConvSave => match cut_of_save[i] {
+        Some(j) => {
+          drop[i] = true;
+          drop[j] = true
+        }
+        None => used[k] = true,
+      },
+      // `Dummy` and `Hyp` always allocate, so they keep their slot
+      _ => used[k] = true,
+    }
+  }
+  let remap = {
+    let mut next = 0;
+    used.iter().map(|&u| (next, next += u32::from(u)).0).collect::<Vec<u32>>()
+  };
+  let mut out = Vec::with_capacity(cmds.len());
+  for (i, &cmd) in cmds.iter().enumerate() {
+    if drop[i] {
+      continue
+    }
+    let unused = matches!(slot[i], Some(k) if !used[k]);
+    out.push(match cmd {
+      ProofCmd::Ref(n) => ProofCmd::Ref(remap[u32_as_usize(n)]),
+      ProofCmd::Term { tid, .. } if unused => ProofCmd::Term { tid, save: false },
+      ProofCmd::Thm { tid, .. } if unused => ProofCmd::Thm { tid, save: false },
+      _ => cmd,
+    })
+  }
+  out
+}
@@ -1,7 +1,8 @@
 //! Parser for MMB binary proof files.
 use crate::{
   cmd, cstr_from_bytes_prefix, exhausted, u32_as_usize, u64_as_usize, Arg, Header, NameEntry,
-  NumdStmtCmd, ProofCmd, SortData, StmtCmd, TableEntry, TermEntry, ThmEntry, UnifyCmd,
+  NumdStmtCmd, ProofCmd, SortData, SourceMapEntry, StmtCmd, TableEntry, TermEntry, ThmEntry,
+  UnifyCmd,
 };
 use byteorder::LE;
 use mm0_util::{SortId, TermId, ThmId};
@@ -151,6 +152,7 @@ make_index_trait! {
 }
 impl<'a> NoSymbolNames for Option<VarNames<'a>> {}
 impl<'a> NoSymbolNames for Option<HypNames<'a>> {}
+impl<'a> NoSymbolNames for Option<SourceMap<'a>> {}
 
 /// This index subcomponent supplies variable names for terms and theorems.
 #[derive(Debug)]
@@ -184,6 +186,7 @@ make_index_trait! {
 }
 impl<'a> NoVarNames for Option<SymbolNames<'a>> {}
 impl<'a> NoVarNames for Option<HypNames<'a>> {}
+impl<'a> NoVarNames for Option<SourceMap<'a>> {}
 
 /// This index subcomponent supplies hypothesis names for theorems.
 #[derive(Debug)]
@@ -213,6 +216,44 @@ make_index_trait! {
 }
 impl<'a> NoHypNames for Option<SymbolNames<'a>> {}
 impl<'a> NoHypNames for Option<VarNames<'a>> {}
+impl<'a> NoHypNames for Option<SourceMap<'a>> {}
+
+/// This index subcomponent maps ranges of the proof stream back to the source text.
+/// It is not part of the [`BasicIndex`], so it has to be requested explicitly, for example
+/// using `MmbFile<'a, (BasicIndex<'a>, Option<SourceMap<'a>>)>`.
+#[derive(Debug)]
+pub struct SourceMap<'a> {
+  /// The source map entries, sorted by `p_start`
+  entries: &'a [SourceMapEntry],
+}
+
+impl<'a> MmbIndexBuilder<'a> for Option<SourceMap<'a>> {
+  fn build<X>(&mut self, f: &mut MmbFile<'a, X>, e: &'a TableEntry) -> Result<(), ParseError> {
+    if e.id == cmd::INDEX_SOURCE_MAP {
+      let (entries, _) = (|| -> Option<_> {
+        let (num_entries, rest) = LayoutVerified::<_, U64<LE>>::new_unaligned_from_prefix(
+          f.buf.get(u64_as_usize(e.ptr)..)?,
+        )?;
+        new_slice_prefix(rest, num_entries.get().try_into().ok()?)
+      })()
+      .ok_or_else(|| f.bad_index_parse())?;
+      if self.replace(SourceMap { entries }).is_some() {
+        return Err(ParseError::DuplicateIndexTable {
+          p_index: u64_as_usize(f.header.p_index),
+          id: e.id,
+        })
+      }
+    }
+    Ok(())
+  }
+}
+
+make_index_trait! {
+  [<'a>, SourceMap, HasSourceMap, NoSourceMap, get_source_map, get_source_map_mut]
+}
+impl<'a> NoSourceMap for Option<SymbolNames<'a>> {}
+impl<'a> NoSourceMap for Option<VarNames<'a>> {}
+impl<'a> NoSourceMap for Option<HypNames<'a>> {}
 
 /// A basic index, usable for getting names of declarations and variables.
 pub type BasicIndex<'a> = (Option<SymbolNames<'a>>, (Option<VarNames<'a>>, Option<HypNames<'a>>));
@@ -283,6 +324,13 @@ pub struct ProofIter<'a> {
 }
 
 impl<'a> ProofIter<'a> {
+  /// Construct an iterator over the proof stream in `mmb_source[pos..ends_at]`, which should
+  /// either be empty or end with the terminating `CMD_END` command. This is useful when the
+  /// proof stream has been read separately from the rest of the file.
+  pub fn new(mmb_source: &'a [u8], pos: usize, ends_at: usize) -> Self {
+    Self { mmb_source, pos, ends_at }
+  }
+
   /// True if this iterator is "null", meaning that it has zero commands.
   /// This is not the same as being empty, which happens when there is one command
   /// which is the terminating `CMD_END` command.
@@ -382,6 +430,8 @@ pub struct TermRef<'a> {
   pub tid: TermId,
   /// The sort of the term.
   sort: u8,
+  /// The high bit of the sort of the term (always 0 in version 1 files).
+  sort_hi: u8,
   /// The array of arguments, including the `ret` element at the end.
   args_and_ret: &'a [Arg],
   /// The pointer to the start of the unify stream.
@@ -438,7 +488,8 @@ pub enum ParseError {
   },
   /// The version is unrecognized.
   BadVersion {
-    /// The MMB file version, greater than [`MM0B_VERSION`](crate::cmd::MM0B_VERSION)
+    /// The MMB file version, which is 0 or greater than
+    /// [`MM0B_VERSION`](crate::cmd::MM0B_VERSION)
     parsed_version: u8,
   },
   /// The portion of the mmb file that's supposed to contain sorts was malformed.
@@ -645,7 +696,7 @@ impl<'a, X: MmbIndexBuilder<'a>> MmbFile<'a, X> {
     // wrt whether the contents are valid sets of sort modifiers,
     // so if this fails, it's a size issue.
     let sorts = sorts
-      .get(..header.num_sorts.into())
+      .get(..header.sort_count())
       .and_then(LayoutVerified::new_slice_unaligned)
       .ok_or_else(|| BadSorts(p_sorts..u32_as_usize(header.p_terms.get())))?
       .into_slice();
@@ -728,7 +779,7 @@ pub fn find_header_error(mmb: &[u8]) -> ParseError {
       return Err(ParseError::BadMagic { parsed_magic: magic })
     }
     let (version, pos) = parse_u8((mmb, pos))?;
-    if version != crate::cmd::MM0B_VERSION {
+    if !(crate::cmd::MM0B_VERSION_V1..=crate::cmd::MM0B_VERSION).contains(&version) {
       return Err(ParseError::BadVersion { parsed_version: version })
     }
     let (_num_sorts, pos) = parse_u8((mmb, pos))?;
@@ -752,11 +803,12 @@ pub fn find_header_error(mmb: &[u8]) -> ParseError {
 }
 
 #[inline]
-fn term_ref(buf: &[u8], t: TermEntry, tid: TermId) -> Option<TermRef<'_>> {
+fn term_ref(buf: &[u8], version: u8, t: TermEntry, tid: TermId) -> Option<TermRef<'_>> {
   let (args_and_ret, unify) =
     new_slice_prefix(buf.get(u32_as_usize(t.p_args.get())..)?, usize::from(t.num_args.get()) + 1)?;
   let unify = (buf, buf.len() - unify.len());
-  Some(TermRef { tid, sort: t.sort, args_and_ret, unify })
+  let sort_hi = if version >= crate::cmd::MM0B_VERSION_WIDE_SORTS { t.sort_hi & 1 } else { 0 };
+  Some(TermRef { tid, sort: t.sort, sort_hi, args_and_ret, unify })
 }
 
 #[inline]
@@ -777,7 +829,7 @@ impl<'a, X> MmbFile<'a, X> {
   #[inline]
   #[must_use]
   pub fn term(&self, n: TermId) -> Option<TermRef<'a>> {
-    term_ref(self.buf, *self.terms.get(u32_as_usize(n.0))?, n)
+    term_ref(self.buf, self.header.version, *self.terms.get(u32_as_usize(n.0))?, n)
   }
 
   /// Get the theorem data for a [`ThmId`].
@@ -853,6 +905,35 @@ impl<'a, X: HasSymbolNames<'a>> MmbFile<'a, X> {
     }
   }
 
+  /// Get the proof stream of a `def`, using the index to jump directly to the declaration
+  /// (so this does not require scanning the declaration stream).
+  /// Returns `None` if this is a `term` rather than a `def`.
+  pub fn term_def(&self, n: TermId) -> Result<Option<ProofIter<'a>>, ParseError> {
+    let td = self.term(n).ok_or_else(|| self.bad_index_lookup())?;
+    let entry = self.term_index(n).ok_or_else(|| self.bad_index_lookup())?;
+    match entry.decl()? {
+      Some((StmtCmd::TermDef { .. }, pf)) => Ok(if td.def() { Some(pf) } else { None }),
+      _ => Err(ParseError::StrError(
+        "index entry does not point to a term",
+        u64_as_usize(entry.p_proof),
+      )),
+    }
+  }
+
+  /// Get the proof stream of an axiom or theorem, using the index to jump directly to the
+  /// declaration (so this does not require scanning the declaration stream).
+  /// For an axiom, the proof stream contains only the statement.
+  pub fn thm_proof(&self, n: ThmId) -> Result<ProofIter<'a>, ParseError> {
+    let entry = self.thm_index(n).ok_or_else(|| self.bad_index_lookup())?;
+    match entry.decl()? {
+      Some((StmtCmd::Axiom | StmtCmd::Thm { .. }, pf)) => Ok(pf),
+      _ => Err(ParseError::StrError(
+        "index entry does not point to a theorem",
+        u64_as_usize(entry.p_proof),
+      )),
+    }
+  }
+
   /// Get the name of a sort, if present.
   #[must_use]
   pub fn try_sort_name(&self, n: SortId) -> Option<&'a str> {
@@ -902,6 +983,28 @@ impl<'a, X: HasSymbolNames<'a>> MmbFile<'a, X> {
   }
 }
 
+/// A location in the source text, as returned by [`MmbFile::source_span`].
+#[derive(Debug, Clone)]
+pub struct SourceSpan<'a> {
+  /// The name of the source file.
+  pub file: &'a str,
+  /// The byte range of the span in the source file.
+  pub span: Range<usize>,
+}
+
+impl<'a, X: HasSourceMap<'a>> MmbFile<'a, X> {
+  /// Get the innermost source span associated to the proof stream position `pos`
+  /// (a pointer into the file), if the source map is present and covers this position.
+  #[must_use]
+  pub fn source_span(&self, pos: usize) -> Option<SourceSpan<'a>> {
+    let entries = self.index.get_source_map()?.entries;
+    let i = entries.partition_point(|e| u64_as_usize(e.p_start) <= pos);
+    let e = entries[..i].iter().rev().find(|e| pos < u64_as_usize(e.p_end))?;
+    let file = cstr_from_bytes_prefix(self.buf.get(u64_as_usize(e.p_file)..)?)?.0.to_str().ok()?;
+    Some(SourceSpan { file, span: u64_as_usize(e.start)..u64_as_usize(e.end) })
+  }
+}
+
 /// A handle to an symbol name entry in the index.
 #[derive(Debug, Clone, Copy)]
 struct StrListRef<'a> {
@@ -1047,7 +1150,7 @@ impl<'a> TermRef<'a> {
   /// The return sort of this term/def.
   #[inline]
   #[must_use]
-  pub fn sort(&self) -> SortId { SortId(self.sort & 0x7F) }
+  pub fn sort(&self) -> SortId { SortId(self.sort & 0x7F | self.sort_hi << 7) }
 
   /// The list of arguments of this term/def, not including the return).
   #[inline]
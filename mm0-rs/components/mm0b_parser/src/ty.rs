@@ -7,16 +7,19 @@ use zerocopy::{AsBytes, FromBytes, Unaligned, U64};
 /// bound mask: `10000000_00000000_00000000_00000000_00000000_00000000_00000000_00000000`
 pub const TYPE_BOUND_MASK: u64 = 1 << 63;
 
-/// `10000000_11111111_11111111_11111111_11111111_11111111_11111111_11111111`
-pub const TYPE_SORT_MASK: u64 = (1 << 63) | ((1 << 56) - 1);
+/// `10000000_01111111_11111111_11111111_11111111_11111111_11111111_11111111`
+pub const TYPE_SORT_MASK: u64 = (1 << 63) | ((1 << 55) - 1);
 
-/// deps mask: `00000000_11111111_11111111_11111111_11111111_11111111_11111111_11111111`
-pub const TYPE_DEPS_MASK: u64 = (1 << 56) - 1;
+/// deps mask: `00000000_01111111_11111111_11111111_11111111_11111111_11111111_11111111`
+pub const TYPE_DEPS_MASK: u64 = (1 << 55) - 1;
 
 /// An argument binder in a term/def or axiom/theorem.
 /// * Bit 63 (the high bit of the high byte) is 1 if this is a bound variable.
-/// * Bits 56-62 (the low 7 bits of the high byte) give the sort of the variable.
-/// * Bits 0-55 (the low 7 bytes) are a bitset giving the set of bound variables
+/// * Bits 56-62 (the low 7 bits of the high byte) give the low 7 bits of the sort
+///   of the variable.
+/// * Bit 55 is the high bit of the sort. This is always 0 in version 1 files,
+///   which are limited to 128 sorts.
+/// * Bits 0-54 are a bitset giving the set of bound variables
 ///   earlier in the list that this variable is allowed to depend on.
 #[repr(C)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, FromBytes, AsBytes, Unaligned)]
@@ -40,7 +43,13 @@ impl Type {
 
   /// Make a new `Type` of sort `sort_num`
   #[must_use]
-  pub fn new_of_sort(sort_num: u8) -> Self { Type::from(u64::from(sort_num) << 56) }
+  pub fn new_of_sort(sort_num: u8) -> Self { Type::from(Self::sort_bits(sort_num)) }
+
+  /// The bits encoding sort `sort_num`, placed at bits 55-62 of the type.
+  #[inline]
+  fn sort_bits(sort_num: u8) -> u64 {
+    u64::from(sort_num & 0x7F) << 56 | u64::from(sort_num >> 7) << 55
+  }
 
   /// A brand new `Type`; bool indicates whether it's bound.
   #[must_use]
@@ -62,7 +71,7 @@ impl Type {
     // clear existing sort if any;
     *self &= Type::from(TYPE_SORT_MASK);
     // Add new sort
-    *self |= Type::from(u64::from(sort_id.into_inner()) << 56)
+    *self |= Type::from(Self::sort_bits(sort_id.into_inner()))
   }
 
   /// Add a dependency on `bv_idx`
@@ -82,7 +91,10 @@ impl Type {
   #[allow(clippy::cast_possible_truncation)]
   #[inline]
   #[must_use]
-  pub fn sort(self) -> SortId { SortId(((self.0.get() >> 56) & 0x7F) as u8) }
+  pub fn sort(self) -> SortId {
+    let n = self.0.get();
+    SortId((((n >> 56) & 0x7F) | ((n >> 48) & 0x80)) as u8)
+  }
 
   /// If this is the type of a bound variable, return a u64
   /// whose only activated bit is the bit indicating which bv
@@ -91,7 +103,7 @@ impl Type {
   #[must_use]
   pub fn bound_digit(self) -> Option<u64> {
     if self.bound() {
-      Some(self.0.get() & !(0xFF << 56))
+      Some(self.0.get() & TYPE_DEPS_MASK)
     } else {
       None
     }
@@ -101,7 +113,7 @@ impl Type {
   #[must_use]
   pub fn bound_pos(self) -> Option<u64> {
     if self.bound() {
-      for i in 0..56 {
+      for i in 0..55 {
         if ((1 << i) & self.0.get()) != 0 {
           return Some(i + 1)
         }
@@ -141,9 +153,9 @@ impl Type {
   /// on which it depends.
   #[inline]
   #[must_use]
-  pub fn deps_unchecked(self) -> u64 { self.0.get() & !(0xFF << 56) }
+  pub fn deps_unchecked(self) -> u64 { self.0.get() & TYPE_DEPS_MASK }
 
-  /// Get the high bit only, which holds the boundedness and the sort.
+  /// Get the high bits only, which hold the boundedness and the sort.
   #[must_use]
   pub fn high_bit(self) -> Self { Type(U64::new(self.0.get() & !TYPE_DEPS_MASK)) }
 
@@ -231,11 +231,12 @@ impl<W: Reopen> Mm0Writer<W> {
     self.proof.write_all(&mmb.buf[start..end])
   }
 
-  fn add_term_core(&mut self, name: Option<&str>, sort_def: u8, args: &[Arg], ret: Arg) -> TermId {
+  fn add_term_core(&mut self, name: Option<&str>, def: bool, args: &[Arg], ret: Arg) -> TermId {
+    let sort = ret.sort().0;
     let n = self.terms.push(TermEntry {
       num_args: args.len().try_into().expect("overflow"),
-      sort: sort_def,
-      reserved: 0,
+      sort: sort & 0x7F | u8::from(def) << 7,
+      sort_hi: sort >> 7,
       p_args: U32::new(self.term_thm_buf.len().try_into().expect("overflow")),
     });
     self.term_names.push((self.proof.1, push_name(&mut self.names_buf, name)));
@@ -244,16 +245,18 @@ impl<W: Reopen> Mm0Writer<W> {
     n
   }
 
-  /// Add a new sort with the given name and sort modifiers. Returns the ID of the new sort.
-  pub fn add_sort(&mut self, name: Option<&str>, data: SortData) -> SortId {
+  /// Add a new sort with the given name and sort modifiers, and write its sort statement to the
+  /// proof stream. Returns the ID of the new sort.
+  pub fn add_sort(&mut self, name: Option<&str>, data: SortData) -> io::Result<SortId> {
     let n = self.sorts.push(data);
     self.sort_names.push((self.proof.1, push_name(&mut self.names_buf, name)));
-    n
+    write_cmd_bytes(&mut self.proof, STMT_SORT, &[])?;
+    Ok(n)
   }
 
   /// Add a new term with the given name and arguments. Returns the ID of the new term.
   pub fn add_term(&mut self, name: Option<&str>, args: &[Arg], ret: Arg) -> io::Result<TermId> {
-    let n = self.add_term_core(name, ret.sort().0, args, ret);
+    let n = self.add_term_core(name, false, args, ret);
     write_cmd_bytes(&mut self.proof, STMT_TERM, &[])?;
     Ok(n)
   }
@@ -264,7 +267,7 @@ impl<W: Reopen> Mm0Writer<W> {
   pub fn add_def(
     &mut self, local: bool, name: Option<&str>, args: &[Arg], ret: Arg,
   ) -> DefBuilder<'_, W> {
-    let n = self.add_term_core(name, ret.sort().0 | 1 << 7, args, ret);
+    let n = self.add_term_core(name, true, args, ret);
     DefBuilder(StmtBuilder::new(self, if local { STMT_LOCAL_DEF } else { STMT_DEF }), n)
   }
 
@@ -277,7 +280,7 @@ impl<W: Reopen> Mm0Writer<W> {
       reserved: [0; 2],
       p_args: U32::new(self.term_thm_buf.len().try_into().expect("overflow")),
     });
-    self.term_names.push((self.proof.1, push_name(&mut self.names_buf, name)));
+    self.thm_names.push((self.proof.1, push_name(&mut self.names_buf, name)));
     self.term_thm_buf.extend_from_slice(args.as_bytes());
     ThmBuilder(StmtBuilder::new(self, cmd), n)
   }
@@ -314,7 +317,7 @@ impl<W: Reopen> Mm0Writer<W> {
     proof.write_u8(0)?;
     let (mut proof, proof_size) = (proof.0.reopen()?, proof.1);
     let num_sorts = sorts.len();
-    assert!(num_sorts <= 128, "too many sorts (max 128)");
+    let version = crate::version_for_sorts(num_sorts).expect("too many sorts (max 256)");
     let num_terms = terms.len();
     let num_thms = thms.len();
     let (pad1, p_terms) = pad_to(size_of::<Header>() + num_sorts * size_of::<SortData>(), 8);
@@ -336,8 +339,8 @@ impl<W: Reopen> Mm0Writer<W> {
 
     // header
     w.write_all(&MM0B_MAGIC)?; // magic
-    #[allow(clippy::cast_possible_truncation)] // impossible
-    w.write_all(&[MM0B_VERSION, num_sorts as u8, 0, 0])?; // two bytes reserved
+    let [sorts_lo, sorts_hi] = u16::try_from(num_sorts).expect("impossible").to_le_bytes();
+    w.write_all(&[version, sorts_lo, sorts_hi, 0])?; // one byte reserved
     w.write_u32::<LE>(num_terms.try_into().expect("too many terms"))?; // num_terms
     w.write_u32::<LE>(num_thms.try_into().expect("too many thms"))?; // num_thms
     #[allow(clippy::cast_possible_truncation)] // impossible
@@ -356,7 +359,7 @@ impl<W: Reopen> Mm0Writer<W> {
 
     let num_entries = 1;
     let p_names_buf = p_index + 8 + num_entries * size_of::<TableEntry>();
-    let p_names = p_names_buf + names_buf.len();
+    let (pad3, p_names) = pad_to(p_names_buf + names_buf.len(), 8);
     let p_names_buf: u64 = p_names_buf.try_into().expect("overflow");
     let index = [(INDEX_NAME, p_names)];
     assert_eq!(index.len(), num_entries);
@@ -369,6 +372,7 @@ impl<W: Reopen> Mm0Writer<W> {
     }
 
     w.write_all(&names_buf)?; // name string data
+    w.write_all(&vec![0; pad3])?; // name table padding
     let p_proof = u64::from(p_proof);
     let mut write = |vec| -> io::Result<()> {
       let offset = |off, i| match i {
@@ -1,11 +1,34 @@
-use mm0b_parser::{BareMmbFile, ParseError};
+use mm0_util::{SortId, TermId, ThmId};
+use mm0b_parser::{
+  cmd, optimize_proof, Arg, BareMmbFile, BasicMmbFile, Mm0Writer, NumdStmtCmd, ParseError,
+  ProofCmd, SortData, UnifyCmd,
+};
 use std::fs::OpenOptions;
 use std::io::Read;
 use std::path::PathBuf;
+use zerocopy::AsBytes;
 
-#[repr(align(1))]
+#[repr(align(8))]
 struct AlignFile<T>(T);
 
+/// Copy an MMB file into a `u64` buffer so that the tables are aligned.
+fn align(mmb_bytes: &[u8]) -> Vec<u64> {
+  let mut buf = vec![0_u64; (mmb_bytes.len() + 7) >> 3];
+  buf.as_bytes_mut()[..mmb_bytes.len()].copy_from_slice(mmb_bytes);
+  buf
+}
+
+fn read_peano() -> Vec<u8> {
+  let mut mmb_bytes = Vec::new();
+  let mut mmb_file = OpenOptions::new()
+    .read(true)
+    .truncate(false)
+    .open(&PathBuf::from("./test_resources/peano.mmb"))
+    .unwrap();
+  mmb_file.read_to_end(&mut mmb_bytes).unwrap();
+  mmb_bytes
+}
+
 #[test]
 fn try_next_decl_infinite_loop() {
   let filedata = AlignFile([
@@ -18,13 +41,151 @@ fn try_next_decl_infinite_loop() {
 
 #[test]
 fn peano0() {
-  let mut mmb_bytes = Vec::new();
-  let mut mmb_file = OpenOptions::new()
-    .read(true)
-    .truncate(false)
-    .open(&PathBuf::from("./test_resources/peano.mmb"))
-    .unwrap();
-  mmb_file.read_to_end(&mut mmb_bytes).unwrap();
+  let mmb_bytes = read_peano();
+  let buf = align(&mmb_bytes);
+  let mmb_bytes = &buf.as_bytes()[..mmb_bytes.len()];
   assert!(!mmb_bytes.is_empty());
-  assert!(BareMmbFile::parse(mmb_bytes.as_slice()).is_ok());
+  assert!(BareMmbFile::parse(mmb_bytes).is_ok());
+}
+
+#[test]
+fn peano_random_access() {
+  let mmb_bytes = read_peano();
+  let buf = align(&mmb_bytes);
+  let mmb_bytes = &buf.as_bytes()[..mmb_bytes.len()];
+  let file = BasicMmbFile::parse(mmb_bytes).unwrap();
+  for (stmt, pf) in file.proof().map(Result::unwrap) {
+    match stmt {
+      NumdStmtCmd::TermDef { term_id, .. } => {
+        let def = file.term_def(term_id).unwrap();
+        assert_eq!(def.map(|it| it.pos), file.term(term_id).unwrap().def().then(|| pf.pos));
+      }
+      NumdStmtCmd::Axiom { thm_id } | NumdStmtCmd::Thm { thm_id, .. } => {
+        assert_eq!(file.thm_proof(thm_id).unwrap().pos, pf.pos)
+      }
+      NumdStmtCmd::Sort { .. } => {}
+    }
+  }
+}
+
+#[test]
+fn wide_sorts() {
+  let mut w = Mm0Writer::new(vec![]);
+  for i in 0..200 {
+    w.add_sort(Some(&format!("s{}", i)), SortData(0)).unwrap();
+  }
+  let mut x = Arg::new(true);
+  x.add_sort(SortId(130));
+  x.add_dep(0);
+  let mut ret = Arg::new_of_sort(199);
+  ret.add_dep(0);
+  w.add_term(Some("t"), &[x], ret).unwrap();
+  let mut mmb_bytes = vec![];
+  w.finish(&mut mmb_bytes).unwrap();
+  let buf = align(&mmb_bytes);
+  let file = BareMmbFile::parse(&buf.as_bytes()[..mmb_bytes.len()]).unwrap();
+  assert_eq!(file.header.version, cmd::MM0B_VERSION_WIDE_SORTS);
+  assert_eq!(file.header.sort_count(), 200);
+  assert_eq!(file.sorts.len(), 200);
+  let t = file.term(TermId(0)).unwrap();
+  assert!(!t.def());
+  assert_eq!(t.sort(), SortId(199));
+  assert_eq!(t.args()[0].sort(), SortId(130));
+  assert!(t.args()[0].bound());
+  assert_eq!(t.ret().deps(), Some(1));
+}
+
+#[test]
+fn writer_roundtrip() {
+  let mut w = Mm0Writer::new(vec![]);
+  let wff = w.add_sort(Some("wff"), SortData(4)).unwrap();
+  let a = Arg::new_of_sort(wff.0);
+  let imp = w.add_term(Some("imp"), &[a, a], a).unwrap();
+  let mut b = w.add_def(false, Some("self_imp"), &[a], a);
+  for cmd in [UnifyCmd::Term { tid: imp, save: false }, UnifyCmd::Ref(0), UnifyCmd::Ref(0)] {
+    cmd.write_to(b.unify()).unwrap();
+  }
+  for cmd in [ProofCmd::Ref(0), ProofCmd::Ref(0), ProofCmd::Term { tid: imp, save: false }] {
+    cmd.write_to(b.proof()).unwrap();
+  }
+  let self_imp = b.finish().unwrap();
+  let mut b = w.add_axiom(Some("ax"), &[a]);
+  UnifyCmd::Ref(0).write_to(b.unify()).unwrap();
+  ProofCmd::Ref(0).write_to(b.proof()).unwrap();
+  let ax = b.finish().unwrap();
+  let mut b = w.add_thm(false, Some("th"), &[a]);
+  for cmd in [UnifyCmd::Ref(0), UnifyCmd::Hyp, UnifyCmd::Ref(0)] {
+    cmd.write_to(b.unify()).unwrap();
+  }
+  for cmd in [ProofCmd::Ref(0), ProofCmd::Hyp, ProofCmd::Ref(1)] {
+    cmd.write_to(b.proof()).unwrap();
+  }
+  let th = b.finish().unwrap();
+  let mut mmb_bytes = vec![];
+  w.finish(&mut mmb_bytes).unwrap();
+
+  let buf = align(&mmb_bytes);
+  let file = BasicMmbFile::parse(&buf.as_bytes()[..mmb_bytes.len()]).unwrap();
+  assert_eq!(file.header.version, cmd::MM0B_VERSION_V1);
+  let stmts = file.proof().map(|r| r.unwrap().0).collect::<Vec<_>>();
+  assert!(matches!(stmts[..], [
+    NumdStmtCmd::Sort { sort_id: SortId(0) },
+    NumdStmtCmd::TermDef { term_id: TermId(0), local: false },
+    NumdStmtCmd::TermDef { term_id: TermId(1), local: false },
+    NumdStmtCmd::Axiom { thm_id: ThmId(0) },
+    NumdStmtCmd::Thm { thm_id: ThmId(1), local: false },
+  ]));
+  assert_eq!(file.sort_name(wff), "wff");
+  assert_eq!(file.term_name(imp), "imp");
+  assert_eq!(file.term_name(self_imp), "self_imp");
+  assert_eq!(file.thm_name(ax), "ax");
+  assert_eq!(file.thm_name(th), "th");
+  assert!(!file.term(imp).unwrap().def());
+  assert!(file.term(self_imp).unwrap().def());
+  let unify = file.term(self_imp).unwrap().unify().map(Result::unwrap).collect::<Vec<_>>();
+  assert!(matches!(unify[..], [
+    UnifyCmd::Term { tid: TermId(0), save: false },
+    UnifyCmd::Ref(0),
+    UnifyCmd::Ref(0),
+  ]));
+  let proof = file.thm_proof(th).unwrap().map(Result::unwrap).collect::<Vec<_>>();
+  assert_eq!(proof, [ProofCmd::Ref(0), ProofCmd::Hyp, ProofCmd::Ref(1)]);
+}
+
+#[test]
+fn optimize_unused_saves() {
+  use ProofCmd::*;
+  let t = TermId(0);
+  let cmds = [
+    Ref(0),
+    Term { tid: t, save: true }, // 1: unused
+    Term { tid: t, save: true }, // 2: used
+    Hyp,                         // 3
+    Ref(2),
+    Thm { tid: ThmId(0), save: false },
+    Conv,
+    ConvCut,
+    Refl,
+    ConvSave,         // 4: unused
+    Save,             // 5: unused
+    Dummy(SortId(0)), // 6
+    Ref(6),
+    Ref(3),
+  ];
+  assert_eq!(
+    optimize_proof(1, &cmds),
+    [
+      Ref(0),
+      Term { tid: t, save: false },
+      Term { tid: t, save: true },
+      Hyp,
+      Ref(1),
+      Thm { tid: ThmId(0), save: false },
+      Conv,
+      Refl,
+      Dummy(SortId(0)),
+      Ref(3),
+      Ref(2),
+    ]
+  );
 }
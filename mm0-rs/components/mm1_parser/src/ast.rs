@@ -244,6 +244,10 @@ pub enum SExprKind {
   /// interpreted as variable accesses or variable declarations, unless they appear inside
   /// a quoted context, in which case they evaluate to themselves as a `LispKind::Atom`.
   Atom(Atom),
+  /// An atom whose text is given directly rather than by the span. These are not produced
+  /// by the parser, but they appear in s-expressions constructed by macro expansion,
+  /// and are otherwise interpreted the same as [`Atom::Ident`].
+  Name(ArcString),
   /// A proper list, like `(a b c)`. This is normally interpreted as a function application,
   /// unless the head of the list is a `Syntax`, in which case it has special semantics.
   /// The empty list `()` evaluates to itself, and when quoted a list evaluates to a
@@ -373,6 +377,19 @@ impl SExpr {
   }
 }
 
+/// An attribute like `deprecated foo2 "use foo2"`, in an attribute list
+/// `@[simp, deprecated foo2 "use foo2"]` on a statement.
+#[cfg_attr(feature = "memory", derive(DeepSizeOf))]
+#[derive(Clone, Debug)]
+pub struct Attr {
+  /// The span of the whole attribute.
+  pub span: Span,
+  /// The span of the name of the attribute, `deprecated` in the example.
+  pub name: Span,
+  /// The arguments of the attribute, which are not evaluated.
+  pub args: Vec<SExpr>,
+}
+
 /// Holds a Declaration as a [`DeclKind`] with some extra data.
 #[cfg_attr(feature = "memory", derive(DeepSizeOf))]
 #[derive(Clone, Debug)]
@@ -416,6 +433,9 @@ pub enum SimpleNotaKind {
 #[cfg_attr(feature = "memory", derive(DeepSizeOf))]
 #[derive(Copy, Clone, Debug)]
 pub struct SimpleNota {
+  /// True if the notation is declared `local`, so that it is not exported to importing files
+  /// (and it ends with the enclosing `section`, if any).
+  pub local: bool,
   /// The initial notation keyword, one of `prefix`, `infixl`, or `infixr`.
   pub k: SimpleNotaKind,
   /// The span of the identifier, the `"foo"` in `infix foo: $++$ prec 25;`.
@@ -444,6 +464,9 @@ pub enum Literal {
 #[cfg_attr(feature = "memory", derive(DeepSizeOf))]
 #[derive(Clone, Debug)]
 pub struct GenNota {
+  /// True if the notation is declared `local`, so that it is not exported to importing files
+  /// (and it ends with the enclosing `section`, if any).
+  pub local: bool,
   /// The span of the identifier, the `foo` in `notation foo ...`.
   pub id: Span,
   /// The binder list. The `notation` command mimics the `def` syntax, so it accepts
@@ -461,6 +484,16 @@ pub struct GenNota {
   pub prec: Option<(Prec, bool)>,
 }
 
+/// A restriction on the names made visible by an `import` statement.
+#[cfg_attr(feature = "memory", derive(DeepSizeOf))]
+#[derive(Clone, Debug)]
+pub enum ImportFilter {
+  /// `import "file.mm1" (foo bar);`: Only the listed declarations become visible.
+  Only(Vec<Span>),
+  /// `import "file.mm1" as F;`: The declarations are visible as `F.foo` instead of `foo`.
+  As(Span),
+}
+
 /// A statement in the file. Every statement ends with a `;`, and an MM0/MM1 file
 /// is a list of statements.
 #[cfg_attr(feature = "memory", derive(DeepSizeOf))]
@@ -498,6 +531,8 @@ pub enum StmtKind {
   /// An annotation on another statement, like `@(foo) sort bar;`. The
   /// annotation is a lisp s-expression.
   Annot(SExpr, Box<Stmt>),
+  /// A list of attributes on another statement, like `@[simp, deprecated foo2] theorem foo ...;`.
+  Attrs(Vec<Attr>, Box<Stmt>),
   /// A documentation comment on another statement.
   DocComment(DocComment, Box<Stmt>),
   /// A `do` block like `do { (print 1) };`. This allows the evaluation of lisp
@@ -505,8 +540,22 @@ pub enum StmtKind {
   Do(Vec<SExpr>),
   /// An `import` statement like `import "file.mm1";`. The span gives
   /// the string literal `"file.mm1"`, and the string is the result of parsing
-  /// (after interpreting string escapes).
-  Import(Span, Vec<u8>),
+  /// (after interpreting string escapes). The [`ImportFilter`] restricts or qualifies
+  /// the names that become visible, in `import "file.mm1" (foo bar);`
+  /// and `import "file.mm1" as F;`.
+  Import(Span, Vec<u8>, Option<ImportFilter>),
+  /// A `section` command like `section foo;`, which opens a section that is closed by
+  /// a matching `end foo;` command. The span is the optional name `foo`.
+  Section(Option<Span>),
+  /// A `namespace` command like `namespace foo;`, which opens a namespace that is closed
+  /// by a matching `end foo;` command. The span is the name `foo`.
+  Namespace(Span),
+  /// An `open` command like `open foo;`, which brings the names in namespace `foo` into scope
+  /// until the end of the enclosing section or namespace. The span is the name `foo`.
+  Open(Span),
+  /// An `end` command like `end foo;`, which closes the innermost open section or namespace.
+  /// The span is the optional name `foo`, which must match the name of the section.
+  End(Option<Span>),
 }
 
 /// The elements of a parsed AST. [`StmtKind`] is the "data", with span providing
@@ -555,7 +604,8 @@ impl Default for Ast {
 }
 
 /// Iteartor over the AST's stmts while also traversing into the nested
-/// stmts in [`DocComment`s](StmtKind::DocComment) and [`Annotation`s](StmtKind::Annot).
+/// stmts in [`DocComment`s](StmtKind::DocComment), [`Annotation`s](StmtKind::Annot)
+/// and [`Attribute`s](StmtKind::Attrs).
 #[must_use] #[derive(Debug)]
 pub struct StmtIter<'a> {
   stmts: std::slice::Iter<'a, Stmt>,
@@ -567,7 +617,7 @@ impl<'a> Iterator for StmtIter<'a> {
   fn next(&mut self) -> Option<Self::Item> {
     let cur = self.nested.take().or_else(|| self.stmts.next())?;
     match &cur.k {
-      StmtKind::DocComment(_, s2) | StmtKind::Annot(_, s2) => {
+      StmtKind::DocComment(_, s2) | StmtKind::Annot(_, s2) | StmtKind::Attrs(_, s2) => {
         self.nested = Some(s2.as_ref());
         Some(cur)
       }
@@ -578,7 +628,8 @@ impl<'a> Iterator for StmtIter<'a> {
 
 impl Ast {
   /// Get an iterator over the AST's stmts while also traversing into the nested
-  /// stmts in [`DocComment`s](StmtKind::DocComment) and [`Annotation`s](StmtKind::Annot).
+  /// stmts in [`DocComment`s](StmtKind::DocComment), [`Annotation`s](StmtKind::Annot)
+  /// and [`Attribute`s](StmtKind::Attrs).
   #[must_use]
   pub fn stmts_iter(&self) -> StmtIter<'_> { StmtIter { stmts: self.stmts.iter(), nested: None } }
 
@@ -59,8 +59,8 @@ pub mod ast;
 
 use annotate_snippets::snippet::AnnotationType;
 use ast::{
-  Atom, Binder, Const, Decl, DeclKind, Delimiter, DepType, Formula, GenNota, Literal, LocalKind,
-  SExpr, SExprKind, SimpleNota, SimpleNotaKind, Stmt, StmtKind, Type,
+  Atom, Attr, Binder, Const, Decl, DeclKind, Delimiter, DepType, Formula, GenNota, ImportFilter,
+  Literal, LocalKind, SExpr, SExprKind, SimpleNota, SimpleNotaKind, Stmt, StmtKind, Type,
 };
 use mm0_util::{
   let_unchecked, unwrap_unchecked, BoxError, LinedString, Modifiers, Position, Prec, Span,
@@ -231,6 +231,10 @@ enum CommandKeyword {
   Do,
   Import,
   Exit,
+  Section,
+  Namespace,
+  Open,
+  End,
 }
 
 impl CommandKeyword {
@@ -252,6 +256,10 @@ impl CommandKeyword {
       b"do" => Some(CommandKeyword::Do),
       b"import" => Some(CommandKeyword::Import),
       b"exit" => Some(CommandKeyword::Exit),
+      b"section" => Some(CommandKeyword::Section),
+      b"namespace" => Some(CommandKeyword::Namespace),
+      b"open" => Some(CommandKeyword::Open),
+      b"end" => Some(CommandKeyword::End),
       _ => None,
     }
   }
@@ -420,6 +428,35 @@ impl<'a> Parser<'a> {
     self.ident().ok_or_else(|| self.err("expecting identifier".into()))
   }
 
+  /// Parse a possibly qualified identifier like `foo.bar`, returning an error on failure.
+  fn dotted_ident_err(&mut self) -> Result<Span> {
+    let mut sp = self.ident_err()?;
+    while self.idx == sp.end && self.cur_opt() == Some(b'.') {
+      self.idx += 1;
+      sp.end = self.ident_err()?.end;
+    }
+    Ok(sp)
+  }
+
+  /// Parse the optional name of a `section` or `end` command.
+  fn section_name(&mut self) -> Result<Option<Span>> {
+    if self.cur_opt().map_or(false, ident_start) { self.dotted_ident_err().map(Some) } else { Ok(None) }
+  }
+
+  /// Parse the optional `(foo bar)` or `as F` after the file name of an `import` statement.
+  fn import_filter(&mut self) -> Result<Option<ImportFilter>> {
+    if self.chr(b'(').is_some() {
+      let mut names = vec![];
+      while self.chr(b')').is_none() { names.push(self.dotted_ident_err()?) }
+      return Ok(Some(ImportFilter::Only(names)))
+    }
+    match self.ident() {
+      None => Ok(None),
+      Some(sp) if self.span(sp) == b"as" => Ok(Some(ImportFilter::As(self.ident_err()?))),
+      Some(sp) => Err(ParseError::new(sp, "expected '(', 'as' or ';'".into())),
+    }
+  }
+
   /// Attempt to parse a `$ .. $` delimited formula.
   /// On success, advances the parser past the formula and any trailing whitespace.
   /// On failure, does not advance the parser.
@@ -777,6 +814,10 @@ impl<'a> Parser<'a> {
         let (span, n) = self.number()?;
         Ok(SExpr { span, k: SExprKind::Number(n) })
       }
+      Some(b'|') => {
+        self.idx += 1;
+        (Ok(SExpr::atom(start..self.idx, Atom::Ident)), self.ws()).0
+      }
       _ => Ok(SExpr::atom(self.lisp_ident()?, Atom::Ident)),
     }
   }
@@ -857,7 +898,17 @@ impl<'a> Parser<'a> {
     }
   }
 
-  fn simple_nota(&mut self, k: SimpleNotaKind) -> Result<(usize, SimpleNota)> {
+  /// Check the modifiers of a notation command, which can only be `local`, and return
+  /// true if it is `local`.
+  fn nota_modifiers(&mut self, m: Modifiers, sp: Span) -> bool {
+    if !(m - Modifiers::LOCAL).is_empty() {
+      self.push_err(Err(ParseError::new(sp,
+        "notation commands do not take modifiers other than 'local'".into())));
+    }
+    m.contains(Modifiers::LOCAL)
+  }
+
+  fn simple_nota(&mut self, local: bool, k: SimpleNotaKind) -> Result<(usize, SimpleNota)> {
     let id = self.ident_err()?;
     self.chr_err(b':')?;
     let c = self.cnst()?;
@@ -866,7 +917,7 @@ impl<'a> Parser<'a> {
       .filter(|&id| self.span(id) == b"prec")
       .ok_or_else(|| self.err("expected 'prec'".into()))?;
     let prec = self.prec()?;
-    Ok((self.chr_err(b';')?, SimpleNota { k, id, c, prec }))
+    Ok((self.chr_err(b';')?, SimpleNota { local, k, id, c, prec }))
   }
 
   fn modifiers_empty(&mut self, m: Modifiers, sp: Span, msg: &'static str) {
@@ -878,8 +929,8 @@ impl<'a> Parser<'a> {
   fn simple_nota_stmt(
     &mut self, start: usize, m: Modifiers, sp: Span, k: SimpleNotaKind,
   ) -> Result<Option<Stmt>> {
-    self.modifiers_empty(m, sp, "notation commands do not take modifiers");
-    let (end, n) = self.simple_nota(k)?;
+    let local = self.nota_modifiers(m, sp);
+    let (end, n) = self.simple_nota(local, k)?;
     Ok(Some(Stmt::new((start..end).into(), StmtKind::SimpleNota(n))))
   }
 
@@ -950,6 +1001,20 @@ impl<'a> Parser<'a> {
     }
   }
 
+  /// Parse an attribute list like `simp, deprecated foo2]`, after the `@[`.
+  fn attrs(&mut self) -> Result<Vec<Attr>> {
+    let mut attrs = vec![];
+    loop {
+      let name = self.ident_err()?;
+      let mut args = vec![];
+      while !matches!(self.cur_opt(), Some(b',' | b']')) { args.push(self.sexpr()?) }
+      let end = args.last().map_or(name.end, |e| e.span.end);
+      attrs.push(Attr {span: (name.start..end).into(), name, args});
+      if self.chr(b']').is_some() { return Ok(attrs) }
+      self.chr_err(b',')?;
+    }
+  }
+
   /// Try to parse a statement. Parsing essentially amounts to looping over this
   /// while handling errors.
   fn stmt(&mut self) -> Result<Option<Stmt>> {
@@ -962,6 +1027,13 @@ impl<'a> Parser<'a> {
     }
 
     if self.chr(b'@').is_some() {
+      if self.chr(b'[').is_some() {
+        let attrs = self.attrs()?;
+        let s = self.stmt()?.ok_or_else(|| {
+          ParseError::new(start..self.idx, "statement expected after attributes".into())
+        })?;
+        return Ok(Some(Stmt::new((start..s.span.end).into(), StmtKind::Attrs(attrs, Box::new(s)))))
+      }
       let e = self.sexpr()?;
       let s = self.stmt()?.ok_or_else(|| {
         ParseError::new(start..e.span.end, "statement expected after annotation".into())
@@ -1027,7 +1099,7 @@ impl<'a> Parser<'a> {
             Ok(Some(Stmt::new((start..end).into(), StmtKind::Coercion { id, from, to })))
           }
           Some(CommandKeyword::Notation) => {
-            self.modifiers_empty(m, id, "notation commands do not take modifiers");
+            let local = self.nota_modifiers(m, id);
             let id = self.ident_err()?;
             let bis = self.binders()?;
             let ty = if self.chr(b':').is_some() { Some(self.ty()?) } else { None };
@@ -1050,7 +1122,7 @@ impl<'a> Parser<'a> {
             let end = self.chr_err(b';')?;
             Ok(Some(Stmt::new(
               (start..end).into(),
-              StmtKind::Notation(GenNota { id, bis, ty, lits, prec }),
+              StmtKind::Notation(GenNota { local, id, bis, ty, lits, prec }),
             )))
           }
           Some(CommandKeyword::Do) => {
@@ -1069,9 +1141,34 @@ impl<'a> Parser<'a> {
           Some(CommandKeyword::Import) => {
             self.modifiers_empty(m, id, "import statements do not take modifiers");
             let (sp, s) = self.string()?;
+            let filter = self.import_filter()?;
             let span = (start..self.chr_err(b';')?).into();
             self.imports.push((sp, s.clone()));
-            Ok(Some(Stmt::new(span, StmtKind::Import(sp, s))))
+            Ok(Some(Stmt::new(span, StmtKind::Import(sp, s, filter))))
+          }
+          Some(CommandKeyword::Section) => {
+            self.modifiers_empty(m, id, "sections do not take modifiers");
+            let name = self.section_name()?;
+            let end = self.chr_err(b';')?;
+            Ok(Some(Stmt::new((start..end).into(), StmtKind::Section(name))))
+          }
+          Some(CommandKeyword::Namespace) => {
+            self.modifiers_empty(m, id, "namespaces do not take modifiers");
+            let name = self.dotted_ident_err()?;
+            let end = self.chr_err(b';')?;
+            Ok(Some(Stmt::new((start..end).into(), StmtKind::Namespace(name))))
+          }
+          Some(CommandKeyword::Open) => {
+            self.modifiers_empty(m, id, "'open' does not take modifiers");
+            let name = self.dotted_ident_err()?;
+            let end = self.chr_err(b';')?;
+            Ok(Some(Stmt::new((start..end).into(), StmtKind::Open(name))))
+          }
+          Some(CommandKeyword::End) => {
+            self.modifiers_empty(m, id, "'end' does not take modifiers");
+            let name = self.section_name()?;
+            let end = self.chr_err(b';')?;
+            Ok(Some(Stmt::new((start..end).into(), StmtKind::End(name))))
           }
           Some(CommandKeyword::Exit) => {
             self.modifiers_empty(m, id, "exit does not take modifiers");
@@ -1147,6 +1244,9 @@ impl<'a> Parser<'a> {
 /// `old` contains the last successful parse of the same file, in order to reuse
 /// previous parsing work. The [`Position`] denotes the first byte where the
 /// new file differs from the old one.
+///
+/// Returns the parsed [`Ast`], along with the number of statements at the start of the file
+/// which were reused from `old` (which is 0 if `old` is `None`).
 #[must_use]
 pub fn parse(file: Arc<LinedString>, old: Option<(Position, Arc<Ast>)>) -> (usize, Ast) {
   let (errors, imports, idx, mut stmts) = if let Some((pos, ast)) = old {
@@ -1168,10 +1268,11 @@ pub fn parse(file: Arc<LinedString>, old: Option<(Position, Arc<Ast>)>) -> (usiz
   } else {
     Default::default()
   };
+  let reused = stmts.len();
   let mut p = Parser { source: file.as_bytes(), errors, imports, idx, restart_pos: None };
   p.ws();
   while let Some(d) = p.stmt_recover() {
     stmts.push(d)
   }
-  (0, Ast { errors: p.errors, imports: p.imports, source: file, stmts })
+  (reused, Ast { errors: p.errors, imports: p.imports, source: file, stmts })
 }
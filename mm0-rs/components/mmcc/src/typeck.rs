@@ -953,7 +953,7 @@ impl<'a> TypeChecker<'a> {
         return Err(ElabError::new_e(self.try_get_span(e), "unexpected arguments"))
       }
       head.unwrapped(|e| match *e {
-        LispKind::Number(ref n) => Ok(PureExpr::Int(n.clone())),
+        LispKind::Number(ref n) => Ok(PureExpr::Int(n.clone().into())),
         LispKind::Bool(b) => Ok(PureExpr::Bool(b)),
         _ => Err(ElabError::new_e(self.try_get_span(&head), "unexpected expression")),
       })
@@ -0,0 +1,149 @@
+//! A library interface to the elaborator, for tools that embed `mm0-rs` directly instead of
+//! talking to the server over LSP or running `mm0-rs compile` in a subprocess.
+//!
+//! [`elaborate`] runs the same pipeline as the server on a file (parse, elaborate, freeze) and
+//! returns a [`Snapshot`], which contains the resulting [`FrozenEnv`], the diagnostics in the
+//! file and the [`Spans`] of the statements, which map positions in the file to the objects
+//! (terms, theorems, variables and so on) they refer to.
+//!
+//! ```no_run
+//! let snap = mm0_rs::api::elaborate("examples/peano.mm1")?;
+//! for d in &snap.diagnostics {
+//!   println!("{}:{:?}: {}", d.file, d.range, d.message)
+//! }
+//! println!("{} theorems", snap.env.thms().len());
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! Files imported by the elaborated file are read and elaborated once, and then shared by all
+//! later calls (errors in them are reported as diagnostics on the `import` statement). Call
+//! [`reload`] to read them again after they change on disk. The file itself is read again on
+//! every call.
+//!
+//! Nothing is printed while elaborating: the diagnostics are only returned in the [`Snapshot`].
+
+use std::{fs, io};
+use std::path::Path;
+use std::sync::Arc;
+use mm1_parser::{ast::Ast, ErrorLevel, ParseError};
+use crate::elab::{spans::Spans, ElabErrorKind};
+use crate::{compiler, ElabError, FileRef, FileSpan, FrozenEnv, LinedString, ObjectKind,
+  Position, Range, Span, WarningCode};
+
+/// A diagnostic (error, warning, info) in an elaborated file.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+  /// The file containing the diagnostic.
+  pub file: FileRef,
+  /// The location of the diagnostic, as byte offsets into the file.
+  pub span: Span,
+  /// The location of the diagnostic as a line/column range, if the file is a text file.
+  pub range: Option<Range>,
+  /// The severity of the diagnostic.
+  pub level: ErrorLevel,
+  /// The [warning code](crate::elab::warning) of the diagnostic, if it is a configurable warning.
+  pub code: Option<WarningCode>,
+  /// The message.
+  pub message: String,
+  /// Other locations related to the diagnostic, with short descriptions.
+  pub related: Vec<(FileSpan, String)>,
+}
+
+impl Diagnostic {
+  fn from_parse_error(file: &FileRef, src: Option<&LinedString>, e: &ParseError) -> Self {
+    Diagnostic {
+      file: file.clone(),
+      span: e.pos,
+      range: src.map(|src| src.to_range(e.pos)),
+      level: e.level,
+      code: None,
+      message: format!("{}", e.msg),
+      related: vec![],
+    }
+  }
+
+  fn from_elab_error(file: &FileRef, src: Option<&LinedString>, e: &ElabError) -> Self {
+    Diagnostic {
+      file: file.clone(),
+      span: e.pos,
+      range: src.map(|src| src.to_range(e.pos)),
+      level: e.level,
+      code: e.code,
+      message: e.kind.msg(),
+      related: match &e.kind {
+        ElabErrorKind::Boxed(_, Some(info)) =>
+          info.iter().map(|(fs, e)| (fs.clone(), e.to_string())).collect(),
+        _ => vec![]
+      },
+    }
+  }
+}
+
+/// The result of elaborating a file with [`elaborate`].
+#[derive(Debug)]
+pub struct Snapshot {
+  /// The (canonicalized) path of the file.
+  pub path: FileRef,
+  /// The text of the file, or `None` if it is a binary (`.mmb`) file.
+  pub source: Option<Arc<LinedString>>,
+  /// The parsed file, for MM1 and MM0 files.
+  pub ast: Option<Arc<Ast>>,
+  /// The environment at the end of the file, including everything it imports.
+  pub env: FrozenEnv,
+  /// The parse and elaboration errors, warnings and messages in the file, in order.
+  pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Snapshot {
+  /// Returns true if any of the diagnostics is an error.
+  #[must_use] pub fn has_errors(&self) -> bool {
+    self.diagnostics.iter().any(|d| d.level == ErrorLevel::Error)
+  }
+
+  /// Get the [`Spans`] of the statements in the file, in order.
+  #[must_use] pub fn spans(&self) -> &[Spans<ObjectKind>] { self.env.spans() }
+
+  /// Get the objects referred to at the byte offset `pos`, with their spans.
+  /// The innermost spans come last.
+  pub fn objects_at(&self, pos: usize) -> impl Iterator<Item=&(Span, ObjectKind)> {
+    self.env.find(pos).into_iter().flat_map(move |spans| spans.find_pos(pos))
+  }
+
+  /// Convert a line/column position in the file to a byte offset.
+  #[must_use] pub fn to_idx(&self, pos: Position) -> Option<usize> {
+    self.source.as_ref()?.to_idx(pos)
+  }
+}
+
+/// Elaborate the file at `path`, which can be an MM1, MM0, MMB, MMU or Metamath file.
+///
+/// This only fails if the file (or a file it imports) cannot be read; errors in the file
+/// itself are returned as [diagnostics](Snapshot::diagnostics).
+pub fn elaborate(path: impl AsRef<Path>) -> io::Result<Snapshot> {
+  snapshot(fs::canonicalize(path)?.into(), None)
+}
+
+/// Elaborate `text` as the contents of the file `path` (which does not have to exist on disk),
+/// as in [`elaborate`]. Imports are resolved relative to `path`.
+pub fn elaborate_text(path: impl AsRef<Path>, text: String) -> io::Result<Snapshot> {
+  let path = path.as_ref();
+  snapshot(fs::canonicalize(path).unwrap_or_else(|_| path.to_owned()).into(), Some(text))
+}
+
+/// Forget all the files that have been read and elaborated, so that they are read from disk
+/// again by the next call to [`elaborate`].
+pub fn reload() { compiler::clear_vfs() }
+
+fn snapshot(path: FileRef, text: Option<String>) -> io::Result<Snapshot> {
+  compiler::set_quiet(true);
+  compiler::set_report(false);
+  let (path, text, ast, errors, env) = compiler::elab_snapshot(path, text)?;
+  let source = text.try_ascii().cloned();
+  let src = source.as_deref();
+  let mut diagnostics: Vec<_> = ast.iter().flat_map(|ast| &ast.errors)
+    .map(|e| Diagnostic::from_parse_error(&path, src, e))
+    .chain(errors.iter().map(|e| Diagnostic::from_elab_error(&path, src, e)))
+    .collect();
+  diagnostics.sort_by_key(|d| d.span.start);
+  Ok(Snapshot { path, source, ast, env, diagnostics })
+}
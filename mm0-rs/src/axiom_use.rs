@@ -0,0 +1,136 @@
+//! Axiom usage audit, which determines the axioms that each theorem depends on.
+//!
+//! A theorem depends on an axiom if the axiom is used in its proof, or in the proof of any
+//! theorem used in its proof, and so on. Theorems without a proof (because they were stated
+//! in an MM0 file, or their proof failed to elaborate) are treated like axioms, and are
+//! reported separately as "holes". An axiom depends only on itself, and a theorem with no
+//! proof is a hole depending only on itself.
+//!
+//! The `mm0-rs axiom-use` subcommand prints the axioms used by every theorem, and fails if
+//! any theorem relies on an axiom outside of an expected set, or on a hole.
+use std::{fs, io};
+use bit_set::BitSet;
+use clap::ArgMatches;
+use serde_json::{json, Value};
+use crate::{FileRef, FrozenEnv, Proof, ThmId, ThmKind, ThmVec, DeclKey, StmtTrace};
+
+/// The axioms and holes used by a single theorem.
+#[derive(Clone, Debug, Default)]
+struct Uses {
+  /// The axioms that the theorem depends on.
+  axioms: BitSet,
+  /// The theorems without proofs that the theorem depends on.
+  holes: BitSet,
+}
+
+/// The result of [`FrozenEnv::axiom_use`], containing the set of axioms and holes
+/// that each theorem in the environment depends on.
+#[derive(Debug)]
+pub struct AxiomUse(ThmVec<Uses>);
+
+fn ids(bs: &BitSet) -> impl Iterator<Item=ThmId> + '_ {
+  bs.iter().map(|i| ThmId(i.try_into().expect("too many theorems")))
+}
+
+impl AxiomUse {
+  /// The axioms that theorem `t` depends on, in declaration order.
+  pub fn axioms(&self, t: ThmId) -> impl Iterator<Item=ThmId> + '_ { ids(&self.0[t].axioms) }
+
+  /// The theorems without proofs that theorem `t` depends on, in declaration order.
+  pub fn holes(&self, t: ThmId) -> impl Iterator<Item=ThmId> + '_ { ids(&self.0[t].holes) }
+
+  /// Returns true if theorem `t` depends on a theorem without a proof.
+  #[must_use] pub fn has_holes(&self, t: ThmId) -> bool { !self.0[t].holes.is_empty() }
+}
+
+impl FrozenEnv {
+  /// Compute the axioms and holes that each theorem depends on, transitively.
+  ///
+  /// This is a single pass over the theorems in declaration order, since a proof can
+  /// only refer to earlier theorems.
+  #[must_use] pub fn axiom_use(&self) -> AxiomUse {
+    let mut uses: ThmVec<Uses> = ThmVec(Vec::with_capacity(self.thms().len()));
+    for (tid, td) in self.thms().enum_iter() {
+      let mut u = Uses::default();
+      match &td.kind {
+        ThmKind::Axiom => { u.axioms.insert(tid.0 as usize); }
+        ThmKind::Thm(None) => { u.holes.insert(tid.0 as usize); }
+        ThmKind::Thm(Some(Proof {heap, hyps, head, ..})) =>
+          for e in heap.iter().chain(&**hyps).chain(Some(head)) {
+            e.for_each_decl(&mut |k| if let DeclKey::Thm(t) = k {
+              let Uses {axioms, holes} = &uses[t];
+              u.axioms.union_with(axioms);
+              u.holes.union_with(holes);
+            })
+          }
+      }
+      uses.push(u);
+    }
+    AxiomUse(uses)
+  }
+}
+
+/// Main entry point for `mm0-rs axiom-use` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs axiom-use [--json] [--expect AXIOMS] <in.mm1>`, where:
+///
+/// - `in.mm1` is the MM1 (or MM0) file to elaborate.
+/// - `--expect` is a comma separated list of the axioms that theorems are allowed to use.
+///   If omitted, all axioms are allowed. A theorem that depends on an axiom not in this list,
+///   or on a theorem without a proof, is reported as unexpected, and the command fails
+///   if there are any such theorems.
+/// - `--json` prints the report as a JSON list of `{name, axioms, holes, unexpected}`
+///   objects, one for each theorem, instead of one line per theorem.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let path = args.value_of("INPUT").expect("required arg");
+  let path: FileRef = fs::canonicalize(path)?.into();
+  crate::compiler::set_quiet(true);
+  let env = crate::compiler::elab_for_result(path)?.1.unwrap_or_else(|| std::process::exit(1));
+  let expected = args.value_of("expect").map(|axs| axs.split(',').filter_map(|ax| {
+    match env.get_atom(ax.as_bytes()).and_then(|a| env.data()[a].decl()) {
+      Some(DeclKey::Thm(t)) if matches!(env.thm(t).kind, ThmKind::Axiom) => return Some(t),
+      _ => eprintln!("warning: unknown axiom '{}'", ax),
+    }
+    None
+  }).collect::<Vec<_>>());
+  let axuse = env.axiom_use();
+  let name = |t: ThmId| env.data()[env.thm(t).atom].name().as_str();
+  let mut report = vec![];
+  let mut failed = 0;
+  for s in env.stmts() {
+    let a = if let StmtTrace::Decl(a) = *s {a} else {continue};
+    let t = if let Some(DeclKey::Thm(t)) = env.data()[a].decl() {t} else {continue};
+    if matches!(env.thm(t).kind, ThmKind::Axiom) {continue}
+    let unexpected = axuse.axioms(t)
+      .filter(|t| expected.as_ref().map_or(false, |exp| !exp.contains(t)))
+      .chain(axuse.holes(t)).map(name).collect::<Vec<_>>();
+    if !unexpected.is_empty() { failed += 1 }
+    if args.is_present("json") {
+      report.push(json!({
+        "name": name(t),
+        "axioms": axuse.axioms(t).map(name).collect::<Value>(),
+        "holes": axuse.holes(t).map(name).collect::<Value>(),
+        "unexpected": unexpected,
+      }))
+    } else {
+      print!("{}:", name(t));
+      for ax in axuse.axioms(t) { print!(" {}", name(ax)) }
+      for h in axuse.holes(t) { print!(" sorry({})", name(h)) }
+      println!();
+      if !unexpected.is_empty() {
+        println!("  unexpected: {}", unexpected.join(", "))
+      }
+    }
+  }
+  if args.is_present("json") {
+    serde_json::to_writer_pretty(io::stdout(), &report)?;
+    println!()
+  }
+  if failed != 0 {
+    eprintln!("{} theorems rely on unexpected axioms", failed);
+    std::process::exit(1)
+  }
+  Ok(())
+}
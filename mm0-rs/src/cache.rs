@@ -0,0 +1,1389 @@
+//! A persistent cache of elaborated environments, stored in `.mmcache` files.
+//!
+//! When a cache directory is set (using `--cache DIR` on `mm0-rs compile` or `mm0-rs server`),
+//! each MM1 or MM0 file that elaborates without any errors or messages is saved to
+//! `DIR/<hash>.mmcache`, where `<hash>` is a hash of the path of the file. The entry records
+//! a hash of the contents of the file and of each of its transitive imports, and the next time
+//! the file is needed the saved environment is used instead of elaborating it again, as long as
+//! none of these files has changed.
+//!
+//! The saved environment contains everything that a file importing it can see, including the
+//! lisp globals, but not the [`Spans`](crate::elab::spans::Spans) used for hovers and
+//! go-to-definition, so the server only uses the cache for files which are not open in the
+//! editor. An environment containing values which cannot be saved, like the state of the
+//! MMC compiler, is not cached. Nothing is cached when the lisp file I/O builtins are enabled
+//! (with `--allow-io`), since the environment can then depend on the contents of files read
+//! with `read-file`, which are not tracked.
+//!
+//! The same format is used to send a copy of an environment and lisp values to another
+//! thread for `(spawn)`, which evaluates a lisp function in the background. Futures are
+//! copied with their result if they have been awaited, and as an error otherwise.
+use std::cell::{Cell, RefCell};
+use std::collections::{hash_map::DefaultHasher, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::rc::{Rc, Weak};
+use std::sync::{Arc, Mutex};
+use std::{fs, io};
+use num::{BigInt, BigRational};
+use crate::elab::lisp::{Annot, BuiltinProc, HashKey, InferTarget, Int, LispFuture, LispWeak, Proc, ProcPos, ProcSpec, Syntax,
+  parser::{ArgKind, Branch, Ir, MVarPattern, Pattern}};
+use crate::{ArcString, AtomData, AtomId, AtomVec, Coe, DeclKey, Delims, Deprecation, DocComment, ElabError, Environment,
+  Expr, ExprNode, FileRef, FileSpan, FrozenEnv, LispData, LispKind, LispVal, Literal,
+  MergeStrategyInner, Modifiers, MutexExt, NotaInfo, OutputString, ParserEnv, Prec, Proof,
+  ProofNode, Sort, SortId, SortVec, Span, StmtTrace, Term, TermId, TermKind, TermVec, Thm, ThmId,
+  ThmKind, ThmVec, Type, render::RenderFormat};
+
+/// The magic number at the start of a cache file.
+const MAGIC: [u8; 8] = *b"MM1CACHE";
+
+/// The version of the cache format. Cache files with a different version are ignored.
+const VERSION: u64 = 7;
+
+lazy_static! {
+  /// The cache directory, if caching is enabled.
+  static ref CACHE_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+/// Set the directory used to cache elaborated files, or disable the cache if `None`.
+pub fn set_cache_dir(dir: Option<PathBuf>) { *CACHE_DIR.ulock() = dir }
+
+/// Get the cache directory, if caching is enabled. Caching is disabled when file I/O is
+/// allowed, as described in the [module documentation](self).
+pub(crate) fn cache_dir() -> Option<PathBuf> {
+  if crate::get_allow_io() { return None }
+  CACHE_DIR.ulock().clone()
+}
+
+/// The hash of the contents of a file, used to detect changes to the file.
+#[must_use] pub fn content_hash(text: &[u8]) -> u64 {
+  let mut hasher = DefaultHasher::new();
+  text.hash(&mut hasher);
+  hasher.finish()
+}
+
+/// The hash of the file being cached, which also depends on the settings that
+/// can affect the result of elaboration.
+fn key_hash(text: &[u8]) -> u64 {
+  let mut hasher = DefaultHasher::new();
+  (VERSION, crate::get_check_proofs(), crate::get_proof_spans(), text).hash(&mut hasher);
+  hasher.finish()
+}
+
+/// The location of the cache entry for `path` in the cache directory `dir`.
+fn entry_path(dir: &Path, path: &FileRef) -> PathBuf {
+  let mut hasher = DefaultHasher::new();
+  path.path().hash(&mut hasher);
+  dir.join(format!("{:016x}.mmcache", hasher.finish()))
+}
+
+/// An environment loaded from the cache.
+#[derive(Debug)]
+pub(crate) struct CacheEntry {
+  /// The cached environment.
+  pub(crate) env: FrozenEnv,
+  /// The files directly imported by the cached file.
+  #[cfg_attr(not(feature = "server"), allow(unused))]
+  pub(crate) deps: Vec<FileRef>,
+  /// The files imported by the cached file, directly or indirectly.
+  pub(crate) trans: Vec<FileRef>,
+}
+
+/// Load the environment for `path`, which has contents `text`, from the cache, if caching is
+/// enabled and the file and its imports are unchanged since the entry was written.
+/// `dep_hash` should return the [`content_hash`] of the current contents of an imported file.
+pub(crate) fn load(path: &FileRef, text: &[u8],
+  mut dep_hash: impl FnMut(&FileRef) -> Option<u64>
+) -> Option<CacheEntry> {
+  let data = fs::read(entry_path(&cache_dir()?, path)).ok()?;
+  let mut r = Reader::new(&data);
+  if r.take(MAGIC.len())? != MAGIC || r.u()? != VERSION || r.u()? != key_hash(text) { return None }
+  let (mut deps, mut trans) = (vec![], vec![]);
+  for _ in 0..r.u()? {
+    let file = r.file_ref()?;
+    let (hash, direct) = (r.u()?, bool::decode(&mut r)?);
+    if dep_hash(&file)? != hash { return None }
+    if direct { deps.push(file.clone()) }
+    trans.push(file);
+  }
+  let env = unpack(&mut r, Environment::decode)?;
+  Some(CacheEntry {env: FrozenEnv::new(env), deps, trans})
+}
+
+/// Save the environment `env` for `path`, which has contents `text`, to the cache, if
+/// caching is enabled and the environment can be saved. `deps` are the files directly imported
+/// by `path`, and `trans` are the files imported directly or indirectly, along with the
+/// [`content_hash`] of each.
+pub(crate) fn store(path: &FileRef, text: &[u8], deps: &[FileRef], trans: &[(FileRef, u64)],
+  env: &FrozenEnv
+) -> io::Result<()> {
+  let dir = if let Some(dir) = cache_dir() {dir} else {return Ok(())};
+  let mut out = Writer::new();
+  out.buf.extend_from_slice(&MAGIC);
+  out.u(VERSION);
+  out.u(key_hash(text));
+  out.usize(trans.len());
+  for (file, hash) in trans {
+    out.path(file);
+    out.u(*hash);
+    deps.contains(file).encode(&mut out);
+  }
+  if !out.ok || !pack(&mut out, |w| unsafe { env.thaw() }.encode(w)) { return Ok(()) }
+  fs::create_dir_all(&dir)?;
+  let dest = entry_path(&dir, path);
+  // Write to a temporary file first, so that concurrent readers never see a partial entry.
+  let tmp = dest.with_extension(format!("{}.tmp", std::process::id()));
+  fs::write(&tmp, &out.buf)?;
+  fs::rename(&tmp, &dest)
+}
+
+/// Write the data produced by `f` to `out`, preceded by the table of files that it refers to
+/// and followed by the targets of its weak references. Returns false if the data cannot be saved.
+fn pack(out: &mut Writer, f: impl FnOnce(&mut Writer)) -> bool {
+  let mut w = Writer::new();
+  f(&mut w);
+  if !w.ok { return false }
+  out.usize(w.files.len());
+  for file in &w.files { out.path(file) }
+  out.buf.extend_from_slice(&w.buf);
+  for p in &w.weak {
+    out.usize(w.lisp.get(p).map_or(0, |&i| i + 1))
+  }
+  out.ok
+}
+
+/// Read data written by [`pack`], using `f` to read the data itself.
+fn unpack<T>(r: &mut Reader<'_>, f: impl FnOnce(&mut Reader<'_>) -> Option<T>) -> Option<T> {
+  for _ in 0..r.u()? {
+    let file = r.file_ref()?;
+    r.files.push(file)
+  }
+  let t = f(r)?;
+  for e in std::mem::take(&mut r.weak) {
+    match r.usize()? {
+      0 => e.as_lref(|m| *m.get_mut_weak() = LispWeak::Weak(Weak::new()))?,
+      n => { let tgt = r.lisp.get(n - 1)?; e.as_lref(|m| m.set_weak(tgt))? }
+    }
+  }
+  Some(t)
+}
+
+/// Serialize a copy of the environment `env`, so that functions can be called in it on
+/// another thread (see [`BuiltinProc::Spawn`]). The result can be shared by several tasks,
+/// each with its own function written by [`encode_task`]. Returns `None` if the environment
+/// contains values that cannot be saved.
+pub(crate) fn encode_env(env: &Environment) -> Option<Vec<u8>> {
+  let mut out = Writer::new();
+  pack(&mut out, |w| env.encode(w)).then_some(out.buf)
+}
+
+/// Serialize a copy of the function `f` called by a task. Returns `None` if it contains
+/// values that cannot be saved.
+pub(crate) fn encode_task(f: &LispVal) -> Option<Vec<u8>> {
+  let mut out = Writer::new();
+  pack(&mut out, |w| f.encode(w)).then_some(out.buf)
+}
+
+/// Read the environment and function written by [`encode_env`] and [`encode_task`].
+pub(crate) fn decode_task(env: &[u8], f: &[u8]) -> Option<(Environment, LispVal)> {
+  let env = unpack(&mut Reader::new(env), Environment::decode)?;
+  Some((env, unpack(&mut Reader::new(f), LispVal::decode)?))
+}
+
+/// Serialize the value `e` returned by a task. The atoms from index `base` onward were created
+/// by the task, and may have different indices in the environment that receives the result,
+/// so their names are saved as well. Returns `None` if `e` contains values that cannot be saved.
+pub(crate) fn encode_result(env: &Environment, base: usize, e: &LispVal) -> Option<Vec<u8>> {
+  let mut out = Writer::new();
+  let atoms = &env.data.0[base..];
+  out.usize(base);
+  out.usize(atoms.len());
+  for ad in atoms { out.bytes(&ad.name) }
+  pack(&mut out, |w| e.encode(w)).then_some(out.buf)
+}
+
+/// Read a value written by [`encode_result`], using `get_atom` to look up the atoms
+/// that were created by the task.
+pub(crate) fn decode_result(data: &[u8], mut get_atom: impl FnMut(&[u8]) -> AtomId) -> Option<LispVal> {
+  let mut r = Reader::new(data);
+  r.atom_base = r.u()?.try_into().ok()?;
+  for _ in 0..r.u()? {
+    let a = get_atom(r.bytes()?);
+    r.new_atoms.push(a)
+  }
+  unpack(&mut r, LispVal::decode)
+}
+
+/// The state of the cache file writer.
+struct Writer {
+  /// The output buffer.
+  buf: Vec<u8>,
+  /// False if we encountered something that cannot be saved.
+  ok: bool,
+  /// The table of files referred to by file spans.
+  files: Vec<FileRef>,
+  /// A map from files to their index in `files`.
+  file_idx: HashMap<FileRef, usize>,
+  /// A map from lisp values that have been written to their index, in the order they are
+  /// first visited. Later occurrences of the same value are written as back references.
+  lisp: HashMap<*const LispKind, usize>,
+  /// A map from shared lambda bodies that have been written to their index,
+  /// in the order they are completed.
+  ir: HashMap<*const Ir, usize>,
+  /// The targets of weak references, in the order they are written. Because the target may
+  /// not have been written yet, these are resolved at the end of the file.
+  weak: Vec<*const LispKind>,
+}
+
+impl Writer {
+  fn new() -> Self {
+    Self {
+      buf: vec![], ok: true, files: vec![], file_idx: HashMap::new(),
+      lisp: HashMap::new(), ir: HashMap::new(), weak: vec![],
+    }
+  }
+
+  fn u(&mut self, mut n: u64) {
+    loop {
+      #[allow(clippy::cast_possible_truncation)]
+      let b = (n & 0x7f) as u8;
+      n >>= 7;
+      if n == 0 { self.buf.push(b); return }
+      self.buf.push(b | 0x80)
+    }
+  }
+
+  fn usize(&mut self, n: usize) { self.u(n as u64) }
+
+  fn bytes(&mut self, s: &[u8]) {
+    self.usize(s.len());
+    self.buf.extend_from_slice(s)
+  }
+
+  fn path(&mut self, file: &FileRef) {
+    match file.path().to_str() {
+      Some(s) => self.bytes(s.as_bytes()),
+      None => self.ok = false,
+    }
+  }
+}
+
+/// The state of the cache file reader.
+struct Reader<'a> {
+  /// The remaining input.
+  data: &'a [u8],
+  /// The table of files referred to by file spans.
+  files: Vec<FileRef>,
+  /// The lisp values that have been read, by index.
+  lisp: Vec<LispVal>,
+  /// The shared lambda bodies that have been read, by index.
+  ir: Vec<Arc<Ir>>,
+  /// The mutable references which are weak references, in the order of the weak reference
+  /// table at the end of the file, which contains their targets.
+  weak: Vec<LispVal>,
+  /// Atoms with index `atom_base` or more are renamed to the corresponding element of
+  /// `new_atoms` (see [`decode_result`]).
+  atom_base: u32,
+  /// The new indices of the atoms from `atom_base` onward.
+  new_atoms: Vec<AtomId>,
+}
+
+impl<'a> Reader<'a> {
+  fn new(data: &'a [u8]) -> Self {
+    Self {
+      data, files: vec![], lisp: vec![], ir: vec![], weak: vec![],
+      atom_base: u32::MAX, new_atoms: vec![],
+    }
+  }
+
+  fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+    if self.data.len() < n { return None }
+    let (s, rest) = self.data.split_at(n);
+    self.data = rest;
+    Some(s)
+  }
+
+  fn u(&mut self) -> Option<u64> {
+    let mut n = 0;
+    for shift in (0..64).step_by(7) {
+      let (&b, rest) = self.data.split_first()?;
+      self.data = rest;
+      n |= u64::from(b & 0x7f) << shift;
+      if b & 0x80 == 0 { return Some(n) }
+    }
+    None
+  }
+
+  fn usize(&mut self) -> Option<usize> { self.u()?.try_into().ok() }
+
+  fn bytes(&mut self) -> Option<&'a [u8]> {
+    let n = self.usize()?;
+    self.take(n)
+  }
+
+  fn file_ref(&mut self) -> Option<FileRef> {
+    Some(PathBuf::from(std::str::from_utf8(self.bytes()?).ok()?).into())
+  }
+}
+
+/// A type that can be written to a cache file.
+trait Encode {
+  /// Write `self` to the cache file.
+  fn encode(&self, w: &mut Writer);
+}
+
+/// A type that can be read from a cache file. This returns `None` if the file is malformed.
+trait Decode: Sized {
+  /// Read a value from the cache file.
+  fn decode(r: &mut Reader<'_>) -> Option<Self>;
+}
+
+impl Encode for bool {
+  fn encode(&self, w: &mut Writer) { w.buf.push(u8::from(*self)) }
+}
+impl Decode for bool {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    match r.take(1)? { [0] => Some(false), [1] => Some(true), _ => None }
+  }
+}
+impl Encode for u8 {
+  fn encode(&self, w: &mut Writer) { w.buf.push(*self) }
+}
+impl Decode for u8 {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> { Some(r.take(1)?[0]) }
+}
+impl Encode for u32 {
+  fn encode(&self, w: &mut Writer) { w.u((*self).into()) }
+}
+impl Decode for u32 {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> { r.u()?.try_into().ok() }
+}
+impl Encode for u64 {
+  fn encode(&self, w: &mut Writer) { w.u(*self) }
+}
+impl Decode for u64 {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> { r.u() }
+}
+impl Encode for usize {
+  fn encode(&self, w: &mut Writer) { w.usize(*self) }
+}
+impl Decode for usize {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> { r.usize() }
+}
+impl<A: Encode> Encode for Option<A> {
+  fn encode(&self, w: &mut Writer) {
+    match self {
+      None => w.buf.push(0),
+      Some(a) => { w.buf.push(1); a.encode(w) }
+    }
+  }
+}
+impl<A: Decode> Decode for Option<A> {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    Some(if bool::decode(r)? { Some(A::decode(r)?) } else { None })
+  }
+}
+impl<A: Encode, B: Encode> Encode for (A, B) {
+  fn encode(&self, w: &mut Writer) { self.0.encode(w); self.1.encode(w) }
+}
+impl<A: Decode, B: Decode> Decode for (A, B) {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> { Some((A::decode(r)?, B::decode(r)?)) }
+}
+impl<A: Encode, B: Encode, C: Encode> Encode for (A, B, C) {
+  fn encode(&self, w: &mut Writer) { self.0.encode(w); self.1.encode(w); self.2.encode(w) }
+}
+impl<A: Decode, B: Decode, C: Decode> Decode for (A, B, C) {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    Some((A::decode(r)?, B::decode(r)?, C::decode(r)?))
+  }
+}
+impl<A: Encode> Encode for Box<A> {
+  fn encode(&self, w: &mut Writer) { (**self).encode(w) }
+}
+impl<A: Decode> Decode for Box<A> {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> { Some(Box::new(A::decode(r)?)) }
+}
+impl<A: Encode> Encode for Rc<A> {
+  fn encode(&self, w: &mut Writer) { (**self).encode(w) }
+}
+impl<A: Decode> Decode for Rc<A> {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> { Some(Rc::new(A::decode(r)?)) }
+}
+impl<A: Encode> Encode for [A] {
+  fn encode(&self, w: &mut Writer) {
+    w.usize(self.len());
+    for a in self { a.encode(w) }
+  }
+}
+impl<A: Encode> Encode for Box<[A]> {
+  fn encode(&self, w: &mut Writer) { (**self).encode(w) }
+}
+impl<A: Decode> Decode for Box<[A]> {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> { Some(Vec::decode(r)?.into()) }
+}
+impl<A: Encode> Encode for Vec<A> {
+  fn encode(&self, w: &mut Writer) { (**self).encode(w) }
+}
+impl<A: Decode> Decode for Vec<A> {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    (0..r.usize()?).map(|_| A::decode(r)).collect()
+  }
+}
+impl<K: Encode, V: Encode> Encode for HashMap<K, V> {
+  fn encode(&self, w: &mut Writer) {
+    w.usize(self.len());
+    for (k, v) in self { k.encode(w); v.encode(w) }
+  }
+}
+impl<K: Decode + Eq + Hash, V: Decode> Decode for HashMap<K, V> {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    (0..r.usize()?).map(|_| <(K, V)>::decode(r)).collect()
+  }
+}
+impl<K: Encode> Encode for HashSet<K> {
+  fn encode(&self, w: &mut Writer) {
+    w.usize(self.len());
+    for k in self { k.encode(w) }
+  }
+}
+impl<K: Decode + Eq + Hash> Decode for HashSet<K> {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    (0..r.usize()?).map(|_| K::decode(r)).collect()
+  }
+}
+
+macro_rules! encode_id {($($ty:ident: $inner:ty;)*) => {$(
+  impl Encode for $ty {
+    fn encode(&self, w: &mut Writer) { self.0.encode(w) }
+  }
+  impl Decode for $ty {
+    fn decode(r: &mut Reader<'_>) -> Option<Self> { Some($ty(<$inner>::decode(r)?)) }
+  }
+)*}}
+encode_id! { SortId: u8; TermId: u32; ThmId: u32; }
+
+impl Encode for AtomId {
+  fn encode(&self, w: &mut Writer) { self.0.encode(w) }
+}
+impl Decode for AtomId {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    let a = u32::decode(r)?;
+    Some(match a.checked_sub(r.atom_base) {
+      Some(i) => *r.new_atoms.get(usize::try_from(i).ok()?)?,
+      None => AtomId(a),
+    })
+  }
+}
+
+impl Encode for ArcString {
+  fn encode(&self, w: &mut Writer) { w.bytes(self) }
+}
+impl Decode for ArcString {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> { Some(r.bytes()?.into()) }
+}
+impl Encode for DocComment {
+  fn encode(&self, w: &mut Writer) { w.bytes(self.as_bytes()) }
+}
+impl Decode for DocComment {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> { Some(std::str::from_utf8(r.bytes()?).ok()?.into()) }
+}
+impl Encode for BigInt {
+  fn encode(&self, w: &mut Writer) { w.bytes(&self.to_signed_bytes_le()) }
+}
+impl Decode for BigInt {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> { Some(BigInt::from_signed_bytes_le(r.bytes()?)) }
+}
+impl Encode for Int {
+  fn encode(&self, w: &mut Writer) {
+    self.as_bigint().encode(w)
+  }
+}
+impl Decode for Int {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> { Some(BigInt::decode(r)?.into()) }
+}
+impl Encode for Span {
+  fn encode(&self, w: &mut Writer) { w.usize(self.start); w.usize(self.end) }
+}
+impl Decode for Span {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> { Some(Span {start: r.usize()?, end: r.usize()?}) }
+}
+impl Encode for FileRef {
+  fn encode(&self, w: &mut Writer) {
+    let i = if let Some(&i) = w.file_idx.get(self) {i} else {
+      w.files.push(self.clone());
+      w.file_idx.insert(self.clone(), w.files.len() - 1);
+      w.files.len() - 1
+    };
+    w.usize(i)
+  }
+}
+impl Decode for FileRef {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    let i = r.usize()?;
+    r.files.get(i).cloned()
+  }
+}
+impl Encode for FileSpan {
+  fn encode(&self, w: &mut Writer) { self.file.encode(w); self.span.encode(w) }
+}
+impl Decode for FileSpan {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    Some(FileSpan {file: FileRef::decode(r)?, span: Span::decode(r)?})
+  }
+}
+impl Encode for Modifiers {
+  fn encode(&self, w: &mut Writer) { self.bits().encode(w) }
+}
+impl Decode for Modifiers {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> { Modifiers::from_bits(u8::decode(r)?) }
+}
+impl Encode for Prec {
+  fn encode(&self, w: &mut Writer) {
+    match *self {
+      Prec::Prec(n) => w.u(u64::from(n) + 1),
+      Prec::Max => w.u(0),
+    }
+  }
+}
+impl Decode for Prec {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    Some(match r.u()? { 0 => Prec::Max, n => Prec::Prec((n - 1).try_into().ok()?) })
+  }
+}
+impl Encode for Delims {
+  fn encode(&self, w: &mut Writer) {
+    for i in 0..32_u8 {
+      w.buf.push((0..8).fold(0, |b, j| b | u8::from(self.get(i << 3 | j)) << j))
+    }
+  }
+}
+impl Decode for Delims {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    let mut d = Delims::default();
+    for (i, &b) in (0..32_u8).zip(r.take(32)?) {
+      for j in 0..8 { if b & (1 << j) != 0 { d.set(i << 3 | j) } }
+    }
+    Some(d)
+  }
+}
+impl Encode for RenderFormat {
+  fn encode(&self, w: &mut Writer) {
+    w.buf.push(match self { RenderFormat::Latex => 0, RenderFormat::Html => 1 })
+  }
+}
+impl Decode for RenderFormat {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    match u8::decode(r)? { 0 => Some(RenderFormat::Latex), 1 => Some(RenderFormat::Html), _ => None }
+  }
+}
+
+impl Encode for Type {
+  fn encode(&self, w: &mut Writer) {
+    match *self {
+      Type::Bound(s) => { w.buf.push(0); s.encode(w) }
+      Type::Reg(s, deps) => { w.buf.push(1); s.encode(w); w.u(deps) }
+    }
+  }
+}
+impl Decode for Type {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    match u8::decode(r)? {
+      0 => Some(Type::Bound(SortId::decode(r)?)),
+      1 => Some(Type::Reg(SortId::decode(r)?, r.u()?)),
+      _ => None
+    }
+  }
+}
+impl Encode for ExprNode {
+  fn encode(&self, w: &mut Writer) {
+    match self {
+      &ExprNode::Ref(i) => { w.buf.push(0); w.usize(i) }
+      &ExprNode::Dummy(a, s) => { w.buf.push(1); a.encode(w); s.encode(w) }
+      ExprNode::App(t, es) => { w.buf.push(2); t.encode(w); es.encode(w) }
+    }
+  }
+}
+impl Decode for ExprNode {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    match u8::decode(r)? {
+      0 => Some(ExprNode::Ref(r.usize()?)),
+      1 => Some(ExprNode::Dummy(AtomId::decode(r)?, SortId::decode(r)?)),
+      2 => Some(ExprNode::App(TermId::decode(r)?, Decode::decode(r)?)),
+      _ => None
+    }
+  }
+}
+impl Encode for Expr {
+  fn encode(&self, w: &mut Writer) { self.heap.encode(w); self.head.encode(w) }
+}
+impl Decode for Expr {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    Some(Expr {heap: Decode::decode(r)?, head: Decode::decode(r)?})
+  }
+}
+impl Encode for ProofNode {
+  fn encode(&self, w: &mut Writer) {
+    match self {
+      &ProofNode::Ref(i) => { w.buf.push(0); w.usize(i) }
+      &ProofNode::Dummy(a, s) => { w.buf.push(1); a.encode(w); s.encode(w) }
+      ProofNode::Term {term, args} => { w.buf.push(2); term.encode(w); args.encode(w) }
+      &ProofNode::Hyp(i, ref e) => { w.buf.push(3); w.usize(i); e.encode(w) }
+      ProofNode::Thm {thm, args, res} => { w.buf.push(4); thm.encode(w); args.encode(w); res.encode(w) }
+      ProofNode::Conv(p) => { w.buf.push(5); p.encode(w) }
+      ProofNode::Refl(p) => { w.buf.push(6); p.encode(w) }
+      ProofNode::Sym(p) => { w.buf.push(7); p.encode(w) }
+      ProofNode::Cong {term, args} => { w.buf.push(8); term.encode(w); args.encode(w) }
+      ProofNode::Unfold {term, args, res} => {
+        w.buf.push(9); term.encode(w); args.encode(w); res.encode(w)
+      }
+    }
+  }
+}
+impl Decode for ProofNode {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    Some(match u8::decode(r)? {
+      0 => ProofNode::Ref(r.usize()?),
+      1 => ProofNode::Dummy(Decode::decode(r)?, Decode::decode(r)?),
+      2 => ProofNode::Term {term: Decode::decode(r)?, args: Decode::decode(r)?},
+      3 => ProofNode::Hyp(r.usize()?, Decode::decode(r)?),
+      4 => ProofNode::Thm {thm: Decode::decode(r)?, args: Decode::decode(r)?, res: Decode::decode(r)?},
+      5 => ProofNode::Conv(Decode::decode(r)?),
+      6 => ProofNode::Refl(Decode::decode(r)?),
+      7 => ProofNode::Sym(Decode::decode(r)?),
+      8 => ProofNode::Cong {term: Decode::decode(r)?, args: Decode::decode(r)?},
+      9 => ProofNode::Unfold {term: Decode::decode(r)?, args: Decode::decode(r)?, res: Decode::decode(r)?},
+      _ => return None
+    })
+  }
+}
+impl Encode for Proof {
+  fn encode(&self, w: &mut Writer) {
+    self.heap.encode(w); self.hyps.encode(w); self.head.encode(w); self.spans.encode(w)
+  }
+}
+impl Decode for Proof {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    Some(Proof {
+      heap: Decode::decode(r)?, hyps: Decode::decode(r)?,
+      head: Decode::decode(r)?, spans: Decode::decode(r)?
+    })
+  }
+}
+
+impl Encode for Sort {
+  fn encode(&self, w: &mut Writer) {
+    self.atom.encode(w); self.name.encode(w); self.span.encode(w);
+    self.full.encode(w); self.doc.encode(w); self.mods.encode(w)
+  }
+}
+impl Decode for Sort {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    Some(Sort {
+      atom: Decode::decode(r)?, name: Decode::decode(r)?, span: Decode::decode(r)?,
+      full: Decode::decode(r)?, doc: Decode::decode(r)?, mods: Decode::decode(r)?,
+    })
+  }
+}
+impl Encode for Term {
+  fn encode(&self, w: &mut Writer) {
+    self.atom.encode(w); self.span.encode(w); self.vis.encode(w); self.full.encode(w);
+    self.doc.encode(w); self.args.encode(w); self.ret.encode(w);
+    match &self.kind {
+      TermKind::Term => w.buf.push(0),
+      TermKind::Def(e) => { w.buf.push(1); e.encode(w) }
+    }
+  }
+}
+impl Decode for Term {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    Some(Term {
+      atom: Decode::decode(r)?, span: Decode::decode(r)?, vis: Decode::decode(r)?,
+      full: Decode::decode(r)?, doc: Decode::decode(r)?, args: Decode::decode(r)?,
+      ret: Decode::decode(r)?,
+      kind: match u8::decode(r)? {
+        0 => TermKind::Term,
+        1 => TermKind::Def(Decode::decode(r)?),
+        _ => return None
+      },
+    })
+  }
+}
+impl Encode for Thm {
+  fn encode(&self, w: &mut Writer) {
+    self.atom.encode(w); self.span.encode(w); self.vis.encode(w); self.full.encode(w);
+    self.doc.encode(w); self.args.encode(w); self.heap.encode(w); self.hyps.encode(w);
+    self.ret.encode(w);
+    match &self.kind {
+      ThmKind::Axiom => w.buf.push(0),
+      ThmKind::Thm(p) => { w.buf.push(1); p.encode(w) }
+    }
+  }
+}
+impl Decode for Thm {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    Some(Thm {
+      atom: Decode::decode(r)?, span: Decode::decode(r)?, vis: Decode::decode(r)?,
+      full: Decode::decode(r)?, doc: Decode::decode(r)?, args: Decode::decode(r)?,
+      heap: Decode::decode(r)?, hyps: Decode::decode(r)?, ret: Decode::decode(r)?,
+      kind: match u8::decode(r)? {
+        0 => ThmKind::Axiom,
+        1 => ThmKind::Thm(Decode::decode(r)?),
+        _ => return None
+      },
+    })
+  }
+}
+impl Encode for StmtTrace {
+  fn encode(&self, w: &mut Writer) {
+    match self {
+      StmtTrace::Sort(a) => { w.buf.push(0); a.encode(w) }
+      StmtTrace::Decl(a) => { w.buf.push(1); a.encode(w) }
+      StmtTrace::Global(a) => { w.buf.push(2); a.encode(w) }
+      StmtTrace::OutputString(s) => {
+        w.buf.push(3); s.span.encode(w); s.heap.encode(w); s.exprs.encode(w)
+      }
+    }
+  }
+}
+impl Decode for StmtTrace {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    Some(match u8::decode(r)? {
+      0 => StmtTrace::Sort(Decode::decode(r)?),
+      1 => StmtTrace::Decl(Decode::decode(r)?),
+      2 => StmtTrace::Global(Decode::decode(r)?),
+      3 => StmtTrace::OutputString(Box::new(OutputString {
+        span: Decode::decode(r)?, heap: Decode::decode(r)?, exprs: Decode::decode(r)?,
+      })),
+      _ => return None
+    })
+  }
+}
+impl Encode for DeclKey {
+  fn encode(&self, w: &mut Writer) {
+    match *self {
+      DeclKey::Term(t) => { w.buf.push(0); t.encode(w) }
+      DeclKey::Thm(t) => { w.buf.push(1); t.encode(w) }
+    }
+  }
+}
+impl Decode for DeclKey {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    match u8::decode(r)? {
+      0 => Some(DeclKey::Term(Decode::decode(r)?)),
+      1 => Some(DeclKey::Thm(Decode::decode(r)?)),
+      _ => None
+    }
+  }
+}
+
+impl Encode for Literal {
+  fn encode(&self, w: &mut Writer) {
+    match self {
+      &Literal::Var(i, p) => { w.buf.push(0); w.usize(i); p.encode(w) }
+      Literal::Const(c) => { w.buf.push(1); c.encode(w) }
+    }
+  }
+}
+impl Decode for Literal {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    match u8::decode(r)? {
+      0 => Some(Literal::Var(r.usize()?, Decode::decode(r)?)),
+      1 => Some(Literal::Const(Decode::decode(r)?)),
+      _ => None
+    }
+  }
+}
+impl Encode for NotaInfo {
+  fn encode(&self, w: &mut Writer) {
+    self.span.encode(w); self.term.encode(w); w.usize(self.nargs);
+    self.rassoc.encode(w); self.lits.encode(w); self.local.encode(w)
+  }
+}
+impl Decode for NotaInfo {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    Some(NotaInfo {
+      span: Decode::decode(r)?, term: Decode::decode(r)?, nargs: r.usize()?,
+      rassoc: Decode::decode(r)?, lits: Decode::decode(r)?, local: Decode::decode(r)?,
+    })
+  }
+}
+impl Encode for Deprecation {
+  fn encode(&self, w: &mut Writer) { self.replacement.encode(w); self.msg.encode(w) }
+}
+impl Decode for Deprecation {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    Some(Deprecation {replacement: Decode::decode(r)?, msg: Decode::decode(r)?})
+  }
+}
+impl Encode for Arc<Coe> {
+  fn encode(&self, w: &mut Writer) {
+    match &**self {
+      Coe::One(fsp, t) => { w.buf.push(0); fsp.encode(w); t.encode(w) }
+      Coe::Trans(c1, s, c2) => { w.buf.push(1); c1.encode(w); s.encode(w); c2.encode(w) }
+    }
+  }
+}
+impl Decode for Arc<Coe> {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    Some(Arc::new(match u8::decode(r)? {
+      0 => Coe::One(Decode::decode(r)?, Decode::decode(r)?),
+      1 => Coe::Trans(Decode::decode(r)?, Decode::decode(r)?, Decode::decode(r)?),
+      _ => return None
+    }))
+  }
+}
+impl Encode for ParserEnv {
+  fn encode(&self, w: &mut Writer) {
+    self.delims_l.encode(w); self.delims_r.encode(w); self.consts.encode(w);
+    self.prec_assoc.encode(w); self.prefixes.encode(w); self.infixes.encode(w);
+    self.coes.encode(w); self.coe_prov.encode(w); self.decl_nota.encode(w);
+    self.templates.encode(w)
+  }
+}
+impl Decode for ParserEnv {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    Some(ParserEnv {
+      delims_l: Decode::decode(r)?, delims_r: Decode::decode(r)?, consts: Decode::decode(r)?,
+      prec_assoc: Decode::decode(r)?, prefixes: Decode::decode(r)?, infixes: Decode::decode(r)?,
+      coes: Decode::decode(r)?, coe_prov: Decode::decode(r)?, decl_nota: Decode::decode(r)?,
+      templates: Decode::decode(r)?,
+    })
+  }
+}
+
+impl Encode for MergeStrategyInner {
+  fn encode(&self, w: &mut Writer) {
+    match self {
+      MergeStrategyInner::AtomMap(m) => { w.buf.push(0); m.encode(w) }
+      MergeStrategyInner::Custom(f) => { w.buf.push(1); f.encode(w) }
+    }
+  }
+}
+impl Decode for MergeStrategyInner {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    match u8::decode(r)? {
+      0 => Some(MergeStrategyInner::AtomMap(Decode::decode(r)?)),
+      1 => Some(MergeStrategyInner::Custom(Decode::decode(r)?)),
+      _ => None
+    }
+  }
+}
+impl Encode for LispData {
+  fn encode(&self, w: &mut Writer) {
+    self.src.encode(w); self.doc.encode(w); self.val.encode(w); self.merge.encode(w)
+  }
+}
+impl Decode for LispData {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    Some(LispData {
+      src: Decode::decode(r)?, doc: Decode::decode(r)?,
+      val: Decode::decode(r)?, merge: Decode::decode(r)?,
+    })
+  }
+}
+impl Encode for AtomData {
+  fn encode(&self, w: &mut Writer) {
+    self.name.encode(w); self.lisp.encode(w); self.graveyard.encode(w);
+    self.sort.encode(w); self.decl.encode(w)
+  }
+}
+impl Decode for AtomData {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    Some(AtomData {
+      name: Decode::decode(r)?, lisp: Decode::decode(r)?, graveyard: Decode::decode(r)?,
+      sort: Decode::decode(r)?, decl: Decode::decode(r)?,
+    })
+  }
+}
+impl Encode for Environment {
+  fn encode(&self, w: &mut Writer) {
+    self.sorts.0.encode(w); self.pe.encode(w); self.terms.0.encode(w);
+    self.thms.0.encode(w); self.data.0.encode(w); self.stmts.encode(w);
+    self.simps.encode(w); self.instances.encode(w); self.deprecated.encode(w);
+    self.hidden.encode(w); self.aliases.encode(w)
+  }
+}
+impl Decode for Environment {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    let sorts = SortVec(Decode::decode(r)?);
+    let pe = Decode::decode(r)?;
+    let terms = TermVec(Decode::decode(r)?);
+    let thms = ThmVec(Decode::decode(r)?);
+    let data = AtomVec::<AtomData>(Decode::decode(r)?);
+    let stmts = Decode::decode(r)?;
+    let simps = Decode::decode(r)?;
+    let instances = Decode::decode(r)?;
+    let deprecated = Decode::decode(r)?;
+    let hidden = Decode::decode(r)?;
+    let aliases = Decode::decode(r)?;
+    let atoms = data.enum_iter().map(|(a, d)| (d.name.clone(), a)).collect();
+    Some(Environment {
+      sorts, pe, terms, thms, atoms, data, stmts, simps, instances, deprecated,
+      hidden, aliases,
+      spans: vec![],
+      rev_deps: Default::default(),
+      snapshots: Default::default(),
+      profile: Default::default(),
+    })
+  }
+}
+
+impl Encode for Syntax {
+  fn encode(&self, w: &mut Writer) { w.bytes(self.to_byte_str()) }
+}
+impl Decode for Syntax {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> { Syntax::from_bytes(r.bytes()?) }
+}
+impl Encode for BuiltinProc {
+  fn encode(&self, w: &mut Writer) { w.bytes(self.to_byte_str()) }
+}
+impl Decode for BuiltinProc {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> { BuiltinProc::from_bytes(r.bytes()?) }
+}
+impl Encode for InferTarget {
+  fn encode(&self, w: &mut Writer) {
+    match *self {
+      InferTarget::Unknown => w.buf.push(0),
+      InferTarget::Provable => w.buf.push(1),
+      InferTarget::Bound(a) => { w.buf.push(2); a.encode(w) }
+      InferTarget::Reg(a) => { w.buf.push(3); a.encode(w) }
+    }
+  }
+}
+impl Decode for InferTarget {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    Some(match u8::decode(r)? {
+      0 => InferTarget::Unknown,
+      1 => InferTarget::Provable,
+      2 => InferTarget::Bound(Decode::decode(r)?),
+      3 => InferTarget::Reg(Decode::decode(r)?),
+      _ => return None
+    })
+  }
+}
+impl Encode for HashKey {
+  fn encode(&self, w: &mut Writer) {
+    match self {
+      HashKey::Atom(a) => { w.buf.push(0); a.encode(w) }
+      HashKey::Number(n) => { w.buf.push(1); n.encode(w) }
+      HashKey::String(s) => { w.buf.push(2); s.encode(w) }
+      HashKey::Bool(b) => { w.buf.push(3); b.encode(w) }
+      HashKey::List(es) => { w.buf.push(4); es.encode(w) }
+      HashKey::DottedList(es, e) => { w.buf.push(5); es.encode(w); e.encode(w) }
+      HashKey::Rational(q) => { w.buf.push(6); q.numer().encode(w); q.denom().encode(w) }
+    }
+  }
+}
+impl Decode for HashKey {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    Some(match u8::decode(r)? {
+      0 => HashKey::Atom(Decode::decode(r)?),
+      1 => HashKey::Number(Decode::decode(r)?),
+      2 => HashKey::String(Decode::decode(r)?),
+      3 => HashKey::Bool(Decode::decode(r)?),
+      4 => HashKey::List(Decode::decode(r)?),
+      5 => HashKey::DottedList(Decode::decode(r)?, Decode::decode(r)?),
+      6 => HashKey::Rational(BigRational::new(Decode::decode(r)?, Decode::decode(r)?)),
+      _ => return None
+    })
+  }
+}
+impl Encode for ProcPos {
+  fn encode(&self, w: &mut Writer) {
+    match self {
+      ProcPos::Named(fsp, sp, a) => { w.buf.push(0); fsp.encode(w); sp.encode(w); a.encode(w) }
+      ProcPos::Unnamed(fsp) => { w.buf.push(1); fsp.encode(w) }
+    }
+  }
+}
+impl Decode for ProcPos {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    match u8::decode(r)? {
+      0 => Some(ProcPos::Named(Decode::decode(r)?, Decode::decode(r)?, Decode::decode(r)?)),
+      1 => Some(ProcPos::Unnamed(Decode::decode(r)?)),
+      _ => None
+    }
+  }
+}
+impl Encode for ProcSpec {
+  fn encode(&self, w: &mut Writer) {
+    match *self {
+      ProcSpec::Exact(n) => { w.buf.push(0); w.usize(n) }
+      ProcSpec::AtLeast(n) => { w.buf.push(1); w.usize(n) }
+    }
+  }
+}
+impl Decode for ProcSpec {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    match u8::decode(r)? {
+      0 => Some(ProcSpec::Exact(r.usize()?)),
+      1 => Some(ProcSpec::AtLeast(r.usize()?)),
+      _ => None
+    }
+  }
+}
+impl Encode for ArgKind {
+  fn encode(&self, w: &mut Writer) {
+    match *self {
+      ArgKind::Pos(keys) => { w.buf.push(0); keys.encode(w) }
+      ArgKind::Key(a) => { w.buf.push(1); a.encode(w) }
+    }
+  }
+}
+impl Decode for ArgKind {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    match u8::decode(r)? {
+      0 => Some(ArgKind::Pos(Decode::decode(r)?)),
+      1 => Some(ArgKind::Key(Decode::decode(r)?)),
+      _ => None
+    }
+  }
+}
+impl Encode for Proc {
+  fn encode(&self, w: &mut Writer) {
+    match self {
+      Proc::Builtin(p) => { w.buf.push(0); p.encode(w) }
+      Proc::Lambda {pos, env, spec, doc, code} => {
+        w.buf.push(1); pos.encode(w); env.encode(w); spec.encode(w); doc.encode(w); code.encode(w)
+      }
+      // A match continuation is only valid during the match that created it,
+      // so it is saved as an invalid continuation.
+      Proc::MatchCont(_) => w.buf.push(2),
+      Proc::DelimCont(..) => w.buf.push(6),
+      Proc::RefineCallback => w.buf.push(3),
+      Proc::MergeMap(m) => { w.buf.push(4); m.encode(w) }
+      Proc::Macro(e) => { w.buf.push(7); e.encode(w) }
+      Proc::ProofThunk(a, m) => {
+        w.buf.push(5); a.encode(w);
+        match &*m.borrow() {
+          Ok(e) => { w.buf.push(0); e.encode(w) }
+          Err(args) => { w.buf.push(1); args.encode(w) }
+        }
+      }
+      Proc::Future(fut) => {
+        w.buf.push(8);
+        match &*fut.borrow() {
+          LispFuture::Done(Ok(e)) => { w.buf.push(0); e.encode(w) }
+          _ => w.buf.push(1),
+        }
+      }
+      #[cfg(feature = "mmc")]
+      Proc::MmcCompiler(_) => { w.ok = false; w.buf.push(3) }
+    }
+  }
+}
+impl Decode for Proc {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    Some(match u8::decode(r)? {
+      0 => Proc::Builtin(Decode::decode(r)?),
+      1 => Proc::Lambda {
+        pos: Decode::decode(r)?, env: Decode::decode(r)?,
+        spec: Decode::decode(r)?, doc: Decode::decode(r)?, code: Decode::decode(r)?,
+      },
+      2 => Proc::MatchCont(Rc::new(Cell::new(false))),
+      3 => Proc::RefineCallback,
+      4 => Proc::MergeMap(Decode::decode(r)?),
+      5 => Proc::ProofThunk(Decode::decode(r)?, RefCell::new(match u8::decode(r)? {
+        0 => Ok(Decode::decode(r)?),
+        1 => Err(Decode::decode(r)?),
+        _ => return None
+      })),
+      6 => Proc::DelimCont(Rc::new(Cell::new(false)), 0),
+      7 => Proc::Macro(Decode::decode(r)?),
+      8 => Proc::Future(RefCell::new(LispFuture::Done(match u8::decode(r)? {
+        0 => Ok(Decode::decode(r)?),
+        1 => Err(ElabError::new_e(Span::default(),
+          "await: the result of this task is not available here")),
+        _ => return None
+      }))),
+      _ => return None
+    })
+  }
+}
+
+/// Lisp values are written with sharing, so that each object is written once and later
+/// references to it are written as its index in the order the objects are first visited.
+/// A mutable reference gets its index before its contents are written, so cycles through
+/// references are preserved.
+impl Encode for LispVal {
+  fn encode(&self, w: &mut Writer) {
+    let ptr: *const LispKind = &**self;
+    if let Some(&i) = w.lisp.get(&ptr) { w.buf.push(0); w.usize(i); return }
+    let n = w.lisp.len();
+    w.lisp.insert(ptr, n);
+    match &**self {
+      LispKind::Atom(a) => { w.buf.push(1); a.encode(w) }
+      LispKind::List(es) => { w.buf.push(2); es.encode(w) }
+      LispKind::DottedList(es, e) => { w.buf.push(3); es.encode(w); e.encode(w) }
+      LispKind::Annot(Annot::Span(fsp), e) => { w.buf.push(4); fsp.encode(w); e.encode(w) }
+      LispKind::Number(n) => { w.buf.push(5); n.encode(w) }
+      LispKind::String(s) => { w.buf.push(6); s.encode(w) }
+      LispKind::Bool(b) => { w.buf.push(7); b.encode(w) }
+      LispKind::Syntax(s) => { w.buf.push(8); s.encode(w) }
+      LispKind::Undef => w.buf.push(9),
+      LispKind::Proc(p) => { w.buf.push(10); p.encode(w) }
+      LispKind::AtomMap(m) => { w.buf.push(11); m.encode(w) }
+      LispKind::Ref(m) => {
+        w.buf.push(12);
+        match &*m.get_weak() {
+          LispWeak::Strong(e) => { w.buf.push(0); e.encode(w) }
+          LispWeak::Weak(e) if e.strong_count() == 0 => w.buf.push(1),
+          LispWeak::Weak(e) => { w.buf.push(2); w.weak.push(e.as_ptr()) }
+        }
+      }
+      &LispKind::MVar(n, tgt) => { w.buf.push(13); w.usize(n); tgt.encode(w) }
+      LispKind::Goal(e) => { w.buf.push(14); e.encode(w) }
+      LispKind::HashTable(m) => { w.buf.push(15); m.encode(w) }
+      LispKind::Vector(v) => { w.buf.push(16); v.borrow().encode(w) }
+      LispKind::Rational(q) => { w.buf.push(17); q.numer().encode(w); q.denom().encode(w) }
+      LispKind::StringPort(s) => { w.buf.push(18); s.borrow().encode(w) }
+    }
+  }
+}
+impl Decode for LispVal {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    let tag = u8::decode(r)?;
+    if tag == 0 {
+      let i = r.usize()?;
+      return r.lisp.get(i).cloned()
+    }
+    let n = r.lisp.len();
+    r.lisp.push(LispVal::undef());
+    let e = match tag {
+      1 => LispVal::atom(Decode::decode(r)?),
+      2 => LispVal::list(<Box<[_]>>::decode(r)?),
+      3 => LispVal::dotted_list(<Box<[_]>>::decode(r)?, Decode::decode(r)?),
+      4 => LispVal::new(LispKind::Annot(Annot::Span(Decode::decode(r)?), Decode::decode(r)?)),
+      5 => LispVal::number(Int::decode(r)?),
+      6 => LispVal::string(Decode::decode(r)?),
+      7 => LispVal::bool(Decode::decode(r)?),
+      8 => LispVal::syntax(Decode::decode(r)?),
+      9 => LispVal::undef(),
+      10 => LispVal::proc(Decode::decode(r)?),
+      11 => LispVal::new(LispKind::AtomMap(Decode::decode(r)?)),
+      12 => {
+        let e = LispVal::new_ref(LispVal::undef());
+        r.lisp[n] = e.clone();
+        match u8::decode(r)? {
+          0 => {
+            let v = Decode::decode(r)?;
+            e.as_lref(|m| *m.get_mut_weak() = LispWeak::Strong(v))?
+          }
+          1 => e.as_lref(|m| *m.get_mut_weak() = LispWeak::Weak(Weak::new()))?,
+          2 => r.weak.push(e.clone()),
+          _ => return None
+        }
+        e
+      }
+      13 => LispVal::new(LispKind::MVar(r.usize()?, Decode::decode(r)?)),
+      14 => LispVal::new(LispKind::Goal(Decode::decode(r)?)),
+      15 => LispVal::new(LispKind::HashTable(Decode::decode(r)?)),
+      16 => {
+        let e = LispVal::vector(vec![]);
+        r.lisp[n] = e.clone();
+        let es = Decode::decode(r)?;
+        if let LispKind::Vector(v) = &*e {*v.borrow_mut() = es}
+        e
+      }
+      17 => LispVal::rational(BigRational::new(Decode::decode(r)?, Decode::decode(r)?)),
+      18 => LispVal::new(LispKind::StringPort(RefCell::new(Decode::decode(r)?))),
+      _ => return None
+    };
+    r.lisp[n] = e.clone();
+    Some(e)
+  }
+}
+
+/// Lambda bodies are shared between the closures created from the same code,
+/// so these are also written with sharing.
+impl Encode for Arc<Ir> {
+  fn encode(&self, w: &mut Writer) {
+    let ptr: *const Ir = &**self;
+    if let Some(&i) = w.ir.get(&ptr) { w.buf.push(0); w.usize(i); return }
+    w.buf.push(1);
+    (**self).encode(w);
+    let n = w.ir.len();
+    w.ir.insert(ptr, n);
+  }
+}
+impl Decode for Arc<Ir> {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    if !bool::decode(r)? {
+      let i = r.usize()?;
+      return r.ir.get(i).cloned()
+    }
+    let ir = Arc::new(Ir::decode(r)?);
+    r.ir.push(ir.clone());
+    Some(ir)
+  }
+}
+impl Encode for Ir {
+  fn encode(&self, w: &mut Writer) {
+    match self {
+      &Ir::Local(i) => { w.buf.push(0); w.usize(i) }
+      &Ir::Global(sp, a) => { w.buf.push(1); sp.encode(w); a.encode(w) }
+      Ir::Const(e) => { w.buf.push(2); e.encode(w) }
+      Ir::List(sp, es) => { w.buf.push(3); sp.encode(w); es.encode(w) }
+      Ir::DottedList(es, e) => { w.buf.push(4); es.encode(w); e.encode(w) }
+      Ir::App(sp1, sp2, f, es) => { w.buf.push(5); sp1.encode(w); sp2.encode(w); f.encode(w); es.encode(w) }
+      Ir::If(e) => { w.buf.push(6); e.encode(w) }
+      Ir::Focus(sp, es) => { w.buf.push(7); sp.encode(w); es.encode(w) }
+      Ir::SetMergeStrategy(sp, a, e) => { w.buf.push(8); sp.encode(w); a.encode(w); e.encode(w) }
+      Ir::Def(n, tgt, e) => {
+        w.buf.push(9); w.usize(*n);
+        match tgt {
+          None => w.buf.push(0),
+          Some((sp1, sp2, doc, a)) => {
+            w.buf.push(1); sp1.encode(w); sp2.encode(w); doc.encode(w); a.encode(w)
+          }
+        }
+        e.encode(w)
+      }
+      Ir::Eval(keep, es) => { w.buf.push(10); keep.encode(w); es.encode(w) }
+      Ir::NoTailRec => w.buf.push(11),
+      Ir::Lambda(sp, n, spec, doc, e) => {
+        w.buf.push(12); sp.encode(w); w.usize(*n); spec.encode(w); doc.encode(w); e.encode(w)
+      }
+      Ir::Match(sp, e, brs) => { w.buf.push(13); sp.encode(w); e.encode(w); brs.encode(w) }
+      Ir::Try(sp, e) => { w.buf.push(14); sp.encode(w); e.encode(w) }
+      Ir::Reset(sp, e) => { w.buf.push(15); sp.encode(w); e.encode(w) }
+      Ir::Shift(sp, n, e) => { w.buf.push(16); sp.encode(w); w.usize(*n); e.encode(w) }
+      Ir::Arg(sp, n, k, e) => { w.buf.push(17); sp.encode(w); w.usize(*n); k.encode(w); e.encode(w) }
+      Ir::ArgsEnd(sp, n) => { w.buf.push(18); sp.encode(w); w.usize(*n) }
+    }
+  }
+}
+impl Decode for Ir {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    Some(match u8::decode(r)? {
+      0 => Ir::Local(r.usize()?),
+      1 => Ir::Global(Decode::decode(r)?, Decode::decode(r)?),
+      2 => Ir::Const(Decode::decode(r)?),
+      3 => Ir::List(Decode::decode(r)?, Decode::decode(r)?),
+      4 => Ir::DottedList(Decode::decode(r)?, Decode::decode(r)?),
+      5 => Ir::App(Decode::decode(r)?, Decode::decode(r)?, Decode::decode(r)?, Decode::decode(r)?),
+      6 => Ir::If(Decode::decode(r)?),
+      7 => Ir::Focus(Decode::decode(r)?, Decode::decode(r)?),
+      8 => Ir::SetMergeStrategy(Decode::decode(r)?, Decode::decode(r)?, Decode::decode(r)?),
+      9 => {
+        let n = r.usize()?;
+        let tgt = match u8::decode(r)? {
+          0 => None,
+          1 => Some((Decode::decode(r)?, Decode::decode(r)?, Decode::decode(r)?, Decode::decode(r)?)),
+          _ => return None
+        };
+        Ir::Def(n, tgt, Decode::decode(r)?)
+      }
+      10 => Ir::Eval(Decode::decode(r)?, Decode::decode(r)?),
+      11 => Ir::NoTailRec,
+      12 => Ir::Lambda(Decode::decode(r)?, r.usize()?,
+        Decode::decode(r)?, Decode::decode(r)?, Decode::decode(r)?),
+      13 => Ir::Match(Decode::decode(r)?, Decode::decode(r)?, Decode::decode(r)?),
+      14 => Ir::Try(Decode::decode(r)?, Decode::decode(r)?),
+      15 => Ir::Reset(Decode::decode(r)?, Decode::decode(r)?),
+      16 => Ir::Shift(Decode::decode(r)?, r.usize()?, Decode::decode(r)?),
+      17 => Ir::Arg(Decode::decode(r)?, r.usize()?, Decode::decode(r)?, Decode::decode(r)?),
+      18 => Ir::ArgsEnd(Decode::decode(r)?, r.usize()?),
+      _ => return None
+    })
+  }
+}
+impl Encode for Branch {
+  fn encode(&self, w: &mut Writer) {
+    w.usize(self.vars); self.cont.encode(w); self.pat.encode(w); self.eval.encode(w)
+  }
+}
+impl Decode for Branch {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    Some(Branch {
+      vars: r.usize()?, cont: Decode::decode(r)?,
+      pat: Decode::decode(r)?, eval: Decode::decode(r)?,
+    })
+  }
+}
+impl Encode for Pattern {
+  fn encode(&self, w: &mut Writer) {
+    match self {
+      Pattern::Skip => w.buf.push(0),
+      &Pattern::Atom(i) => { w.buf.push(1); w.usize(i) }
+      Pattern::QuoteAtom(a) => { w.buf.push(2); a.encode(w) }
+      Pattern::String(s) => { w.buf.push(3); s.encode(w) }
+      Pattern::Bool(b) => { w.buf.push(4); b.encode(w) }
+      Pattern::Undef => w.buf.push(5),
+      Pattern::Number(n) => { w.buf.push(6); n.encode(w) }
+      Pattern::MVar(MVarPattern::Unknown) => w.buf.push(7),
+      Pattern::MVar(MVarPattern::Any) => w.buf.push(8),
+      Pattern::MVar(MVarPattern::Simple(p)) => { w.buf.push(9); p.encode(w) }
+      Pattern::Goal(p) => { w.buf.push(10); p.encode(w) }
+      Pattern::DottedList(ps, p) => { w.buf.push(11); ps.encode(w); p.encode(w) }
+      Pattern::List(ps, n) => { w.buf.push(12); ps.encode(w); n.encode(w) }
+      Pattern::And(ps) => { w.buf.push(13); ps.encode(w) }
+      Pattern::Or(ps) => { w.buf.push(14); ps.encode(w) }
+      Pattern::Not(ps) => { w.buf.push(15); ps.encode(w) }
+      Pattern::Test(sp, f, ps) => { w.buf.push(16); sp.encode(w); f.encode(w); ps.encode(w) }
+      Pattern::QExprAtom(a) => { w.buf.push(17); a.encode(w) }
+      Pattern::Guard(p, ir) => { w.buf.push(18); p.encode(w); ir.encode(w) }
+    }
+  }
+}
+impl Decode for Pattern {
+  fn decode(r: &mut Reader<'_>) -> Option<Self> {
+    Some(match u8::decode(r)? {
+      0 => Pattern::Skip,
+      1 => Pattern::Atom(r.usize()?),
+      2 => Pattern::QuoteAtom(Decode::decode(r)?),
+      3 => Pattern::String(Decode::decode(r)?),
+      4 => Pattern::Bool(Decode::decode(r)?),
+      5 => Pattern::Undef,
+      6 => Pattern::Number(Decode::decode(r)?),
+      7 => Pattern::MVar(MVarPattern::Unknown),
+      8 => Pattern::MVar(MVarPattern::Any),
+      9 => Pattern::MVar(MVarPattern::Simple(Decode::decode(r)?)),
+      10 => Pattern::Goal(Decode::decode(r)?),
+      11 => Pattern::DottedList(Decode::decode(r)?, Decode::decode(r)?),
+      12 => Pattern::List(Decode::decode(r)?, Decode::decode(r)?),
+      13 => Pattern::And(Decode::decode(r)?),
+      14 => Pattern::Or(Decode::decode(r)?),
+      15 => Pattern::Not(Decode::decode(r)?),
+      16 => Pattern::Test(Decode::decode(r)?, Decode::decode(r)?, Decode::decode(r)?),
+      17 => Pattern::QExprAtom(Decode::decode(r)?),
+      18 => Pattern::Guard(Decode::decode(r)?, Decode::decode(r)?),
+      _ => return None
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Cursor;
+  use super::*;
+  use crate::mmb::export::{ExportOptions, Exporter};
+  use crate::ErrorLevel;
+
+  /// Export `env`, the environment of the file `path` with contents `source`, as an MMB file.
+  fn export(path: &FileRef, source: &crate::LinedString, env: &FrozenEnv) -> Vec<u8> {
+    let mut report = |level: ErrorLevel, msg: &str|
+      assert!(level != ErrorLevel::Error, "{}", msg);
+    let mut w = Cursor::new(vec![]);
+    Exporter::new(path.clone(), Some(source), env, &mut report, &mut w)
+      .export(&ExportOptions::new()).expect("export failed");
+    w.into_inner()
+  }
+
+  #[test]
+  fn roundtrip() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../examples/peano.mm1");
+    let snap = crate::api::elaborate(path).expect("failed to read peano.mm1");
+    assert!(!snap.has_errors());
+    let source = snap.source.as_deref().expect("expected a text file");
+    let dir = std::env::temp_dir().join(format!("mm0-cache-test-{}", std::process::id()));
+    set_cache_dir(Some(dir.clone()));
+    store(&snap.path, source.as_bytes(), &[], &[], &snap.env).expect("failed to write cache");
+    let entry = load(&snap.path, source.as_bytes(), |_| None).expect("failed to read cache");
+    set_cache_dir(None);
+    fs::remove_dir_all(&dir).expect("failed to remove cache");
+    assert!(entry.deps.is_empty() && entry.trans.is_empty());
+    assert!(export(&snap.path, source, &entry.env) == export(&snap.path, source, &snap.env),
+      "the environment loaded from the cache exports differently");
+  }
+}
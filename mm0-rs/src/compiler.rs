@@ -8,7 +8,8 @@
 //!
 //! [`mm0_rs::server`]: crate::server
 //! [`mm0-c`]: https://github.com/digama0/mm0/tree/master/mm0-c
-use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}};
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}};
+use std::time::{Duration, Instant};
 use std::collections::{HashMap, hash_map::Entry};
 use std::{io, fs};
 use futures::{FutureExt, future::BoxFuture};
@@ -21,11 +22,14 @@ use annotate_snippets::{
 use typed_arena::Arena;
 use clap::ArgMatches;
 use mm1_parser::{parse, ErrorLevel, ParseError};
-use crate::elab::{ElabError, ElabErrorKind, ElabResult, ElaborateBuilder};
-use crate::{ArcList, FileRef, FileSpan, FrozenEnv, LinedString, MutexExt, Position, Range, Span};
+use crate::elab::{lisp::eval::Debugger, profile, CancelToken, ElabError, ElabErrorKind, ElabResult, ElaborateBuilder};
+use crate::{cache, ArcList, FileRef, FileSpan, FrozenEnv, LinedString, MutexExt, Position, Range, Span,
+  ThmKind, WarningCode};
 use crate::mmb::import::elab as mmb_elab;
+use crate::mm::import::elab as mm_elab;
 use crate::mmu::import::elab as mmu_elab;
-use crate::mmb::export::Exporter as MmbExporter;
+use crate::mmb::export::{BigBuffer, Exporter as MmbExporter, ExportOptions, ExportSubset};
+use crate::diagnostics::{self, Diagnostic, ErrorFormat, Region};
 
 lazy_static! {
   /// The thread pool (used for running MM1 files in parallel, when possible)
@@ -37,6 +41,67 @@ lazy_static! {
 
 static QUIET: AtomicBool = AtomicBool::new(false);
 
+/// Suppress the `elab` progress messages, for subcommands that print their result to stdout.
+pub(crate) fn set_quiet(b: bool) { QUIET.store(b, Ordering::Relaxed) }
+
+/// If false, parse and elaboration errors are neither printed nor counted, because the
+/// caller collects them itself (as in the [`api`](crate::api)).
+static REPORT: AtomicBool = AtomicBool::new(true);
+
+/// Set whether parse and elaboration errors are printed as files are elaborated.
+pub(crate) fn set_report(b: bool) { REPORT.store(b, Ordering::Relaxed) }
+
+/// If true, statements are [profiled](crate::elab::profile) during elaboration,
+/// and files are not loaded from the cache.
+static PROFILE: AtomicBool = AtomicBool::new(false);
+
+/// The number of diagnostics of each kind reported while compiling a file, which are
+/// summarized at the end of `mm0-rs compile` and determine its exit code.
+struct Counts {
+  /// Errors reported by the parser.
+  parse_errors: AtomicUsize,
+  /// Errors reported by the elaborator, including errors in proofs.
+  elab_errors: AtomicUsize,
+  /// Errors reported while exporting the proofs, for example because a theorem contains
+  /// `sorry` or a public theorem depends on a local one.
+  verify_errors: AtomicUsize,
+  /// Warnings reported as errors, because their warning code is denied.
+  denied_warnings: AtomicUsize,
+  /// Warnings.
+  warnings: AtomicUsize,
+}
+
+static COUNTS: Counts = Counts {
+  parse_errors: AtomicUsize::new(0),
+  elab_errors: AtomicUsize::new(0),
+  verify_errors: AtomicUsize::new(0),
+  denied_warnings: AtomicUsize::new(0),
+  warnings: AtomicUsize::new(0),
+};
+
+impl Counts {
+  fn all(&self) -> [&AtomicUsize; 5] {
+    [&self.parse_errors, &self.elab_errors, &self.verify_errors, &self.denied_warnings, &self.warnings]
+  }
+
+  fn reset(&self) { for n in self.all() { n.store(0, Ordering::Relaxed) } }
+
+  /// Count a diagnostic with level `level`, which is counted in `errors` if it is an error.
+  fn add(&self, errors: &AtomicUsize, level: ErrorLevel) {
+    match level {
+      ErrorLevel::Error => errors.fetch_add(1, Ordering::Relaxed),
+      ErrorLevel::Warning => self.warnings.fetch_add(1, Ordering::Relaxed),
+      ErrorLevel::Info => return,
+    };
+  }
+
+  /// The exit code of `mm0-rs compile`, as described in [`main`].
+  fn exit_code(&self) -> i32 {
+    let [parse, elab, verify, denied, _] = self.all().map(|n| n.load(Ordering::Relaxed));
+    if parse != 0 { 2 } else if elab != 0 { 3 } else if verify != 0 { 4 } else if denied != 0 { 5 } else { 0 }
+  }
+}
+
 /// The cached [`Environment`](crate::elab::Environment) representing a
 /// completed parse, or an incomplete parse.
 #[derive(DeepSizeOf)]
@@ -51,8 +116,9 @@ enum FileCache {
   /// [`Sender`]: FSender
   /// [`Receiver`]: futures::channel::oneshot::Receiver
   InProgress(Vec<FSender<ElabResult<()>>>),
-  /// The file has been elaborated and the result is ready.
-  Ready(FrozenEnv),
+  /// The file has been elaborated and the result is ready. The list contains the files
+  /// it imports, directly or indirectly, if the [`cache`](crate::cache) is enabled.
+  Ready(FrozenEnv, Vec<FileRef>),
 }
 
 #[derive(DeepSizeOf, Clone)]
@@ -149,6 +215,14 @@ impl VirtualFile {
 struct Vfs(Mutex<HashMap<FileRef, Arc<VirtualFile>>>);
 
 impl Vfs {
+  /// The paths of the files that have been loaded.
+  #[cfg(not(target_arch = "wasm32"))]
+  fn paths(&self) -> Vec<FileRef> { self.0.ulock().keys().cloned().collect() }
+
+  /// Forget all the files that have been loaded, so that they are read and elaborated
+  /// again when they are next needed.
+  fn clear(&self) { self.0.ulock().clear() }
+
   /// Get the file at `path`, returning the canonicalized `path` and the file record.
   ///
   /// **Note:** If the file has not yet been read, it will read the file from disk
@@ -218,10 +292,11 @@ impl ElabErrorKind {
 /// - `pos`: The position of the error
 /// - `msg`: The error message
 /// - `level`: The error level
+/// - `id`: The warning code, if any
 /// - `footer`: The snippet footer (calculated by [`ElabErrorKind::to_footer`])
 /// - `to_range`: a function for converting (index-based) spans to (line/col) ranges
 fn make_snippet<'a>(path: &'a FileRef, file: &'a LinedString, pos: Span,
-    msg: &'a str, level: ErrorLevel, footer: Vec<Annotation<'a>>) -> Snippet<'a> {
+    msg: &'a str, level: ErrorLevel, id: Option<&'a str>, footer: Vec<Annotation<'a>>) -> Snippet<'a> {
   let annotation_type = level.to_annotation_type();
   let Range {start, end} = file.to_range(pos);
   let start2 = pos.start - start.character as usize;
@@ -229,7 +304,7 @@ fn make_snippet<'a>(path: &'a FileRef, file: &'a LinedString, pos: Span,
     .unwrap_or_else(|| file.len());
   Snippet {
     title: Some(Annotation {
-      id: None,
+      id,
       label: Some(msg),
       annotation_type,
     }),
@@ -286,7 +361,7 @@ impl ElabError {
       to_range: impl FnMut(&FileSpan) -> Option<Range>,
       f: impl for<'a> FnOnce(Snippet<'a>) -> T) -> T {
     f(make_snippet(path, file, self.pos, &self.kind.msg(), self.level,
-      self.kind.to_footer(&Arena::new(), to_range)))
+      self.code.map(WarningCode::name), self.kind.to_footer(&Arena::new(), to_range)))
   }
 
   /// Create a [`Snippet`] from an error when the file source is not available
@@ -305,13 +380,72 @@ impl ElabError {
     };
     f(make_snippet_no_source(&s, self.level))
   }
+
+  /// Report this error in the file `path` with contents `file`, either by printing it as a
+  /// [`Snippet`], or as a [`Diagnostic`] in the machine-readable [error format](diagnostics).
+  fn report(&self, path: &FileRef, file: &FileContents,
+      mut to_range: impl FnMut(&FileSpan) -> Option<Range>) {
+    fn print(s: Snippet<'_>) { diagnostics::print(format_args!("{}\n\n", DisplayList::from(s))) }
+    COUNTS.add(if self.code.is_some() {&COUNTS.denied_warnings} else {&COUNTS.elab_errors}, self.level);
+    match (diagnostics::error_format(), file) {
+      (ErrorFormat::Human, FileContents::Ascii(text)) => self.to_snippet(path, text, to_range, print),
+      (ErrorFormat::Human, _) => self.to_snippet_no_source(path, self.pos, print),
+      (_, file) => diagnostics::emit(&Diagnostic {
+        file: path.clone(),
+        region: file.try_ascii().map_or(Region::Bytes(self.pos), |text| Region::Text(text.to_range(self.pos))),
+        level: self.level,
+        code: self.code.map(WarningCode::name),
+        message: self.kind.msg(),
+        related: match &self.kind {
+          ElabErrorKind::Boxed(_, Some(info)) => info.iter().map(|(fs, e)|
+            (fs.file.clone(), to_range(fs).map_or(Region::Bytes(fs.span), Region::Text), e.to_string())
+          ).collect(),
+          _ => vec![]
+        },
+      }),
+    }
+  }
+}
+
+/// Report a parse error in the file `path` with contents `file`, either by printing it as a
+/// [`Snippet`], or as a [`Diagnostic`] in the machine-readable [error format](diagnostics).
+fn report_parse_error(err: &ParseError, path: &FileRef, file: &LinedString) {
+  COUNTS.add(&COUNTS.parse_errors, err.level);
+  if diagnostics::error_format() == ErrorFormat::Human {
+    to_snippet(err, path, file, |s| diagnostics::print(format_args!("{}\n", DisplayList::from(s))))
+  } else {
+    diagnostics::emit(&Diagnostic {
+      region: Region::Text(file.to_range(err.pos)),
+      ..Diagnostic::message(path.clone(), err.level, format!("{}", err.msg))
+    })
+  }
+}
+
+/// Report a message from the exporter about the file `path` which has no position, either by
+/// printing it, or as a [`Diagnostic`] in the machine-readable [error format](diagnostics).
+pub(crate) fn report_msg(path: &FileRef, level: ErrorLevel, msg: &str) {
+  COUNTS.add(&COUNTS.verify_errors, level);
+  if diagnostics::error_format() == ErrorFormat::Human {
+    diagnostics::print(format_args!("{}\n\n", DisplayList::from(Snippet {
+      title: Some(Annotation {
+        label: Some(msg),
+        id: None,
+        annotation_type: level.to_annotation_type(),
+      }),
+      footer: vec![],
+      slices: vec![],
+      opt: FormatOptions { color: true, ..Default::default() },
+    })))
+  } else {
+    diagnostics::emit(&Diagnostic::message(path.clone(), level, msg.into()))
+  }
 }
 
 /// Create a [`Snippet`] from this error. See [`ElabError::to_snippet`] for information
 /// about the parameters.
 fn to_snippet<T>(err: &ParseError, path: &FileRef, file: &LinedString,
   f: impl for<'a> FnOnce(Snippet<'a>) -> T) -> T {
-  f(make_snippet(path, file, err.pos, &format!("{}", err.msg), err.level, vec![]))
+  f(make_snippet(path, file, err.pos, &format!("{}", err.msg), err.level, None, vec![]))
 }
 
 fn log_msg(#[allow(unused_mut)] mut s: String) {
@@ -323,7 +457,7 @@ fn log_msg(#[allow(unused_mut)] mut s: String) {
       write!(s, ", memory = {}M", n >> 20).expect("writing to a string");
     }
   }
-  println!("{}", s)
+  diagnostics::print(format_args!("{}\n", s))
 }
 
 /// Elaborate a file for an [`Environment`](crate::elab::Environment) result.
@@ -343,8 +477,12 @@ fn log_msg(#[allow(unused_mut)] mut s: String) {
 /// which will later be joined when the result is required.
 /// (**Note**: This can result in deadlock if the import graph has a cycle.)
 ///
+/// If `debugger` is set, it is attached to the lisp evaluator while elaborating the file,
+/// and the file is elaborated even if it is in the [`cache`].
+///
 /// [`Ast`]: crate::parser::Ast
-async fn elaborate(path: FileRef, rd: ArcList<FileRef>) -> io::Result<ElabResult<()>> {
+async fn elaborate(path: FileRef, rd: ArcList<FileRef>,
+    debugger: Option<Box<dyn Debugger>>) -> io::Result<ElabResult<()>> {
   let (path, file) = VFS.get_or_insert(path)?;
   {
     let mut g = file.parsed.lock().await;
@@ -356,23 +494,33 @@ async fn elaborate(path: FileRef, rd: ArcList<FileRef>) -> io::Result<ElabResult
         drop(g);
         return Ok(recv.await.unwrap_or(ElabResult::Canceled))
       }
-      Some(FileCache::Ready(env)) => return Ok(ElabResult::Ok((), None, env.clone()))
+      Some(FileCache::Ready(env, _)) => return Ok(ElabResult::Ok((), None, env.clone()))
     }
   }
   let text = file.text.clone();
+  let mut trans = vec![];
+  // The cache is not used when the result depends on the settings for this run, like
+  // profiling or proof minimization, which are not recorded in the cache.
+  let use_cache = debugger.is_none() && !PROFILE.load(Ordering::Relaxed) && !crate::get_minimize_proofs();
   let (cyc, errors, env) = if path.has_extension("mmb") {
     let (error, env) = mmb_elab(&path, &text);
     (None, if let Err(e) = error {vec![e]} else {vec![]}, FrozenEnv::new(env))
   } else if path.has_extension("mmu") {
     let (error, env) = mmu_elab(&path, &text);
     (None, if let Err(e) = error {vec![e]} else {vec![]}, FrozenEnv::new(env))
+  } else if path.has_extension("mm") {
+    let (error, env) = mm_elab(&path, &text);
+    (None, if let Err(e) = error {vec![e]} else {vec![]}, FrozenEnv::new(env))
+  } else if let Some(entry) = use_cache.then(|| cache::load(&path, &text, |p|
+    Some(cache::content_hash(&VFS.get_or_insert(p.clone()).ok()?.1.text))
+  )).flatten() {
+    if !QUIET.load(Ordering::Relaxed) { log_msg(format!("loaded {} from cache", path)) }
+    trans = entry.trans;
+    (None, vec![], entry.env)
   } else {
     let (_, ast) = parse(text.ascii().clone(), None);
-    if !ast.errors.is_empty() {
-      for e in &ast.errors {
-        to_snippet(e, &path, &ast.source,
-          |s| println!("{}", DisplayList::from(s)))
-      }
+    if REPORT.load(Ordering::Relaxed) {
+      for e in &ast.errors { report_parse_error(e, &path, &ast.source) }
     }
     let ast = Arc::new(ast);
     let mut deps = Vec::new();
@@ -385,8 +533,10 @@ async fn elaborate(path: FileRef, rd: ArcList<FileRef>) -> io::Result<ElabResult
         mm0_mode: path.has_extension("mm0"),
         check_proofs: crate::get_check_proofs(),
         report_upstream_errors: false,
-        cancel: Arc::default(),
+        cancel: CancelToken::new(),
         old: None,
+        snapshots: false,
+        profile: PROFILE.load(Ordering::Relaxed),
         recv_dep: |p| {
           let p = VFS.get_or_insert(p)?.0;
           let (send, recv) = channel();
@@ -399,18 +549,29 @@ async fn elaborate(path: FileRef, rd: ArcList<FileRef>) -> io::Result<ElabResult
           Ok(recv)
         },
         recv_goal: None,
+        recv_goal_state: None,
+        recv_stmt: None,
+        debugger,
       }.elab();
     let (cyc, _, errors, env) = fut.await;
+    if use_cache && cache::cache_dir().is_some() {
+      trans = transitive_deps(&deps).await;
+      if cyc.is_none() && errors.is_empty() && ast.errors.is_empty() {
+        let hashes = trans.iter().map(|p| Ok((p.clone(),
+          cache::content_hash(&VFS.get_or_insert(p.clone())?.1.text))))
+          .collect::<io::Result<Vec<_>>>()?;
+        if let Err(e) = cache::store(&path, &text, &deps, &hashes, &env) {
+          report_msg(&path, ErrorLevel::Warning, &format!("could not write cache: {}", e))
+        }
+      }
+    }
     (cyc, errors, env)
   };
   if !QUIET.load(Ordering::Relaxed) { log_msg(format!("elabbed {}", path)) }
   let errors: Option<Arc<[_]>> = if errors.is_empty() { None } else {
-    fn print(s: Snippet<'_>) { println!("{}\n", DisplayList::from(s)) }
-    let mut to_range = mk_to_range();
-    if let FileContents::Ascii(text) = &file.text {
-      for e in &errors { e.to_snippet(&path, text, &mut to_range, print) }
-    } else {
-      for e in &errors { e.to_snippet_no_source(&path, e.pos, print) }
+    if REPORT.load(Ordering::Relaxed) {
+      let mut to_range = mk_to_range();
+      for e in &errors { e.report(&path, &file.text, &mut to_range) }
     }
     Some(errors.into())
   };
@@ -425,11 +586,28 @@ async fn elaborate(path: FileRef, rd: ArcList<FileRef>) -> io::Result<ElabResult
         drop(s.send(res.clone()));
       }
     }
-    *g = Some(FileCache::Ready(env));
+    *g = Some(FileCache::Ready(env, trans));
   }
   Ok(res)
 }
 
+/// Get the files imported by the files `deps`, directly or indirectly (including `deps`
+/// themselves). The files in `deps` should have already been elaborated.
+async fn transitive_deps(deps: &[FileRef]) -> Vec<FileRef> {
+  let mut trans = vec![];
+  for p in deps {
+    let file = VFS.0.ulock().get(p).cloned();
+    if let Some(file) = file {
+      if let Some(FileCache::Ready(_, ps)) = &*file.parsed.lock().await {
+        for p in ps.iter().chain(Some(p)) {
+          if !trans.contains(p) { trans.push(p.clone()) }
+        }
+      }
+    }
+  }
+  trans
+}
+
 /// Elaborate a file, and pass the [`Environment`](crate::elab::Environment)
 /// result to a [`Sender`](FSender).
 ///
@@ -439,7 +617,7 @@ async fn elaborate(path: FileRef, rd: ArcList<FileRef>) -> io::Result<ElabResult
 fn elaborate_and_send(path: FileRef, send: FSender<ElabResult<()>>, rd: ArcList<FileRef>) ->
   BoxFuture<'static, ()> {
   async {
-    if let Ok(env) = elaborate(path, rd).await {
+    if let Ok(env) = elaborate(path, rd, None).await {
       drop(send.send(env));
     }
   }.boxed()
@@ -449,29 +627,323 @@ fn elaborate_and_send(path: FileRef, send: FSender<ElabResult<()>>, rd: ArcList<
 /// file contents.
 pub(crate) fn elab_for_result(path: FileRef) -> io::Result<(FileContents, Option<FrozenEnv>)> {
   let (path, file) = VFS.get_or_insert(path)?;
-  let env = match block_on(elaborate(path, Default::default()))? {
+  let env = match block_on(elaborate(path, Default::default(), None))? {
     ElabResult::Ok(_, _, env) => Some(env),
     _ => None
   };
   Ok((file.text.clone(), env))
 }
 
+/// Parse and elaborate the file `path` again, for the [`api`](crate::api), even if it has
+/// already been elaborated. The file is read from disk, unless `text` is given, and replaces
+/// the previous version in the [`VFS`]; the files it imports are elaborated as usual.
+/// Returns the canonicalized path, the file contents, the [`Ast`] (for MM1 and MM0 files),
+/// the parse and elaboration errors, and the resulting environment. Errors in imported files
+/// are reported as errors on the `import` statement.
+///
+/// [`Ast`]: crate::ast::Ast
+#[allow(clippy::type_complexity)]
+pub(crate) fn elab_snapshot(path: FileRef, text: Option<String>) -> io::Result<(
+  FileRef, FileContents, Option<Arc<crate::ast::Ast>>, Vec<ElabError>, FrozenEnv
+)> {
+  let (path, text) = match text {
+    Some(text) => (path, FileContents::new(text)),
+    None => {
+      VFS.0.ulock().remove(&path);
+      let (path, file) = VFS.get_or_insert(path)?;
+      (path, file.text.clone())
+    }
+  };
+  let file = Arc::new(VirtualFile::new(text.clone()));
+  VFS.0.ulock().insert(path.clone(), file.clone());
+  let (ast, errors, env) = if path.has_extension("mmb") {
+    let (error, env) = mmb_elab(&path, &text);
+    (None, error.err().into_iter().collect(), FrozenEnv::new(env))
+  } else if path.has_extension("mmu") {
+    let (error, env) = mmu_elab(&path, &text);
+    (None, error.err().into_iter().collect(), FrozenEnv::new(env))
+  } else if path.has_extension("mm") {
+    let (error, env) = mm_elab(&path, &text);
+    (None, error.err().into_iter().collect(), FrozenEnv::new(env))
+  } else {
+    let ast = Arc::new(parse(text.ascii().clone(), None).1);
+    let rd = ArcList::default().push(path.clone());
+    let (_, _, errors, env) = block_on(ElaborateBuilder {
+      ast: &ast,
+      path: path.clone(),
+      mm0_mode: path.has_extension("mm0"),
+      check_proofs: crate::get_check_proofs(),
+      report_upstream_errors: true,
+      cancel: CancelToken::new(),
+      old: None,
+      snapshots: false,
+      profile: false,
+      recv_dep: |p| {
+        let p = VFS.get_or_insert(p)?.0;
+        let (send, recv) = channel();
+        if rd.contains(&p) {
+          send.send(ElabResult::ImportCycle(rd.clone())).expect("failed to send");
+        } else {
+          POOL.spawn_ok(elaborate_and_send(p, send, rd.clone()));
+        }
+        Ok(recv)
+      },
+      recv_goal: None,
+      recv_goal_state: None,
+      recv_stmt: None,
+      debugger: None,
+    }.elab());
+    (Some(ast), errors, env)
+  };
+  *block_on(file.parsed.lock()) = Some(FileCache::Ready(env.clone(), vec![]));
+  Ok((path, text, ast, errors, env))
+}
+
+/// Forget all the files in the [`VFS`], so that they are read from disk and elaborated
+/// again when they are next needed.
+pub(crate) fn clear_vfs() { VFS.clear() }
+
+/// Elaborate a file with a [`Debugger`] attached to the lisp evaluator (the files
+/// it imports are elaborated normally), and return the elaboration errors in the file.
+#[cfg(feature = "dap")]
+pub(crate) fn elab_for_debug(path: FileRef, debugger: Box<dyn Debugger>) ->
+    io::Result<Option<Arc<[ElabError]>>> {
+  Ok(match block_on(elaborate(path, Default::default(), Some(debugger)))? {
+    ElabResult::Ok(_, errors, _) => errors,
+    _ => None
+  })
+}
+
+/// Set the contents of a file that does not exist on disk (like the input to the REPL)
+/// in the virtual file system, replacing any previous version.
+/// Returns the text of the file as a [`LinedString`].
+pub(crate) fn set_virtual_file(path: FileRef, text: String) -> Arc<LinedString> {
+  let fc = FileContents::new(text);
+  let text = fc.ascii().clone();
+  VFS.0.ulock().insert(path, Arc::new(VirtualFile::new(fc)));
+  text
+}
+
+/// Print parse and elaboration errors in the file `path` (which must be in the [`VFS`])
+/// to stdout.
+#[cfg(feature = "repl")]
+pub(crate) fn print_errors(path: &FileRef, text: &LinedString,
+    parse_errors: &[ParseError], errors: &[ElabError]) {
+  fn print(s: Snippet<'_>) { println!("{}\n", DisplayList::from(s)) }
+  for e in parse_errors { to_snippet(e, path, text, print) }
+  let mut to_range = mk_to_range();
+  for e in errors { e.to_snippet(path, text, &mut to_range, print) }
+}
+
+/// Get the elaborated files with profiling data, along with their source text and
+/// environment, ordered by path.
+fn profiled_files() -> Vec<(FileRef, Arc<LinedString>, FrozenEnv)> {
+  let files = VFS.0.ulock().iter().map(|(p, f)| (p.clone(), f.clone())).collect::<Vec<_>>();
+  let mut out = vec![];
+  for (path, file) in files {
+    if let (FileContents::Ascii(text), Some(FileCache::Ready(env, _))) =
+      (&file.text, &*block_on(file.parsed.lock())) {
+      if !env.profile().is_empty() { out.push((path, text.clone(), env.clone())) }
+    }
+  }
+  out.sort_by(|a, b| a.0.rel().cmp(b.0.rel()));
+  out
+}
+
+/// Print the [unused declaration warnings](crate::unused) of `env`, after applying the
+/// warning configuration. Returns true if one of them is denied, and so reported as an error.
+pub(crate) fn print_unused_warnings(env: &FrozenEnv) -> io::Result<bool> {
+  let mut to_range = mk_to_range();
+  let config = crate::get_warning_config();
+  let mut error = false;
+  for (file, e) in env.unused_decl_warnings(None) {
+    let e = if let Some(e) = config.apply(e) {e} else {continue};
+    error |= e.level == ErrorLevel::Error;
+    e.report(&file, &VFS.get_or_insert(file.clone())?.1.text, &mut to_range)
+  }
+  Ok(error)
+}
+
+/// Write the MMB file for the environment `env` of the file at `path` with contents `file`
+/// to `w`, printing the statistics if they are requested in `opts`.
+pub(crate) fn write_mmb<W: io::Write + io::Seek>(path: &FileRef, file: &FileContents, env: &FrozenEnv,
+    w: W, opts: ExportOptions) -> io::Result<()> {
+  let mut report = |level: ErrorLevel, err: &str| report_msg(path, level, err);
+  let ex = MmbExporter::new(path.clone(), file.try_ascii().map(|fc| &**fc), env, &mut report, w);
+  if let Some(stats) = ex.export(&opts)? { diagnostics::print(format_args!("{}", stats)) }
+  Ok(())
+}
+
 /// Main entry point for `mm0-rs compile` subcommand.
 ///
 /// # Arguments
 ///
 /// `mm0-rs compile <in.mm1> [out.mmb]`, where:
 ///
-/// - `in.mm1` is the MM1 (or MM0) file to elaborate
+/// - `in.mm1` is the MM1 (or MM0) file to elaborate, or `-` to read an MM1 file from stdin.
+///   Imports in a file read from stdin are relative to the current directory.
 /// - `out.mmb` (or `out.mmu`) is the MMB file to generate, if the elaboration is
 ///   successful. The file extension is used to determine if we are outputting
-///   binary. If this argument is omitted, the input is only elaborated.
+///   binary. If this argument is omitted, the input is only elaborated. A `.lean`,
+///   `.v` or `.dk` extension exports the environment to Lean 4, Coq or Dedukti instead.
+///   If it is `-`, the MMB file is written to stdout (after it is completely generated,
+///   so nothing is written if exporting fails), and the diagnostics are printed to stderr.
+///   In this case `--output -` (which prints the `output` commands to stdout) is an error.
+///
+/// For example, `mm0-rs compile - - < foo.mm1 > foo.mmb` compiles `foo.mm1` without
+/// naming any files.
+///
+/// With `--allow-sorry`, admitted theorems (see [`sorry`](crate::elab::lisp::BuiltinProc::Sorry))
+/// are written to the MMB file with a `sorry` proof; otherwise exporting them is an error.
+///
+/// With `--mm0 <out.mm0>`, the MM0 specification of the elaborated file is also written,
+/// from the same environment as the MMB file so that the two are in sync.
+///
+/// With `--warn-unused`, local defs and theorems which are never used are reported
+/// (see [`unused`](crate::unused)). These warnings have the `unused-declaration` warning code,
+/// and if it is denied with `--deny unused-declaration` they are errors, and no output is written.
+///
+/// With `--minimize-proofs`, the proofs are [minimized](crate::elab::minimize) after
+/// elaboration, and their total size before and after is printed. Files are not loaded from
+/// the cache in this mode, so that every proof is minimized.
+///
+/// With `--profile`, a report of the slowest statements in the elaborated files is printed
+/// (to stderr if the MMB file is written to stdout), and with `--profile-json <out.json>` the data for every statement is written as JSON
+/// (see [`profile`](crate::elab::profile)). Files are not loaded from the cache while profiling.
+///
+/// With `--watch`, the file is compiled again whenever it or one of the files it imports
+/// changes on disk, until the process is interrupted. Together with `--cache`, only the
+/// changed files and the files depending on them are elaborated again.
+///
+/// When the compilation finishes, a summary line like
+///
+/// ```text
+/// peano.mm1: 2935 statements, 2646/2646 theorems proved, 3 warnings, 0 errors in 0.70s
+/// ```
+///
+/// is printed, unless `--quiet` is given. (In the `json` and `sarif` [error formats](diagnostics)
+/// the summary is part of the output instead.) The statements and theorems are counted in
+/// the file and all the files it imports, and a theorem is proved if it has a proof without
+/// `sorry`. The exit code is:
+///
+/// - 0 if there are no errors (there may be warnings),
+/// - 1 if the compiler fails for another reason, for example if a file cannot be read or written,
+/// - 2 if there are parse errors,
+/// - 3 if there are elaboration errors, including errors in proofs,
+/// - 4 if the proofs cannot be exported to the MMB or MMU output file, for example because
+///   a theorem contains `sorry` and `--allow-sorry` is not given,
+/// - 5 if there are warnings reported as errors, because their warning code is denied.
+///
+/// If there are errors of several kinds, the exit code is that of the first kind in this list.
 pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
   let path = args.value_of("INPUT").expect("required arg");
-  let path: FileRef = fs::canonicalize(path)?.into();
+  let stdin = path == "-";
+  let path: FileRef = if stdin { read_stdin()? } else { fs::canonicalize(path)?.into() };
+  PROFILE.store(args.is_present("profile") || args.is_present("profile_json"), Ordering::Relaxed);
+  if args.value_of("OUTPUT") == Some("-") {
+    if args.value_of_os("output").map_or(false, |s| s == "-") {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput,
+        "cannot print 'output' commands to stdout (--output -) when writing the MMB file to stdout"))
+    }
+    diagnostics::set_stderr(true)
+  }
+  #[cfg(not(target_arch = "wasm32"))]
+  if args.is_present("watch") {
+    if stdin {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "cannot watch a file read from stdin"))
+    }
+    return watch(args, &path)
+  }
+  match compile_and_finish(args, &path)? {
+    0 => Ok(()),
+    code => std::process::exit(code),
+  }
+}
+
+/// Read an MM1 file from stdin into the [`VFS`], as the file `<stdin>` in the current
+/// directory, and return its path.
+fn read_stdin() -> io::Result<FileRef> {
+  let mut text = String::new();
+  io::Read::read_to_string(&mut io::stdin(), &mut text)?;
+  let path: FileRef = std::env::current_dir()?.join("<stdin>").into();
+  set_virtual_file(path.clone(), text);
+  Ok(path)
+}
+
+/// Run [`compile`], print the summary and finish the [diagnostics] output, and return the
+/// exit code. In a machine-readable error format, an error while compiling is reported as a
+/// diagnostic rather than returned.
+fn compile_and_finish(args: &ArgMatches<'_>, path: &FileRef) -> io::Result<i32> {
+  let start = Instant::now();
+  COUNTS.reset();
+  let (env, failed) = match compile(args, path.clone()) {
+    Ok(env) => (env, false),
+    Err(e) if diagnostics::error_format() != ErrorFormat::Human => {
+      diagnostics::emit(&Diagnostic::message(path.clone(), ErrorLevel::Error, e.to_string()));
+      (None, true)
+    }
+    Err(e) => return Err(e),
+  };
+  let code = match COUNTS.exit_code() {
+    0 if failed || env.is_none() => 1,
+    code => code,
+  };
+  print_summary(path, env.as_ref(), start.elapsed(), code);
+  diagnostics::finish();
+  Ok(code)
+}
+
+/// Print the summary of compiling the file at `path` to the environment `env` in time `time`,
+/// which exits with `code`, as described in [`main`].
+fn print_summary(path: &FileRef, env: Option<&FrozenEnv>, time: Duration, code: i32) {
+  let (stmts, thms, proved) = env.map_or((0, 0, 0), |env| {
+    let thms = env.thms().iter().filter(|td| matches!(td.kind, ThmKind::Thm(_)));
+    let proved = thms.clone().filter(|td| matches!(td.kind, ThmKind::Thm(Some(_)))).count();
+    (env.stmts().len(), thms.count(), proved)
+  });
+  let [parse, elab, verify, denied, warnings] = COUNTS.all().map(|n| n.load(Ordering::Relaxed));
+  let errors = parse + elab + verify + denied;
+  if diagnostics::error_format() != ErrorFormat::Human {
+    diagnostics::summary(code, serde_json::json!({
+      "file": path.rel(), "statements": stmts, "theorems": thms, "proved": proved,
+      "warnings": warnings, "errors": errors, "parse_errors": parse, "elab_errors": elab,
+      "verify_errors": verify, "denied_warnings": denied, "time": time.as_secs_f64(),
+    }))
+  } else if !QUIET.load(Ordering::Relaxed) {
+    diagnostics::print(format_args!(
+      "{}: {} statements, {}/{} theorems proved, {} warnings, {} errors in {:.2}s\n",
+      path, stmts, proved, thms, warnings, errors, time.as_secs_f64()))
+  }
+}
+
+/// Elaborate the file at `path`, and write the outputs requested by `args`, as described in
+/// [`main`]. Returns the environment of the file, or `None` if the elaboration did not finish.
+/// The errors are counted in [`COUNTS`].
+fn compile(args: &ArgMatches<'_>, path: FileRef) -> io::Result<Option<FrozenEnv>> {
   let (file, env) = elab_for_result(path.clone())?;
-  let env = env.unwrap_or_else(|| std::process::exit(1));
-  QUIET.store(args.is_present("quiet"), Ordering::Relaxed);
+  let env = if let Some(env) = env {env} else { return Ok(None) };
+  if args.is_present("quiet") { QUIET.store(true, Ordering::Relaxed) }
+  if args.is_present("warn_unused") && print_unused_warnings(&env)? { return Ok(Some(env)) }
+  if crate::get_minimize_proofs() {
+    let (before, after) = crate::elab::minimize::stats();
+    #[allow(clippy::integer_division)] // the percentage is rounded down
+    let percent = 100 * after / before.max(1);
+    diagnostics::print(format_args!("minimized proofs: {} -> {} nodes ({}%)\n",
+      before, after, percent));
+  }
+  if PROFILE.load(Ordering::Relaxed) {
+    let files = profiled_files();
+    if args.is_present("profile") {
+      let mut report = vec![];
+      profile::write_report(&files, &mut report)?;
+      diagnostics::print(format_args!("{}", String::from_utf8_lossy(&report)))
+    }
+    if let Some(out) = args.value_of("profile_json") {
+      let mut w = io::BufWriter::new(fs::File::create(out)?);
+      serde_json::to_writer_pretty(&mut w, &profile::json_report(&files))?;
+      io::Write::flush(&mut w)?;
+    }
+  }
   if let Some(s) = args.value_of_os("output") {
     if let Err((fsp, e)) =
       if s == "-" { env.run_output(io::stdout()) }
@@ -479,34 +951,95 @@ pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
     {
       let e = ElabError::new_e(fsp.span, e);
       let file = VFS.get_or_insert(fsp.file.clone())?.1;
-      e.to_snippet(&fsp.file, file.text.ascii(), &mut mk_to_range(),
-        |s| println!("{}\n", DisplayList::from(s)));
-      std::process::exit(1);
+      e.report(&fsp.file, &file.text, mk_to_range());
+      return Ok(Some(env))
     }
   }
   if let Some(out) = args.value_of("OUTPUT") {
-    use {fs::File, io::BufWriter};
-    let w = BufWriter::new(File::create(out)?);
-    if out.rsplit('.').next().map_or(false, |ext| ext.eq_ignore_ascii_case("mmu")) {
-      env.export_mmu(w)?;
-    } else {
-      fn report(lvl: ErrorLevel, err: &str) {
-        println!("{}\n", DisplayList::from(Snippet {
-          title: Some(Annotation {
-            label: Some(err),
-            id: None,
-            annotation_type: lvl.to_annotation_type(),
-          }),
-          footer: vec![],
-          slices: vec![],
-          opt: FormatOptions { color: true, ..Default::default() },
-        }))
+    if let Err(e) = write_output(args, &path, &file, &env, out) {
+      // The exporters fail with `InvalidInput` if the proofs cannot be exported.
+      if e.kind() != io::ErrorKind::InvalidInput { return Err(e) }
+      report_msg(&path, ErrorLevel::Error, &e.to_string())
+    }
+  }
+  if let Some(out) = args.value_of("mm0") {
+    // The spec is generated in memory first, so that nothing is written if it fails.
+    let mut w = vec![];
+    match env.export_mm0(&mut w) {
+      Ok(()) => fs::write(out, w)?,
+      Err(e) if e.kind() == io::ErrorKind::InvalidInput =>
+        report_msg(&path, ErrorLevel::Error, &e.to_string()),
+      Err(e) => return Err(e),
+    }
+  }
+  Ok(Some(env))
+}
+
+/// Write the environment `env` of the file at `path` with contents `file` to the output
+/// file `out`, in the format given by its extension, as described in [`main`].
+fn write_output(args: &ArgMatches<'_>, path: &FileRef, file: &FileContents, env: &FrozenEnv,
+    out: &str) -> io::Result<()> {
+  use {fs::File, io::BufWriter};
+  let opts = ExportOptions::new()
+    .source_map(args.is_present("source_map"))
+    .subset(if args.is_present("public") { ExportSubset::Public } else { ExportSubset::All })
+    .optimize(args.is_present("optimize"))
+    .stats(args.is_present("stats"))
+    .allow_sorry(args.is_present("allow_sorry"));
+  if out == "-" {
+    // stdout can't seek, so the file is generated in memory first
+    let mut w = BigBuffer::new(io::stdout());
+    return match write_mmb(path, file, env, &mut w, opts) {
+      Ok(()) => w.finish(),
+      Err(e) => { w.discard(); Err(e) }
+    }
+  }
+  let w = BufWriter::new(File::create(out)?);
+  let ext = out.rsplit('.').next();
+  if ext.map_or(false, |ext| ext.eq_ignore_ascii_case("mmu")) {
+    env.export_mmu(w)
+  } else if ext.map_or(false, |ext| ext.eq_ignore_ascii_case("lean")) {
+    env.export_lean(w)
+  } else if ext.map_or(false, |ext| ext.eq_ignore_ascii_case("v")) {
+    env.export_coq(w)
+  } else if ext.map_or(false, |ext| ext.eq_ignore_ascii_case("dk")) {
+    env.export_dedukti(w)
+  } else {
+    write_mmb(path, file, env, w, opts)
+  }
+}
+
+/// Compile the file at `path` as in [`compile`], and compile it again every time one of
+/// the files loaded while compiling it changes on disk.
+#[cfg(not(target_arch = "wasm32"))]
+fn watch(args: &ArgMatches<'_>, path: &FileRef) -> io::Result<()> {
+  use notify::{DebouncedEvent, RecursiveMode, Watcher};
+  let to_io = |e: notify::Error| io::Error::new(io::ErrorKind::Other, e.to_string());
+  loop {
+    if let Err(e) = compile_and_finish(args, path) { eprintln!("error: {}", e) }
+    // Editors often save a file by replacing it, which ends the watch on the old file,
+    // so the files are watched again after each compilation.
+    let (send, recv) = std::sync::mpsc::channel();
+    let mut watcher = notify::watcher(send, std::time::Duration::from_millis(100)).map_err(to_io)?;
+    let mut files = VFS.paths();
+    if files.is_empty() { files.push(path.clone()) }
+    for p in &files { watcher.watch(p.path(), RecursiveMode::NonRecursive).map_err(to_io)? }
+    if !QUIET.load(Ordering::Relaxed) {
+      diagnostics::print(format_args!("watching {} files for changes\n", files.len()))
+    }
+    loop {
+      match recv.recv() {
+        Ok(DebouncedEvent::Create(_) | DebouncedEvent::Write(_) |
+          DebouncedEvent::Remove(_) | DebouncedEvent::Rename(_, _)) => break,
+        Ok(DebouncedEvent::Error(e, _)) => return Err(to_io(e)),
+        Ok(_) => {}
+        Err(_) => return Ok(()),
       }
-      let mut report = report;
-      let mut ex = MmbExporter::new(path, file.try_ascii().map(|fc| &**fc), &env, &mut report, w);
-      ex.run(true)?;
-      ex.finish()?;
+    }
+    drop(watcher);
+    VFS.clear();
+    if !QUIET.load(Ordering::Relaxed) {
+      diagnostics::print(format_args!("recompiling {}\n", path))
     }
   }
-  Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,321 @@
+//! A debugger for lisp code in MM1 files, using the
+//! [Debug Adapter Protocol](https://microsoft.github.io/debug-adapter-protocol/) (DAP).
+//!
+//! `mm0-rs dap` listens for a connection from a DAP client (such as VS Code) on a TCP port,
+//! and then elaborates the file given in the `launch` request with a [`Debugger`] attached
+//! to the lisp evaluator. Evaluation pauses at breakpoints (which are set on lines of
+//! lisp code), and can then be stepped through while inspecting the local variables and
+//! the proof state. Local variables are not named at runtime, so they are shown by their
+//! index in the local context (`#0`, `#1`, ...).
+//!
+//! Only one debugging session is supported, and the process exits when the client
+//! disconnects. Messages from the elaborator are printed to stdout as in `mm0-rs compile`,
+//! and the elaboration errors are also sent to the client as `output` events.
+use std::collections::{HashMap, HashSet, hash_map::Entry};
+use std::fs;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use clap::ArgMatches;
+use serde_json::{json, Value};
+use crate::elab::lisp::eval::{DebugFrame, Debugger};
+use crate::{Elaborator, FileRef, FileSpan, LinedString, LispVal, MutexExt};
+
+/// The sending half of the connection to the client.
+#[derive(Debug)]
+struct Output {
+  stream: TcpStream,
+  /// The sequence number of the last message sent.
+  seq: u64,
+}
+
+impl Output {
+  fn send(&mut self, mut msg: Value) {
+    self.seq += 1;
+    msg["seq"] = self.seq.into();
+    let s = msg.to_string();
+    // If the client has gone away we will find out when reading the next request
+    drop(write!(self.stream, "Content-Length: {}\r\n\r\n{}", s.len(), s)
+      .and_then(|_| self.stream.flush()))
+  }
+
+  fn event(&mut self, event: &str, body: Value) {
+    let mut msg = json!({"type": "event", "event": event});
+    msg["body"] = body;
+    self.send(msg)
+  }
+}
+
+/// What the debugger should do when the evaluator reaches a new expression.
+#[derive(Clone, Copy, Debug)]
+enum Mode {
+  /// Run until the next breakpoint.
+  Continue,
+  /// Pause as soon as possible (the `pause` request, or `stopOnEntry`).
+  Pause,
+  /// Pause at the next line (the `stepIn` request).
+  StepIn,
+  /// Pause at the next line with at most this call depth (the `next` request).
+  StepOver(usize),
+  /// Pause at the next expression with less than this call depth (the `stepOut` request).
+  StepOut(usize),
+}
+
+/// A paused evaluation, rendered to strings to be sent to the client.
+#[derive(Debug)]
+struct Paused {
+  /// The call depth at the pause.
+  depth: usize,
+  /// The stack frames, innermost first, with their file and range
+  /// (the 1-based start line and column, and end line and column).
+  frames: Vec<(String, FileRef, [u32; 4])>,
+  /// The local variables of the innermost frame.
+  locals: Vec<(String, String)>,
+  /// The hypotheses and goals of the proof state.
+  goals: Vec<(String, String)>,
+}
+
+/// The state of the session which is shared between the request loop and the
+/// elaboration thread.
+#[derive(Debug)]
+struct State {
+  /// The lines with breakpoints in each file.
+  breakpoints: HashMap<PathBuf, HashSet<u32>>,
+  /// What to do at the next expression.
+  mode: Mode,
+  /// The current paused evaluation. The elaboration thread waits until this is cleared.
+  paused: Option<Paused>,
+}
+
+#[derive(Debug)]
+struct Shared {
+  out: Mutex<Output>,
+  state: Mutex<State>,
+  /// Signaled when the paused evaluation should resume.
+  resume: Condvar,
+}
+
+/// The [`Debugger`] attached to the elaborator.
+#[derive(Debug)]
+struct DapDebugger {
+  shared: Arc<Shared>,
+  /// The source text of files containing lisp code, for converting spans to lines.
+  files: HashMap<FileRef, LinedString>,
+  /// The line of the last expression, so that we stop at a breakpoint only once per visit.
+  last: Option<(FileRef, u32)>,
+  /// The line where evaluation was last paused, which a step must leave.
+  start: Option<(FileRef, u32)>,
+  /// The call depth of the last expression.
+  depth: usize,
+  /// The reason for stopping, reported in the `stopped` event.
+  reason: &'static str,
+}
+
+impl DapDebugger {
+  fn range(&mut self, fsp: &FileSpan) -> Option<[u32; 4]> {
+    let text = match self.files.entry(fsp.file.clone()) {
+      Entry::Occupied(e) => e.into_mut(),
+      Entry::Vacant(e) => e.insert(fs::read_to_string(fsp.file.path()).ok()?.into()),
+    };
+    if fsp.span.end > text.len() { return None }
+    let r = text.to_range(fsp.span);
+    Some([r.start.line + 1, r.start.character + 1, r.end.line + 1, r.end.character + 1])
+  }
+}
+
+impl Debugger for DapDebugger {
+  fn check(&mut self, fsp: &FileSpan, depth: usize) -> bool {
+    let here = match self.range(fsp) { Some(r) => Some((fsp.file.clone(), r[0])), None => return false };
+    let new_line = self.last != here;
+    let state = self.shared.state.ulock();
+    let left = self.start != here;
+    let (stop, reason) = match state.mode {
+      Mode::Continue => (false, ""),
+      Mode::Pause => (true, "pause"),
+      Mode::StepIn => (left, "step"),
+      Mode::StepOver(d) => (left && depth <= d, "step"),
+      Mode::StepOut(d) => (depth < d, "step"),
+    };
+    let bp = new_line && here.as_ref().map_or(false, |(file, line)|
+      state.breakpoints.get(file.path()).map_or(false, |bps| bps.contains(line)));
+    drop(state);
+    self.last = here;
+    self.depth = depth;
+    if stop { self.reason = reason } else if bp { self.reason = "breakpoint" }
+    stop || bp
+  }
+
+  fn pause(&mut self, elab: &Elaborator, frames: &[DebugFrame], locals: &[LispVal]) {
+    let frames = frames.iter().map(|f|
+      (f.name.clone(), f.fsp.file.clone(), self.range(&f.fsp).unwrap_or_default())).collect();
+    let locals = locals.iter().enumerate()
+      .map(|(i, v)| (format!("#{}", i), format!("{}", elab.format_env().pp(v, 80)))).collect();
+    let goals = elab.proof_state();
+    self.start = self.last.clone();
+    let mut state = self.shared.state.ulock();
+    state.paused = Some(Paused {depth: self.depth, frames, locals, goals});
+    drop(state);
+    self.shared.out.ulock().event("stopped",
+      json!({"reason": self.reason, "threadId": 1, "allThreadsStopped": true}));
+    drop(self.shared.resume.wait_while(self.shared.state.ulock(), |s| s.paused.is_some())
+      .expect("poisoned"));
+  }
+}
+
+/// Reads a message from the client, or returns `None` if the connection was closed.
+fn read_message(r: &mut impl BufRead) -> io::Result<Option<Value>> {
+  let mut len = None;
+  loop {
+    let mut line = String::new();
+    if r.read_line(&mut line)? == 0 { return Ok(None) }
+    let line = line.trim_end();
+    if line.is_empty() { break }
+    if let Some(n) = line.strip_prefix("Content-Length:") {
+      len = Some(n.trim().parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?)
+    }
+  }
+  let len = len.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+  let mut buf = vec![0; len];
+  r.read_exact(&mut buf)?;
+  Ok(Some(serde_json::from_slice(&buf)?))
+}
+
+/// Resumes a paused evaluation, in the mode given by a function of the current call depth.
+fn resume(shared: &Shared, mode: impl FnOnce(usize) -> Mode) -> Result<Value, String> {
+  let mut state = shared.state.ulock();
+  let paused = state.paused.take().ok_or("not paused")?;
+  state.mode = mode(paused.depth);
+  drop(state);
+  shared.resume.notify_all();
+  Ok(json!({"allThreadsContinued": true}))
+}
+
+/// Elaborates the file `path` with the debugger attached, on a new thread.
+fn launch(shared: &Arc<Shared>, path: PathBuf) {
+  let shared = shared.clone();
+  std::thread::spawn(move || {
+    let dbg = DapDebugger {
+      shared: shared.clone(), files: HashMap::new(),
+      last: None, start: None, depth: 0, reason: "",
+    };
+    let path: FileRef = path.into();
+    let msg = match crate::compiler::elab_for_debug(path.clone(), Box::new(dbg)) {
+      Ok(errors) => {
+        let text: Option<LinedString> = fs::read_to_string(path.path()).ok().map(Into::into);
+        let mut msg = String::new();
+        for e in errors.iter().flat_map(|errs| errs.iter()) {
+          let pos = text.as_ref().map_or_else(Default::default, |text| text.to_pos(e.pos.start));
+          writeln!(msg, "{}:{}:{}: {}: {}",
+            path.rel(), pos.line + 1, pos.character + 1, e.level, e.kind.msg()).expect("impossible");
+        }
+        msg
+      }
+      Err(e) => format!("{}: {}\n", path.rel(), e),
+    };
+    let mut out = shared.out.ulock();
+    if !msg.is_empty() { out.event("output", json!({"category": "stderr", "output": msg})) }
+    out.event("terminated", json!({}));
+  });
+}
+
+/// Main entry point for `mm0-rs dap` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs dap [--port PORT]`, where:
+///
+/// - `PORT` is the TCP port to listen on for the client (default 4711).
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let port = args.value_of("port").map_or(Ok(4711), str::parse::<u16>)
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+  let listener = TcpListener::bind(("127.0.0.1", port))?;
+  println!("listening on port {}", listener.local_addr()?.port());
+  io::stdout().flush()?;
+  let (stream, _) = listener.accept()?;
+  let shared = Arc::new(Shared {
+    out: Mutex::new(Output {stream: stream.try_clone()?, seq: 0}),
+    state: Mutex::new(State {breakpoints: HashMap::new(), mode: Mode::Continue, paused: None}),
+    resume: Condvar::new(),
+  });
+  let mut reader = BufReader::new(stream);
+  let mut program = None;
+  while let Some(msg) = read_message(&mut reader)? {
+    if msg["type"] != "request" { continue }
+    let command = msg["command"].as_str().unwrap_or_default();
+    let args = &msg["arguments"];
+    let res = match command {
+      "initialize" => Ok(json!({"supportsConfigurationDoneRequest": true})),
+      "launch" => (|| {
+        let path = args["program"].as_str().ok_or("missing 'program'")?;
+        program = Some(fs::canonicalize(path).map_err(|e| format!("{}: {}", path, e))?);
+        if args["noProofs"] == true { crate::set_check_proofs(false) }
+        if args["stopOnEntry"] == true { shared.state.ulock().mode = Mode::Pause }
+        Ok(json!({}))
+      })(),
+      "setBreakpoints" => (|| {
+        let path = args["source"]["path"].as_str().ok_or("missing 'source.path'")?;
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.into());
+        let lines = args["breakpoints"].as_array().map_or_else(Vec::new, |bps|
+          bps.iter().filter_map(|bp| bp["line"].as_u64()?.try_into().ok()).collect());
+        let res = lines.iter().map(|line| json!({"verified": true, "line": line})).collect::<Vec<_>>();
+        shared.state.ulock().breakpoints.insert(path, lines.into_iter().collect());
+        Ok(json!({"breakpoints": res}))
+      })(),
+      "configurationDone" => match program.take() {
+        Some(path) => { launch(&shared, path); Ok(json!({})) }
+        None => Err("no program launched".into()),
+      },
+      "threads" => Ok(json!({"threads": [{"id": 1, "name": "main"}]})),
+      "stackTrace" => match &shared.state.ulock().paused {
+        Some(paused) => {
+          let frames = paused.frames.iter().enumerate().map(|(i, (name, file, r))| json!({
+            "id": i, "name": name, "source": {"path": file.path()},
+            "line": r[0], "column": r[1], "endLine": r[2], "endColumn": r[3],
+          })).collect::<Vec<_>>();
+          Ok(json!({"stackFrames": frames, "totalFrames": paused.frames.len()}))
+        }
+        None => Err("not paused".into()),
+      },
+      "scopes" => {
+        let mut scopes = vec![];
+        if args["frameId"] == 0 {
+          scopes.push(json!({"name": "Locals", "variablesReference": 1, "expensive": false}))
+        }
+        scopes.push(json!({"name": "Proof state", "variablesReference": 2, "expensive": false}));
+        Ok(json!({"scopes": scopes}))
+      }
+      "variables" => match (&shared.state.ulock().paused, args["variablesReference"].as_u64()) {
+        (Some(paused), Some(r @ (1 | 2))) => {
+          let vars = if r == 1 { &paused.locals } else { &paused.goals };
+          let vars = vars.iter().map(|(name, value)|
+            json!({"name": name, "value": value, "variablesReference": 0})).collect::<Vec<_>>();
+          Ok(json!({"variables": vars}))
+        }
+        (Some(_), _) => Err("invalid variablesReference".into()),
+        (None, _) => Err("not paused".into()),
+      },
+      "continue" => resume(&shared, |_| Mode::Continue),
+      "next" => resume(&shared, Mode::StepOver),
+      "stepIn" => resume(&shared, |_| Mode::StepIn),
+      "stepOut" => resume(&shared, Mode::StepOut),
+      "pause" => { shared.state.ulock().mode = Mode::Pause; Ok(json!({})) }
+      "disconnect" | "terminate" => Ok(json!({})),
+      _ => Err(format!("unsupported request '{}'", command)),
+    };
+    let mut out = shared.out.ulock();
+    out.send(match res {
+      Ok(body) => json!({"type": "response", "request_seq": msg["seq"], "success": true,
+        "command": command, "body": body}),
+      Err(e) => json!({"type": "response", "request_seq": msg["seq"], "success": false,
+        "command": command, "message": e}),
+    });
+    match command {
+      "initialize" => out.event("initialized", json!({})),
+      "disconnect" | "terminate" => std::process::exit(0),
+      _ => {}
+    }
+  }
+  Ok(())
+}
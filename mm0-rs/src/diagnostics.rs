@@ -0,0 +1,250 @@
+//! Machine-readable diagnostics, selected with the `--error-format` option of
+//! `mm0-rs compile` and `mm0-rs verify`.
+//!
+//! * `human` (the default) prints each diagnostic as an annotated source snippet.
+//! * `json` prints each diagnostic as a JSON object on its own line, as soon as it is reported:
+//!
+//!   ```json
+//!   {"file": "foo.mm1", "range": {"start": {"line": 3, "column": 5}, "end": {"line": 3, "column": 9}},
+//!    "severity": "warning", "code": "deprecated", "message": "'foo' is deprecated",
+//!    "related": [{"file": "foo.mm1", "range": {...}, "message": "..."}]}
+//!   ```
+//!
+//!   Lines and columns are 1-based, and the end position is exclusive. If the file is not a
+//!   text file, there is a `"bytes": {"start": 16, "end": 32}` field instead of `"range"`,
+//!   and if the diagnostic has no position, neither field is present. The `"code"` is the
+//!   [warning code](crate::elab::warning) of the diagnostic, or `null`.
+//! * `sarif` prints a single [SARIF 2.1.0] log containing all the diagnostics when the
+//!   command finishes.
+//!
+//! At the end of `mm0-rs compile`, the summary of the compilation (see
+//! [`compiler::main`](crate::compiler::main)) is printed in the `json` format as a last line
+//! `{"summary": {...}}`, and in the `sarif` format it is the `summary` property of the run,
+//! whose invocation records the exit code.
+//!
+//! In the `json` and `sarif` formats the progress messages are not printed, so that the
+//! output contains only the diagnostics (and any other output explicitly requested, such
+//! as `--stats`). The diagnostics are printed to stdout, or to stderr if stdout is used for
+//! the output file, as in `mm0-rs compile foo.mm1 -`.
+//!
+//! [SARIF 2.1.0]: https://docs.oasis-open.org/sarif/sarif/v2.1.0/sarif-v2.1.0.html
+
+use std::fmt;
+use std::sync::{Mutex, atomic::{AtomicBool, AtomicU8, Ordering}};
+use serde_json::{json, Value};
+use crate::{ErrorLevel, FileRef, MutexExt, Range, Span};
+
+/// The format of the diagnostics printed by `mm0-rs compile` and `mm0-rs verify`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ErrorFormat {
+  /// Annotated source snippets, for people.
+  Human = 0,
+  /// One JSON object per diagnostic, one per line.
+  Json = 1,
+  /// A SARIF log.
+  Sarif = 2,
+}
+
+impl ErrorFormat {
+  /// Get an error format by name: `human`, `json` or `sarif`.
+  #[must_use] pub fn from_name(s: &str) -> Option<ErrorFormat> {
+    match s {
+      "human" => Some(ErrorFormat::Human),
+      "json" => Some(ErrorFormat::Json),
+      "sarif" => Some(ErrorFormat::Sarif),
+      _ => None,
+    }
+  }
+}
+
+static FORMAT: AtomicU8 = AtomicU8::new(ErrorFormat::Human as u8);
+
+/// If true, diagnostics are printed to stderr instead of stdout.
+static STDERR: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+  /// The SARIF results reported so far, which are printed by [`finish`].
+  static ref SARIF_RESULTS: Mutex<Vec<Value>> = Mutex::new(vec![]);
+  /// The exit code and the summary reported by [`summary`], which are printed by [`finish`].
+  static ref SARIF_SUMMARY: Mutex<Option<(i32, Value)>> = Mutex::new(None);
+}
+
+pub(crate) fn error_format() -> ErrorFormat {
+  match FORMAT.load(Ordering::Relaxed) {
+    1 => ErrorFormat::Json,
+    2 => ErrorFormat::Sarif,
+    _ => ErrorFormat::Human,
+  }
+}
+
+/// Set the format of the diagnostics. In the `json` and `sarif` formats the progress
+/// messages of the compiler are suppressed.
+pub fn set_error_format(fmt: ErrorFormat) {
+  FORMAT.store(fmt as u8, Ordering::Relaxed);
+  if fmt != ErrorFormat::Human { crate::compiler::set_quiet(true) }
+}
+
+/// Print diagnostics to stderr instead of stdout, because stdout is used for the output.
+pub(crate) fn set_stderr(b: bool) { STDERR.store(b, Ordering::Relaxed) }
+
+/// Print a diagnostic or progress message to stdout, or to stderr if stdout is used for
+/// the output.
+pub(crate) fn print(args: fmt::Arguments<'_>) {
+  if STDERR.load(Ordering::Relaxed) { eprint!("{}", args) } else { print!("{}", args) }
+}
+
+/// The location of a diagnostic in its file.
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Region {
+  /// A line/column range in a text file.
+  Text(Range),
+  /// A byte range in a binary file.
+  Bytes(Span),
+  /// The diagnostic is about the whole file.
+  None,
+}
+
+/// A diagnostic message, in a form which does not depend on the output format.
+#[derive(Debug)]
+pub(crate) struct Diagnostic {
+  /// The file the diagnostic is about.
+  pub(crate) file: FileRef,
+  /// The location of the diagnostic in the file.
+  pub(crate) region: Region,
+  /// The severity of the diagnostic.
+  pub(crate) level: ErrorLevel,
+  /// The warning code of the diagnostic, if any.
+  pub(crate) code: Option<&'static str>,
+  /// The message.
+  pub(crate) message: String,
+  /// Related locations, with a message for each.
+  pub(crate) related: Vec<(FileRef, Region, String)>,
+}
+
+fn severity(level: ErrorLevel) -> &'static str {
+  match level {
+    ErrorLevel::Info => "info",
+    ErrorLevel::Warning => "warning",
+    ErrorLevel::Error => "error",
+  }
+}
+
+impl Region {
+  /// Add this region to the JSON object `obj`.
+  fn to_json(self, obj: &mut serde_json::Map<String, Value>) {
+    match self {
+      Region::Text(Range {start, end}) => { obj.insert("range".into(), json!({
+        "start": {"line": start.line + 1, "column": start.character + 1},
+        "end": {"line": end.line + 1, "column": end.character + 1},
+      })); }
+      Region::Bytes(sp) => { obj.insert("bytes".into(), json!({"start": sp.start, "end": sp.end})); }
+      Region::None => {}
+    }
+  }
+
+  /// The SARIF `physicalLocation` of this region in `file`.
+  fn to_sarif(self, file: &FileRef) -> Value {
+    let mut loc = json!({"artifactLocation": {"uri": file.rel()}});
+    match self {
+      Region::Text(Range {start, end}) => loc["region"] = json!({
+        "startLine": start.line + 1, "startColumn": start.character + 1,
+        "endLine": end.line + 1, "endColumn": end.character + 1,
+      }),
+      Region::Bytes(sp) => loc["region"] = json!({"byteOffset": sp.start, "byteLength": sp.end - sp.start}),
+      Region::None => {}
+    }
+    loc
+  }
+}
+
+impl Diagnostic {
+  /// A diagnostic about the file `file` as a whole, with no code or related locations.
+  pub(crate) fn message(file: FileRef, level: ErrorLevel, message: String) -> Self {
+    Diagnostic {file, region: Region::None, level, code: None, message, related: vec![]}
+  }
+
+  /// The diagnostic in the `json` format.
+  fn to_json(&self) -> Value {
+    let mut obj = serde_json::Map::new();
+    obj.insert("file".into(), self.file.rel().into());
+    self.region.to_json(&mut obj);
+    obj.insert("severity".into(), severity(self.level).into());
+    obj.insert("code".into(), self.code.into());
+    obj.insert("message".into(), self.message.clone().into());
+    obj.insert("related".into(), self.related.iter().map(|(file, region, msg)| {
+      let mut obj = serde_json::Map::new();
+      obj.insert("file".into(), file.rel().into());
+      region.to_json(&mut obj);
+      obj.insert("message".into(), msg.clone().into());
+      Value::Object(obj)
+    }).collect());
+    Value::Object(obj)
+  }
+
+  /// The diagnostic as a SARIF `result` object.
+  fn to_sarif(&self) -> Value {
+    let mut res = json!({
+      "level": match self.level {
+        ErrorLevel::Info => "note",
+        ErrorLevel::Warning => "warning",
+        ErrorLevel::Error => "error",
+      },
+      "message": {"text": self.message},
+      "locations": [{"physicalLocation": self.region.to_sarif(&self.file)}],
+    });
+    if let Some(code) = self.code { res["ruleId"] = code.into() }
+    if !self.related.is_empty() {
+      res["relatedLocations"] = self.related.iter().map(|(file, region, msg)| json!({
+        "physicalLocation": region.to_sarif(file),
+        "message": {"text": msg},
+      })).collect();
+    }
+    res
+  }
+}
+
+/// Report a diagnostic in the current (non-`human`) error format.
+pub(crate) fn emit(d: &Diagnostic) {
+  match error_format() {
+    ErrorFormat::Human => unreachable!("human readable diagnostics are printed as snippets"),
+    ErrorFormat::Json => print(format_args!("{}\n", d.to_json())),
+    ErrorFormat::Sarif => SARIF_RESULTS.ulock().push(d.to_sarif()),
+  }
+}
+
+/// Report the summary of the command, which exits with `exit_code`, in the current
+/// (non-`human`) error format.
+pub(crate) fn summary(exit_code: i32, mut summary: Value) {
+  summary["exit_code"] = exit_code.into();
+  match error_format() {
+    ErrorFormat::Human => unreachable!("the human readable summary is printed as a line"),
+    ErrorFormat::Json => print(format_args!("{}\n", json!({"summary": summary}))),
+    ErrorFormat::Sarif => *SARIF_SUMMARY.ulock() = Some((exit_code, summary)),
+  }
+}
+
+/// Print the SARIF log, if the error format is `sarif`. This should be called once,
+/// after all the diagnostics are reported.
+pub(crate) fn finish() {
+  if error_format() == ErrorFormat::Sarif {
+    let results = std::mem::take(&mut *SARIF_RESULTS.ulock());
+    let mut run = json!({
+      "tool": {"driver": {
+        "name": "mm0-rs",
+        "informationUri": "https://github.com/digama0/mm0",
+        "rules": crate::WarningCode::ALL.iter().map(|c| json!({"id": c.name()})).collect::<Vec<_>>(),
+      }},
+      "results": results,
+    });
+    let summary = SARIF_SUMMARY.ulock().take();
+    if let Some((exit_code, summary)) = summary {
+      run["invocations"] = json!([{"executionSuccessful": true, "exitCode": exit_code}]);
+      run["properties"] = json!({"summary": summary});
+    }
+    print(format_args!("{:#}\n", json!({
+      "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+      "version": "2.1.0",
+      "runs": [run],
+    })))
+  }
+}
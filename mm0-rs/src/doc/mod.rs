@@ -1,15 +1,14 @@
 //! Build documentation pages for MM1/MM0 files
 use std::{collections::{hash_map::Entry, HashMap}, hash::Hash, path::PathBuf};
-use bit_set::BitSet;
 use clap::ArgMatches;
 use lsp_types::Url;
 use pulldown_cmark::escape::WriteWrapper;
 use std::fs::{self, File};
 use std::io::{self, BufWriter, Write};
 use std::mem;
-use crate::{lisp::pretty::Annot, ArcString, AtomData, AtomId, DeclKey, DocComment, EnvMergeIter,
-  Environment, ExprNode, FileRef, FormatEnv, LinedString, LispVal, Proof, ProofNode, SliceUninit,
-  StmtTrace, TermId, Thm, ThmId, ThmKind, Type};
+use crate::{axiom_use::AxiomUse, lisp::pretty::Annot, ArcString, AtomData, AtomId, DeclKey, DocComment, EnvMergeIter,
+  Environment, ExprNode, FileRef, FormatEnv, FrozenEnv, LinedString, LispVal, Proof, ProofNode, SliceUninit,
+  SortId, StmtTrace, TermId, TermKind, Thm, ThmId, ThmKind, Type};
 
 const PP_WIDTH: usize = 160;
 
@@ -59,32 +58,15 @@ impl<'a, 'b, W: Write> pretty::RenderAnnotated<'b, Annot> for HtmlPrinter<'a, W>
       Annot::Visibility(_) => tag!(span "vis"),
       Annot::Keyword => tag!(span "kw"),
       Annot::SortName(sid) => {
-        write!(self.w.0, "<a class=\"sortname\" href=\"{}index.html#", self.rel)?;
-        let ad = &self.env.data[self.env.sorts[sid].atom];
-        disambiguated_anchor(&mut self.w.0, ad, false)?;
-        write!(self.w.0, "\">")?;
+        self.mangler.link(self.env, &mut self.w.0, self.rel, sid)?;
         self.stack.push("a");
       }
       Annot::TermName(tid) => {
-        let kind = match &self.env.terms[tid].kind {
-          crate::elab::environment::TermKind::Term => "term",
-          crate::elab::environment::TermKind::Def(_) => "def"
-        };
-        write!(self.w.0, "<a class=\"{}\" href=\"{}index.html#", kind, self.rel)?;
-        let ad = &self.env.data[self.env.terms[tid].atom];
-        disambiguated_anchor(&mut self.w.0, ad, false)?;
-        write!(self.w.0, "\">")?;
+        self.mangler.link(self.env, &mut self.w.0, self.rel, tid)?;
         self.stack.push("a");
       }
       Annot::ThmName(tid) => {
-        let w = &mut *self.w.0;
-        let rel = self.rel;
-        let kind = match &self.env.thms[tid].kind {
-          ThmKind::Axiom => "ax",
-          ThmKind::Thm(_) => "thm"
-        };
-        self.mangler.mangle(self.env, tid, |_, mangled|
-          write!(w, r#"<a class="{}" href="{}thms/{}.html">"#, kind, rel, mangled))?;
+        self.mangler.link(self.env, &mut self.w.0, self.rel, tid)?;
         self.stack.push("a");
       }
     }
@@ -98,64 +80,6 @@ impl<'a, 'b, W: Write> pretty::RenderAnnotated<'b, Annot> for HtmlPrinter<'a, W>
   }
 }
 
-struct AxiomUse(HashMap<ThmId, BitSet>);
-
-impl AxiomUse {
-  fn new(env: &Environment) -> (Vec<ThmId>, Self) {
-    let mut axuse = HashMap::new();
-    let mut to_tid = vec![ThmId(u32::MAX)];
-    for (tid, td) in env.thms.enum_iter() {
-      if let ThmKind::Axiom = td.kind {
-        let axid = to_tid.len();
-        to_tid.push(tid);
-        let mut bs = BitSet::new();
-        bs.insert(axid);
-        axuse.insert(tid, bs);
-      }
-    }
-    (to_tid, AxiomUse(axuse))
-  }
-
-  fn accumulate(&mut self, env: &Environment, bs: &mut BitSet, node: &ProofNode) {
-    match node {
-      ProofNode::Ref(_) |
-      ProofNode::Dummy(_, _) |
-      ProofNode::Term {..} |
-      ProofNode::Hyp(_, _) |
-      ProofNode::Refl(_) |
-      ProofNode::Sym(_) |
-      ProofNode::Cong {..} |
-      ProofNode::Unfold {..} => {}
-      ProofNode::Conv(p) => self.accumulate(env, bs, &p.2),
-      &ProofNode::Thm {thm: tid, ref args, ..} => {
-        bs.union_with(self.get(env, tid));
-        for p in &**args { self.accumulate(env, bs, p) }
-      }
-    }
-  }
-
-  fn get<'a, 'b>(&'a mut self, env: &'b Environment, tid: ThmId) -> &'a BitSet {
-    if let Some(bs) = self.0.get(&tid) {
-      // Safety: This is the same issue that comes up in Spans::insert.
-      // We are performing a lifetime cast here because rust can't see that
-      // in the None case it is safe to drop the borrow of `self.axuse`.
-      #[allow(clippy::useless_transmute, clippy::transmute_ptr_to_ptr)]
-      return unsafe { std::mem::transmute(bs) }
-    }
-    let mut bs = BitSet::new();
-    let td = &env.thms[tid];
-    match &td.kind {
-      ThmKind::Axiom => unreachable!(),
-      ThmKind::Thm(None) => {bs.insert(0);}
-      ThmKind::Thm(Some(Proof {heap, head, ..})) => {
-        for p in &heap[td.args.len()..] { self.accumulate(env, &mut bs, p) }
-        self.accumulate(env, &mut bs, head)
-      }
-    }
-    self.0.entry(tid).or_insert(bs)
-  }
-}
-
 #[derive(Debug, Clone)]
 enum LineKind {
   Hyp(Option<AtomId>),
@@ -322,10 +246,9 @@ fn render_line<'a>(fe: FormatEnv<'_>, mangler: &'a mut Mangler, w: &mut impl Wri
       let mut first = true;
       for &def in &*defs {
         if !mem::take(&mut first) { write!(w, ",")? }
-        write!(w, " <a class=\"def\" href=\"../index.html#")?;
-        let ad = &fe.env.data[fe.env.terms[def].atom];
-        disambiguated_anchor(w, ad, false)?;
-        write!(w, "\">{}</a>", ad.name)?;
+        write!(w, " ")?;
+        mangler.link(fe.env, w, "../", def)?;
+        write!(w, "{}</a>", fe.env.data[fe.env.terms[def].atom].name)?;
       }
     }
   }
@@ -395,33 +318,95 @@ impl PartialEq for CaseInsensitiveName {
 }
 impl Eq for CaseInsensitiveName {}
 
-#[derive(Clone, Copy)]
-enum ProofOrder { Pre, Post }
+/// The order in which the steps of a proof are listed on a theorem page.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum ProofOrder {
+  /// Each step is listed before the steps it uses.
+  Pre,
+  /// Each step is listed after the steps it uses.
+  Post,
+}
 
 struct BuildDoc<'a, W> {
-  thm_folder: PathBuf,
+  folder: PathBuf,
   source: &'a LinedString,
   base_url: Option<Url>,
   env: Environment,
-  axuse: (Vec<ThmId>, AxiomUse),
+  axuse: AxiomUse,
   index: Option<W>,
   mangler: Mangler,
   order: ProofOrder,
 }
 
+/// A declaration with its own documentation page.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Page { Sort(SortId), Term(TermId), Thm(ThmId) }
+
+impl From<SortId> for Page { fn from(s: SortId) -> Self { Page::Sort(s) } }
+impl From<TermId> for Page { fn from(t: TermId) -> Self { Page::Term(t) } }
+impl From<ThmId> for Page { fn from(t: ThmId) -> Self { Page::Thm(t) } }
+impl From<DeclKey> for Page {
+  fn from(k: DeclKey) -> Self {
+    match k {
+      DeclKey::Term(t) => Page::Term(t),
+      DeclKey::Thm(t) => Page::Thm(t),
+    }
+  }
+}
+
+impl Page {
+  fn atom(self, env: &Environment) -> AtomId {
+    match self {
+      Page::Sort(s) => env.sorts[s].atom,
+      Page::Term(t) => env.terms[t].atom,
+      Page::Thm(t) => env.thms[t].atom,
+    }
+  }
+
+  /// The folder containing the page, relative to the root of the documentation.
+  fn folder(self) -> &'static str {
+    match self {
+      Page::Sort(_) => "sorts",
+      Page::Term(_) => "terms",
+      Page::Thm(_) => "thms",
+    }
+  }
+
+  /// The CSS class for links to this page.
+  fn class(self, env: &Environment) -> &'static str {
+    match self {
+      Page::Sort(_) => "sortname",
+      Page::Term(t) => if let TermKind::Term = env.terms[t].kind {"term"} else {"def"},
+      Page::Thm(t) => if let ThmKind::Axiom = env.thms[t].kind {"ax"} else {"thm"},
+    }
+  }
+
+  /// The next declaration of the same kind, in declaration order.
+  fn next(self, env: &Environment) -> Option<Page> {
+    match self {
+      Page::Sort(s) => s.0.checked_add(1).map(SortId)
+        .filter(|&s| env.sorts.get(s).is_some()).map(Page::Sort),
+      Page::Term(t) => t.0.checked_add(1).map(TermId)
+        .filter(|&t| env.terms.get(t).is_some()).map(Page::Term),
+      Page::Thm(t) => t.0.checked_add(1).map(ThmId)
+        .filter(|&t| env.thms.get(t).is_some()).map(Page::Thm),
+    }
+  }
+}
+
 #[derive(Default)]
 struct Mangler {
-  used: HashMap<CaseInsensitiveName, usize>,
-  thms: HashMap<ThmId, usize>,
+  used: HashMap<(&'static str, CaseInsensitiveName), usize>,
+  pages: HashMap<Page, usize>,
 }
 impl Mangler {
-  fn get(&mut self, env: &Environment, tid: ThmId) -> usize {
-    match self.thms.entry(tid) {
+  fn get(&mut self, env: &Environment, p: Page) -> usize {
+    match self.pages.entry(p) {
       Entry::Occupied(e) => *e.get(),
       Entry::Vacant(e) => {
-        let n = match self.used.entry(CaseInsensitiveName(
-          env.data[env.thms[tid].atom].name.clone()
-        )) {
+        let n = match self.used.entry((p.folder(), CaseInsensitiveName(
+          env.data[p.atom(env)].name.clone()
+        ))) {
           Entry::Occupied(mut e) => {
             let p = e.get_mut();
             let n = *p + 1;
@@ -434,13 +419,20 @@ impl Mangler {
       }
     }
   }
-  fn mangle<T>(&mut self, env: &Environment, tid: ThmId, f: impl FnOnce(&str, &str) -> T) -> T {
-    let s = env.data[env.thms[tid].atom].name.as_str();
-    match self.get(env, tid) {
+  fn mangle<T>(&mut self, env: &Environment, p: impl Into<Page>, f: impl FnOnce(&str, &str) -> T) -> T {
+    let p = p.into();
+    let s = env.data[p.atom(env)].name.as_str();
+    match self.get(env, p) {
       0 => f(s, s),
       n => f(s, &format!("{}.{}", s, n))
     }
   }
+  /// Write the opening tag of a link to the page of `p`, from a page in folder `rel`.
+  fn link(&mut self, env: &Environment, w: &mut impl Write, rel: &str, p: impl Into<Page>) -> io::Result<()> {
+    let p = p.into();
+    self.mangle(env, p, |_, mangled| write!(w, r#"<a class="{}" href="{}{}/{}.html">"#,
+      p.class(env), rel, p.folder(), mangled))
+  }
 }
 
 fn header(w: &mut impl Write,
@@ -496,31 +488,58 @@ fn disambiguated_anchor(w: &mut impl Write, ad: &AtomData, sort: bool) -> io::Re
   }
 }
 
+/// Write the list of links to the declarations in `uses`, under the heading `title`.
+fn render_uses(env: &Environment, mangler: &mut Mangler, w: &mut impl Write,
+  title: &str, uses: &mut Vec<Page>
+) -> io::Result<()> {
+  if uses.is_empty() { return Ok(()) }
+  uses.sort_unstable_by_key(|&p| match p {
+    Page::Sort(s) => (0, s.0.into()),
+    Page::Term(t) => (1, t.0),
+    Page::Thm(t) => (2, t.0),
+  });
+  uses.dedup();
+  writeln!(w, "    <h2 class=\"uses\">{}</h2>", title)?;
+  let mut first = true;
+  for &p in &*uses {
+    if !mem::take(&mut first) { writeln!(w, ",")? }
+    write!(w, "    ")?;
+    mangler.link(env, w, "../", p)?;
+    write!(w, "{}</a>", env.data[p.atom(env)].name)?
+  }
+  writeln!(w)
+}
+
 impl<'a, W: Write> BuildDoc<'a, W> {
-  fn thm_doc(&mut self, prev: Option<ThmId>, tid: ThmId, next: Option<ThmId>) -> io::Result<()> {
-    let mut file = self.thm_folder.clone();
-    #[allow(clippy::useless_transmute)]
-    let td: &Thm = unsafe { mem::transmute(&self.env.thms[tid]) };
-    self.mangler.mangle(&self.env, tid, |_, s| file.push(&format!("{}.html", s)));
-    let mut file = BufWriter::new(File::create(file)?);
-    let ad = &self.env.data[td.atom];
-    let thmname = &ad.name;
-    let filename = td.span.file.rel();
+  /// Create the page file for `p`, and build the navigation bar for it, with links to
+  /// the `prev` and `next` pages, the index, and the source.
+  fn page(&mut self, prev: Option<Page>, p: Page, next: Option<Page>
+  ) -> io::Result<(BufWriter<File>, String)> {
+    let mut file = self.folder.clone();
+    file.push(p.folder());
+    self.mangler.mangle(&self.env, p, |_, s| file.push(&format!("{}.html", s)));
+    let file = BufWriter::new(File::create(file)?);
+    let (span, full) = match p {
+      Page::Sort(s) => (&self.env.sorts[s].span, self.env.sorts[s].full),
+      Page::Term(t) => (&self.env.terms[t].span, self.env.terms[t].full),
+      Page::Thm(t) => (&self.env.thms[t].span, self.env.thms[t].full),
+    };
     let mut nav = String::new();
     if let Some(prev) = prev {
       use std::fmt::Write;
-      self.mangler.mangle(&self.env, prev, |thm, mangled|
-        write!(&mut nav, r#"<a href="{}.html" title="{}">&#8810;</a> | "#, mangled, thm)
+      self.mangler.mangle(&self.env, prev, |name, mangled|
+        write!(&mut nav, r#"<a href="{}.html" title="{}">&#8810;</a> | "#, mangled, name)
           .expect("writing to a string"));
     }
     nav.push_str("<a href=\"../index.html#");
-    disambiguated_anchor(unsafe {nav.as_mut_vec()}, ad, true)?;
+    disambiguated_anchor(unsafe {nav.as_mut_vec()}, &self.env.data[p.atom(&self.env)],
+      matches!(p, Page::Sort(_)))?;
     nav.push_str("\">index</a>");
     if let Some(base) = &self.base_url {
       use std::fmt::Write;
-      let url = base.join(filename).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+      let url = base.join(span.file.rel()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
       write!(&mut nav, " | <a href=\"{}", url).expect("writing to a string");
-      let range = self.source.to_range(td.full);
+      let range = self.source.to_range(full);
       if range.start.line == range.end.line {
         write!(&mut nav, "#L{}\">src</a>", range.start.line + 1)
       } else {
@@ -529,10 +548,65 @@ impl<'a, W: Write> BuildDoc<'a, W> {
     }
     if let Some(next) = next {
       use std::fmt::Write;
-      self.mangler.mangle(&self.env, next, |thm, mangled|
-        write!(&mut nav, r#" | <a href="{}.html" title="{}">&#8811;</a>"#, mangled, thm)
+      self.mangler.mangle(&self.env, next, |name, mangled|
+        write!(&mut nav, r#" | <a href="{}.html" title="{}">&#8811;</a>"#, mangled, name)
           .expect("writing to a string"));
     }
+    Ok((file, nav))
+  }
+
+  fn sort_doc(&mut self, prev: Option<Page>, sid: SortId, next: Option<Page>) -> io::Result<()> {
+    let (mut file, nav) = self.page(prev, Page::Sort(sid), next)?;
+    let sd = &self.env.sorts[sid];
+    let filename = sd.span.file.rel();
+    header(&mut file, "../",
+      &format!("Documentation for sort `{}` in `{}`.", sd.name, filename),
+      &format!("{} - {}", sd.name, filename),
+      &format!(r#"Sort <a class="sort" href="">{}</a>"#, sd.name),
+      &nav, &[])?;
+    render_doc(&mut file, &sd.doc)?;
+    write!(file, "    <pre>")?;
+    let fe = FormatEnv {source: self.source, env: &self.env};
+    let w = &mut HtmlPrinter::new(fe.env, &mut self.mangler, &mut file, "../");
+    fe.pretty(|pr| pr.sort(sid).render_raw(PP_WIDTH, w))?;
+    writeln!(file, "</pre>")?;
+    let mut terms = self.env.terms.enum_iter()
+      .filter(|(_, td)| td.ret.0 == sid).map(|(t, _)| Page::Term(t)).collect();
+    render_uses(&self.env, &mut self.mangler, &mut file, "Terms", &mut terms)?;
+    writeln!(file, "{}", FOOTER)
+  }
+
+  fn term_doc(&mut self, prev: Option<Page>, tid: TermId, next: Option<Page>) -> io::Result<()> {
+    let (mut file, nav) = self.page(prev, Page::Term(tid), next)?;
+    let td = &self.env.terms[tid];
+    let termname = &self.env.data[td.atom].name;
+    let filename = td.span.file.rel();
+    let kind = if let TermKind::Term = td.kind {"Term"} else {"Definition"};
+    header(&mut file, "../",
+      &format!("Documentation for term `{}` in `{}`.", termname, filename),
+      &format!("{} - {}", termname, filename),
+      &format!(r#"{} <a class="{}" href="">{}</a>"#, kind, Page::Term(tid).class(&self.env), termname),
+      &nav, &[])?;
+    render_doc(&mut file, &td.doc)?;
+    write!(file, "    <pre>")?;
+    let fe = FormatEnv {source: self.source, env: &self.env};
+    let w = &mut HtmlPrinter::new(fe.env, &mut self.mangler, &mut file, "../");
+    fe.pretty(|pr| pr.term(tid, true).render_raw(PP_WIDTH, w))?;
+    writeln!(file, "</pre>")?;
+    let mut uses = vec![];
+    for &(_, ty) in &*td.args { uses.push(Page::Sort(ty.sort())) }
+    uses.push(Page::Sort(td.ret.0));
+    td.for_each_dep(|k| uses.push(k.into()));
+    render_uses(&self.env, &mut self.mangler, &mut file, "Uses", &mut uses)?;
+    writeln!(file, "{}", FOOTER)
+  }
+
+  fn thm_doc(&mut self, prev: Option<Page>, tid: ThmId, next: Option<Page>) -> io::Result<()> {
+    let (mut file, nav) = self.page(prev, Page::Thm(tid), next)?;
+    #[allow(clippy::useless_transmute)]
+    let td: &Thm = unsafe { mem::transmute(&self.env.thms[tid]) };
+    let thmname = &self.env.data[td.atom].name;
+    let filename = td.span.file.rel();
     let (kind, kindclass) = if let ThmKind::Axiom = td.kind {("Axiom", "ax")} else {("Theorem", "thm")};
     header(&mut file, "../",
       &format!("Documentation for theorem `{}` in `{}`.", thmname, filename),
@@ -540,7 +614,11 @@ impl<'a, W: Write> BuildDoc<'a, W> {
       &format!(r#"{} <a class="{}" href="">{}</a>"#, kind, kindclass, thmname),
       &nav, &["../proof.js"])?;
     render_doc(&mut file, &td.doc)?;
-    writeln!(file, "    <pre>{}</pre>", FormatEnv {source: self.source, env: &self.env}.to(td))?;
+    write!(file, "    <pre>")?;
+    let fe = FormatEnv {source: self.source, env: &self.env};
+    let w = &mut HtmlPrinter::new(fe.env, &mut self.mangler, &mut file, "../");
+    fe.pretty(|pr| pr.thm(tid).render_raw(PP_WIDTH, w))?;
+    writeln!(file, "</pre>")?;
     if let ThmKind::Thm(Some(pf)) = &td.kind {
       writeln!(file, "    \
               <table class=\"proof\">\
@@ -552,17 +630,18 @@ impl<'a, W: Write> BuildDoc<'a, W> {
         &mut file, self.order, &td.args, &td.hyps, pf)?;
       writeln!(file, "      </tbody>\n    </table>")?;
     }
+    let mut uses = vec![];
+    for &(_, ty) in &*td.args { uses.push(Page::Sort(ty.sort())) }
+    td.for_each_dep(|k| uses.push(k.into()));
+    render_uses(&self.env, &mut self.mangler, &mut file, "Uses", &mut uses)?;
     if let ThmKind::Thm(_) = td.kind {
       writeln!(file, "    <h2 class=\"axioms\">Axiom use</h2>")?;
-      let mut first = true;
-      for i in self.axuse.1.get(&self.env, tid) {
+      let mut first = !self.axuse.has_holes(tid);
+      if !first { write!(file, "<i>sorry</i>")? }
+      for ax in self.axuse.axioms(tid) {
         if !mem::take(&mut first) { writeln!(file, ",")? }
-        if i == 0 {
-          write!(file, "<i>sorry</i>")?
-        } else {
-          self.mangler.mangle(&self.env, self.axuse.0[i], |thm, mangled|
-            write!(file, r#"    <a class="ax" href="{}.html">{}</a>"#, mangled, thm))?
-        }
+        self.mangler.mangle(&self.env, ax, |thm, mangled|
+          write!(file, r#"    <a class="ax" href="{}.html">{}</a>"#, mangled, thm))?
       }
       writeln!(file)?
     }
@@ -581,7 +660,7 @@ impl<'a, W: Write> BuildDoc<'a, W> {
       &format!("Documentation index for `{}`.", path.rel()),
       &format!("{} - Index", path.rel()),
       "Index", nav, &[])?;
-    let mut prev = None;
+    let (mut prev_sort, mut prev_term, mut prev_thm) = (None, None, None);
     for s in stmts {
       let mut file = self.index.as_mut().expect("index file missing");
       let fe = FormatEnv {source: self.source, env: &self.env};
@@ -599,7 +678,9 @@ impl<'a, W: Write> BuildDoc<'a, W> {
           writeln!(file, "      <pre>")?;
           let w = &mut HtmlPrinter::new(fe.env, &mut self.mangler, file, "");
           fe.pretty(|pr| pr.sort(sid).render_raw(PP_WIDTH, w))?;
-          writeln!(file, "</pre>\n    </div>")?
+          writeln!(file, "</pre>\n    </div>")?;
+          self.sort_doc(prev_sort, sid, Page::Sort(sid).next(&self.env))?;
+          prev_sort = Some(Page::Sort(sid));
         }
         StmtTrace::Decl(a) => {
           let ad = &self.env.data[a];
@@ -613,7 +694,9 @@ impl<'a, W: Write> BuildDoc<'a, W> {
               write!(file, "      <pre>")?;
               let w = &mut HtmlPrinter::new(fe.env, &mut self.mangler, file, "");
               fe.pretty(|pr| pr.term(tid, true).render_raw(PP_WIDTH, w))?;
-              writeln!(file, "</pre>\n    </div>")?
+              writeln!(file, "</pre>\n    </div>")?;
+              self.term_doc(prev_term, tid, Page::Term(tid).next(&self.env))?;
+              prev_term = Some(Page::Term(tid));
             }
             DeclKey::Thm(tid) => {
               let td = &self.env.thms[tid];
@@ -622,10 +705,8 @@ impl<'a, W: Write> BuildDoc<'a, W> {
               let w = &mut HtmlPrinter::new(fe.env, &mut self.mangler, file, "");
               fe.pretty(|pr| pr.thm(tid).render_raw(PP_WIDTH, w))?;
               writeln!(file, "</pre>\n    </div>")?;
-              let next = tid.0.checked_add(1).map(ThmId)
-                .filter(|&tid| self.env.thms.get(tid).is_some());
-              self.thm_doc(prev, tid, next)?;
-              prev = Some(tid);
+              self.thm_doc(prev_thm, tid, Page::Thm(tid).next(&self.env))?;
+              prev_thm = Some(Page::Thm(tid));
             }
           }
         }
@@ -643,16 +724,39 @@ impl<'a, W: Write> BuildDoc<'a, W> {
 ///
 /// - `in.mm1` is the initial file to elaborate.
 /// - `doc` is the output folder, which will be created if not present.
+///
+/// The output consists of `index.html`, which lists every declaration in order, and one
+/// page for each sort, term and theorem in the `sorts/`, `terms/` and `thms/` subfolders,
+/// which shows its statement, documentation, proof (for theorems), and links to the
+/// declarations it uses.
 pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
   let path = args.value_of("INPUT").expect("required arg");
   let path: FileRef = fs::canonicalize(path)?.into();
   let (fc, old) = crate::compiler::elab_for_result(path.clone())?;
   let old = old.unwrap_or_else(|| std::process::exit(1));
+  let dir = PathBuf::from(args.value_of("OUTPUT").unwrap_or("doc"));
+  let order = match args.value_of("order") {
+    Some("pre") => ProofOrder::Pre,
+    Some("post") => ProofOrder::Post,
+    _ => unreachable!(),
+  };
+  let base_url = match args.value_of("src") {
+    Some("-") => None,
+    src => Some(Url::parse(src.unwrap_or("https://github.com/digama0/mm0/blob/master/examples/"))
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?),
+  };
+  write_docs(&path, fc.ascii(), &old, dir, order, args.value_of("only"), base_url)
+}
+
+/// Write the documentation pages for the environment `old` of the file at `path` with
+/// contents `source` to the folder `dir`, as described in [`main`]. If `only` is set, only
+/// the pages of the listed theorems are written. Source links are relative to `base_url`.
+pub(crate) fn write_docs(path: &FileRef, source: &LinedString, old: &FrozenEnv, dir: PathBuf,
+    order: ProofOrder, only: Option<&str>, base_url: Option<Url>) -> io::Result<()> {
   println!("writing docs");
   let mut env = Environment::new();
   assert!(matches!(
-    EnvMergeIter::new(&mut env, &old, (0..0).into()).next(&mut env, &mut vec![]), Ok(None)));
-  let mut dir = PathBuf::from(args.value_of("OUTPUT").unwrap_or("doc"));
+    EnvMergeIter::new(&mut env, old, (0..0).into()).next(&mut env, &mut vec![]), Ok(None)));
   fs::create_dir_all(&dir)?;
   macro_rules! import {($($str:expr),*) => {$({
     let mut file = dir.to_owned();
@@ -662,29 +766,19 @@ pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
     }
   })*}}
   import!("stylesheet.css", "proof.js");
-  let order = match args.value_of("order") {
-    Some("pre") => ProofOrder::Pre,
-    Some("post") => ProofOrder::Post,
-    _ => unreachable!(),
-  };
-  let only = args.value_of("only");
   let index = if only.is_some() {None} else {
     let mut file = dir.clone();
     file.push("index.html");
     Some(BufWriter::new(File::create(file)?))
   };
-  dir.push("thms");
-  fs::create_dir_all(&dir)?;
-  let base_url = match args.value_of("src") {
-    Some("-") => None,
-    src => Some(Url::parse(src.unwrap_or("https://github.com/digama0/mm0/blob/master/examples/"))
-      .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?),
-  };
+  for folder in ["sorts", "terms", "thms"] {
+    fs::create_dir_all(dir.join(folder))?;
+  }
   let mut bd = BuildDoc {
-    source: fc.ascii(),
+    source,
     base_url, order,
-    axuse: AxiomUse::new(&env),
-    thm_folder: dir, env, index,
+    axuse: old.axiom_use(),
+    folder: dir, env, index,
     mangler: Mangler::default(),
   };
   if let Some(only) = only {
@@ -699,10 +793,10 @@ pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
         None
       }).collect::<Vec<_>>();
     for (i, &tid) in thms.iter().enumerate() {
-      bd.thm_doc(i.checked_sub(1).map(|j| thms[j]), tid, thms.get(i+1).copied())?;
+      bd.thm_doc(i.checked_sub(1).map(|j| thms[j].into()), tid, thms.get(i+1).map(|&t| t.into()))?;
     }
   } else {
-    bd.write_all(&path, old.stmts())?;
+    bd.write_all(path, old.stmts())?;
   }
   Ok(())
 }
\ No newline at end of file
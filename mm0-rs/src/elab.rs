@@ -15,12 +15,25 @@ pub mod local_context;
 pub mod refine;
 pub mod proof;
 pub mod inout;
+pub mod snapshot;
+pub mod profile;
+pub mod cc;
+pub mod lia;
+pub mod sat;
+pub mod simp;
+pub mod smt;
+pub mod auto;
+pub mod instance;
+pub mod minimize;
+pub mod namespace;
+pub mod attr;
+pub mod import;
+pub mod warning;
 
 
 use std::collections::HashMap;
 use std::mem;
 use std::ops::{Deref, DerefMut};
-use std::path::PathBuf;
 use std::sync::{atomic::{AtomicBool, Ordering}, Arc};
 use std::{future::Future, pin::Pin, task::{Context, Poll}};
 use std::time::{Duration, Instant};
@@ -30,16 +43,19 @@ use spans::Spans;
 use crate::ast::{self, Ast, DeclKind, Delimiter, GenNota, Literal as ALiteral,
   LocalKind, SExpr, SExprKind, SimpleNota, SimpleNotaKind, Stmt, StmtKind};
 use inout::InoutHandlers;
+use snapshot::Snapshots;
+use profile::{LispProfiler, Profiler, StmtProfile};
 use environment::Literal as ELiteral;
-use lisp::LispVal;
+use lisp::{LispVal, eval::{Debugger, EvalLimits}};
 use local_context::try_get_span_opt;
+use warning::{WarningCode, WarningConfig};
 use crate::{ArcList, ArcString, AtomId, BoxError, Coe, DeclKey, DocComment, EnvMergeIter,
   Environment, ErrorLevel, Expr, ExprNode, FileRef, FileSpan, FrozenEnv,
   FrozenLispVal, LocalContext, Modifiers, NotaInfo, ObjectKind, Prec,
   Proof, ProofNode, Remap, Remapper, SortId, Span, Term, TermId, Thm, ThmId};
 
 #[cfg(feature = "server")]
-use {crate::LinedString, lsp_types::{Diagnostic, DiagnosticRelatedInformation, Location}};
+use {crate::LinedString, lsp_types::{Diagnostic, DiagnosticRelatedInformation, Location, NumberOrString}};
 
 /// An error payload.
 #[derive(Debug, DeepSizeOf)]
@@ -98,6 +114,18 @@ impl ElabErrorKind {
   }
 }
 
+impl Clone for ElabErrorKind {
+  /// Clones the error. Because [`BoxError`] is not cloneable,
+  /// a boxed error is copied as its error message.
+  fn clone(&self) -> Self {
+    match self {
+      ElabErrorKind::Boxed(e, info) => ElabErrorKind::Boxed(e.to_string().into(),
+        info.as_ref().map(|info| info.iter().map(|(fsp, e)| (fsp.clone(), e.to_string().into())).collect())),
+      ElabErrorKind::Upstream(file, e, n) => ElabErrorKind::Upstream(file.clone(), e.clone(), *n),
+    }
+  }
+}
+
 impl From<BoxError> for ElabErrorKind {
   fn from(e: BoxError) -> ElabErrorKind { ElabErrorKind::Boxed(e, None) }
 }
@@ -105,7 +133,7 @@ impl From<BoxError> for ElabErrorKind {
 /// The main error type for the elaborator. Each error has a location (which must be in
 /// the currently elaborating file), an error level, a message, and an optional list of
 /// related locations (possibly in other files) along with short messages.
-#[derive(Debug, DeepSizeOf)]
+#[derive(Clone, Debug, DeepSizeOf)]
 pub struct ElabError {
   /// The location of the error in the current file.
   pub pos: Span,
@@ -113,6 +141,8 @@ pub struct ElabError {
   pub level: ErrorLevel,
   /// The type of error (currently there is only [`ElabErrorKind::Boxed`])
   pub kind: ElabErrorKind,
+  /// The warning code, for warnings which can be configured (see [`warning`]).
+  pub code: Option<WarningCode>,
 }
 
 /// The main result type used by functions in the elaborator.
@@ -122,7 +152,7 @@ impl ElabError {
 
   /// Make an elaboration error from a position and an [`ElabErrorKind`].
   pub fn new(pos: impl Into<Span>, kind: ElabErrorKind) -> ElabError {
-    ElabError { pos: pos.into(), level: ErrorLevel::Error, kind }
+    ElabError { pos: pos.into(), level: ErrorLevel::Error, kind, code: None }
   }
 
   /// Make an elaboration error from a position and anything that can be converted to a [`BoxError`].
@@ -137,12 +167,17 @@ impl ElabError {
 
   /// Make an elaboration warning from a position and a message.
   pub fn warn(pos: impl Into<Span>, e: impl Into<BoxError>) -> ElabError {
-    ElabError { pos: pos.into(), level: ErrorLevel::Warning, kind: ElabErrorKind::Boxed(e.into(), None)}
+    ElabError { pos: pos.into(), level: ErrorLevel::Warning, kind: ElabErrorKind::Boxed(e.into(), None), code: None }
+  }
+
+  /// Make an elaboration warning with a warning code from a position and a message.
+  pub fn lint(code: WarningCode, pos: impl Into<Span>, e: impl Into<BoxError>) -> ElabError {
+    ElabError { code: Some(code), ..ElabError::warn(pos, e) }
   }
 
   /// Make an info message at a position
   pub fn info(pos: impl Into<Span>, e: impl Into<BoxError>) -> ElabError {
-    ElabError { pos: pos.into(), level: ErrorLevel::Info, kind: ElabErrorKind::Boxed(e.into(), None)}
+    ElabError { pos: pos.into(), level: ErrorLevel::Info, kind: ElabErrorKind::Boxed(e.into(), None), code: None }
   }
 
   /// Convert an [`ElabError`] into the LSP [`Diagnostic`] type.
@@ -153,7 +188,7 @@ impl ElabError {
     Diagnostic {
       range: file.to_range(self.pos),
       severity: Some(self.level.to_diag_severity()),
-      code: None,
+      code: self.code.map(|c| NumberOrString::String(c.name().into())),
       code_description: None,
       source: Some("mm0-rs".to_owned()),
       message: self.kind.msg(),
@@ -166,7 +201,7 @@ impl ElabError {
 
 impl From<mm1_parser::ParseError> for ElabError {
   fn from(e: mm1_parser::ParseError) -> Self {
-    ElabError {pos: e.pos, level: e.level, kind: ElabErrorKind::Boxed(e.msg, None) }
+    ElabError {pos: e.pos, level: e.level, kind: ElabErrorKind::Boxed(e.msg, None), code: None }
   }
 }
 
@@ -177,7 +212,7 @@ impl From<mm0b_parser::ParseError> for ElabError {
 /// Records the current reporting setting. A report that is suppressed by the reporting mode
 /// will not appear in the error list / as a diagnostic, but a fatal error will still prevent
 /// proof export.
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 struct ReportMode {
   /// Do we report on errors?
   error: bool,
@@ -216,6 +251,80 @@ impl std::fmt::Debug for GoalListener {
   }
 }
 
+/// A function that gets called on the proof states of tactic blocks.
+///
+/// It is called once before the first tactic and once after each tactic, with the span
+/// of the block and the number of tactics left to run. For a `focus` block, the span is
+/// that of the `focus` keyword. The state itself can be read using
+/// [`Elaborator::goal_state`].
+#[allow(clippy::type_complexity)]
+pub struct GoalStateListener(Box<dyn for<'a> FnMut(&'a Elaborator, Span, usize)>);
+
+impl GoalStateListener {
+  /// Creates a new [`GoalStateListener`] from a callback.
+  pub fn new(f: impl for<'a> FnMut(&'a Elaborator, Span, usize) + 'static) -> Self { Self(Box::new(f)) }
+}
+
+impl std::fmt::Debug for GoalStateListener {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    "GoalStateListener".fmt(f)
+  }
+}
+
+/// A function that gets called before each statement of the file is elaborated, with the
+/// index of the statement in the [`Ast`]. The errors found so far can be read using
+/// [`Elaborator::errors`].
+#[allow(clippy::type_complexity)]
+pub struct StmtListener(Box<dyn for<'a> FnMut(&'a Elaborator, usize)>);
+
+impl StmtListener {
+  /// Creates a new [`StmtListener`] from a callback.
+  pub fn new(f: impl for<'a> FnMut(&'a Elaborator, usize) + 'static) -> Self { Self(Box::new(f)) }
+}
+
+impl std::fmt::Debug for StmtListener {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    "StmtListener".fmt(f)
+  }
+}
+
+/// A structured snapshot of the proof state, returned by [`Elaborator::goal_state`].
+/// The expressions are pretty printed.
+#[derive(Debug, Default)]
+pub struct GoalState {
+  /// The hypotheses, as pairs `(name, statement)`.
+  pub hyps: Vec<(String, String)>,
+  /// The goals, starting with the main goal, with the span of the proof term that
+  /// created each goal, if it is in the current file.
+  pub goals: Vec<(Option<Span>, String)>,
+  /// The unassigned metavariables, as `(span, name, target)`, where the span is the
+  /// location of the hole that created the metavariable, if it is in the current file.
+  pub mvars: Vec<(Option<Span>, String, String)>,
+}
+
+/// A flag that can be set from another thread to signal that an elaboration (or other
+/// long-running task) should be abandoned.
+///
+/// Cancellation is cooperative: the elaborator checks the token between statements and
+/// periodically in the lisp evaluator, and stops as soon as it sees that it is set.
+/// Clones of a token share the same flag.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+  /// Creates a new token which is not canceled.
+  #[must_use] pub fn new() -> Self { Self::default() }
+
+  /// Signals cancellation to every holder of this token.
+  pub fn cancel(&self) { self.0.store(true, Ordering::Release) }
+
+  /// Returns true if [`cancel`](Self::cancel) has been called on this token or a clone of it.
+  #[must_use] pub fn is_canceled(&self) -> bool { self.0.load(Ordering::Acquire) }
+
+  /// Returns true if the two tokens are clones of each other.
+  #[must_use] pub fn ptr_eq(&self, other: &Self) -> bool { Arc::ptr_eq(&self.0, &other.0) }
+}
+
 /// The [`Elaborator`] struct contains the working data for elaboration, and is the
 /// main interface to MM1 operations (along with [`Evaluator`](lisp::eval::Evaluator),
 /// which a lisp execution context).
@@ -225,9 +334,9 @@ pub struct Elaborator {
   ast: Arc<Ast>,
   /// The location and name of the currently elaborating file
   pub(crate) path: FileRef,
-  /// A flag that will be flipped from another thread to signal that this elaboration
+  /// A token that will be canceled from another thread to signal that this elaboration
   /// should be abandoned
-  cancel: Arc<AtomicBool>,
+  cancel: CancelToken,
   /// The accumulated list of errors
   errors: Vec<ElabError>,
   /// The permanent data of the elaborator: the completed proofs and lisp definitions
@@ -236,8 +345,13 @@ pub struct Elaborator {
   timeout: Option<Duration>,
   /// The time at which the current lisp evaluation will be aborted
   cur_timeout: Option<Instant>,
-  /// The maximum number of permitted stack frames during elaboration
-  stack_limit: usize,
+  /// The limits on the resources used by lisp evaluation
+  limits: EvalLimits,
+  /// The number of lisp evaluation steps taken in the current statement
+  steps: u64,
+  /// The number of lisp values that had been allocated at the start of the current statement
+  /// (see [`lisp::alloc_count`])
+  allocs: usize,
   /// The current proof context
   lc: LocalContext,
   /// Information attached to spans, used for hover queries
@@ -248,6 +362,8 @@ pub struct Elaborator {
   check_proofs: bool,
   /// The current reporting mode, whether we will report each severity of error
   reporting: ReportMode,
+  /// The current level of each warning code
+  warnings: WarningConfig,
   /// Should we report backtraces in lisp errors?
   backtrace: ReportMode,
   /// The handlers for different kinds of input and output.
@@ -256,6 +372,24 @@ pub struct Elaborator {
   arena: lisp::LispArena,
   /// A listener for goal view events.
   recv_goal: Option<GoalListener>,
+  /// A listener for the proof state in tactic blocks.
+  recv_goal_state: Option<GoalStateListener>,
+  /// A listener called before each statement.
+  recv_stmt: Option<StmtListener>,
+  /// A debugger attached to the lisp evaluator.
+  debugger: Option<Box<dyn Debugger>>,
+  /// The lisp profiler, if `(profile-start)` has been called.
+  lisp_profile: Option<Box<LispProfiler>>,
+  /// The index of theorem conclusions used by `(auto)`, which is updated on use.
+  auto_index: auto::Index,
+  /// The open sections and namespaces.
+  scopes: namespace::Scopes,
+  /// The copy of the environment made for `(spawn)` (see [`cache::encode_env`]) and the
+  /// number of atoms at that point. It is shared by the tasks spawned until the environment
+  /// may have changed (see [`Elaborator::env_changed`]).
+  ///
+  /// [`cache::encode_env`]: crate::cache::encode_env
+  spawn_env: Option<(usize, Arc<[u8]>)>,
 }
 
 impl Deref for Elaborator {
@@ -278,11 +412,11 @@ impl Elaborator {
   ///   differently, and proofs of `theorem` are not allowed.
   /// - `check_proofs`: The initial setting of the `check_proofs` state at the top of each
   ///   file, which can be changed later using the `(check-proofs)` lisp command.
-  /// - `cancel`: An atomic flag that can be flipped in another thread in order to cancel
+  /// - `cancel`: A token that can be canceled in another thread in order to stop
   ///   the elaboration before completion.
   /// - `recv_goal`: A listener for goal view events.
   #[must_use] pub fn new(ast: Arc<Ast>, path: FileRef,
-      mm0_mode: bool, check_proofs: bool, cancel: Arc<AtomicBool>,
+      mm0_mode: bool, check_proofs: bool, cancel: CancelToken,
       recv_goal: Option<GoalListener>,
     ) -> Elaborator {
     Elaborator {
@@ -291,7 +425,9 @@ impl Elaborator {
       env: Environment::new(),
       timeout: Some(Duration::from_secs(5)),
       cur_timeout: None,
-      stack_limit: 1024,
+      limits: crate::get_eval_limits(),
+      steps: 0,
+      allocs: 0,
       lc: LocalContext::new(),
       spans: Spans::new(),
       mm0_mode,
@@ -299,8 +435,16 @@ impl Elaborator {
       backtrace: ReportMode {error: true, warn: false, info: false},
       inout: InoutHandlers::default(),
       reporting: ReportMode::new(),
+      warnings: crate::get_warning_config(),
       arena: Default::default(),
       recv_goal,
+      recv_goal_state: None,
+      recv_stmt: None,
+      debugger: None,
+      lisp_profile: None,
+      auto_index: Default::default(),
+      scopes: Default::default(),
+      spawn_env: None,
     }
   }
 
@@ -312,9 +456,13 @@ impl Elaborator {
   /// Report a non-fatal elaboration error. This method suppresses the error collection
   /// if the error reporting level is low enough.
   pub fn report(&mut self, e: ElabError) {
+    let e = match self.warnings.apply(e) {Some(e) => e, None => return};
     if self.reporting.active(e.level) {self.errors.push(e)}
   }
 
+  /// The errors reported so far.
+  #[must_use] pub fn errors(&self) -> &[ElabError] { &self.errors }
+
   fn push_spans(&mut self) {
     self.env.spans.push(mem::take(&mut self.spans));
   }
@@ -326,18 +474,37 @@ impl Elaborator {
     }
   }
 
+  fn call_stmt_listener(&mut self, idx: usize) {
+    if let Some(mut listener) = self.recv_stmt.take() {
+      listener.0(self, idx);
+      self.recv_stmt = Some(listener);
+    }
+  }
+
+  fn call_goal_state_listener(&mut self, sp: Span, n: usize) {
+    if let Some(mut listener) = self.recv_goal_state.take() {
+      listener.0(self, sp, n);
+      self.recv_goal_state = Some(listener);
+    }
+  }
+
   fn name_of(&mut self, stmt: &Stmt) -> LispVal {
     match &stmt.k {
-      StmtKind::Annot(_, s) => self.name_of(s),
-      StmtKind::Decl(d) => LispVal::atom(self.env.get_atom(self.ast.span(d.id))),
+      StmtKind::Annot(_, s) | StmtKind::Attrs(_, s) => self.name_of(s),
+      StmtKind::Decl(d) => { let ast = self.ast.clone(); LispVal::atom(self.decl_atom(ast.span(d.id))) }
       &StmtKind::Sort(id, _) => LispVal::atom(self.env.get_atom(self.ast.span(id))),
       _ => LispVal::bool(false),
     }
   }
 
   fn elab_simple_nota(&mut self, n: &SimpleNota) -> Result<()> {
+    if self.mm0_mode && n.local {
+      return Err(ElabError::new_e(n.id, "(MM0 mode) local notations not allowed"))
+    }
     let a = self.env.get_atom(self.ast.span(n.id));
-    let term = self.term(a).ok_or_else(|| ElabError::new_e(n.id, "term not declared"))?;
+    let a = self.resolve_name(a);
+    let term = self.term(a).ok_or_else(|| ElabError::new_e(n.id, format!("term not declared{}",
+      self.did_you_mean(a, |_, ad| matches!(ad.decl, Some(DeclKey::Term(_)))))))?;
     self.spans.insert(n.id, ObjectKind::Term(term, n.id));
     let tk: ArcString = self.span(n.c.trim).into();
     let (rassoc, nargs, lits) = match n.k {
@@ -364,7 +531,7 @@ impl Elaborator {
         }
     };
     self.add_const(n.c.trim, n.prec)?;
-    let info = NotaInfo { span: self.fspan(n.id), term, nargs, rassoc: Some(rassoc), lits };
+    let info = NotaInfo { span: self.fspan(n.id), term, nargs, rassoc: Some(rassoc), lits, local: n.local };
     match n.k {
       SimpleNotaKind::Prefix => self.pe.add_prefix(tk.clone(), info),
       SimpleNotaKind::Infix {..} => self.pe.add_infix(tk.clone(), info),
@@ -375,11 +542,15 @@ impl Elaborator {
 
   fn elab_coe(&mut self, id: Span, from: Span, to: Span) -> Result<()> {
     let a_id = self.env.get_atom(self.ast.span(id));
+    let a_id = self.resolve_name(a_id);
     let a_from = self.env.get_atom(self.ast.span(from));
     let a_to = self.env.get_atom(self.ast.span(to));
-    let t = self.term(a_id).ok_or_else(|| ElabError::new_e(id, "term not declared"))?;
-    let s1 = self.data[a_from].sort.ok_or_else(|| ElabError::new_e(from, "sort not declared"))?;
-    let s2 = self.data[a_to].sort.ok_or_else(|| ElabError::new_e(to, "sort not declared"))?;
+    let t = self.term(a_id).ok_or_else(|| ElabError::new_e(id, format!("term not declared{}",
+      self.did_you_mean(a_id, |_, ad| matches!(ad.decl, Some(DeclKey::Term(_)))))))?;
+    let s1 = self.data[a_from].sort.ok_or_else(|| ElabError::new_e(from,
+      format!("sort not declared{}", self.did_you_mean(a_from, |_, ad| ad.sort.is_some()))))?;
+    let s2 = self.data[a_to].sort.ok_or_else(|| ElabError::new_e(to,
+      format!("sort not declared{}", self.did_you_mean(a_to, |_, ad| ad.sort.is_some()))))?;
     self.check_term_nargs(id, t, 1)?;
     self.spans.insert(id, ObjectKind::Term(t, id));
     self.spans.insert(from, ObjectKind::Sort(s1));
@@ -410,7 +581,9 @@ impl Elaborator {
     }
 
     let a = self.env.get_atom(self.ast.span(nota.id));
-    let term = self.term(a).ok_or_else(|| ElabError::new_e(nota.id, "term not declared"))?;
+    let a = self.resolve_name(a);
+    let term = self.term(a).ok_or_else(|| ElabError::new_e(nota.id, format!("term not declared{}",
+      self.did_you_mean(a, |_, ad| matches!(ad.decl, Some(DeclKey::Term(_)))))))?;
     let nargs = nota.bis.len();
     self.check_term_nargs(nota.id, term, nargs)?;
     self.spans.insert(nota.id, ObjectKind::Term(term, nota.id));
@@ -426,6 +599,9 @@ impl Elaborator {
       }
     }
 
+    if self.mm0_mode && nota.local {
+      return Err(ElabError::new_e(nota.id, "(MM0 mode) local notations not allowed"))
+    }
     if self.mm0_mode && nota.prec.is_some() {
       return Err(ElabError::new_e(nota.id,
         "(MM0 mode) generalized infix precedence specifier not allowed"))
@@ -442,7 +618,15 @@ impl Elaborator {
     let (mut lits, mut rassoc, infix, tk, prec) = match it.next() {
       None => return Err(ElabError::new_e(nota.id,
         "notation requires at least one literal")),
-      Some(&ALiteral::Const(ref cnst, prec)) => (vec![], Some(true), false, cnst, prec),
+      Some(&ALiteral::Const(ref cnst, prec)) => {
+        let rassoc = match nota.prec {
+          None => true,
+          Some((q, _)) if q != prec => return Err(ElabError::new_e(cnst.fmla.0,
+            "notation precedence must match first constant")),
+          Some((_, rassoc)) => rassoc,
+        };
+        (vec![], Some(rassoc), false, cnst, prec)
+      }
       Some(&ALiteral::Var(var)) if self.mm0_mode =>
         return Err(ElabError::new_e(var,
           "(MM0 mode) generalized infix notations not allowed")),
@@ -502,7 +686,7 @@ impl Elaborator {
       }
     }
     let s: ArcString = self.span(tk.trim).into();
-    let info = NotaInfo { span: self.fspan(nota.id), term, nargs, rassoc, lits };
+    let info = NotaInfo { span: self.fspan(nota.id), term, nargs, rassoc, lits, local: nota.local };
     if infix { self.pe.add_infix(s.clone(), info) }
     else { self.pe.add_prefix(s.clone(), info) }
       .map_err(|r| ElabError::with_info(nota.id,
@@ -542,6 +726,73 @@ impl Elaborator {
 enum ElabStmt { Ok, Import(Span) }
 
 impl Elaborator {
+  /// Forget the copy of the environment made for `(spawn)`, because the environment or a
+  /// lisp value in it may have changed.
+  pub(crate) fn env_changed(&mut self) { self.spawn_env = None }
+
+  /// Resets the timeout and evaluation limits before elaborating a statement at `span`.
+  fn start_stmt(&mut self, span: Span) {
+    self.env_changed();
+    self.cur_timeout = self.timeout.and_then(|d| Instant::now().checked_add(d));
+    self.steps = 0;
+    self.allocs = lisp::alloc_count();
+    self.spans.set_stmt(span);
+  }
+
+  /// Merges the environment of an imported file into the current environment.
+  /// `sp` is the span of the import statement, used for error reporting.
+  /// The `local` notations of `env` are merged only if `local` is true, which is used
+  /// to work in the context of a file rather than importing it.
+  /// The lisp arena must be installed (see [`Elaborator::with_arena`]).
+  pub(crate) fn import_env(&mut self, sp: Span, env: &FrozenEnv, local: bool) {
+    let mut it = if local {
+      EnvMergeIter::new(&mut self.env, env, sp)
+    } else {
+      EnvMergeIter::new_import(&mut self.env, env, sp)
+    };
+    loop {
+      match it.next(&mut self.env, &mut self.errors) {
+        Err(e) => {self.report(e); break}
+        Ok(None) => break,
+        Ok(Some(mut merge)) => {
+          merge.val = self.apply_merge(sp,
+              merge.strat.as_deref(), merge.val.clone(), merge.new.val.clone())
+            .unwrap_or_else(|e| {self.report(e); merge.new.val.clone()});
+          merge.apply(&mut self.env);
+        }
+      }
+    }
+  }
+
+  /// Runs `f` with the lisp arena of this elaborator installed on the current thread.
+  #[cfg(feature = "repl")]
+  pub(crate) fn with_arena<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+    self.arena.install_thread_local();
+    let r = f(self);
+    lisp::LispArena::uninstall_thread_local();
+    r
+  }
+
+  /// Evaluates the expressions in the final `do` block of `ast`, which is the current file
+  /// extended with a new line of REPL input. Returns the (non-`#undef`) values of the
+  /// expressions, and the errors reported during evaluation.
+  /// The lisp arena must be installed (see [`Elaborator::with_arena`]).
+  #[cfg(feature = "repl")]
+  pub(crate) fn eval_repl(&mut self, ast: &Arc<Ast>) -> (Vec<LispVal>, Vec<ElabError>) {
+    self.ast = ast.clone();
+    let mut vals = vec![];
+    if let Some(Stmt {k: StmtKind::Do(es), span}) = ast.stmts.last() {
+      self.start_stmt(*span);
+      for e in es {
+        match self.eval_lisp(e) {
+          Ok(v) => if v.is_def() { vals.push(v) },
+          Err(e) => {self.report(e); break}
+        }
+      }
+    }
+    (vals, mem::take(&mut self.errors))
+  }
+
   /// Elaborates a single statement.
   ///
   /// # Returns
@@ -556,8 +807,7 @@ impl Elaborator {
       if doc.is_empty() {None} else {Some(doc.into())}
     }
 
-    self.cur_timeout = self.timeout.and_then(|d| Instant::now().checked_add(d));
-    self.spans.set_stmt(span);
+    self.start_stmt(span);
     match &stmt.k {
       &StmtKind::Sort(sp, sd) => {
         let a = self.env.get_atom(self.ast.span(sp));
@@ -571,16 +821,42 @@ impl Elaborator {
       StmtKind::SimpleNota(n) => self.elab_simple_nota(n)?,
       &StmtKind::Coercion {id, from, to} => self.elab_coe(id, from, to)?,
       StmtKind::Notation(n) => self.elab_gen_nota(n)?,
-      &StmtKind::Import(sp, _) => return Ok(ElabStmt::Import(sp)),
+      &StmtKind::Import(sp, _, _) => return Ok(ElabStmt::Import(sp)),
+      &StmtKind::Section(name) => self.elab_section(stmt.span, name, false)?,
+      &StmtKind::Namespace(name) => self.elab_section(stmt.span, Some(name), true)?,
+      &StmtKind::Open(name) => self.elab_open(stmt.span, name)?,
+      &StmtKind::End(name) => self.elab_end(stmt.span, name)?,
       StmtKind::Do(es) => {
         if self.mm0_mode {
           self.report(ElabError::warn(span, "(MM0 mode) do blocks not allowed"))
         }
         for e in es { self.parse_and_print(e, mem::take(&mut doc))? }
       }
+      StmtKind::Attrs(attrs, s) => {
+        self.elab_stmt(doc, s, span)?;
+        self.elab_attrs(attrs, s)?;
+      }
       StmtKind::Annot(e, s) => {
         let v = self.eval_lisp(e)?;
         self.elab_stmt(doc, s, span)?;
+        // `@simp` and `@instance` are built in, so that they do not depend on the
+        // library's `annotate`.
+        match v.unwrapped(|r| if let lisp::LispKind::Proc(lisp::Proc::Builtin(p)) = *r { Some(p) } else { None }) {
+          Some(lisp::BuiltinProc::Simp) => {
+            let x = self.name_of(s).as_atom().ok_or_else(||
+              ElabError::new_e(e.span, "@simp: expected a theorem"))?;
+            self.add_simp(x).map_err(|msg| ElabError::new_e(e.span, format!("@simp: {}", msg)))?;
+            return Ok(ElabStmt::Ok)
+          }
+          Some(lisp::BuiltinProc::Instance) => {
+            let x = self.name_of(s).as_atom().ok_or_else(||
+              ElabError::new_e(e.span, "@instance: expected a theorem"))?;
+            self.add_instance(x, instance::DEFAULT_PRIORITY)
+              .map_err(|msg| ElabError::new_e(e.span, format!("@instance: {}", msg)))?;
+            return Ok(ElabStmt::Ok)
+          }
+          _ => {}
+        }
         let ann = match &self.data[AtomId::ANNOTATE].lisp {
           Some(e) => e.val.clone(),
           None => return Err(ElabError::new_e(e.span, "define 'annotate' before using annotations")),
@@ -618,6 +894,7 @@ pub enum ElabResult<T> {
 
 /// This is a builder struct to provide inputs to [`ElaborateBuilder::elab`].
 #[derive(Debug)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct ElaborateBuilder<'a, F> {
   /// The parsed abstract syntax tree for the file
   pub ast: &'a Arc<Ast>,
@@ -630,14 +907,22 @@ pub struct ElaborateBuilder<'a, F> {
   /// If true, an error will be reported if a file in an import itself
   /// has an error. This can be disabled to avoid reporting the same error many times.
   pub report_upstream_errors: bool,
-  /// A flag that will be flipped from another thread to signal that this elaboration
+  /// A token that will be canceled from another thread to signal that this elaboration
   /// should be abandoned
-  pub cancel: Arc<AtomicBool>,
+  pub cancel: CancelToken,
   /// The last successful parse of the same file, used for incremental elaboration.
-  /// A value of `Some((idx, errs, env))` means that the new file first differs from the
-  /// old one at `idx`, and the last parse produced environment `env` with errors `errs`.
+  /// A value of `Some((idx, errs, env))` means that the first `idx` statements of the new
+  /// file are unchanged from the old one, and the last parse produced environment `env`
+  /// with errors `errs`. If `env` has a [snapshot](snapshot) at or before statement `idx`,
+  /// elaboration restarts from there instead of the beginning of the file.
   #[allow(clippy::type_complexity)]
   pub old: Option<(usize, Option<Arc<[ElabError]>>, FrozenEnv)>,
+  /// If true, [snapshots](snapshot) of the elaborator state are stored in the resulting
+  /// environment, so that a later elaboration of the same file can use it as `old`.
+  pub snapshots: bool,
+  /// If true, the time, proof size and lisp allocations of each statement are
+  /// [profiled](profile) and stored in the resulting environment.
+  pub profile: bool,
   /// A function which is called when an `import` is encountered, with the [`FileRef`] of
   /// the file being imported. It sets up a channel and passes the [`Receiver`] end here,
   /// to transfer an [`Environment`] containing the elaborated theorems, as well as any
@@ -648,6 +933,15 @@ pub struct ElaborateBuilder<'a, F> {
   /// to transfer an [`Environment`] containing the elaborated theorems, as well as any
   /// extra data `T`, which is collected and passed through the function.
   pub recv_goal: Option<GoalListener>,
+  /// A listener which is called with the proof state in the tactic blocks of this file
+  /// (but not the files it imports), see [`GoalStateListener`].
+  pub recv_goal_state: Option<GoalStateListener>,
+  /// A listener which is called before each statement of this file (but not the files
+  /// it imports), see [`StmtListener`].
+  pub recv_stmt: Option<StmtListener>,
+  /// A debugger to attach to the lisp evaluator while elaborating this file
+  /// (but not the files it imports).
+  pub debugger: Option<Box<dyn Debugger>>,
 }
 
 impl<'a, T: Send, F> ElaborateBuilder<'a, F>
@@ -666,7 +960,8 @@ where F: FnMut(FileRef) -> Result<Receiver<ElabResult<T>>, BoxError> {
   ///
   /// If elaboration of an individual statement fails, the error is pushed and then elaboration
   /// continues at the next statement, so the overall elaboration process cannot fail and an
-  /// environment is always produced.
+  /// environment is always produced. If `cancel` is canceled, elaboration stops at the next
+  /// check, and the resulting environment and errors are incomplete and should be discarded.
   pub fn elab(self) -> impl Future<Output=(Option<ArcList<FileRef>>, Vec<T>, Vec<ElabError>, FrozenEnv)> + Send {
 
     type ImportMap<D> = HashMap<Span, (FileRef, D)>;
@@ -685,6 +980,13 @@ where F: FnMut(FileRef) -> Result<Receiver<ElabResult<T>>, BoxError> {
       report_upstream_errors: bool,
       cyc: Option<ArcList<FileRef>>,
       recv: ImportMap<Receiver<ElabResult<T>>>,
+      /// The imports in the statements skipped by a restart, which are only awaited
+      /// to get the `T` values
+      skipped: Vec<Receiver<ElabResult<T>>>,
+      /// The snapshots taken so far, if snapshots are enabled
+      snapshots: Option<Snapshots>,
+      /// The statement profiles so far, if profiling is enabled
+      profile: Option<Vec<StmtProfile>>,
       idx: usize,
       progress: UnfinishedStmt<T>
     }
@@ -697,9 +999,17 @@ where F: FnMut(FileRef) -> Result<Receiver<ElabResult<T>>, BoxError> {
         let this = &mut unsafe { self.get_unchecked_mut() }.0;
         let ElabFutureInner {
           elab: FrozenElaborator(elab),
-          cyc, toks, recv, idx, progress, report_upstream_errors
+          cyc, toks, recv, skipped, snapshots, profile, idx, progress, report_upstream_errors
         } = this.as_mut().expect("poll called after Ready");
         elab.arena.install_thread_local();
+        while let Some(other) = skipped.first_mut() {
+          match ready!(Pin::new(other).poll(cx)) {
+            Ok(ElabResult::Ok(t, _, _)) => toks.push(t),
+            Ok(ElabResult::Canceled) => elab.cancel.cancel(),
+            _ => {}
+          }
+          drop(skipped.remove(0));
+        }
         'l: loop {
           match progress {
             UnfinishedStmt::None => {},
@@ -720,29 +1030,17 @@ where F: FnMut(FileRef) -> Result<Receiver<ElabResult<T>>, BoxError> {
                             p.clone()
                           };
                           let e = OwningRef::new(errs).map(|errs| &errs[i]);
-                          elab.report(ElabError {pos: *sp, level, kind: ElabErrorKind::Upstream(file, e, n)});
+                          elab.report(ElabError {pos: *sp, level, kind: ElabErrorKind::Upstream(file, e, n), code: None});
                           break
                         }
                       }
                     }
                   }
-                  let mut it = EnvMergeIter::new(&mut elab.env, &env, *sp);
-                  loop {
-                    match it.next(&mut elab.env, &mut elab.errors) {
-                      Err(e) => {elab.report(e); break}
-                      Ok(None) => break,
-                      Ok(Some(mut merge)) => {
-                        merge.val = elab.apply_merge(*sp,
-                            merge.strat.as_deref(), merge.val.clone(), merge.new.val.clone())
-                          .unwrap_or_else(|e| {elab.report(e); merge.new.val.clone()});
-                        merge.apply(&mut elab.env);
-                      }
-                    }
-                  }
+                  elab.import_file(*sp, &env);
                 }
                 Ok(ElabResult::Canceled) => {
                   elab.report(ElabError::new_e(*sp, "canceled"));
-                  elab.cancel.store(true, Ordering::Relaxed);
+                  elab.cancel.cancel();
                   break
                 }
                 Ok(ElabResult::ImportCycle(cyc2)) => {
@@ -759,7 +1057,10 @@ where F: FnMut(FileRef) -> Result<Receiver<ElabResult<T>>, BoxError> {
           }
           let ast = elab.ast.clone();
           while let Some(s) = ast.stmts.get(*idx) {
-            if elab.cancel.load(Ordering::Relaxed) {break}
+            if elab.cancel.is_canceled() {break}
+            if let Some(snaps) = snapshots { snaps.step(elab, *idx) }
+            elab.call_stmt_listener(*idx);
+            let prof = profile.as_ref().map(|_| Profiler::start(elab));
             match elab.elab_stmt(String::new(), s, s.span) {
               Ok(ElabStmt::Ok) => {}
               Ok(ElabStmt::Import(sp)) => {
@@ -770,36 +1071,58 @@ where F: FnMut(FileRef) -> Result<Receiver<ElabResult<T>>, BoxError> {
                   continue 'l
                 }
               }
+              // The error of a statement interrupted by cancellation is not reported,
+              // since the statement is incomplete rather than wrong.
+              Err(_) if elab.cancel.is_canceled() => break,
               Err(e) => elab.report(e)
             }
+            if let (Some(profile), Some(prof)) = (&mut *profile, prof) {
+              profile.push(prof.finish(elab, s.span))
+            }
             elab.push_spans();
+            elab.arena.maybe_collect();
             *idx += 1;
           }
           break
         }
         lisp::LispArena::uninstall_thread_local();
-        let ElabFutureInner {elab: FrozenElaborator(elab), cyc, toks, ..} =
+        let ElabFutureInner {elab: FrozenElaborator(elab), cyc, toks, snapshots, profile, ..} =
           this.take().expect("impossible");
-        elab.arena.clear();
-        Poll::Ready((cyc, toks, elab.errors, FrozenEnv::new(elab.env)))
+        elab.arena.collect();
+        let mut env = elab.env;
+        if let Some(snaps) = snapshots { env.snapshots = snaps.finish() }
+        env.profile = profile.unwrap_or_default();
+        Poll::Ready((cyc, toks, elab.errors, FrozenEnv::new(env)))
       }
     }
 
     let mut recv_dep = self.recv_dep;
     let mut recv = HashMap::new();
+    let mut skipped = vec![];
     let mut elab = Elaborator::new(self.ast.clone(),
       self.path, self.mm0_mode, self.check_proofs, self.cancel, self.recv_goal);
+    elab.recv_goal_state = self.recv_goal_state;
+    elab.recv_stmt = self.recv_stmt;
+    elab.debugger = self.debugger;
     elab.arena.install_thread_local();
+    let (idx, snaps) = match &self.old {
+      Some((idx, errs, env)) => elab.restart(*idx, errs.as_deref(), env),
+      None => (0, &[][..]),
+    };
+    let start = self.ast.stmts.get(idx).map_or(self.ast.source.len(), |s| s.span.start);
     for &(sp, ref f) in &self.ast.imports {
+      // Errors in skipped imports have already been restored by `restart`.
+      let skip = sp.start < start;
       (|| -> Result<_> {
         let f = std::str::from_utf8(f).map_err(|e| ElabError::new_e(sp, e))?;
-        let path = elab.path.path().parent().map_or_else(|| PathBuf::from(f), |p| p.join(f));
+        let path = crate::resolve_import(elab.path.path(), f);
         let r: FileRef = path.canonicalize().map_err(|e| ElabError::new_e(sp, e))?.into();
         let tok = recv_dep(r.clone()).map_err(|e| ElabError::new_e(sp, e))?;
-        recv.insert(sp, (r, tok));
+        if skip { skipped.push(tok) } else { recv.insert(sp, (r, tok)); }
         Ok(())
-      })().unwrap_or_else(|e| elab.report(e));
+      })().unwrap_or_else(|e| if !skip { elab.report(e) });
     }
+    let snapshots = if self.snapshots { Some(Snapshots::new(snaps)) } else { None };
     lisp::LispArena::uninstall_thread_local();
     ElabFuture(Some(ElabFutureInner {
       elab: FrozenElaborator(elab),
@@ -807,7 +1130,10 @@ where F: FnMut(FileRef) -> Result<Receiver<ElabResult<T>>, BoxError> {
       report_upstream_errors: self.report_upstream_errors,
       cyc: None,
       recv,
-      idx: 0,
+      skipped,
+      snapshots,
+      profile: if self.profile { Some(vec![]) } else { None },
+      idx,
       progress: UnfinishedStmt::None,
     }))
   }
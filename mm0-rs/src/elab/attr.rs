@@ -0,0 +1,102 @@
+//! Attributes on declarations, written `@[simp, deprecated foo2 "use foo2"] theorem foo ...;`.
+//!
+//! The built in attributes are:
+//!
+//! * `simp`, which registers a theorem as a simp lemma, like the `@simp` annotation.
+//! * `instance`, with an optional priority, which registers a theorem as an instance,
+//!   like the `@instance` annotation.
+//! * `deprecated`, with an optional replacement declaration and an optional message,
+//!   which causes a warning at each later use of the term or theorem. The language server
+//!   offers to replace such a use by the replacement.
+//!
+//! Any other attribute `@[foo a b]` is passed to the lisp function `annotate` as the quoted
+//! list `(foo a b)`, so `@[foo a b] theorem ...;` is the same as `@'(foo a b) theorem ...;`.
+//! The arguments of an attribute are never evaluated.
+
+use crate::{ArcString, AtomId, Deprecation, Span, WarningCode};
+use crate::ast::{Atom, Attr, SExpr, SExprKind, Stmt};
+use super::{instance, ElabError, Elaborator, Result};
+
+impl Elaborator {
+  /// Apply the attributes `attrs` to the statement `s`, which has already been elaborated.
+  pub(crate) fn elab_attrs(&mut self, attrs: &[Attr], s: &Stmt) -> Result<()> {
+    let val = self.name_of(s);
+    for attr in attrs {
+      let name = ArcString::from(self.span(attr.name));
+      let x = || val.as_atom().ok_or_else(|| ElabError::new_e(attr.name,
+        format!("{}: expected a declaration", name)));
+      match (&*name, &*attr.args) {
+        (b"simp", []) => {
+          let x = x()?;
+          self.add_simp(x).map_err(|msg| ElabError::new_e(attr.span, format!("simp: {}", msg)))?
+        }
+        (b"instance", args @ ([] | [_])) => {
+          let x = x()?;
+          let prio = match args.first() {
+            None => instance::DEFAULT_PRIORITY,
+            Some(SExpr {k: SExprKind::Number(n), ..}) => n.try_into().map_err(|_|
+              ElabError::new_e(args[0].span, "instance: priority out of range"))?,
+            Some(e) => return Err(ElabError::new_e(e.span, "instance: expected a number")),
+          };
+          self.add_instance(x, prio)
+            .map_err(|msg| ElabError::new_e(attr.span, format!("instance: {}", msg)))?
+        }
+        (b"simp" | b"instance", _) => return Err(ElabError::new_e(attr.span,
+          format!("{}: too many arguments", name))),
+        (b"deprecated", args) => {
+          let x = x()?;
+          if self.data[x].decl.is_none() {
+            return Err(ElabError::new_e(attr.name, "deprecated: expected a term or theorem"))
+          }
+          let mut d = Deprecation {replacement: None, msg: None};
+          for e in args {
+            match &e.k {
+              SExprKind::Atom(Atom::Ident) if d.replacement.is_none() && d.msg.is_none() => {
+                let a = self.env.get_atom(self.ast.clone().span(e.span));
+                let a = self.resolve_name(a);
+                if self.data[a].decl.is_none() {
+                  return Err(ElabError::new_e(e.span,
+                    format!("deprecated: unknown declaration '{}'", self.data[a].name)))
+                }
+                d.replacement = Some(a)
+              }
+              SExprKind::String(s) if d.msg.is_none() => d.msg = Some(s.clone()),
+              _ => return Err(ElabError::new_e(e.span,
+                "deprecated: expected a replacement declaration and/or a message")),
+            }
+          }
+          self.env.deprecated.insert(x, d);
+        }
+        (_, args) => {
+          let ann = match &self.data[AtomId::ANNOTATE].lisp {
+            Some(e) => e.val.clone(),
+            None => return Err(ElabError::new_e(attr.name,
+              format!("unknown attribute '{}'", name))),
+          };
+          let mut es = vec![SExpr::atom(attr.name, Atom::Ident)];
+          es.extend(args.iter().cloned());
+          let e = SExpr::list(attr.span, vec![SExpr::atom(attr.name, Atom::Quote), SExpr::list(attr.span, es)]);
+          let v = self.eval_lisp(&e)?;
+          self.call_func(attr.span, ann, vec![v, val.clone()])?;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// The warning message for a use of the deprecated declaration `a`, if it is deprecated.
+  #[must_use] pub fn deprecation_msg(&self, a: AtomId) -> Option<String> {
+    let d = self.deprecated.get(&a)?;
+    let name = &self.data[a].name;
+    Some(match (&d.msg, d.replacement) {
+      (Some(msg), _) => format!("'{}' is deprecated: {}", name, msg),
+      (None, Some(b)) => format!("'{}' is deprecated, use '{}' instead", name, self.data[b].name),
+      (None, None) => format!("'{}' is deprecated", name),
+    })
+  }
+
+  /// Report a warning at `sp` if `a` is a deprecated declaration.
+  pub(crate) fn check_deprecated(&mut self, sp: Span, a: AtomId) {
+    if let Some(msg) = self.deprecation_msg(a) { self.report(ElabError::lint(WarningCode::Deprecated, sp, msg)) }
+  }
+}
@@ -0,0 +1,477 @@
+//! Automatic lemma application, which proves a goal by bounded backward search.
+//!
+//! The theorems in the environment are indexed by their conclusion in a discrimination
+//! tree, a trie over the preorder traversal of the conclusion in which the theorem
+//! variables are replaced by a wildcard. A lookup in the tree returns the theorems whose
+//! conclusion may unify with the goal, without trying every theorem in the environment.
+//! The index is kept in the elaborator and updated incrementally, so only the theorems
+//! added since the last search are indexed.
+//!
+//! The search itself applies a hypothesis or a matching theorem to the goal, and then
+//! recursively proves the hypotheses of the theorem, backtracking over the choices. The
+//! arguments of a theorem become metavariables, which are assigned by (syntactic, first
+//! order) unification, so that a theorem like `mp: $ a -> b $ > $ a $ > $ b $` can be
+//! applied with `a` determined by a later hypothesis. Definitions are not unfolded.
+//! A proof is only accepted if it determines all the theorem arguments and respects the
+//! dependencies of the bound variables of each theorem.
+//! The result is passed to `refine`, so the proof is checked as usual.
+//!
+//! The search is available as the `(auto depth)` lisp tactic, and it is also used for
+//! [instance resolution](super::instance), which only applies the registered instances.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Instant;
+use crate::{AtomId, Environment, ExprNode, LispKind, LispVal, TermId, ThmId, Type};
+use super::Elaborator;
+use super::local_context::InferSort;
+
+/// A key in the [`Index`], which is one node of the preorder traversal of a term.
+#[derive(Clone, Copy, Debug)]
+enum Key {
+  /// A theorem variable, which matches any term.
+  Star,
+  /// An application of a term constructor, which is followed by the keys of the arguments.
+  App(TermId),
+}
+
+/// A node in the discrimination tree.
+#[derive(Debug, Default)]
+struct Node {
+  /// The child for [`Key::Star`].
+  star: Option<usize>,
+  /// The children for [`Key::App`].
+  apps: HashMap<TermId, usize>,
+  /// The theorems whose conclusion ends at this node.
+  thms: Vec<ThmId>,
+}
+
+/// A discrimination tree over the conclusions of the theorems in the environment.
+#[derive(Debug)]
+pub struct Index {
+  /// The nodes of the tree. The root is node 0.
+  nodes: Vec<Node>,
+  /// The number of theorems in the environment that have been indexed.
+  len: usize,
+}
+
+impl Default for Index {
+  fn default() -> Self { Index { nodes: vec![Node::default()], len: 0 } }
+}
+
+impl Index {
+  /// Push the keys for `e` in the context of theorem variables `nargs` and `heap`.
+  /// Returns false if `e` contains a dummy variable.
+  fn keys(heap: &[ExprNode], nargs: usize, e: &ExprNode, out: &mut Vec<Key>) -> bool {
+    match *e {
+      ExprNode::Ref(i) if i < nargs => { out.push(Key::Star); true }
+      ExprNode::Ref(i) => Self::keys(heap, nargs, &heap[i], out),
+      ExprNode::Dummy(..) => false,
+      ExprNode::App(t, ref es) => {
+        out.push(Key::App(t));
+        es.iter().all(|e| Self::keys(heap, nargs, e, out))
+      }
+    }
+  }
+
+  /// Add the theorems in `env` that have not been indexed yet.
+  pub fn update(&mut self, env: &Environment) {
+    let mut keys = vec![];
+    for (tid, td) in env.thms.enum_iter().skip(self.len) {
+      keys.clear();
+      if !Self::keys(&td.heap, td.args.len(), &td.ret, &mut keys) { continue }
+      let mut n = 0;
+      for &k in &keys {
+        let next = self.nodes.len();
+        let child = match k {
+          Key::Star => self.nodes[n].star.get_or_insert(next),
+          Key::App(t) => self.nodes[n].apps.entry(t).or_insert(next),
+        };
+        n = *child;
+        if n == next { self.nodes.push(Node::default()) }
+      }
+      self.nodes[n].thms.push(tid)
+    }
+    self.len = env.thms.0.len();
+  }
+
+  /// Push to `out` the nodes reached from node `n` by skipping `k` terms.
+  fn skip(&self, env: &Environment, n: usize, k: usize, out: &mut Vec<usize>) {
+    if k == 0 { return out.push(n) }
+    let node = &self.nodes[n];
+    if let Some(c) = node.star { self.skip(env, c, k - 1, out) }
+    for (&t, &c) in &node.apps { self.skip(env, c, k - 1 + env.terms[t].args.len(), out) }
+  }
+
+  /// Push to `out` the theorems in the subtree at node `n` whose conclusion may unify
+  /// with the terms in `todo` (in reverse order), where `subst` is the current assignment.
+  fn find(&self, env: &Environment, subst: &[Option<Rc<Term>>],
+    n: usize, todo: &mut Vec<Rc<Term>>, out: &mut Vec<ThmId>
+  ) {
+    let e = match todo.pop() {
+      None => return out.extend_from_slice(&self.nodes[n].thms),
+      Some(e) => e,
+    };
+    let node = &self.nodes[n];
+    if let Some(c) = node.star { self.find(env, subst, c, todo, out) }
+    match &*deref(subst, &e) {
+      Term::App(t, es) => if let Some(&c) = node.apps.get(t) {
+        let len = todo.len();
+        todo.extend(es.iter().rev().cloned());
+        self.find(env, subst, c, todo, out);
+        todo.truncate(len);
+      },
+      Term::Meta(_) => {
+        let mut nodes = vec![];
+        for (&t, &c) in &node.apps { self.skip(env, c, env.terms[t].args.len(), &mut nodes) }
+        for c in nodes { self.find(env, subst, c, todo, out) }
+      }
+      Term::Var(_) | Term::Opaque(_) => {}
+    }
+    todo.push(e)
+  }
+}
+
+/// A term in the search.
+#[derive(Debug)]
+enum Term {
+  /// A variable in the local context.
+  Var(AtomId),
+  /// A metavariable of the enclosing proof, which is treated as a constant.
+  Opaque(LispVal),
+  /// A metavariable of the search, which stands for a theorem argument.
+  Meta(usize),
+  /// An application of a term constructor.
+  App(TermId, Box<[Rc<Term>]>),
+}
+
+/// Follow the assignments of the metavariable `e`, if it is one.
+fn deref(subst: &[Option<Rc<Term>>], e: &Rc<Term>) -> Rc<Term> {
+  let mut e = e;
+  while let Term::Meta(i) = **e {
+    match &subst[i] { Some(e2) => e = e2, None => break }
+  }
+  e.clone()
+}
+
+/// A step in the proof found by the search, in preorder, where the hypotheses of a
+/// theorem are in reverse order.
+#[derive(Debug)]
+enum Step {
+  /// A hypothesis in the local context.
+  Hyp(AtomId),
+  /// A theorem, and the first metavariable of its arguments.
+  Thm(ThmId, usize),
+}
+
+/// The theorems that the search can apply.
+pub(super) enum Lemmas {
+  /// All the theorems in the environment, found using the [`Index`].
+  Index,
+  /// The instances for each head term constructor, in the order they are tried.
+  Instances(HashMap<TermId, Vec<ThmId>>),
+}
+
+/// The deepest goal that the search failed to prove, which is used in error messages.
+pub(super) struct Failure {
+  /// The remaining depth at the goal.
+  depth: u32,
+  /// The goal, with `_` for unassigned metavariables.
+  pub(super) goal: LispVal,
+  /// The theorems that were tried on the goal.
+  pub(super) tried: Vec<ThmId>,
+}
+
+/// The state of the backward search.
+pub(super) struct Search<'a> {
+  elab: &'a Elaborator,
+  /// The theorems that the search can apply.
+  lemmas: Lemmas,
+  /// The hypotheses in the local context.
+  hyps: Vec<(AtomId, Rc<Term>)>,
+  /// The assignment of the metavariables.
+  subst: Vec<Option<Rc<Term>>>,
+  /// True if the metavariable is the argument of a bound variable.
+  bound: Vec<bool>,
+  /// The metavariables that have been assigned, in order, for backtracking.
+  trail: Vec<usize>,
+  /// The proof so far.
+  steps: Vec<Step>,
+  /// The deepest goal that could not be proved, if the lemmas are instances.
+  pub(super) fail: Option<Failure>,
+}
+
+impl<'a> Search<'a> {
+  /// Create a new search, which can use the hypotheses in the local context.
+  pub(super) fn new(elab: &'a Elaborator, lemmas: Lemmas) -> Self {
+    let mut search = Search {
+      elab, lemmas, hyps: vec![], subst: vec![], bound: vec![], trail: vec![], steps: vec![],
+      fail: None,
+    };
+    for (h, ty, _) in &elab.lc.proof_order {
+      if let Some(ty) = search.term(ty) { search.hyps.push((*h, ty)) }
+    }
+    search
+  }
+
+  /// Convert the lisp expression `e` to a [`Term`], or return `None` if it is not a
+  /// well formed expression.
+  fn term(&self, e: &LispVal) -> Option<Rc<Term>> {
+    match &**e {
+      LispKind::Annot(_, e) => self.term(e),
+      LispKind::Ref(m) => m.get(|e2| if let LispKind::MVar(..) = **e2 {
+        Some(Rc::new(Term::Opaque(e.clone())))
+      } else { self.term(e2) }),
+      &LispKind::Atom(a) if self.elab.lc.vars.contains_key(&a) => Some(Rc::new(Term::Var(a))),
+      &LispKind::Atom(a) => {
+        let t = self.elab.term(a)?;
+        if !self.elab.terms[t].args.is_empty() { return None }
+        Some(Rc::new(Term::App(t, Box::new([]))))
+      }
+      LispKind::List(es) if !es.is_empty() => {
+        let t = self.elab.term(es[0].as_atom()?)?;
+        if self.elab.terms[t].args.len() + 1 != es.len() { return None }
+        Some(Rc::new(Term::App(t, es[1..].iter().map(|e| self.term(e)).collect::<Option<_>>()?)))
+      }
+      _ => None,
+    }
+  }
+
+  /// Convert the expression `e` of a theorem statement to a [`Term`], where `heap` contains
+  /// the terms for the heap references. Returns `None` on a dummy variable.
+  fn node(heap: &[Rc<Term>], e: &ExprNode) -> Option<Rc<Term>> {
+    match *e {
+      ExprNode::Ref(i) => Some(heap[i].clone()),
+      ExprNode::Dummy(..) => None,
+      ExprNode::App(t, ref es) =>
+        Some(Rc::new(Term::App(t, es.iter().map(|e| Self::node(heap, e)).collect::<Option<_>>()?))),
+    }
+  }
+
+  /// Instantiate the statement of theorem `t` with new metavariables for the arguments,
+  /// returning the conclusion and the hypotheses.
+  fn instantiate(&mut self, t: ThmId) -> Option<(Rc<Term>, Vec<Rc<Term>>)> {
+    let td = &self.elab.thms[t];
+    let mut heap = Vec::with_capacity(td.heap.len());
+    for (_, ty) in &*td.args {
+      heap.push(Rc::new(Term::Meta(self.subst.len())));
+      self.subst.push(None);
+      self.bound.push(ty.bound());
+    }
+    for e in &td.heap[td.args.len()..] {
+      let e = Self::node(&heap, e)?;
+      heap.push(e)
+    }
+    let hyps = td.hyps.iter().map(|(_, e)| Self::node(&heap, e)).collect::<Option<_>>()?;
+    Some((Self::node(&heap, &td.ret)?, hyps))
+  }
+
+  /// Returns true if `v`, which is a variable or an unassigned metavariable, occurs in `e`.
+  fn occurs(&self, v: &Term, e: &Rc<Term>) -> bool {
+    match (v, &*deref(&self.subst, e)) {
+      (Term::Var(a), Term::Var(b)) => a == b,
+      (Term::Meta(i), Term::Meta(j)) => i == j,
+      (_, Term::App(_, es)) => es.iter().any(|e| self.occurs(v, e)),
+      _ => false,
+    }
+  }
+
+  /// Returns true if `e` contains no unassigned metavariables.
+  fn closed(&self, e: &Rc<Term>) -> bool {
+    match &*deref(&self.subst, e) {
+      Term::Meta(_) => false,
+      Term::App(_, es) => es.iter().all(|e| self.closed(e)),
+      Term::Var(_) | Term::Opaque(_) => true,
+    }
+  }
+
+  /// Returns true if the proof is complete: all the theorem arguments are assigned, the
+  /// bound arguments are bound variables, and each bound argument does not occur in the
+  /// later arguments that do not depend on it, as required for a theorem application.
+  fn check(&self) -> bool {
+    for step in &self.steps {
+      let (t, base) = match *step { Step::Thm(t, base) => (t, base), Step::Hyp(_) => continue };
+      let args = &self.elab.thms[t].args;
+      let es = (base..base + args.len())
+        .map(|i| deref(&self.subst, &Rc::new(Term::Meta(i)))).collect::<Vec<_>>();
+      if !es.iter().all(|e| self.closed(e)) { return false }
+      let mut k = 0;
+      for (i, (_, ty)) in args.iter().enumerate() {
+        if !ty.bound() { continue }
+        match *es[i] {
+          Term::Var(a) if matches!(self.elab.lc.vars.get(&a), Some((_, InferSort::Bound(_)))) => {}
+          _ => return false,
+        }
+        for ((_, ty2), e) in args.iter().zip(&es).skip(i + 1) {
+          let dep = matches!(*ty2, Type::Reg(_, deps) if deps & (1 << k) != 0);
+          if !dep && self.occurs(&es[i], e) { return false }
+        }
+        k += 1;
+      }
+    }
+    true
+  }
+
+  /// Assign the metavariable `i` to `e`, which is not an assigned metavariable.
+  fn assign(&mut self, i: usize, e: &Rc<Term>) -> bool {
+    match **e {
+      Term::Meta(j) if i == j => return true,
+      Term::Meta(j) if self.bound[i] && !self.bound[j] => {
+        self.subst[j] = Some(Rc::new(Term::Meta(i)));
+        self.trail.push(j);
+        return true
+      }
+      Term::Meta(_) | Term::Var(_) => {}
+      Term::App(..) | Term::Opaque(_) => if self.bound[i] || self.occurs(&Term::Meta(i), e) { return false },
+    }
+    self.subst[i] = Some(e.clone());
+    self.trail.push(i);
+    true
+  }
+
+  /// Unify `e1` and `e2`, assigning metavariables. On failure, the assignments are not
+  /// undone; the caller backtracks to a saved state.
+  fn unify(&mut self, e1: &Rc<Term>, e2: &Rc<Term>) -> bool {
+    let (e1, e2) = (deref(&self.subst, e1), deref(&self.subst, e2));
+    match (&*e1, &*e2) {
+      (&Term::Meta(i), _) => self.assign(i, &e2),
+      (_, &Term::Meta(j)) => self.assign(j, &e1),
+      (Term::Var(a), Term::Var(b)) => a == b,
+      (Term::Opaque(a), Term::Opaque(b)) => a.ptr_eq(b),
+      (Term::App(t, es1), Term::App(u, es2)) =>
+        t == u && es1.iter().zip(&**es2).all(|(e1, e2)| self.unify(e1, e2)),
+      _ => false,
+    }
+  }
+
+  /// Undo the assignments and proof steps after the saved state.
+  fn backtrack(&mut self, (trail, steps, metas): (usize, usize, usize)) {
+    for i in self.trail.drain(trail..) { self.subst[i] = None }
+    self.steps.truncate(steps);
+    self.subst.truncate(metas);
+    self.bound.truncate(metas);
+  }
+
+  /// Returns the error message if the search has to stop because of a timeout or
+  /// cancellation.
+  fn over_limit(&self) -> Option<&'static str> {
+    if self.elab.cur_timeout.map_or(false, |t| t < Instant::now()) { return Some("timeout") }
+    if self.elab.cancel.is_canceled() { return Some("cancelled") }
+    None
+  }
+
+  /// Prove all the goals in `goals`, which are pairs of a goal and the remaining depth,
+  /// starting from the last one. Returns false if there is no proof, in which case
+  /// `goals` and the search state are unchanged.
+  fn solve(&mut self, goals: &mut Vec<(Rc<Term>, u32)>) -> Result<bool, &'static str> {
+    let (g, depth) = match goals.pop() { Some(g) => g, None => return Ok(self.check()) };
+    if let Some(msg) = self.over_limit() { return Err(msg) }
+    let n = goals.len();
+    for i in 0..self.hyps.len() {
+      let save = (self.trail.len(), self.steps.len(), self.subst.len());
+      let (h, ty) = self.hyps[i].clone();
+      if self.unify(&g, &ty) {
+        self.steps.push(Step::Hyp(h));
+        if self.solve(goals)? { return Ok(true) }
+      }
+      self.backtrack(save)
+    }
+    let mut thms = vec![];
+    // A goal which is an unassigned metavariable would match every theorem,
+    // so we only try the hypotheses.
+    if depth != 0 {
+      match (&self.lemmas, &*deref(&self.subst, &g)) {
+        (_, Term::Meta(_)) => {}
+        (Lemmas::Index, _) => {
+          self.elab.auto_index.find(&self.elab.env, &self.subst, 0, &mut vec![g.clone()], &mut thms);
+          thms.sort_unstable();
+        }
+        (Lemmas::Instances(insts), Term::App(t, _)) =>
+          if let Some(ts) = insts.get(t) { thms.clone_from(ts) },
+        (Lemmas::Instances(_), _) => {}
+      }
+    }
+    for &t in &thms {
+      let save = (self.trail.len(), self.steps.len(), self.subst.len());
+      if let Some((ret, hyps)) = self.instantiate(t) {
+        if self.unify(&g, &ret) {
+          self.steps.push(Step::Thm(t, save.2));
+          // The last hypothesis is proved first, since the earlier hypotheses are often
+          // just a metavariable, like `a` in `mp: $ a $ > $ a -> b $ > $ b $`.
+          goals.extend(hyps.into_iter().map(|h| (h, depth - 1)));
+          if self.solve(goals)? { return Ok(true) }
+          goals.truncate(n);
+        }
+      }
+      self.backtrack(save)
+    }
+    if let Lemmas::Instances(_) = self.lemmas {
+      if self.fail.as_ref().map_or(true, |f| depth < f.depth) {
+        self.fail = Some(Failure { depth, goal: self.to_lisp(&g, true), tried: thms })
+      }
+    }
+    goals.push((g, depth));
+    Ok(false)
+  }
+
+  /// Search for a proof of `goal`, with at most `depth` nested theorem applications.
+  /// Returns false if there is no proof, and an error if the goal is not a well formed
+  /// expression or the search was interrupted.
+  pub(super) fn run(&mut self, goal: &LispVal, depth: u32) -> Result<bool, String> {
+    let g = self.term(goal).ok_or_else(|| {
+      format!("unsupported goal {}", self.elab.format_env().pp(goal, 80))
+    })?;
+    Ok(self.solve(&mut vec![(g, depth)])?)
+  }
+
+  /// Convert `e` back to a lisp term. If `refine` is true, the term is in the form
+  /// expected by `refine`, with `_` for unassigned metavariables; otherwise all the
+  /// metavariables must be assigned.
+  fn to_lisp(&self, e: &Rc<Term>, refine: bool) -> LispVal {
+    match &*deref(&self.subst, e) {
+      Term::Meta(_) => LispVal::atom(AtomId::UNDER),
+      &Term::Var(a) => LispVal::atom(a),
+      Term::Opaque(e) if refine => LispVal::list(vec![LispVal::atom(AtomId::VERB), e.clone()]),
+      Term::Opaque(e) => e.clone(),
+      Term::App(t, es) => {
+        let a = LispVal::atom(self.elab.terms[*t].atom);
+        if es.is_empty() && refine { return a }
+        LispVal::list(std::iter::once(a).chain(es.iter().map(|e| self.to_lisp(e, refine)))
+          .collect::<Vec<_>>())
+      }
+    }
+  }
+
+  /// Build the proof starting at step `*i`. If `refine` is true, the proof is in the form
+  /// expected by `refine`, otherwise it is a fully elaborated proof term.
+  pub(super) fn proof(&self, i: &mut usize, refine: bool) -> LispVal {
+    let step = &self.steps[*i];
+    *i += 1;
+    match *step {
+      Step::Hyp(h) => LispVal::atom(h),
+      Step::Thm(t, base) => {
+        let td = &self.elab.thms[t];
+        let mut args = if refine { vec![LispVal::atom(AtomId::BANG)] } else { vec![] };
+        args.push(LispVal::atom(td.atom));
+        args.extend((base..base + td.args.len())
+          .map(|j| self.to_lisp(&Rc::new(Term::Meta(j)), refine)));
+        let n = args.len();
+        for _ in 0..td.hyps.len() { args.push(self.proof(i, refine)) }
+        args[n..].reverse();
+        LispVal::list(args)
+      }
+    }
+  }
+}
+
+impl Elaborator {
+  /// Search for a proof of `goal` using the hypotheses in the local context and the
+  /// theorems in the environment, with at most `depth` nested theorem applications.
+  /// Returns a proof in the form expected by `refine`, or `None` if there is no proof.
+  pub fn auto(&mut self, goal: &LispVal, depth: u32) -> Result<Option<LispVal>, String> {
+    self.auto_index.update(&self.env);
+    let mut search = Search::new(self, Lemmas::Index);
+    if !search.run(goal, depth).map_err(|e| format!("auto: {}", e))? { return Ok(None) }
+    Ok(Some(search.proof(&mut 0, true)))
+  }
+}
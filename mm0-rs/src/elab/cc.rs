@@ -0,0 +1,275 @@
+//! Congruence closure, which proves an equality `a = b` from a list of equations, using
+//! reflexivity, symmetry, transitivity and congruence.
+//!
+//! In MM0, equality is not built in: it is an ordinary term constructor, axiomatized by the
+//! library (and there is usually one equality for each sort). So the closure itself is
+//! computed on the term structure only, and the proof is built at the end by lisp callbacks,
+//! which know the names of the library's equality lemmas. See
+//! [`BuiltinProc::CongruenceClosure`](super::lisp::BuiltinProc::CongruenceClosure)
+//! for the interface.
+//!
+//! The algorithm is the one of Nieuwenhuis and Oliveras, "Proof-producing congruence
+//! closure". Terms are hash-consed into nodes, which are merged in a union-find structure,
+//! and a signature table detects when two applications become congruent. Every merge also
+//! adds an edge to a *proof forest*, and the proof of `a = b` is read off the path between
+//! `a` and `b` in the forest, explaining congruence edges recursively.
+
+use std::collections::{hash_map::Entry, HashMap};
+use crate::{AtomId, LispKind, LispVal, Span};
+use super::{Elaborator, Result};
+
+/// The hash-consing key of a term node.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Key {
+  /// A variable.
+  Atom(AtomId),
+  /// An application `(t e1 ... en)`, with the nodes of the arguments.
+  App(AtomId, Box<[usize]>),
+  /// Anything else (like a metavariable), compared by identity.
+  Other(*const LispKind),
+}
+
+/// The reason for an edge in the proof forest.
+#[derive(Copy, Clone, Debug)]
+enum Reason {
+  /// The equation with this index in the input list.
+  Hyp(usize),
+  /// The two endpoints are applications of the same term whose arguments are equal.
+  Congr,
+}
+
+/// The names of the lisp callbacks used to build the proof, in the order they are stored
+/// in the array passed to [`Elaborator::congruence_closure`].
+pub const CALLBACKS: [&str; 4] = ["refl", "symm", "trans", "congr"];
+
+/// An index into [`CALLBACKS`].
+#[derive(Copy, Clone, Debug)]
+enum Callback { Refl = 0, Symm = 1, Trans = 2, Congr = 3 }
+
+#[derive(Default)]
+struct Closure {
+  /// The term for each node.
+  vals: Vec<LispVal>,
+  /// The head and arguments of each node, if it is an application.
+  apps: Vec<Option<(AtomId, Box<[usize]>)>>,
+  /// The node for each term.
+  keys: HashMap<Key, usize>,
+  /// The union-find parent of each node.
+  uf: Vec<usize>,
+  /// The size of the class of each representative.
+  size: Vec<usize>,
+  /// The applications with an argument in the class of each representative.
+  uses: Vec<Vec<usize>>,
+  /// The signature table, mapping an application head and the representatives of its
+  /// arguments to an application with that signature.
+  sigs: HashMap<(AtomId, Box<[usize]>), usize>,
+  /// The parent of each node in the proof forest, and the reason for the edge.
+  forest: Vec<Option<(usize, Reason)>>,
+  /// Merges that have been discovered but not yet performed.
+  pending: Vec<(usize, usize, Reason)>,
+}
+
+impl Closure {
+  fn find(&mut self, mut x: usize) -> usize {
+    let mut root = x;
+    while self.uf[root] != root { root = self.uf[root] }
+    while self.uf[x] != root { x = std::mem::replace(&mut self.uf[x], root) }
+    root
+  }
+
+  fn sig(&mut self, u: usize) -> (AtomId, Box<[usize]>) {
+    let (t, args) = self.apps[u].clone().expect("not an application");
+    (t, args.iter().map(|&a| self.find(a)).collect())
+  }
+
+  /// Get the node for the term `e`, adding it if necessary.
+  fn add(&mut self, e: &LispVal) -> usize {
+    enum Shape { Atom(AtomId), App(AtomId, Vec<LispVal>), Other(*const LispKind) }
+    let shape = e.unwrapped(|r| match r {
+      &LispKind::Atom(a) => Shape::Atom(a),
+      LispKind::List(es) => match es.first().and_then(|e| e.as_atom()) {
+        Some(t) => Shape::App(t, es[1..].to_vec()),
+        None => Shape::Other(r),
+      },
+      _ => Shape::Other(r),
+    });
+    let (key, app) = match shape {
+      Shape::Atom(a) => (Key::Atom(a), None),
+      Shape::Other(p) => (Key::Other(p), None),
+      Shape::App(t, es) => {
+        let args: Box<[usize]> = es.iter().map(|e| self.add(e)).collect();
+        (Key::App(t, args.clone()), Some((t, args)))
+      }
+    };
+    if let Some(&n) = self.keys.get(&key) { return n }
+    let n = self.vals.len();
+    self.keys.insert(key, n);
+    self.vals.push(e.clone());
+    self.uf.push(n);
+    self.size.push(1);
+    self.uses.push(vec![]);
+    self.forest.push(None);
+    if let Some((_, args)) = &app {
+      for &a in &**args {
+        let a = self.find(a);
+        self.uses[a].push(n)
+      }
+    }
+    self.apps.push(app);
+    if self.apps[n].is_some() {
+      let sig = self.sig(n);
+      match self.sigs.entry(sig) {
+        Entry::Occupied(e) => self.pending.push((n, *e.get(), Reason::Congr)),
+        Entry::Vacant(e) => { e.insert(n); }
+      }
+    }
+    n
+  }
+
+  /// Make `x` the root of its tree in the proof forest, by reversing the path to the root.
+  fn reroot(&mut self, mut x: usize) {
+    let mut prev = None;
+    loop {
+      let next = std::mem::replace(&mut self.forest[x], prev);
+      match next {
+        None => break,
+        Some((y, r)) => { prev = Some((x, r)); x = y }
+      }
+    }
+  }
+
+  /// Perform all pending merges, and the merges they imply by congruence.
+  fn propagate(&mut self) {
+    while let Some((a, b, r)) = self.pending.pop() {
+      let (ra, rb) = (self.find(a), self.find(b));
+      if ra == rb { continue }
+      self.reroot(a);
+      self.forest[a] = Some((b, r));
+      let (small, large) = if self.size[ra] < self.size[rb] {(ra, rb)} else {(rb, ra)};
+      self.uf[small] = large;
+      self.size[large] += self.size[small];
+      let uses = std::mem::take(&mut self.uses[small]);
+      for &u in &uses {
+        let sig = self.sig(u);
+        match self.sigs.entry(sig) {
+          Entry::Occupied(e) => {
+            let v = *e.get();
+            if self.find(u) != self.find(v) { self.pending.push((u, v, Reason::Congr)) }
+          }
+          Entry::Vacant(e) => { e.insert(u); }
+        }
+      }
+      self.uses[large].extend(uses);
+    }
+  }
+
+  /// The path from `x` to the root of its tree in the proof forest.
+  fn path(&self, mut x: usize) -> Vec<(usize, usize, Reason)> {
+    let mut path = vec![];
+    while let Some((y, r)) = self.forest[x] { path.push((x, y, r)); x = y }
+    path
+  }
+}
+
+/// The state for building the proof from the proof forest.
+struct Explain<'a> {
+  cc: &'a Closure,
+  elab: &'a mut Elaborator,
+  sp: Span,
+  fns: &'a [LispVal; 4],
+  /// The proof and the nodes of the two sides of each input equation.
+  hyps: &'a [(LispVal, usize, usize)],
+  /// The proofs that have already been built, where `None` means reflexivity.
+  memo: HashMap<(usize, usize), Option<LispVal>>,
+}
+
+impl Explain<'_> {
+  fn call(&mut self, f: Callback, args: Vec<LispVal>) -> Result<LispVal> {
+    self.elab.call_func(self.sp, self.fns[f as usize].clone(), args)
+  }
+
+  fn or_refl(&mut self, x: usize, p: Option<LispVal>) -> Result<LispVal> {
+    match p {
+      Some(p) => Ok(p),
+      None => self.call(Callback::Refl, vec![self.cc.vals[x].clone()]),
+    }
+  }
+
+  /// Prove `x = y` from a single edge in the proof forest.
+  fn step(&mut self, x: usize, y: usize, reason: Reason) -> Result<LispVal> {
+    let vals = &self.cc.vals;
+    match reason {
+      Reason::Hyp(i) => {
+        let (ref pf, lhs, _) = self.hyps[i];
+        if lhs == x { return Ok(pf.clone()) }
+        let args = vec![pf.clone(), vals[y].clone(), vals[x].clone()];
+        self.call(Callback::Symm, args)
+      }
+      Reason::Congr => {
+        let (head, xs) = self.cc.apps[x].as_ref().expect("not an application");
+        let (_, ys) = self.cc.apps[y].as_ref().expect("not an application");
+        let mut ps = Vec::with_capacity(xs.len());
+        for (&a, &b) in xs.iter().zip(&**ys) {
+          let pf = self.explain(a, b)?;
+          ps.push(self.or_refl(a, pf)?)
+        }
+        let args = vec![LispVal::atom(*head),
+          LispVal::list(xs.iter().map(|&a| vals[a].clone()).collect::<Vec<_>>()),
+          LispVal::list(ys.iter().map(|&b| vals[b].clone()).collect::<Vec<_>>()),
+          LispVal::list(ps)];
+        self.call(Callback::Congr, args)
+      }
+    }
+  }
+
+  /// Prove `a = b`, which must be connected in the proof forest. Returns `None` if
+  /// `a` and `b` are the same node.
+  fn explain(&mut self, a: usize, b: usize) -> Result<Option<LispVal>> {
+    if a == b { return Ok(None) }
+    if let Some(p) = self.memo.get(&(a, b)) { return Ok(p.clone()) }
+    let (mut pa, mut pb) = (self.cc.path(a), self.cc.path(b));
+    // Remove the common part of the paths to the root, leaving the paths to the
+    // nearest common ancestor.
+    while pa.last().is_some() && pa.last().map(|e| e.0) == pb.last().map(|e| e.0) {
+      pa.pop();
+      pb.pop();
+    }
+    let steps = pa.into_iter().chain(pb.into_iter().rev().map(|(x, y, r)| (y, x, r)));
+    let mut acc: Option<LispVal> = None;
+    for (x, y, r) in steps {
+      let p = self.step(x, y, r)?;
+      acc = Some(match acc {
+        None => p,
+        Some(q) => {
+          let args = vec![q, p, self.cc.vals[a].clone(), self.cc.vals[x].clone(), self.cc.vals[y].clone()];
+          self.call(Callback::Trans, args)?
+        }
+      })
+    }
+    self.memo.insert((a, b), acc.clone());
+    Ok(acc)
+  }
+}
+
+impl Elaborator {
+  /// Prove `a = b` by congruence closure from the equations `eqs`, where each equation
+  /// `(p, x, y)` is a proof `p` of `x = y`. `fns` contains the callbacks
+  /// `refl`, `symm`, `trans` and `congr` used to build the proof. Returns `#undef` if
+  /// `a = b` does not follow from the equations.
+  pub fn congruence_closure(&mut self, sp: Span,
+    eqs: &[(LispVal, LispVal, LispVal)], a: &LispVal, b: &LispVal, fns: &[LispVal; 4]
+  ) -> Result<LispVal> {
+    let mut cc = Closure::default();
+    let (a, b) = (cc.add(a), cc.add(b));
+    let hyps = eqs.iter().enumerate().map(|(i, (p, x, y))| {
+      let (x, y) = (cc.add(x), cc.add(y));
+      cc.pending.push((x, y, Reason::Hyp(i)));
+      (p.clone(), x, y)
+    }).collect::<Vec<_>>();
+    cc.propagate();
+    if cc.find(a) != cc.find(b) { return Ok(LispVal::undef()) }
+    let mut ex = Explain { cc: &cc, elab: self, sp, fns, hyps: &hyps, memo: HashMap::new() };
+    let p = ex.explain(a, b)?;
+    ex.or_refl(a, p)
+  }
+}
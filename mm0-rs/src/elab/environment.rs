@@ -2,14 +2,16 @@
 
 use std::ops::Deref;
 use std::rc::Rc;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::fmt::Write;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use super::{ElabError, BoxError, spans::Spans, FrozenEnv, FrozenLispVal};
 use crate::{ArcString, AtomId, AtomVec, DocComment, FileRef, FileSpan, HashMapExt, Modifiers,
-  Prec, SortId, SortVec, Span, TermId, TermVec, ThmId, ThmVec,
+  Prec, SortId, SortVec, Span, TermId, TermVec, ThmId, ThmVec, render::RenderFormat,
   lisp::{LispVal, RefineSyntax, Syntax}};
-use super::frozen::{FrozenLispKind, FrozenLispRef};
+use super::frozen::{FrozenLispKind, FrozenLispRef, RevDeps};
+use super::snapshot::Snapshot;
+use super::profile::StmtProfile;
 
 /// The information associated to a defined [`Sort`].
 #[derive(Clone, Debug, DeepSizeOf)]
@@ -125,6 +127,16 @@ pub struct Term {
   pub kind: TermKind,
 }
 
+impl Term {
+  /// Call `f` on every term referenced in the value of this term, if it is a def.
+  /// The same term may be passed to `f` more than once.
+  pub fn for_each_dep(&self, mut f: impl FnMut(DeclKey)) {
+    if let TermKind::Def(Some(Expr {heap, head})) = &self.kind {
+      for e in heap.iter().chain(Some(head)) { e.for_each_term(&mut |t| f(DeclKey::Term(t))) }
+    }
+  }
+}
+
 /// A [`ProofNode`] is a stored proof term. This is an extension of [`ExprNode`] with
 /// more constructors, so a [`ProofNode`] can represent an expr, a proof, or a conversion,
 /// and the typing determines which. A [`ProofNode`] is interpreted in a context of
@@ -190,6 +202,62 @@ impl ProofNode {
       }
     }
   }
+
+  /// Call `f` on every term and theorem referenced in this node. This does not
+  /// follow [`Ref`](ProofNode::Ref) nodes, so it should be called on every element
+  /// of the heap as well to get all references in a proof.
+  pub fn for_each_decl(&self, f: &mut impl FnMut(DeclKey)) {
+    match self {
+      ProofNode::Ref(_) | ProofNode::Dummy(_, _) => {}
+      &ProofNode::Term {term, ref args} | &ProofNode::Cong {term, ref args} => {
+        f(DeclKey::Term(term));
+        for e in &**args { e.for_each_decl(f) }
+      }
+      ProofNode::Hyp(_, e) | ProofNode::Refl(e) | ProofNode::Sym(e) => e.for_each_decl(f),
+      &ProofNode::Thm {thm, ref args, ref res} => {
+        f(DeclKey::Thm(thm));
+        for e in &**args { e.for_each_decl(f) }
+        res.for_each_decl(f)
+      }
+      ProofNode::Conv(p) => {
+        let (e1, c, p) = &**p;
+        e1.for_each_decl(f);
+        c.for_each_decl(f);
+        p.for_each_decl(f)
+      }
+      &ProofNode::Unfold {term, ref args, ref res} => {
+        f(DeclKey::Term(term));
+        for e in &**args { e.for_each_decl(f) }
+        res.0.for_each_decl(f);
+        res.1.for_each_decl(f)
+      }
+    }
+  }
+
+  /// Call `f` on the immediate children of this node, in order. This does not
+  /// follow [`Ref`](ProofNode::Ref) nodes.
+  pub fn for_each_child<'a>(&'a self, mut f: impl FnMut(&'a ProofNode)) {
+    match self {
+      ProofNode::Ref(_) | ProofNode::Dummy(_, _) => {}
+      ProofNode::Term {args, ..} | ProofNode::Cong {args, ..} => args.iter().for_each(f),
+      ProofNode::Hyp(_, e) | ProofNode::Refl(e) | ProofNode::Sym(e) => f(e),
+      ProofNode::Thm {args, res, ..} => { args.iter().for_each(&mut f); f(res) }
+      ProofNode::Conv(p) => { f(&p.0); f(&p.1); f(&p.2) }
+      ProofNode::Unfold {args, res, ..} => { args.iter().for_each(&mut f); f(&res.0); f(&res.1) }
+    }
+  }
+}
+
+impl ExprNode {
+  /// Call `f` on every term constructor referenced in this node. This does not
+  /// follow [`Ref`](ExprNode::Ref) nodes, so it should be called on every element
+  /// of the heap as well to get all references in an expression.
+  pub fn for_each_term(&self, f: &mut impl FnMut(TermId)) {
+    if let &ExprNode::App(t, ref es) = self {
+      f(t);
+      for e in &**es { e.for_each_term(f) }
+    }
+  }
 }
 
 impl From<&ExprNode> for ProofNode {
@@ -221,6 +289,27 @@ pub struct Proof {
   pub hyps: Box<[ProofNode]>,
   /// The target proof term.
   pub head: ProofNode,
+  /// The source spans of the nodes of the proof that have one, as pairs `(n, span)` sorted
+  /// by `n`, where `n` is the position of the node in the order of [`for_each_node`].
+  /// The spans are in the file containing the theorem. This is used for the source map
+  /// of the MMB file, and is empty if the proof was not elaborated from source, or if
+  /// [`set_proof_spans`](crate::set_proof_spans) was off when it was elaborated.
+  ///
+  /// [`for_each_node`]: Proof::for_each_node
+  pub spans: Box<[(u32, Span)]>,
+}
+
+impl Proof {
+  /// Call `f` on every node of the proof in preorder: first the elements of the `heap`,
+  /// then the `hyps`, then the `head`. [`Ref`](ProofNode::Ref) nodes are visited,
+  /// but not followed.
+  pub fn for_each_node<'a>(&'a self, mut f: impl FnMut(&'a ProofNode)) {
+    fn go<'a>(e: &'a ProofNode, f: &mut impl FnMut(&'a ProofNode)) {
+      f(e);
+      e.for_each_child(|e| go(e, f))
+    }
+    for e in self.heap.iter().chain(&*self.hyps).chain(Some(&self.head)) { go(e, &mut f) }
+  }
 }
 
 /// The proof of the axiom or theorem.
@@ -269,6 +358,20 @@ pub struct Thm {
   pub kind: ThmKind,
 }
 
+impl Thm {
+  /// Call `f` on every term referenced in the statement of this theorem, and every
+  /// term and theorem referenced in its proof.
+  /// The same declaration may be passed to `f` more than once.
+  pub fn for_each_dep(&self, mut f: impl FnMut(DeclKey)) {
+    for e in self.heap.iter().chain(self.hyps.iter().map(|h| &h.1)).chain(Some(&self.ret)) {
+      e.for_each_term(&mut |t| f(DeclKey::Term(t)))
+    }
+    if let ThmKind::Thm(Some(Proof {heap, hyps, head, ..})) = &self.kind {
+      for e in heap.iter().chain(&**hyps).chain(Some(head)) { e.for_each_decl(&mut f) }
+    }
+  }
+}
+
 /// An `output string` directive, which is anonymous and hence stored directly
 /// in the [`StmtTrace`] list.
 #[derive(Clone, Debug, DeepSizeOf)]
@@ -298,7 +401,7 @@ pub enum StmtTrace {
 /// A declaration is either a [`Term`] or a [`Thm`]. This is done because in MM1
 /// Terms and Thms share a namespace (although they are put in separate number-spaces
 /// for compilation to MM0).
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum DeclKey {
   /// A term or def, with its Id
   Term(TermId),
@@ -335,6 +438,17 @@ pub struct NotaInfo {
   /// The literals of the notation declaration. For a `notation` these are declared directly,
   /// but for a `prefix` or `infix`, the equivalent notation literals are generated.
   pub lits: Vec<Literal>,
+  /// True if this is a `local` notation, which is not exported to files that import this one.
+  pub local: bool,
+}
+
+/// The data associated to a `@[deprecated]` attribute on a term or theorem.
+#[derive(Clone, Debug, DeepSizeOf)]
+pub struct Deprecation {
+  /// The declaration that should be used instead, if given.
+  pub replacement: Option<AtomId>,
+  /// The message shown at uses of the deprecated declaration, if given.
+  pub msg: Option<ArcString>,
 }
 
 /// A coercion between two sorts. These are interpreted in a context `c: s1 -> s2` where `s1` and
@@ -398,6 +512,9 @@ pub struct ParserEnv {
   /// and there is one `(c, infx)` for each constant `c` that maps to `t`, where `infx` is true
   /// if `c` is infix and false if `c` is prefix.
   pub decl_nota: HashMap<TermId, (bool, Vec<(ArcString, bool)>)>,
+  /// A map of terms and output formats to the templates used to render the term in that
+  /// format, declared using `set-render-template`. See [`render`](crate::render).
+  pub templates: HashMap<(TermId, RenderFormat), ArcString>,
 }
 
 /// The merge strategy for a lisp definition, which allows a global to be multiply-declared,
@@ -486,6 +603,9 @@ pub enum ObjectKind {
   /// This is a global lisp definition; hovering yields the lisp definition line and go-to-definition works.
   /// Either `lisp` or `graveyard` for the atom must be non-`None` if this is constructed
   Global(AtomId),
+  /// This is a local lisp variable, bound by a `fn`, `let` or `def` in the enclosing lisp code;
+  /// hovering and go-to-definition do nothing.
+  LispVar(AtomId),
   /// This is an expression; hovering shows the type and go-to-definition goes to the head term definition
   Expr(FrozenLispVal),
   /// This is a proof; hovering shows the intermediate statement
@@ -516,6 +636,25 @@ impl ObjectKind {
   }
 }
 
+impl Remap for ObjectKind {
+  type Target = Self;
+  fn remap(&self, r: &mut Remapper) -> Self {
+    match *self {
+      ObjectKind::Sort(s) => ObjectKind::Sort(s.remap(r)),
+      ObjectKind::Term(t, sp) => ObjectKind::Term(t.remap(r), sp),
+      ObjectKind::Thm(t) => ObjectKind::Thm(t.remap(r)),
+      ObjectKind::Var(a) => ObjectKind::Var(a.remap(r)),
+      ObjectKind::Global(a) => ObjectKind::Global(a.remap(r)),
+      ObjectKind::LispVar(a) => ObjectKind::LispVar(a.remap(r)),
+      ObjectKind::Expr(ref e) => ObjectKind::expr(e.remap(r)),
+      ObjectKind::Proof(ref e) => ObjectKind::proof(e.remap(r)),
+      ObjectKind::Syntax(s) => ObjectKind::Syntax(s),
+      ObjectKind::RefineSyntax(s) => ObjectKind::RefineSyntax(s),
+      ObjectKind::Import(ref f) => ObjectKind::Import(f.clone()),
+    }
+  }
+}
+
 /// The main environment struct, containing all permanent data to be exported from an MM1 file.
 #[derive(Debug, DeepSizeOf)]
 pub struct Environment {
@@ -533,8 +672,32 @@ pub struct Environment {
   pub data: AtomVec<AtomData>,
   /// The global statement order.
   pub stmts: Vec<StmtTrace>,
+  /// The theorems registered as simp lemmas with `@simp` or `(add-simp!)`, in the order
+  /// they were added (see [`simp`](super::simp)).
+  pub simps: Vec<ThmId>,
+  /// The theorems registered as instances with `@instance` or `(add-instance!)`, with their
+  /// priorities, in the order they were added (see [`instance`](super::instance)).
+  pub instances: Vec<(ThmId, u32)>,
+  /// The terms and theorems marked `@[deprecated]`, which cause a warning when used.
+  pub deprecated: HashMap<AtomId, Deprecation>,
+  /// The declarations which are not visible by name in this file, because they were
+  /// imported by a selective or qualified import (see [`import`](super::import)).
+  /// This is not inherited by files that import this one.
+  pub hidden: HashSet<AtomId>,
+  /// The qualified names `F.foo` of the declarations imported with `import "file" as F;`,
+  /// mapped to the declaration `foo`. This is not inherited by files that import this one.
+  pub aliases: HashMap<AtomId, AtomId>,
   /// The list of spans that have been collected in the current statement.
   pub spans: Vec<Spans<ObjectKind>>,
+  /// The reverse dependency index, which is built on first use by [`FrozenEnv::uses_of`]
+  /// (and so is only valid once the environment is frozen).
+  pub(crate) rev_deps: Mutex<Option<Arc<RevDeps>>>,
+  /// The snapshots taken while elaborating this file, which are used to skip the unchanged
+  /// statements when the file is elaborated again (see [`snapshot`](super::snapshot)).
+  pub(crate) snapshots: Vec<Snapshot>,
+  /// The profiling data for the statements of this file, if it was elaborated with
+  /// profiling enabled (see [`profile`](super::profile)).
+  pub(crate) profile: Vec<StmtProfile>,
 }
 
 impl Environment {
@@ -556,7 +719,15 @@ impl Environment {
       terms: Default::default(),
       thms: Default::default(),
       stmts: Default::default(),
+      simps: Default::default(),
+      instances: Default::default(),
+      deprecated: Default::default(),
+      hidden: Default::default(),
+      aliases: Default::default(),
       spans: Default::default(),
+      rev_deps: Default::default(),
+      snapshots: Default::default(),
+      profile: Default::default(),
     }
   }
 }
@@ -608,6 +779,19 @@ pub struct Remapper {
   pub(crate) refs: HashMap<*const FrozenLispRef, LispVal>,
 }
 
+impl Remapper {
+  /// Create a remapper which maps the sorts, terms, theorems and atoms of `env` to themselves.
+  pub(crate) fn identity(env: &FrozenEnv) -> Self {
+    Remapper {
+      sort: env.sorts().enum_iter().map(|(s, _)| s).collect(),
+      term: env.terms().enum_iter().map(|(t, _)| t).collect(),
+      thm: env.thms().enum_iter().map(|(t, _)| t).collect(),
+      atom: env.data().enum_iter().map(|(a, _)| a).collect(),
+      ..Default::default()
+    }
+  }
+}
+
 /// A trait for types that can be remapped.
 /// This is like [`Clone`] except it uses a `&mut R` as auxiliary state.
 pub trait Remap: Sized {
@@ -767,6 +951,7 @@ impl Remap for Proof {
       heap: self.heap.remap(r),
       hyps: self.hyps.remap(r),
       head: self.head.remap(r),
+      spans: self.spans.clone(),
     }
   }
 }
@@ -805,6 +990,7 @@ impl Remap for NotaInfo {
       nargs: self.nargs,
       rassoc: self.rassoc,
       lits: self.lits.clone(),
+      local: self.local,
     }
   }
 }
@@ -951,11 +1137,46 @@ impl ParserEnv {
     Ok(())
   }
 
+  /// The constants used by the notations satisfying `f`.
+  fn nota_consts(&self, mut f: impl FnMut(&NotaInfo) -> bool) -> HashSet<ArcString> {
+    let mut consts = HashSet::new();
+    for (tk, n) in self.prefixes.iter().chain(&self.infixes) {
+      if f(n) {
+        consts.insert(tk.clone());
+        for lit in &n.lits {
+          if let Literal::Const(c) = lit { consts.insert(c.clone()); }
+        }
+      }
+    }
+    consts
+  }
+
+  /// Remove the notations satisfying `f` from the parser, along with the constants
+  /// that are not used by any remaining notation.
+  pub fn remove_notations(&mut self, mut f: impl FnMut(&NotaInfo) -> bool) {
+    if !self.prefixes.values().chain(self.infixes.values()).any(&mut f) { return }
+    self.prefixes.retain(|_, n| !f(n));
+    self.infixes.retain(|_, n| !f(n));
+    let consts = self.nota_consts(|_| true);
+    self.consts.retain(|tk, _| consts.contains(tk));
+    let (prefixes, infixes) = (&self.prefixes, &self.infixes);
+    self.decl_nota.retain(|&t, (has_coe, ls)| {
+      ls.retain(|(tk, infx)|
+        if *infx { infixes } else { prefixes }.get(tk).map_or(false, |n| n.term == t));
+      *has_coe || !ls.is_empty()
+    })
+  }
+
   /// Merge environment `other` into this environment.
-  fn merge(&mut self, other: &Self, r: &mut Remapper, sp: Span, sorts: &SortVec<Sort>, errors: &mut Vec<ElabError>) {
+  /// If `import` is true, then the `local` notations of `other` are skipped.
+  fn merge(&mut self, other: &Self, r: &mut Remapper, sp: Span, sorts: &SortVec<Sort>,
+    import: bool, errors: &mut Vec<ElabError>
+  ) {
     self.delims_l.merge(&other.delims_l);
     self.delims_r.merge(&other.delims_r);
+    let keep = import.then(|| other.nota_consts(|n| !n.local));
     for (tk, &(ref fsp, p)) in &other.consts {
+      if keep.as_ref().map_or(false, |keep| !keep.contains(tk)) { continue }
       self.add_const(tk.clone(), fsp.clone(), p).unwrap_or_else(|r|
         errors.push(ElabError::with_info(sp,
           format!("constant '{}' declared with two precedences", tk).into(),
@@ -968,12 +1189,14 @@ impl ParserEnv {
           vec![(r.decl1, "left assoc here".into()), (r.decl2, "right assoc here".into())])))
     }
     for (tk, i) in &other.prefixes {
+      if import && i.local { continue }
       self.add_prefix(tk.clone(), i.remap(r)).unwrap_or_else(|r|
         errors.push(ElabError::with_info(sp,
           format!("constant '{}' declared twice", tk).into(),
           vec![(r.decl1, "declared here".into()), (r.decl2, "declared here".into())])))
     }
     for (tk, i) in &other.infixes {
+      if import && i.local { continue }
       self.add_infix(tk.clone(), i.remap(r)).unwrap_or_else(|r|
         errors.push(ElabError::with_info(sp,
           format!("constant '{}' declared twice", tk).into(),
@@ -987,6 +1210,9 @@ impl ParserEnv {
         }
       }
     }
+    for (&(t, fmt), tpl) in &other.templates {
+      self.templates.insert((t.remap(r), fmt), tpl.clone());
+    }
     self.update_provs(sp, sorts).unwrap_or_else(|r| errors.push(r))
   }
 }
@@ -1018,10 +1244,62 @@ impl Environment {
   #[must_use] pub fn thm(&self, a: AtomId) -> Option<ThmId> {
     if let Some(DeclKey::Thm(i)) = self.data[a].decl { Some(i) } else { None }
   }
+
+  /// A hint for an error about the unknown name `a`, suggesting the names satisfying `f` which
+  /// are [near misses](near_misses) of it. This is appended to the error message, so it is
+  /// either empty or `", did you mean 'foo'?"`.
+  #[must_use] pub fn did_you_mean(&self, a: AtomId, f: impl Fn(AtomId, &AtomData) -> bool) -> String {
+    let cands = self.data.enum_iter().filter(|&(b, ad)| b != a && f(b, ad)).map(|(b, ad)| (b, &*ad.name));
+    let names = near_misses(&self.data[a].name, cands).into_iter()
+      .map(|b| format!("'{}'", self.data[b].name)).collect::<Vec<_>>();
+    match names.split_last() {
+      None => String::new(),
+      Some((last, [])) => format!(", did you mean {}?", last),
+      Some((last, rest)) => format!(", did you mean {} or {}?", rest.join(", "), last),
+    }
+  }
+}
+
+/// The edit distance between `a` and `b`, counting insertions, deletions, substitutions
+/// and transpositions of adjacent characters.
+#[must_use] pub fn edit_distance(a: &[u8], b: &[u8]) -> usize {
+  // `d[i][j]` is the distance between `a[..i]` and `b[..j]`; only the last three rows are kept.
+  let mut rows = [vec![0; b.len() + 1], vec![0; b.len() + 1], (0..=b.len()).collect::<Vec<_>>()];
+  for i in 1..=a.len() {
+    rows.rotate_left(1);
+    let [r2, r1, r] = &mut rows;
+    r[0] = i;
+    for j in 1..=b.len() {
+      let cost = usize::from(a[i-1] != b[j-1]);
+      r[j] = (r1[j] + 1).min(r[j-1] + 1).min(r1[j-1] + cost);
+      if i > 1 && j > 1 && a[i-1] == b[j-2] && a[i-2] == b[j-1] {
+        r[j] = r[j].min(r2[j-2] + 1)
+      }
+    }
+  }
+  rows[2][b.len()]
+}
+
+/// Find the candidates whose names are closest to `name` by [`edit_distance`], if they are close
+/// enough to be a typo of it: at most a third of its length apart, and at most 3. At most 3 of
+/// the closest candidates are returned, in the order they are given.
+pub fn near_misses<T, S: AsRef<[u8]>>(name: &[u8], cands: impl IntoIterator<Item=(T, S)>) -> Vec<T> {
+  #[allow(clippy::integer_division)] // a third, rounded down
+  let mut max = (name.len() / 3).clamp(1, 3);
+  let mut res = vec![];
+  for (t, s) in cands {
+    let s = s.as_ref();
+    // The distance is at least the difference in lengths.
+    if s.len() + max < name.len() || name.len() + max < s.len() { continue }
+    let d = edit_distance(name, s);
+    if d < max { max = d; res.clear() }
+    if d == max && res.len() < 3 { res.push(t) }
+  }
+  res
 }
 
 /// Adding an item (sort, term, theorem, atom) can result in a redeclaration error,
-/// or an overflow error (especially for sorts, which can only have 128 due to the
+/// or an overflow error (especially for sorts, which can only have 256 due to the
 /// MMB format). The redeclaration case allows returning a value `A`.
 #[derive(Debug)]
 pub enum AddItemError<A> {
@@ -1166,6 +1444,19 @@ impl Environment {
     })
   }
 
+  /// Allocate a fresh atom whose name is `prefix` followed by a number, such that
+  /// the name is distinct from every atom allocated so far. (The resulting name is a
+  /// valid identifier if `prefix` is.)
+  pub fn gensym(&mut self, prefix: &[u8]) -> AtomId {
+    let mut n = self.data.len();
+    loop {
+      let mut s = prefix.to_vec();
+      s.extend_from_slice(n.to_string().as_bytes());
+      if !self.atoms.contains_key(&*s) { return self.get_atom_arc(s.into()) }
+      n += 1;
+    }
+  }
+
   /// Merge statement `s` from `other` into this environment.
   /// This merges definitions with the same name and type,
   /// and relabels lisp objects with the new [`AtomId`] mapping.
@@ -1255,6 +1546,7 @@ pub struct EnvMergeIter<'a> {
   remap: Remapper,
   other: &'a FrozenEnv,
   sp: Span,
+  import: bool,
   it: std::slice::Iter<'a, StmtTrace>,
 }
 
@@ -1284,7 +1576,13 @@ impl<'a> EnvMergeIter<'a> {
       atom: other.data().iter().map(|d| env.get_atom_arc(d.name().clone())).collect(),
       ..Default::default()
     };
-    Self {remap, other, sp, it: other.stmts().iter()}
+    Self {remap, other, sp, import: false, it: other.stmts().iter()}
+  }
+
+  /// Starts an environment merge operation for an `import` statement, which is the same
+  /// as [`new`](Self::new) except that the `local` notations of `other` are not merged.
+  pub fn new_import(env: &mut Environment, other: &'a FrozenEnv, sp: Span) -> Self {
+    Self {import: true, ..Self::new(env, other, sp)}
   }
 
   /// Poll the environment merge iterator for a result.
@@ -1313,7 +1611,19 @@ impl<'a> EnvMergeIter<'a> {
         env.merge_no_lisp(&mut self.remap, self.other, s, self.sp, errors)?;
       }
     }
-    env.pe.merge(self.other.pe(), &mut self.remap, self.sp, &env.sorts, errors);
+    env.pe.merge(self.other.pe(), &mut self.remap, self.sp, &env.sorts, self.import, errors);
+    for &t in self.other.simps() {
+      let t = self.remap.thm[t];
+      if !env.simps.contains(&t) { env.simps.push(t) }
+    }
+    for &(t, prio) in self.other.instances() {
+      let t = self.remap.thm[t];
+      if !env.instances.iter().any(|&(t2, _)| t == t2) { env.instances.push((t, prio)) }
+    }
+    for (&a, d) in self.other.deprecated() {
+      let replacement = d.replacement.map(|b| self.remap.atom[b]);
+      env.deprecated.insert(self.remap.atom[a], Deprecation {replacement, msg: d.msg.clone()});
+    }
     Ok(None)
   }
 }
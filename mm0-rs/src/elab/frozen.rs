@@ -35,12 +35,12 @@ use std::cell::{Cell, RefCell};
 use std::ops::Deref;
 use std::sync::Arc;
 use std::rc::Rc;
-use std::collections::{HashMap, hash_map::Entry};
-use num::BigInt;
-use crate::{mk_lisp_kind, ArcString, AtomData, AtomId, AtomVec, DeclKey, DocComment, Environment,
+use std::collections::{HashMap, HashSet, hash_map::Entry};
+use num::BigRational;
+use crate::{mk_lisp_kind, ArcString, MutexExt, AtomData, AtomId, AtomVec, DeclKey, Deprecation, DocComment, Environment,
   FileSpan, LinedString, LispData, LispKind, LispVal, MergeStrategy, MergeStrategyInner, ParserEnv, Sort,
-  SortId, SortVec, Span, StmtTrace, Term, TermId, TermVec, Thm, ThmId, ThmVec,
-  lisp::{print::FormatEnv, Annot, InferTarget, LispRef, LispWeak, Proc, Syntax}};
+  SortId, SortVec, Span, ElabError, StmtTrace, Term, TermId, TermVec, Thm, ThmId, ThmVec,
+  lisp::{print::FormatEnv, Annot, HashKey, InferTarget, Int, LispFuture, LispRef, LispWeak, Proc, Syntax}};
 use super::{ObjectKind, Remap, Remapper, Spans};
 
 /// A "frozen" environment, which is a thread-safe read only
@@ -97,10 +97,83 @@ impl FrozenEnv {
   #[must_use] pub fn thm(&self, t: ThmId) -> &Thm { &self.thms()[t] }
   /// Accessor for [`Environment::stmts`]
   #[must_use] pub fn stmts(&self) -> &[StmtTrace] { &unsafe { self.thaw() }.stmts }
+  /// Accessor for [`Environment::simps`]
+  #[must_use] pub fn simps(&self) -> &[ThmId] { &unsafe { self.thaw() }.simps }
+  /// Accessor for [`Environment::instances`]
+  #[must_use] pub fn instances(&self) -> &[(ThmId, u32)] { &unsafe { self.thaw() }.instances }
+  /// Accessor for [`Environment::deprecated`]
+  #[must_use] pub fn deprecated(&self) -> &HashMap<AtomId, Deprecation> { &unsafe { self.thaw() }.deprecated }
+  /// Accessor for [`Environment::hidden`]
+  #[must_use] pub fn hidden(&self) -> &HashSet<AtomId> { &unsafe { self.thaw() }.hidden }
+  /// Accessor for [`Environment::aliases`]
+  #[must_use] pub fn aliases(&self) -> &HashMap<AtomId, AtomId> { &unsafe { self.thaw() }.aliases }
   /// Parse a string into an atom.
   #[must_use] pub fn get_atom(&self, s: &[u8]) -> Option<AtomId> { unsafe { self.thaw() }.atoms.get(s).copied() }
   /// Accessor for [`Environment::pe`]
   #[must_use] pub fn pe(&self) -> &ParserEnv { &unsafe { self.thaw() }.pe }
+
+  /// Call `f` on every declaration used by `k`. See [`Thm::for_each_dep`].
+  pub fn for_each_dep(&self, k: DeclKey, f: impl FnMut(DeclKey)) {
+    match k {
+      DeclKey::Term(t) => self.term(t).for_each_dep(f),
+      DeclKey::Thm(t) => self.thm(t).for_each_dep(f),
+    }
+  }
+
+  /// Get the reverse dependency index, building it if this is the first call.
+  fn rev_deps(&self) -> Arc<RevDeps> {
+    unsafe { self.thaw() }.rev_deps.ulock().get_or_insert_with(|| {
+      let mut idx = RevDeps {
+        terms: TermVec(vec![vec![]; self.terms().len()]),
+        thms: ThmVec(vec![vec![]; self.thms().len()]),
+      };
+      for s in self.stmts() {
+        let k = if let StmtTrace::Decl(a) = *s {self.data()[a].decl()} else {None};
+        let k = if let Some(k) = k {k} else {continue};
+        self.for_each_dep(k, |k2| {
+          let v = idx.get_mut(k2);
+          // The uses of `k2` by `k` are pushed consecutively, so this removes duplicates
+          if k != k2 && v.last() != Some(&k) { v.push(k) }
+        })
+      }
+      Arc::new(idx)
+    }).clone()
+  }
+
+  /// Returns the declarations which use `k` directly, in declaration order
+  /// (see [`for_each_dep`](Self::for_each_dep)).
+  ///
+  /// This uses an index which is built by the first call and then shared by all
+  /// clones of this environment, so it is cheap to call repeatedly.
+  pub fn uses_of(&self, k: DeclKey) -> impl Iterator<Item=DeclKey> {
+    let idx = self.rev_deps();
+    (0..idx.get(k).len()).map(move |i| idx.get(k)[i])
+  }
+}
+
+/// The reverse dependency index of an environment. See [`FrozenEnv::uses_of`].
+#[derive(Debug, DeepSizeOf)]
+pub(crate) struct RevDeps {
+  /// The declarations using each term
+  terms: TermVec<Vec<DeclKey>>,
+  /// The declarations using each theorem
+  thms: ThmVec<Vec<DeclKey>>,
+}
+
+impl RevDeps {
+  fn get(&self, k: DeclKey) -> &[DeclKey] {
+    match k {
+      DeclKey::Term(t) => &self.terms[t],
+      DeclKey::Thm(t) => &self.thms[t],
+    }
+  }
+
+  fn get_mut(&mut self, k: DeclKey) -> &mut Vec<DeclKey> {
+    match k {
+      DeclKey::Term(t) => &mut self.terms[t],
+      DeclKey::Thm(t) => &mut self.thms[t],
+    }
+  }
 }
 
 /// A wrapper around an [`AtomData`] that is frozen.
@@ -366,6 +439,17 @@ impl Remap for FrozenLispKind {
       FrozenLispKind::Annot(sp, m) => LispVal::new(LispKind::Annot(sp.clone(), m.remap(r))),
       FrozenLispKind::Proc(f) => LispVal::proc(f.remap(r)),
       FrozenLispKind::AtomMap(m) => LispVal::new(LispKind::AtomMap(m.remap(r))),
+      FrozenLispKind::HashTable(m) => LispVal::new(LispKind::HashTable(
+        m.iter().map(|(k, v)| (k.remap(r), v.remap(r))).collect())),
+      FrozenLispKind::Vector(m) => {
+        let vec = LispVal::vector(vec![]);
+        r.lisp.insert(ptr, vec.clone());
+        let es = unsafe { m.try_borrow_unguarded() }.expect("failed to deref vector").remap(r);
+        if let LispKind::Vector(v) = &*vec {*v.borrow_mut() = es}
+        vec
+      }
+      FrozenLispKind::StringPort(s) => LispVal::new(LispKind::StringPort(RefCell::new(
+        unsafe { s.try_borrow_unguarded() }.expect("failed to deref port").clone()))),
       FrozenLispKind::Ref(m) => match r.refs.entry(m as *const _) {
         Entry::Occupied(e) => e.get().clone(),
         Entry::Vacant(e) => {
@@ -380,6 +464,7 @@ impl Remap for FrozenLispKind {
       &FrozenLispKind::MVar(n, is) => LispVal::new(LispKind::MVar(n, is.remap(r))),
       FrozenLispKind::Goal(e) => LispVal::new(LispKind::Goal(e.remap(r))),
       FrozenLispKind::Number(n) => LispVal::number(n.clone()),
+      FrozenLispKind::Rational(q) => LispVal::new(LispKind::Rational(q.clone())),
       FrozenLispKind::String(s) => LispVal::string(s.clone()),
       &FrozenLispKind::Bool(b) => LispVal::bool(b),
       &FrozenLispKind::Syntax(s) => LispVal::syntax(s),
@@ -401,17 +486,27 @@ impl Remap for FrozenProc {
   fn remap(&self, r: &mut Remapper) -> Proc {
     match &self.0 {
       &Proc::Builtin(p) => Proc::Builtin(p),
-      &Proc::Lambda {ref pos, ref env, spec, ref code} =>
-        Proc::Lambda {pos: pos.remap(r), env: env.remap(r), spec, code: code.remap(r)},
+      &Proc::Lambda {ref pos, ref env, spec, ref doc, ref code} =>
+        Proc::Lambda {pos: pos.remap(r), env: env.remap(r), spec, doc: doc.clone(), code: code.remap(r)},
       Proc::MatchCont(_) => Proc::MatchCont(Rc::new(Cell::new(false))),
+      Proc::DelimCont(..) => Proc::DelimCont(Rc::new(Cell::new(false)), 0),
       Proc::RefineCallback => Proc::RefineCallback,
       Proc::MergeMap(m) => Proc::MergeMap(unsafe {freeze_merge_strategy(m)}.remap(r)),
+      Proc::Macro(e) => Proc::Macro(e.remap(r)),
       Proc::ProofThunk(x, m) => Proc::ProofThunk(x.remap(r), RefCell::new(
         match &*unsafe { m.try_borrow_unguarded() }.expect("failed to deref ref") {
           Ok(e) => Ok(e.remap(r)),
           Err(v) => Err(v.remap(r)),
         }
       )),
+      Proc::Future(fut) => Proc::Future(RefCell::new(LispFuture::Done(
+        match &*unsafe { fut.try_borrow_unguarded() }.expect("failed to deref ref") {
+          LispFuture::Done(Ok(e)) => Ok(e.remap(r)),
+          LispFuture::Done(Err(e)) => Err(e.clone()),
+          LispFuture::Running(_) =>
+            Err(ElabError::new_e(Span::default(), "await: the task was started in another file")),
+        }
+      ))),
       #[cfg(feature = "mmc")]
       Proc::MmcCompiler(c) => Proc::MmcCompiler(c.remap(r)),
     }
@@ -0,0 +1,118 @@
+//! Selective and qualified imports.
+//!
+//! A plain `import "file.mm1";` makes all the declarations of the imported file visible
+//! by name. The declarations brought in by an import can be restricted in two ways:
+//!
+//! * `import "file.mm1" (foo bar);` makes only the terms and theorems `foo` and `bar`
+//!   visible by name.
+//! * `import "file.mm1" as F;` makes the declarations visible as `F.foo` instead of `foo`.
+//!
+//! In both cases the other declarations are still imported, and they can be used through
+//! notations, by lisp code and by the imported theorems; they are only hidden when used by
+//! name in a math expression or in `refine`. A declaration which is already visible,
+//! because it was declared or imported before, stays visible. The hidden declarations and
+//! the qualified names are local to the importing file: a file importing it in turn sees all
+//! of its declarations.
+
+use std::collections::HashSet;
+use crate::{AtomId, FrozenEnv, Span};
+use crate::ast::{ImportFilter, StmtKind};
+use super::{namespace::qualify, ElabError, Elaborator, Result};
+
+/// A declaration of an imported file.
+struct Imported {
+  /// The name of the declaration.
+  name: crate::ArcString,
+  /// True if the declaration was already visible by name before the import.
+  visible: bool,
+}
+
+impl Elaborator {
+  /// Find the filter of the import statement whose file name is at `sp`.
+  fn import_filter(&self, sp: Span) -> Option<ImportFilter> {
+    self.ast.stmts.iter().find_map(|s| {
+      let mut k = &s.k;
+      while let StmtKind::DocComment(_, s2) | StmtKind::Annot(_, s2) | StmtKind::Attrs(_, s2) = k {
+        k = &s2.k
+      }
+      match k {
+        StmtKind::Import(sp2, _, f) if *sp2 == sp => Some(f.clone()),
+        _ => None,
+      }
+    }).flatten()
+  }
+
+  /// Returns true if the declaration `a` is visible by name in this file.
+  fn is_visible(&self, a: AtomId) -> bool {
+    self.data[a].decl.is_some() && !self.hidden.contains(&a)
+  }
+
+  /// Import the environment `env` of the file imported by the import statement at `sp`,
+  /// applying the filter of the import statement, as described in the
+  /// [module documentation](self).
+  pub(crate) fn import_file(&mut self, sp: Span, env: &FrozenEnv) {
+    let filter = self.import_filter(sp);
+    if filter.is_some() && self.mm0_mode {
+      self.report(ElabError::new_e(sp, "(MM0 mode) selective imports not allowed"))
+    }
+    let decls: Vec<_> = env.data().iter()
+      .filter(|d| d.decl().is_some())
+      .map(|d| Imported {
+        name: d.name().clone(),
+        visible: self.atoms.get(d.name()).map_or(false, |&a| self.is_visible(a)),
+      })
+      .collect();
+    self.import_env(sp, env, false);
+    let hide = |this: &mut Self, d: &Imported, a: AtomId, keep: bool| {
+      if keep { this.env.hidden.remove(&a); } else if !d.visible { this.env.hidden.insert(a); }
+    };
+    match filter {
+      _ if self.mm0_mode => {}
+      None => for d in &decls {
+        let a = self.env.get_atom(&d.name);
+        hide(self, d, a, true)
+      }
+      Some(ImportFilter::Only(names)) => {
+        let mut kept = HashSet::new();
+        for sp2 in names {
+          let name = self.span(sp2);
+          if let Some(d) = decls.iter().find(|d| &*d.name == name) {
+            kept.insert(d.name.clone());
+          } else {
+            let msg = format!("'{}' is not declared in the imported file",
+              String::from_utf8_lossy(name));
+            self.report(ElabError::new_e(sp2, msg))
+          }
+        }
+        for d in &decls {
+          let a = self.env.get_atom(&d.name);
+          hide(self, d, a, kept.contains(&d.name))
+        }
+      }
+      Some(ImportFilter::As(sp2)) => {
+        let ns = self.span(sp2).to_owned();
+        for d in &decls {
+          let a = self.env.get_atom(&d.name);
+          let b = self.env.get_atom(&qualify(&ns, &d.name));
+          if self.data[b].decl.is_some() {
+            let msg = format!("'{}' is already declared", self.data[b].name);
+            self.report(ElabError::new_e(sp2, msg))
+          } else {
+            self.env.aliases.insert(b, a);
+          }
+          hide(self, d, a, false)
+        }
+      }
+    }
+  }
+
+  /// Report an error at `sp` if the declaration `a` is used by name there, but it is
+  /// hidden by a selective or qualified import.
+  pub(crate) fn check_visible(&self, sp: Span, a: AtomId) -> Result<()> {
+    if self.hidden.contains(&a) && self.span(sp) == &*self.data[a].name {
+      return Err(ElabError::new_e(sp, format!(
+        "'{}' is hidden by a selective or qualified import", self.data[a].name)))
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,120 @@
+//! Instance resolution, which proves goals like closure properties automatically.
+//!
+//! An instance is a theorem or axiom whose conclusion is an application of a term
+//! constructor, the *class* of the instance, like `addcl: $ Nat a $ > $ Nat b $ > $ Nat (a + b) $`
+//! for the class `Nat`. Instances are registered in the environment using the `@instance`
+//! attribute or `(add-instance!)`, and a goal whose head is a class is proved by applying
+//! the instances of the class backwards, and then proving their hypotheses in the same way.
+//! Instances with a higher priority are tried first, and among instances with the same
+//! priority, the most recently added one is tried first. The search uses the
+//! [`auto`](super::auto) engine, restricted to the instances and the hypotheses in the
+//! local context, and when it fails, the error describes the deepest subgoal that could
+//! not be proved.
+//!
+//! Instance resolution is available as `(instance e)`, and it is also run on the goals
+//! whose head is a class that remain at the end of a proof.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::mem;
+use crate::{AtomId, DeclKey, ExprNode, LispVal, TermId, Thm, ThmId};
+use super::Elaborator;
+use super::auto::{Lemmas, Search};
+
+/// The priority of an instance registered with `@instance`.
+pub const DEFAULT_PRIORITY: u32 = 1000;
+
+/// The maximum number of nested instance applications.
+const MAX_DEPTH: u32 = 32;
+
+/// Get the class of theorem `td`, that is, the head of its conclusion, if it is a term
+/// constructor application.
+fn class(td: &Thm) -> Option<TermId> {
+  let mut e = &td.ret;
+  loop {
+    match *e {
+      ExprNode::Ref(i) if i >= td.args.len() => e = &td.heap[i],
+      ExprNode::App(t, _) => return Some(t),
+      _ => return None,
+    }
+  }
+}
+
+impl Elaborator {
+  /// Register the theorem `x` as an instance with priority `prio`, or return an error
+  /// message if it cannot be used as an instance. Registering an instance again changes
+  /// its priority.
+  pub fn add_instance(&mut self, x: AtomId, prio: u32) -> Result<(), String> {
+    let t = match self.data[x].decl {
+      Some(DeclKey::Thm(t)) => t,
+      _ => return Err(format!("'{}' is not a theorem", self.data[x].name)),
+    };
+    if class(&self.thms[t]).is_none() {
+      return Err(format!("the conclusion of '{}' is a variable", self.data[x].name))
+    }
+    if let Some(inst) = self.env.instances.iter_mut().find(|inst| inst.0 == t) {
+      inst.1 = prio
+    } else {
+      self.env.instances.push((t, prio))
+    }
+    Ok(())
+  }
+
+  /// Get the instances of each class, in the order they are tried.
+  fn instance_table(&self) -> HashMap<TermId, Vec<ThmId>> {
+    let mut table: HashMap<TermId, Vec<(u32, usize, ThmId)>> = HashMap::new();
+    for (i, &(t, prio)) in self.instances.iter().enumerate() {
+      if let Some(c) = class(&self.thms[t]) { table.entry(c).or_default().push((prio, i, t)) }
+    }
+    table.into_iter().map(|(c, mut insts)| {
+      insts.sort_unstable_by(|a, b| b.cmp(a));
+      (c, insts.into_iter().map(|(_, _, t)| t).collect())
+    }).collect()
+  }
+
+  /// Prove `goal` by instance resolution. Returns the proof, or an error message which
+  /// describes the deepest subgoal that could not be proved.
+  pub fn resolve_instance(&self, goal: &LispVal) -> Result<LispVal, String> {
+    let mut search = Search::new(self, Lemmas::Instances(self.instance_table()));
+    if search.run(goal, MAX_DEPTH).map_err(|e| format!("instance: {}", e))? {
+      return Ok(search.proof(&mut 0, false))
+    }
+    let fe = self.format_env();
+    let mut msg = format!("instance: failed to prove {}", fe.pp(goal, 80));
+    if let Some(fail) = &search.fail {
+      write!(msg, "\nno instance applies to {}", fe.pp(&fail.goal, 80)).expect("writing to a string");
+      if !fail.tried.is_empty() {
+        let tried = fail.tried.iter().map(|&t| self.data[self.thms[t].atom].name.to_string())
+          .collect::<Vec<_>>();
+        write!(msg, " (tried {})", tried.join(", ")).expect("writing to a string");
+      }
+    }
+    Err(msg)
+  }
+
+  /// Prove the goals whose head is a class by instance resolution. The goals that are
+  /// proved are removed from the goal list, and the others are left in place, except for
+  /// the goals for which resolution fails, which are removed and returned with the error.
+  pub fn resolve_instance_goals(&mut self) -> Vec<(LispVal, String)> {
+    let mut errors = vec![];
+    if self.instances.is_empty() { return errors }
+    let classes = self.instance_table();
+    for g in mem::take(&mut self.lc.goals) {
+      if let Some(ty) = g.goal_type() {
+        let head = ty.unwrapped(|r| match r {
+          crate::LispKind::List(es) if !es.is_empty() => es[0].as_atom(),
+          _ => None,
+        }).and_then(|a| self.term(a));
+        if head.map_or(false, |t| classes.contains_key(&t)) {
+          match self.resolve_instance(&ty) {
+            Ok(p) => { g.as_ref_(|e| *e = p).expect("a goal is a ref"); }
+            Err(msg) => errors.push((g, msg)),
+          }
+          continue
+        }
+      }
+      self.lc.goals.push(g)
+    }
+    errors
+  }
+}
@@ -0,0 +1,364 @@
+//! A decision procedure for linear arithmetic, which proves a goal `t <= u` or `t < u`,
+//! or a contradiction, from hypotheses that are linear inequalities and equations.
+//!
+//! The hypotheses are parsed into linear forms over the atomic subterms (anything that is not
+//! an addition, a multiplication by a numeral, or a numeral), and Fourier–Motzkin elimination
+//! is used to find nonnegative integer multipliers for the hypotheses whose sum is a trivially
+//! false inequality `u + c <= u` where `c > 0`. The proof then scales and adds the hypotheses
+//! using the lemmas of the library, which are given in a table, since the arithmetic is not
+//! built in. See [`BuiltinProc::Lia`](super::lisp::BuiltinProc::Lia) for the interface.
+//!
+//! The elimination is done over the rationals, so it is complete for rational arithmetic
+//! but not for integer arithmetic. Strict inequalities `t < u` are strengthened to
+//! `t + 1 <= u`, which is enough for most goals about the integers and natural numbers.
+
+use std::collections::HashMap;
+use num::{BigInt, Integer, One, Signed, Zero};
+use crate::{AtomId, LispKind, LispVal, Span};
+use super::{ElabError, Elaborator, Result};
+
+/// The limit on the number of inequalities during elimination, after which the search gives up.
+const MAX_ROWS: usize = 4096;
+
+/// A proof step in the [`LiaTable`]. Each step is either a theorem, which is applied to
+/// the proofs, or a function, which is called with the proofs followed by the terms.
+#[derive(Copy, Clone, Debug)]
+enum Step {
+  /// `(lt->le p t u)`: from `p: t < u` prove `t + 1 <= u`.
+  LtLe,
+  /// `(eq->le p t u)`: from `p: t = u` prove `t <= u`.
+  EqLe,
+  /// `(eq->ge p t u)`: from `p: t = u` prove `u <= t`.
+  EqGe,
+  /// `(le-mul p k t u)`: from `p: t <= u` prove `k * t <= k * u`, where `k` is a numeral.
+  LeMul,
+  /// `(le-add p q t u t2 u2)`: from `p: t <= u` and `q: t2 <= u2` prove `t + t2 <= u + u2`.
+  LeAdd,
+  /// `(contra p t u c)`: from `p: t <= u`, where `t = u + c` and `c` is a positive numeral,
+  /// prove a contradiction.
+  Contra,
+}
+
+/// The names of the proof steps, in the order of [`Step`].
+const STEPS: [&str; 6] = ["lt->le", "eq->le", "eq->ge", "le-mul", "le-add", "contra"];
+
+/// The table of term constructors and proof steps used by [`Elaborator::lia`].
+#[derive(Debug)]
+pub struct LiaTable {
+  /// The term constructors for `t <= u`, `t < u`, `t = u`, `t + u` and `t * u`.
+  le: AtomId,
+  lt: AtomId,
+  eq: AtomId,
+  add: AtomId,
+  mul: AtomId,
+  /// `(num e)` returns the value of `e` if it is a numeral, and `#undef` otherwise.
+  num: LispVal,
+  /// `(numeral n)` returns a numeral with value `n`.
+  numeral: LispVal,
+  /// The proof steps, indexed by [`Step`].
+  steps: [LispVal; 6],
+  /// `(nonneg x)` proves `0 <= x` for an atomic subterm `x`, or returns `#undef`.
+  nonneg: Option<LispVal>,
+  /// `(assume g)` returns a proof of `g`, the negation of the goal, as a hypothesis.
+  assume: Option<LispVal>,
+  /// `(by-contra p g)` proves the goal `g`, where `p` proves a contradiction using the
+  /// hypothesis returned by `assume`.
+  by_contra: Option<LispVal>,
+}
+
+impl LiaTable {
+  /// Read the table from an atom map. See [`BuiltinProc::Lia`](super::lisp::BuiltinProc::Lia)
+  /// for the keys.
+  pub fn new(elab: &mut Elaborator, m: &HashMap<AtomId, LispVal>) -> std::result::Result<Self, String> {
+    let mut get = |s: &str| m.get(&elab.get_atom(s.as_bytes())).cloned();
+    let req = |e: Option<LispVal>, s: &str| e.ok_or_else(|| format!("lia: missing '{}", s));
+    let term = |e: Option<LispVal>, s: &str|
+      req(e, s)?.as_atom().ok_or_else(|| format!("lia: '{}' should be a term constructor", s));
+    Ok(Self {
+      le: term(get("le"), "le")?,
+      lt: term(get("lt"), "lt")?,
+      eq: term(get("eq"), "eq")?,
+      add: term(get("add"), "add")?,
+      mul: term(get("mul"), "mul")?,
+      num: req(get("num"), "num")?,
+      numeral: req(get("numeral"), "numeral")?,
+      steps: [req(get(STEPS[0]), STEPS[0])?, req(get(STEPS[1]), STEPS[1])?,
+        req(get(STEPS[2]), STEPS[2])?, req(get(STEPS[3]), STEPS[3])?,
+        req(get(STEPS[4]), STEPS[4])?, req(get(STEPS[5]), STEPS[5])?],
+      nonneg: get("nonneg"),
+      assume: get("assume"),
+      by_contra: get("by-contra"),
+    })
+  }
+}
+
+/// A hypothesis `t <= u`, with the way to prove it, which is only done if the
+/// hypothesis is used in the final proof.
+struct Ineq {
+  t: LispVal,
+  u: LispVal,
+  proof: HypProof,
+}
+
+/// The proof of an [`Ineq`].
+enum HypProof {
+  /// A proof of `t <= u`.
+  Done(LispVal),
+  /// The step which proves `t <= u` from a proof of an input hypothesis `t0 R u0`,
+  /// with the proof, `t0` and `u0`.
+  Step(Step, LispVal, LispVal, LispVal),
+}
+
+/// A linear inequality `a . x + c <= 0`, where `x` are the atomic subterms, with the
+/// multipliers of the hypotheses whose sum is this inequality.
+#[derive(Clone)]
+struct Row {
+  a: Vec<BigInt>,
+  c: BigInt,
+  mult: Vec<BigInt>,
+}
+
+impl Row {
+  /// Returns true if this is the trivially false inequality `c <= 0` where `c > 0`.
+  fn is_contra(&self) -> bool { self.a.iter().all(Zero::is_zero) && self.c.is_positive() }
+
+  /// Eliminate variable `j` from `pos` and `neg`, which have a positive and negative
+  /// coefficient for `j` respectively.
+  fn combine(pos: &Row, neg: &Row, j: usize) -> Row {
+    let (kp, kn) = (-&neg.a[j], pos.a[j].clone());
+    let lin = |x: &BigInt, y: &BigInt| &kp * x + &kn * y;
+    let mut r = Row {
+      a: pos.a.iter().zip(&neg.a).map(|(x, y)| lin(x, y)).collect(),
+      c: lin(&pos.c, &neg.c),
+      mult: pos.mult.iter().zip(&neg.mult).map(|(x, y)| lin(x, y)).collect(),
+    };
+    let g = r.a.iter().chain(Some(&r.c)).chain(&r.mult).fold(BigInt::zero(), |g, x| g.gcd(x));
+    if g > BigInt::one() {
+      for x in r.a.iter_mut().chain(Some(&mut r.c)).chain(&mut r.mult) { *x /= &g }
+    }
+    r
+  }
+}
+
+/// Find multipliers for the rows whose sum is trivially false, by Fourier–Motzkin elimination.
+fn eliminate(mut rows: Vec<Row>, nvars: usize) -> Option<Row> {
+  loop {
+    if let Some(r) = rows.iter().find(|r| r.is_contra()) { return Some(r.clone()) }
+    rows.retain(|r| r.a.iter().any(|x| !x.is_zero()));
+    // Eliminate the variable that produces the fewest new rows.
+    let (j, _) = (0..nvars).map(|j| {
+      let pos = rows.iter().filter(|r| r.a[j].is_positive()).count();
+      let neg = rows.iter().filter(|r| r.a[j].is_negative()).count();
+      (j, (pos * neg, pos + neg))
+    }).filter(|(_, (_, n))| *n != 0).min_by_key(|(_, k)| *k)?;
+    let (mut pos, mut neg, mut rest) = (vec![], vec![], vec![]);
+    for r in rows {
+      if r.a[j].is_positive() { pos.push(r) }
+      else if r.a[j].is_negative() { neg.push(r) }
+      else { rest.push(r) }
+    }
+    if rest.len() + pos.len() * neg.len() > MAX_ROWS { return None }
+    for p in &pos { for n in &neg { rest.push(Row::combine(p, n, j)) } }
+    rows = rest;
+  }
+}
+
+/// Build the application `(t e1 e2)` of a binary term constructor.
+fn app(t: AtomId, e1: LispVal, e2: LispVal) -> LispVal {
+  LispVal::list(vec![LispVal::atom(t), e1, e2])
+}
+
+/// The state of the decision procedure.
+struct Lia<'a> {
+  elab: &'a mut Elaborator,
+  sp: Span,
+  table: &'a LiaTable,
+  /// The atomic subterms.
+  vars: Vec<LispVal>,
+}
+
+impl Lia<'_> {
+  fn call(&mut self, f: LispVal, args: Vec<LispVal>) -> Result<LispVal> {
+    self.elab.call_func(self.sp, f, args)
+  }
+
+  /// Apply a proof step to the proofs `ps` of the hypotheses, and the terms `ts`.
+  fn step(&mut self, s: Step, ps: Vec<LispVal>, ts: Vec<LispVal>) -> Result<LispVal> {
+    let f = self.table.steps[s as usize].clone();
+    match f.as_atom() {
+      Some(a) => Ok(LispVal::list(std::iter::once(LispVal::atom(a)).chain(ps).collect::<Vec<_>>())),
+      None => self.call(f, ps.into_iter().chain(ts).collect()),
+    }
+  }
+
+  fn numeral(&mut self, n: BigInt) -> Result<LispVal> {
+    self.call(self.table.numeral.clone(), vec![LispVal::number(n)])
+  }
+
+  /// Get the value of `e` if it is a numeral.
+  fn num(&mut self, e: &LispVal) -> Result<Option<BigInt>> {
+    let n = self.call(self.table.num.clone(), vec![e.clone()])?;
+    Ok(n.as_int(|n| n.clone().into()))
+  }
+
+  /// Get the index of the atomic subterm `e`.
+  fn var(&mut self, e: &LispVal) -> usize {
+    if let Some(i) = self.vars.iter().position(|v| v == e) { return i }
+    self.vars.push(e.clone());
+    self.vars.len() - 1
+  }
+
+  /// If `e` is `t R u` for one of the relations `le`, `lt` or `eq`, return `(R, t, u)`.
+  fn literal(&self, e: &LispVal) -> Option<(AtomId, LispVal, LispVal)> {
+    e.unwrapped(|r| match r {
+      LispKind::List(es) if es.len() == 3 => es[0].as_atom()
+        .filter(|&r| [self.table.le, self.table.lt, self.table.eq].contains(&r))
+        .map(|r| (r, es[1].clone(), es[2].clone())),
+      _ => None,
+    })
+  }
+
+  /// Add `k * e` to the linear form `(coeffs, cst)`.
+  fn linear(&mut self, e: &LispVal, k: &BigInt,
+    coeffs: &mut HashMap<usize, BigInt>, cst: &mut BigInt,
+  ) -> Result<()> {
+    let app = e.unwrapped(|r| match r {
+      LispKind::List(es) if es.len() == 3 => es[0].as_atom().map(|t| (t, es[1].clone(), es[2].clone())),
+      _ => None,
+    });
+    match app {
+      Some((t, e1, e2)) if t == self.table.add => {
+        self.linear(&e1, k, coeffs, cst)?;
+        return self.linear(&e2, k, coeffs, cst)
+      }
+      Some((t, e1, e2)) if t == self.table.mul => {
+        if let Some(n) = self.num(&e1)? { return self.linear(&e2, &(k * n), coeffs, cst) }
+        if let Some(n) = self.num(&e2)? { return self.linear(&e1, &(k * n), coeffs, cst) }
+      }
+      _ => if let Some(n) = self.num(e)? {
+        *cst += k * n;
+        return Ok(())
+      }
+    }
+    let i = self.var(e);
+    *coeffs.entry(i).or_default() += k;
+    Ok(())
+  }
+
+  /// Build the proof of the contradiction from the multipliers in `row`.
+  fn reconstruct(&mut self, ineqs: Vec<Ineq>, row: Row) -> Result<LispVal> {
+    let mut acc: Option<(LispVal, LispVal, LispVal)> = None;
+    for (ineq, k) in ineqs.into_iter().zip(row.mult) {
+      if !k.is_positive() { continue }
+      let Ineq {mut t, mut u, proof} = ineq;
+      let mut p = match proof {
+        HypProof::Done(p) => p,
+        HypProof::Step(s, p, t0, u0) => self.step(s, vec![p], vec![t0, u0])?,
+      };
+      if !k.is_one() {
+        let k = self.numeral(k)?;
+        p = self.step(Step::LeMul, vec![p], vec![k.clone(), t.clone(), u.clone()])?;
+        t = app(self.table.mul, k.clone(), t);
+        u = app(self.table.mul, k, u);
+      }
+      acc = Some(match acc {
+        None => (p, t, u),
+        Some((p1, t1, u1)) => {
+          let p = self.step(Step::LeAdd, vec![p1, p], vec![t1.clone(), u1.clone(), t.clone(), u.clone()])?;
+          (p, app(self.table.add, t1, t), app(self.table.add, u1, u))
+        }
+      })
+    }
+    let (p, t, u) = acc.expect("a contradiction uses at least one hypothesis");
+    let c = self.numeral(row.c)?;
+    self.step(Step::Contra, vec![p], vec![t, u, c])
+  }
+
+  /// Add the hypothesis `p: e`, if it is a literal, to `ineqs`.
+  fn add_hyp(&mut self, ineqs: &mut Vec<Ineq>, p: LispVal, e: &LispVal) -> Result<()> {
+    let (rel, t, u) = match self.literal(e) { Some(l) => l, None => return Ok(()) };
+    if rel == self.table.le {
+      ineqs.push(Ineq {t, u, proof: HypProof::Done(p)})
+    } else if rel == self.table.lt {
+      let one = self.numeral(BigInt::one())?;
+      let t1 = app(self.table.add, t.clone(), one);
+      ineqs.push(Ineq {t: t1, u: u.clone(), proof: HypProof::Step(Step::LtLe, p, t, u)})
+    } else {
+      ineqs.push(Ineq {t: t.clone(), u: u.clone(), proof: HypProof::Step(Step::EqLe, p.clone(), t.clone(), u.clone())});
+      ineqs.push(Ineq {t: u.clone(), u: t.clone(), proof: HypProof::Step(Step::EqGe, p, t, u)})
+    }
+    Ok(())
+  }
+
+  /// Prove a contradiction from the inequalities, or return `None` if none was found.
+  fn run(&mut self, mut ineqs: Vec<Ineq>) -> Result<Option<LispVal>> {
+    let mut forms = vec![];
+    for ineq in &ineqs {
+      let (mut a, mut c) = (HashMap::new(), BigInt::zero());
+      self.linear(&ineq.t, &BigInt::one(), &mut a, &mut c)?;
+      self.linear(&ineq.u, &-BigInt::one(), &mut a, &mut c)?;
+      forms.push((a, c))
+    }
+    if let Some(nonneg) = self.table.nonneg.clone() {
+      for i in 0..self.vars.len() {
+        let x = self.vars[i].clone();
+        let p = self.call(nonneg.clone(), vec![x.clone()])?;
+        if !p.is_def() { continue }
+        let zero = self.numeral(BigInt::zero())?;
+        forms.push((std::iter::once((i, -BigInt::one())).collect(), BigInt::zero()));
+        ineqs.push(Ineq {t: zero, u: x, proof: HypProof::Done(p)})
+      }
+    }
+    let (nvars, n) = (self.vars.len(), ineqs.len());
+    let rows = forms.into_iter().enumerate().map(|(i, (a, c))| {
+      let mut row = Row { a: vec![BigInt::zero(); nvars], c, mult: vec![BigInt::zero(); n] };
+      for (j, k) in a { row.a[j] = k }
+      row.mult[i] = BigInt::one();
+      row
+    }).collect();
+    match eliminate(rows, nvars) {
+      None => Ok(None),
+      Some(row) => Ok(Some(self.reconstruct(ineqs, row)?)),
+    }
+  }
+}
+
+impl Elaborator {
+  /// Prove `goal` (or a contradiction, if `goal` is `None`) by linear arithmetic from the
+  /// hypotheses `hyps`, where each hypothesis `(p, e)` is a proof `p` of `e`, using the
+  /// term constructors and proof steps in `table`. Hypotheses that are not linear
+  /// inequalities or equations are ignored. Returns `#undef` if no proof was found.
+  pub fn lia(&mut self, sp: Span, hyps: &[(LispVal, LispVal)], goal: Option<&LispVal>,
+    table: &LiaTable,
+  ) -> Result<LispVal> {
+    let mut lia = Lia { elab: self, sp, table, vars: vec![] };
+    let mut ineqs = vec![];
+    for (p, e) in hyps { lia.add_hyp(&mut ineqs, p.clone(), e)? }
+    let goal = match goal {
+      None => None,
+      Some(g) => {
+        let (assume, by_contra) = match (&table.assume, &table.by_contra) {
+          (Some(a), Some(b)) => (a.clone(), b.clone()),
+          _ => return Err(ElabError::new_e(sp, "lia: proving a goal needs 'assume and 'by-contra")),
+        };
+        // The negation of `t <= u` is `u < t`, and the negation of `t < u` is `u <= t`.
+        let neg = match lia.literal(g) {
+          Some((r, t, u)) if r == table.le => app(table.lt, u, t),
+          Some((r, t, u)) if r == table.lt => app(table.le, u, t),
+          _ => return Err(ElabError::new_e(sp, format!("lia: unsupported goal {}", lia.elab.print(g)))),
+        };
+        let p = lia.call(assume, vec![neg.clone()])?;
+        lia.add_hyp(&mut ineqs, p, &neg)?;
+        Some((g.clone(), by_contra))
+      }
+    };
+    let p = match lia.run(ineqs)? { Some(p) => p, None => return Ok(LispVal::undef()) };
+    match goal {
+      None => Ok(p),
+      Some((g, by_contra)) => match by_contra.as_atom() {
+        Some(a) => Ok(LispVal::list(vec![LispVal::atom(a), p])),
+        None => lia.call(by_contra, vec![p, g]),
+      }
+    }
+  }
+}
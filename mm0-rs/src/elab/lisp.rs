@@ -9,6 +9,8 @@ pub mod eval;
 pub mod debug;
 pub mod print;
 pub mod pretty;
+pub mod int;
+pub mod future;
 
 use std::ops::{Deref, DerefMut};
 use std::hash::Hash;
@@ -16,12 +18,14 @@ use std::rc::{Rc, Weak};
 use std::cell::{Cell, RefCell};
 use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
-use num::BigInt;
+use num::BigRational;
 use owning_ref::{OwningRef, StableAddress, CloneStableAddress};
-use crate::{ast::Atom, ArcString, AtomId, FileSpan, MergeStrategy, MergeStrategyInner, Modifiers,
+use crate::{ast::Atom, ArcString, AtomId, DocComment, FileSpan, MergeStrategy, MergeStrategyInner, Modifiers,
   MutexExt, Remap, Remapper, SliceExt, Span, StackList};
 use parser::Ir;
 pub use super::math_parser::{QExpr, QExprKind};
+pub use int::Int;
+pub use future::LispFuture;
 
 macro_rules! str_enum {
   ($(#[$doc:meta])* enum $name:ident $($rest:tt)*) => {
@@ -97,6 +101,15 @@ str_enum! {
     If: "if",
     /// `begin`: a sequence of expressions
     Begin: "begin",
+    /// `(try e handler)`: evaluates `e`, and if it throws an error, calls `handler` with
+    /// an error object describing the error (see [`BuiltinProc::Error`]).
+    Try: "try",
+    /// `(reset es)`: evaluates the sequence `es`, delimiting the continuations
+    /// captured by `shift` inside it.
+    Reset: "reset",
+    /// `(shift k es)`: captures the continuation up to the nearest enclosing `reset` as
+    /// a function `k`, and then evaluates the sequence `es` in place of the `reset`.
+    Shift: "shift",
     /// `focus`: a tactic that focuses on the main goal, calls a sequence of `refine` calls,
     /// and then closes the goal.
     Focus: "focus",
@@ -122,6 +135,12 @@ str_enum! {
     ///   overwriting the originals but preserving any keys not in `new`.
     ///   * This can also be used as `(merge-map strat)` where `strat` is a subsidiary merge strategy.
     SetMergeStrategy: "set-merge-strategy",
+    /// `(defmacro (foo x y) body)` defines a macro `foo`. When a later top level
+    /// expression contains `(foo a b)`, the procedure `(fn (x y) body)` is called on the
+    /// unevaluated arguments `'a` and `'b`, and the result is parsed as code in place
+    /// of the macro call. This accepts the same forms as `def`, but only global
+    /// macros are expanded.
+    DefMacro: "defmacro",
   }
 }
 
@@ -231,6 +250,44 @@ impl InferTarget {
 #[derive(Default, Debug, EnvDebug, Clone, DeepSizeOf)]
 pub struct LispVal(Rc<LispKind>);
 
+/// A key in a [`HashTable`](LispKind::HashTable).
+///
+/// This is a frozen copy of a lisp value built out of atoms, numbers, strings,
+/// booleans and lists, which are the values that can be hashed and compared structurally.
+#[derive(Clone, Debug, EnvDebug, PartialEq, Eq, Hash, DeepSizeOf)]
+pub enum HashKey {
+  /// An atom key.
+  Atom(AtomId),
+  /// A number key.
+  Number(Int),
+  /// A rational number key.
+  Rational(BigRational),
+  /// A string key.
+  String(ArcString),
+  /// A boolean key.
+  Bool(bool),
+  /// A list key.
+  List(Box<[HashKey]>),
+  /// An improper list key. The tail is never a list, so that keys are canonical.
+  DottedList(Box<[HashKey]>, Box<HashKey>),
+}
+
+impl HashKey {
+  /// Convert the key back to a lisp value.
+  #[must_use] pub fn to_lisp(&self) -> LispVal {
+    match self {
+      &HashKey::Atom(a) => LispVal::atom(a),
+      HashKey::Number(n) => LispVal::number(n.clone()),
+      HashKey::Rational(q) => LispVal::rational(q.clone()),
+      HashKey::String(s) => LispVal::string(s.clone()),
+      &HashKey::Bool(b) => LispVal::bool(b),
+      HashKey::List(es) => LispVal::list(es.iter().map(HashKey::to_lisp).collect::<Vec<_>>()),
+      HashKey::DottedList(es, r) =>
+        LispVal::dotted_list(es.iter().map(HashKey::to_lisp).collect::<Vec<_>>(), r.to_lisp()),
+    }
+  }
+}
+
 /// This macro is used to define the [`LispKind`] type, as well as the
 /// [`FrozenLispKind`] type, to ensure that they have the same representation
 /// and can be safely transmuted.
@@ -261,8 +318,11 @@ macro_rules! mk_lisp_kind {
       /// lisp objects, so that client code can give targeted error messages.
       Annot(Annot, $val),
       /// A number like `123`. These use bignum arithmetic so that client code
-      /// doesn't have to worry about overflow.
-      Number(BigInt),
+      /// doesn't have to worry about overflow, but small numbers are stored inline.
+      Number(Int),
+      /// An exact rational number like `1/2`. This is never an integer;
+      /// rationals with denominator 1 are always represented as [`Number`](Self::Number).
+      Rational(BigRational),
       /// An immutable string like `"foo"`.
       String(ArcString),
       /// A boolean value, `#t` or `#f`.
@@ -276,6 +336,15 @@ macro_rules! mk_lisp_kind {
       /// A map from atoms to values. This can be used as a mutable map if it is behind a
       /// [`Ref`](Self::Ref).
       AtomMap(HashMap<AtomId, $val>),
+      /// A map from hashable values (see [`HashKey`]) to values. As with
+      /// [`AtomMap`](Self::AtomMap), this is mutable if it is behind a [`Ref`](Self::Ref).
+      HashTable(HashMap<HashKey, $val>),
+      /// A mutable array of values. Unlike [`List`](Self::List), this supports
+      /// constant time indexing and in-place update.
+      Vector(RefCell<Vec<$val>>),
+      /// An output string port, a mutable buffer that text can be written to
+      /// incrementally using `format` and `port-write`.
+      StringPort(RefCell<Vec<u8>>),
       /// A mutable reference. This is the only way to have mutable values in
       /// client code.
       Ref($ref_),
@@ -300,7 +369,10 @@ mk_lisp_kind! {
 
 impl LispVal {
   /// Make a [`LispVal`] from the inner enum type [`LispKind`].
-  #[must_use] pub fn new(e: LispKind) -> LispVal { LispVal(Rc::new(e)) }
+  #[must_use] pub fn new(e: LispKind) -> LispVal {
+    ALLOCS.with(|n| n.set(n.get().wrapping_add(1)));
+    LispVal(Rc::new(e))
+  }
   /// Construct a [`LispVal`] for an atom.
   #[must_use] pub fn atom(a: AtomId) -> LispVal { LispVal::new(LispKind::Atom(a)) }
   /// Construct a [`LispVal`] for a list.
@@ -309,8 +381,15 @@ impl LispVal {
   #[must_use] pub fn dotted_list(es: impl Into<Box<[LispVal]>>, r: LispVal) -> LispVal {
     LispVal::new(LispKind::DottedList(es.into(), r))
   }
-  /// Construct a [`LispVal`] for an improper list.
-  #[must_use] pub fn number(n: BigInt) -> LispVal { LispVal::new(LispKind::Number(n)) }
+  /// Construct a [`LispVal`] for a mutable vector.
+  #[must_use] pub fn vector(es: Vec<LispVal>) -> LispVal { LispVal::new(LispKind::Vector(RefCell::new(es))) }
+  /// Construct a [`LispVal`] for a number.
+  #[must_use] pub fn number(n: impl Into<Int>) -> LispVal { LispVal::new(LispKind::Number(n.into())) }
+  /// Construct a [`LispVal`] for a rational number, which will be a
+  /// [`Number`](LispKind::Number) if the denominator is 1.
+  #[must_use] pub fn rational(q: BigRational) -> LispVal {
+    if q.is_integer() { LispVal::number(q.to_integer()) } else { LispVal::new(LispKind::Rational(q)) }
+  }
   /// Construct a [`LispVal`] for a string.
   #[must_use] pub fn string(s: ArcString) -> LispVal { LispVal::new(LispKind::String(s)) }
   /// Construct a [`LispVal`] for a syntax element.
@@ -369,6 +448,10 @@ impl LispVal {
 
   /// Returns true if this is a clone of `e`.
   #[must_use] pub fn ptr_eq(&self, e: &Self) -> bool { Rc::ptr_eq(&self.0, &e.0) }
+  /// Returns true if there are no other clones of this value (including weak references).
+  #[must_use] pub fn is_unique(&self) -> bool {
+    Rc::strong_count(&self.0) == 1 && Rc::weak_count(&self.0) == 0
+  }
   /// Try to get at the inner data, if this value is not shared,
   /// otherwise return self.
   pub fn try_unwrap(self) -> Result<LispKind, LispVal> { Rc::try_unwrap(self.0).map_err(LispVal) }
@@ -449,20 +532,107 @@ impl PartialEq<LispVal> for LispVal {
 }
 impl Eq for LispVal {}
 
+/// The set of ref cells allocated by an elaborator, used to find and break reference cycles.
+///
+/// Ref cells are the only way to make a cycle of [`LispVal`]s, and a cycle that is no longer
+/// reachable from outside is never freed by reference counting alone. The cycle collector
+/// uses trial deletion: starting from the live ref cells, it counts the references to each
+/// value reachable from them, and any value with no references other than these counted ones
+/// is garbage. Garbage ref cells are set to `#undef`, which breaks the cycles and lets
+/// reference counting free the rest.
 #[derive(Default, DeepSizeOf)]
-pub(crate) struct LispArena(typed_arena::Arena<Weak<LispKind>>);
+pub(crate) struct LispArena {
+  /// Weak pointers to all ref cells allocated while this arena was installed.
+  /// Dead entries are pruned on each collection.
+  refs: RefCell<Vec<Weak<LispKind>>>,
+  /// The number of ref cells that survived the last collection.
+  live: Cell<usize>,
+}
+
+/// The minimum number of ref cells to register before [`LispArena::maybe_collect`]
+/// will run the cycle collector.
+const MIN_COLLECT: usize = 1024;
 
 thread_local!(static REFS: Cell<Option<*const LispArena>> = Cell::new(None));
+thread_local!(static ALLOCS: Cell<usize> = Cell::new(0));
+
+/// The number of [`LispVal`]s that have been allocated on this thread so far
+/// (modulo `usize::MAX`, so use `wrapping_sub` to get the number allocated in an interval).
+pub(crate) fn alloc_count() -> usize { ALLOCS.with(Cell::get) }
 
 impl LispArena {
   pub(crate) fn install_thread_local(&self) { REFS.with(|refs| refs.set(Some(self))) }
   pub(crate) fn uninstall_thread_local() { REFS.with(|refs| refs.set(None)) }
 
-  #[allow(clippy::unused_self)]
-  pub(crate) fn clear(self) {
-    // for e in self.0.iter_mut() {
-      // if let Some(e) = e.upgrade() { e.as_ref_(|r| *r = LispVal::undef()); }
-    // }
+  /// Get the arena installed on this thread, so that it can be restored with
+  /// [`set_thread_local`](Self::set_thread_local) after running another elaborator here.
+  pub(crate) fn thread_local() -> Option<*const LispArena> { REFS.with(Cell::get) }
+  pub(crate) fn set_thread_local(arena: Option<*const LispArena>) { REFS.with(|refs| refs.set(arena)) }
+
+  fn register(&self, e: &LispVal) { self.refs.borrow_mut().push(Rc::downgrade(&e.0)) }
+
+  /// Run the cycle collector if the number of ref cells has at least doubled since the last
+  /// collection. This should be called between statements, when the lisp evaluator is idle.
+  pub(crate) fn maybe_collect(&self) {
+    if self.refs.borrow().len() >= 2 * self.live.get().max(MIN_COLLECT) { self.collect(); }
+  }
+
+  /// Find all ref cells that are only reachable from unreachable reference cycles, and
+  /// set them to `#undef` so that the cycles are freed. Returns the number of cleared cells.
+  pub(crate) fn collect(&self) -> usize {
+    let roots = {
+      let mut refs = self.refs.borrow_mut();
+      refs.retain(|e| e.strong_count() != 0);
+      refs.iter().filter_map(|e| e.upgrade().map(LispVal)).collect::<Vec<_>>()
+    };
+    let garbage = {
+      // Each node is a value reachable from the roots, with the number of references to it
+      // from other nodes (or from `roots` itself), and whether it is known to be live.
+      let mut nodes: Vec<(&LispVal, usize, bool)> = Vec::with_capacity(roots.len());
+      let mut edges: Vec<Vec<usize>> = Vec::with_capacity(roots.len());
+      let mut index = HashMap::new();
+      for e in &roots {
+        index.insert(Rc::as_ptr(&e.0), nodes.len());
+        nodes.push((e, 1, false));
+        edges.push(vec![]);
+      }
+      let mut i = 0;
+      while let Some(&(e, _, _)) = nodes.get(i) {
+        // Safety: no ref cells are modified until `nodes` is dropped.
+        unsafe { e.for_each_child(&mut |c| {
+          let j = *index.entry(Rc::as_ptr(&c.0)).or_insert_with(|| {
+            nodes.push((c, 0, false));
+            edges.push(vec![]);
+            nodes.len() - 1
+          });
+          nodes[j].1 += 1;
+          edges[i].push(j);
+        }) }
+        i += 1;
+      }
+      // A node is live if it has references that we did not find, and everything
+      // reachable from a live node is live.
+      let mut stack = vec![];
+      for (i, (e, n, live)) in nodes.iter_mut().enumerate() {
+        if Rc::strong_count(&e.0) > *n { *live = true; stack.push(i) }
+      }
+      while let Some(i) = stack.pop() {
+        for &j in &edges[i] {
+          if !nodes[j].2 { nodes[j].2 = true; stack.push(j) }
+        }
+      }
+      nodes.into_iter().filter(|&(e, _, live)| !live && matches!(**e, LispKind::Ref(_)))
+        .map(|(e, _, _)| e.clone()).collect::<Vec<_>>()
+    };
+    // We move the contents out before dropping anything, so that values are not freed
+    // while we are still clearing the cells that point to them.
+    let contents = garbage.iter().map(|e| match &**e {
+      LispKind::Ref(m) => std::mem::replace(&mut *m.0.borrow_mut(), LispWeak::Strong(LispVal::undef())),
+      _ => unreachable!(),
+    }).collect::<Vec<_>>();
+    self.live.set(roots.len() - garbage.len());
+    drop((contents, roots));
+    garbage.len()
   }
 }
 
@@ -520,6 +690,18 @@ impl LispWeak {
     }
   }
 }
+/// Returns true if this refcell has suspciously many readers.
+/// This is used to detect cycles when printing.
+pub(crate) fn too_many_readers<T>(r: &RefCell<T>) -> bool {
+  struct RefCell2<T: ?Sized> {
+    borrow: Cell<isize>,
+    _value: std::cell::UnsafeCell<T>,
+  }
+  // Safety: This ties us to the representation of RefCell, but I don't think
+  // that is going to change.
+  unsafe { &*<*const _>::cast::<RefCell2<T>>(r) }.borrow.get() > 30
+}
+
 /// A mutable reference to a [`LispVal`], the inner type used by `ref!` and related functions.
 #[derive(Debug, EnvDebug, DeepSizeOf)]
 pub struct LispRef(RefCell<LispWeak>);
@@ -527,8 +709,10 @@ pub struct LispRef(RefCell<LispWeak>);
 impl LispRef {
   /// Construct a [`LispVal`] for a mutable reference.
   fn new_as_val(w: LispWeak) -> LispVal {
-    LispVal::new(LispKind::Ref(LispRef(RefCell::new(w))))
-    // REFS.with(|refs| {unsafe{&*refs.get().unwrap()}.0.alloc(Rc::downgrade(&r.0));});
+    let e = LispVal::new(LispKind::Ref(LispRef(RefCell::new(w))));
+    // Safety: the arena is uninstalled before it is dropped
+    REFS.with(|refs| if let Some(arena) = refs.get() { unsafe { &*arena }.register(&e) });
+    e
   }
   /// Get a reference to the stored value.
   pub fn get<T>(&self, f: impl FnOnce(&LispVal) -> T) -> T {
@@ -556,15 +740,7 @@ impl LispRef {
   pub fn into_inner(self) -> LispVal { self.0.into_inner().upgrade() }
 
   /// Returns true if this refcell has suspciously many readers
-  pub(crate) fn too_many_readers(&self) -> bool {
-    struct RefCell2<T: ?Sized> {
-      borrow: Cell<isize>,
-      _value: std::cell::UnsafeCell<T>,
-    }
-    // Safety: This ties us to the representation of RefCell, but I don't think
-    // that is going to change.
-    unsafe { &*<*const _>::cast::<RefCell2<LispWeak>>(&self.0) }.borrow.get() > 30
-  }
+  pub(crate) fn too_many_readers(&self) -> bool { too_many_readers(&self.0) }
 
   /// Get the value of this reference without changing the reference count.
   /// # Safety
@@ -594,6 +770,28 @@ impl From<&LispKind> for bool {
 }
 
 impl LispKind {
+  /// Call `f` on the values directly referenced by this one, for the cycle collector.
+  /// Some kinds of reference (such as constants in lambda bodies) are not reported, which is
+  /// safe because the cycle collector treats unreported references as external.
+  /// # Safety
+  /// The contents of ref cells and vectors are borrowed without a guard, so they must not be
+  /// modified during the lifetime `'a`.
+  unsafe fn for_each_child<'a>(&'a self, f: &mut impl FnMut(&'a LispVal)) {
+    match self {
+      LispKind::List(es) => es.iter().for_each(f),
+      LispKind::DottedList(es, r) => { es.iter().for_each(&mut *f); f(r) }
+      LispKind::Annot(_, e) |
+      LispKind::Goal(e) |
+      LispKind::Proc(Proc::Macro(e)) => f(e),
+      LispKind::Proc(Proc::Lambda {env, ..}) => env.iter().for_each(f),
+      LispKind::AtomMap(m) => m.values().for_each(f),
+      LispKind::HashTable(m) => m.values().for_each(f),
+      LispKind::Vector(es) => if let Ok(es) = es.try_borrow_unguarded() { es.iter().for_each(f) }
+      LispKind::Ref(m) => if let Ok(LispWeak::Strong(e)) = m.0.try_borrow_unguarded() { f(e) }
+      _ => {}
+    }
+  }
+
   /// Unwrap [`Ref`](Self::Ref) and [`Annot`](Self::Annot) nodes,
   /// which are ignored by most lisp primitives, and run `f`
   /// with a reference to the inner value.
@@ -631,6 +829,30 @@ impl LispKind {
     rec(self, StackList(None), fsp, f)
   }
 
+  /// Convert this value to a [`HashKey`], or return [`None`] if it contains
+  /// values that are not hashable, such as procedures or maps. Refs are read through,
+  /// so the key is a snapshot of the current value.
+  #[must_use] pub fn to_hash_key(&self) -> Option<HashKey> {
+    self.unwrapped(|e| Some(match e {
+      &LispKind::Atom(a) => HashKey::Atom(a),
+      LispKind::Number(n) => HashKey::Number(n.clone()),
+      LispKind::Rational(q) => HashKey::Rational(q.clone()),
+      LispKind::String(s) => HashKey::String(s.clone()),
+      &LispKind::Bool(b) => HashKey::Bool(b),
+      LispKind::List(es) =>
+        HashKey::List(es.iter().map(|e| e.to_hash_key()).collect::<Option<_>>()?),
+      LispKind::DottedList(es, r) => {
+        let mut es = es.iter().map(|e| e.to_hash_key()).collect::<Option<Vec<_>>>()?;
+        match r.to_hash_key()? {
+          HashKey::List(es2) => {es.extend(es2.into_vec()); HashKey::List(es.into())}
+          HashKey::DottedList(es2, r) => {es.extend(es2.into_vec()); HashKey::DottedList(es.into(), r)}
+          r => HashKey::DottedList(es.into(), Box::new(r)),
+        }
+      }
+      _ => return None
+    }))
+  }
+
   /// Returns true if this value is to be treated as true in `if` statements.
   /// Everything is truthy except `#f` and references to `#f`.
   pub fn truthy(&self) -> bool {
@@ -657,9 +879,13 @@ impl LispKind {
     self.unwrapped(|e| matches!(e, LispKind::Number(_)))
   }
   /// Get the number that this value stores, if applicable.
-  pub fn as_int<T>(&self, f: impl FnOnce(&BigInt) -> T) -> Option<T> {
+  pub fn as_int<T>(&self, f: impl FnOnce(&Int) -> T) -> Option<T> {
     self.unwrapped(|e| if let LispKind::Number(n) = e {Some(f(n))} else {None})
   }
+  /// Returns true if this value is a number, either an integer or a rational.
+  pub fn is_number(&self) -> bool {
+    self.unwrapped(|e| matches!(e, LispKind::Number(_) | LispKind::Rational(_)))
+  }
   /// Returns true if this value is a procedure.
   pub fn is_proc(&self) -> bool {
     self.unwrapped(|e| matches!(e, LispKind::Proc(_)))
@@ -672,6 +898,18 @@ impl LispKind {
   pub fn is_map(&self) -> bool {
     self.unwrapped(|e| matches!(e, LispKind::AtomMap(_)))
   }
+  /// Returns true if this value is a hash table.
+  pub fn is_hash_table(&self) -> bool {
+    self.unwrapped(|e| matches!(e, LispKind::HashTable(_)))
+  }
+  /// Returns true if this value is a vector.
+  pub fn is_vector(&self) -> bool {
+    self.unwrapped(|e| matches!(e, LispKind::Vector(_)))
+  }
+  /// Returns true if this value is an output string port.
+  pub fn is_port(&self) -> bool {
+    self.unwrapped(|e| matches!(e, LispKind::StringPort(_)))
+  }
   /// Returns true if this value is not `#undef` or a reference to `#undef`.
   pub fn is_def(&self) -> bool {
     self.unwrapped(|e| !matches!(e, LispKind::Undef))
@@ -814,6 +1052,7 @@ impl PartialEq<LispKind> for LispKind {
     self.unwrapped(|s| other.unwrapped(|o| match (s, o) {
       (&LispKind::Atom(a), &LispKind::Atom(b)) => a == b,
       (LispKind::Number(a), LispKind::Number(b)) => a == b,
+      (LispKind::Rational(a), LispKind::Rational(b)) => a == b,
       (LispKind::String(a), LispKind::String(b)) => a == b,
       (LispKind::Bool(a), LispKind::Bool(b)) => a == b,
       (LispKind::Syntax(a), LispKind::Syntax(b)) => a == b,
@@ -833,7 +1072,7 @@ impl PartialEq<LispKind> for LispKind {
           }
         }
       }
-      _ => false // Goal, Proc, MVar, AtomMap all have only reference equality
+      _ => false // Goal, Proc, MVar, AtomMap, HashTable, Vector, StringPort all have only reference equality
     }))
   }
 }
@@ -894,6 +1133,8 @@ pub enum Proc {
     /// As the language is untyped, the only real information we have here
     /// is how many arguments are expected.
     spec: ProcSpec,
+    /// The doc string of the procedure, given by `(fn xs "doc" e)` or `(def (f . xs) "doc" e)`.
+    doc: Option<DocComment>,
     /// The code of the procedure.
     code: Arc<Ir>
   },
@@ -905,6 +1146,12 @@ pub enum Proc {
   /// multiple are in scope, as well as to determine if we are still in the dynamic
   /// extent of `code`.
   MatchCont(Rc<Cell<bool>>),
+  /// A delimited continuation, which is passed to client code in the variable `k`
+  /// of `(shift k code)`. The stack frames of the continuation are stored by the evaluator
+  /// that captured it, which is identified by the `Rc<Cell<bool>>`, and the `usize` is
+  /// the index of the continuation in its list. The continuation can only be called
+  /// while that evaluator is running, and the flag is set to false when it finishes.
+  DelimCont(Rc<Cell<bool>>, usize),
   /// A callback used by `refine` when it finds a procedure in a refine script.
   /// The callback acts like `refine` as well, but it orders generated subgoals with
   /// respect to an outer invocation of `refine`. This callback also only works
@@ -914,6 +1161,10 @@ pub enum Proc {
   RefineCallback,
   /// A partially applied `(merge-map f)` invocation.
   MergeMap(MergeStrategy),
+  /// A macro, created by `defmacro` or `(macro f)`, which stores the expansion procedure.
+  /// Macro calls are expanded when code is parsed, but calling the macro as a function
+  /// will also call the expansion procedure, returning the expanded code.
+  Macro(LispVal),
   /// A delayed proof, generated by a call to `get-decl`, which returns a lisp
   /// data structure reflecting the requested definition, but delays the proof
   /// unless forced by calling this thunk. The unevaluated form of the thunk
@@ -925,7 +1176,9 @@ pub enum Proc {
   ///
   /// [`Compiler::call`]: crate::mmc::Compiler::call
   #[cfg(feature = "mmc")]
-  MmcCompiler(RefCell<Box<crate::mmc::Compiler>>) // TODO: use extern instead
+  MmcCompiler(RefCell<Box<crate::mmc::Compiler>>), // TODO: use extern instead
+  /// A task started by `(spawn f)`, which holds the result of `(f)` once it has been awaited.
+  Future(RefCell<LispFuture>),
 }
 
 /// A procedure specification, which defines the number of arguments expected
@@ -951,6 +1204,12 @@ impl ProcSpec {
   }
 }
 
+/// Convert the doc comment on a builtin (as returned by [`BuiltinProc::doc`] or
+/// [`Syntax::doc`]) into a [`DocComment`], removing the leading space on each line.
+#[must_use] pub fn builtin_doc(doc: &str) -> DocComment {
+  doc.lines().map(|l| l.strip_prefix(' ').unwrap_or(l)).collect::<Vec<_>>().join("\n").into()
+}
+
 impl Proc {
   /// Returns the specification (number of expected arguments) for a procedure.
   #[allow(clippy::match_same_arms)]
@@ -959,11 +1218,24 @@ impl Proc {
       Proc::Builtin(p) => p.spec(),
       &Proc::Lambda {spec, ..} => spec,
       Proc::MatchCont(_) |
+      Proc::Macro(_) |
       Proc::ProofThunk(_, _) => ProcSpec::AtLeast(0),
+      Proc::DelimCont(..) => ProcSpec::Exact(1),
       Proc::MergeMap(_) => ProcSpec::Exact(2),
       Proc::RefineCallback => ProcSpec::AtLeast(1),
       #[cfg(feature = "mmc")]
       Proc::MmcCompiler(_) => ProcSpec::AtLeast(1),
+      Proc::Future(_) => ProcSpec::Exact(0),
+    }
+  }
+
+  /// Returns the documentation for a procedure, if it has any.
+  #[must_use] pub fn doc(&self) -> Option<DocComment> {
+    match self {
+      Proc::Builtin(p) => Some(builtin_doc(p.doc())),
+      Proc::Lambda {doc, ..} => doc.clone(),
+      Proc::Macro(f) => f.unwrapped(|e| if let LispKind::Proc(f) = e { f.doc() } else { None }),
+      _ => None
     }
   }
 }
@@ -979,8 +1251,18 @@ str_enum! {
     /// (display 42)                    -- error, expected string
     /// ```
     Display: "display",
-    /// `error` takes a string and throws an error with the given string as the message.
+    /// `(error msg)` throws an error with the string `msg` as the message.
+    /// `(error msg data)` does the same, and attaches `data` to the error.
+    ///
+    /// An error thrown inside `(try e handler)` is passed to `handler` as an error object,
+    /// which is an atom map with keys `message` (the message string), `span`
+    /// (a value carrying the location of the error, which can be used with `report-at`),
+    /// and `data` (the attached data, or `#undef`).
     Error: "error",
+    /// `(raise obj)` throws the error object `obj` again, with the same message and data.
+    /// If `obj` is not an error object, the error has the printed form of `obj`
+    /// as its message and `obj` as its data.
+    Raise: "raise",
     /// `print` takes an arbitrary expression and pretty-prints it.
     Print: "print",
     /// `(report-at sp type msg)` will report the message `msg` at a position
@@ -999,24 +1281,59 @@ str_enum! {
     /// head of the list. `(apply)` is an error, and if `f` is a syntax form then this
     /// is also an error, i.e. `(apply def (x 5))` does not work.
     Apply: "apply",
-    /// `(+ a b c)` computes the sum of the (integer) arguments. `(+)` is zero and `(+ a)` is `a`.
+    /// `(macro f)` returns a macro with expansion procedure `f`. `(def foo (macro f))`
+    /// is equivalent to `(defmacro foo f)`.
+    Macro: "macro",
+    /// `(help f)` returns the documentation for `f` as a string, or `#undef` if there is
+    /// none. `f` can be a procedure, or an atom naming a global definition or syntax form.
+    /// User procedures are documented by a doc string `(def (f x) "doc" body)` or a
+    /// `--|` doc comment on the definition.
+    Help: "help",
+    /// `(+ a b c)` computes the sum of the (integer or rational) arguments.
+    /// `(+)` is zero and `(+ a)` is `a`.
     Add: "+",
-    /// `(* a b c)` computes the product of the (integer) arguments. `(*)` is one and `(* a)` is `a`.
+    /// `(* a b c)` computes the product of the (integer or rational) arguments.
+    /// `(*)` is one and `(* a)` is `a`.
     Mul: "*",
-    /// `{a ^ b}` computes `a` to the power of `b`. It gives an error if `b` is negative.
+    /// `{a ^ b}` computes `a` to the power of `b`, where `b` is an integer.
+    /// If `b` is negative then the result is `1 / a ^ -b`, which is a rational unless `a` is `1` or `-1`.
     /// Additional arguments are right associative.
     Pow: "^",
-    /// `(max a b c)` computes the maximum of the (integer) arguments. `(max)` is an error.
+    /// `(max a b c)` computes the maximum of the (integer or rational) arguments. `(max)` is an error.
     Max: "max",
-    /// `(min a b c)` computes the minimum of the (integer) arguments. `(min)` is an error.
+    /// `(min a b c)` computes the minimum of the (integer or rational) arguments. `(min)` is an error.
     Min: "min",
     /// `(- a b)` computes the subtraction `a - b`. `(- a b c)` is `a - b - c`,
     /// `(- a)` is `-a`, and `(-)` is an error.
     Sub: "-",
     /// {a // b}` computes the integer (flooring) division. More arguments associate to the left.
+    /// If any argument is rational, then this is `a / b` rounded toward zero.
     Div: "//",
-    /// `{a % b}` computes the integer modulus. More arguments associate to the left.
+    /// `{a / b}` computes the exact division of `a` by `b`, which may be a rational number.
+    /// `(/ a)` is `1 / a`, and division by zero yields zero. More arguments associate to the left.
+    /// ```metamath-zero
+    /// {6 / 3}       -- 2
+    /// {1 / 2}       -- 1/2
+    /// (/ 1 2 3)     -- 1/6
+    /// {(/ 1 2) + (/ 1 3)} -- 5/6
+    /// ```
+    Divide: "/",
+    /// `{a % b}` computes the modulus, with the sign of `a`. More arguments associate to the left.
     Mod: "%",
+    /// `(mod a b)` computes the integer modulus rounding toward negative infinity,
+    /// so that the result has the sign of `b`. `(mod a 0)` is `a`.
+    /// ```metamath-zero
+    /// {(- 7) % 3}  -- -1
+    /// (mod (- 7) 3) -- 2
+    /// ```
+    ModFloor: "mod",
+    /// `(divmod a b)` returns the list `(q r)` of the flooring integer division
+    /// `q` and the modulus `r = (mod a b)`, so that `a = q * b + r`.
+    /// `(divmod a 0)` is `(0 a)`.
+    DivMod: "divmod",
+    /// `(pow-mod a b m)` computes `a ^ b` modulo `m` efficiently, returning a value
+    /// in the range `0 <= r < m`. It is an error if `b` is negative or `m` is not positive.
+    PowMod: "pow-mod",
     /// `{a < b}` is true if `a` is less than `b`. `(< a b c)` means `a < b` and `b < c`.
     Lt: "<",
     /// `{a <= b}` is true if `a` is less or equal to `b`. `(<= a b c)` means `a <= b` and `b <= c`.
@@ -1026,7 +1343,20 @@ str_enum! {
     /// `{a >= b}` is true if `a` is greater or equal to `b`. `(>= a b c)` means `a >= b` and `b >= c`.
     Ge: ">=",
     /// `{a = b}` is true if `a` and `b` are equal numbers. `(= a b c)` means `a = b` and `b = c`.
+    /// These comparison operators work on both integers and rationals.
     Eq: "=",
+    /// `(numerator q)` returns the numerator of the rational number `q` in lowest terms.
+    /// The numerator of an integer is itself.
+    Numerator: "numerator",
+    /// `(denominator q)` returns the (positive) denominator of the rational number `q`
+    /// in lowest terms. The denominator of an integer is `1`.
+    Denominator: "denominator",
+    /// `(floor q)` returns the largest integer less or equal to `q`.
+    Floor: "floor",
+    /// `(ceil q)` returns the smallest integer greater or equal to `q`.
+    Ceil: "ceil",
+    /// `(round q)` returns the nearest integer to `q`, rounding half-way cases away from zero.
+    Round: "round",
     /// `{a shl b}` performs a left shift `a << b`, equivalent to `a * 2 ^ b`.
     /// Negative `b` causes a right shift. Additional arguments are left associative;
     /// `3 << -1 << 1 = 2`.
@@ -1050,8 +1380,9 @@ str_enum! {
     /// * Strings, atoms, `#t`, `#f`, `#undef` all perform structural comparison as expected
     ///   (`#t` is equal to `#t` but not equal to `#undef` or `"#t"` or `'#t`).
     /// * Two pairs are equal if their components are equal.
-    /// * Procedures (both builtins and `fn` declarations), `atom-map`s, `goal`s and `mvar`s
-    ///   have no structural equality; they compare equal only if they are pointer-equal.
+    /// * Procedures (both builtins and `fn` declarations), `atom-map`s, hash tables, vectors,
+    ///   string ports, `goal`s and `mvar`s have no structural equality; they compare equal
+    ///   only if they are pointer-equal.
     /// * Indirections are ignored; `(ref! 1)` is equal to `1`.
     /// * The comparison routine performs no cycle detection so equality on cyclic data structures can loop.
     /// * Like the numeric equality operator `=`, `==` can be used on more than two arguments,
@@ -1076,6 +1407,15 @@ str_enum! {
     /// (string->atom "foo$bar baz") -- foo$bar baz
     /// ```
     StringToAtom: "string->atom",
+    /// `(gensym prefix)` returns a fresh atom whose name is the string or atom `prefix`
+    /// followed by a number, which is distinct from every atom that has been used so far.
+    /// This is useful for naming auxiliary definitions and hypotheses generated by tactics
+    /// without capturing user names. `(gensym)` uses the prefix `g`.
+    /// ```metamath-zero
+    /// (gensym 'h)  -- h1234
+    /// (gensym 'h)  -- h1235
+    /// ```
+    Gensym: "gensym",
     /// `(string-append s1 s2 s3)` stringifies and appends all the inputs.
     /// ```metamath-zero
     /// (string-append "foo" 'bar 42) -- "foobar42"
@@ -1108,6 +1448,89 @@ str_enum! {
     /// (list->string '(98 97 114)) -- "bar"
     /// ```
     ListToString: "list->string",
+    /// `(string-split sep s)` splits the string `s` at each occurrence of the nonempty string `sep`,
+    /// returning the list of pieces.
+    /// ```metamath-zero
+    /// (string-split "," "a,b,,c") -- ("a" "b" "" "c")
+    /// ```
+    StringSplit: "string-split",
+    /// `(string-index t s)` returns the index (in bytes) of the first occurrence of the string `t`
+    /// in the string `s`, or `#undef` if it does not occur.
+    /// ```metamath-zero
+    /// (string-index "lo" "hello world") -- 3
+    /// (string-index "x" "hello world")  -- #undef
+    /// ```
+    StringIndex: "string-index",
+    /// `(string-upcase s)` converts the ASCII letters in the string `s` to upper case.
+    /// ```metamath-zero
+    /// (string-upcase "foo1") -- "FOO1"
+    /// ```
+    StringUpcase: "string-upcase",
+    /// `(string-downcase s)` converts the ASCII letters in the string `s` to lower case.
+    /// ```metamath-zero
+    /// (string-downcase "Foo1") -- "foo1"
+    /// ```
+    StringDowncase: "string-downcase",
+    /// `(string->number s)` parses the string `s` as an integer, with an optional sign,
+    /// or a rational like `"-1/2"`, and returns `#undef` if it is not valid.
+    /// `(string->number s base)` parses it in base `base`, which should be between 2 and 36.
+    /// ```metamath-zero
+    /// (string->number "-42")   -- -42
+    /// (string->number "6/4")   -- 3/2
+    /// (string->number "ff" 16) -- 255
+    /// (string->number "foo")   -- #undef
+    /// ```
+    StringToNumber: "string->number",
+    /// `(number->string n)` prints the integer or rational `n` in decimal, like `->string`.
+    /// `(number->string n base)` prints it in base `base`, which should be between 2 and 36.
+    /// ```metamath-zero
+    /// (number->string 255 16)  -- "ff"
+    /// (number->string (- 5) 2) -- "-101"
+    /// ```
+    NumberToString: "number->string",
+    /// `(format fmt a b c)` returns the string `fmt` with each directive replaced
+    /// by the formatted arguments in order. `(format p fmt a b c)` instead writes the
+    /// result to the output string port `p` and returns `#undef`. The directives are:
+    ///
+    /// * `~a` formats the argument like `->string`, so strings are inserted as is.
+    /// * `~s` formats the argument like `print`, so strings are quoted.
+    /// * `~d`, `~x`, `~o`, `~b` format a number in decimal, hexadecimal, octal or binary.
+    /// * `~f` formats a number in decimal notation, with 6 digits after the point.
+    /// * `~%` is a newline, and `~~` is a literal `~`.
+    ///
+    /// Between the `~` and the directive there may be a minimum width, like `~5d`,
+    /// which pads on the left with spaces. The width may be preceded by `-` to
+    /// pad on the right instead, or by `0` to pad numbers with zeros, and followed
+    /// by `.n` to set the number of digits after the point for `~f`.
+    /// ```metamath-zero
+    /// (format "~a = ~s" 'x "foo")  -- "x = \"foo\""
+    /// (format "[~5d|~-5d]" 42 42)  -- "[   42|42   ]"
+    /// (format "0x~08x" 48879)      -- "0x0000beef"
+    /// (format "~.2f" (/ 2 3))      -- "0.67"
+    /// ```
+    Format: "format",
+    /// `(open-output-string)` creates a new, empty output string port.
+    OpenOutputString: "open-output-string",
+    /// `(port? p)` is true if the argument is an output string port.
+    IsPort: "port?",
+    /// `(port-write p a b c)` stringifies the inputs like `string-append`,
+    /// appends them to the output string port `p`, and returns `#undef`.
+    PortWrite: "port-write",
+    /// `(get-output-string p)` returns the text written to the output string port `p` so far.
+    GetOutputString: "get-output-string",
+    /// `(read-file path)` returns the contents of the file at `path` as a string.
+    /// Relative paths are resolved from the directory of the current file.
+    ///
+    /// The file I/O builtins are only available if `mm0-rs` was started with `--allow-io`,
+    /// and they can only access files under the current directory of the `mm0-rs` process.
+    ReadFile: "read-file",
+    /// `(write-file path s)` writes the string `s` to the file at `path`, replacing
+    /// its contents if it already exists, and returns `#undef`.
+    /// (Requires `--allow-io`, see `read-file`.)
+    WriteFile: "write-file",
+    /// `(file-exists? path)` is true if there is a file at `path`.
+    /// (Requires `--allow-io`, see `read-file`.)
+    FileExists: "file-exists?",
     /// `(not e1 e2 e3)` returns `#f` if any argument is truthy, and `#t` otherwise.
     /// It is not short-circuiting.
     Not: "not",
@@ -1147,8 +1570,10 @@ str_enum! {
     IsPair: "pair?",
     /// `(null? e)` is true if its argument is `()`.
     IsNull: "null?",
-    /// `(number? e)` is true if the argument is an integer.
+    /// `(number? e)` is true if the argument is a number, either an integer or a rational.
     IsNumber: "number?",
+    /// `(integer? e)` is true if the argument is an integer.
+    IsInteger: "integer?",
     /// `(string? e)` is true if its argument is a string (not a formula or atom).
     IsString: "string?",
     /// `(fn? e)` is true if the argument is a procedure.
@@ -1203,12 +1628,77 @@ str_enum! {
     /// * `(merge-map f old new)` or `((merge-map f) old new)` will use
     ///   `(f oldval newval)` to resolve keys that are present in both maps.
     MergeMap: "merge-map",
+    /// `(hash? m)` is true if the argument is a hash table.
+    IsHashTable: "hash?",
+    /// `(hash-new [k1 v1] [k2 v2] ...)` creates a new mutable hash table. Unlike atom maps,
+    /// the keys can be any hashable value: atoms, numbers, strings, booleans, and lists
+    /// of hashable values. Keys are compared structurally, so `'(1 2)` and `(list 1 2)`
+    /// are the same key, but the string `"a"` and the atom `'a` are different keys.
+    HashNew: "hash-new",
+    /// * `(hash-ref m k)` gets the value stored in the hash table `m` at `k`,
+    ///   or `#undef` if not present.
+    /// * `(hash-ref m k v)` will return `v` instead if the key is not present,
+    ///   unless `v` is a procedure, in which case it will be called with no arguments on lookup failure.
+    HashRef: "hash-ref",
+    /// * `(hash-set! m k v)` inserts the value `v` at key `k` in the mutable hash table `m`,
+    ///   and returns `#undef`.
+    /// * `(hash-set! m k)` removes the key `k` from `m`.
+    ///
+    /// A hash table whose values are all `#t` can be used as a hash set.
+    HashSet: "hash-set!",
+    /// `(hash-keys m)` returns the list of keys in the hash table `m`, in no particular order.
+    HashKeys: "hash-keys",
+    /// `(vector? v)` is true if the argument is a vector.
+    IsVector: "vector?",
+    /// `(vector a b c)` creates a new mutable vector with elements `a`, `b`, `c`.
+    /// Unlike lists, vectors support constant time indexing and in-place update.
+    Vector: "vector",
+    /// `(vector-ref v n)` returns the `n`th element of the vector `v` (zero-indexed).
+    /// It is an error if `n` is out of range.
+    VectorRef: "vector-ref",
+    /// `(vector-set! v n x)` sets the `n`th element of the vector `v` to `x`,
+    /// and returns `#undef`. It is an error if `n` is out of range.
+    VectorSet: "vector-set!",
+    /// `(vector-push! v x)` appends `x` to the end of the vector `v`, and returns `#undef`.
+    VectorPush: "vector-push!",
+    /// `(vector-length v)` returns the number of elements in the vector `v`.
+    VectorLength: "vector-length",
+    /// `(vector->list v)` returns a list of the current elements of the vector `v`.
+    VectorToList: "vector->list",
     /// `(set-timeout n)` sets the timeout for running individual theorems and
     /// `do` blocks to `n` milliseconds. The default is 5 seconds.
     SetTimeout: "set-timeout",
     /// `(set-stack-limit n)` sets the maximum number of stack frames used during
     /// evaluation of theorems and `do` blocks to `n`. The default is 1024.
     SetStackLimit: "set-stack-limit",
+    /// `(set-step-limit n)` sets the maximum number of evaluation steps used during
+    /// evaluation of theorems and `do` blocks to `n`, or no limit if `n = 0`.
+    /// The default is no limit.
+    SetStepLimit: "set-step-limit",
+    /// `(set-alloc-limit n)` sets the maximum number of lisp values allocated during
+    /// evaluation of theorems and `do` blocks to `n`, or no limit if `n = 0`.
+    /// The default is no limit.
+    SetAllocLimit: "set-alloc-limit",
+    /// `(profile-start)` starts (or restarts) profiling lisp evaluation. Each evaluation
+    /// step, and the time until the next step, is attributed to the call stack of
+    /// lisp procedures at that point, until the next `(profile-report)`.
+    ProfileStart: "profile-start",
+    /// `(profile-report)` stops profiling and returns the profile as a string in the
+    /// "folded stacks" format used by flame graph tools: one line per call stack, with the
+    /// procedure names separated by `;`, followed by the number of evaluation steps spent in
+    /// it. `(profile-report 'time)` reports the time spent in microseconds instead.
+    ProfileReport: "profile-report",
+    /// `(spawn f)` starts evaluating `(f)` on a pool of background threads, using a copy of the
+    /// current environment, and returns a future for the result, while elaboration continues on the
+    /// main thread. Changes that `f` makes to the environment or to ref-cells are not visible
+    /// outside the task, so this is intended for pure computations like proof search.
+    /// It is an error if `f` or the environment contain values that cannot be copied,
+    /// like metavariables.
+    Spawn: "spawn",
+    /// `(await x)` waits for the future `x` created by `(spawn f)` to finish, and returns
+    /// the result of `(f)`, or raises its error. Awaiting the same future again returns the same
+    /// result. Calling the future as `(x)` is the same as `(await x)`.
+    Await: "await",
     /// `(mvar? e)` returns `#t` if `e` is an unsolved metavariable value.
     /// *Note:* Holes in expressions are *not* represented as raw metavariables,
     /// they are ref-cells to metavariables. So to test if a metavariable has not
@@ -1254,6 +1744,13 @@ str_enum! {
     /// responsible for reporting all unfinished goals. Passing `#undef` instead of
     /// a function will reset it to the default closer.
     SetCloseFn: "set-close-fn",
+    /// `(sorry)` admits all the current goals without proof, leaving the goal list empty.
+    /// The goals are reported with a warning and listed as holes, and the theorem is added
+    /// to the environment without a proof (and is refused by the MMB exporter unless
+    /// `--allow-sorry` is given). Goals that come from an explicit `_` in the proof and
+    /// are left unproved at the end of a theorem are admitted in the same way; other
+    /// unsolved goals are errors.
+    Sorry: "sorry",
     /// `(local-ctx)` returns the list of hypothesis names (`(infer-type)`
     /// can be used to get the type of the hypotheses).
     LocalCtx: "local-ctx",
@@ -1300,6 +1797,184 @@ str_enum! {
     ///    or atom map of dummy variables, and `proof` is the proof s-expression. `vtask`
     ///    can also have the form `(ds proof)` itself.
     GetDecl: "get-decl",
+    /// `(get-proof x)` returns the proof of theorem `x`, in the form `(ds proof)` where `ds`
+    /// is the list of dummy variables and `proof` is the proof s-expression, the same as the
+    /// result of forcing the `vtask` thunk of `(get-decl x)`. It returns `:sorry` if `x`
+    /// is an axiom or a theorem without a proof.
+    GetProof: "get-proof",
+    /// `(decls k ...)` returns the list of names of all declarations in the environment,
+    /// in the order they were declared. If any `k` are given, they should be among
+    /// `'term`, `'def`, `'axiom` and `'theorem`, and only declarations of those kinds
+    /// are returned. For example `(decls 'axiom 'theorem)` returns all theorems and axioms.
+    Decls: "decls",
+    /// `(search pat)` returns the list of theorems and axioms whose conclusion matches the
+    /// term pattern `pat`, such as `$ _ -> (a -> _) $`. In the pattern, `_` matches any
+    /// subterm, and other atoms that are not term constructors are pattern variables, which
+    /// match any subterm but must match the same subterm at each occurrence.
+    /// `(search pat n)` also unfolds up to `n` nested definitions in the theorem statements
+    /// to find a match, and `(search pat n #t)` also finds theorems with a matching hypothesis.
+    Search: "search",
+    /// `(congruence-closure eqs a b fns)` proves `a = b` by congruence closure, using the
+    /// equations in `eqs`, and returns the proof, or `#undef` if `a = b` does not follow
+    /// from the equations. Each element of `eqs` is a list `(p x y)` where `p` is a proof
+    /// of `x = y`. Since equality is defined by the library, `fns` is an atom map of
+    /// functions that build the proofs:
+    ///
+    /// * `(refl x)` proves `x = x`.
+    /// * `(symm p x y)` proves `y = x`, where `p` proves `x = y`.
+    /// * `(trans p q x y z)` proves `x = z`, where `p` proves `x = y` and `q` proves `y = z`.
+    /// * `(congr t xs ys ps)` proves `(t . xs) = (t . ys)`, where `ps` is a list of proofs
+    ///   of `x = y` for corresponding elements of `xs` and `ys`.
+    ///
+    /// The closure is computed natively, and the functions are only called to build the
+    /// final proof, so this is much faster than a lisp implementation of the same search.
+    CongruenceClosure: "congruence-closure",
+    /// `(simp e fns [opts])` rewrites the term `e` using the simp lemmas, innermost first,
+    /// until no lemma applies, and returns `(e2 p)` where `p` is a proof of `e = e2`, or
+    /// `#undef` if no lemma applies. `fns` is an atom map of functions that build the
+    /// proof, containing `refl`, `symm`, `trans` and `congr` as in `congruence-closure`, and
+    /// optionally:
+    ///
+    /// * `(rw p x y)` proves `x = y`, where `p` is the application `(! thm . args)` of a
+    ///   simp lemma, which proves `x = y`. This can be used to put the proof in a context,
+    ///   and if it is not given the application is used as is.
+    /// * `(discharge h)` proves the instantiated hypothesis `h` of a conditional lemma, or
+    ///   returns `#undef` if the lemma should not be used. If it is not given, lemmas with
+    ///   hypotheses are not used.
+    ///
+    /// Lemmas like `a + b = b + a`, whose sides are the same up to renaming the variables,
+    /// are only used if they make the term smaller in a fixed order on terms.
+    /// `opts` is an atom map with the options:
+    ///
+    /// * `rtl`: if true, the lemmas are used right to left.
+    /// * `depth`: the maximum number of rewrites (default 1000), after which the
+    ///   simplifier stops.
+    /// * `only`: a list of theorems to use instead of the simp lemmas.
+    /// * `add`: a list of theorems to use in addition to the simp lemmas.
+    Simp: "simp",
+    /// `(add-simp! x ...)` registers the theorems `x ...` as simp lemmas for `simp`.
+    /// The conclusion of each theorem should be an equation `R lhs rhs`, where `lhs` is not a
+    /// variable. The `@simp` attribute on a theorem does the same thing.
+    AddSimp: "add-simp!",
+    /// `(lia hyps goal table)` proves `goal` by linear arithmetic from the hypotheses in
+    /// `hyps`, and returns the proof, or `#undef` if no proof was found. Each element of
+    /// `hyps` is a list `(p e)` where `p` is a proof of `e`, and the hypotheses that are not
+    /// inequalities `t <= u`, `t < u` or equations `t = u` are ignored. `goal` should be
+    /// `t <= u` or `t < u`, or `#undef` to prove a contradiction from the hypotheses.
+    ///
+    /// Since arithmetic is defined by the library, `table` is an atom map that gives the
+    /// term constructors `le`, `lt`, `eq`, `add` and `mul`, the functions `(num e)`, which
+    /// returns the value of `e` if it is a numeral and `#undef` otherwise, and `(numeral n)`,
+    /// which returns a numeral for `n`, and the proof steps below. Each proof step is
+    /// either the name of a theorem, which is applied to the proofs, or a function, which is
+    /// called with the proofs followed by the terms:
+    ///
+    /// * `(lt->le p t u)`: from `p: t < u` prove `t + 1 <= u`.
+    /// * `(eq->le p t u)`: from `p: t = u` prove `t <= u`.
+    /// * `(eq->ge p t u)`: from `p: t = u` prove `u <= t`.
+    /// * `(le-mul p k t u)`: from `p: t <= u` prove `k * t <= k * u`, where `k` is a numeral.
+    /// * `(le-add p q t u t2 u2)`: from `p: t <= u` and `q: t2 <= u2` prove `t + t2 <= u + u2`.
+    /// * `(contra p t u c)`: from `p: t <= u`, where `t = u + c` after normalization and
+    ///   `c` is a positive numeral, prove a contradiction.
+    /// * `(nonneg x)` (optional): prove `0 <= x` for an atomic subterm `x`, or return `#undef`.
+    /// * `(assume g)` and `(by-contra p g)` (needed if there is a goal): `assume` returns
+    ///   a proof of the negated goal `g` as a hypothesis, and `by-contra` proves the goal
+    ///   `g` from a contradiction `p` which uses it.
+    ///
+    /// The search uses Fourier–Motzkin elimination over the rationals, treating all
+    /// subterms other than additions, multiplications by numerals and numerals as atoms.
+    Lia: "lia",
+    /// `(sat-tauto goal table)` proves the propositional tautology `goal` with a SAT solver,
+    /// and returns the proof, or `#undef` if `goal` is not a tautology. Subformulas whose
+    /// head is not one of the connectives in `table` are treated as atoms.
+    ///
+    /// Since the connectives are defined by the library, `table` is an atom map that gives
+    /// the term constructors for the connectives `not` (required), `and`, `or`, `imp`,
+    /// `iff`, `true` and `false`, and the lemmas and proof steps below. The proofs are in
+    /// deduction form `G -> p`, where `F` is a false formula chosen by the library, and the
+    /// result proves `G -> goal` for any `G`. Each lemma or step is either the name of a
+    /// theorem, which is applied to the proofs, or a function, which is called with the
+    /// proofs followed by the terms:
+    ///
+    /// * `(hyp a)`: prove `G /\ a -> a`.
+    /// * `(weaken p a)`: from `p: G -> b` prove `G /\ a -> b`.
+    /// * `(mp p q)`: from `p: G -> a -> b` and `q: G -> a` prove `G -> b`.
+    /// * `(cases p q a)`: from `p: G /\ a -> F` and `q: G /\ ~a -> F` prove `G -> F`.
+    /// * `(intro p a)`: from `p: G /\ a -> b` prove `G -> a -> b`.
+    /// * `(by-contra p a)`: from `p: G /\ ~a -> F` prove `G -> a`.
+    /// * For each connective in the table, the lemmas for its clauses, which are called
+    ///   with the arguments of the connective and prove the following, where `x` is the
+    ///   connective applied to the arguments `a` and `b`:
+    ///   * `not-1`: `G -> x -> a -> F` and `not-2`: `G -> ~x -> ~a -> F`.
+    ///   * `and-1`: `G -> x -> ~a -> F`, `and-2`: `G -> x -> ~b -> F` and
+    ///     `and-3`: `G -> ~x -> a -> b -> F`.
+    ///   * `or-1`: `G -> x -> ~a -> ~b -> F`, `or-2`: `G -> ~x -> a -> F` and
+    ///     `or-3`: `G -> ~x -> b -> F`.
+    ///   * `imp-1`: `G -> x -> a -> ~b -> F`, `imp-2`: `G -> ~x -> ~a -> F` and
+    ///     `imp-3`: `G -> ~x -> b -> F`.
+    ///   * `iff-1`: `G -> x -> a -> ~b -> F`, `iff-2`: `G -> x -> ~a -> b -> F`,
+    ///     `iff-3`: `G -> ~x -> ~a -> ~b -> F` and `iff-4`: `G -> ~x -> a -> b -> F`.
+    ///   * `true-1`: `G -> ~x -> F` and `false-1`: `G -> x -> F`.
+    ///
+    /// The solver is a CDCL solver on the Tseitin encoding of `~goal`, and the proof is
+    /// reconstructed from the clauses it learns, each of which follows by unit propagation.
+    SatTauto: "sat-tauto",
+    /// `(smt hyps goal table)` asks an external SMT solver, like z3 or cvc5, whether `goal`
+    /// follows from the hypotheses in `hyps`, where each element of `hyps` is a list `(p e)`
+    /// and `p` is a proof of `e`. If `goal` is `#undef`, it asks whether the hypotheses are
+    /// contradictory. The result is one of:
+    ///
+    /// * `(unsat hyps2 lemmas)`: the goal follows, and `hyps2` and `lemmas` are the elements
+    ///   of `hyps` and the theorems in the unsat core, which are enough to prove it.
+    /// * `(sat model)`: the goal does not follow, and `model` is a counterexample, as a list
+    ///   of `(e v)` where `e` is a subterm and `v` is its value.
+    /// * `(unknown)`: the solver could not decide.
+    ///
+    /// The solver does not produce a proof: the result is meant to guide a proof search, for
+    /// example by passing the hypotheses of the unsat core to `lia` or `sat-tauto`.
+    /// Running external programs is disabled unless `mm0-rs` was started with `--allow-smt`,
+    /// which runs `z3 -in`, or `--smt-cmd CMD`, which runs `CMD`. The solver should read
+    /// SMT-LIB2 commands from standard input.
+    ///
+    /// `table` is an atom map with the keys:
+    ///
+    /// * `sorts`: an atom map from MM0 sorts to SMT sorts, like
+    ///   `(atom-map! '[wff "Bool"] '[nat "Int"])`. Exactly one sort should be mapped to
+    ///   `Bool`, and it is the sort of the hypotheses and the goal.
+    /// * `ops` (optional): an atom map from term constructors to SMT operators, like
+    ///   `'[an "and"]` or `'[add "+"]`. Term constructors that are not in `ops` are
+    ///   declared as uninterpreted functions.
+    /// * `num` (optional): a function `(num e)` which returns the value of `e` if it is a
+    ///   numeral, and `#undef` otherwise.
+    /// * `lemmas` (optional): a list of theorems, which are sent to the solver as universally
+    ///   quantified axioms.
+    /// * `timeout` (optional): the time limit for the solver, in milliseconds.
+    ///
+    /// Subterms that cannot be translated, like metavariables, are sent as fresh constants,
+    /// and hypotheses and lemmas that use a sort which is not in `sorts` are not sent.
+    Smt: "smt",
+    /// `(auto n)` proves the first goal by backward search, applying hypotheses and
+    /// theorems whose conclusion unifies with the goal and then proving their hypotheses
+    /// in the same way, with at most `n` nested theorem applications. The candidate
+    /// theorems are found in an index of the theorem conclusions, so this is fast even in
+    /// large environments. The arguments of a theorem that are not determined by the goal
+    /// are found by unification with the later hypotheses, and definitions are not unfolded.
+    /// The proof is applied with `refine`, and it is an error if there is no proof.
+    Auto: "auto",
+    /// `(instance e)` proves `e` by instance resolution, and returns the proof. A goal
+    /// whose head is a term constructor with registered instances (a *class*) is proved
+    /// by applying the instances for that head and the hypotheses in the local context,
+    /// trying instances with higher priority first, and then proving the hypotheses of the
+    /// instance in the same way. If there is no proof, the error describes the deepest
+    /// subgoal that could not be proved and the instances that were tried on it.
+    ///
+    /// The `@instance` attribute on a theorem registers it as an instance, and goals whose
+    /// head is a class that remain at the end of a proof are proved by instance resolution.
+    Instance: "instance",
+    /// `(add-instance! x)` registers the theorem `x` as an instance for `instance`, with
+    /// the default priority 1000, and `(add-instance! x n)` registers it with priority `n`.
+    /// The conclusion of the theorem should not be a variable.
+    AddInstance: "add-instance!",
     /// `(add-decl! decl-data ...)` adds a new declaration, as if a new `def` or `theorem`
     /// declaration was created. This does not do any elaboration - all information is
     /// expected to be fully elaborated. The input format is the same as the output format
@@ -1324,10 +1999,19 @@ str_enum! {
     ///   display is suppressed.)
     /// * `(set-reporting b)` will set the error reporting to `b` for all error types.
     SetReporting: "set-reporting",
+    /// * `(set-warning code level)` sets the level of the warnings with warning code `code`
+    ///   (like `'unused-hypothesis`) to `level`, which can be `'allow` (suppress the warning),
+    ///   `'warn` or `'deny` (report the warning as an error), for the rest of the file.
+    /// * `(set-warning level)` sets the level of all warning codes to `level`.
+    SetWarning: "set-warning",
     /// * `(set-backtrace b)` turns on (`b = #t`) or off (`b = #f`) backtraces in lisp for theorems.
     /// * `(set-backtrace type b)` does the same but for specific error type `type`,
     ///   which can be `'error`, `'info` or `'warn`.
     SetBacktrace: "set-backtrace",
+    /// `(set-render-template fmt t tpl)` sets the template string `tpl` used to render the
+    /// term `t` in the output format `fmt`, which can be `'latex` or `'html`. In the template,
+    /// `#1` through `#9` refer to the arguments of `t`. See [`render`](crate::render).
+    SetRenderTemplate: "set-render-template",
     /// `refine-extra-args` can be called directly, but it simply returns an error. It is called
     /// by `refine` when elaborating a term with too many arguments, and is expected to be
     /// overridden by user code to provide a more useful behavior.
@@ -1346,6 +2030,18 @@ str_enum! {
   }
 }
 
+impl BuiltinProc {
+  /// Returns true if this builtin can change the environment or a lisp value in place,
+  /// rather than only returning a new value. After such a call, the copy of the environment
+  /// made for [`Spawn`](Self::Spawn) tasks is out of date.
+  #[must_use] pub fn is_mutating(self) -> bool {
+    matches!(self,
+      Self::SetRef | Self::SetWeak | Self::Insert | Self::HashSet | Self::VectorSet |
+      Self::VectorPush | Self::PortWrite | Self::AddSimp | Self::AddInstance | Self::AddDecl |
+      Self::AddTerm | Self::AddThm | Self::SetRenderTemplate | Self::EvalString)
+  }
+}
+
 impl std::fmt::Display for BuiltinProc {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     self.to_str().fmt(f)
@@ -1534,6 +2230,17 @@ impl<K: Clone + Hash + Eq, V: Remap, S> Remap for HashMap<K, V, S> {
   type Target = HashMap<K, V::Target>;
   fn remap(&self, r: &mut Remapper) -> Self::Target { self.iter().map(|(k, v)| (k.clone(), v.remap(r))).collect() }
 }
+impl Remap for HashKey {
+  type Target = Self;
+  fn remap(&self, r: &mut Remapper) -> Self {
+    match self {
+      HashKey::Atom(a) => HashKey::Atom(a.remap(r)),
+      HashKey::List(es) => HashKey::List(es.remap(r)),
+      HashKey::DottedList(es, e) => HashKey::DottedList(es.remap(r), e.remap(r)),
+      _ => self.clone(),
+    }
+  }
+}
 impl<A: Remap> Remap for RefCell<A> {
   type Target = RefCell<A::Target>;
   fn remap(&self, r: &mut Remapper) -> Self::Target { RefCell::new(self.borrow().remap(r)) }
@@ -1572,3 +2279,65 @@ impl Remap for Proc {
   type Target = Self;
   fn remap(&self, r: &mut Remapper) -> Self { unsafe { self.freeze() }.remap(r) }
 }
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+  use super::*;
+  use crate::{CancelToken, Elaborator};
+
+  /// Elaborate `text`, and return the elaborator, with its arena installed on this thread.
+  fn elab(text: &str) -> Elaborator {
+    let ast = Arc::new(mm1_parser::parse(Arc::new(text.to_owned().into()), None).1);
+    let path = PathBuf::from("test.mm1").into();
+    let mut elab = Elaborator::new(ast.clone(), path, false, true, CancelToken::new(), None);
+    elab.arena.install_thread_local();
+    for s in &ast.stmts { elab.elab_stmt(String::new(), s, s.span).expect("elaboration failed"); }
+    elab
+  }
+
+  /// Get the value of the global definition `x`.
+  fn global(elab: &mut Elaborator, x: &str) -> LispVal {
+    let a = elab.get_atom(x.as_bytes());
+    elab.data[a].lisp.as_ref().expect("undefined global").val.clone()
+  }
+
+  /// Returns true if the ref cell `e` contains a value other than `#undef`.
+  fn is_set(e: &LispVal) -> bool {
+    e.as_lref(|m| m.get(|e| e.is_def())).expect("not a ref cell")
+  }
+
+  #[test]
+  fn collect_unreachable_cycles() {
+    // `w` is a weak reference to a cycle through a list, and `v` to a cycle through the
+    // environment of a lambda, which are both unreachable.
+    let mut elab = elab("do {
+      (def w (ref!)) (def v (ref!))
+      (let ([r (ref!)]) (set! r (list r)) (set-weak! w r))
+      (let ([r (ref!)]) (set! r (fn () r)) (set-weak! v r))
+    };");
+    let (w, v) = (global(&mut elab, "w"), global(&mut elab, "v"));
+    assert!(is_set(&w) && is_set(&v));
+    assert_eq!(elab.arena.collect(), 2);
+    assert!(!is_set(&w) && !is_set(&v));
+    LispArena::uninstall_thread_local();
+  }
+
+  #[test]
+  fn collect_keeps_reachable_cycles() {
+    // `g` is a cycle reachable from a global, and `f` holds a cycle in its environment.
+    let mut elab = elab("do {
+      (def g (ref!)) (set! g (list g))
+      (def f (let ([r (ref!)]) (set! r (list r)) (fn () r)))
+    };");
+    assert_eq!(elab.arena.collect(), 0);
+    assert!(is_set(&global(&mut elab, "g")));
+    let f = global(&mut elab, "f");
+    let r = f.unwrapped(|e| match e {
+      LispKind::Proc(Proc::Lambda {env, ..}) => env[0].clone(),
+      _ => panic!("not a lambda"),
+    });
+    assert!(is_set(&r));
+    LispArena::uninstall_thread_local();
+  }
+}
@@ -245,6 +245,9 @@ env_debug! {
   std::path::PathBuf,
   std::sync::atomic::AtomicBool,
   num::BigInt,
+  num::BigRational,
+  crate::lisp::Int,
+  crate::lisp::LispFuture,
   crate::ArcString,
   crate::lisp::Syntax,
   crate::lisp::BuiltinProc,
@@ -5,23 +5,35 @@
 //! and timeout.
 
 use std::collections::{hash_map::Entry, HashMap};
+use std::cmp::Ordering;
 use std::mem;
+use std::path::{Path, PathBuf};
 use std::ops::{Deref, DerefMut};
-use std::sync::atomic::Ordering;
 use std::time::{Duration, Instant};
-use num::{BigInt, Signed, ToPrimitive, Zero};
+use num::{BigInt, BigRational, Integer, Signed, ToPrimitive, Zero};
 use crate::{ast::SExpr, ArcString, AtomData, AtomId, BoxError, DeclKey, DocComment, ElabError,
   Elaborator, Environment, ErrorLevel, FileRef, FileSpan, LispData,
   MergeStrategy, MergeStrategyInner, ObjectKind, SliceExt, Span, StmtTrace,
-  ExprNode, ProofNode, TermKind, ThmKind, ThmId};
+  ExprNode, ProofNode, TermKind, ThmKind, ThmId, WarningCode, WarningLevel};
+use crate::render::{check_template, RenderFormat};
 use crate::elab::local_context::{try_get_span, try_get_span_from, AwaitingProof, InferSort};
 use crate::elab::{
   refine::{RStack, RState, RefineResult},
-  ElabErrorKind, ReportMode, Result};
-use super::parser::{Branch, DefTarget, Ir, MVarPattern, Pattern};
+  ElabErrorKind, GoalState, ReportMode, Result};
+use super::parser::{ArgKind, Branch, DefTarget, Ir, MVarPattern, Pattern};
 use super::print::{EnvDisplay, FormatEnv};
-use super::{Arc, BuiltinProc, Cell, InferTarget, LispKind, LispRef, LispVal, Modifiers, Proc,
-  ProcPos, ProcSpec, QExpr, Rc, RefCell, Uncons};
+use super::{builtin_doc, Arc, BuiltinProc, Cell, HashKey, InferTarget, Int, LispKind, LispRef, LispVal, Modifiers, Proc,
+  ProcPos, ProcSpec, QExpr, Rc, RefCell, Syntax, Uncons};
+
+/// The tail calls made by a procedure call, recorded in its stack frame so that
+/// stack traces can show the procedure that was originally called.
+#[derive(Clone, Debug)]
+struct TailCalls {
+  /// The procedure that was called at the call site of the frame.
+  caller: ProcPos,
+  /// The number of tail calls that have reused the frame.
+  count: usize,
+}
 
 #[derive(Debug)]
 enum Stack<'a> {
@@ -40,7 +52,7 @@ enum Stack<'a> {
   TestPattern(Span, LispVal, std::slice::Iter<'a, Branch>,
     &'a Branch, Vec<PatternStack<'a>>, Box<[LispVal]>),
   Drop(usize),
-  Ret(FileSpan, ProcPos, Vec<LispVal>, Arc<Ir>),
+  Ret(FileSpan, ProcPos, Vec<LispVal>, Arc<Ir>, Option<Box<TailCalls>>),
   MatchCont(Span, LispVal, std::slice::Iter<'a, Branch>, Rc<Cell<bool>>),
   SetMergeStrategy(Span, AtomId),
   MapProc(Span, Span, LispVal, Box<[Uncons]>, Vec<LispVal>),
@@ -50,6 +62,9 @@ enum Stack<'a> {
   Refine {sp: Span, stack: Vec<RStack>},
   Focus(Span, bool, Vec<LispVal>),
   Have(Span, LispVal, AtomId),
+  Try(Span, &'a Ir),
+  Reset,
+  Restore(FileRef, Vec<LispVal>),
 }
 
 impl<'a> EnvDisplay for Stack<'a> {
@@ -75,7 +90,7 @@ impl<'a> EnvDisplay for Stack<'a> {
         "(match {}\n  {}\n  {})\n  ->(? _)",
         fe.to(e), fe.to(br), fe.to(bs.as_slice())),
       &Stack::Drop(n) => write!(f, "drop {}", n),
-      Stack::Ret(_, pos, _, _) => match pos {
+      Stack::Ret(_, pos, _, _, _) => match pos {
         &ProcPos::Named(_, _, a) => write!(f, "ret {}", fe.to(&a)),
         ProcPos::Unnamed(_) => write!(f, "ret"),
       },
@@ -90,10 +105,67 @@ impl<'a> EnvDisplay for Stack<'a> {
       Stack::Refine {..} => write!(f, "(refine _)"),
       &Stack::Focus(_, cl, ref es) => write!(f, "(focus {} _)\n  ->{}", cl, fe.to(es)),
       Stack::Have(_, _, a) => write!(f, "(have {} _)", fe.to(a)),
+      &Stack::Try(_, ir) => write!(f, "(try _ {})", fe.to(ir)),
+      Stack::Reset => write!(f, "(reset _)"),
+      Stack::Restore(..) => write!(f, "restore"),
     }
   }
 }
 
+impl<'a> Stack<'a> {
+  /// Copy a stack frame, to capture it in a continuation. This returns `None` for frames
+  /// with state that can't be shared between copies, which are the frames of `refine`,
+  /// of proof procedures for `add-thm`, and of match clauses `[pat (=> k) e]`.
+  fn try_clone(&self) -> Option<Self> {
+    Some(match *self {
+      Stack::List(sp, ref es, ref it) => Stack::List(sp, es.clone(), it.clone()),
+      Stack::DottedList(ref es, ref it, e) => Stack::DottedList(es.clone(), it.clone(), e),
+      Stack::DottedList2(ref es) => Stack::DottedList2(es.clone()),
+      Stack::App(sp1, sp2, es) => Stack::App(sp1, sp2, es),
+      Stack::App2(sp1, sp2, ref f, ref es, ref it) =>
+        Stack::App2(sp1, sp2, f.clone(), es.clone(), it.clone()),
+      Stack::AppHead(sp1, sp2, ref e) => Stack::AppHead(sp1, sp2, e.clone()),
+      Stack::If(e1, e2) => Stack::If(e1, e2),
+      Stack::NoTailRec => Stack::NoTailRec,
+      Stack::Def(x) => Stack::Def(x),
+      Stack::DefMerge(ref loc, a, ref doc) => Stack::DefMerge(loc.clone(), a, doc.clone()),
+      Stack::Eval(e, ref it) => Stack::Eval(e, it.clone()),
+      Stack::Match(sp, ref it) => Stack::Match(sp, it.clone()),
+      Stack::TestPattern(sp, ref e, ref it, br, ref pstack, ref vars) =>
+        Stack::TestPattern(sp, e.clone(), it.clone(), br, pstack.clone(), vars.clone()),
+      Stack::Drop(n) => Stack::Drop(n),
+      Stack::Ret(ref fsp, ref pos, ref old, ref code, ref tail) =>
+        Stack::Ret(fsp.clone(), pos.clone(), old.clone(), code.clone(), tail.clone()),
+      Stack::SetMergeStrategy(sp, a) => Stack::SetMergeStrategy(sp, a),
+      Stack::MapProc(sp1, sp2, ref f, ref us, ref es) =>
+        Stack::MapProc(sp1, sp2, f.clone(), us.clone(), es.clone()),
+      Stack::MergeMap(sp, ref old, ref strat, ref it, ref map, k) =>
+        Stack::MergeMap(sp, old.clone(), strat.clone(), it.clone(), map.clone(), k),
+      Stack::Refines(sp, esp, ref it) => Stack::Refines(sp, esp, it.clone()),
+      Stack::Focus(sp, close, ref gs) => Stack::Focus(sp, close, gs.clone()),
+      Stack::Have(sp, ref x, a) => Stack::Have(sp, x.clone(), a),
+      Stack::Try(sp, ir) => Stack::Try(sp, ir),
+      Stack::Reset => Stack::Reset,
+      Stack::Restore(ref file, ref ctx) => Stack::Restore(file.clone(), ctx.clone()),
+      Stack::MatchCont(..) | Stack::AddThmProc(..) | Stack::Refine {..} => return None,
+    })
+  }
+}
+
+/// A continuation captured by `(shift k e)`.
+#[derive(Debug)]
+struct Continuation<'a> {
+  /// The stack frames between the `reset` and the `shift`.
+  frames: Vec<Stack<'a>>,
+  /// The context at the `shift`.
+  ctx: Vec<LispVal>,
+  /// The file containing the `shift`.
+  file: FileRef,
+  /// The code of the function containing the `reset`, which the frames can refer to,
+  /// or `None` if it is at top level.
+  _code: Option<Arc<Ir>>,
+}
+
 #[derive(Debug)]
 enum State<'a> {
   Eval(&'a Ir),
@@ -160,6 +232,23 @@ impl LispKind {
       _ => (None, None)
     }
   }
+
+  fn make_hash_table_mut<T>(&self,
+    f: impl FnOnce(&mut HashMap<HashKey, LispVal>) -> T
+  ) -> (Option<T>, Option<LispVal>) {
+    match self {
+      LispKind::HashTable(m) => {
+        let mut m = m.clone();
+        (Some(f(&mut m)), Some(LispVal::new(LispKind::HashTable(m))))
+      }
+      LispKind::Annot(sp, e) => match e.make_hash_table_mut(f) {
+        (r, None) => (r, None),
+        (r, Some(e)) => (r, Some(LispVal::new(LispKind::Annot(sp.clone(), e)))),
+      },
+      LispKind::Ref(m) => (m.get_mut(|e| e.as_hash_table_mut(f)), None),
+      _ => (None, None)
+    }
+  }
 }
 impl LispVal {
   fn as_map_mut<T>(&mut self, f: impl FnOnce(&mut HashMap<AtomId, LispVal>) -> T) -> Option<T> {
@@ -175,15 +264,112 @@ impl LispVal {
       Some(_) => None
     }
   }
+
+  fn as_hash_table_mut<T>(&mut self, f: impl FnOnce(&mut HashMap<HashKey, LispVal>) -> T) -> Option<T> {
+    match self.get_mut() {
+      None => {
+        let (r, new) = self.make_hash_table_mut(f);
+        if let Some(e) = new {*self = e}
+        r
+      }
+      Some(LispKind::HashTable(m)) => Some(f(m)),
+      Some(LispKind::Annot(_, e)) => Self::as_hash_table_mut(e, f),
+      Some(LispKind::Ref(m)) => m.get_mut(|e| Self::as_hash_table_mut(e, f)),
+      Some(_) => None
+    }
+  }
 }
 
-#[derive(Debug)]
+/// A number in the lisp numeric tower, used for arithmetic on mixed integer and
+/// rational arguments. A [`Num::Rat`] is never an integer.
+#[derive(Clone, Debug)]
+enum Num {
+  Int(Int),
+  Rat(BigRational),
+}
+
+impl Num {
+  fn rat(q: BigRational) -> Num {
+    if q.is_integer() { Num::Int(q.to_integer().into()) } else { Num::Rat(q) }
+  }
+
+  fn into_rat(self) -> BigRational {
+    match self {
+      Num::Int(n) => BigInt::from(n).into(),
+      Num::Rat(q) => q,
+    }
+  }
+
+  fn is_zero(&self) -> bool { matches!(self, Num::Int(n) if n.is_zero()) }
+
+  /// Apply a binary operation, using `fs` if both arguments are small integers and it does not
+  /// overflow, `fi` if both arguments are integers, and `fq` otherwise.
+  fn binop(self, other: Num,
+    fs: impl FnOnce(i64, i64) -> Option<i64>,
+    fi: impl FnOnce(BigInt, BigInt) -> BigInt,
+    fq: impl FnOnce(BigRational, BigRational) -> BigRational
+  ) -> Num {
+    match (self, other) {
+      (Num::Int(a), Num::Int(b)) => Num::Int(a.binop(b, fs, fi)),
+      (a, b) => Num::rat(fq(a.into_rat(), b.into_rat())),
+    }
+  }
+
+  /// Exact division, with the convention that division by zero yields zero.
+  fn div(self, other: Num) -> Num {
+    if other.is_zero() { return Num::Int(Int::from(0)) }
+    match (self, other) {
+      (Num::Int(a), Num::Int(b)) => match (a.to_i64(), b.to_i64()) {
+        (Some(x), Some(y)) if x.checked_rem(y) == Some(0) => Num::Int(x.div_euclid(y).into()),
+        _ => Num::rat(BigRational::new(a.into(), b.into())),
+      },
+      (a, b) => Num::rat(a.into_rat() / b.into_rat()),
+    }
+  }
+
+  fn cmp(&self, other: &Num) -> Ordering {
+    match (self, other) {
+      (Num::Int(a), Num::Int(b)) => a.cmp(b),
+      (Num::Int(a), Num::Rat(b)) => BigRational::from(BigInt::from(a.clone())).cmp(b),
+      (Num::Rat(a), Num::Int(b)) => a.cmp(&BigInt::from(b.clone()).into()),
+      (Num::Rat(a), Num::Rat(b)) => a.cmp(b),
+    }
+  }
+
+  fn into_lisp(self) -> LispVal {
+    match self {
+      Num::Int(n) => LispVal::number(n),
+      Num::Rat(q) => LispVal::rational(q),
+    }
+  }
+
+  fn to_str_radix(&self, radix: u32) -> String {
+    match self {
+      Num::Int(n) => n.to_str_radix(radix),
+      Num::Rat(q) => format!("{}/{}", q.numer().to_str_radix(radix), q.denom().to_str_radix(radix)),
+    }
+  }
+
+  /// Print the number in decimal notation with `prec` digits after the point.
+  fn into_fixed(self, prec: u32) -> String {
+    let n = (self.into_rat() * BigRational::from(BigInt::from(10).pow(prec))).round().to_integer();
+    let prec = prec as usize;
+    let mut s = n.magnitude().to_string();
+    if s.len() <= prec { s.insert_str(0, &"0".repeat(prec + 1 - s.len())) }
+    if prec > 0 { s.insert(s.len() - prec, '.') }
+    if n.is_negative() { s.insert(0, '-') }
+    s
+  }
+}
+
+#[derive(Clone, Debug)]
 enum Dot<'a> { List(Option<usize>), DottedList(&'a Pattern) }
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 enum PatternStack<'a> {
   Bool(&'a Pattern, bool),
   List(Uncons, std::slice::Iter<'a, Pattern>, Dot<'a>),
   Binary(bool, bool, LispVal, std::slice::Iter<'a, Pattern>),
+  Guard(&'a Ir),
 }
 
 #[derive(Debug)]
@@ -215,7 +401,13 @@ impl<'a> EnvDisplay for PatternState<'a> {
   }
 }
 
-struct TestPending<'a>(Span, LispVal, &'a Ir);
+/// An expression that needs to be evaluated before pattern matching can continue.
+enum TestPending<'a> {
+  /// The `(? f ps)` pattern: `f` is evaluated and then applied to the value.
+  Test(Span, LispVal, &'a Ir),
+  /// The `(p if cond)` pattern: `cond` is evaluated with the pattern variables bound.
+  Guard(&'a Ir),
+}
 
 /// A [`Result`](std::result::Result) type alias for string errors, used by functions that
 /// work without an elaboration context.
@@ -274,12 +466,17 @@ fn pattern_match<'b>(stack: &mut Vec<PatternStack<'b>>, ctx: &mut [LispVal],
         Pattern::Not(ps) => PatternState::Binary(true, false, e, ps.iter()),
         &Pattern::Test(sp, ref ir, ref ps) => {
           stack.push(PatternStack::Binary(false, false, e.clone(), ps.iter()));
-          return Err(TestPending(sp, e, ir))
+          return Err(TestPending::Test(sp, e, ir))
         },
+        Pattern::Guard(p, ir) => {
+          stack.push(PatternStack::Guard(ir));
+          PatternState::Eval(p, e)
+        }
       },
       PatternState::Ret(b) => match stack.pop() {
         None => return Ok(b),
-        Some(PatternStack::Bool(_, _)) if !b => PatternState::Ret(false),
+        Some(PatternStack::Bool(_, _) | PatternStack::Guard(_)) if !b => PatternState::Ret(false),
+        Some(PatternStack::Guard(ir)) => return Err(TestPending::Guard(ir)),
         Some(PatternStack::Bool(p, e)) =>
           PatternState::Eval(p, LispVal::bool(e)),
         Some(PatternStack::List(u, it, r)) =>
@@ -392,16 +589,31 @@ impl Elaborator {
     })
   }
 
-  fn with_int<T>(&self, e: &LispVal, f: impl FnOnce(&BigInt) -> SResult<T>) -> SResult<T> {
+  fn with_int<T>(&self, e: &LispVal, f: impl FnOnce(&Int) -> SResult<T>) -> SResult<T> {
     e.unwrapped(|e| if let LispKind::Number(n) = e {f(n)} else {
       Err(format!("expected a integer, got {}", self.print(e)))
     })
   }
 
-  fn as_int(&self, e: &LispVal) -> SResult<BigInt> {
+  /// Get the numeric base argument of `string->number` or `number->string`, which is 10
+  /// if it is not provided.
+  fn as_base(&self, e: Option<&LispVal>) -> SResult<u32> {
+    e.map_or(Ok(10), |e| self.with_int(e, |n| n.to_u32().filter(|b| (2..=36).contains(b))
+      .ok_or_else(|| format!("invalid base {}, expected 2 to 36", n))))
+  }
+
+  fn as_int(&self, e: &LispVal) -> SResult<Int> {
     self.with_int(e, |n| Ok(n.clone()))
   }
 
+  fn as_num(&self, e: &LispVal) -> SResult<Num> {
+    e.unwrapped(|e| match e {
+      LispKind::Number(n) => Ok(Num::Int(n.clone())),
+      LispKind::Rational(q) => Ok(Num::Rat(q.clone())),
+      _ => Err(format!("expected a number, got {}", self.print(e)))
+    })
+  }
+
   fn as_lref<T>(&self, e: &LispKind, f: impl FnOnce(&LispRef) -> SResult<T>) -> SResult<T> {
     e.as_lref(f).unwrap_or_else(|| Err(format!("not a ref-cell: {}", self.print(e))))
   }
@@ -417,6 +629,134 @@ impl Elaborator {
     })
   }
 
+  fn as_hash_table<T>(&self, e: &LispKind,
+    f: impl FnOnce(&HashMap<HashKey, LispVal>) -> SResult<T>
+  ) -> SResult<T> {
+    e.unwrapped(|e| match e {
+      LispKind::HashTable(m) => f(m),
+      _ => Err(format!("not a hash table: {}", self.print(e)))
+    })
+  }
+
+  fn as_vector<T>(&self, e: &LispKind, f: impl FnOnce(&RefCell<Vec<LispVal>>) -> SResult<T>) -> SResult<T> {
+    e.unwrapped(|e| match e {
+      LispKind::Vector(v) => f(v),
+      _ => Err(format!("not a vector: {}", self.print(e)))
+    })
+  }
+
+  /// Get the index argument of `vector-ref` or `vector-set!`, checking it against the length.
+  fn as_index(&self, e: &LispVal, len: usize) -> SResult<usize> {
+    self.with_int(e, |n| match n.to_usize() {
+      Some(i) if i < len => Ok(i),
+      _ => Err(format!("index out of range: index {}, length {}", n, len))
+    })
+  }
+
+  fn as_port<T>(&self, e: &LispKind, f: impl FnOnce(&RefCell<Vec<u8>>) -> SResult<T>) -> SResult<T> {
+    e.unwrapped(|e| match e {
+      LispKind::StringPort(p) => f(p),
+      _ => Err(format!("not a port: {}", self.print(e)))
+    })
+  }
+
+  /// Format the arguments according to the format string `fmt`,
+  /// using the directives supported by the `format` builtin.
+  fn format(&self, fmt: &[u8], args: &[LispVal]) -> SResult<Vec<u8>> {
+    fn digits(fmt: &[u8], i: &mut usize) -> u32 {
+      let mut n = 0_u32;
+      while let Some(&d @ b'0'..=b'9') = fmt.get(*i) {
+        n = n.saturating_mul(10).saturating_add((d - b'0').into());
+        *i += 1;
+      }
+      n
+    }
+    let mut out = vec![];
+    let mut args = args.iter();
+    let mut i = 0;
+    while let Some(&c) = fmt.get(i) {
+      i += 1;
+      if c != b'~' { out.push(c); continue }
+      let (mut left, mut zero) = (false, false);
+      loop {
+        match fmt.get(i) {
+          Some(b'-') => left = true,
+          Some(b'0') => zero = true,
+          _ => break
+        }
+        i += 1
+      }
+      let width = digits(fmt, &mut i) as usize;
+      let prec = if fmt.get(i) == Some(&b'.') { i += 1; Some(digits(fmt, &mut i)) } else { None };
+      let dir = *fmt.get(i).ok_or("format: unterminated directive")?;
+      i += 1;
+      let mut next = || args.next().ok_or("format: not enough arguments");
+      let s = match dir {
+        b'~' => vec![b'~'],
+        b'%' => vec![b'\n'],
+        b'a' => self.to_string(next()?).to_vec(),
+        b's' => format!("{}", self.print(next()?)).into_bytes(),
+        b'd' => self.as_num(next()?)?.to_str_radix(10).into_bytes(),
+        b'x' => self.as_num(next()?)?.to_str_radix(16).into_bytes(),
+        b'o' => self.as_num(next()?)?.to_str_radix(8).into_bytes(),
+        b'b' => self.as_num(next()?)?.to_str_radix(2).into_bytes(),
+        b'f' => self.as_num(next()?)?.into_fixed(prec.unwrap_or(6)).into_bytes(),
+        _ => return Err(format!("format: unknown directive ~{}", dir as char))
+      };
+      let pad = width.saturating_sub(s.len());
+      if left {
+        out.extend_from_slice(&s);
+        out.resize(out.len() + pad, b' ')
+      } else if zero && matches!(dir, b'd' | b'x' | b'o' | b'b' | b'f') {
+        let sign = usize::from(s.first() == Some(&b'-'));
+        out.extend_from_slice(&s[..sign]);
+        out.resize(out.len() + pad, b'0');
+        out.extend_from_slice(&s[sign..])
+      } else {
+        out.resize(out.len() + pad, b' ');
+        out.extend_from_slice(&s)
+      }
+    }
+    if args.next().is_some() { return Err("format: too many arguments".into()) }
+    Ok(out)
+  }
+
+  /// Resolve a path argument of the file I/O builtins, relative to the directory of the
+  /// current file. This fails if I/O is disabled or the path is outside the current directory
+  /// after following symbolic links. If `write` is true, this returns `None` if the directory
+  /// containing the path does not exist, and otherwise if the file does not exist.
+  fn io_path(&self, e: &LispVal, write: bool) -> SResult<Option<PathBuf>> {
+    if !crate::get_allow_io() { return Err("file I/O is disabled (use --allow-io to enable it)".into()) }
+    let s = self.as_string(e)?;
+    let s = std::str::from_utf8(&s).map_err(|_| "invalid path")?;
+    let path = self.path.path().parent().map_or_else(|| PathBuf::from(s), |p| p.join(s));
+    let path = if write {
+      let name = path.file_name().ok_or_else(|| format!("invalid path: {}", s))?;
+      let dir = match path.parent().unwrap_or_else(|| Path::new(".")).canonicalize() {
+        Ok(dir) => dir,
+        Err(_) => return Ok(None)
+      };
+      let path = dir.join(name);
+      // Writing to a symbolic link writes to its target, which must also be checked
+      if path.symlink_metadata().map_or(false, |m| m.file_type().is_symlink()) {
+        path.canonicalize().map_err(|_| format!("{}: broken symbolic link", s))?
+      } else { path }
+    } else {
+      match path.canonicalize() {
+        Ok(path) => path,
+        Err(_) => return Ok(None)
+      }
+    };
+    if !path.starts_with(&*crate::CURRENT_DIR) {
+      return Err(format!("{}: path is outside the project directory", s))
+    }
+    Ok(Some(path))
+  }
+
+  fn as_hash_key(&self, e: &LispKind) -> SResult<HashKey> {
+    e.to_hash_key().ok_or_else(|| format!("not a hashable value: {}", self.print(e)))
+  }
+
   fn to_string(&self, e: &LispKind) -> ArcString {
     match e {
       LispKind::Ref(m) => m.get(|e| self.to_string(e)),
@@ -428,12 +768,12 @@ impl Elaborator {
     }
   }
 
-  fn int_bool_binop(&self, mut f: impl FnMut(&BigInt, &BigInt) -> bool, args: &[LispVal]) -> SResult<bool> {
+  fn num_bool_binop(&self, mut f: impl FnMut(Ordering) -> bool, args: &[LispVal]) -> SResult<bool> {
     let mut it = args.iter();
-    let mut last = self.as_int(it.next().expect("int_bool_binop([])"))?;
+    let mut last = self.as_num(it.next().expect("num_bool_binop([])"))?;
     for v in it {
-      let new = self.as_int(v)?;
-      if !f(&last, &new) {return Ok(false)}
+      let new = self.as_num(v)?;
+      if !f(last.cmp(&new)) {return Ok(false)}
       last = new;
     }
     Ok(true)
@@ -454,6 +794,40 @@ impl Elaborator {
     s
   }
 
+  /// Returns the current proof context as a list of hypotheses `(name, type)`,
+  /// followed by the goals `("|-", goal)`.
+  pub fn proof_state(&self) -> Vec<(String, String)> {
+    let mut out = vec![];
+    for (a, e, _) in &self.lc.proof_order {
+      out.push((format!("{}", self.print(a)), format!("{}", self.format_env().pp(e, 80))))
+    }
+    for e in &self.lc.goals {
+      e.unwrapped(|r| if let LispKind::Goal(e) = r {
+        out.push(("|-".into(), format!("{}", self.format_env().pp(e, 80))))
+      })
+    }
+    out
+  }
+
+  /// Returns a structured snapshot of the current proof context, with the hypotheses,
+  /// goals and unassigned metavariables.
+  pub fn goal_state(&self) -> GoalState {
+    let fe = self.format_env();
+    let local = |e: &LispVal| e.fspan().filter(|fsp| fsp.file.ptr_eq(&self.path)).map(|fsp| fsp.span);
+    GoalState {
+      hyps: self.lc.proof_order.iter()
+        .map(|(a, e, _)| (format!("{}", self.print(a)), format!("{}", fe.pp(e, 80)))).collect(),
+      goals: self.lc.goals.iter().filter_map(|g| {
+        let ty = g.goal_type()?;
+        Some((local(g), format!("{}", fe.pp(&ty, 80))))
+      }).collect(),
+      mvars: self.lc.mvars.iter().filter_map(|m| {
+        let tgt = m.mvar_target()?;
+        Some((local(m), format!("{}", self.print(m)), format!("{}", self.print(&tgt))))
+      }).collect(),
+    }
+  }
+
   fn head_err(&self, e: &LispKind) -> SResult<LispVal> {
     e.unwrapped(|e| match e {
       LispKind::List(es) if es.is_empty() => Err("evaluating 'hd ()'".into()),
@@ -647,6 +1021,56 @@ fn set_report_mode(fe: FormatEnv<'_>, mode: &mut ReportMode, args: &[LispVal]) -
   } else {Err("invalid arguments".into())}
 }
 
+/// Limits on the resources used by the lisp evaluator. A lisp program that exceeds one of
+/// these limits is aborted with an error (and a backtrace, if enabled), rather than
+/// overflowing the stack or running forever.
+///
+/// The step and allocation limits apply to each theorem and `do` block separately,
+/// and they are only checked every 256 evaluation steps, like the timeout.
+#[derive(Copy, Clone, Debug)]
+pub struct EvalLimits {
+  /// The maximum number of stack frames, set by `(set-stack-limit)`. The default is 1024.
+  pub stack: usize,
+  /// The maximum number of evaluation steps, set by `(set-step-limit)`.
+  /// The default is `None`, meaning no limit.
+  pub steps: Option<u64>,
+  /// The maximum number of lisp values allocated, set by `(set-alloc-limit)`.
+  /// The default is `None`, meaning no limit.
+  pub allocs: Option<usize>,
+}
+crate::deep_size_0!(EvalLimits);
+
+impl Default for EvalLimits {
+  fn default() -> Self { EvalLimits {stack: 1024, steps: None, allocs: None} }
+}
+
+/// A frame of the lisp evaluation stack, as reported to a [`Debugger`].
+#[derive(Clone, Debug)]
+pub struct DebugFrame {
+  /// The procedure running in this frame, like `(foo)` or `[fn]` for an anonymous
+  /// procedure, or `[top]` for the top level expression.
+  pub name: String,
+  /// The location currently being evaluated in this frame.
+  pub fsp: FileSpan,
+}
+
+/// A debugger attached to the lisp evaluator, using [`ElaborateBuilder::debugger`].
+/// Before evaluating each expression, the evaluator calls [`check`](Self::check),
+/// and if it returns true then it calls [`pause`](Self::pause), which blocks until
+/// evaluation should resume.
+///
+/// [`ElaborateBuilder::debugger`]: crate::elab::ElaborateBuilder::debugger
+pub trait Debugger: Send + std::fmt::Debug {
+  /// Returns true if evaluation should pause before the expression at `fsp`.
+  /// `depth` is the number of procedure calls on the evaluation stack.
+  fn check(&mut self, fsp: &FileSpan, depth: usize) -> bool;
+
+  /// Called when evaluation is paused, and returns when it should resume.
+  /// `frames` is the evaluation stack, innermost frame first, and `locals` contains the
+  /// values of the local variables of the innermost frame.
+  fn pause(&mut self, elab: &Elaborator, frames: &[DebugFrame], locals: &[LispVal]);
+}
+
 /// The lisp evaluation context, representing a lisp evaluation in progress.
 /// This is an explicitly unfolled state machine (rather than using recursive functions)
 /// so that we can explicitly manipulate the program stack for error reporting purposes.
@@ -666,6 +1090,13 @@ pub struct Evaluator<'a> {
   /// The evaluation stack. This is a structured object containing a stack of continuations
   /// each of which represent a context which awaiting a value from a sub-computation.
   stack: Vec<Stack<'a>>,
+  /// The data attached to the error being thrown by `error` or `raise`, if any.
+  thrown: Option<LispVal>,
+  /// The continuations captured by `shift`, indexed by [`Proc::DelimCont`].
+  conts: Vec<Continuation<'a>>,
+  /// The validity flag of the continuations in `conts`, which is set to false when
+  /// evaluation ends. This is created when the first continuation is captured.
+  conts_valid: Option<Rc<Cell<bool>>>,
 }
 impl<'a> Deref for Evaluator<'a> {
   type Target = Elaborator;
@@ -678,26 +1109,67 @@ impl<'a> DerefMut for Evaluator<'a> {
 impl<'a> Evaluator<'a> {
   fn new(elab: &'a mut Elaborator, orig_span: Span) -> Evaluator<'a> {
     let file = elab.path.clone();
-    Evaluator {elab, ctx: vec![], file, orig_span, stack: vec![]}
+    Evaluator {elab, ctx: vec![], file, orig_span, stack: vec![], thrown: None,
+      conts: vec![], conts_valid: None}
   }
 
   fn fspan_base(&mut self, sp: Span) -> FileSpan {
     for s in &self.stack {
-      if let Stack::Ret(fsp, _, _, _) = s {return fsp.clone()}
+      if let Stack::Ret(fsp, ..) = s {return fsp.clone()}
     }
     self.fspan(sp)
   }
 
+  /// Calls the goal state listener with the proof state in the tactic block at `sp`,
+  /// which has `n` tactics left to run, if the block is in the file being elaborated.
+  fn goal_state_probe(&mut self, sp: Span, n: usize) {
+    if self.recv_goal_state.is_some() && self.file.ptr_eq(&self.elab.path) {
+      self.elab.call_goal_state_listener(sp, n)
+    }
+  }
+
+  fn proc_name(&self, pos: &ProcPos) -> String {
+    match *pos {
+      ProcPos::Named(_, _, a) => format!("({})", self.data[a].name),
+      ProcPos::Unnamed(_) => "[fn]".into(),
+    }
+  }
+
+  /// Consults the attached [`Debugger`] before evaluating `ir`, and pauses if requested.
+  fn debug_step(&mut self, ir: &Ir) {
+    let sp = if let Some(sp) = ir.span() {sp} else {return};
+    let mut dbg = if let Some(dbg) = self.elab.debugger.take() {dbg} else {return};
+    let mut fsp = FileSpan {file: self.file.clone(), span: sp};
+    let depth = self.stack.iter().filter(|s| matches!(s, Stack::Ret(..))).count();
+    if dbg.check(&fsp, depth) {
+      let mut frames = vec![];
+      for s in self.stack.iter().rev() {
+        if let Stack::Ret(call, pos, ..) = s {
+          let fsp = mem::replace(&mut fsp, call.clone());
+          frames.push(DebugFrame {name: self.proc_name(pos), fsp});
+        }
+      }
+      frames.push(DebugFrame {name: "[top]".into(), fsp});
+      dbg.pause(self.elab, &frames, &self.ctx);
+      // don't count the time spent paused against the timeout
+      self.elab.cur_timeout = self.elab.timeout.and_then(|d| Instant::now().checked_add(d));
+    }
+    self.elab.debugger = Some(dbg);
+  }
+
   fn make_stack_err(&mut self, sp: Option<(Span, bool)>, level: ErrorLevel,
       base: BoxError, err: impl Into<BoxError>) -> ElabError {
     let mut old = sp.map(|(sp, good)| (self.fspan(sp), good, base));
     let mut info = vec![];
     for s in self.stack.iter().rev() {
-      if let Stack::Ret(fsp, pos, _, _) = s {
-        let x = match pos {
-          ProcPos::Named(_, _, a) => format!("({})", self.data[*a].name).into(),
-          ProcPos::Unnamed(_) => "[fn]".into(),
-        };
+      if let Stack::Ret(fsp, pos, _, _, tail) = s {
+        let x = match tail.as_deref() {
+          None => self.proc_name(pos),
+          Some(&TailCalls {ref caller, count: 1}) =>
+            format!("{} -> {}", self.proc_name(caller), self.proc_name(pos)),
+          Some(&TailCalls {ref caller, count}) => format!("{} -> ... -> {} ({} tail calls)",
+            self.proc_name(caller), self.proc_name(pos), count),
+        }.into();
         if let Some((sp, good, base)) = old.take() {
           let (sp, osp) = if good {(sp, fsp.clone())} else {(fsp.clone(), sp)};
           info.push((osp, base));
@@ -711,13 +1183,14 @@ impl<'a> Evaluator<'a> {
       pos: old.map_or(self.orig_span, |(sp, _, _)| sp.span),
       level,
       kind: ElabErrorKind::Boxed(err.into(),
-        if self.backtrace.active(level) {Some(info)} else {None})
+        if self.backtrace.active(level) {Some(info)} else {None}),
+      code: None,
     }
   }
 
   fn stack_span(&self, mut n: usize) -> Option<FileSpan> {
     for s in self.stack.iter().rev() {
-      if let Stack::Ret(fsp, _, _, _) = s {
+      if let Stack::Ret(fsp, ..) = s {
         match n.checked_sub(1) {
           None => return Some(fsp.clone()),
           Some(i) => n = i
@@ -828,8 +1301,23 @@ make_builtins! { self, sp1, sp2, args,
     print!(sp1, String::from_utf8_lossy(&s));
     LispVal::undef()
   },
-  Error: Exact(1) => {
+  Error: AtLeast(1) => {
+    if args.len() > 2 {try1!(Err("expected at most 2 arguments"))}
     let s = try1!(self.as_string(&args[0]));
+    self.thrown = args.get(1).cloned();
+    try1!(Err(String::from_utf8_lossy(&s)))
+  },
+  Raise: Exact(1) => {
+    let (message, data) = (self.get_atom(b"message"), self.get_atom(b"data"));
+    let err = args[0].unwrapped(|e| match e {
+      LispKind::AtomMap(m) => m.get(&message)
+        .and_then(|s| s.unwrapped(|s| if let LispKind::String(s) = s {Some(s.clone())} else {None}))
+        .map(|s| (s, m.get(&data).cloned())),
+      _ => None
+    });
+    let (s, data) = err.unwrap_or_else(||
+      (format!("{}", self.print(&args[0])).into(), Some(args[0].clone())));
+    self.thrown = data;
     try1!(Err(String::from_utf8_lossy(&s)))
   },
   Print: Exact(1) => {print!(sp1, format!("{}", self.print(&args[0]))); LispVal::undef()},
@@ -847,7 +1335,7 @@ make_builtins! { self, sp1, sp2, args,
       let msg = if args[1].as_bool() == Some(true) {
         self.make_stack_err(Some((span, true)), level, "(report-at)".into(), s)
       } else {
-        ElabError { pos: span, level, kind: ElabErrorKind::Boxed(s, None) }
+        ElabError { pos: span, level, kind: ElabErrorKind::Boxed(s, None), code: None }
       };
       self.report(msg);
     }
@@ -870,86 +1358,164 @@ make_builtins! { self, sp1, sp2, args,
     }
     return Ok(State::App(sp1, sp, proc, args, [].iter()))
   },
+  Macro: Exact(1) => {
+    if !args[0].is_proc() { try1!(Err(format!("macro: not a procedure: {}", self.print(&args[0])))) }
+    LispVal::proc(Proc::Macro(args.swap_remove(0)))
+  },
+  Help: Exact(1) => {
+    let doc = match args[0].as_atom() {
+      Some(a) => match &self.data[a].lisp {
+        Some(LispData {doc: Some(doc), ..}) => Some(doc.clone()),
+        Some(ld) => ld.val.unwrapped(|e| if let LispKind::Proc(p) = e { p.doc() } else { None }),
+        None => BuiltinProc::from_bytes(&self.data[a].name).map(|p| builtin_doc(p.doc())),
+      },
+      None => args[0].unwrapped(|e| match e {
+        LispKind::Proc(p) => p.doc(),
+        &LispKind::Syntax(s) => Some(builtin_doc(s.doc())),
+        _ => None
+      }),
+    };
+    doc.map_or_else(LispVal::undef, |doc| LispVal::string(doc.as_bytes().into()))
+  },
   Add: AtLeast(0) => {
-    let mut n: BigInt = 0.into();
-    for e in args { n += try1!(self.as_int(&e)) }
-    LispVal::number(n)
+    let mut n = Num::Int(Int::from(0));
+    for e in args { n = n.binop(try1!(self.as_num(&e)), i64::checked_add, |a, b| a + b, |a, b| a + b) }
+    n.into_lisp()
   },
   Mul: AtLeast(0) => {
-    let mut n: BigInt = 1.into();
-    for e in args { n *= try1!(self.as_int(&e)) }
-    LispVal::number(n)
+    let mut n = Num::Int(Int::from(1));
+    for e in args { n = n.binop(try1!(self.as_num(&e)), i64::checked_mul, |a, b| a * b, |a, b| a * b) }
+    n.into_lisp()
   },
   Pow: AtLeast(0) => {
     let mut it = args.into_iter().rev();
     match it.next() {
-      None => LispVal::number(1.into()),
+      None => LispVal::number(1),
       Some(b) => {
-        let mut n = try1!(self.as_int(&b));
+        let mut n = try1!(self.as_num(&b));
         for e in it {
-          let exp: u32 = try1!(n.try_into().map_err(|_| "exponent out of range"));
-          let base = try1!(self.as_int(&e));
-          n = if base == 2.into() { BigInt::from(1) << exp } else { BigInt::pow(&base, exp) };
+          let exp = match n {
+            Num::Int(exp) => exp,
+            Num::Rat(_) => try1!(Err("exponent must be an integer")),
+          };
+          let neg = exp.is_negative();
+          let exp: u32 = try1!(exp.to_i64().and_then(|exp| exp.unsigned_abs().try_into().ok())
+            .ok_or("exponent out of range"));
+          n = match try1!(self.as_num(&e)) {
+            Num::Int(base) => Num::Int(match base.to_i64() {
+              Some(b) => match b.checked_pow(exp) {
+                Some(n) => n.into(),
+                None if b == 2 => (BigInt::from(1) << exp).into(),
+                None => BigInt::from(b).pow(exp).into(),
+              },
+              None => BigInt::from(base).pow(exp).into(),
+            }),
+            Num::Rat(base) => Num::Rat(BigRational::new_raw(base.numer().pow(exp), base.denom().pow(exp))),
+          };
+          if neg { n = Num::Int(Int::from(1)).div(n) }
         }
-        LispVal::number(n)
+        n.into_lisp()
       }
     }
   },
   Max: AtLeast(1) => {
     let mut it = args.into_iter();
-    let mut n: BigInt = try1!(self.as_int(&it.next().unwrap()));
-    for e in it { n = n.max(try1!(self.as_int(&e)).clone()) }
-    LispVal::number(n)
+    let mut n = try1!(self.as_num(&it.next().unwrap()));
+    for e in it {
+      let a = try1!(self.as_num(&e));
+      if a.cmp(&n) == Ordering::Greater { n = a }
+    }
+    n.into_lisp()
   },
   Min: AtLeast(1) => {
     let mut it = args.into_iter();
-    let mut n: BigInt = try1!(self.as_int(&it.next().unwrap()));
-    for e in it { n = n.min(try1!(self.as_int(&e)).clone()) }
-    LispVal::number(n)
+    let mut n = try1!(self.as_num(&it.next().unwrap()));
+    for e in it {
+      let a = try1!(self.as_num(&e));
+      if a.cmp(&n) == Ordering::Less { n = a }
+    }
+    n.into_lisp()
   },
   Sub: AtLeast(1) => if args.len() == 1 {
-    LispVal::number(-try1!(self.as_int(&args[0])))
+    match try1!(self.as_num(&args[0])) {
+      Num::Int(n) => LispVal::number(-n),
+      Num::Rat(q) => LispVal::rational(-q),
+    }
   } else {
     let mut it = args.into_iter();
-    let mut n: BigInt = try1!(self.as_int(&it.next().unwrap()));
-    for e in it { n -= try1!(self.as_int(&e)) }
-    LispVal::number(n)
+    let mut n = try1!(self.as_num(&it.next().unwrap()));
+    for e in it { n = n.binop(try1!(self.as_num(&e)), i64::checked_sub, |a, b| a - b, |a, b| a - b) }
+    n.into_lisp()
   },
   Div: AtLeast(1) => {
     let mut it = args.into_iter();
-    let mut n: BigInt = try1!(self.as_int(&it.next().unwrap()));
+    let mut n = try1!(self.as_num(&it.next().unwrap()));
     for e in it {
-      let a = try1!(self.as_int(&e));
-      if a.is_zero() {n.set_zero()} else {n /= a}
+      let a = try1!(self.as_num(&e));
+      n = if a.is_zero() { Num::Int(Int::from(0)) } else {
+        n.binop(a, i64::checked_div, |a, b| a / b, |a, b| (a / b).trunc())
+      }
     }
-    LispVal::number(n)
+    n.into_lisp()
+  },
+  Divide: AtLeast(1) => {
+    let mut it = args.into_iter();
+    let mut n = try1!(self.as_num(&it.next().unwrap()));
+    if it.as_slice().is_empty() { n = Num::Int(Int::from(1)).div(n) }
+    for e in it { n = n.div(try1!(self.as_num(&e))) }
+    n.into_lisp()
   },
   Mod: AtLeast(1) => {
     let mut it = args.into_iter();
-    let mut n: BigInt = try1!(self.as_int(&it.next().unwrap()));
+    let mut n = try1!(self.as_num(&it.next().unwrap()));
     for e in it {
-      let a = try1!(self.as_int(&e));
-      if !a.is_zero() {n %= a}
+      let a = try1!(self.as_num(&e));
+      if !a.is_zero() { n = n.binop(a, i64::checked_rem, |a, b| a % b, |a, b| a % b) }
     }
-    LispVal::number(n)
+    n.into_lisp()
+  },
+  ModFloor: Exact(2) => {
+    let a = BigInt::from(try1!(self.as_int(&args[0])));
+    let b = BigInt::from(try1!(self.as_int(&args[1])));
+    LispVal::number(if b.is_zero() {a} else {a.mod_floor(&b)})
   },
-  Lt: AtLeast(1) => LispVal::bool(try1!(self.int_bool_binop(|a, b| a < b, &args))),
-  Le: AtLeast(1) => LispVal::bool(try1!(self.int_bool_binop(|a, b| a <= b, &args))),
-  Gt: AtLeast(1) => LispVal::bool(try1!(self.int_bool_binop(|a, b| a > b, &args))),
-  Ge: AtLeast(1) => LispVal::bool(try1!(self.int_bool_binop(|a, b| a >= b, &args))),
-  Eq: AtLeast(1) => LispVal::bool(try1!(self.int_bool_binop(|a, b| a == b, &args))),
+  DivMod: Exact(2) => {
+    let a = BigInt::from(try1!(self.as_int(&args[0])));
+    let b = BigInt::from(try1!(self.as_int(&args[1])));
+    let (q, r) = if b.is_zero() {(BigInt::zero(), a)} else {a.div_mod_floor(&b)};
+    LispVal::list(vec![LispVal::number(q), LispVal::number(r)])
+  },
+  PowMod: Exact(3) => {
+    let a = BigInt::from(try1!(self.as_int(&args[0])));
+    let b = BigInt::from(try1!(self.as_int(&args[1])));
+    let m = BigInt::from(try1!(self.as_int(&args[2])));
+    if b.is_negative() {try1!(Err("pow-mod: negative exponent"))}
+    if !m.is_positive() {try1!(Err("pow-mod: modulus must be positive"))}
+    LispVal::number(a.modpow(&b, &m))
+  },
+  Lt: AtLeast(1) => LispVal::bool(try1!(self.num_bool_binop(Ordering::is_lt, &args))),
+  Le: AtLeast(1) => LispVal::bool(try1!(self.num_bool_binop(Ordering::is_le, &args))),
+  Gt: AtLeast(1) => LispVal::bool(try1!(self.num_bool_binop(Ordering::is_gt, &args))),
+  Ge: AtLeast(1) => LispVal::bool(try1!(self.num_bool_binop(Ordering::is_ge, &args))),
+  Eq: AtLeast(1) => LispVal::bool(try1!(self.num_bool_binop(Ordering::is_eq, &args))),
+  Numerator: Exact(1) => match try1!(self.as_num(&args[0])) {
+    Num::Int(n) => LispVal::number(n),
+    Num::Rat(q) => LispVal::number(q.numer().clone()),
+  },
+  Denominator: Exact(1) => match try1!(self.as_num(&args[0])) {
+    Num::Int(_) => LispVal::number(1),
+    Num::Rat(q) => LispVal::number(q.denom().clone()),
+  },
+  Floor: Exact(1) => LispVal::number(try1!(self.as_num(&args[0])).into_rat().floor().to_integer()),
+  Ceil: Exact(1) => LispVal::number(try1!(self.as_num(&args[0])).into_rat().ceil().to_integer()),
+  Round: Exact(1) => LispVal::number(try1!(self.as_num(&args[0])).into_rat().round().to_integer()),
   Shl: AtLeast(1) => {
     let mut it = args.into_iter();
-    let mut n: BigInt = try1!(self.as_int(&it.next().unwrap()));
+    let mut n = BigInt::from(try1!(self.as_int(&it.next().unwrap())));
     for e in it {
       try1!(self.with_int(&e, |e| {
-        if e.is_negative() {
-          let i: u64 = e.magnitude().try_into().map_err(|_| "shift out of range")?;
-          n >>= &i
-        } else {
-          let i: u64 = e.try_into().map_err(|_| "shift out of range")?;
-          n <<= &i
-        }
+        let i = e.to_i64().ok_or("shift out of range")?;
+        if i < 0 { n >>= i.unsigned_abs() } else { n <<= i.unsigned_abs() }
         Ok(())
       }))
     }
@@ -957,42 +1523,37 @@ make_builtins! { self, sp1, sp2, args,
   },
   Shr: AtLeast(1) => {
     let mut it = args.into_iter();
-    let mut n: BigInt = try1!(self.as_int(&it.next().unwrap()));
+    let mut n = BigInt::from(try1!(self.as_int(&it.next().unwrap())));
     for e in it {
       try1!(self.with_int(&e, |e| {
-        if e.is_negative() {
-          let i: u64 = e.magnitude().try_into().map_err(|_| "shift out of range")?;
-          n <<= &i
-        } else {
-          let i: u64 = e.try_into().map_err(|_| "shift out of range")?;
-          n >>= &i
-        }
+        let i = e.to_i64().ok_or("shift out of range")?;
+        if i < 0 { n <<= i.unsigned_abs() } else { n >>= i.unsigned_abs() }
         Ok(())
       }))
     }
     LispVal::number(n)
   },
   BAnd: AtLeast(0) => {
-    let mut n: BigInt = (-1).into();
-    for e in args { n &= try1!(self.as_int(&e)) }
+    let mut n = Int::from(-1);
+    for e in args { n = n.binop(try1!(self.as_int(&e)), |a, b| Some(a & b), |a, b| a & b) }
     LispVal::number(n)
   },
   BOr: AtLeast(0) => {
-    let mut n: BigInt = 0.into();
-    for e in args { n |= try1!(self.as_int(&e)) }
+    let mut n = Int::from(0);
+    for e in args { n = n.binop(try1!(self.as_int(&e)), |a, b| Some(a | b), |a, b| a | b) }
     LispVal::number(n)
   },
   BXor: AtLeast(0) => {
-    let mut n: BigInt = 0.into();
-    for e in args { n ^= try1!(self.as_int(&e)) }
+    let mut n = Int::from(0);
+    for e in args { n = n.binop(try1!(self.as_int(&e)), |a, b| Some(a ^ b), |a, b| a ^ b) }
     LispVal::number(n)
   },
   BNot: AtLeast(0) => {
     let n = if let [e] = &*args {
       try1!(self.as_int(e))
     } else {
-      let mut n: BigInt = (-1).into();
-      for e in args { n &= try1!(self.as_int(&e)) }
+      let mut n = Int::from(-1);
+      for e in args { n = n.binop(try1!(self.as_int(&e)), |a, b| Some(a & b), |a, b| a & b) }
       n
     };
     LispVal::number(!n)
@@ -1006,25 +1567,37 @@ make_builtins! { self, sp1, sp2, args,
     let s = try1!(self.as_string(&args[0]));
     LispVal::atom(self.get_atom(&s))
   },
+  Gensym: AtLeast(0) => {
+    if args.len() > 1 {try1!(Err("expected at most 1 argument"))}
+    let prefix = match args.first() {
+      None => ArcString::from(&b"g"[..]),
+      Some(e) => try1!(e.unwrapped(|e| match e {
+        LispKind::String(s) => Ok(s.clone()),
+        &LispKind::Atom(a) => Ok(self.data[a].name.clone()),
+        _ => Err(format!("expected a string or atom, got {}", self.print(e)))
+      }))
+    };
+    LispVal::atom(self.gensym(&prefix))
+  },
   StringAppend: AtLeast(0) => {
     let mut out = Vec::new();
     for e in args { out.extend_from_slice(&self.to_string(&e)) }
     LispVal::string(out.into())
   },
-  StringLen: Exact(1) => LispVal::number(try1!(self.as_string(&args[0])).len().into()),
+  StringLen: Exact(1) => LispVal::number(try1!(self.as_string(&args[0])).len()),
   StringNth: Exact(2) => {
     let i: usize = try1!(self.with_int(&args[0],
-      |n| n.try_into().map_err(|_| format!("index out of range: {}", n))));
+      |n| n.to_usize().ok_or_else(|| format!("index out of range: {}", n))));
     let s = try1!(self.as_string(&args[1]));
     let c = *try1!(s.get(i).ok_or_else(||
       format!("index out of range: index {}, length {}", i, s.len())));
-    LispVal::number(c.into())
+    LispVal::number(c)
   },
   Substr: Exact(3) => {
     let start: usize = try1!(self.with_int(&args[0],
-      |n| n.try_into().map_err(|_| format!("index out of range: start {}", n))));
+      |n| n.to_usize().ok_or_else(|| format!("index out of range: start {}", n))));
     let end: usize = try1!(self.with_int(&args[1],
-      |n| n.try_into().map_err(|_| format!("index out of range: end {}", n))));
+      |n| n.to_usize().ok_or_else(|| format!("index out of range: end {}", n))));
     if start > end { try1!(Err(format!("start {} > end {}", start, end))) }
     let s = try1!(self.as_string(&args[2]));
     if end > s.len() { try1!(Err(format!("index out of range: end {}, length {}", end, s.len()))) }
@@ -1033,7 +1606,7 @@ make_builtins! { self, sp1, sp2, args,
   StringToList: Exact(1) => {
     let s = try1!(self.as_string(&args[0]));
     LispVal::list(s.iter()
-      .map(|&c| LispVal::number(c.into()))
+      .map(|&c| LispVal::number(c))
       .collect::<Vec<_>>())
   },
   ListToString: Exact(1) => {
@@ -1041,13 +1614,86 @@ make_builtins! { self, sp1, sp2, args,
     let mut out: Vec<u8> = Vec::with_capacity(u.len());
     for e in &mut u {
       out.push(try1!(self.with_int(&e,
-        |n| n.try_into().map_err(|_| format!("character out of range: {}", n)))));
+        |n| n.to_u8().ok_or_else(|| format!("character out of range: {}", n)))));
     }
     if !u.is_empty() {
       try1!(Err(format!("list->string: not a list: {}", self.print(&args[0]))))
     }
     LispVal::string(out.into())
   },
+  StringSplit: Exact(2) => {
+    let sep = try1!(self.as_string(&args[0]));
+    let s = try1!(self.as_string(&args[1]));
+    if sep.is_empty() { try1!(Err("string-split: empty separator")) }
+    let (mut out, mut start, mut i) = (vec![], 0, 0);
+    while i + sep.len() <= s.len() {
+      if s[i..].starts_with(&sep) {
+        out.push(LispVal::string(ArcString::new(s[start..i].into())));
+        i += sep.len();
+        start = i;
+      } else { i += 1 }
+    }
+    out.push(LispVal::string(ArcString::new(s[start..].into())));
+    LispVal::list(out)
+  },
+  StringIndex: Exact(2) => {
+    let t = try1!(self.as_string(&args[0]));
+    let s = try1!(self.as_string(&args[1]));
+    let i = if t.is_empty() { Some(0) } else { s.windows(t.len()).position(|w| *w == *t) };
+    i.map_or_else(LispVal::undef, LispVal::number)
+  },
+  StringUpcase: Exact(1) =>
+    LispVal::string(try1!(self.as_string(&args[0])).to_ascii_uppercase().into()),
+  StringDowncase: Exact(1) =>
+    LispVal::string(try1!(self.as_string(&args[0])).to_ascii_lowercase().into()),
+  StringToNumber: AtLeast(1) => {
+    if args.len() > 2 {try1!(Err("expected at most 2 arguments"))}
+    let s = try1!(self.as_string(&args[0]));
+    let base = try1!(self.as_base(args.get(1)));
+    match s.iter().position(|&c| c == b'/') {
+      None => BigInt::parse_bytes(&s, base).map_or_else(LispVal::undef, LispVal::number),
+      Some(i) => match (BigInt::parse_bytes(&s[..i], base), BigInt::parse_bytes(&s[i+1..], base)) {
+        (Some(n), Some(d)) if !d.is_zero() => LispVal::rational(BigRational::new(n, d)),
+        _ => LispVal::undef(),
+      }
+    }
+  },
+  NumberToString: AtLeast(1) => {
+    if args.len() > 2 {try1!(Err("expected at most 2 arguments"))}
+    let n = try1!(self.as_num(&args[0]));
+    let base = try1!(self.as_base(args.get(1)));
+    LispVal::string(n.to_str_radix(base).into())
+  },
+  Format: AtLeast(1) => if args[0].is_port() {
+    if args.len() < 2 {try1!(Err("expected at least 2 arguments"))}
+    let fmt = try1!(self.as_string(&args[1]));
+    let s = try1!(self.format(&fmt, &args[2..]));
+    try1!(self.as_port(&args[0], |p| {p.borrow_mut().extend_from_slice(&s); Ok(())}));
+    LispVal::undef()
+  } else {
+    let fmt = try1!(self.as_string(&args[0]));
+    LispVal::string(try1!(self.format(&fmt, &args[1..])).into())
+  },
+  OpenOutputString: Exact(0) => LispVal::new(LispKind::StringPort(RefCell::new(vec![]))),
+  IsPort: Exact(1) => LispVal::bool(args[0].is_port()),
+  PortWrite: AtLeast(1) => {
+    let mut out = Vec::new();
+    for e in &args[1..] { out.extend_from_slice(&self.to_string(e)) }
+    try1!(self.as_port(&args[0], |p| {p.borrow_mut().extend_from_slice(&out); Ok(())}));
+    LispVal::undef()
+  },
+  GetOutputString: Exact(1) => LispVal::string(try1!(self.as_port(&args[0], |p| Ok(p.borrow().clone()))).into()),
+  ReadFile: Exact(1) => {
+    let path = try1!(try1!(self.io_path(&args[0], false)).ok_or("file does not exist"));
+    LispVal::string(try1!(std::fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))).into())
+  },
+  WriteFile: Exact(2) => {
+    let path = try1!(try1!(self.io_path(&args[0], true)).ok_or("directory does not exist"));
+    let s = try1!(self.as_string(&args[1]));
+    try1!(std::fs::write(&path, &*s).map_err(|e| format!("{}: {}", path.display(), e)));
+    LispVal::undef()
+  },
+  FileExists: Exact(1) => LispVal::bool(try1!(self.io_path(&args[0], false)).map_or(false, |p| p.is_file())),
   Not: AtLeast(0) => LispVal::bool(!args.iter().any(|e| e.truthy())),
   And: AtLeast(0) => LispVal::bool(args.iter().all(|e| e.truthy())),
   Or: AtLeast(0) => LispVal::bool(args.iter().any(|e| e.truthy())),
@@ -1079,7 +1725,8 @@ make_builtins! { self, sp1, sp2, args,
   IsAtom: Exact(1) => LispVal::bool(args[0].is_atom()),
   IsPair: Exact(1) => LispVal::bool(args[0].at_least(1)),
   IsNull: Exact(1) => LispVal::bool(args[0].exactly(0)),
-  IsNumber: Exact(1) => LispVal::bool(args[0].is_int()),
+  IsNumber: Exact(1) => LispVal::bool(args[0].is_number()),
+  IsInteger: Exact(1) => LispVal::bool(args[0].is_int()),
   IsString: Exact(1) => LispVal::bool(args[0].is_string()),
   IsProc: Exact(1) => LispVal::bool(args[0].is_proc()),
   IsDef: Exact(1) => LispVal::bool(args[0].is_def()),
@@ -1173,6 +1820,67 @@ make_builtins! { self, sp1, sp2, args,
     }).ok_or("expected a map")));
     LispVal::undef()
   },
+  IsHashTable: Exact(1) => LispVal::bool(args[0].is_hash_table()),
+  HashNew: AtLeast(0) => {
+    let mut m = HashMap::new();
+    for e in args {
+      let mut u = Uncons::from(e);
+      let k = try1!(u.next().ok_or("invalid arguments"));
+      let k = try1!(self.as_hash_key(&k));
+      let ret = u.next();
+      if !u.exactly(0) {try1!(Err("invalid arguments"))}
+      if let Some(v) = ret {m.insert(k, v);} else {m.remove(&k);}
+    }
+    LispVal::new_ref(LispVal::new(LispKind::HashTable(m)))
+  },
+  HashRef: AtLeast(2) => {
+    let k = try1!(self.as_hash_key(&args[1]));
+    let e = try1!(self.as_hash_table(&args[0], |m| Ok(m.get(&k).cloned())));
+    if let Some(e) = e {e} else {
+      let v = args.get(2).cloned().unwrap_or_else(LispVal::undef);
+      if v.is_proc() {
+        let sp = v.fspan().map_or(sp2, |fsp| fsp.span);
+        return Ok(State::App(sp1, sp, v, vec![], [].iter()))
+      }
+      v
+    }
+  },
+  HashSet: AtLeast(2) => {
+    if args.len() > 3 {try1!(Err("expected at most 3 arguments"))}
+    let k = try1!(self.as_hash_key(&args[1]));
+    let v = args.get(2).cloned();
+    try1!(args[0].as_ref_mut(|r| {
+      r.as_hash_table_mut(|m| match v {
+        Some(v) => {m.insert(k, v);}
+        None => {m.remove(&k);}
+      })
+    }).flatten().ok_or("expected a mutable hash table"));
+    LispVal::undef()
+  },
+  HashKeys: Exact(1) => LispVal::list(try1!(self.as_hash_table(&args[0],
+    |m| Ok(m.keys().map(HashKey::to_lisp).collect::<Vec<_>>())))),
+  IsVector: Exact(1) => LispVal::bool(args[0].is_vector()),
+  Vector: AtLeast(0) => LispVal::vector(args),
+  VectorRef: Exact(2) => try1!(self.as_vector(&args[0], |v| {
+    let v = v.borrow();
+    Ok(v[self.as_index(&args[1], v.len())?].clone())
+  })),
+  VectorSet: Exact(3) => {
+    try1!(self.as_vector(&args[0], |v| {
+      let mut v = v.borrow_mut();
+      let i = self.as_index(&args[1], v.len())?;
+      v[i] = args[2].clone();
+      Ok(())
+    }));
+    LispVal::undef()
+  },
+  VectorPush: Exact(2) => {
+    try1!(self.as_vector(&args[0], |v| {v.borrow_mut().push(args[1].clone()); Ok(())}));
+    LispVal::undef()
+  },
+  VectorLength: Exact(1) =>
+    LispVal::number(try1!(self.as_vector(&args[0], |v| Ok(v.borrow().len())))),
+  VectorToList: Exact(1) => LispVal::list(try1!(self.as_vector(&args[0], |v| Ok(v.borrow().clone())))),
   MergeMap: AtLeast(0) => {
     let mut it = args.drain(..);
     if let Some(arg1) = it.next() {
@@ -1186,7 +1894,7 @@ make_builtins! { self, sp1, sp2, args,
     } else {LispVal::proc(Proc::MergeMap(None))}
   },
   SetTimeout: Exact(1) => {
-    match try1!(args[0].as_int(BigInt::to_u64).ok_or("expected a number")) {
+    match try1!(args[0].as_int(Int::to_u64).ok_or("expected a number")) {
       None | Some(0) => {self.timeout = None; self.cur_timeout = None},
       Some(n) => {
         let d = Duration::from_millis(n);
@@ -1196,12 +1904,51 @@ make_builtins! { self, sp1, sp2, args,
     }
     LispVal::undef()
   },
+  ProfileStart: Exact(0) => {
+    self.lisp_profile = Some(Box::default());
+    LispVal::undef()
+  },
+  ProfileReport: AtLeast(0) => {
+    let time = match &*args {
+      [] => false,
+      [e] => match e.as_atom().map(|a| self.data[a].name.as_str()) {
+        Some("steps") => false,
+        Some("time") => true,
+        _ => try1!(Err("profile-report: expected 'steps or 'time")),
+      },
+      _ => try1!(Err("profile-report: expected 0 or 1 arguments")),
+    };
+    let prof = try1!(self.lisp_profile.take().ok_or("profiling not started (use profile-start)"));
+    LispVal::string(prof.report(self, time).into())
+  },
+  Spawn: Exact(1) => {
+    if !args[0].is_proc() { try1!(Err("spawn: expected a function")) }
+    let sp = self.respan(sp1);
+    LispVal::proc(Proc::Future(RefCell::new(try1!(self.spawn_future(sp, &args[0])))))
+  },
+  Await: Exact(1) => {
+    let sp = self.respan(sp1);
+    match &*args[0].unwrapped_arc() {
+      LispKind::Proc(Proc::Future(fut)) => self.await_future(sp, fut)?,
+      _ => try1!(Err("await: expected a future")),
+    }
+  },
   SetStackLimit: Exact(1) => {
-    self.stack_limit =
-      try1!(args[0].as_int(BigInt::to_usize).ok_or("expected a number"))
+    self.limits.stack =
+      try1!(args[0].as_int(Int::to_usize).ok_or("expected a number"))
         .unwrap_or(usize::MAX);
     LispVal::undef()
   },
+  SetStepLimit: Exact(1) => {
+    self.limits.steps = try1!(args[0].as_int(Int::to_u64).ok_or("expected a number"))
+      .filter(|&n| n != 0);
+    LispVal::undef()
+  },
+  SetAllocLimit: Exact(1) => {
+    self.limits.allocs = try1!(args[0].as_int(Int::to_usize).ok_or("expected a number"))
+      .filter(|&n| n != 0);
+    LispVal::undef()
+  },
   IsMVar: Exact(1) => LispVal::bool(args[0].is_mvar()),
   IsGoal: Exact(1) => LispVal::bool(args[0].is_goal()),
   NewMVar: AtLeast(0) => {
@@ -1236,6 +1983,12 @@ make_builtins! { self, sp1, sp2, args,
     self.lc.closer = e;
     LispVal::undef()
   },
+  Sorry: Exact(0) => {
+    let fsp = self.fspan(sp1);
+    let gs = mem::take(&mut self.lc.goals);
+    self.admit_goals(&fsp, gs);
+    LispVal::undef()
+  },
   LocalCtx: Exact(0) =>
     LispVal::list(self.lc.proof_order.iter().map(|a| LispVal::atom(a.0)).collect::<Vec<_>>()),
   ToExpr: Exact(1) => return Ok(State::Refine {
@@ -1274,6 +2027,192 @@ make_builtins! { self, sp1, sp2, args,
     let x = try1!(args[0].as_atom().ok_or("expected an atom"));
     self.get_decl(args[0].fspan(), x)
   },
+  GetProof: Exact(1) => {
+    let x = try1!(args[0].as_atom().ok_or("expected an atom"));
+    let t = match self.data[x].decl {
+      Some(DeclKey::Thm(t)) => t,
+      _ => try1!(Err(format!("get-proof: '{}' is not a theorem", self.data[x].name))),
+    };
+    if let Some(fsp) = args[0].fspan() {
+      self.spans.insert_if(fsp.span, || ObjectKind::Thm(t));
+    }
+    let mut heap = vec![];
+    self.binders(&self.thms[t].args, &mut heap, &mut vec![]);
+    self.get_proof(t, heap)
+  },
+  Decls: AtLeast(0) => {
+    const TERM: u8 = 1;
+    const DEF: u8 = 2;
+    const AXIOM: u8 = 4;
+    const THM: u8 = 8;
+    let mut mask = if args.is_empty() { TERM | DEF | AXIOM | THM } else { 0 };
+    for e in &args {
+      mask |= match e.unwrapped(|e| match *e {
+        LispKind::Atom(AtomId::TERM) => TERM,
+        // `'def` evaluates to the `def` syntax form rather than an atom
+        LispKind::Atom(AtomId::DEF) | LispKind::Syntax(Syntax::Define) => DEF,
+        LispKind::Atom(AtomId::AXIOM) => AXIOM,
+        LispKind::Atom(AtomId::THM) => THM,
+        _ => 0,
+      }) {
+        0 => try1!(Err("decls: expected 'term, 'def, 'axiom or 'theorem")),
+        k => k,
+      }
+    }
+    LispVal::list(self.stmts.iter().filter_map(|s| match *s {
+      StmtTrace::Decl(a) => Some(a),
+      _ => None,
+    }).filter(|&a| mask & match self.data[a].decl {
+      Some(DeclKey::Term(t)) => match self.terms[t].kind {
+        TermKind::Term => TERM,
+        TermKind::Def(_) => DEF,
+      },
+      Some(DeclKey::Thm(t)) => match self.thms[t].kind {
+        ThmKind::Axiom => AXIOM,
+        ThmKind::Thm(_) => THM,
+      },
+      None => 0,
+    } != 0).map(LispVal::atom).collect::<Vec<_>>())
+  },
+  Search: AtLeast(1) => {
+    if args.len() > 3 { try1!(Err("search: expected at most 3 arguments")) }
+    let depth = match args.get(1) {
+      Some(e) => try1!(e.as_int(Int::to_u32).flatten().ok_or("search: expected a number")),
+      None => 0,
+    };
+    let hyps = args.get(2).map_or(false, |e| e.truthy());
+    let thms = try1!(self.search(&args[0], depth, hyps));
+    LispVal::list(thms.into_iter().map(|t| LispVal::atom(self.thms[t].atom)).collect::<Vec<_>>())
+  },
+  CongruenceClosure: Exact(4) => {
+    let mut eqs = vec![];
+    let mut it = Uncons::New(args[0].clone());
+    for e in &mut it {
+      let mut eq = Uncons::from(e.clone());
+      match (eq.next(), eq.next(), eq.next()) {
+        (Some(p), Some(x), Some(y)) if eq.exactly(0) => eqs.push((p, x, y)),
+        _ => try1!(Err(format!("congruence-closure: expected (p x y), got {}", self.print(&e)))),
+      }
+    }
+    if !it.is_empty() { try1!(Err("congruence-closure: expected a list of equations")) }
+    let names = crate::elab::cc::CALLBACKS.map(|s| self.get_atom(s.as_bytes()));
+    let fns = try1!(self.as_map(&args[3], |m| {
+      let get = |a: AtomId| m.get(&a).cloned().ok_or_else(||
+        format!("congruence-closure: missing '{} function", self.data[a].name));
+      Ok([get(names[0])?, get(names[1])?, get(names[2])?, get(names[3])?])
+    }));
+    let sp = self.respan(sp1);
+    self.congruence_closure(sp, &eqs, &args[1], &args[2], &fns)?
+  },
+  Simp: AtLeast(2) => {
+    let names = crate::elab::cc::CALLBACKS.map(|s| self.get_atom(s.as_bytes()));
+    let (rw, discharge) = (self.get_atom(b"rw"), self.get_atom(b"discharge"));
+    let cbs = try1!(self.as_map(&args[1], |m| {
+      let get = |a: AtomId| m.get(&a).cloned().ok_or_else(||
+        format!("simp: missing '{} function", self.data[a].name));
+      Ok(crate::elab::simp::SimpCallbacks {
+        fns: [get(names[0])?, get(names[1])?, get(names[2])?, get(names[3])?],
+        rw: m.get(&rw).cloned(),
+        discharge: m.get(&discharge).cloned(),
+      })
+    }));
+    let mut opts = crate::elab::simp::SimpOptions::default();
+    let mut thms = self.env.simps.clone();
+    if let Some(e) = args.get(2) {
+      let keys = ["rtl", "depth", "only", "add"].map(|s| self.get_atom(s.as_bytes()));
+      let vals = try1!(self.as_map(e, |m| Ok(keys.map(|k| m.get(&k).cloned()))));
+      let [rtl, depth, only, add] = vals;
+      if let Some(e) = rtl { opts.rev = e.truthy() }
+      if let Some(e) = depth {
+        opts.depth = try1!(e.as_int(ToPrimitive::to_usize).flatten()
+          .ok_or_else(|| format!("simp: expected a depth, got {}", self.print(&e))))
+      }
+      let get_thms = |e: &LispVal| -> SResult<Vec<ThmId>> {
+        let mut res = vec![];
+        for x in Uncons::from(e.clone()) {
+          match x.as_atom().and_then(|a| self.data[a].decl) {
+            Some(DeclKey::Thm(t)) => res.push(t),
+            _ => return Err(format!("simp: expected a theorem, got {}", self.print(&x))),
+          }
+        }
+        Ok(res)
+      };
+      if let Some(e) = only { thms = try1!(get_thms(&e)) }
+      if let Some(e) = add { thms.extend(try1!(get_thms(&e))) }
+    }
+    let sp = self.respan(sp1);
+    self.simp(sp, &args[0], &thms, opts, cbs)?
+  },
+  Lia: Exact(3) => {
+    let mut hyps = vec![];
+    let mut it = Uncons::New(args[0].clone());
+    for e in &mut it {
+      let mut h = Uncons::from(e.clone());
+      match (h.next(), h.next()) {
+        (Some(p), Some(t)) if h.exactly(0) => hyps.push((p, t)),
+        _ => try1!(Err(format!("lia: expected (p e), got {}", self.print(&e)))),
+      }
+    }
+    if !it.is_empty() { try1!(Err("lia: expected a list of hypotheses")) }
+    let m = try1!(self.as_map(&args[2], |m| Ok(m.clone())));
+    let table = try1!(crate::elab::lia::LiaTable::new(self, &m));
+    let sp = self.respan(sp1);
+    let goal = if args[1].is_def() { Some(&args[1]) } else { None };
+    self.lia(sp, &hyps, goal, &table)?
+  },
+  SatTauto: Exact(2) => {
+    let m = try1!(self.as_map(&args[1], |m| Ok(m.clone())));
+    let table = try1!(crate::elab::sat::SatTable::new(self, &m));
+    let sp = self.respan(sp1);
+    self.sat_tauto(sp, &args[0], &table)?
+  },
+  Smt: Exact(3) => {
+    let mut hyps = vec![];
+    let mut it = Uncons::New(args[0].clone());
+    for e in &mut it {
+      let mut h = Uncons::from(e.clone());
+      match (h.next(), h.next()) {
+        (Some(p), Some(t)) if h.exactly(0) => hyps.push((p, t)),
+        _ => try1!(Err(format!("smt: expected (p e), got {}", self.print(&e)))),
+      }
+    }
+    if !it.is_empty() { try1!(Err("smt: expected a list of hypotheses")) }
+    let m = try1!(self.as_map(&args[2], |m| Ok(m.clone())));
+    let table = try1!(crate::elab::smt::SmtTable::new(self, &m));
+    let sp = self.respan(sp1);
+    let goal = if args[1].is_def() { Some(&args[1]) } else { None };
+    self.smt(sp, &hyps, goal, &table)?
+  },
+  Auto: Exact(1) => {
+    let depth = try1!(args[0].as_int(Int::to_u32).flatten().ok_or("auto: expected a number"));
+    let goal = try1!(self.lc.goals.iter().find_map(|g| g.goal_type()).ok_or("auto: no goals"));
+    let p = try1!(try1!(self.auto(&goal, depth)).ok_or("auto: no proof found"));
+    return Ok(State::Refine {
+      sp: sp1, stack: vec![],
+      state: RState::Goals {
+        gs: mem::take(&mut self.lc.goals).into_iter(),
+        es: vec![p].into_iter()
+      }
+    })
+  },
+  Instance: Exact(1) => try1!(self.resolve_instance(&args[0])),
+  AddInstance: AtLeast(1) => {
+    if args.len() > 2 { try1!(Err("add-instance!: expected at most 2 arguments")) }
+    let x = try1!(args[0].as_atom().ok_or("add-instance!: expected an atom"));
+    let prio = match args.get(1) {
+      Some(e) => try1!(e.as_int(Int::to_u32).flatten().ok_or("add-instance!: expected a number")),
+      None => crate::elab::instance::DEFAULT_PRIORITY,
+    };
+    try1!(self.add_instance(x, prio).map_err(|e| format!("add-instance!: {}", e)));
+    LispVal::undef()
+  },
+  AddSimp: AtLeast(0) => {
+    for e in args {
+      let x = try1!(e.as_atom().ok_or("add-simp!: expected an atom"));
+      try1!(self.add_simp(x).map_err(|e| format!("add-simp!: {}", e)))
+    }
+    LispVal::undef()
+  },
   AddDecl: AtLeast(4) => {
     let fsp = self.fspan_base(sp1);
     match try1!(args[0].as_atom().ok_or("expected an atom")) {
@@ -1315,11 +2254,35 @@ make_builtins! { self, sp1, sp2, args,
     try1!(set_report_mode(fe, &mut self.elab.reporting, &args));
     LispVal::undef()
   },
+  SetWarning: AtLeast(1) => {
+    let (code, lvl) = match &*args {
+      [lvl] => (None, lvl),
+      [code, lvl] => {
+        let code = try1!(code.as_atom().ok_or("expected an atom"));
+        (Some(try1!(WarningCode::from_name(self.data[code].name.as_str()).ok_or_else(||
+          format!("unknown warning code '{}'", self.data[code].name)))), lvl)
+      }
+      _ => try1!(Err("expected one or two arguments")),
+    };
+    let lvl = try1!(lvl.as_atom().and_then(|a| WarningLevel::from_name(self.data[a].name.as_str()))
+      .ok_or("expected 'allow, 'warn or 'deny"));
+    self.elab.warnings.set(code, lvl);
+    LispVal::undef()
+  },
   SetBacktrace: AtLeast(1) => {
     let fe = FormatEnv {source: &self.elab.ast.source, env: &self.elab.env};
     try1!(set_report_mode(fe, &mut self.elab.backtrace, &args));
     LispVal::undef()
   },
+  SetRenderTemplate: Exact(3) => {
+    let fmt = try1!(args[0].as_atom().and_then(|a| RenderFormat::from_name(self.data[a].name.as_str()))
+      .ok_or("expected 'latex or 'html"));
+    let t = try1!(args[1].as_atom().and_then(|a| self.term(a)).ok_or("expected a term"));
+    let tpl = try1!(self.as_string(&args[2]));
+    try1!(check_template(tpl.as_str(), self.terms[t].args.len()));
+    self.pe.templates.insert((t, fmt), tpl);
+    LispVal::undef()
+  },
   CheckProofs: Exact(1) => {
     if let Some(b) = args[0].as_bool() {
       self.check_proofs = b;
@@ -1364,6 +2327,122 @@ impl<'a> Evaluator<'a> {
 
   #[allow(clippy::never_loop)]
   fn run(&mut self, mut active: State<'a>) -> Result<LispVal> {
+    let res = loop {
+      match self.run_inner(active) {
+        Ok(ret) => break Ok(ret),
+        Err(err) => match self.catch(err) {
+          Ok(st) => active = st,
+          Err(err) => break Err(err),
+        }
+      }
+    };
+    if let Some(valid) = &self.conts_valid { valid.set(false) }
+    if let Some(prof) = &mut self.elab.lisp_profile { prof.stop() }
+    res
+  }
+
+  /// Returns the error message if evaluation has to stop because of a timeout,
+  /// cancellation, or exceeded step or allocation limit.
+  fn over_limit(&self) -> Option<&'static str> {
+    if self.cur_timeout.map_or(false, |t| t < Instant::now()) { return Some("timeout") }
+    if self.cancel.is_canceled() { return Some("cancelled") }
+    if self.limits.steps.map_or(false, |n| self.steps > n) { return Some("step limit exceeded") }
+    if self.limits.allocs.map_or(false, |n| super::alloc_count().wrapping_sub(self.allocs) > n) {
+      return Some("allocation limit exceeded")
+    }
+    None
+  }
+
+  /// Handle an error thrown during evaluation. If there is an enclosing `(try e handler)`,
+  /// the stack is unwound to it and we continue by calling `handler` on the error object.
+  /// Errors caused by [`over_limit`](Self::over_limit) cannot be caught, and side effects
+  /// of the failed evaluation, such as changes to refs or to the goals, are not undone.
+  fn catch(&mut self, err: ElabError) -> Result<State<'a>> {
+    let data = self.thrown.take();
+    if self.over_limit().is_some() || !self.stack.iter().any(|s| matches!(s, Stack::Try(..))) {
+      return Err(err)
+    }
+    let i = self.stack.iter().rposition(|s| matches!(s, Stack::Try(..))).expect("impossible");
+    self.unwind(i + 1);
+    let_unchecked!((sp, handler) as Some(Stack::Try(sp, handler)) = self.stack.pop());
+    let span = FileSpan {file: self.path.clone(), span: err.pos};
+    let mut m = HashMap::new();
+    m.insert(self.get_atom(b"message"), LispVal::string(err.kind.raw_msg().into()));
+    m.insert(self.get_atom(b"span"), LispVal::undef().span(span));
+    if let Some(data) = data { m.insert(self.get_atom(b"data"), data); }
+    self.stack.push(Stack::AppHead(sp, sp, LispVal::new(LispKind::AtomMap(m))));
+    Ok(State::Eval(handler))
+  }
+
+  /// Pop the stack frames above index `n`, restoring the context to what it was when
+  /// frame `n - 1` was pushed.
+  fn unwind(&mut self, n: usize) {
+    for s in self.stack.drain(n..).rev() {
+      match s {
+        Stack::Drop(n) => self.ctx.truncate(n),
+        Stack::Ret(fsp, _, old, ..) => {self.file = fsp.file; self.ctx = old}
+        Stack::Restore(file, old) => {self.file = file; self.ctx = old}
+        Stack::MatchCont(_, _, _, valid) => valid.set(false),
+        _ => {}
+      }
+    }
+  }
+
+  /// Capture the continuation up to the nearest enclosing `reset`, for `(shift k e)` at `sp`.
+  /// This removes the captured frames from the stack, and pushes a frame to restore the
+  /// context of the `reset`, so that `e` can be evaluated in the current context.
+  fn shift(&mut self, sp: Span) -> Result<LispVal> {
+    let i = match self.stack.iter().rposition(|s| matches!(s, Stack::Reset)) {
+      Some(i) => i,
+      None => return Err(self.err(Some((sp, false)), "shift: no enclosing reset")),
+    };
+    let frames = match self.stack[i+1..].iter().map(Stack::try_clone).collect() {
+      Some(frames) => frames,
+      None => return Err(self.err(Some((sp, false)),
+        "shift: cannot capture a continuation inside refine or a match clause with (=> k)")),
+    };
+    let code = self.stack[..i].iter().rev().find_map(|s|
+      if let Stack::Ret(_, _, _, code, _) = s {Some(code.clone())} else {None});
+    let (ctx, file) = (self.ctx.clone(), self.file.clone());
+    self.unwind(i + 1);
+    let old = Stack::Restore(mem::replace(&mut self.file, file.clone()),
+      mem::replace(&mut self.ctx, ctx.clone()));
+    self.stack.push(old);
+    let valid = self.conts_valid.get_or_insert_with(|| Rc::new(Cell::new(true))).clone();
+    self.conts.push(Continuation {frames, ctx, file, _code: code});
+    Ok(LispVal::proc(Proc::DelimCont(valid, self.conts.len() - 1)))
+  }
+
+  /// Returns true if the continuation `k` of a match clause `[pat (=> k) expr]` is only
+  /// reachable through the variable `k` in the current context. In this case a tail call
+  /// in `expr` can drop the match frame, because `k` can no longer be called afterwards.
+  fn match_cont_local(&self, valid: &Rc<Cell<bool>>) -> bool {
+    Rc::strong_count(valid) == 2 && self.ctx.iter().rev().any(|e| e.is_unique() &&
+      matches!(**e, LispKind::Proc(Proc::MatchCont(ref v)) if Rc::ptr_eq(v, valid)))
+  }
+
+  /// Removes the argument described by `kind` from the list of unconsumed arguments
+  /// in variable `n`, for an [`Ir::Arg`] instruction.
+  fn take_arg(&mut self, n: usize, kind: ArgKind) -> Option<LispVal> {
+    let mut args = Uncons::from(self.ctx[n].clone()).collect::<Vec<_>>();
+    let i = match kind {
+      ArgKind::Pos(keys) => match args.first()?.as_atom() {
+        Some(a) if keys && matches!(*self.data[a].name, [b':', _, ..]) => return None,
+        _ => 0,
+      },
+      ArgKind::Key(k) => {
+        let i = args.iter().step_by(2).position(|e| e.as_atom() == Some(k))? * 2;
+        if i + 1 == args.len() { return None }
+        args.remove(i);
+        i
+      }
+    };
+    let v = args.remove(i);
+    self.ctx[n] = LispVal::list(args);
+    Some(v)
+  }
+
+  fn run_inner(&mut self, mut active: State<'a>) -> Result<LispVal> {
     macro_rules! throw {($sp:expr, $e:expr) => {{
       let err = $e;
       return Err(self.err(Some(($sp, false)), err))
@@ -1378,14 +2457,12 @@ impl<'a> Evaluator<'a> {
     loop {
       iters = iters.wrapping_add(1);
       if iters == 0 {
-        if self.cur_timeout.map_or(false, |t| t < Instant::now()) {
-          return Err(self.err(None, "timeout"))
-        }
-        if self.cancel.load(Ordering::Relaxed) {
-          return Err(self.err(None, "cancelled"))
+        self.steps += 256;
+        if let Some(msg) = self.over_limit() {
+          return Err(self.err(None, msg))
         }
       }
-      if self.stack.len() >= self.stack_limit {
+      if self.stack.len() >= self.limits.stack {
         return Err(self.err(None, "stack overflow"))
       }
       // if self.check_proofs {
@@ -1403,6 +2480,13 @@ impl<'a> Evaluator<'a> {
       //   }
       //   println!("[{}] {}\n", self.ctx.len(), self.print(&active));
       // }
+      if let Some(prof) = &mut self.elab.lisp_profile {
+        prof.step(self.stack.iter().filter_map(|s|
+          if let Stack::Ret(_, pos, ..) = s {Some(pos)} else {None}))
+      }
+      if self.elab.debugger.is_some() {
+        if let State::Eval(ir) = active { self.debug_step(ir) }
+      }
       active = match active {
         State::Eval(ir) => match ir {
           &Ir::Local(i) => State::Ret(self.ctx[i].clone()),
@@ -1432,8 +2516,17 @@ impl<'a> Evaluator<'a> {
           &Ir::Focus(sp, ref irs) => {
             if self.lc.goals.is_empty() {throw!(sp, "no goals")}
             let gs = self.lc.goals.drain(1..).collect();
+            self.goal_state_probe(sp, irs.len());
             push!(Focus(sp, true, gs); Refines(sp, irs.iter()))
           }
+          &Ir::Try(sp, ref es) => push!(Try(sp, &es.1); Eval(&es.0)),
+          Ir::Reset(_, e) => push!(Reset; Eval(e)),
+          &Ir::Shift(sp, n, ref e) => {
+            let k = self.shift(sp)?;
+            assert!(self.ctx.len() == n);
+            self.ctx.push(k);
+            State::Eval(e)
+          }
           &Ir::SetMergeStrategy(sp, a, ref ir) => push!(SetMergeStrategy(sp, a); Eval(ir)),
           &Ir::Def(n, ref x, ref val) => {
             assert!(self.ctx.len() == n);
@@ -1450,15 +2543,25 @@ impl<'a> Evaluator<'a> {
               }
             }
           }
-          &Ir::Lambda(sp, n, spec, ref e) => {
+          &Ir::Lambda(sp, n, spec, ref doc, ref e) => {
             assert!(self.ctx.len() == n);
             State::Ret(LispVal::proc(Proc::Lambda {
               pos: self.proc_pos(sp),
               env: self.ctx.clone().into(),
               spec,
+              doc: doc.clone(),
               code: e.clone()
             }))
           }
+          &Ir::Arg(_, n, kind, ref e) => match self.take_arg(n, kind) {
+            Some(v) => State::Ret(v),
+            None => State::Eval(e),
+          },
+          &Ir::ArgsEnd(sp, n) => {
+            if self.ctx[n].exactly(0) { State::Ret(LispVal::undef()) } else {
+              throw!(sp, format!("unexpected argument(s) {}", self.print(&self.ctx[n])))
+            }
+          }
           &Ir::Match(sp, ref e, ref brs) => push!(Match(sp, brs.iter()); Eval(e)),
         },
         State::Ret(ret) => match self.stack.pop() {
@@ -1496,6 +2599,7 @@ impl<'a> Evaluator<'a> {
               _ => {self.stack.push(s); State::Ret(LispVal::undef())}
             }
           } else if let Some(&Some((sp1, sp2, ref doc, a))) = x {
+            self.env_changed();
             let loc = (self.fspan(sp2), sp1);
             let lisp = &mut self.data[a].lisp;
             if let Some(LispData {merge: strat @ Some(_), val, ..}) = lisp {
@@ -1516,6 +2620,7 @@ impl<'a> Evaluator<'a> {
             }
           } else { State::Ret(LispVal::undef()) },
           Some(Stack::DefMerge(loc1, a, doc)) => {
+            self.env_changed();
             match (&mut self.data[a].lisp, ret.is_def_strict()) {
               (l @ None, true) =>
                 *l = Some(LispData {src: Some(loc1), doc, val: ret, merge: None}),
@@ -1531,12 +2636,15 @@ impl<'a> Evaluator<'a> {
           Some(Stack::TestPattern(sp, e, it, br, pstack, vars)) =>
             State::Pattern(sp, e, it, br, pstack, vars, PatternState::Ret(ret.truthy())),
           Some(Stack::Drop(n)) => {self.ctx.truncate(n); State::Ret(ret)}
-          Some(Stack::Ret(fsp, _, old, _)) => {self.file = fsp.file; self.ctx = old; State::Ret(ret)}
+          Some(Stack::Try(..) | Stack::Reset) => State::Ret(ret),
+          Some(Stack::Restore(file, old)) => {self.file = file; self.ctx = old; State::Ret(ret)}
+          Some(Stack::Ret(fsp, _, old, ..)) => {self.file = fsp.file; self.ctx = old; State::Ret(ret)}
           Some(Stack::MatchCont(_, _, _, valid)) => {
             if let Err(valid) = Rc::try_unwrap(valid) {valid.set(false)}
             State::Ret(ret)
           }
           Some(Stack::SetMergeStrategy(sp1, a)) => {
+            self.env_changed();
             if let Some(ref mut data) = self.data[a].lisp {
               data.merge = ret.into_merge_strategy()
             } else {
@@ -1556,12 +2664,14 @@ impl<'a> Evaluator<'a> {
             ap.finish(self, &fsp, ret)?;
             State::Ret(LispVal::undef())
           }
-          Some(Stack::Refines(sp, Some(_), it)) if !ret.is_def() => State::Refines(sp, it),
-          Some(Stack::Refines(sp, Some(esp), it)) => {
+          Some(Stack::Refines(sp, Some(esp), it)) if ret.is_def() => {
             self.stack.push(Stack::Refines(sp, None, it));
             self.evaluate_builtin(esp, esp, BuiltinProc::Refine, vec![ret])?
           }
-          Some(Stack::Refines(sp, None, it)) => State::Refines(sp, it),
+          Some(Stack::Refines(sp, _, it)) => {
+            self.goal_state_probe(sp, it.len());
+            State::Refines(sp, it)
+          }
           Some(Stack::Focus(sp, close, gs)) => loop { // labeled block, not a loop. See rust#48594
             if close {
               if self.lc.closer.is_def() {
@@ -1619,13 +2729,17 @@ impl<'a> Evaluator<'a> {
               }
             }
             Ok(match func {
-              &Proc::Builtin(func) => self.evaluate_builtin(sp1, sp2, func, args)?,
+              &Proc::Builtin(func) => {
+                if func.is_mutating() { self.env_changed() }
+                self.evaluate_builtin(sp1, sp2, func, args)?
+              }
               Proc::Lambda {pos, env, code, ..} => {
                 let tail_call = (|| {
                   for (i, s) in self.stack.iter().enumerate().rev() {
                     match s {
                       Stack::Ret(..) => return Some(i),
                       Stack::Drop(_) => {}
+                      Stack::MatchCont(_, _, _, valid) if self.match_cont_local(valid) => {}
                       _ => break
                     }
                   }
@@ -1633,12 +2747,17 @@ impl<'a> Evaluator<'a> {
                 })();
                 if let Some(i) = tail_call { // tail call
                   let s = self.stack.drain(i..).next();
-                  let_unchecked!((fsp, old) as Some(Stack::Ret(fsp, _, old, _)) = s);
+                  let_unchecked!((fsp, old_pos, old, tail) as
+                    Some(Stack::Ret(fsp, old_pos, old, _, tail)) = s);
                   self.ctx = (**env).into();
-                  self.stack.push(Stack::Ret(fsp, pos.clone(), old, code.clone()));
+                  let tail = match tail {
+                    Some(mut tail) => { tail.count += 1; tail }
+                    None => Box::new(TailCalls {caller: old_pos, count: 1}),
+                  };
+                  self.stack.push(Stack::Ret(fsp, pos.clone(), old, code.clone(), Some(tail)));
                 } else {
                   self.stack.push(Stack::Ret(self.fspan(sp1), pos.clone(),
-                    mem::replace(&mut self.ctx, (**env).into()), code.clone()));
+                    mem::replace(&mut self.ctx, (**env).into()), code.clone(), None));
                 }
                 self.file = pos.fspan().file.clone();
                 self.stack.push(Stack::Drop(self.ctx.len()));
@@ -1660,6 +2779,19 @@ impl<'a> Evaluator<'a> {
                 let code: *const Ir = &**code;
                 State::Eval(unsafe { &*code })
               },
+              &Proc::DelimCont(ref valid, i) => {
+                if !valid.get() || !self.conts_valid.as_ref().map_or(false, |v| Rc::ptr_eq(v, valid)) {
+                  throw!(sp2, "continuation has expired")
+                }
+                let Continuation {frames, ctx, file, ..} = &self.conts[i];
+                let frames = frames.iter().map(|s| s.try_clone().expect("impossible")).collect::<Vec<_>>();
+                let old = Stack::Restore(mem::replace(&mut self.file, file.clone()),
+                  mem::replace(&mut self.ctx, ctx.clone()));
+                self.stack.push(old);
+                self.stack.push(Stack::Reset);
+                self.stack.extend(frames);
+                State::Ret(args.pop().expect("impossible"))
+              }
               Proc::MatchCont(valid) => {
                 if !valid.get() {throw!(sp2, "continuation has expired")}
                 loop {
@@ -1671,12 +2803,14 @@ impl<'a> Evaluator<'a> {
                       }
                     }
                     Some(Stack::Drop(n)) => {self.ctx.truncate(n);}
-                    Some(Stack::Ret(fsp, _, old, _)) => {self.file = fsp.file; self.ctx = old},
+                    Some(Stack::Ret(fsp, _, old, ..)) => {self.file = fsp.file; self.ctx = old},
+                    Some(Stack::Restore(file, old)) => {self.file = file; self.ctx = old},
                     Some(_) => {}
                     None => throw!(sp2, "continuation has expired")
                   }
                 }
               }
+              Proc::Macro(f) => State::App(sp1, sp2, f.clone(), args, [].iter()),
               Proc::MergeMap(strat) => {
                 let new = args.pop().expect("impossible");
                 let old = args.pop().expect("impossible");
@@ -1711,6 +2845,10 @@ impl<'a> Evaluator<'a> {
                   } else {unreachable!()}
                 }
               }
+              Proc::Future(fut) => {
+                let sp = self.respan(sp1);
+                State::Ret(self.await_future(sp, fut)?)
+              }
               #[cfg(feature = "mmc")]
               Proc::MmcCompiler(c) => {
                 let sp = self.respan(sp1);
@@ -1727,11 +2865,16 @@ impl<'a> Evaluator<'a> {
         },
         State::Pattern(sp, e, it, br, mut pstack, mut vars, st) => {
           match pattern_match(&mut pstack, &mut vars, st) {
-            Err(TestPending(sp2, e2, ir)) => push!(
+            Err(TestPending::Test(sp2, e2, ir)) => push!(
               TestPattern(sp, e, it, br, pstack, vars),
               AppHead(sp2, sp2, e2),
               Drop(self.ctx.len());
               Eval(ir)),
+            Err(TestPending::Guard(ir)) => {
+              let start = self.ctx.len();
+              self.ctx.extend_from_slice(&vars);
+              push!(TestPattern(sp, e, it, br, pstack, vars), Drop(start); Eval(ir))
+            }
             Ok(false) => State::Match(sp, e, it),
             Ok(true) => {
               let start = self.ctx.len();
@@ -0,0 +1,179 @@
+//! Background evaluation of lisp functions, using `(spawn)` and `(await)`.
+//!
+//! Lisp values use non-atomic reference counting, so they cannot be shared between threads.
+//! Instead, `(spawn f)` copies the environment and `f` using the serialization format of the
+//! [`cache`](crate::cache), and calls `f` in a new [`Elaborator`] on another thread. The
+//! result is copied back the same way when the future is awaited, so the result does not
+//! depend on when the task finishes.
+//!
+//! The copy of the environment is shared by all the tasks spawned until the environment may
+//! have changed (see [`Elaborator::env_changed`]), so spawning many tasks in a loop only
+//! copies it once. The tasks run on a pool with one thread per CPU. A task that is awaited
+//! before a thread has picked it up is run on the awaiting thread instead, so tasks that
+//! spawn and await other tasks cannot exhaust the pool.
+
+use std::cell::RefCell;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use futures::executor::ThreadPool;
+use crate::{cache, ElabError, Elaborator, LispVal, MutexExt, Span};
+use super::LispArena;
+
+lazy_static! {
+  /// The pool of threads which run the tasks started by `(spawn)`. This is separate from the
+  /// elaboration pool, since elaboration blocks while awaiting a task.
+  static ref POOL: ThreadPool = ThreadPool::builder().name_prefix("mm0-spawn-").create()
+    .expect("could not start thread pool");
+}
+
+/// The result of a task: the copied return value, or the error it raised,
+/// along with the messages it reported.
+type TaskResult = (Result<Vec<u8>, ElabError>, Vec<ElabError>);
+
+/// The work of a task which has not started yet.
+type Job = Box<dyn FnOnce() -> TaskResult + Send>;
+
+/// The state of a task started by `(spawn f)`, shared with the thread running it.
+enum TaskState {
+  /// The task is waiting for a thread.
+  Queued(Job),
+  /// The task is running.
+  Running,
+  /// The task has finished, or panicked.
+  Finished(std::thread::Result<TaskResult>),
+}
+
+/// A task started by `(spawn f)`, shared with the thread running it.
+struct TaskInner {
+  /// The state of the task.
+  state: Mutex<TaskState>,
+  /// Signaled when the task finishes.
+  done: Condvar,
+}
+
+/// A handle to a task started by `(spawn f)`.
+#[derive(Clone)]
+pub struct Task(Arc<TaskInner>);
+
+impl std::fmt::Debug for Task {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { f.write_str("Task") }
+}
+
+impl Task {
+  /// Run the task on the current thread, if it has not started yet.
+  fn run(&self) {
+    let job = {
+      let mut g = self.0.state.ulock();
+      match std::mem::replace(&mut *g, TaskState::Running) {
+        TaskState::Queued(job) => job,
+        s => { *g = s; return }
+      }
+    };
+    // The task installs its own lisp arena, which replaces that of an awaiting elaborator.
+    let arena = LispArena::thread_local();
+    let r = catch_unwind(AssertUnwindSafe(job));
+    LispArena::set_thread_local(arena);
+    *self.0.state.ulock() = TaskState::Finished(r);
+    self.0.done.notify_all();
+  }
+
+  /// Wait for the task to finish, running it here if no thread has picked it up yet,
+  /// and take its result.
+  fn join(&self) -> std::thread::Result<TaskResult> {
+    self.run();
+    let mut g = self.0.state.ulock();
+    loop {
+      match std::mem::replace(&mut *g, TaskState::Running) {
+        TaskState::Finished(r) => return r,
+        s => *g = s,
+      }
+      g = self.0.done.wait(g).unwrap_or_else(PoisonError::into_inner);
+    }
+  }
+}
+
+/// The state of a task started by `(spawn f)`.
+#[derive(Debug)]
+pub enum LispFuture {
+  /// The task is running, or has finished but has not been awaited yet.
+  Running(Task),
+  /// The task has been awaited, and this is the result of `(f)`.
+  Done(Result<LispVal, ElabError>),
+}
+
+#[cfg(feature = "memory")]
+impl mm0_deepsize::DeepSizeOf for LispFuture {
+  fn deep_size_of_children(&self, context: &mut mm0_deepsize::Context) -> usize {
+    match self {
+      LispFuture::Running(_) => 0,
+      LispFuture::Done(Ok(e)) => e.deep_size_of_children(context),
+      LispFuture::Done(Err(e)) => e.deep_size_of_children(context),
+    }
+  }
+}
+
+impl Elaborator {
+  /// Start evaluating `(f)` on the thread pool, using a copy of the current environment.
+  /// `sp` is the span of the `(spawn)` call.
+  pub(crate) fn spawn_future(&mut self, sp: Span, f: &LispVal) -> Result<LispFuture, String> {
+    const NO_COPY: &str = "spawn: the function or the environment contain values that cannot be copied";
+    let base = self.data.len();
+    let env = match &self.spawn_env {
+      Some((n, env)) if *n == base => env.clone(),
+      _ => {
+        let env: Arc<[u8]> = cache::encode_env(&self.env).ok_or(NO_COPY)?.into();
+        self.spawn_env = Some((base, env.clone()));
+        env
+      }
+    };
+    let f = cache::encode_task(f).ok_or(NO_COPY)?;
+    let (ast, path, cancel) = (self.ast.clone(), self.path.clone(), self.cancel.clone());
+    let (mm0_mode, check_proofs) = (self.mm0_mode, self.check_proofs);
+    let (timeout, limits, reporting) = (self.timeout, self.limits, self.reporting);
+    let job: Job = Box::new(move || {
+      let mut elab = Elaborator::new(ast, path, mm0_mode, check_proofs, cancel, None);
+      elab.arena.install_thread_local();
+      let (env, f) = cache::decode_task(&env, &f).expect("failed to read spawned task");
+      elab.env = env;
+      elab.timeout = timeout;
+      elab.limits = limits;
+      elab.reporting = reporting;
+      elab.start_stmt(sp);
+      let r = elab.call_func(sp, f, vec![]).and_then(|e|
+        cache::encode_result(&elab.env, base, &e).ok_or_else(||
+          ElabError::new_e(sp, "spawn: the result contains values that cannot be copied")));
+      elab.arena.collect();
+      LispArena::uninstall_thread_local();
+      (r, elab.errors)
+    });
+    let task = Task(Arc::new(TaskInner {
+      state: Mutex::new(TaskState::Queued(job)),
+      done: Condvar::new(),
+    }));
+    let task2 = task.clone();
+    POOL.spawn_ok(async move { task2.run() });
+    Ok(LispFuture::Running(task))
+  }
+
+  /// Wait for the task `fut` to finish and return its result. The messages reported by the
+  /// task are reported here the first time it is awaited. `sp` is the span of the `(await)` call.
+  pub(crate) fn await_future(&mut self, sp: Span, fut: &RefCell<LispFuture>) -> Result<LispVal, ElabError> {
+    let mut g = fut.borrow_mut();
+    if let LispFuture::Running(_) = *g {
+      let task = match std::mem::replace(&mut *g, LispFuture::Done(Ok(LispVal::undef()))) {
+        LispFuture::Running(task) => task,
+        LispFuture::Done(_) => unreachable!(),
+      };
+      let (r, errors) = task.join().unwrap_or_else(|_|
+        (Err(ElabError::new_e(sp, "await: the spawned task panicked")), vec![]));
+      for e in errors { self.report(e) }
+      *g = LispFuture::Done(r.and_then(|data|
+        cache::decode_result(&data, |s| self.get_atom(s)).ok_or_else(||
+          ElabError::new_e(sp, "await: failed to read the result"))));
+    }
+    match &*g {
+      LispFuture::Done(r) => r.clone(),
+      LispFuture::Running(_) => unreachable!(),
+    }
+  }
+}
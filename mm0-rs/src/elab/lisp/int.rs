@@ -0,0 +1,192 @@
+//! Lisp integers, with an inline representation for small values.
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::fmt;
+use num::{BigInt, BigUint, Signed, ToPrimitive, bigint::ToBigInt};
+
+/// An arbitrary precision integer, stored inline if it fits in an `i64` and as a [`BigInt`]
+/// otherwise. Almost all numbers in lisp code are small, and this avoids allocating
+/// the digits of the number, so a number value costs only the [`LispVal`] itself.
+///
+/// The representation is canonical: an `Int` can only be built with the `From` impls,
+/// which use a [`BigInt`] only for values outside the range of `i64`, so structural
+/// equality and hashing agree with numeric equality.
+///
+/// [`LispVal`]: super::LispVal
+#[derive(Clone, Debug, PartialEq, Eq, Hash, DeepSizeOf)]
+pub struct Int(Repr);
+
+/// The representation of an [`Int`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash, DeepSizeOf)]
+enum Repr {
+  /// A number in the range of `i64`.
+  Small(i64),
+  /// A number outside the range of `i64`.
+  Big(BigInt),
+}
+
+impl Int {
+  /// Returns true if this is zero.
+  #[must_use] pub fn is_zero(&self) -> bool { matches!(self.0, Repr::Small(0)) }
+
+  /// Returns true if this is negative.
+  #[must_use] pub fn is_negative(&self) -> bool {
+    match &self.0 {
+      &Repr::Small(n) => n < 0,
+      Repr::Big(n) => n.is_negative(),
+    }
+  }
+
+  /// Returns true if this is positive.
+  #[must_use] pub fn is_positive(&self) -> bool {
+    match &self.0 {
+      &Repr::Small(n) => n > 0,
+      Repr::Big(n) => n.is_positive(),
+    }
+  }
+
+  /// Get the number as a [`BigInt`], which is borrowed if the number is stored as one.
+  #[must_use] pub fn as_bigint(&self) -> Cow<'_, BigInt> {
+    match &self.0 {
+      &Repr::Small(n) => Cow::Owned(n.into()),
+      Repr::Big(n) => Cow::Borrowed(n),
+    }
+  }
+
+  /// Convert the number to a string in the given base.
+  #[must_use] pub fn to_str_radix(&self, radix: u32) -> String {
+    self.as_bigint().to_str_radix(radix)
+  }
+
+  /// Apply a binary operation, using `fs` if both arguments are small and the result does not
+  /// overflow (signaled by returning `None`), and falling back to `fb` otherwise.
+  #[must_use] pub fn binop(self, other: Int,
+    fs: impl FnOnce(i64, i64) -> Option<i64>,
+    fb: impl FnOnce(BigInt, BigInt) -> BigInt,
+  ) -> Int {
+    if let (&Repr::Small(a), &Repr::Small(b)) = (&self.0, &other.0) {
+      if let Some(c) = fs(a, b) { return Int(Repr::Small(c)) }
+    }
+    fb(self.into(), other.into()).into()
+  }
+}
+
+impl From<BigInt> for Int {
+  fn from(n: BigInt) -> Self {
+    match n.to_i64() {
+      Some(n) => Int(Repr::Small(n)),
+      None => Int(Repr::Big(n)),
+    }
+  }
+}
+
+impl From<BigUint> for Int {
+  fn from(n: BigUint) -> Self { BigInt::from(n).into() }
+}
+
+impl From<Int> for BigInt {
+  fn from(n: Int) -> Self {
+    match n.0 {
+      Repr::Small(n) => n.into(),
+      Repr::Big(n) => n,
+    }
+  }
+}
+
+impl ToBigInt for Int {
+  fn to_bigint(&self) -> Option<BigInt> { Some(self.as_bigint().into_owned()) }
+}
+
+macro_rules! int_from_small {
+  ($($ty:ty),*) => {$(
+    impl From<$ty> for Int {
+      fn from(n: $ty) -> Self { Int(Repr::Small(n.into())) }
+    }
+  )*}
+}
+int_from_small!(i8, i16, i32, i64, u8, u16, u32);
+
+macro_rules! int_from_large {
+  ($($ty:ty),*) => {$(
+    impl From<$ty> for Int {
+      fn from(n: $ty) -> Self {
+        Int(i64::try_from(n).map_or_else(|_| Repr::Big(n.into()), Repr::Small))
+      }
+    }
+  )*}
+}
+int_from_large!(u64, usize, i128, u128);
+
+impl ToPrimitive for Int {
+  fn to_i64(&self) -> Option<i64> {
+    match self.0 {
+      Repr::Small(n) => Some(n),
+      Repr::Big(_) => None,
+    }
+  }
+  fn to_u64(&self) -> Option<u64> {
+    match &self.0 {
+      &Repr::Small(n) => n.to_u64(),
+      Repr::Big(n) => n.to_u64(),
+    }
+  }
+  fn to_i128(&self) -> Option<i128> {
+    match &self.0 {
+      &Repr::Small(n) => Some(n.into()),
+      Repr::Big(n) => n.to_i128(),
+    }
+  }
+  fn to_u128(&self) -> Option<u128> {
+    match &self.0 {
+      &Repr::Small(n) => n.to_u128(),
+      Repr::Big(n) => n.to_u128(),
+    }
+  }
+}
+
+impl PartialOrd for Int {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+
+impl Ord for Int {
+  fn cmp(&self, other: &Self) -> Ordering {
+    // Because the representation is canonical, a big number is larger than every
+    // small number if it is positive, and smaller if it is negative.
+    match (&self.0, &other.0) {
+      (Repr::Small(a), Repr::Small(b)) => a.cmp(b),
+      (Repr::Small(_), Repr::Big(b)) => if b.is_negative() {Ordering::Greater} else {Ordering::Less},
+      (Repr::Big(a), Repr::Small(_)) => if a.is_negative() {Ordering::Less} else {Ordering::Greater},
+      (Repr::Big(a), Repr::Big(b)) => a.cmp(b),
+    }
+  }
+}
+
+impl std::ops::Neg for Int {
+  type Output = Int;
+  fn neg(self) -> Int {
+    match self.0 {
+      Repr::Small(n) => Int(n.checked_neg().map_or_else(|| Repr::Big(-BigInt::from(n)), Repr::Small)),
+      Repr::Big(n) => (-n).into(),
+    }
+  }
+}
+
+impl std::ops::Not for Int {
+  type Output = Int;
+  fn not(self) -> Int {
+    match self.0 {
+      Repr::Small(n) => Int(Repr::Small(!n)),
+      Repr::Big(n) => (!n).into(),
+    }
+  }
+}
+
+impl fmt::Display for Int {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match &self.0 {
+      Repr::Small(n) => n.fmt(f),
+      Repr::Big(n) => n.fmt(f),
+    }
+  }
+}
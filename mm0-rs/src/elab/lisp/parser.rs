@@ -9,8 +9,8 @@ use num::{BigInt, ToPrimitive};
 use itertools::Itertools;
 use crate::ast::{SExpr, SExprKind, Atom};
 use crate::ArcString;
-use super::super::{AtomId, Span, DocComment, Elaborator, ElabError, ObjectKind};
-use super::{BuiltinProc, FileSpan, LispKind, LispVal, Proc, ProcSpec,
+use super::super::{AtomId, Span, DocComment, Elaborator, ElabError, ObjectKind, WarningCode};
+use super::{BuiltinProc, FileSpan, Int, LispKind, LispVal, Proc, ProcSpec,
   Remap, Remapper, Syntax};
 use super::super::math_parser::{QExpr, QExprKind};
 use super::print::{FormatEnv, EnvDisplay};
@@ -45,6 +45,15 @@ pub enum Ir {
   /// The `(focus es)` syntax form. This should be a regular function, but it does some
   /// preparation work before it starts executing the list of arguments.
   Focus(Span, Box<[Ir]>),
+  /// The `(try e handler)` syntax form: evaluate `e`, and if it throws an error,
+  /// evaluate `handler` and call it on the error object.
+  Try(Span, Box<(Ir, Ir)>),
+  /// The `(reset es)` syntax form: evaluate `es` with a delimiter for `shift`.
+  Reset(Span, Box<Ir>),
+  /// The `(shift k es)` syntax form: capture the continuation up to the nearest `reset`,
+  /// extend the context with it, and evaluate `es`. The `usize` is the number of the
+  /// variable `k`, which is only there for sanity checking, like in [`Ir::Def`].
+  Shift(Span, usize, Box<Ir>),
   /// The `(set-merge-strategy)` function, which is a macro because it directly binds
   /// to a global name.
   SetMergeStrategy(Span, AtomId, Box<Ir>),
@@ -70,13 +79,42 @@ pub enum Ir {
   /// the weak references to the functions.
   NoTailRec,
   /// The `(fn xs e)` syntax form. Create a closure from the current context, and return
-  /// it, using the provided [`ProcSpec`] and code. It can later be called by the
+  /// it, using the provided [`ProcSpec`], doc string and code. It can later be called by the
   /// [`App`](Self::App) instruction.
-  Lambda(Span, usize, ProcSpec, Arc<Ir>),
+  Lambda(Span, usize, ProcSpec, Option<DocComment>, Arc<Ir>),
+  /// An optional or keyword parameter in the prologue of a `(fn xs e)` with an extended
+  /// argument list. Variable `n` holds the list of arguments not yet consumed; if the
+  /// argument described by the [`ArgKind`] is present, it is removed from this list and
+  /// returned, otherwise the default value is evaluated.
+  Arg(Span, usize, ArgKind, Box<Ir>),
+  /// The end of the prologue of a `(fn xs e)` with an extended argument list.
+  /// Throws an error if the list of unconsumed arguments in variable `n` is not empty.
+  ArgsEnd(Span, usize),
   /// The `(match e bs)` syntax form. Evaluate `e`, and then match it against the branches.
   Match(Span, Box<Ir>, Box<[Branch]>),
 }
 
+/// The kind of argument extracted by an [`Ir::Arg`] instruction.
+#[derive(Copy, Clone, Debug, EnvDebug, DeepSizeOf)]
+#[allow(variant_size_differences)]
+pub enum ArgKind {
+  /// The next positional argument. If the `bool` is true, the function also has keyword
+  /// parameters, and a keyword in this position is not consumed as a positional argument.
+  Pos(bool),
+  /// The argument following the keyword `a` (which is an atom like `:foo`).
+  Key(AtomId),
+}
+
+impl Remap for ArgKind {
+  type Target = Self;
+  fn remap(&self, r: &mut Remapper) -> Self {
+    match *self {
+      ArgKind::Pos(b) => ArgKind::Pos(b),
+      ArgKind::Key(a) => ArgKind::Key(a.remap(r)),
+    }
+  }
+}
+
 impl<'a> EnvDisplay for Ir {
   fn fmt(&self, fe: FormatEnv<'_>, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     match self {
@@ -91,13 +129,16 @@ impl<'a> EnvDisplay for Ir {
       Ir::If(es) => write!(f, "(if {} {} {})",
         fe.to(&es.0), fe.to(&es.1), fe.to(&es.2)),
       Ir::Focus(_, es) => write!(f, "(focus {})", es.iter().map(|ir| fe.to(ir)).format(" ")),
+      Ir::Try(_, es) => write!(f, "(try {} {})", fe.to(&es.0), fe.to(&es.1)),
+      Ir::Reset(_, e) => write!(f, "(reset {})", fe.to(e)),
+      Ir::Shift(_, n, e) => write!(f, "(shift {} {})", n, fe.to(e)),
       Ir::SetMergeStrategy(_, a, _) => write!(f, "(set-merge-strategy {} _)", fe.to(a)),
       Ir::NoTailRec => write!(f, "(no-tail-rec)"),
       Ir::Def(n, a, e) => write!(f, "(def {}:{} {})",
         n, fe.to(&a.as_ref().map_or(AtomId::UNDER, |&(_, _, _, a)| a)), fe.to(e)),
       Ir::Eval(false, es) => write!(f, "(def _ {})", es.iter().map(|ir| fe.to(ir)).format(" ")),
       Ir::Eval(true, es) => write!(f, "(begin {})", es.iter().map(|ir| fe.to(ir)).format(" ")),
-      Ir::Lambda(_, n, sp, e) => {
+      Ir::Lambda(_, n, sp, _, e) => {
         write!(f, "(lambda {}:", n)?;
         match sp {
           ProcSpec::Exact(n) => write!(f, "{}", n)?,
@@ -105,6 +146,9 @@ impl<'a> EnvDisplay for Ir {
         }
         write!(f, " {})", fe.to(e))
       }
+      Ir::Arg(_, n, ArgKind::Pos(_), e) => write!(f, "(arg x{} {})", n, fe.to(e)),
+      Ir::Arg(_, n, ArgKind::Key(a), e) => write!(f, "(arg x{} {} {})", n, fe.to(a), fe.to(e)),
+      Ir::ArgsEnd(_, n) => write!(f, "(args-end x{})", n),
       Ir::Match(_, e, bs) => write!(f, "(match {} {})", fe.to(e), fe.to(&**bs))
     }
   }
@@ -141,7 +185,12 @@ impl Ir {
       Ir::List(sp, _) |
       Ir::App(sp, _, _, _) |
       Ir::Focus(sp, _) |
-      Ir::Lambda(sp, _, _, _) |
+      Ir::Try(sp, _) |
+      Ir::Reset(sp, _) |
+      Ir::Shift(sp, _, _) |
+      Ir::Lambda(sp, _, _, _, _) |
+      Ir::Arg(sp, _, _, _) |
+      Ir::ArgsEnd(sp, _) |
       Ir::Match(sp, _, _) => Some(sp),
       _ => None
     }
@@ -200,7 +249,7 @@ pub enum Pattern {
   /// The `#undef` pattern. Matches `#undef`, binds nothing.
   Undef,
   /// The `123` pattern. Matches the number `123`, binds nothing.
-  Number(BigInt),
+  Number(Int),
   /// The `(mvar)` or `(mvar bd s)` pattern. `(mvar)` matches metavars with unknown type,
   /// `(mvar bd s)` matches a metavar with known type, matching the boundedness and sort
   /// against patterns `bd` ans `s`.
@@ -228,10 +277,25 @@ pub enum Pattern {
   /// resulting in a procedure, and then `(f e)` is called, where `e` is the input.
   /// If this function returns truthy, then it acts like `(and ps)`, otherwise the pattern fails.
   Test(Span, Box<Ir>, Box<[Pattern]>),
+  /// The `(p if cond)` pattern. Matches the input against `p`, and then evaluates `cond`
+  /// with the variables bound by the pattern so far, succeeding if the result is truthy.
+  Guard(Box<Pattern>, Box<Ir>),
   /// The `$foo$` pattern. This is equivalent to `(or 'foo ('foo))`.
   QExprAtom(AtomId),
 }
 
+impl Pattern {
+  /// Returns true if the pattern matches every input.
+  fn irrefutable(&self) -> bool {
+    match self {
+      Pattern::Skip | Pattern::Atom(_) => true,
+      Pattern::And(ps) => ps.iter().all(Pattern::irrefutable),
+      Pattern::Or(ps) => ps.iter().any(Pattern::irrefutable),
+      _ => false,
+    }
+  }
+}
+
 /// The `(mvar)` patterns, which match a metavariable of different kinds.
 #[derive(Debug, EnvDebug, DeepSizeOf)]
 pub enum MVarPattern {
@@ -271,6 +335,7 @@ impl<'a> EnvDisplay for Pattern {
       Pattern::Or(es) => write!(f, "(or {})", es.iter().map(|ir| fe.to(ir)).format(" ")),
       Pattern::Not(es) => write!(f, "(not {})", es.iter().map(|ir| fe.to(ir)).format(" ")),
       Pattern::Test(_, ir, p) => write!(f, "(? {} {})", fe.to(&**ir), fe.to(&**p)),
+      Pattern::Guard(p, ir) => write!(f, "({} if {})", fe.to(&**p), fe.to(&**ir)),
       Pattern::QExprAtom(a) => write!(f, "${}$", fe.to(a)),
     }
   }
@@ -289,12 +354,17 @@ impl Remap for Ir {
       Ir::If(e) => Ir::If(e.remap(r)),
       Ir::NoTailRec => Ir::NoTailRec,
       Ir::Focus(sp, e) => Ir::Focus(*sp, e.remap(r)),
+      Ir::Try(sp, e) => Ir::Try(*sp, e.remap(r)),
+      Ir::Reset(sp, e) => Ir::Reset(*sp, e.remap(r)),
+      &Ir::Shift(sp, n, ref e) => Ir::Shift(sp, n, e.remap(r)),
       &Ir::SetMergeStrategy(sp, a, ref e) => Ir::SetMergeStrategy(sp, a.remap(r), e.remap(r)),
       &Ir::Def(n, ref a, ref e) => Ir::Def(n,
         a.as_ref().map(|&(sp1, sp2, ref doc, a)| (sp1, sp2, doc.clone(), a.remap(r))),
         e.remap(r)),
       &Ir::Eval(b, ref e) => Ir::Eval(b, e.remap(r)),
-      &Ir::Lambda(sp, n, spec, ref e) => Ir::Lambda(sp, n, spec, e.remap(r)),
+      &Ir::Lambda(sp, n, spec, ref doc, ref e) => Ir::Lambda(sp, n, spec, doc.clone(), e.remap(r)),
+      &Ir::Arg(sp, n, k, ref e) => Ir::Arg(sp, n, k.remap(r), e.remap(r)),
+      &Ir::ArgsEnd(sp, n) => Ir::ArgsEnd(sp, n),
       &Ir::Match(sp, ref e, ref br) => Ir::Match(sp, e.remap(r), br.remap(r)),
     }
   }
@@ -331,6 +401,7 @@ impl Remap for Pattern {
       Pattern::Or(es) => Pattern::Or(es.remap(r)),
       Pattern::Not(es) => Pattern::Not(es.remap(r)),
       &Pattern::Test(sp, ref ir, ref es) => Pattern::Test(sp, ir.remap(r), es.remap(r)),
+      Pattern::Guard(p, ir) => Pattern::Guard(p.remap(r), ir.remap(r)),
       Pattern::QExprAtom(a) => Pattern::QExprAtom(a.remap(r)),
     }
   }
@@ -424,9 +495,14 @@ impl LocalCtx {
   }
 }
 
+/// The maximum number of nested macro expansions, to catch macros that expand to themselves.
+const MAX_MACRO_DEPTH: usize = 256;
+
 struct LispParser<'a> {
   elab: &'a mut Elaborator,
   ctx: LocalCtx,
+  /// The number of macro expansions we are currently inside.
+  macro_depth: usize,
 }
 impl<'a> Deref for LispParser<'a> {
   type Target = Elaborator;
@@ -449,7 +525,7 @@ impl<'a> LispParser<'a> {
     let mut stack = vec![];
     loop {
       match &e.k {
-        &SExprKind::Atom(a) => break Ok((e.span, self.parse_atom(e.span, a)?, stack)),
+        SExprKind::Atom(_) | SExprKind::Name(_) => break Ok((e.span, self.parse_ident(e)?, stack)),
         SExprKind::List(xs) if !xs.is_empty() =>
           {stack.push(Item::List(&xs[1..])); e = &xs[0]}
         SExprKind::DottedList(xs, y) if !xs.is_empty() =>
@@ -459,34 +535,32 @@ impl<'a> LispParser<'a> {
     }
   }
 
+  /// Split off the doc string `"doc"` of a function body `"doc" exprs`. A string that is
+  /// the only expression in the body is the return value, not a doc string.
+  fn doc_string(es: &[SExpr]) -> (Option<DocComment>, &[SExpr]) {
+    match es {
+      [SExpr {k: SExprKind::String(s), ..}, es @ ..] if !es.is_empty() =>
+        (Some(String::from_utf8_lossy(s).into()), es),
+      _ => (None, es)
+    }
+  }
+
   fn def_ir(&mut self, sp: Span, es: &[SExpr], stack: Vec<Item<'_>>) -> Result<Vec<Ir>, ElabError> {
-    for e in stack.iter().rev() {
-      match e {
-        Item::List(xs) => {
-          let xs = self.parse_idents(xs)?;
-          self.ctx.push_list(&xs);
-        }
-        Item::DottedList(xs, y) => {
-          let xs = self.parse_idents(xs)?;
-          self.ctx.push_list(&xs);
-          let y = self.parse_ident(y)?;
-          self.ctx.push(y);
-        }
-      }
+    let (mut doc, es) = if stack.is_empty() { (None, es) } else { Self::doc_string(es) };
+    let mut args = vec![];
+    for e in stack.into_iter().rev() {
+      args.push(match e {
+        Item::List(xs) => self.lambda_args(xs, None)?,
+        Item::DottedList(xs, y) => self.lambda_args(xs, Some(y))?,
+      })
     }
     let mut len = self.ctx.len();
     let mut ir = self.exprs(false, es)?;
-    for e in stack {
-      ir = match e {
-        Item::List(xs) => {
-          len -= xs.len();
-          vec![Ir::Lambda(sp, len, ProcSpec::Exact(xs.len()), Ir::eval(ir).into())]
-        }
-        Item::DottedList(xs, _) => {
-          len -= xs.len() + 1;
-          vec![Ir::Lambda(sp, len, ProcSpec::AtLeast(xs.len()), Ir::eval(ir).into())]
-        }
-      }
+    while let Some((n, spec, mut code)) = args.pop() {
+      code.append(&mut ir);
+      let doc = if args.is_empty() { doc.take() } else { None };
+      ir = vec![Ir::Lambda(sp, n, spec, doc, Ir::eval(code).into())];
+      len = n;
     }
     self.ctx.restore(len);
     Ok(ir)
@@ -497,6 +571,8 @@ impl<'a> LispParser<'a> {
     let ir = self.def_ir(sp, es, stack)?;
     if self.ctx.len() == 0 {
       self.spans.insert(sp, ObjectKind::Global(x));
+    } else {
+      self.local_binder(sp, x)
     }
     Ok((sp, x, ir))
   }
@@ -510,16 +586,40 @@ impl<'a> LispParser<'a> {
     }
   }
 
-  fn parse_atom(&mut self, sp: Span, a: Atom) -> Result<AtomId, ElabError> {
-    self.parse_ident_or_syntax(sp, a).map_err(|_|
-      ElabError::new_e(sp, "keyword in invalid position"))
+  fn parse_name(&mut self, s: &[u8]) -> Result<AtomId, Syntax> {
+    match Syntax::from_bytes(s) {
+      Some(s) => Err(s),
+      None => Ok(self.get_atom(s))
+    }
+  }
+
+  /// Parse an atom expression (either [`SExprKind::Atom`] or [`SExprKind::Name`]),
+  /// returning `None` if `e` is not an atom.
+  fn parse_sexpr_atom(&mut self, e: &SExpr) -> Option<Result<AtomId, Syntax>> {
+    match &e.k {
+      &SExprKind::Atom(a) => Some(self.parse_ident_or_syntax(e.span, a)),
+      SExprKind::Name(s) => Some(self.parse_name(s)),
+      _ => None
+    }
+  }
+
+  /// Get the text of an atom expression, without interning it.
+  fn atom_text<'b>(&'b self, e: &'b SExpr) -> Option<&'b [u8]> {
+    match &e.k {
+      &SExprKind::Atom(a) => Some(self.ast.span_atom(e.span, a)),
+      SExprKind::Name(s) => Some(s),
+      _ => None
+    }
+  }
+
+  fn parse_atom(sp: Span, x: Result<AtomId, Syntax>) -> Result<AtomId, ElabError> {
+    x.map_err(|_| ElabError::new_e(sp, "keyword in invalid position"))
   }
 
   fn parse_ident(&mut self, e: &SExpr) -> Result<AtomId, ElabError> {
-    if let SExprKind::Atom(a) = e.k {
-      self.parse_atom(e.span, a)
-    } else {
-      Err(ElabError::new_e(e.span, "expected an identifier"))
+    match self.parse_sexpr_atom(e) {
+      Some(x) => Self::parse_atom(e.span, x),
+      None => Err(ElabError::new_e(e.span, "expected an identifier"))
     }
   }
 
@@ -529,6 +629,76 @@ impl<'a> LispParser<'a> {
     Ok(xs)
   }
 
+  /// If `e` is a keyword atom like `:foo`, returns the text `foo`.
+  fn keyword(&self, e: &SExpr) -> Option<Vec<u8>> {
+    match self.atom_text(e)? {
+      [b':', s @ ..] if !s.is_empty() => Some(s.into()),
+      _ => None
+    }
+  }
+
+  /// Parse the parameter list `(xs . y)` of a `(fn)` or `(def (f . xs) e)`, and push the
+  /// parameters onto the context. Returns the number of the first parameter, the spec of
+  /// the function, and the code of the prologue that binds the optional and keyword
+  /// parameters, which should be placed at the start of the function body.
+  fn lambda_args(&mut self, xs: &[SExpr], y: Option<&SExpr>) -> Result<(usize, ProcSpec, Vec<Ir>), ElabError> {
+    let n = self.ctx.len();
+    let req = xs.iter().position(|x|
+      !matches!(x.k, SExprKind::Atom(_) | SExprKind::Name(_)) || self.keyword(x).is_some()
+    ).unwrap_or(xs.len());
+    let ys = self.parse_idents(&xs[..req])?;
+    for (x, &y) in xs.iter().zip(&ys) { self.local_binder(x.span, y) }
+    self.ctx.push_list(&ys);
+    if req == xs.len() {
+      return Ok(match y {
+        None => (n, ProcSpec::Exact(req), vec![]),
+        Some(e) => {
+          let y = self.parse_ident(e)?;
+          self.local_binder(e.span, y);
+          self.ctx.push(y);
+          (n, ProcSpec::AtLeast(req), vec![])
+        }
+      })
+    }
+    let params = xs[req..].iter().map(|x| match &x.k {
+      SExprKind::List(es) if es.len() == 2 => Ok((x.span, &es[0], Some(&es[1]))),
+      SExprKind::Atom(_) | SExprKind::Name(_) => Ok((x.span, x, None)),
+      _ => Err(ElabError::new_e(x.span, "invalid parameter"))
+    }).collect::<Result<Vec<_>, _>>()?;
+    let has_keys = params.iter().any(|(_, x, _)| self.keyword(x).is_some());
+    let rest = self.ctx.push(AtomId::UNDER);
+    let (mut code, mut seen_key) = (vec![], false);
+    for (sp, x, default) in params {
+      let (kind, a) = if let Some(s) = self.keyword(x) {
+        seen_key = true;
+        let k = self.get_atom(&[&b":"[..], &s].concat());
+        (ArgKind::Key(k), Self::parse_atom(x.span, self.parse_name(&s))?)
+      } else if seen_key {
+        return Err(ElabError::new_e(sp, "positional parameter after keyword parameter"))
+      } else if default.is_none() {
+        return Err(ElabError::new_e(sp, "required parameter after optional parameter"))
+      } else {
+        (ArgKind::Pos(has_keys), self.parse_ident(x)?)
+      };
+      let default = match default {
+        Some(e) => self.expr(false, e)?,
+        None => Ir::Const(LispVal::undef()),
+      };
+      let arg = Ir::Arg(sp, rest, kind, Box::new(default));
+      self.local_binder(x.span, a);
+      code.push(Ir::Def(self.ctx.push(a), None, Box::new(arg)));
+    }
+    match y {
+      None => code.push(Ir::ArgsEnd(xs[req].span, rest)),
+      Some(e) => {
+        let y = self.parse_ident(e)?;
+        self.local_binder(e.span, y);
+        code.push(Ir::Def(self.ctx.push(y), None, Box::new(Ir::Local(rest))))
+      }
+    }
+    Ok((n, ProcSpec::AtLeast(req), code))
+  }
+
   fn qexpr(&mut self, e: QExpr) -> Result<Ir, ElabError> {
     match e.k {
       QExprKind::IdentApp(sp, es) => {
@@ -578,6 +748,7 @@ impl<'a> LispParser<'a> {
       let mut ds = Vec::with_capacity(ls.len());
       for l in ls {
         let ((sp, x, stk), e2) = self.let_var(l)?;
+        self.local_binder(sp, x);
         let n = self.ctx.push(x);
         let sps = if x == AtomId::UNDER {None} else {Some((l.span, sp, None, x))};
         cs.push(Ir::Def(n, sps.clone(),
@@ -601,6 +772,7 @@ impl<'a> LispParser<'a> {
         if x == AtomId::UNDER {
           cs.push(Ir::Eval(false, v.into()))
         } else {
+          self.local_binder(sp, x);
           cs.push(Ir::Def(self.ctx.push(x), Some((l.span, sp, None, x)), Ir::eval(v).into()))
         }
       }
@@ -615,21 +787,31 @@ impl<'a> LispParser<'a> {
     let pat = loop {
       match es {
         [] => return Ok(Pattern::List(pfx.into(), None)),
-        &[SExpr {span, k: SExprKind::Atom(a)}, ref e] if quote =>
-          if self.ast.span_atom(span, a) == b"unquote" {
-            break self.pattern(ctx, code, false, e)?
-          },
+        [head, e] if quote && self.atom_text(head) == Some(b"unquote") =>
+          break self.pattern(ctx, code, false, e)?,
         _ if quote => {},
-        [head, args @ ..] => if let SExprKind::Atom(a) = head.k {
-          match self.ast.span_atom(head.span, a) {
+        [p, head, cond] if pfx.is_empty() && self.atom_text(head) == Some(b"if") => {
+          let p = self.pattern(ctx, code, quote, p)?;
+          let start = self.ctx.push_list(&ctx.ctx);
+          let ir = self.expr(false, cond)?;
+          self.ctx.restore(start);
+          return Ok(Pattern::Guard(Box::new(p), Box::new(ir)))
+        }
+        [_, _, ..] if pfx.is_empty() && es.len() % 2 == 1 &&
+          es.iter().skip(1).step_by(2).all(|e| self.atom_text(e) == Some(b"|")) => {
+          let mut ps = vec![];
+          for p in es.iter().step_by(2) { ps.push(self.pattern(ctx, code, quote, p)?) }
+          return Ok(Pattern::Or(ps.into()))
+        }
+        [head, args @ ..] => if let Some(head_text) = self.atom_text(head) {
+          match head_text {
             b"quote" => match args {
               [e] => break self.pattern(ctx, code, true, e)?,
               _ => return Err(ElabError::new_e(head.span, "expected one argument")),
             },
             b"mvar" => match args {
               [] => break Pattern::MVar(MVarPattern::Unknown),
-              &[SExpr {span, k: SExprKind::Atom(a)}]
-                if matches!(self.ast.span_atom(span, a), b"___" | b"...") =>
+              [e] if matches!(self.atom_text(e), Some(b"___" | b"...")) =>
                 break Pattern::MVar(MVarPattern::Any),
               [bd, s] => {
                 let bd = self.pattern(ctx, code, quote, bd)?;
@@ -720,15 +902,14 @@ impl<'a> LispParser<'a> {
   fn pattern(&mut self, ctx: &mut LocalCtx, code: &mut Vec<Ir>,
       quote: bool, e: &SExpr) -> Result<Pattern, ElabError> {
     match &e.k {
-      &SExprKind::Atom(a) => Ok(
-        if quote {
-          Pattern::QuoteAtom(self.elab.env.get_atom(self.elab.ast.span_atom(e.span, a)))
-        } else {
-          let x = self.parse_atom(e.span, a)?;
-          if x == AtomId::UNDER {Pattern::Skip}
-          else {Pattern::Atom(ctx.get_or_push(x))}
-        }
-      ),
+      &SExprKind::Atom(a) if quote =>
+        Ok(Pattern::QuoteAtom(self.elab.env.get_atom(self.elab.ast.span_atom(e.span, a)))),
+      SExprKind::Name(s) if quote => Ok(Pattern::QuoteAtom(self.elab.env.get_atom(s))),
+      SExprKind::Atom(_) | SExprKind::Name(_) => Ok({
+        let x = self.parse_ident(e)?;
+        if x == AtomId::UNDER {Pattern::Skip}
+        else {Pattern::Atom(ctx.get_or_push(x))}
+      }),
       SExprKind::DottedList(es, e) => Ok(Pattern::DottedList(
         self.patterns(ctx, code, quote, es)?,
         self.pattern(ctx, code, quote, e)?.into())),
@@ -753,8 +934,8 @@ impl<'a> LispParser<'a> {
     let mut cont = AtomId::UNDER;
     if let Some(e2) = es.get(0) {
       if let SExprKind::List(v) = &e2.k {
-        if let [SExpr {span, k: SExprKind::Atom(a)}, ref x] = **v {
-          if self.ast.span_atom(span, a) == b"=>" {
+        if let [head, x] = &**v {
+          if self.atom_text(head) == Some(b"=>") {
             cont = self.parse_ident(x)?;
             es = &es[1..];
           }
@@ -771,8 +952,14 @@ impl<'a> LispParser<'a> {
     Ok(Branch {pat, vars, cont: cont != AtomId::UNDER, eval})
   }
   fn branches(&mut self, code: &mut Vec<Ir>, es: &[SExpr]) -> Result<Box<[Branch]>, ElabError> {
-    let mut bs = vec![];
-    for e in es { bs.push(self.branch(code, e)?) }
+    let mut bs: Vec<Branch> = vec![];
+    for e in es {
+      if bs.last().map_or(false, |b| !b.cont && b.pat.irrefutable()) {
+        self.report(ElabError::lint(WarningCode::UnreachableBranch, e.span,
+          "unreachable match branch: the previous pattern always matches"))
+      }
+      bs.push(self.branch(code, e)?)
+    }
     Ok(bs.into())
   }
   fn match_(&mut self, es: &[SExpr], f: impl FnOnce(Box<[Branch]>) -> Ir) -> Result<Ir, ElabError> {
@@ -786,14 +973,101 @@ impl<'a> LispParser<'a> {
     }
   }
 
+  /// Record that the lisp local `x` is bound at `sp`.
+  fn local_binder(&mut self, sp: Span, x: AtomId) {
+    if x != AtomId::UNDER { self.spans.insert(sp, ObjectKind::LispVar(x)); }
+  }
+
   fn eval_atom(&mut self, sp: Span, x: AtomId) -> Ir {
     match self.ctx.get(x) {
       None => {
         self.spans.insert(sp, ObjectKind::Global(x));
         Ir::Global(sp, x)
       },
-      Some(i) => Ir::Local(i)
+      Some(i) => {
+        self.spans.insert(sp, ObjectKind::LispVar(x));
+        Ir::Local(i)
+      }
+    }
+  }
+
+  fn atom(&mut self, quote: bool, sp: Span, x: Result<AtomId, Syntax>) -> Result<Ir, ElabError> {
+    Ok(if quote {
+      Ir::Const(match x {
+        Ok(x) => LispVal::atom(x),
+        Err(s) => LispVal::syntax(s),
+      }.span(self.fspan(sp)))
+    } else {
+      match Self::parse_atom(sp, x)? {
+        AtomId::UNDER => Ir::Const(LispVal::atom(AtomId::UNDER).span(self.fspan(sp))),
+        x if matches!(*self.data[x].name, [b':', _, ..]) =>
+          Ir::Const(LispVal::atom(x).span(self.fspan(sp))),
+        x => self.eval_atom(sp, x),
+      }
+    })
+  }
+
+  /// Get the expansion procedure of `x`, if it is a global macro not shadowed by a local.
+  fn get_macro(&self, x: AtomId) -> Option<LispVal> {
+    if self.ctx.get(x).is_some() { return None }
+    self.data[x].lisp.as_ref()?.val.unwrapped(|e| match e {
+      LispKind::Proc(Proc::Macro(f)) => Some(f.clone()),
+      _ => None
+    })
+  }
+
+  /// Convert the result of a macro expansion back into an s-expression so that it can be
+  /// parsed. Subterms keep their spans if they come from the current file, and otherwise
+  /// use the span `sp` of the macro call.
+  fn macro_sexpr(&mut self, sp: Span, e: &LispVal) -> Result<SExpr, ElabError> {
+    let span = match e.fspan() {
+      Some(fsp) if fsp.file == self.path => fsp.span,
+      _ => sp
+    };
+    e.unwrapped(|r| Ok(SExpr {span, k: match r {
+      &LispKind::Atom(a) => SExprKind::Name(self.data[a].name.clone()),
+      LispKind::Syntax(s) => SExprKind::Name(s.to_str().as_bytes().into()),
+      LispKind::List(es) => SExprKind::List(
+        es.iter().map(|e| self.macro_sexpr(sp, e)).collect::<Result<_, _>>()?),
+      LispKind::DottedList(es, r) => return Ok(SExpr::dotted_list(span,
+        es.iter().map(|e| self.macro_sexpr(sp, e)).collect::<Result<_, _>>()?,
+        Some(self.macro_sexpr(sp, r)?))),
+      LispKind::Number(n) => {
+        let n = BigInt::from(n.clone());
+        match n.to_biguint() {
+          Some(n) => SExprKind::Number(n),
+          None => SExprKind::List(vec![
+            SExpr {span, k: SExprKind::Name((&b"-"[..]).into())},
+            SExpr {span, k: SExprKind::Number(n.magnitude().clone())}]),
+        }
+      }
+      LispKind::String(s) => SExprKind::String(s.clone()),
+      &LispKind::Bool(b) => SExprKind::Bool(b),
+      LispKind::Undef => SExprKind::Undef,
+      _ => return Err(ElabError::new_e(sp,
+        format!("macro expansion contains a value with no syntax: {}", self.print(e))))
+    }}))
+  }
+
+  /// Expand a macro call `(f . es)`, where `m` is the expansion procedure of `f`,
+  /// and parse the result in place of the call.
+  fn expand_macro(&mut self, doc: String, sp: Span, m: LispVal, es: &[SExpr]) -> Result<Ir, ElabError> {
+    if self.macro_depth >= MAX_MACRO_DEPTH {
+      return Err(ElabError::new_e(sp, "macro expansion depth exceeded"))
     }
+    let mut args = vec![];
+    for e in es {
+      match self.expr(true, e)? {
+        Ir::Const(e) => args.push(e),
+        _ => return Err(ElabError::new_e(e.span, "unquotation is not allowed in macro arguments")),
+      }
+    }
+    let res = self.call_func(sp, m, args)?;
+    let e = self.macro_sexpr(sp, &res)?;
+    self.macro_depth += 1;
+    let res = self.expr_doc(doc, false, &e);
+    self.macro_depth -= 1;
+    res
   }
 
   fn expr(&mut self, quote: bool, e: &SExpr) -> Result<Ir, ElabError> {
@@ -804,19 +1078,14 @@ impl<'a> LispParser<'a> {
     macro_rules! span {($sp:expr, $e:expr) => {{$e.span(self.fspan($sp))}}}
     let mut restore = Some(self.ctx.len());
     let res = match &e.k {
-      &SExprKind::Atom(a) => if quote {
-        Ok(Ir::Const(span!(e.span,
-          match self.parse_ident_or_syntax(e.span, a) {
-            Ok(x) => LispVal::atom(x),
-            Err(s) => LispVal::syntax(s),
-          }
-        )))
-      } else {
-        Ok(match self.parse_atom(e.span, a)? {
-          AtomId::UNDER => Ir::Const(span!(e.span, LispVal::atom(AtomId::UNDER))),
-          x => self.eval_atom(e.span, x),
-        })
-      },
+      &SExprKind::Atom(a) => {
+        let x = self.parse_ident_or_syntax(e.span, a);
+        self.atom(quote, e.span, x)
+      }
+      SExprKind::Name(s) => {
+        let x = self.parse_name(s);
+        self.atom(quote, e.span, x)
+      }
       SExprKind::DottedList(es, e) => {
         if !quote {
           return Err(ElabError::new_e(e.span, "cannot evaluate an improper list"))
@@ -832,7 +1101,7 @@ impl<'a> LispParser<'a> {
         }
         Ok(Ir::dotted_list(e.span, cs, self.expr(true, e)?))
       }
-      SExprKind::Number(n) => Ok(Ir::Const(span!(e.span, LispVal::number(n.clone().into())))),
+      SExprKind::Number(n) => Ok(Ir::Const(span!(e.span, LispVal::number(n.clone())))),
       SExprKind::String(s) => Ok(Ir::Const(span!(e.span, LispVal::string(s.clone())))),
       &SExprKind::Bool(b) => Ok(Ir::Const(span!(e.span, LispVal::bool(b)))),
       SExprKind::Undef => Ok(Ir::Const(span!(e.span, LispVal::undef()))),
@@ -848,57 +1117,69 @@ impl<'a> LispParser<'a> {
         let mut it = es.iter();
         Ok(loop {
           if let Some(arg) = it.next() {
-            if let SExprKind::Atom(a) = arg.k {
-              if Syntax::parse(self.ast.span(arg.span), a) == Ok(Syntax::Unquote) {
-                let r = it.next().ok_or_else(||
-                  ElabError::new_e(arg.span, "expected at least one argument"))?;
-                break Ir::dotted_list(e.span, cs, self.expr(false, r)?)
-              }
+            if self.atom_text(arg) == Some(b"unquote") {
+              let r = it.next().ok_or_else(||
+                ElabError::new_e(arg.span, "expected at least one argument"))?;
+              break Ir::dotted_list(e.span, cs, self.expr(false, r)?)
             }
             cs.push(self.expr(true, arg)?)
           } else {break Ir::list(self.fspan(e.span), cs)}
         })
-      } else if let SExprKind::Atom(a) = es[0].k {
-        match self.parse_ident_or_syntax(es[0].span, a) {
+      } else if let Some(head) = self.parse_sexpr_atom(&es[0]) {
+        match head {
           Ok(AtomId::UNDER) => return Err(ElabError::new_e(es[0].span, "'_' is not a function")),
-          Ok(x) =>
+          Ok(x) => if let Some(m) = self.get_macro(x) {
+            self.spans.insert(es[0].span, ObjectKind::Global(x));
+            return self.expand_macro(doc, e.span, m, &es[1..])
+          } else {
             Ok(Ir::App(e.span, es[0].span,
-              Box::new(self.eval_atom(es[0].span, x)), self.exprs(false, &es[1..])?.into())),
+              Box::new(self.eval_atom(es[0].span, x)), self.exprs(false, &es[1..])?.into()))
+          },
           Err(stx) => {
             self.spans.insert_if(es[0].span, || ObjectKind::Syntax(stx));
             match stx {
               Syntax::Begin => Ok(Ir::Eval(true, self.exprs(false, &es[1..])?.into())),
-              Syntax::Define if es.len() < 2 => return Err(
+              Syntax::Define | Syntax::DefMacro if es.len() < 2 => return Err(
                 ElabError::new_e(es[0].span, "expected at least one argument")),
               Syntax::Define =>
                 Ok(match self.def(&es[1], &es[2..])? {
                   (_, AtomId::UNDER, cs) => Ir::Eval(false, cs.into()),
                   (sp, x, cs) => {
                     restore = None;
-                    let doc = if doc.is_empty() {None} else {Some(doc.into())};
+                    let doc = if !doc.is_empty() {Some(doc.into())} else if let
+                      [Ir::Lambda(_, _, _, Some(doc), _)] = &*cs {Some(doc.clone())} else {None};
                     Ir::Def(self.ctx.push(x), Some((e.span, sp, doc, x)), Ir::eval(cs).into())
                   }
                 }),
+              Syntax::DefMacro => {
+                let (sp, x, cs) = self.def(&es[1], &es[2..])?;
+                let m = Ir::builtin_app(e.span, es[0].span, BuiltinProc::Macro, Box::new([Ir::eval(cs)]));
+                Ok(if x == AtomId::UNDER { m } else {
+                  restore = None;
+                  let doc = if doc.is_empty() {None} else {Some(doc.into())};
+                  Ir::Def(self.ctx.push(x), Some((e.span, sp, doc, x)), m.into())
+                })
+              }
               Syntax::Lambda if es.len() < 2 => return Err(
                 ElabError::new_e(es[0].span, "expected at least one argument")),
               Syntax::Lambda => match &es[1].k {
                 SExprKind::List(xs) => {
-                  let xs = self.parse_idents(xs)?;
-                  Ok(Ir::Lambda(es[0].span, self.ctx.push_list(&xs), ProcSpec::Exact(xs.len()),
-                    Ir::eval(self.exprs(false, &es[2..])?).into()))
+                  let (doc, body) = Self::doc_string(&es[2..]);
+                  let (n, spec, mut code) = self.lambda_args(xs, None)?;
+                  code.extend(self.exprs(false, body)?);
+                  Ok(Ir::Lambda(es[0].span, n, spec, doc, Ir::eval(code).into()))
                 }
                 SExprKind::DottedList(xs, y) => {
-                  let xs = self.parse_idents(xs)?;
-                  let y = self.parse_ident(y)?;
-                  let n = self.ctx.push_list(&xs);
-                  self.ctx.push(y);
-                  Ok(Ir::Lambda(es[0].span, n, ProcSpec::AtLeast(xs.len()),
-                    Ir::eval(self.exprs(false, &es[2..])?).into()))
+                  let (doc, body) = Self::doc_string(&es[2..]);
+                  let (n, spec, mut code) = self.lambda_args(xs, Some(y))?;
+                  code.extend(self.exprs(false, body)?);
+                  Ok(Ir::Lambda(es[0].span, n, spec, doc, Ir::eval(code).into()))
                 }
                 _ => {
+                  let (doc, body) = Self::doc_string(&es[2..]);
                   let x = self.parse_ident(&es[1])?;
-                  Ok(Ir::Lambda(es[0].span, self.ctx.push(x), ProcSpec::AtLeast(0),
-                    Ir::eval(self.exprs(false, &es[2..])?).into()))
+                  Ok(Ir::Lambda(es[0].span, self.ctx.push(x), ProcSpec::AtLeast(0), doc,
+                    Ir::eval(self.exprs(false, body)?).into()))
                 }
               },
               Syntax::Quote if es.len() < 2 => return Err(
@@ -918,6 +1199,23 @@ impl<'a> LispParser<'a> {
               Syntax::If => return Err(
                 ElabError::new_e(es[0].span, "expected two or three arguments")),
               Syntax::Focus => Ok(Ir::Focus(es[0].span, self.exprs(false, &es[1..])?.into())),
+              Syntax::Try if es.len() == 3 => Ok(Ir::Try(es[0].span, Box::new((
+                self.expr(false, &es[1])?,
+                {
+                  // the handler is evaluated in the context before the `try`
+                  self.ctx.restore(unwrap_unchecked!(restore));
+                  self.expr(false, &es[2])?
+                }
+              )))),
+              Syntax::Try => return Err(
+                ElabError::new_e(es[0].span, "expected two arguments")),
+              Syntax::Reset => Ok(Ir::Reset(es[0].span, Ir::eval(self.exprs(false, &es[1..])?).into())),
+              Syntax::Shift if es.len() < 2 => return Err(
+                ElabError::new_e(es[0].span, "expected at least one argument")),
+              Syntax::Shift => {
+                let k = self.parse_ident(&es[1])?;
+                Ok(Ir::Shift(es[0].span, self.ctx.push(k), Ir::eval(self.exprs(false, &es[2..])?).into()))
+              }
               Syntax::Let => self.let_(false, &es[1..]),
               Syntax::Letrec => self.let_(true, &es[1..]),
               Syntax::SetMergeStrategy if 2 <= es.len() && es.len() <= 3 =>
@@ -938,12 +1236,12 @@ impl<'a> LispParser<'a> {
               },
               Syntax::MatchFn => {
                 let i = self.ctx.push(AtomId::UNDER);
-                Ok(Ir::Lambda(es[0].span, i, ProcSpec::Exact(1),
+                Ok(Ir::Lambda(es[0].span, i, ProcSpec::Exact(1), None,
                   Arc::new(self.match_(&es[1..], |m| Ir::match_fn_body(es[0].span, i, m))?)))
               }
               Syntax::MatchFns => {
                 let i = self.ctx.push(AtomId::UNDER);
-                Ok(Ir::Lambda(es[0].span, i, ProcSpec::AtLeast(0),
+                Ok(Ir::Lambda(es[0].span, i, ProcSpec::AtLeast(0), None,
                   Arc::new(self.match_(&es[1..], |m| Ir::match_fn_body(es[0].span, i, m))?)))
               }
             }
@@ -969,13 +1267,13 @@ impl Elaborator {
   /// Parse a lisp `SExpr` from the surface syntax into an `IR` object suitable for evaluation.
   /// The `doc` argument is an additional doc string, if applicable.
   pub fn parse_lisp_doc(&mut self, e: &SExpr, doc: String) -> Result<Ir, ElabError> {
-    LispParser {elab: &mut *self, ctx: LocalCtx::new()}.expr_doc(doc, false, e)
+    LispParser {elab: &mut *self, ctx: LocalCtx::new(), macro_depth: 0}.expr_doc(doc, false, e)
   }
 
   /// Parse a `QExpr`, the result of parsing a math formula,
   /// into an `IR` object suitable for evaluation. (Usually this will be a `IR::Const`,
   /// but `QExpr`'s can contain antiquotations which require evaluation.)
   pub fn parse_qexpr(&mut self, e: QExpr) -> Result<Ir, ElabError> {
-    LispParser {elab: &mut *self, ctx: LocalCtx::new()}.qexpr(e)
+    LispParser {elab: &mut *self, ctx: LocalCtx::new(), macro_depth: 0}.qexpr(e)
   }
 }
\ No newline at end of file
@@ -12,7 +12,7 @@ use itertools::Itertools;
 use crate::{LispVal, LispKind, Uncons, FormatEnv,
   Prec, DeclKey, Literal, TermKind, ThmKind, Modifiers,
   Environment, NotaInfo, AtomData, AtomId, TermId, ThmId, SortId, Thm, Type,
-  APP_PREC};
+  ProofNode, APP_PREC};
 
 /// The possible annotations around subparts of a pretty printed display.
 /// These are ignored under usual printing settings, but they are used in
@@ -77,11 +77,15 @@ impl LispKind {
       LispKind::List(es) => es.is_empty(),
       LispKind::DottedList(..) |
       LispKind::AtomMap(..) |
+      LispKind::HashTable(..) |
+      LispKind::Vector(..) |
       LispKind::Goal(..) => false,
       LispKind::Atom(..) |
       LispKind::MVar(..) |
       LispKind::Proc(..) |
       LispKind::Number(..) |
+      LispKind::Rational(..) |
+      LispKind::StringPort(..) |
       LispKind::String(..) |
       LispKind::Bool(..) |
       LispKind::Syntax(..) |
@@ -100,6 +104,9 @@ pub struct Pretty<'a> {
   fe: FormatEnv<'a>,
   pub(crate) alloc: &'a Arena<'a>,
   hash: RefCell<HashMap<*const LispKind, PrettyCache<'a>>>,
+  /// False if notations are not used, so that formulas are printed as applications of
+  /// the term constructors, like `$ im a (not b) $`.
+  notation: bool,
   lparen: Pp<'a>,
   rparen: Pp<'a>,
 }
@@ -134,11 +141,14 @@ impl<'a> Pretty<'a> {
   fn softline() -> RefDoc<'a> {covariant(SOFTLINE)}
   fn softline_() -> RefDoc<'a> {covariant(SOFTLINE_)}
 
-  fn new(fe: FormatEnv<'a>, alloc: &'a Arena<'a>) -> Pretty<'a> {
+  fn new(fe: FormatEnv<'a>, alloc: &'a Arena<'a>, notation: bool) -> Pretty<'a> {
+    // The parentheses around a subexpression always open and close it, even if `(` and `)`
+    // are declared as both left and right delimiters, so that `if (x) then` is not
+    // notated as `if(x)then`.
     Pretty {
-      lparen: Pp::token(alloc, fe.env, "("),
-      rparen: Pp::token(alloc, fe.env, ")"),
-      fe, alloc, hash: RefCell::new(HashMap::new())
+      lparen: Pp {left: false, ..Pp::token(alloc, fe.env, "(")},
+      rparen: Pp {right: false, ..Pp::token(alloc, fe.env, ")")},
+      fe, alloc, hash: RefCell::new(HashMap::new()), notation
     }
   }
 
@@ -262,7 +272,7 @@ impl<'a> Pretty<'a> {
     let v = (|| Some({
       let env = self.fe.env;
       let (ad, t, args) = self.get_term_args(e)?;
-      if let Some(&(coe, ref fix)) = env.pe.decl_nota.get(&t) {
+      if let (true, Some(&(coe, ref fix))) = (self.notation, env.pe.decl_nota.get(&t)) {
         if coe {return Some(self.pp_expr(&args[0]))}
         if let Some(&(ref tk, infix)) = fix.first() {
           let doc = if infix {
@@ -277,9 +287,9 @@ impl<'a> Pretty<'a> {
             let info = &env.pe.prefixes[tk];
             let mut doc = self.token(tk);
             for lit in &info.lits {
-              doc = self.append(doc, self.lit(lit, &args))
+              doc = self.append(doc, self.group(self.lit(lit, &args)))
             }
-            doc
+            self.group(self.nest(2, doc))
           };
           return Some((env.pe.consts[tk].1, doc))
         }
@@ -523,6 +533,56 @@ impl<'a> Pretty<'a> {
     self.alloc(Doc::Group(self.alloc(Doc::Nest(2, doc))))
   }
 
+  /// Pretty-prints the proof of a theorem as an explicit proof term in `refine` syntax,
+  /// such as `(! mp a b (! ax_1 a b) h)`, which can replace the tactic script that produced
+  /// it. Conversions are left implicit, since `refine` reconstructs them by unification.
+  /// Returns `None` if the theorem has no proof.
+  pub fn refine_proof(&'a self, tid: ThmId) -> Option<RefDoc<'a>> {
+    let t = &self.fe.env.thms[tid];
+    if let ThmKind::Thm(Some(pr)) = &t.kind {
+      Some(self.refine_node(t, &pr.heap, &pr.head))
+    } else {None}
+  }
+
+  fn refine_node(&'a self, td: &'a Thm, heap: &'a [ProofNode], p: &'a ProofNode) -> RefDoc<'a> {
+    match *p {
+      ProofNode::Ref(i) if i >= td.args.len() => self.refine_node(td, heap, &heap[i]),
+      ProofNode::Hyp(h, _) => self.alloc(Doc::text(format!("{}", self.fe.to(&td.hyps[h].0)))),
+      ProofNode::Conv(ref p) => self.refine_node(td, heap, &p.2),
+      ProofNode::Thm {thm, ref args, ..} => {
+        let ad = &self.fe.env.data[self.fe.env.thms[thm].atom];
+        let name = self.annot(Annot::ThmName(thm), self.alloc(Doc::BorrowedText(ad.name.as_str())));
+        if args.is_empty() {return name}
+        let nargs = self.fe.env.thms[thm].args.len();
+        let doc = self.app_doc(self.append_doc(s!("! "), name),
+          args.iter().enumerate().map(|(i, e)| if i < nargs {
+            let e = self.proof_expr(td, heap, e);
+            if e.is_atom() {(true, self.pp_lisp(&e))} else {(e.small(), self.expr(&e))}
+          } else {
+            (false, self.refine_node(td, heap, e))
+          }));
+        let doc = self.append_doc(self.lparen, self.append_doc(doc, self.rparen));
+        self.alloc(Doc::Group(self.alloc(Doc::Nest(2, doc))))
+      }
+      _ => {
+        let e = self.proof_expr(td, heap, p);
+        if e.is_atom() {self.pp_lisp(&e)} else {self.expr(&e)}
+      }
+    }
+  }
+
+  fn proof_expr(&self, td: &Thm, heap: &[ProofNode], e: &ProofNode) -> LispVal {
+    match *e {
+      ProofNode::Ref(i) if i < td.args.len() => LispVal::atom(td.args[i].0.unwrap_or(AtomId::UNDER)),
+      ProofNode::Ref(i) => self.proof_expr(td, heap, &heap[i]),
+      ProofNode::Dummy(a, _) => LispVal::atom(a),
+      ProofNode::Term {term, ref args} => LispVal::list(
+        std::iter::once(LispVal::atom(self.fe.env.terms[term].atom))
+          .chain(args.iter().map(|e| self.proof_expr(td, heap, e))).collect::<Vec<_>>()),
+      _ => LispVal::undef(),
+    }
+  }
+
   /// Pretty-prints a unification error, as `failed to unify: e1 =?= e2`.
   pub fn unify_err(&'a self, e1: &LispVal, e2: &LispVal) -> RefDoc<'a> {
     let doc = self.append_doc(s!("failed to unify:"), Self::line());
@@ -546,7 +606,14 @@ pub struct PpExpr<'a> {
 impl<'a> FormatEnv<'a> {
   /// Construct a `Pretty<'a>` object, and use it within the scope of the input function `f`.
   pub fn pretty<T>(self, f: impl for<'b> FnOnce(&'b Pretty<'b>) -> T) -> T {
-    f(&Pretty::new(self, &Arena::new()))
+    f(&Pretty::new(self, &Arena::new(), true))
+  }
+
+  /// Construct a `Pretty<'a>` object which does not use notations, so that math formulas
+  /// are printed fully parenthesized as applications of the term constructors, like
+  /// `$ im a (not b) $` instead of `$ a -> ~b $`, and use it within the scope of `f`.
+  pub fn pretty_desugared<T>(self, f: impl for<'b> FnOnce(&'b Pretty<'b>) -> T) -> T {
+    f(&Pretty::new(self, &Arena::new(), false))
   }
 
   /// Pretty-print an expression at the given display width. The returned struct implements
@@ -13,7 +13,7 @@ use std::ops::Deref;
 use std::fmt::{self, Display};
 use itertools::Itertools;
 use mm0_util::alphanumber;
-use crate::{AtomId, LispKind, LispVal, lisp::{Uncons, InferTarget, Proc, ProcPos},
+use crate::{AtomId, LispKind, LispVal, lisp::{too_many_readers, Uncons, InferTarget, Proc, ProcPos},
   LinedString, Environment, Elaborator, TermId, ThmId, SortId,
   Sort, Term, Thm, DeclKey, ast::{SExpr, SExprKind, span_atom}};
 
@@ -140,6 +140,7 @@ impl EnvDisplay for LispKind {
       LispKind::List(es) => list(es, None, true, fe, f),
       LispKind::Annot(_, e) => e.fmt(fe, f),
       LispKind::Number(n) => n.fmt(f),
+      LispKind::Rational(q) => q.fmt(f),
       LispKind::String(s) => {
         write!(f, "\"")?;
         for &c in &**s {
@@ -171,9 +172,12 @@ impl EnvDisplay for LispKind {
         write!(f, "#[fn {} at {} {}:{}]", x, fname, r.line + 1, r.character + 1)
       }
       LispKind::Proc(Proc::MatchCont(_)) => write!(f, "#[match cont]"),
+      LispKind::Proc(Proc::DelimCont(..)) => write!(f, "#[cont]"),
       LispKind::Proc(Proc::RefineCallback) => write!(f, "#[refine]"),
       LispKind::Proc(Proc::ProofThunk(x, _)) => write!(f, "#[proof of {}]", fe.to(x)),
       LispKind::Proc(Proc::MergeMap(_)) => write!(f, "#[merge-map]"),
+      LispKind::Proc(Proc::Macro(e)) => write!(f, "#[macro {}]", fe.to(e)),
+      LispKind::Proc(Proc::Future(_)) => write!(f, "#[future]"),
       #[cfg(feature = "mmc")]
       LispKind::Proc(Proc::MmcCompiler(_)) => write!(f, "#[mmc-compiler]"),
       LispKind::AtomMap(m) => {
@@ -181,6 +185,18 @@ impl EnvDisplay for LispKind {
         for (a, v) in m {write!(f, " [{} {}]", fe.data[*a].name, fe.to(v))?}
         write!(f, ")")
       }
+      LispKind::HashTable(m) => {
+        write!(f, "(hash-new")?;
+        for (k, v) in m {write!(f, " [{} {}]", fe.to(&k.to_lisp()), fe.to(v))?}
+        write!(f, ")")
+      }
+      LispKind::Vector(v) if too_many_readers(v) => write!(f, "#[vector]"),
+      LispKind::Vector(v) => {
+        write!(f, "(vector")?;
+        for e in &*v.borrow() {write!(f, " {}", fe.to(e))?}
+        write!(f, ")")
+      }
+      LispKind::StringPort(_) => write!(f, "#[port]"),
       LispKind::Ref(m) if m.too_many_readers() => write!(f, "#[ref]"),
       LispKind::Ref(m) => m.get(|e| e.fmt(fe, f)),
       &LispKind::MVar(n, _) => write!(f, "?{}", alphanumber(n)),
@@ -263,6 +279,7 @@ impl EnvDisplay for SExpr {
       &SExprKind::Atom(a) => {
         unsafe {std::str::from_utf8_unchecked(span_atom(fe.source, self.span, a))}.fmt(f)
       }
+      SExprKind::Name(s) => s.fmt(f),
       SExprKind::List(es) => {
         let mut it = es.iter();
         match it.next() {
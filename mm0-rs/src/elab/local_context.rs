@@ -5,10 +5,10 @@ use std::ops::Deref;
 use std::mem;
 use std::collections::{HashMap, hash_map::Entry};
 use itertools::Itertools;
-use crate::{AtomId, TermKind, ThmKind, Type as EType, Span, FileSpan, BoxError, MAX_BOUND_VARS};
+use crate::{AtomId, DeclKey, TermKind, ThmKind, Type as EType, Span, FileSpan, BoxError, MAX_BOUND_VARS, WarningCode};
 use crate::ast::{Decl, Type, DepType, LocalKind};
 use super::{Coe, DeclKind, DerefMut, DocComment, ElabError, Elaborator, Environment,
-  Expr, Modifiers, ObjectKind, Proof, Result, SExprKind, SortId, Term, TermId, Thm};
+  Expr, Modifiers, ObjectKind, Proof, Remap, Remapper, Result, SExprKind, SortId, Term, TermId, Thm};
 use super::lisp::{LispVal, LispKind, Uncons, InferTarget, print::FormatEnv};
 use super::proof::{NodeHasher, ProofKind, ProofHash, build, Dedup};
 
@@ -64,6 +64,20 @@ impl InferSort {
   }
 }
 
+impl Remap for InferSort {
+  type Target = Self;
+  fn remap(&self, r: &mut Remapper) -> Self {
+    match self {
+      InferSort::Bound(s) => InferSort::Bound(s.remap(r)),
+      InferSort::Reg(s, deps) => InferSort::Reg(s.remap(r), deps.remap(r)),
+      &InferSort::Unknown {src, must_bound, dummy, ref sorts} => InferSort::Unknown {
+        src, must_bound, dummy,
+        sorts: Box::new(sorts.iter().map(|(s, e)| (s.remap(r), e.remap(r))).collect()),
+      },
+    }
+  }
+}
+
 /// The local context is the collection of proof-local data. This is manipulated
 /// by lisp tactics in order to keep track of the proof state and eventually produce a proof.
 #[derive(Default, Debug, EnvDebug, DeepSizeOf)]
@@ -87,6 +101,21 @@ pub struct LocalContext {
   /// This can be manipulated by user code, but the builtin tactics will manage this list
   /// automatically. When the set of goals is empty, the proof is complete.
   pub goals: Vec<LispVal>,
+  /// The queue of postponed unification problems. When `refine` cannot assign a
+  /// metavariable yet because of the occurs check, as in `?a =?= (f ?a)`, the problem is
+  /// added to this list instead of failing, and it is retried after later unifications,
+  /// which may assign `?a` so that `f` can be unfolded. The value is the span of the
+  /// unification, a reference to the conversion proof of the problem, and the two sides.
+  /// The problems that remain at the end of the statement are reported as errors.
+  pub constraints: Vec<(Span, LispVal, LispVal, LispVal)>,
+  /// The holes of the proof, which are the goals that were admitted with `(sorry)`, or
+  /// which come from an explicit `_` in the proof and are left unproved at the end of the
+  /// proof, with their location and statement. A theorem with holes is added to the
+  /// environment without a proof.
+  pub holes: Vec<(Span, LispVal)>,
+  /// The goals created by an explicit `_` in a proof. If one of them is still unsolved at
+  /// the end of the proof, it is admitted as a hole instead of being reported as an error.
+  pub explicit_holes: Vec<LispVal>,
   /// The proof name map. The keys are subproof name bindings created by `have` or hypothesis
   /// names from the initial proof state, and the values are indexes into `proof_order`.
   pub proofs: HashMap<AtomId, usize>,
@@ -102,6 +131,25 @@ pub struct LocalContext {
   pub closer: LispVal,
 }
 
+impl Remap for LocalContext {
+  type Target = Self;
+  fn remap(&self, r: &mut Remapper) -> Self {
+    LocalContext {
+      vars: self.vars.iter().map(|(a, v)| (a.remap(r), v.remap(r))).collect(),
+      var_order: self.var_order.iter().map(|(sp, a, is)| (*sp, a.remap(r), is.remap(r))).collect(),
+      mvars: self.mvars.remap(r),
+      goals: self.goals.remap(r),
+      constraints: self.constraints.iter().map(|(sp, c, e1, e2)|
+        (*sp, c.remap(r), e1.remap(r), e2.remap(r))).collect(),
+      holes: self.holes.iter().map(|(sp, e)| (*sp, e.remap(r))).collect(),
+      explicit_holes: self.explicit_holes.remap(r),
+      proofs: self.proofs.iter().map(|(a, &i)| (a.remap(r), i)).collect(),
+      proof_order: self.proof_order.remap(r),
+      closer: self.closer.remap(r),
+    }
+  }
+}
+
 fn new_mvar(mvars: &mut Vec<LispVal>, tgt: InferTarget, sp: Option<FileSpan>) -> LispVal {
   let n = mvars.len();
   let e = LispVal::new(LispKind::MVar(n, tgt));
@@ -121,6 +169,9 @@ impl LocalContext {
     self.var_order.clear();
     self.mvars.clear();
     self.goals.clear();
+    self.constraints.clear();
+    self.holes.clear();
+    self.explicit_holes.clear();
     self.proofs.clear();
     self.proof_order.clear();
     self.closer = LispVal::undef();
@@ -209,6 +260,9 @@ struct ElabTerm<'a> {
 struct ElabTermMut<'a> {
   elab: &'a mut Elaborator,
   fsp: FileSpan,
+  /// The errors in subterms that have been recovered from, by replacing the
+  /// subterm with a metavariable.
+  errors: Vec<ElabError>,
 }
 
 impl<'a> Deref for ElabTermMut<'a> {
@@ -306,8 +360,8 @@ impl<'a> ElabTerm<'a> {
       },
       LispKind::List(es) if !es.is_empty() => {
         let a = es[0].as_atom().ok_or_else(|| self.err(&es[0], "expected an atom"))?;
-        let tid = self.fe.term(a).ok_or_else(||
-          self.err(&es[0], format!("term '{}' not declared", self.fe.data[a].name)))?;
+        let tid = self.fe.term(a).ok_or_else(|| self.err(&es[0], format!("term '{}' not declared{}",
+          self.fe.data[a].name, self.fe.did_you_mean(a, |_, ad| matches!(ad.decl, Some(DeclKey::Term(_)))))))?;
         Ok(self.fe.terms[tid].ret.0)
       }
       _ => Err(self.err(e, "invalid expression"))
@@ -317,7 +371,7 @@ impl<'a> ElabTerm<'a> {
 
 impl<'a> ElabTermMut<'a> {
   fn new(elab: &'a mut Elaborator, sp: Span) -> Self {
-    ElabTermMut { fsp: elab.fspan(sp), elab }
+    ElabTermMut { fsp: elab.fspan(sp), elab, errors: vec![] }
   }
 
   fn as_ref(&self) -> ElabTerm<'_> {
@@ -380,9 +434,13 @@ impl<'a> ElabTermMut<'a> {
       return Err(self.as_ref().err(&t,
         format!("term '{}' is shadowed by a local variable", self.env.data[a].name)))
     }
-    let tid = self.env.term(a).ok_or_else(||
-      self.as_ref().err(&t, format!("term '{}' not declared", self.env.data[a].name)))?;
+    let a = self.resolve_name(a);
+    let tid = self.env.term(a).ok_or_else(|| self.as_ref().err(&t, format!("term '{}' not declared{}",
+      self.env.data[a].name, self.env.did_you_mean(a, |_, ad| matches!(ad.decl, Some(DeclKey::Term(_)))))))?;
     let sp1 = self.as_ref().try_get_span(e);
+    let sp2 = self.as_ref().try_get_span(&t);
+    self.check_visible(sp2, a)?;
+    self.check_deprecated(sp2, a);
     self.spans_insert(&t, || ObjectKind::Term(tid, sp1));
     let tdata = &self.env.terms[tid];
     let nargs = tdata.args.len();
@@ -397,7 +455,16 @@ impl<'a> ElabTermMut<'a> {
         Some(&(_, EType::Bound(s))) => InferTarget::Bound(self.env.sorts[s].atom),
         Some(&(_, EType::Reg(s, _))) => InferTarget::Reg(self.env.sorts[s].atom),
       };
-      args.push(self.expr(&arg, tgt)?);
+      // If an argument fails to elaborate, record the error and continue with a
+      // metavariable in its place, so that errors in the other arguments are also found.
+      match self.expr(&arg, tgt) {
+        Ok(v) => args.push(v),
+        Err(err) => {
+          self.errors.push(err);
+          let fsp = arg.fspan().or_else(|| Some(self.fsp.clone()));
+          args.push(self.elab.lc.new_mvar(tgt, fsp))
+        }
+      }
     }
     if next_ty!().is_some() {
       return Err(ElabError::new_e(sp1,
@@ -422,7 +489,7 @@ impl<'a> ElabTermMut<'a> {
   fn expr(&mut self, e: &LispVal, tgt: InferTarget) -> Result<LispVal> {
     e.unwrapped(|r| match r {
       &LispKind::Atom(a) if self.lc.vars.contains_key(&a) => self.atom(e, a, tgt),
-      &LispKind::Atom(a) if self.env.term(a).is_some() =>
+      &LispKind::Atom(a) if self.env.term(self.resolve_name(a)).is_some() =>
         self.list(e, Some(e.clone()).into_iter(), tgt),
       &LispKind::Atom(a) => self.atom(e, a, tgt),
       LispKind::List(_) | LispKind::DottedList(_, _) if e.is_list() => match e.len() {
@@ -527,7 +594,8 @@ impl Elaborator {
   /// on dummy variables. The bool in the result's pair indicates whether the variable is a dummy variable.
   fn elab_dep_type(&mut self, error: &mut bool, lk: LocalKind, d: &DepType) -> Result<(bool, InferSort)> {
     let a = self.env.get_atom(self.ast.span(d.sort));
-    let sort = self.data[a].sort.ok_or_else(|| ElabError::new_e(d.sort, "sort not found"))?;
+    let sort = self.data[a].sort.ok_or_else(|| ElabError::new_e(d.sort,
+      format!("sort not found{}", self.did_you_mean(a, |_, ad| ad.sort.is_some()))))?;
     self.spans.insert(d.sort, ObjectKind::Sort(sort));
     Ok(if lk.is_bound() {
       if let Some(&Span {end, ..}) = d.deps.last() {
@@ -589,8 +657,25 @@ impl Elaborator {
   }
 
   /// Elaborate a term used in a theorem statement.
+  ///
+  /// Errors in independent subterms are all reported: all but the last are reported
+  /// directly, and the last one is returned.
   pub fn elaborate_term(&mut self, sp: Span, e: &LispVal, tgt: InferTarget) -> Result<LispVal> {
-    ElabTermMut::new(self, sp).expr(e, tgt)
+    let n = self.lc.mvars.len();
+    let mut et = ElabTermMut::new(self, sp);
+    let res = et.expr(e, tgt);
+    let mut errs = mem::take(&mut et.errors);
+    match res {
+      Ok(e) if errs.is_empty() => return Ok(e),
+      Ok(_) => {}
+      Err(e) => errs.push(e),
+    }
+    // The term is discarded, so forget the metavariables created for it,
+    // including those standing in for the subterms with errors.
+    self.lc.mvars.truncate(n);
+    let last = errs.pop().expect("nonempty");
+    for e in errs { self.report(e) }
+    Err(last)
   }
 
   /// Get the sort of an expression, assuming it is well typed.
@@ -660,6 +745,20 @@ impl Elaborator {
     errs
   }
 
+  /// Admit the goals `gs` without proof. Each unsolved goal is reported with a warning,
+  /// recorded in the list of holes with its location (using `fsp` if it has none), and
+  /// assigned to `:sorry`.
+  pub fn admit_goals(&mut self, fsp: &FileSpan, gs: impl IntoIterator<Item=LispVal>) {
+    for g in gs {
+      if let Some(ty) = g.goal_type() {
+        let sp = try_get_span(fsp, &g);
+        self.report(ElabError::warn(sp, format!("|- {}", self.format_env().pp(&ty, 80))));
+        self.lc.holes.push((sp, ty));
+        g.as_ref_(|e| *e = LispVal::atom(AtomId::SORRY));
+      }
+    }
+  }
+
   /// Elaborate a declaration (`term`, `axiom`, `def`, `theorem`).
   pub fn elab_decl(&mut self, full: Span, d: &Decl, doc: Option<DocComment>) -> Result<()> {
     let mut e_hyps = Vec::new();
@@ -691,12 +790,22 @@ impl Elaborator {
           if self.lc.push_var(bi.local.unwrap_or(bi.span), x, is) {
             report!(bi.local.expect("this can't happen unless the variable was written explicitly"),
               "variable occurs twice in binder list");
+          } else if let Some(x) = x.filter(|&x| self.env.term(x).is_some()) {
+            self.report(ElabError::lint(WarningCode::ShadowedBinder, bi.local.unwrap_or(bi.span),
+              format!("variable '{}' shadows a term", self.data[x].name)))
+          }
+        }
+        Ok(InferBinder::Hyp(x, e)) => {
+          if let Some(x) = x.filter(|&x| e_hyps.iter().any(|&(_, y, _)| y == Some(x))) {
+            self.report(ElabError::lint(WarningCode::ShadowedBinder, bi.local.unwrap_or(bi.span),
+              format!("hypothesis '{}' shadows an earlier hypothesis", self.data[x].name)))
           }
+          e_hyps.push((bi, x, e))
         }
-        Ok(InferBinder::Hyp(x, e)) => e_hyps.push((bi, x, e)),
       }
     }
-    let atom = self.env.get_atom(self.ast.span(d.id));
+    let ast = self.ast.clone();
+    let atom = self.decl_atom(ast.span(d.id));
     self.spans.set_decl(atom);
     if self.mm0_mode && atom == AtomId::UNDER {
       self.report(ElabError::warn(d.id, "(MM0 mode) declaration name required"))
@@ -825,7 +934,8 @@ impl Elaborator {
         if d.val.is_none() {
           for bi in &d.bis {
             if bi.kind == LocalKind::Dummy {
-              self.report(ElabError::warn(bi.local.unwrap_or(bi.span), "useless dummy variable"))
+              self.report(ElabError::lint(WarningCode::UselessDummy,
+                bi.local.unwrap_or(bi.span), "useless dummy variable"))
             }
           }
         }
@@ -886,30 +996,50 @@ impl Elaborator {
               (|| -> Result<Option<Proof>> {
                 let mut de: Dedup<ProofHash> = de.map_proof();
                 let mut is2 = Vec::new();
-                for (i, (_, a, e)) in e_hyps.into_iter().enumerate() {
+                let mut named = Vec::new();
+                for (i, (bi, a, e)) in e_hyps.into_iter().enumerate() {
                   if let Some(a) = a {
                     let p = LispVal::atom(a);
                     is2.push(de.add(ProofKind::Proof, p.clone(), ProofHash::Hyp(i, is[i].1)));
+                    named.push((bi.local.unwrap_or(bi.span), a, is2.len() - 1));
                     self.lc.add_proof(a, e, p)
                   }
                 }
                 let g = LispVal::new_ref(LispVal::goal(self.fspan(e.span), e_ret));
                 self.lc.goals = vec![g.clone()];
                 self.elab_lisp(e)?;
+                for (g, msg) in self.resolve_instance_goals() {
+                  report!(try_get_span(&span, &g), msg)
+                }
+                for e in self.unsolved_constraints() {report!(e)}
                 if !self.lc.goals.is_empty() {
                   let stat = self.stat();
                   self.call_goal_listener(&stat);
                 }
-                for g in mem::take(&mut self.lc.goals) {
+                let (holes, gs): (Vec<_>, _) = mem::take(&mut self.lc.goals).into_iter()
+                  .partition(|g| self.lc.explicit_holes.iter().any(|h| h.ptr_eq(g)));
+                for g in gs {
                   report!(try_get_span(&span, &g),
                     format!("|- {}", self.format_env().pp(&g.goal_type().expect("expected a goal"), 80)))
                 }
-                if error {return Ok(None)}
+                self.admit_goals(&span, holes);
+                if error || !self.lc.holes.is_empty() {return Ok(None)}
                 let nh = NodeHasher {var_map, fsp, fe: self.format_env(), lc: &self.lc};
                 let ip = de.dedup(&nh, ProofKind::Proof, &g)?;
+                // A hypothesis is marked as shared by `dedup` when the proof refers to it.
+                for (sp, a, j) in named {
+                  if !de.vec[is2[j]].1 && !self.data[a].name.starts_with(b"_") {
+                    self.report(ElabError::lint(WarningCode::UnusedHypothesis, sp,
+                      format!("hypothesis '{}' is never used", self.data[a].name)))
+                  }
+                }
                 let (mut ids, heap) = build(&de);
-                let hyps = is2.into_iter().map(|i| ids[i].take()).collect();
-                Ok(Some(Proof {heap, hyps, head: ids[ip].take()}))
+                let hyps = is2.iter().map(|&i| ids[i].take()).collect();
+                let mut pr = Proof {heap, hyps, head: ids[ip].take(), spans: Box::new([])};
+                if crate::get_minimize_proofs() { pr.minimize(args.len()); } else {
+                  pr.spans = de.proof_spans(&ids, &pr, &is2, ip)
+                }
+                Ok(Some(pr))
               })().unwrap_or_else(|e| {self.report(e); None})
             } else {None}
           })
@@ -969,7 +1099,7 @@ fn dummies(fe: FormatEnv<'_>, fsp: &FileSpan, lc: &mut LocalContext, e: &LispVal
   let mut dummy = |x: AtomId, es: &LispKind| -> Result<()> {
     let s = es.as_atom().ok_or_else(|| ElabError::new_e(sp!(es), "expected an atom"))?;
     let sort = fe.data[s].sort.ok_or_else(|| ElabError::new_e(sp!(es),
-      format!("unknown sort '{}'", fe.to(&s))))?;
+      format!("unknown sort '{}'{}", fe.to(&s), fe.did_you_mean(s, |_, ad| ad.sort.is_some()))))?;
     if x != AtomId::UNDER {lc.vars.insert(x, (true, InferSort::Bound(sort)));}
     Ok(())
   };
@@ -1019,7 +1149,7 @@ impl Elaborator {
         let a = if a == AtomId::UNDER {None} else {Some(a)};
         let s = es.as_atom().ok_or_else(|| ElabError::new_e(sp!(es), "expected an atom"))?;
         let sort = self.data[s].sort.ok_or_else(|| ElabError::new_e(sp!(es),
-          format!("unknown sort '{}'", self.print(&s))))?;
+          format!("unknown sort '{}'{}", self.print(&s), self.did_you_mean(s, |_, ad| ad.sort.is_some()))))?;
         let (is, ty) = match u.next() {
           None => {
             if let Some(a) = a {
@@ -1076,14 +1206,14 @@ impl Elaborator {
     let (mut lc, args) = self.binders(fsp, Uncons::from(args.clone()), &mut vars)?;
     let ret = if let Some(s) = ret.as_atom() {
       let s = self.data[s].sort.ok_or_else(|| ElabError::new_e(sp!(ret),
-        format!("unknown sort '{}'", self.print(&s))))?;
+        format!("unknown sort '{}'{}", self.print(&s), self.did_you_mean(s, |_, ad| ad.sort.is_some()))))?;
       (s, 0)
     } else {
       let mut u = Uncons::from(ret.clone());
       if let (Some(e), Some(vs)) = (u.next(), u.next()) {
         let s = e.as_atom().ok_or_else(|| ElabError::new_e(sp!(e), "expected an atom"))?;
         let s = self.data[s].sort.ok_or_else(|| ElabError::new_e(sp!(e),
-          format!("unknown sort '{}'", self.print(&s))))?;
+          format!("unknown sort '{}'{}", self.print(&s), self.did_you_mean(s, |_, ad| ad.sort.is_some()))))?;
         (s, self.deps(fsp, &vars.0, vs)?.1)
       } else {
         return Err(ElabError::new_e(sp!(ret), format!("syntax error: {}", self.print(ret))))
@@ -1215,8 +1345,12 @@ impl Elaborator {
           let nh = NodeHasher {var_map, lc, fe, fsp: fsp.clone()};
           let ip = de.dedup(&nh, ProofKind::Proof, &pf)?;
           let (mut ids, heap) = build(&de);
-          let hyps = is2.into_iter().map(|i| ids[i].take()).collect();
-          Ok(Some(Proof {heap, hyps, head: ids[ip].take()}))
+          let hyps = is2.iter().map(|&i| ids[i].take()).collect();
+          let mut pr = Proof {heap, hyps, head: ids[ip].take(), spans: Box::new([])};
+          if crate::get_minimize_proofs() { pr.minimize(t.args.len()); } else {
+            pr.spans = de.proof_spans(&ids, &pr, &is2, ip)
+          }
+          Ok(Some(pr))
         })().unwrap_or_else(|e| {
           self.report(ElabError::new_e(e.pos,
             format!("while adding {}: {}", self.print(&t.atom), e.kind.msg())));
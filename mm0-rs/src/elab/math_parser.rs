@@ -202,7 +202,9 @@ impl<'a> MathParser<'a> {
           })
         }
       }
-    } else if ident_start(c) && (sp.start + 1..sp.end).all(|i| ident_rest(self.source[i])) {
+    } else if ident_start(c) && (sp.start + 1..sp.end).all(|i| ident_rest(self.source[i]) ||
+      // qualified names like `foo.bar`
+      self.source[i] == b'.' && i + 1 < sp.end && ident_start(self.source[i + 1])) {
       let mut args = Vec::new();
       let mut start = self.idx;
       let mut span = sp;
@@ -0,0 +1,202 @@
+//! Proof term minimization, an optional pass which shrinks the stored proofs of theorems
+//! after they are elaborated, enabled by `mm0-rs compile --minimize-proofs`.
+//!
+//! The pass rewrites the [`ProofNode`] dag of a [`Proof`] as follows:
+//!
+//! * Conversions that do nothing are removed: `Conv(e, c, p)` becomes `p` when `c` is a
+//!   reflexivity proof, `Cong` of reflexivity proofs becomes `Refl`, and `Sym` of `Refl`
+//!   and of `Sym` are simplified.
+//! * Heap entries that are not used by the proof are removed, and heap entries that are
+//!   used only once are inlined at their use.
+//!
+//! The size of a proof is the number of nodes in its heap, hypotheses and head, and the
+//! total size of the minimized proofs before and after minimization is available from
+//! [`stats`].
+
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::{Proof, ProofNode};
+
+static SIZE_BEFORE: AtomicUsize = AtomicUsize::new(0);
+static SIZE_AFTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Get the total size of the proofs that have been minimized, before and after minimization.
+pub fn stats() -> (usize, usize) {
+  (SIZE_BEFORE.load(Ordering::Relaxed), SIZE_AFTER.load(Ordering::Relaxed))
+}
+
+/// The number of nodes in `p`, not counting the heap entries it refers to.
+fn size(p: &ProofNode) -> usize {
+  1 + match p {
+    ProofNode::Ref(_) | ProofNode::Dummy(_, _) => 0,
+    ProofNode::Term {args, ..} | ProofNode::Cong {args, ..} => args.iter().map(size).sum(),
+    ProofNode::Hyp(_, e) | ProofNode::Refl(e) | ProofNode::Sym(e) => size(e),
+    ProofNode::Thm {args, res, ..} => args.iter().map(size).sum::<usize>() + size(res),
+    ProofNode::Conv(p) => size(&p.0) + size(&p.1) + size(&p.2),
+    ProofNode::Unfold {args, res, ..} =>
+      args.iter().map(size).sum::<usize>() + size(&res.0) + size(&res.1),
+  }
+}
+
+/// Call `f` on the index of every heap reference in `p`.
+fn for_each_ref(p: &ProofNode, f: &mut impl FnMut(usize)) {
+  match p {
+    &ProofNode::Ref(i) => f(i),
+    ProofNode::Dummy(_, _) => {}
+    ProofNode::Term {args, ..} | ProofNode::Cong {args, ..} =>
+      for e in &**args { for_each_ref(e, f) },
+    ProofNode::Hyp(_, e) | ProofNode::Refl(e) | ProofNode::Sym(e) => for_each_ref(e, f),
+    ProofNode::Thm {args, res, ..} => {
+      for e in &**args { for_each_ref(e, f) }
+      for_each_ref(res, f)
+    }
+    ProofNode::Conv(p) => {
+      for_each_ref(&p.0, f);
+      for_each_ref(&p.1, f);
+      for_each_ref(&p.2, f)
+    }
+    ProofNode::Unfold {args, res, ..} => {
+      for e in &**args { for_each_ref(e, f) }
+      for_each_ref(&res.0, f);
+      for_each_ref(&res.1, f)
+    }
+  }
+}
+
+/// Replace every heap reference in `p` using `f`.
+fn map_refs(p: ProofNode, f: &mut impl FnMut(usize) -> ProofNode) -> ProofNode {
+  let map_all = |es: Box<[ProofNode]>, f: &mut _|
+    es.into_vec().into_iter().map(|e| map_refs(e, f)).collect();
+  match p {
+    ProofNode::Ref(i) => f(i),
+    ProofNode::Dummy(_, _) => p,
+    ProofNode::Term {term, args} => ProofNode::Term {term, args: map_all(args, f)},
+    ProofNode::Cong {term, args} => ProofNode::Cong {term, args: map_all(args, f)},
+    ProofNode::Hyp(i, e) => ProofNode::Hyp(i, Box::new(map_refs(*e, f))),
+    ProofNode::Refl(e) => ProofNode::Refl(Box::new(map_refs(*e, f))),
+    ProofNode::Sym(e) => ProofNode::Sym(Box::new(map_refs(*e, f))),
+    ProofNode::Thm {thm, args, res} => {
+      let args = map_all(args, f);
+      ProofNode::Thm {thm, args, res: Box::new(map_refs(*res, f))}
+    }
+    ProofNode::Conv(p) => {
+      let (e, c, p) = *p;
+      ProofNode::Conv(Box::new((map_refs(e, f), map_refs(c, f), map_refs(p, f))))
+    }
+    ProofNode::Unfold {term, args, res} => {
+      let args = map_all(args, f);
+      let (e, c) = *res;
+      ProofNode::Unfold {term, args, res: Box::new((map_refs(e, f), map_refs(c, f)))}
+    }
+  }
+}
+
+/// The proof minimizer for one proof. The heap entries from `nargs` on are proof nodes,
+/// while the first `nargs` are the variables.
+struct Minimizer<'a> {
+  heap: &'a [ProofNode],
+  nargs: usize,
+}
+
+impl Minimizer<'_> {
+  /// If `c` is a reflexivity proof `Refl(e)`, possibly behind a reference, return `e`.
+  fn refl<'b>(&'b self, c: &'b ProofNode) -> Option<&'b ProofNode> {
+    match *c {
+      ProofNode::Ref(i) if i >= self.nargs => self.refl(&self.heap[i]),
+      ProofNode::Refl(ref e) => Some(e),
+      _ => None,
+    }
+  }
+
+  /// Simplify the conversions in `p`. The heap entries that `p` refers to should already
+  /// be simplified.
+  fn simplify(&self, p: ProofNode) -> ProofNode {
+    let simplify_all = |es: Box<[ProofNode]>|
+      es.into_vec().into_iter().map(|e| self.simplify(e)).collect();
+    match p {
+      ProofNode::Ref(_) | ProofNode::Dummy(_, _) | ProofNode::Term {..} |
+      ProofNode::Hyp(_, _) | ProofNode::Refl(_) => p,
+      ProofNode::Thm {thm, args, res} => ProofNode::Thm {thm, args: simplify_all(args), res},
+      ProofNode::Conv(p) => {
+        let (e, c, p) = *p;
+        let (c, p) = (self.simplify(c), self.simplify(p));
+        if self.refl(&c).is_some() {p} else {ProofNode::Conv(Box::new((e, c, p)))}
+      }
+      ProofNode::Sym(c) => match self.simplify(*c) {
+        c if self.refl(&c).is_some() => c,
+        ProofNode::Sym(c) => *c,
+        c => ProofNode::Sym(Box::new(c)),
+      },
+      ProofNode::Cong {term, args} => {
+        let args: Box<[ProofNode]> = simplify_all(args);
+        if let Some(es) = args.iter().map(|c| self.refl(c).cloned()).collect::<Option<Box<[_]>>>() {
+          ProofNode::Refl(Box::new(ProofNode::Term {term, args: es}))
+        } else {
+          ProofNode::Cong {term, args}
+        }
+      }
+      ProofNode::Unfold {term, args, res} => {
+        let (e, c) = *res;
+        ProofNode::Unfold {term, args, res: Box::new((e, self.simplify(c)))}
+      }
+    }
+  }
+}
+
+impl Proof {
+  /// Minimize this proof of a theorem with `nargs` arguments, as described in the
+  /// [module documentation](self). Returns the size of the proof before and after.
+  pub fn minimize(&mut self, nargs: usize) -> (usize, usize) {
+    let before = self.size(nargs);
+    let mut heap = mem::take(&mut self.heap).into_vec();
+    for i in nargs..heap.len() {
+      let p = mem::replace(&mut heap[i], ProofNode::Ref(i));
+      heap[i] = Minimizer {heap: &heap, nargs}.simplify(p);
+    }
+    let m = Minimizer {heap: &heap, nargs};
+    let hyps: Vec<_> = mem::take(&mut self.hyps).into_vec().into_iter()
+      .map(|p| m.simplify(p)).collect();
+    let head = m.simplify(mem::replace(&mut self.head, ProofNode::Ref(0)));
+
+    // Count the uses of each heap entry reachable from the hypotheses and the head.
+    // Heap entries only refer to earlier entries, so a reverse pass suffices.
+    let mut uses = vec![0_u32; heap.len()];
+    for p in hyps.iter().chain(Some(&head)) { for_each_ref(p, &mut |i| uses[i] += 1) }
+    for i in (nargs..heap.len()).rev() {
+      if uses[i] != 0 { for_each_ref(&heap[i], &mut |j| uses[j] += 1) }
+    }
+
+    // Rebuild the heap, keeping the entries with more than one use. `subst[i]` is what
+    // a reference to entry `i` becomes, which is taken if the entry is inlined.
+    let mut subst: Vec<Option<ProofNode>> = (0..nargs).map(|i| Some(ProofNode::Ref(i))).collect();
+    let rename = |p: ProofNode, subst: &mut Vec<Option<ProofNode>>| map_refs(p, &mut |j|
+      match &subst[j] {
+        Some(r @ ProofNode::Ref(_)) => r.clone(),
+        _ => subst[j].take().expect("heap entry used more than once"),
+      });
+    let mut new_heap: Vec<_> = heap.drain(..nargs).collect();
+    for (p, n) in heap.into_iter().zip(&uses[nargs..]) {
+      let p = if *n == 0 {None} else {
+        let p = rename(p, &mut subst);
+        Some(if *n == 1 || matches!(p, ProofNode::Ref(_)) {p} else {
+          new_heap.push(p);
+          ProofNode::Ref(new_heap.len() - 1)
+        })
+      };
+      subst.push(p)
+    }
+    self.hyps = hyps.into_iter().map(|p| rename(p, &mut subst)).collect();
+    self.head = rename(head, &mut subst);
+    self.heap = new_heap.into();
+    let after = self.size(nargs);
+    SIZE_BEFORE.fetch_add(before, Ordering::Relaxed);
+    SIZE_AFTER.fetch_add(after, Ordering::Relaxed);
+    (before, after)
+  }
+
+  /// The size of this proof of a theorem with `nargs` arguments, as the number of nodes
+  /// in the heap (excluding the variables), the hypotheses and the head.
+  #[must_use] pub fn size(&self, nargs: usize) -> usize {
+    self.heap[nargs..].iter().chain(&*self.hyps).chain(Some(&self.head)).map(size).sum()
+  }
+}
@@ -0,0 +1,145 @@
+//! Sections and namespaces, which delimit the scope of local notations and qualify names.
+//!
+//! A `section foo; ... end foo;` block delimits the scope of the `local` notations declared
+//! in it. A `namespace foo; ... end foo;` block does the same, and in addition the terms,
+//! definitions, axioms and theorems declared in it are given qualified names `foo.x`. Inside
+//! the namespace, and after an `open foo;` command, a declaration `foo.x` can also be referred
+//! to as `x`. An `open` command is in effect until the end of the enclosing section or
+//! namespace.
+//!
+//! Name resolution happens when an atom is used as the head of a term or proof, in math
+//! expressions and in `refine`. An atom `x` is resolved to the first declared atom among
+//! `ns.x` for the enclosing namespaces `ns` (innermost first), `x` itself, and `o.x` for the
+//! open namespaces `o` (most recently opened first).
+
+use crate::{ArcString, AtomId, Span};
+use crate::ast::{Stmt, StmtKind};
+use super::{ElabError, Elaborator, Result};
+
+/// A `section` or `namespace` that is open at the current point of elaboration.
+#[derive(Clone, Debug, DeepSizeOf)]
+struct Scope {
+  /// The span of the `section` or `namespace` command.
+  span: Span,
+  /// The name of the section or namespace, if given.
+  name: Option<ArcString>,
+  /// The full name of the namespace, like `foo.bar`, if this is a namespace.
+  ns: Option<ArcString>,
+  /// The number of open namespaces when this scope was entered.
+  opens: usize,
+}
+
+/// The sections and namespaces that are open at the current point of elaboration.
+#[derive(Clone, Debug, Default, DeepSizeOf)]
+pub(crate) struct Scopes {
+  /// The open sections and namespaces, innermost last.
+  stack: Vec<Scope>,
+  /// The namespaces brought into scope by `open` commands, most recent last.
+  opens: Vec<ArcString>,
+}
+
+impl Scopes {
+  /// The full name of the innermost namespace, if any.
+  fn namespace(&self) -> Option<&ArcString> { self.stack.iter().rev().find_map(|s| s.ns.as_ref()) }
+}
+
+/// Append `.x` to the namespace `ns`.
+pub(crate) fn qualify(ns: &[u8], x: &[u8]) -> Vec<u8> {
+  let mut s = Vec::with_capacity(ns.len() + 1 + x.len());
+  s.extend_from_slice(ns);
+  s.push(b'.');
+  s.extend_from_slice(x);
+  s
+}
+
+impl Elaborator {
+  /// Get the atom for the name of a declaration `x`, which is `ns.x` inside namespace `ns`.
+  pub(crate) fn decl_atom(&mut self, x: &[u8]) -> AtomId {
+    match self.scopes.namespace() {
+      Some(ns) if x != b"_" => { let s = qualify(ns, x); self.env.get_atom(&s) }
+      _ => self.env.get_atom(x),
+    }
+  }
+
+  /// Resolve the atom `a`, which is used as the name of a term or theorem, using the
+  /// enclosing and open namespaces, as described in the [module documentation](self).
+  /// Returns `a` if no declaration is found, or if `a` is a local variable or hypothesis.
+  /// A qualified name `F.x` introduced by `import "file" as F;` resolves to `x`.
+  #[must_use] pub(crate) fn resolve_name(&self, a: AtomId) -> AtomId {
+    let a = match self.aliases.get(&a) {
+      Some(&b) if self.data[a].decl.is_none() => b,
+      _ => a,
+    };
+    let Scopes {stack, opens} = &self.scopes;
+    if opens.is_empty() && stack.iter().all(|s| s.ns.is_none()) { return a }
+    if self.lc.vars.contains_key(&a) || self.lc.get_proof(a).is_some() { return a }
+    let x = &*self.data[a].name;
+    let lookup = |ns: &ArcString| self.atoms.get(&*qualify(ns, x)).copied()
+      .filter(|&b| self.data[b].decl.is_some());
+    stack.iter().rev().filter_map(|s| s.ns.as_ref()).find_map(lookup)
+      .or_else(|| self.data[a].decl.map(|_| a))
+      .or_else(|| opens.iter().rev().find_map(lookup))
+      .unwrap_or(a)
+  }
+
+  /// Find the `end` command that closes the `section` or `namespace` command at `span`,
+  /// by scanning the statements of the file.
+  fn find_end(&self, span: Span) -> Option<&Stmt> {
+    let i = self.ast.stmts.iter().position(|s| s.span == span)?;
+    let mut depth = 0_usize;
+    self.ast.stmts[i+1..].iter().find(|s| match s.k {
+      StmtKind::Section(_) | StmtKind::Namespace(_) => { depth += 1; false }
+      StmtKind::End(_) => depth.checked_sub(1).map(|d| depth = d).is_none(),
+      _ => false,
+    })
+  }
+
+  /// Elaborate a `section` command at `span`, or a `namespace` command if `ns` is true.
+  pub(crate) fn elab_section(&mut self, span: Span, name: Option<Span>, ns: bool) -> Result<()> {
+    if self.mm0_mode {
+      return Err(ElabError::new_e(span,
+        if ns {"(MM0 mode) namespaces not allowed"} else {"(MM0 mode) sections not allowed"}))
+    }
+    let name_str = name.map(|sp| ArcString::from(self.span(sp)));
+    let full = if ns {
+      let x = name_str.as_ref().expect("namespace has a name");
+      Some(match self.scopes.namespace() { Some(outer) => qualify(outer, x).into(), None => x.clone() })
+    } else { None };
+    self.scopes.stack.push(Scope {span, name: name_str, ns: full, opens: self.scopes.opens.len()});
+    if self.find_end(span).is_none() {
+      return Err(ElabError::new_e(name.unwrap_or(span),
+        if ns {"namespace is not closed"} else {"section is not closed"}))
+    }
+    Ok(())
+  }
+
+  /// Elaborate an `open` command.
+  pub(crate) fn elab_open(&mut self, span: Span, name: Span) -> Result<()> {
+    if self.mm0_mode {
+      return Err(ElabError::new_e(span, "(MM0 mode) namespaces not allowed"))
+    }
+    let ns = ArcString::from(self.span(name));
+    self.scopes.opens.push(ns);
+    Ok(())
+  }
+
+  /// Elaborate an `end` command at `span`, which closes the innermost section or namespace.
+  pub(crate) fn elab_end(&mut self, span: Span, name: Option<Span>) -> Result<()> {
+    if self.mm0_mode {
+      return Err(ElabError::new_e(span, "(MM0 mode) sections not allowed"))
+    }
+    let scope = self.scopes.stack.pop()
+      .ok_or_else(|| ElabError::new_e(span, "'end' without an open section or namespace"))?;
+    self.scopes.opens.truncate(scope.opens);
+    // The local notations declared in the section go out of scope.
+    let path = self.path.clone();
+    let start = scope.span.start;
+    self.pe.remove_notations(|n| n.local && n.span.file == path && n.span.span.start >= start);
+    if scope.name.as_deref() != name.map(|sp| self.span(sp)) {
+      return Err(ElabError::with_info(name.unwrap_or(span),
+        if scope.ns.is_some() {"namespace name does not match"} else {"section name does not match"}.into(),
+        vec![(self.fspan(scope.span), "opened here".into())]))
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,222 @@
+//! Per-statement profiling of elaboration, used by `mm0-rs compile --profile`.
+//!
+//! If [`ElaborateBuilder::profile`](super::ElaborateBuilder::profile) is set, the elaborator
+//! records a [`StmtProfile`] for every statement other than an `import`, with the time it took
+//! to elaborate, the size of the proofs it produced, and the number of lisp values allocated
+//! while elaborating it. The lisp heap is not measured directly, so the allocation count is
+//! an upper bound on how much the heap grew during the statement, which is usually
+//! dominated by tactic evaluation.
+//!
+//! The [`LispProfiler`] is a finer grained profiler for lisp code, controlled by the
+//! `(profile-start)` and `(profile-report)` builtins. It records the number of evaluation
+//! steps and the time spent in each call stack of lisp procedures.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use serde_json::{json, Value};
+use crate::{AtomId, DeclKey, FileRef, FrozenEnv, LinedString, Proof, Span, StmtTrace, ThmKind};
+use super::{lisp::{self, ProcPos}, Elaborator};
+
+/// The number of statements listed in the text report.
+const TOP: usize = 20;
+
+/// The profiling data for a single statement.
+#[derive(Copy, Clone, Debug)]
+pub struct StmtProfile {
+  /// The span of the statement.
+  pub span: Span,
+  /// The first term or theorem declared by the statement, if any.
+  pub decl: Option<AtomId>,
+  /// The time it took to elaborate the statement.
+  pub time: Duration,
+  /// The number of theorem applications in the proofs of the theorems declared by the
+  /// statement (counting shared subproofs once), or `None` if it declared no proved theorems.
+  pub proof_size: Option<usize>,
+  /// The number of lisp values allocated while elaborating the statement.
+  pub lisp_allocs: usize,
+}
+crate::deep_size_0!(StmtProfile);
+
+/// The state of the profiler at the start of a statement.
+#[derive(Debug)]
+pub(crate) struct Profiler {
+  start: Instant,
+  allocs: usize,
+  stmts: usize,
+}
+
+impl Profiler {
+  /// Start profiling a statement.
+  pub(crate) fn start(elab: &Elaborator) -> Self {
+    Profiler { start: Instant::now(), allocs: lisp::alloc_count(), stmts: elab.stmts.len() }
+  }
+
+  /// Finish profiling the statement at `span`, which has just been elaborated by `elab`.
+  pub(crate) fn finish(self, elab: &Elaborator, span: Span) -> StmtProfile {
+    let time = self.start.elapsed();
+    let lisp_allocs = lisp::alloc_count().wrapping_sub(self.allocs);
+    let (mut decl, mut proof_size) = (None, None);
+    for s in &elab.stmts[self.stmts..] {
+      let a = if let StmtTrace::Decl(a) = *s {a} else {continue};
+      decl.get_or_insert(a);
+      if let Some(DeclKey::Thm(t)) = elab.data[a].decl {
+        if let ThmKind::Thm(Some(Proof {heap, hyps, head, ..})) = &elab.thms[t].kind {
+          let n = proof_size.get_or_insert(0);
+          for e in heap.iter().chain(&**hyps).chain(Some(head)) {
+            e.for_each_decl(&mut |k| if let DeclKey::Thm(_) = k { *n += 1 })
+          }
+        }
+      }
+    }
+    StmtProfile {span, decl, time, proof_size, lisp_allocs}
+  }
+}
+
+/// A procedure in a call stack recorded by the [`LispProfiler`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Frame {
+  /// A named procedure.
+  Named(AtomId),
+  /// An anonymous procedure, identified by its location.
+  Unnamed(FileRef, Span),
+}
+
+/// A deterministic profiler for lisp code. Each step of the lisp evaluator is attributed
+/// to the call stack of procedures at that point, along with the time until the next step.
+#[derive(Debug, Default)]
+pub(crate) struct LispProfiler {
+  /// The call stacks which have been seen (outermost procedure first),
+  /// with the number of steps and the time spent in each.
+  stacks: Vec<(Box<[Frame]>, u64, Duration)>,
+  /// The index of each call stack in `stacks`.
+  index: HashMap<Box<[Frame]>, usize>,
+  /// A buffer for the current call stack.
+  buf: Vec<Frame>,
+  /// The call stack of the last step and its start time, during evaluation.
+  last: Option<(usize, Instant)>,
+}
+
+impl LispProfiler {
+  /// Record an evaluation step, with the given call stack (outermost procedure first).
+  pub(crate) fn step<'a>(&mut self, stack: impl Iterator<Item=&'a ProcPos>) {
+    let now = Instant::now();
+    if let Some((i, start)) = self.last { self.stacks[i].2 += now - start }
+    self.buf.clear();
+    self.buf.extend(stack.map(|pos| match *pos {
+      ProcPos::Named(_, _, a) => Frame::Named(a),
+      ProcPos::Unnamed(ref fsp) => Frame::Unnamed(fsp.file.clone(), fsp.span),
+    }));
+    let i = if let Some(&i) = self.index.get(&*self.buf) { i } else {
+      let i = self.stacks.len();
+      self.stacks.push((self.buf.clone().into(), 0, Duration::ZERO));
+      self.index.insert(self.buf.clone().into(), i);
+      i
+    };
+    self.stacks[i].1 += 1;
+    self.last = Some((i, now));
+  }
+
+  /// Record the end of a lisp evaluation.
+  pub(crate) fn stop(&mut self) {
+    if let Some((i, start)) = self.last.take() { self.stacks[i].2 += start.elapsed() }
+  }
+
+  /// Write the profile in the "folded stacks" format used by flame graph tools:
+  /// one line per call stack, with the procedure names separated by `;`, followed by
+  /// the number of evaluation steps (or microseconds if `time` is set) spent in it.
+  pub(crate) fn report(&self, elab: &Elaborator, time: bool) -> String {
+    use std::fmt::Write;
+    let mut texts = HashMap::new();
+    let mut lines = self.stacks.iter().filter_map(|(stack, steps, dur)| {
+      let n = if time { dur.as_micros() } else { (*steps).into() };
+      if n == 0 { return None }
+      let mut s = String::from("[top]");
+      for f in &**stack {
+        s.push(';');
+        match f {
+          &Frame::Named(a) => s.push_str(elab.data[a].name.as_str()),
+          Frame::Unnamed(file, sp) => {
+            let text = texts.entry(file.clone()).or_insert_with(|| -> Option<LinedString> {
+              Some(std::fs::read_to_string(file.path()).ok()?.into())
+            });
+            match text.as_ref().filter(|text| sp.start <= text.len()) {
+              Some(text) => write!(s, "[fn {}:{}]", file.rel(), text.to_pos(sp.start).line + 1),
+              None => write!(s, "[fn {}]", file.rel()),
+            }.expect("impossible")
+          }
+        }
+      }
+      Some((s, n))
+    }).collect::<Vec<_>>();
+    lines.sort();
+    let mut out = String::new();
+    for (s, n) in lines { writeln!(out, "{} {}", s, n).expect("impossible") }
+    out
+  }
+}
+
+impl FrozenEnv {
+  /// The profiling data recorded while elaborating this environment, in statement order.
+  /// This is empty unless the environment was elaborated with profiling enabled.
+  #[must_use] pub fn profile(&self) -> &[StmtProfile] { &unsafe { self.thaw() }.profile }
+}
+
+/// A statement in a profiling report.
+struct Entry<'a> {
+  file: &'a FileRef,
+  /// The (1-based) line number of the statement.
+  line: u32,
+  name: Option<&'a str>,
+  prof: &'a StmtProfile,
+}
+
+fn entries(files: &[(FileRef, Arc<LinedString>, FrozenEnv)]) -> Vec<Entry<'_>> {
+  files.iter().flat_map(|(file, text, env)| env.profile().iter().map(move |prof| Entry {
+    file,
+    line: text.to_pos(prof.span.start).line + 1,
+    name: prof.decl.map(|a| env.data()[a].name().as_str()),
+    prof,
+  })).collect()
+}
+
+/// Write a text report of the profiling data in `files` (which are the profiled files,
+/// with their source text and environment), listing the total time spent on each file
+/// and the statements which took the longest to elaborate.
+pub(crate) fn write_report(files: &[(FileRef, Arc<LinedString>, FrozenEnv)],
+  mut w: impl Write
+) -> io::Result<()> {
+  let mut entries = entries(files);
+  let total = entries.iter().map(|e| e.prof.time).sum::<Duration>();
+  writeln!(w, "profile: {} statements in {} files, {:.3?} total",
+    entries.len(), files.len(), total)?;
+  for (file, _, env) in files {
+    writeln!(w, "  {:>10.1?}  {} ({} statements)",
+      env.profile().iter().map(|p| p.time).sum::<Duration>(), file, env.profile().len())?;
+  }
+  entries.sort_by_key(|e| std::cmp::Reverse(e.prof.time));
+  writeln!(w, "slowest statements:\n  {:>10}  {:>8}  {:>10}  statement", "time", "proof", "allocs")?;
+  for e in entries.iter().take(TOP) {
+    let proof = e.prof.proof_size.map_or_else(|| "-".into(), |n| n.to_string());
+    write!(w, "  {:>10.1?}  {:>8}  {:>10}  {}:{}",
+      e.prof.time, proof, e.prof.lisp_allocs, e.file, e.line)?;
+    if let Some(name) = e.name { write!(w, " {}", name)? }
+    writeln!(w)?
+  }
+  Ok(())
+}
+
+/// Make a JSON report of the profiling data in `files` (see [`write_report`]). This is a list
+/// of `{file, line, name, time, proof_size, lisp_allocs}` objects (with `time` in seconds),
+/// one for each statement, in the order the statements were elaborated.
+pub(crate) fn json_report(files: &[(FileRef, Arc<LinedString>, FrozenEnv)]) -> Value {
+  entries(files).into_iter().map(|e| json!({
+    "file": e.file.rel(),
+    "line": e.line,
+    "name": e.name,
+    "time": e.prof.time.as_secs_f64(),
+    "proof_size": e.prof.proof_size,
+    "lisp_allocs": e.prof.lisp_allocs,
+  })).collect()
+}
@@ -8,10 +8,10 @@ use std::mem;
 use std::collections::{HashMap, hash_map::Entry};
 use crate::{AtomId, Type};
 use super::{LocalContext, ElabError, Result, Environment,
-  SortId, TermId, ThmId, ExprNode, ProofNode, DeclKey, Modifiers};
+  SortId, TermId, ThmId, ExprNode, Proof, ProofNode, DeclKey, Modifiers};
 use super::lisp::{LispVal, LispKind, Uncons, InferTarget, print::FormatEnv};
 use super::local_context::{InferSort, try_get_span_from};
-use crate::{BoxError, FileSpan};
+use crate::{BoxError, FileSpan, Span};
 
 /// This struct represents the context for the hash-consing step of proof compaction
 #[derive(Debug)]
@@ -125,6 +125,10 @@ pub struct Dedup<H: NodeHash> {
   /// reference, and `deps` is the dependencies of this expression
   /// (calculated as a useful side effect of deduplication).
   pub vec: Vec<(Rc<H>, bool, u64)>,
+  /// The span of the first lisp object in the theorem's file that was deduplicated to each
+  /// index, if any. This is used to build [`Proof::spans`], and is only collected if
+  /// [`set_proof_spans`](crate::set_proof_spans) is on.
+  spans: Option<HashMap<usize, Span>>,
   /// `2 ^ n` where `n` is the number of bound variables currently allocated.
   /// (Yes, this puts a limit of 64 simultaneous bound variables. In fact the limit is
   /// lower than that, [55](mm0b_parser::MAX_BOUND_VARS),
@@ -146,6 +150,7 @@ impl<H: NodeHash> Dedup<H> {
       map: vec.iter().enumerate().map(|(i, r)| (r.0.clone(), i)).collect(),
       prev: Default::default(),
       vec,
+      spans: crate::get_proof_spans().then(HashMap::new),
       bv,
     }
   }
@@ -170,10 +175,14 @@ impl<H: NodeHash> Dedup<H> {
       },
       Entry::Vacant(v) => {
         v.insert(None);
-        let n = match H::from(nh, e.fspan().as_ref(), kind, &arc, self)? {
+        let fsp = e.fspan();
+        let n = match H::from(nh, fsp.as_ref(), kind, &arc, self)? {
           Ok(v) => self.add_direct(v),
           Err(n) => n,
         };
+        if let (Some(spans), Some(fsp)) = (&mut self.spans, fsp) {
+          if fsp.file == nh.fsp.file { spans.entry(n).or_insert(fsp.span); }
+        }
         self.prev.get_mut(kind).insert(ptr, Some((arc, n))); n
       }
     })
@@ -189,7 +198,8 @@ impl<H: NodeHash> Dedup<H> {
       map.insert(t.clone(), i);
       (t, b, v)
     }).collect();
-    Dedup { map, prev: self.prev.clone(), vec, bv: self.bv }
+    let spans = self.spans.as_ref().map(|_| HashMap::new());
+    Dedup { map, prev: self.prev.clone(), vec, spans, bv: self.bv }
   }
 }
 
@@ -379,8 +389,8 @@ impl NodeHash for ExprHash {
         let head = u.next().ok_or_else(||
           nh.err_sp(fsp, format!("bad expression {}", nh.fe.to(r))))?;
         let a = head.as_atom().ok_or_else(|| nh.err(&head, "expected an atom"))?;
-        let tid = nh.fe.term(a).ok_or_else(||
-          nh.err(&head, format!("term '{}' not declared", nh.fe.data[a].name)))?;
+        let tid = nh.fe.term(a).ok_or_else(|| nh.err(&head, format!("term '{}' not declared{}",
+          nh.fe.data[a].name, nh.fe.did_you_mean(a, |_, ad| matches!(ad.decl, Some(DeclKey::Term(_)))))))?;
         let mut ns = Vec::new();
         for e in &mut u { ns.push(de.dedup(nh, ProofKind::Expr, &e)?) }
         if !u.exactly(0) {
@@ -496,6 +506,20 @@ pub enum ProofHash {
 }
 
 impl ProofHash {
+  /// Call `f` on the indexes of the children of this node, in the order of the children
+  /// of the [`ProofNode`] built from it (see [`ProofNode::for_each_child`]).
+  pub fn for_each_child(&self, mut f: impl FnMut(usize)) {
+    match *self {
+      ProofHash::Ref(..) | ProofHash::Dummy(..) => {}
+      ProofHash::Term(_, ref ns) => ns.iter().for_each(|&i| f(i)),
+      ProofHash::Cong(_, ref ns) => ns.iter().for_each(|&i| f(i)),
+      ProofHash::Hyp(_, i) | ProofHash::Refl(i) | ProofHash::Sym(i) => f(i),
+      ProofHash::Thm(_, ref ns, r) => { ns.iter().for_each(|&i| f(i)); f(r) }
+      ProofHash::Conv(i, j, k) => { f(i); f(j); f(k) }
+      ProofHash::Unfold(_, ref ns, _, m, c) => { ns.iter().for_each(|&i| f(i)); f(m); f(c) }
+    }
+  }
+
   /// Apply a substitution, while preserving sharing. The `n_heap` array contains
   /// indexes for substituted subterms, in case we see the same subterm multiple times.
   pub fn subst(de: &mut impl IDedup<Self>,
@@ -707,7 +731,8 @@ impl NodeHash for ProofHash {
               let l2 = Self::conv_side(de, c, false);
               ProofHash::Unfold(tid, ns.into(), lhs, l2, c)
             },
-            _ => return Err(nh.err(&th_head, format!("term/theorem '{}' not declared", adata.name)))
+            _ => return Err(nh.err(&th_head, format!("term/theorem '{}' not declared{}",
+              adata.name, nh.fe.did_you_mean(a, |_, ad| ad.decl.is_some()))))
           }
         }
       }
@@ -745,6 +770,36 @@ impl Dedup<ExprHash> {
   }
 }
 
+impl Dedup<ProofHash> {
+  /// Get the source spans of the nodes of the proof `pf`, which was built from this
+  /// [`Dedup`] by [`build`] (producing `ids`), in the format of [`Proof::spans`].
+  /// `hyps` and `head` are the indexes of the hypotheses and the head of the proof.
+  #[must_use] pub fn proof_spans(&self, ids: &[Val<ProofNode>], pf: &Proof,
+      hyps: &[usize], head: usize) -> Box<[(u32, Span)]> {
+    fn go(de: &Dedup<ProofHash>, e: &ProofNode, i: usize, n: &mut u32, out: &mut Vec<(u32, Span)>) {
+      let k = *n;
+      *n += 1;
+      if let ProofNode::Ref(_) = e { return }
+      if let Some(&sp) = de.spans.as_ref().and_then(|spans| spans.get(&i)) { out.push((k, sp)) }
+      let mut cs = vec![];
+      de.vec[i].0.for_each_child(|j| cs.push(j));
+      let mut cs = cs.into_iter();
+      e.for_each_child(|e| if let Some(j) = cs.next() { go(de, e, j, n, out) })
+    }
+    if self.spans.as_ref().map_or(true, HashMap::is_empty) { return Box::new([]) }
+    let mut heap_ids = vec![0; pf.heap.len()];
+    for (i, v) in ids.iter().enumerate() {
+      if let Val::Ref(j) = *v { heap_ids[j] = i }
+    }
+    let (mut n, mut out) = (0, vec![]);
+    for (e, &i) in pf.heap.iter().zip(&heap_ids).chain(pf.hyps.iter().zip(hyps)) {
+      go(self, e, i, &mut n, &mut out)
+    }
+    go(self, &pf.head, head, &mut n, &mut out);
+    out.into()
+  }
+}
+
 impl Node for ProofNode {
   type Hash = ProofHash;
   const REF: fn(usize) -> Self = ProofNode::Ref;
@@ -5,6 +5,7 @@
 //!
 //! [`mm1.md`]: https://github.com/digama0/mm0/blob/master/mm0-hs/mm1.md#pre-expressions
 
+use std::mem;
 use crate::{FileSpan, Span};
 use super::{Elaborator, ElabError, Result};
 use crate::{AtomId, TermKind, DeclKey, Modifiers,
@@ -126,6 +127,7 @@ pub(crate) enum RStack {
   ///   return RState::RefineApp(tgt, t, u, args ++ [arg])
   /// ```
   RefineApp {
+    /** The span of the application */      sp: Span,
     /** The span of the term `t` */         sp2: Span,
     /** The expected type */                tgt: InferTarget,
     /** The head term */                    t: TermId,
@@ -221,11 +223,12 @@ pub(crate) enum RState {
   /// ...
   /// ```
   RefineApp {
-    /** The span of `t` */            sp2: Span,
-    /** The expected type */          tgt: InferTarget,
-    /** The head term */              t: TermId,
-    /** The unelaborated arguments */ u: Uncons,
-    /** The elaborated arguments */   args: Vec<LispVal>,
+    /** The span of the application */ sp: Span,
+    /** The span of `t` */                sp2: Span,
+    /** The expected type */              tgt: InferTarget,
+    /** The head term */                  t: TermId,
+    /** The unelaborated arguments */     u: Uncons,
+    /** The elaborated arguments */       args: Vec<LispVal>,
   },
   /// Elaborate the (possibly) extra arguments of a completed theorem application.
   /// ```text
@@ -387,6 +390,7 @@ impl Elaborator {
     Ok(match &*e.unwrapped_arc() {
       &LispKind::Atom(head) => {
         let sp = try_get_span(fsp, e);
+        let head = self.resolve_name(head);
         RefineExpr::App {sp, sp2: sp, im: InferMode::Regular, head, u: Uncons::nil()}
       }
       LispKind::List(_) | LispKind::DottedList(_, _) => {
@@ -444,6 +448,7 @@ impl Elaborator {
             };
             let sp2 = try_get_span(fsp, &t);
             let head = t.as_atom().ok_or_else(|| ElabError::new_e(sp2, "refine: expected an atom"))?;
+            let head = self.resolve_name(head);
             RefineExpr::App {sp, sp2, im, head, u}
           }
         }
@@ -475,8 +480,8 @@ impl Elaborator {
         let mut u = Uncons::from(e.clone());
         let head = u.next().ok_or_else(|| err!(e, "not a term"))?;
         let a = head.as_atom().ok_or_else(|| err!(head, "expected an atom"))?;
-        let tid = self.term(a).ok_or_else(||
-          err!(head, format!("unknown term '{}'", self.data[a].name)))?;
+        let tid = self.term(a).ok_or_else(|| err!(head, format!("unknown term '{}'{}",
+          self.data[a].name, self.did_you_mean(a, |_, ad| matches!(ad.decl, Some(DeclKey::Term(_)))))))?;
         let sort = self.env.terms[tid].ret.0;
         InferTarget::Reg(self.sorts[sort].atom)
       }
@@ -501,8 +506,8 @@ impl Elaborator {
         match head.as_atom().ok_or_else(|| err!(head, "expected an atom"))? {
           AtomId::CONV => u.next().ok_or_else(|| err!(e, "bad :conv"))?,
           a => {
-            let tid = self.thm(a).ok_or_else(||
-              err!(head, format!("unknown theorem '{}'", self.data[a].name)))?;
+            let tid = self.thm(a).ok_or_else(|| err!(head, format!("unknown theorem '{}'{}",
+              self.data[a].name, self.did_you_mean(a, |_, ad| matches!(ad.decl, Some(DeclKey::Thm(_)))))))?;
             let tdata = &self.env.thms[tid];
             let num_args = tdata.args.len();
             let mut args = Vec::with_capacity(num_args);
@@ -578,8 +583,64 @@ impl Elaborator {
 
   /// Unify expressions `e1` and `e2`. Returns a conversion proof
   /// `u: e1 = e2`, with `#undef` meaning that `e1` and `e2` are equal after unification.
+  /// The problems that are postponed are added to the constraint queue with span `sp`,
+  /// and after a successful unification the problems already in the queue are retried.
   fn unify(&mut self, sp: Span, e1: &LispVal, e2: &LispVal) -> Result<LispVal> {
-    self.unify1(e1, e2).map_err(|e| ElabError::new_e(sp, e))
+    let n = self.lc.constraints.len();
+    let c = self.unify1(e1, e2).map_err(|e| {
+      self.lc.constraints.truncate(n);
+      ElabError::new_e(sp, e)
+    })?;
+    for cs in &mut self.lc.constraints[n..] {cs.0 = sp}
+    if n != 0 {self.retry_constraints(n)}
+    Ok(c)
+  }
+
+  /// Postpone the unification problem `e1 =?= e2`, which cannot be solved yet.
+  /// Returns the conversion proof, which is a reference that is assigned when the
+  /// problem is solved; it starts out as `e1`, the reflexivity proof, which is correct
+  /// if the problem is solved without unfolding definitions.
+  fn postpone(&mut self, e1: &LispVal, e2: &LispVal) -> LispVal {
+    let conv = LispVal::new_ref(e1.clone());
+    self.lc.constraints.push((Span::default(), conv.clone(), e1.clone(), e2.clone()));
+    conv
+  }
+
+  /// Retry the postponed unification problems `lc.constraints[..n]`. The problems that
+  /// are solved are removed from the queue, and the others are kept in the queue to be
+  /// retried later, even if they fail, so that they are reported at the end of the statement.
+  fn retry_constraints(&mut self, n: usize) {
+    let rest = self.lc.constraints.split_off(n);
+    for (sp, conv, e1, e2) in mem::replace(&mut self.lc.constraints, rest) {
+      let m = self.lc.constraints.len();
+      match self.unify1(&e1, &e2) {
+        Ok(c) if self.lc.constraints.len() == m + 1 && self.lc.constraints[m].1.ptr_eq(&c) =>
+          self.lc.constraints[m] = (sp, conv, e1, e2),
+        Ok(c) => {
+          for cs in &mut self.lc.constraints[m..] {cs.0 = sp}
+          if c.is_def() {conv.as_ref_(|e| *e = c).expect("a conversion is a ref")}
+        }
+        Err(_) => {
+          self.lc.constraints.truncate(m);
+          self.lc.constraints.push((sp, conv, e1, e2))
+        }
+      }
+    }
+  }
+
+  /// Retry the postponed unification problems at the end of a statement, and return
+  /// an error for each problem that is still not solved.
+  pub fn unsolved_constraints(&mut self) -> Vec<ElabError> {
+    if self.lc.constraints.is_empty() {return vec![]}
+    self.retry_constraints(self.lc.constraints.len());
+    let errs = mem::take(&mut self.lc.constraints).into_iter().map(|(sp, _, e1, e2)| {
+      let msg = self.unify1(&e1, &e2).err().unwrap_or_else(|| self.format_env().pretty(|p|
+        format!("{}\nunsolved constraint: occurs-check failed, can't build infinite assignment",
+          p.unify_err(&e1, &e2).pretty(80))));
+      ElabError::new_e(sp, msg)
+    }).collect();
+    self.lc.constraints.clear();
+    errs
   }
 
   /// Unify expressions `e1` and `e2`. Returns a conversion proof
@@ -597,12 +658,10 @@ impl Elaborator {
     if e1.ptr_eq(e2) {return Ok(LispVal::undef())}
     match e1.as_mvar(|e1, m| self.assign(false, e1, m, e2)) {
       Some(Ok(())) => return Ok(LispVal::undef()),
-      Some(Err(AssignError::Cyclic)) =>
-        return Err("occurs-check failed, can't build infinite assignment".into()),
+      Some(Err(AssignError::Cyclic)) => return Ok(self.postpone(e1, e2)),
       r1 => match (r1, e2.as_mvar(|e2, m| self.assign(true, e2, m, e1))) {
         (_, Some(Ok(()))) => return Ok(LispVal::undef()),
-        (_, Some(Err(AssignError::Cyclic))) =>
-          return Err("occurs-check failed, can't build infinite assignment".into()),
+        (_, Some(Err(AssignError::Cyclic))) => return Ok(self.postpone(e1, e2)),
         (Some(Err(AssignError::BoundVar)), None) =>
           return Err(format!("type error: expected bound var, got {}", self.print(e2))),
         (None, Some(Err(AssignError::BoundVar))) =>
@@ -734,11 +793,13 @@ impl Elaborator {
           RefineExpr::App {sp, sp2, head: AtomId::UNDER, u, ..} => {
             if u.is_empty() {
               let head = self.new_goal(sp, tgt);
+              self.lc.explicit_holes.push(head.clone());
               self.spans.insert_if(sp2, || ObjectKind::proof(head.clone()));
               RState::Ret(head)
             } else {
               let mv = self.lc.new_mvar(InferTarget::Unknown, Some(self.fspan(sp2)));
               let head = self.new_goal(sp, mv);
+              self.lc.explicit_holes.push(head.clone());
               self.spans.insert_if(sp2, || ObjectKind::proof(head.clone()));
               return Ok(RefineResult::RefineExtraArgs(tgt, head, u))
             }
@@ -749,10 +810,13 @@ impl Elaborator {
               self.spans.insert_if(sp2, || ObjectKind::proof(head.clone()));
               RState::RefineArgs {sp, ty: ty.clone(), tgt, p: head, u}
             } else if let Some(DeclKey::Thm(t)) = self.data[a].decl {
+              self.check_visible(sp2, a)?;
+              self.check_deprecated(sp2, a);
               RState::RefineBis {sp, sp2, tgt, im, t, args: vec![head], u}
             } else {
-              return Err(ElabError::new_e(sp2, format!(
-                "unknown theorem/hypothesis '{}'", self.data[a].name)))
+              return Err(ElabError::new_e(sp2, format!("unknown theorem/hypothesis '{}'{}",
+                self.data[a].name, self.did_you_mean(a, |b, ad|
+                  matches!(ad.decl, Some(DeclKey::Thm(_))) || self.lc.get_proof(b).is_some()))))
             }
           }
           RefineExpr::Typed {ty, e: q} => {
@@ -783,13 +847,16 @@ impl Elaborator {
               };
               RState::Ret(self.coerce_term(sp, tgt, sort, bd, head)?)
             } else if let Some(t) = if tgt.bound() {None} else {self.term(a)} {
-              RState::RefineApp {sp2, tgt, t, u, args: vec![head]}
+              self.check_visible(sp2, a)?;
+              self.check_deprecated(sp2, a);
+              RState::RefineApp {sp, sp2, tgt, t, u, args: vec![head]}
             } else if let Some(s) = tgt.sort().filter(|_| empty) {
               let sort = self.data[s].sort.ok_or_else(|| ElabError::new_e(sp, "bad sort"))?;
               self.lc.vars.insert(a, (true, InferSort::Bound(sort)));
               RState::Ret(head)
             } else {
-              return Err(ElabError::new_e(sp, format!("unknown term '{}'", self.data[a].name)))
+              return Err(ElabError::new_e(sp, format!("unknown term '{}'{}",
+                self.data[a].name, self.did_you_mean(a, |_, ad| matches!(ad.decl, Some(DeclKey::Term(_)))))))
             }
           }
           Ok(RefineExpr::Typed {ty: s, e}) => {
@@ -813,21 +880,22 @@ impl Elaborator {
             Err(err)
           })()?
         },
-        RState::RefineApp {sp2, tgt: ret, t, mut u, mut args} => {
+        RState::RefineApp {sp, sp2, tgt: ret, t, mut u, mut args} => {
          'l: loop { // labeled block, not a loop. See rust#48594
             let tdata = &self.env.terms[t];
             for (_, ty) in &tdata.args[args.len() - 1..] {
               let tgt = self.type_target(ty);
               match u.next() {
                 Some(e) => {
-                  stack.push(RStack::RefineApp {sp2, tgt: ret, t, u, args});
+                  stack.push(RStack::RefineApp {sp, sp2, tgt: ret, t, u, args});
                   break 'l RState::RefineExpr {tgt, e}
                 }
                 None => args.push(self.lc.new_mvar(tgt, Some(self.fspan(sp2))))
               }
             }
             let s = tdata.ret.0;
-            break RState::Ret(self.coerce_term(sp, ret, s, false, LispVal::list(args))?)
+            let e = LispVal::list(args).span(self.fspan(sp));
+            break RState::Ret(self.coerce_term(sp, ret, s, false, e)?)
           }
         }
         RState::RefineArgs {sp, tgt, ty, p, u} if u.is_empty() =>
@@ -872,7 +940,7 @@ impl Elaborator {
               }
               args.push(self.new_goal(sp, h))
             }
-            let head = LispVal::list(args);
+            let head = LispVal::list(args).span(self.fspan(sp));
             self.spans.insert_if(sp2, || ObjectKind::proof(head.clone()));
             break match res {
               RefineHypsResult::Ok(c) => RState::Ret(LispVal::apply_conv(c, tgt, head)),
@@ -903,9 +971,9 @@ impl Elaborator {
             RState::RefineProof {tgt: ret, p}
           }
           Some(RStack::Typed(p)) => RState::RefineProof {tgt: ret, p},
-          Some(RStack::RefineApp {sp2, tgt, t, u, mut args}) => {
+          Some(RStack::RefineApp {sp, sp2, tgt, t, u, mut args}) => {
             args.push(ret);
-            RState::RefineApp {sp2, tgt, t, u, args}
+            RState::RefineApp {sp, sp2, tgt, t, u, args}
           }
           Some(RStack::RefineBis {sp, sp2, tgt, im, t, u, mut args}) => {
             args.push(ret);
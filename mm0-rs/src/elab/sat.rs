@@ -0,0 +1,539 @@
+//! A SAT solver, which proves a propositional tautology by showing that its negation is
+//! unsatisfiable, and reconstructs an MM0 proof from the resolution certificate.
+//!
+//! The goal is converted to clauses by the Tseitin encoding, where every subformula whose head
+//! is one of the supported connectives is a variable, together with the clauses which relate
+//! it to the variables of its arguments, and every other subformula is an atom. Since the
+//! variable of a subformula is the subformula itself, each clause of the encoding is an
+//! instance of a fixed lemma about the connective, which is given in a table, because the
+//! connectives are defined by the library and not built in. See
+//! [`BuiltinProc::SatTauto`](super::lisp::BuiltinProc::SatTauto) for the interface.
+//!
+//! The solver is a CDCL solver with two watched literals, first-UIP clause learning and
+//! activity-based decisions. Each learned clause records the clauses which were resolved to
+//! derive it, and the clause is proved by *reverse unit propagation*: assuming the negations
+//! of its literals, unit propagation over those clauses reaches a conflict. In the proof,
+//! every propagation is a case split on the propagated variable, where the branch with the
+//! wrong value is closed by the propagating clause. All proofs are in deduction form
+//! `G -> p`, where the context `G` is extended with a conjunct for each assumption.
+
+use std::collections::HashMap;
+use crate::{AtomId, LispKind, LispVal, Span};
+use super::{CancelToken, ElabError, Elaborator, Result};
+
+/// A literal, which is twice the index of its variable, plus one if it is negated.
+type Lit = u32;
+
+/// The variable of a literal.
+fn var(l: Lit) -> usize { (l >> 1) as usize }
+
+/// The literal for variable `v`, negated if `neg` is true.
+#[allow(clippy::cast_possible_truncation)]
+fn lit(v: usize, neg: bool) -> Lit { (v as u32) << 1 | u32::from(neg) }
+
+/// The result of [`Solver::solve`].
+enum Outcome {
+  /// The clauses are unsatisfiable, and these clauses propagate to a conflict at level 0.
+  Unsat(Box<[usize]>),
+  /// The clauses are satisfiable.
+  Sat,
+  /// The search was cancelled.
+  Cancelled,
+}
+
+/// A CDCL SAT solver which records the derivation of each learned clause.
+#[derive(Default)]
+struct Solver {
+  /// The literals of each clause, in their original order.
+  lits: Vec<Box<[Lit]>>,
+  /// The literals of each clause, where the first two literals are watched.
+  clauses: Vec<Vec<Lit>>,
+  /// The clauses resolved to derive each clause, which is empty for the input clauses.
+  ante: Vec<Box<[usize]>>,
+  /// The clauses watching each literal, which are visited when the literal becomes false.
+  watches: Vec<Vec<usize>>,
+  /// The value of each variable.
+  value: Vec<Option<bool>>,
+  /// The decision level at which each variable was assigned.
+  level: Vec<usize>,
+  /// The clause which propagated each variable, or `None` for decisions and assumptions.
+  reason: Vec<Option<usize>>,
+  /// The assigned literals, in order of assignment.
+  trail: Vec<Lit>,
+  /// The start of each decision level in the trail.
+  levels: Vec<usize>,
+  /// The number of literals in the trail which have been propagated.
+  qhead: usize,
+  /// The activity of each variable, which is increased when it takes part in a conflict.
+  activity: Vec<u64>,
+  /// The amount by which the activity is increased, which grows over time so that
+  /// recent conflicts count for more.
+  inc: u64,
+}
+
+impl Solver {
+  fn new_var(&mut self) -> usize {
+    let v = self.value.len();
+    self.value.push(None);
+    self.level.push(0);
+    self.reason.push(None);
+    self.activity.push(0);
+    self.watches.push(vec![]);
+    self.watches.push(vec![]);
+    v
+  }
+
+  fn lit_value(&self, l: Lit) -> Option<bool> {
+    self.value[var(l)].map(|b| b != (l & 1 != 0))
+  }
+
+  fn enqueue(&mut self, l: Lit, reason: Option<usize>) {
+    let v = var(l);
+    self.value[v] = Some(l & 1 == 0);
+    self.level[v] = self.levels.len();
+    self.reason[v] = reason;
+    self.trail.push(l)
+  }
+
+  /// Add a clause with at least one literal, derived from the clauses `ante`.
+  fn add_clause(&mut self, lits: Vec<Lit>, ante: Box<[usize]>) -> usize {
+    let c = self.clauses.len();
+    if lits.len() >= 2 {
+      self.watches[lits[0] as usize].push(c);
+      self.watches[lits[1] as usize].push(c);
+    }
+    self.lits.push(lits.clone().into());
+    self.clauses.push(lits);
+    self.ante.push(ante);
+    c
+  }
+
+  /// Propagate the assigned literals, returning a clause whose literals are all false,
+  /// if there is one.
+  fn propagate(&mut self) -> Option<usize> {
+    while self.qhead < self.trail.len() {
+      let fl = self.trail[self.qhead] ^ 1;
+      self.qhead += 1;
+      let ws = std::mem::take(&mut self.watches[fl as usize]);
+      let mut keep = Vec::with_capacity(ws.len());
+      let mut conflict = None;
+      for &c in &ws {
+        if conflict.is_some() { keep.push(c); continue }
+        let cl = &mut self.clauses[c];
+        if cl[0] == fl { cl.swap(0, 1) }
+        let first = cl[0];
+        let value = &self.value;
+        let val = |l: Lit| value[var(l)].map(|b| b != (l & 1 != 0));
+        if val(first) == Some(true) { keep.push(c); continue }
+        if let Some(j) = (2..cl.len()).find(|&j| val(cl[j]) != Some(false)) {
+          cl.swap(1, j);
+          let w = cl[1];
+          self.watches[w as usize].push(c);
+          continue
+        }
+        keep.push(c);
+        match val(first) {
+          Some(_) => conflict = Some(c),
+          None => self.enqueue(first, Some(c)),
+        }
+      }
+      self.watches[fl as usize] = keep;
+      if conflict.is_some() { return conflict }
+    }
+    None
+  }
+
+  fn bump(&mut self, v: usize) {
+    self.activity[v] += self.inc;
+    if self.activity[v] > 1 << 60 {
+      for a in &mut self.activity { *a >>= 40 }
+      self.inc = (self.inc >> 40).max(1);
+    }
+  }
+
+  /// Derive a clause from the conflict `c` by resolving with the reasons of the literals
+  /// of the current decision level, until only one of them is left. The first literal of
+  /// the result is the one of the current level, and the second has the highest level of
+  /// the rest. Literals from level 0 are kept, so that the clause follows from the
+  /// resolved clauses alone.
+  fn analyze(&mut self, mut c: usize) -> (Vec<Lit>, Box<[usize]>) {
+    let mut seen = vec![false; self.value.len()];
+    let mut learnt = vec![0];
+    let mut ante = vec![c];
+    let (mut counter, mut idx) = (0, self.trail.len());
+    let cur = self.levels.len();
+    loop {
+      for i in 0..self.clauses[c].len() {
+        let q = self.clauses[c][i];
+        let v = var(q);
+        if seen[v] { continue }
+        seen[v] = true;
+        self.bump(v);
+        if self.level[v] == cur { counter += 1 } else { learnt.push(q) }
+      }
+      loop {
+        idx -= 1;
+        if seen[var(self.trail[idx])] { break }
+      }
+      let l = self.trail[idx];
+      counter -= 1;
+      if counter == 0 { learnt[0] = l ^ 1; break }
+      c = self.reason[var(l)].expect("propagated literal");
+      ante.push(c);
+    }
+    if let Some(i) = (1..learnt.len()).max_by_key(|&i| self.level[var(learnt[i])]) {
+      learnt.swap(1, i)
+    }
+    ante.reverse();
+    (learnt, ante.into())
+  }
+
+  /// Find the clauses that propagate to the conflict `c` at level 0.
+  fn analyze_final(&self, c: usize) -> Box<[usize]> {
+    let mut seen = vec![false; self.value.len()];
+    for &l in &self.clauses[c] { seen[var(l)] = true }
+    let mut ante = vec![c];
+    for &l in self.trail.iter().rev() {
+      if let (true, Some(r)) = (seen[var(l)], self.reason[var(l)]) {
+        for &q in &self.clauses[r] { seen[var(q)] = true }
+        ante.push(r)
+      }
+    }
+    ante.reverse();
+    ante.into()
+  }
+
+  fn backtrack(&mut self, level: usize) {
+    if level >= self.levels.len() { return }
+    for l in self.trail.drain(self.levels[level]..) {
+      self.value[var(l)] = None;
+      self.reason[var(l)] = None;
+    }
+    self.levels.truncate(level);
+    self.qhead = self.trail.len();
+  }
+
+  /// Decide whether the clauses are satisfiable, under the literals already in the trail.
+  fn solve(&mut self, cancel: &CancelToken) -> Outcome {
+    self.inc = 1 << 8;
+    for c in 0..self.clauses.len() {
+      if let [l] = *self.clauses[c] {
+        match self.lit_value(l) {
+          None => self.enqueue(l, Some(c)),
+          Some(false) => return Outcome::Unsat(self.analyze_final(c)),
+          Some(true) => {}
+        }
+      }
+    }
+    loop {
+      if let Some(c) = self.propagate() {
+        if self.levels.is_empty() { return Outcome::Unsat(self.analyze_final(c)) }
+        if cancel.is_canceled() { return Outcome::Cancelled }
+        let (lits, ante) = self.analyze(c);
+        self.backtrack(lits.get(1).map_or(0, |&l| self.level[var(l)]));
+        let l = lits[0];
+        let c = self.add_clause(lits, ante);
+        self.enqueue(l, Some(c));
+        self.inc += self.inc >> 4;
+      } else {
+        let v = (0..self.value.len()).filter(|&v| self.value[v].is_none())
+          .max_by_key(|&v| self.activity[v]);
+        let v = match v { Some(v) => v, None => return Outcome::Sat };
+        self.levels.push(self.trail.len());
+        self.enqueue(lit(v, true), None)
+      }
+    }
+  }
+}
+
+/// An argument of a connective in a Tseitin clause.
+#[derive(Copy, Clone, Debug)]
+enum Sub {
+  /// The connective application `x` itself.
+  X,
+  /// The first argument `a`.
+  A,
+  /// The second argument `b`.
+  B,
+}
+use Sub::{A, B, X};
+
+/// A supported connective, with its Tseitin clauses. Each clause is a list of literals,
+/// given as whether the literal is negated and the subformula.
+struct Conn {
+  name: &'static str,
+  arity: usize,
+  clauses: &'static [&'static [(bool, Sub)]],
+}
+
+/// The supported connectives. The lemmas for the clauses are named after the connective,
+/// like `and-1`, and the clause `[l1, ..., ln]` is stated as `~l1 -> ... -> ~ln -> F`.
+const CONNS: [Conn; 7] = [
+  // x = ~a: `x -> a -> F`, `~x -> ~a -> F`
+  Conn { name: "not", arity: 1, clauses: &[&[(true, X), (true, A)], &[(false, X), (false, A)]] },
+  // x = a /\ b: `x -> ~a -> F`, `x -> ~b -> F`, `~x -> a -> b -> F`
+  Conn { name: "and", arity: 2, clauses: &[
+    &[(true, X), (false, A)], &[(true, X), (false, B)], &[(false, X), (true, A), (true, B)]] },
+  // x = a \/ b: `x -> ~a -> ~b -> F`, `~x -> a -> F`, `~x -> b -> F`
+  Conn { name: "or", arity: 2, clauses: &[
+    &[(true, X), (false, A), (false, B)], &[(false, X), (true, A)], &[(false, X), (true, B)]] },
+  // x = a -> b: `x -> a -> ~b -> F`, `~x -> ~a -> F`, `~x -> b -> F`
+  Conn { name: "imp", arity: 2, clauses: &[
+    &[(true, X), (true, A), (false, B)], &[(false, X), (false, A)], &[(false, X), (true, B)]] },
+  // x = a <-> b: `x -> a -> ~b -> F`, `x -> ~a -> b -> F`,
+  // `~x -> ~a -> ~b -> F`, `~x -> a -> b -> F`
+  Conn { name: "iff", arity: 2, clauses: &[
+    &[(true, X), (true, A), (false, B)], &[(true, X), (false, A), (true, B)],
+    &[(false, X), (false, A), (false, B)], &[(false, X), (true, A), (true, B)]] },
+  // x = T.: `~x -> F`
+  Conn { name: "true", arity: 0, clauses: &[&[(false, X)]] },
+  // x = F.: `x -> F`
+  Conn { name: "false", arity: 0, clauses: &[&[(true, X)]] },
+];
+
+/// A proof step in the [`SatTable`]. Each step is either a theorem, which is applied to
+/// the proofs, or a function, which is called with the proofs followed by the terms.
+#[derive(Copy, Clone, Debug)]
+enum Step {
+  /// `(hyp a)`: prove `G /\ a -> a`.
+  Hyp,
+  /// `(weaken p a)`: from `p: G -> b` prove `G /\ a -> b`.
+  Weaken,
+  /// `(mp p q)`: from `p: G -> a -> b` and `q: G -> a` prove `G -> b`.
+  Mp,
+  /// `(cases p q a)`: from `p: G /\ a -> F` and `q: G /\ ~a -> F` prove `G -> F`.
+  Cases,
+  /// `(intro p a)`: from `p: G /\ a -> b` prove `G -> a -> b`.
+  Intro,
+  /// `(by-contra p a)`: from `p: G /\ ~a -> F` prove `G -> a`.
+  ByContra,
+}
+
+/// The names of the proof steps, in the order of [`Step`].
+const STEPS: [&str; 6] = ["hyp", "weaken", "mp", "cases", "intro", "by-contra"];
+
+/// The table of connectives and proof steps used by [`Elaborator::sat_tauto`].
+#[derive(Debug)]
+pub struct SatTable {
+  /// The term constructor for each connective in [`CONNS`], if it is supported.
+  conns: [Option<AtomId>; 7],
+  /// The lemmas for the Tseitin clauses of each supported connective.
+  rules: [Box<[LispVal]>; 7],
+  /// The proof steps, indexed by [`Step`].
+  steps: [LispVal; 6],
+}
+
+impl SatTable {
+  /// Read the table from an atom map. See
+  /// [`BuiltinProc::SatTauto`](super::lisp::BuiltinProc::SatTauto) for the keys.
+  pub fn new(elab: &mut Elaborator, m: &HashMap<AtomId, LispVal>) -> std::result::Result<Self, String> {
+    let mut get = |s: &str| m.get(&elab.get_atom(s.as_bytes())).cloned()
+      .ok_or_else(|| format!("sat-tauto: missing '{}", s));
+    let mut conns = [None; 7];
+    let mut rules: [Box<[LispVal]>; 7] = Default::default();
+    for (k, conn) in CONNS.iter().enumerate() {
+      let t = match get(conn.name) {
+        Ok(t) => t,
+        Err(_) if k != 0 => continue,
+        Err(e) => return Err(e),
+      };
+      conns[k] = Some(t.as_atom()
+        .ok_or_else(|| format!("sat-tauto: '{}' should be a term constructor", conn.name))?);
+      rules[k] = (1..=conn.clauses.len())
+        .map(|i| get(&format!("{}-{}", conn.name, i))).collect::<std::result::Result<_, _>>()?;
+    }
+    Ok(Self {
+      conns,
+      rules,
+      steps: [get(STEPS[0])?, get(STEPS[1])?, get(STEPS[2])?,
+        get(STEPS[3])?, get(STEPS[4])?, get(STEPS[5])?],
+    })
+  }
+
+  /// The index of the connective `t` with `n` arguments, if it is supported.
+  fn conn(&self, t: AtomId, n: usize) -> Option<usize> {
+    self.conns.iter().position(|&c| c == Some(t)).filter(|&k| CONNS[k].arity == n)
+  }
+}
+
+/// The state of the proof search and reconstruction.
+struct Sat<'a> {
+  elab: &'a mut Elaborator,
+  sp: Span,
+  table: &'a SatTable,
+  /// The formula of each variable.
+  forms: Vec<LispVal>,
+  /// The variable of each connective application, by the head and the argument variables.
+  nodes: HashMap<(AtomId, Box<[usize]>), usize>,
+  /// The variables of the atomic formulas.
+  atoms: Vec<usize>,
+  /// The connective, the clause index and the arguments for each input clause.
+  rules: Vec<(usize, usize, Box<[LispVal]>)>,
+  solver: Solver,
+  /// The proof of each clause of the solver that has been built, in the context `G /\ ~goal`.
+  proofs: Vec<Option<LispVal>>,
+}
+
+impl Sat<'_> {
+  /// Apply a proof step to the proofs `ps` and the terms `ts`.
+  fn apply_step(&mut self, f: LispVal, ps: Vec<LispVal>, ts: Vec<LispVal>) -> Result<LispVal> {
+    match f.as_atom() {
+      Some(a) => Ok(LispVal::list(std::iter::once(LispVal::atom(a)).chain(ps).collect::<Vec<_>>())),
+      None => self.elab.call_func(self.sp, f, ps.into_iter().chain(ts).collect()),
+    }
+  }
+
+  fn step(&mut self, s: Step, ps: Vec<LispVal>, ts: Vec<LispVal>) -> Result<LispVal> {
+    self.apply_step(self.table.steps[s as usize].clone(), ps, ts)
+  }
+
+  /// The formula of a literal.
+  fn form(&self, l: Lit) -> LispVal {
+    let e = self.forms[var(l)].clone();
+    if l & 1 == 0 { return e }
+    LispVal::list(vec![LispVal::atom(self.table.conns[0].expect("required")), e])
+  }
+
+  /// Get the variable of the formula `e`, adding the clauses of the encoding if it is new.
+  fn encode(&mut self, e: &LispVal) -> usize {
+    let shape = e.unwrapped(|r| match r {
+      &LispKind::Atom(t) => Some((t, vec![])),
+      LispKind::List(es) => es.first().and_then(|t| t.as_atom()).map(|t| (t, es[1..].to_vec())),
+      _ => None,
+    });
+    if let Some((t, args)) = shape {
+      if let Some(k) = self.table.conn(t, args.len()) {
+        let vs: Box<[usize]> = args.iter().map(|a| self.encode(a)).collect();
+        if let Some(&v) = self.nodes.get(&(t, vs.clone())) { return v }
+        let v = self.solver.new_var();
+        self.forms.push(e.clone());
+        for (i, cl) in CONNS[k].clauses.iter().enumerate() {
+          let lits = cl.iter().map(|&(neg, s)| lit(match s { X => v, A => vs[0], B => vs[1] }, neg));
+          self.solver.add_clause(lits.collect(), Box::new([]));
+          self.rules.push((k, i, args.clone().into()))
+        }
+        self.nodes.insert((t, vs), v);
+        return v
+      }
+    }
+    if let Some(&v) = self.atoms.iter().find(|&&v| self.forms[v] == *e) { return v }
+    let v = self.solver.new_var();
+    self.forms.push(e.clone());
+    self.atoms.push(v);
+    v
+  }
+
+  /// Prove the formula of the literal `l`, which is in the context `ctx`.
+  fn lookup(&mut self, l: Lit, ctx: &[Lit]) -> Result<LispVal> {
+    let i = ctx.iter().rposition(|&m| m == l).expect("literal in context");
+    let mut p = self.step(Step::Hyp, vec![], vec![self.form(l)])?;
+    for &m in &ctx[i + 1..] { p = self.step(Step::Weaken, vec![p], vec![self.form(m)])? }
+    Ok(p)
+  }
+
+  /// Prove `F` in the context `ctx`, which contains the negations of all the literals of
+  /// the clause `c`.
+  fn close(&mut self, c: usize, ctx: &[Lit]) -> Result<LispVal> {
+    let mut p = self.proofs[c].clone().expect("clause proved");
+    if c >= self.rules.len() {
+      for &m in &ctx[1..] { p = self.step(Step::Weaken, vec![p], vec![self.form(m)])? }
+    }
+    for i in 0..self.solver.lits[c].len() {
+      let l = self.solver.lits[c][i];
+      let q = self.lookup(l ^ 1, ctx)?;
+      p = self.step(Step::Mp, vec![p, q], vec![])?
+    }
+    Ok(p)
+  }
+
+  /// Prove `F` in the context `ctx` by unit propagation over the clauses `ante`.
+  fn rup(&mut self, ctx: &mut Vec<Lit>, ante: &[usize]) -> Result<LispVal> {
+    let mut value = vec![None; self.forms.len()];
+    for &l in &**ctx { value[var(l)] = Some(l & 1 == 0) }
+    let mut props = vec![];
+    let conflict = 'search: loop {
+      let mut progress = false;
+      for &c in ante {
+        let (mut unit, mut open, mut sat) = (None, false, false);
+        for &l in &*self.solver.lits[c] {
+          match value[var(l)].map(|b| b != (l & 1 != 0)) {
+            Some(true) => { sat = true; break }
+            Some(false) => {}
+            None => if unit.map_or(true, |u| u == l) { unit = Some(l) } else { open = true },
+          }
+        }
+        if sat || open { continue }
+        let l = match unit { Some(l) => l, None => break 'search c };
+        value[var(l)] = Some(l & 1 == 0);
+        ctx.push(l);
+        props.push((l, c));
+        progress = true
+      }
+      if !progress {
+        return Err(ElabError::new_e(self.sp, "sat-tauto: unit propagation failed to find a conflict"))
+      }
+    };
+    let mut p = self.close(conflict, ctx)?;
+    while let Some((l, c)) = props.pop() {
+      ctx.pop();
+      ctx.push(l ^ 1);
+      let q = self.close(c, ctx)?;
+      ctx.pop();
+      let a = self.forms[var(l)].clone();
+      let ps = if l & 1 == 0 { vec![p, q] } else { vec![q, p] };
+      p = self.step(Step::Cases, ps, vec![a])?
+    }
+    Ok(p)
+  }
+
+  /// Prove the clause `c`, whose derivation uses only clauses that have been proved.
+  fn prove(&mut self, c: usize, goal: Lit) -> Result<LispVal> {
+    if let Some((k, i, args)) = self.rules.get(c) {
+      let f = self.table.rules[*k][*i].clone();
+      let args = args.to_vec();
+      return self.apply_step(f, vec![], args)
+    }
+    let lits = self.solver.lits[c].clone();
+    let ante = self.solver.ante[c].clone();
+    let mut ctx = vec![goal];
+    ctx.extend(lits.iter().map(|&l| l ^ 1));
+    let mut p = self.rup(&mut ctx, &ante)?;
+    for &l in lits.iter().rev() { p = self.step(Step::Intro, vec![p], vec![self.form(l ^ 1)])? }
+    Ok(p)
+  }
+}
+
+impl Elaborator {
+  /// Prove the propositional formula `goal` using the connectives and proof steps in
+  /// `table`, treating all subformulas whose head is not a connective of the table as atoms.
+  /// Returns `#undef` if the formula is not a tautology.
+  pub fn sat_tauto(&mut self, sp: Span, goal: &LispVal, table: &SatTable) -> Result<LispVal> {
+    let cancel = self.cancel.clone();
+    let mut sat = Sat {
+      elab: self, sp, table,
+      forms: vec![], nodes: HashMap::new(), atoms: vec![], rules: vec![],
+      solver: Solver::default(), proofs: vec![],
+    };
+    let goal_lit = lit(sat.encode(goal), true);
+    sat.solver.enqueue(goal_lit, None);
+    let ante = match sat.solver.solve(&cancel) {
+      Outcome::Unsat(ante) => ante,
+      Outcome::Sat => return Ok(LispVal::undef()),
+      Outcome::Cancelled => return Err(ElabError::new_e(sp, "sat-tauto: cancelled")),
+    };
+    // Find the clauses used in the refutation, and prove them in order.
+    let n = sat.solver.clauses.len();
+    let mut used = vec![false; n];
+    for &c in &*ante { used[c] = true }
+    for c in (0..n).rev() {
+      if used[c] { for &d in &*sat.solver.ante[c] { used[d] = true } }
+    }
+    sat.proofs = vec![None; n];
+    for c in (0..n).filter(|&c| used[c]) {
+      let p = sat.prove(c, goal_lit)?;
+      sat.proofs[c] = Some(p)
+    }
+    let p = sat.rup(&mut vec![goal_lit], &ante)?;
+    sat.step(Step::ByContra, vec![p], vec![goal.clone()])
+  }
+}
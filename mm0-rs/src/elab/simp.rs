@@ -0,0 +1,345 @@
+//! The simplifier, which rewrites a term using a set of equational theorems.
+//!
+//! A simp lemma is a theorem or axiom whose conclusion is `R lhs rhs`, where `R` is the
+//! equality for the sort of `lhs` and `lhs` is a term constructor application, like
+//! `add0: $ a + 0 = a $`. The lemmas are registered in the environment using the `@simp`
+//! attribute or `(add-simp!)`, and the simplifier rewrites every instance of `lhs` into
+//! `rhs`, innermost first, until no lemma applies. Lemmas with hypotheses are conditional
+//! rewrites, which are only applied if the instantiated hypotheses can be discharged.
+//!
+//! A lemma is *permutative* if `rhs` is `lhs` with the variables rearranged, like
+//! `addcom: $ a + b = b + a $`. These lemmas would loop if applied freely, so they are only
+//! applied when the result is smaller than the input in a fixed total order on terms
+//! (ordered rewriting), which puts sums and products in a normal form.
+//!
+//! As in [`cc`](super::cc), equality is defined by the library, so the proof is built by lisp
+//! callbacks. See [`BuiltinProc::Simp`](super::lisp::BuiltinProc::Simp) for the interface.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use crate::{AtomId, DeclKey, ExprNode, LispKind, LispVal, Span, ThmId};
+use super::{ElabError, Elaborator, Result};
+
+/// A pattern for one side of a simp lemma.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Pat {
+  /// The theorem variable with this index.
+  Var(usize),
+  /// A term constructor application, with the name of the term.
+  App(AtomId, Box<[Pat]>),
+}
+
+impl Pat {
+  /// Convert the expression `e` in the context of `heap` to a pattern. Returns `None` if
+  /// the expression contains a dummy variable, which cannot be matched.
+  fn from_node(elab: &Elaborator, heap: &[ExprNode], nargs: usize, e: &ExprNode) -> Option<Pat> {
+    match *e {
+      ExprNode::Ref(i) if i < nargs => Some(Pat::Var(i)),
+      ExprNode::Ref(i) => Pat::from_node(elab, heap, nargs, &heap[i]),
+      ExprNode::Dummy(..) => None,
+      ExprNode::App(t, ref es) => Some(Pat::App(elab.terms[t].atom,
+        es.iter().map(|e| Pat::from_node(elab, heap, nargs, e)).collect::<Option<_>>()?)),
+    }
+  }
+
+  /// Get the term at the head of the pattern, if it is an application.
+  fn head(&self) -> Option<AtomId> {
+    if let Pat::App(t, _) = *self { Some(t) } else { None }
+  }
+
+  /// Push the variables in the pattern to `vars`, in order of occurrence.
+  fn vars(&self, vars: &mut Vec<usize>) {
+    match self {
+      &Pat::Var(i) => vars.push(i),
+      Pat::App(_, ps) => for p in &**ps { p.vars(vars) },
+    }
+  }
+
+  /// Returns true if `other` is this pattern with the variables renamed.
+  fn is_permutation(&self, other: &Pat) -> bool {
+    fn go(map: &mut HashMap<usize, usize>, p: &Pat, q: &Pat) -> bool {
+      match (p, q) {
+        (&Pat::Var(i), &Pat::Var(j)) => *map.entry(i).or_insert(j) == j,
+        (Pat::App(t, ps), Pat::App(u, qs)) =>
+          t == u && ps.len() == qs.len() && ps.iter().zip(&**qs).all(|(p, q)| go(map, p, q)),
+        _ => false,
+      }
+    }
+    go(&mut HashMap::new(), self, other)
+  }
+
+  /// Returns true if `e` matches the pattern, assigning the variables in `subst`.
+  fn matches(&self, e: &LispVal, subst: &mut [Option<LispVal>]) -> bool {
+    match self {
+      &Pat::Var(i) => if let Some(v) = &subst[i] { v == e } else {
+        subst[i] = Some(e.clone());
+        true
+      },
+      Pat::App(t, ps) => e.unwrapped(|r| match r {
+        &LispKind::Atom(a) => ps.is_empty() && a == *t,
+        LispKind::List(es) => es.len() == ps.len() + 1 && es[0].as_atom() == Some(*t) &&
+          ps.iter().zip(&es[1..]).all(|(p, e)| p.matches(e, subst)),
+        _ => false,
+      }),
+    }
+  }
+
+  /// Substitute `subst` into the pattern. All the variables in the pattern must be assigned.
+  fn inst(&self, subst: &[Option<LispVal>]) -> LispVal {
+    match self {
+      &Pat::Var(i) => subst[i].clone().expect("unassigned variable"),
+      Pat::App(t, ps) => LispVal::list(
+        std::iter::once(LispVal::atom(*t)).chain(ps.iter().map(|p| p.inst(subst)))
+          .collect::<Vec<_>>()),
+    }
+  }
+}
+
+/// A simp lemma, oriented in the direction it is used.
+#[derive(Debug)]
+struct Lemma {
+  /// The name of the theorem.
+  thm: AtomId,
+  /// The number of variables in the theorem.
+  nargs: usize,
+  /// The side of the equation that is rewritten.
+  from: Pat,
+  /// The side of the equation that is the result of the rewrite.
+  to: Pat,
+  /// The hypotheses of the theorem, which must be discharged to apply it.
+  hyps: Box<[Pat]>,
+  /// True if the lemma is used right to left.
+  rev: bool,
+  /// True if the lemma is permutative, and so is only used to make the term smaller.
+  perm: bool,
+}
+
+impl Elaborator {
+  /// Get the two sides of the conclusion and the hypotheses of a simp lemma,
+  /// or an error message if `t` cannot be used as a simp lemma.
+  fn simp_lemma_parts(&self, t: ThmId) -> std::result::Result<(Pat, Pat, Box<[Pat]>), String> {
+    let td = &self.thms[t];
+    let nargs = td.args.len();
+    let err = || format!("'{}' is not an equation", self.data[td.atom].name);
+    let (lhs, rhs) = match Pat::from_node(self, &td.heap, nargs, &td.ret).ok_or_else(err)? {
+      Pat::App(_, args) if args.len() == 2 => {
+        let mut it = args.into_vec().into_iter();
+        (it.next().expect("impossible"), it.next().expect("impossible"))
+      }
+      _ => return Err(err()),
+    };
+    let hyps = td.hyps.iter().map(|(_, e)| Pat::from_node(self, &td.heap, nargs, e))
+      .collect::<Option<_>>().ok_or_else(err)?;
+    Ok((lhs, rhs, hyps))
+  }
+
+  /// Register the theorem `x` as a simp lemma. It is an error if `x` is not a theorem or
+  /// axiom, or if its conclusion is not an equation whose left side is an application.
+  pub fn add_simp(&mut self, x: AtomId) -> std::result::Result<(), String> {
+    let t = match self.data[x].decl {
+      Some(DeclKey::Thm(t)) => t,
+      _ => return Err(format!("'{}' is not a theorem", self.data[x].name)),
+    };
+    let (lhs, _, _) = self.simp_lemma_parts(t)?;
+    if lhs.head().is_none() {
+      return Err(format!("the left side of '{}' is a variable", self.data[x].name))
+    }
+    if !self.env.simps.contains(&t) { self.env.simps.push(t) }
+    Ok(())
+  }
+
+  /// Simplify `e` using the lemmas `thms`, with the options in `opts` and the callbacks
+  /// in `cbs` to build the proof. Returns `(e2 p)` where `p` is a proof of `e = e2`,
+  /// or `#undef` if no lemma applies.
+  pub fn simp(&mut self, sp: Span, e: &LispVal, thms: &[ThmId], opts: SimpOptions,
+    cbs: SimpCallbacks,
+  ) -> Result<LispVal> {
+    let mut lemmas: HashMap<AtomId, Vec<Lemma>> = HashMap::new();
+    for &t in thms {
+      let (lhs, rhs, hyps) = self.simp_lemma_parts(t).map_err(|e| ElabError::new_e(sp, e))?;
+      let (from, to) = if opts.rev {(rhs, lhs)} else {(lhs, rhs)};
+      // A lemma can only be used if its variables are determined by the side that is matched,
+      // and if the hypotheses can be discharged.
+      let mut vs = vec![];
+      from.vars(&mut vs);
+      let nargs = self.thms[t].args.len();
+      let head = match from.head() { Some(head) => head, None => continue };
+      if (0..nargs).any(|i| !vs.contains(&i)) || !hyps.is_empty() && cbs.discharge.is_none() { continue }
+      let perm = from.is_permutation(&to);
+      lemmas.entry(head).or_default().push(Lemma {
+        thm: self.thms[t].atom, nargs, from, to, hyps, rev: opts.rev, perm
+      })
+    }
+    let mut s = Simp { elab: self, sp, cbs, lemmas, fuel: opts.depth };
+    Ok(match s.simp(e)? {
+      (_, None) => LispVal::undef(),
+      (e2, Some(p)) => LispVal::list(vec![e2, p]),
+    })
+  }
+}
+
+/// The lisp callbacks for [`Elaborator::simp`], which build the proof.
+#[derive(Debug)]
+pub struct SimpCallbacks {
+  /// The `refl`, `symm`, `trans` and `congr` callbacks, as in
+  /// [`congruence_closure`](Elaborator::congruence_closure).
+  pub fns: [LispVal; 4],
+  /// The `rw` callback, which puts a lemma application in context.
+  pub rw: Option<LispVal>,
+  /// The `discharge` callback, which proves the hypotheses of a conditional lemma.
+  pub discharge: Option<LispVal>,
+}
+
+/// The options for [`Elaborator::simp`].
+#[derive(Copy, Clone, Debug)]
+pub struct SimpOptions {
+  /// If true, the lemmas are used right to left.
+  pub rev: bool,
+  /// The maximum number of rewrites. The simplifier stops and returns the current term
+  /// when this runs out.
+  pub depth: usize,
+}
+
+impl Default for SimpOptions {
+  fn default() -> Self { Self { rev: false, depth: 1000 } }
+}
+
+/// Compare two terms in the order used for ordered rewriting: first by size, then by the
+/// name of the head, then by the arguments from left to right. Anything that is not an atom
+/// or application is treated as equal to everything else.
+fn term_cmp(elab: &Elaborator, a: &LispVal, b: &LispVal) -> Ordering {
+  fn size(e: &LispVal) -> usize {
+    e.unwrapped(|r| match r {
+      LispKind::List(es) => 1 + es[1..].iter().map(size).sum::<usize>(),
+      _ => 1,
+    })
+  }
+  fn cmp(elab: &Elaborator, a: &LispVal, b: &LispVal) -> Ordering {
+    a.unwrapped(|r1| b.unwrapped(|r2| match (r1, r2) {
+      (&LispKind::Atom(x), &LispKind::Atom(y)) => elab.data[x].name.cmp(&elab.data[y].name),
+      (LispKind::Atom(_), LispKind::List(_)) => Ordering::Less,
+      (LispKind::List(_), LispKind::Atom(_)) => Ordering::Greater,
+      (LispKind::List(es1), LispKind::List(es2)) => es1.len().cmp(&es2.len()).then_with(||
+        es1.iter().zip(&**es2).map(|(e1, e2)| cmp(elab, e1, e2))
+          .find(|&o| o != Ordering::Equal).unwrap_or(Ordering::Equal)),
+      _ => Ordering::Equal,
+    }))
+  }
+  size(a).cmp(&size(b)).then_with(|| cmp(elab, a, b))
+}
+
+/// The state of the simplifier.
+struct Simp<'a> {
+  elab: &'a mut Elaborator,
+  sp: Span,
+  cbs: SimpCallbacks,
+  /// The lemmas, indexed by the head of the side that is matched.
+  lemmas: HashMap<AtomId, Vec<Lemma>>,
+  /// The number of rewrites that can still be performed.
+  fuel: usize,
+}
+
+impl Simp<'_> {
+  fn call(&mut self, f: LispVal, args: Vec<LispVal>) -> Result<LispVal> {
+    self.elab.call_func(self.sp, f, args)
+  }
+
+  fn refl(&mut self, x: &LispVal) -> Result<LispVal> {
+    self.call(self.cbs.fns[0].clone(), vec![x.clone()])
+  }
+
+  /// Combine proofs `p: x = y` and `q: y = z`, where `None` means reflexivity.
+  fn trans(&mut self, lhs: &LispVal, p1: Option<LispVal>, mid: &LispVal, p2: Option<LispVal>,
+    rhs: &LispVal,
+  ) -> Result<Option<LispVal>> {
+    Ok(match (p1, p2) {
+      (None, p) | (p, None) => p,
+      (Some(p1), Some(p2)) => Some(self.call(self.cbs.fns[2].clone(),
+        vec![p1, p2, lhs.clone(), mid.clone(), rhs.clone()])?),
+    })
+  }
+
+  /// Simplify the arguments of `e`, returning the new term and a proof that it is equal to `e`.
+  fn simp_args(&mut self, e: &LispVal) -> Result<(LispVal, Option<LispVal>)> {
+    let (t, xs) = match e.unwrapped(|r| match r {
+      LispKind::List(es) if !es.is_empty() => match es[0].as_atom() {
+        Some(t) if matches!(self.elab.data[t].decl, Some(DeclKey::Term(_))) => Some((t, es[1..].to_vec())),
+        _ => None,
+      },
+      _ => None,
+    }) {
+      Some(r) => r,
+      None => return Ok((e.clone(), None)),
+    };
+    let mut res = Vec::with_capacity(xs.len());
+    for x in &xs { res.push(self.simp(x)?) }
+    if res.iter().all(|(_, p)| p.is_none()) { return Ok((e.clone(), None)) }
+    let mut ys = Vec::with_capacity(xs.len());
+    let mut ps = Vec::with_capacity(xs.len());
+    for (x, (y, p)) in xs.iter().zip(res) {
+      ps.push(match p { Some(p) => p, None => self.refl(x)? });
+      ys.push(y)
+    }
+    let e2 = LispVal::list(std::iter::once(LispVal::atom(t)).chain(ys.iter().cloned()).collect::<Vec<_>>());
+    let p = self.call(self.cbs.fns[3].clone(),
+      vec![LispVal::atom(t), LispVal::list(xs), LispVal::list(ys), LispVal::list(ps)])?;
+    Ok((e2, Some(p)))
+  }
+
+  /// Rewrite `e` at the root using the first lemma that applies, returning the result and
+  /// a proof that it is equal to `e`.
+  fn rewrite(&mut self, e: &LispVal) -> Result<Option<(LispVal, LispVal)>> {
+    let head = match e.unwrapped(|r| match r {
+      &LispKind::Atom(a) => Some(a),
+      LispKind::List(es) => es.first().and_then(|e| e.as_atom()),
+      _ => None,
+    }) {
+      Some(t) => t,
+      None => return Ok(None),
+    };
+    let len = self.lemmas.get(&head).map_or(0, Vec::len);
+    'next: for i in 0..len {
+      let lem = &self.lemmas[&head][i];
+      let mut subst = vec![None; lem.nargs];
+      if !lem.from.matches(e, &mut subst) { continue }
+      let e2 = lem.to.inst(&subst);
+      if lem.perm && term_cmp(self.elab, &e2, e) != Ordering::Less { continue }
+      let (thm, rev, hyps) = (lem.thm, lem.rev, lem.hyps.clone());
+      let mut args: Vec<_> = [LispVal::atom(AtomId::BANG), LispVal::atom(thm)].into_iter()
+        .chain(subst.iter().map(|v| v.clone().expect("checked")))
+        .collect();
+      for h in &*hyps {
+        let h = h.inst(&subst);
+        let discharge = self.cbs.discharge.clone().expect("checked");
+        let p = self.call(discharge, vec![h])?;
+        if !p.is_def() { continue 'next }
+        args.push(p)
+      }
+      let (lhs, rhs) = if rev {(&e2, e)} else {(e, &e2)};
+      let mut p = LispVal::list(args);
+      if let Some(rw) = self.cbs.rw.clone() {
+        p = self.call(rw, vec![p, lhs.clone(), rhs.clone()])?
+      }
+      if rev { p = self.call(self.cbs.fns[1].clone(), vec![p, e2.clone(), e.clone()])? }
+      return Ok(Some((e2, p)))
+    }
+    Ok(None)
+  }
+
+  /// Simplify `e`, returning the new term and a proof that it is equal to `e`, or `None` if
+  /// it is unchanged.
+  fn simp(&mut self, e: &LispVal) -> Result<(LispVal, Option<LispVal>)> {
+    let (e1, p1) = self.simp_args(e)?;
+    if self.fuel == 0 { return Ok((e1, p1)) }
+    match self.rewrite(&e1)? {
+      None => Ok((e1, p1)),
+      Some((e2, p2)) => {
+        self.fuel -= 1;
+        let p = self.trans(e, p1, &e1, Some(p2), &e2)?;
+        let (e3, p3) = self.simp(&e2)?;
+        let p = self.trans(e, p, &e2, p3, &e3)?;
+        Ok((e3, p))
+      }
+    }
+  }
+}
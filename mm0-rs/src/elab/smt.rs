@@ -0,0 +1,467 @@
+//! A bridge to external SMT solvers like z3 and cvc5, using the SMT-LIB2 format.
+//!
+//! The hypotheses and the negated goal are translated to SMT-LIB2 using a table which maps
+//! MM0 sorts to SMT sorts and term constructors to SMT operators. Term constructors that are
+//! not in the table become uninterpreted functions, and subterms that cannot be translated
+//! (like metavariables) become fresh constants. Theorems of the environment can also be sent
+//! as universally quantified axioms. The solver is run as a subprocess, and its answer is
+//! translated back: for `unsat`, the unsat core, which says which hypotheses and theorems
+//! are needed for the proof, and for `sat`, the model, which is a counterexample.
+//!
+//! The solver is not trusted: its answer only guides a proof search in lisp, for example by
+//! passing the hypotheses of the unsat core to [`lia`](super::lisp::BuiltinProc::Lia)
+//! or [`sat-tauto`](super::lisp::BuiltinProc::SatTauto). Since the solver is an arbitrary
+//! program, running it must be enabled with the `--allow-smt` flag, which runs `z3 -in`, or
+//! the `--smt-cmd` option, which gives the command. The solver command is deliberately not
+//! configurable from lisp, since that would let any MM1 file run arbitrary programs. See
+//! [`BuiltinProc::Smt`](super::lisp::BuiltinProc::Smt) for the interface.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Write as _};
+use std::io::{BufRead, BufReader, Write};
+use std::process::{ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use num::{BigInt, Signed};
+use crate::{AtomId, DeclKey, ExprNode, LispKind, LispVal, SortId, Span, TermId, ThmId};
+use super::{CancelToken, ElabError, Elaborator, Result};
+
+/// The table of sorts, operators and options used by [`Elaborator::smt`].
+#[derive(Debug)]
+pub struct SmtTable {
+  /// The SMT sort of each MM0 sort that can be translated.
+  sorts: HashMap<SortId, String>,
+  /// The sort which is translated to `Bool`, which is the sort of the hypotheses.
+  bool_sort: SortId,
+  /// The SMT operator for each term constructor that is built in to the solver.
+  ops: HashMap<TermId, String>,
+  /// `(num e)` returns the value of `e` if it is a numeral, and `#undef` otherwise.
+  num: Option<LispVal>,
+  /// The theorems that are sent to the solver as axioms.
+  lemmas: Vec<ThmId>,
+  /// The time limit for the solver.
+  timeout: Option<Duration>,
+}
+
+/// Get the name in a string or atom.
+fn name_of(elab: &Elaborator, e: &LispVal) -> Option<String> {
+  e.unwrapped(|r| match r {
+    LispKind::String(s) => Some(s.to_string()),
+    &LispKind::Atom(a) => Some(elab.data[a].name.to_string()),
+    _ => None,
+  })
+}
+
+/// Get the contents of an atom map.
+fn atom_map(e: &LispVal) -> Option<HashMap<AtomId, LispVal>> {
+  e.unwrapped(|r| if let LispKind::AtomMap(m) = r { Some(m.clone()) } else { None })
+}
+
+impl SmtTable {
+  /// Read the table from an atom map. See [`BuiltinProc::Smt`](super::lisp::BuiltinProc::Smt)
+  /// for the keys.
+  pub fn new(elab: &mut Elaborator, m: &HashMap<AtomId, LispVal>) -> std::result::Result<Self, String> {
+    let mut get = |s: &str| m.get(&elab.get_atom(s.as_bytes())).cloned();
+    let (sorts_map, ops_map, num, lemmas_list, timeout) =
+      (get("sorts"), get("ops"), get("num"), get("lemmas"), get("timeout"));
+    let sorts_map = sorts_map.and_then(|e| atom_map(&e)).ok_or("smt: 'sorts should be an atom map")?;
+    let mut sorts = HashMap::new();
+    for (a, e) in sorts_map {
+      let s = elab.data[a].sort.ok_or_else(|| format!("smt: unknown sort '{}'", elab.data[a].name))?;
+      sorts.insert(s, name_of(elab, &e).ok_or("smt: expected a string")?);
+    }
+    let bool_sort = sorts.iter().find(|(_, s)| *s == "Bool").map(|(&s, _)| s)
+      .ok_or("smt: no sort is mapped to Bool")?;
+    let mut ops = HashMap::new();
+    for (a, e) in ops_map.map_or_else(|| Some(HashMap::new()), |e| atom_map(&e))
+      .ok_or("smt: 'ops should be an atom map")? {
+      let t = match elab.data[a].decl {
+        Some(DeclKey::Term(t)) => t,
+        _ => return Err(format!("smt: unknown term constructor '{}'", elab.data[a].name)),
+      };
+      ops.insert(t, name_of(elab, &e).ok_or("smt: expected a string")?);
+    }
+    let mut lemmas = vec![];
+    if let Some(e) = lemmas_list {
+      let mut it = crate::Uncons::from(e);
+      for e in &mut it {
+        match e.as_atom().and_then(|a| elab.data[a].decl) {
+          Some(DeclKey::Thm(t)) => lemmas.push(t),
+          _ => return Err(format!("smt: unknown theorem {}", elab.print(&e))),
+        }
+      }
+      if !it.is_empty() { return Err("smt: 'lemmas should be a list".into()) }
+    }
+    let timeout = match timeout {
+      None => None,
+      Some(e) => Some(Duration::from_millis(e.as_int(num::ToPrimitive::to_u64).flatten()
+        .ok_or("smt: 'timeout should be a number of milliseconds")?)),
+    };
+    Ok(Self { sorts, bool_sort, ops, num, lemmas, timeout })
+  }
+}
+
+/// An s-expression in the output of the solver.
+#[derive(Debug)]
+enum SmtExpr {
+  /// A symbol, numeral or string literal. Quoted symbols are stored without the bars.
+  Atom(String),
+  /// A list.
+  List(Vec<SmtExpr>),
+}
+
+impl fmt::Display for SmtExpr {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      SmtExpr::Atom(s) => write!(f, "{}", s),
+      SmtExpr::List(es) => {
+        write!(f, "(")?;
+        for (i, e) in es.iter().enumerate() {
+          if i != 0 { write!(f, " ")? }
+          write!(f, "{}", e)?
+        }
+        write!(f, ")")
+      }
+    }
+  }
+}
+
+impl SmtExpr {
+  /// Parse the first s-expression in `s`.
+  fn parse(s: &str) -> Option<SmtExpr> {
+    let mut stack: Vec<Vec<SmtExpr>> = vec![];
+    let mut it = s.char_indices().peekable();
+    while let Some((i, c)) = it.next() {
+      let e = match c {
+        ';' => { while it.next_if(|&(_, c)| c != '\n').is_some() {} continue }
+        c if c.is_whitespace() => continue,
+        '(' => { stack.push(vec![]); continue }
+        ')' => SmtExpr::List(stack.pop()?),
+        '|' => {
+          let start = i + 1;
+          let end = loop { if let (j, '|') = it.next()? { break j } };
+          SmtExpr::Atom(s[start..end].into())
+        }
+        '"' => {
+          // A `""` inside a string literal is an escaped quote.
+          let end = loop {
+            if let (j, '"') = it.next()? {
+              if it.next_if(|&(_, c)| c == '"').is_none() { break j + 1 }
+            }
+          };
+          SmtExpr::Atom(s[i..end].into())
+        }
+        _ => {
+          let mut end = i + c.len_utf8();
+          while let Some((j, c)) = it.next_if(|&(_, c)| !c.is_whitespace() && !"()|\";".contains(c)) {
+            end = j + c.len_utf8()
+          }
+          SmtExpr::Atom(s[i..end].into())
+        }
+      };
+      match stack.last_mut() {
+        None => return Some(e),
+        Some(es) => es.push(e),
+      }
+    }
+    None
+  }
+
+  /// Convert a value in a model to lisp: numerals become numbers, `true` and `false`
+  /// become booleans, and anything else becomes a string.
+  fn to_lisp(&self) -> LispVal {
+    match self {
+      SmtExpr::Atom(s) if s == "true" => LispVal::bool(true),
+      SmtExpr::Atom(s) if s == "false" => LispVal::bool(false),
+      SmtExpr::Atom(s) => match s.parse::<BigInt>() {
+        Ok(n) => LispVal::number(n),
+        Err(_) => LispVal::string(s.clone().into()),
+      },
+      SmtExpr::List(es) => match &**es {
+        [SmtExpr::Atom(m), SmtExpr::Atom(s)] if m == "-" => match s.parse::<BigInt>() {
+          Ok(n) => LispVal::number(-n),
+          Err(_) => LispVal::string(self.to_string().into()),
+        },
+        _ => LispVal::string(self.to_string().into()),
+      },
+    }
+  }
+}
+
+/// The answer of the solver.
+enum Answer {
+  /// The assertions are unsatisfiable, with the names in the unsat core.
+  Unsat(Vec<String>),
+  /// The assertions are satisfiable, with the model, which is a list of `define-fun`s.
+  Sat(Vec<SmtExpr>),
+  /// The solver could not decide.
+  Unknown,
+}
+
+/// Run the solver `cmd` on `script`, which ends with `(check-sat)`, and then ask for the
+/// unsat core or the model.
+fn run_solver(cmd: &[String], script: &str, timeout: Option<Duration>, cancel: &CancelToken,
+) -> std::result::Result<Answer, String> {
+  let mut child = Command::new(&cmd[0]).args(&cmd[1..])
+    .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::null())
+    .spawn().map_err(|e| format!("smt: failed to run {}: {}", cmd[0], e))?;
+  let stdin = child.stdin.take().expect("piped");
+  let stdout = child.stdout.take().expect("piped");
+  // The output is read on another thread, so that the solver can be killed on a timeout.
+  let (send, recv) = mpsc::channel();
+  std::thread::spawn(move || {
+    for line in BufReader::new(stdout).lines() {
+      if send.send(line).is_err() { break }
+    }
+  });
+  let deadline = timeout.map(|t| Instant::now() + t);
+  let mut next_line = || loop {
+    match recv.recv_timeout(Duration::from_millis(10)) {
+      Ok(line) => return Ok(line.ok()),
+      Err(RecvTimeoutError::Disconnected) => return Ok(None),
+      Err(RecvTimeoutError::Timeout) => {
+        if cancel.is_canceled() { return Err("smt: cancelled") }
+        if deadline.map_or(false, |d| d < Instant::now()) { return Err("smt: timeout") }
+      }
+    }
+  };
+  let res = converse(stdin, &mut next_line, script);
+  if res.is_err() { let _ = child.kill(); }
+  let _ = child.wait();
+  res
+}
+
+/// Write `script` to the solver, read the answer to `(check-sat)`, and ask for the
+/// unsat core or the model. `next_line` reads a line of the output, or returns `None`
+/// at the end of the output.
+fn converse(mut stdin: ChildStdin,
+  next_line: &mut impl FnMut() -> std::result::Result<Option<String>, &'static str>, script: &str,
+) -> std::result::Result<Answer, String> {
+  stdin.write_all(script.as_bytes()).and_then(|()| stdin.flush())
+    .map_err(|e| format!("smt: failed to write to the solver: {}", e))?;
+  let status = loop {
+    match next_line()? {
+      None => return Err("smt: the solver exited without an answer".into()),
+      Some(l) if l.trim().is_empty() => {}
+      Some(l) => break l.trim().to_owned(),
+    }
+  };
+  let query = match &*status {
+    "unsat" => "(get-unsat-core)\n(exit)\n",
+    "sat" => "(get-model)\n(exit)\n",
+    "unknown" => "(exit)\n",
+    _ => return Err(format!("smt: unexpected answer from the solver: {}", status)),
+  };
+  // The solver may have exited already, in which case there is nothing more to read.
+  let _ = stdin.write_all(query.as_bytes()).and_then(|()| stdin.flush());
+  drop(stdin);
+  let mut out = String::new();
+  while let Some(l) = next_line()? { out.push_str(&l); out.push('\n') }
+  let resp = SmtExpr::parse(&out);
+  if let Some(SmtExpr::List(es)) = &resp {
+    if let Some(SmtExpr::Atom(s)) = es.first() {
+      if s == "error" { return Err(format!("smt: solver error: {}", resp.expect("some"))) }
+    }
+  }
+  Ok(match (&*status, resp) {
+    ("unsat", Some(SmtExpr::List(es))) => Answer::Unsat(es.into_iter().map(|e| e.to_string()).collect()),
+    ("sat", Some(SmtExpr::List(mut es))) => {
+      // Some solvers print `(model ...)` instead of a bare list.
+      if matches!(es.first(), Some(SmtExpr::Atom(s)) if s == "model") { es.remove(0); }
+      Answer::Sat(es)
+    }
+    ("unknown", _) => Answer::Unknown,
+    _ => return Err("smt: could not parse the output of the solver".into()),
+  })
+}
+
+/// The state of the translation to SMT-LIB2.
+struct Smt<'a> {
+  elab: &'a mut Elaborator,
+  sp: Span,
+  table: &'a SmtTable,
+  /// The declarations of the constants and functions used so far.
+  decls: String,
+  /// The MM0 term of each declared constant.
+  names: HashMap<String, LispVal>,
+  /// The uninterpreted functions which have been declared.
+  funs: HashSet<TermId>,
+  /// The subterms that were translated as fresh constants.
+  opaque: Vec<LispVal>,
+}
+
+impl Smt<'_> {
+  /// Declare a constant of sort `s` for the term `e`, if it is not already declared.
+  fn constant(&mut self, name: String, s: &str, e: &LispVal) -> String {
+    let q = format!("|{}|", name);
+    if self.names.insert(name, e.clone()).is_none() {
+      writeln!(self.decls, "(declare-const {} {})", q, s).expect("writing to a string")
+    }
+    q
+  }
+
+  /// Translate the application of `t` to the translated arguments `args`, declaring it
+  /// as an uninterpreted function if it is not built in.
+  fn app(&mut self, t: TermId, args: &[String]) -> Option<String> {
+    let td = &self.elab.terms[t];
+    let op = if let Some(op) = self.table.ops.get(&t) { op.clone() } else {
+      let name = format!("|t.{}|", self.elab.data[td.atom].name);
+      let sorts = td.args.iter().map(|(_, ty)| self.table.sorts.get(&ty.sort()))
+        .collect::<Option<Vec<_>>>()?;
+      let ret = self.table.sorts.get(&td.ret.0)?;
+      if self.funs.insert(t) {
+        let sorts = sorts.iter().map(|s| s.as_str()).collect::<Vec<_>>().join(" ");
+        writeln!(self.decls, "(declare-fun {} ({}) {})", name, sorts, ret).expect("writing to a string");
+      }
+      name
+    };
+    if args.is_empty() { return Some(op) }
+    Some(format!("({} {})", op, args.join(" ")))
+  }
+
+  /// Translate the term `e` of sort `s`, or return `None` if the sort is not supported.
+  fn expr(&mut self, e: &LispVal, s: SortId) -> Result<Option<String>> {
+    enum Shape { Var(AtomId), App(TermId, Vec<LispVal>), Other }
+    if let Some(num) = self.table.num.clone() {
+      let n = self.elab.call_func(self.sp, num, vec![e.clone()])?;
+      if let Some(n) = n.as_int(|n| BigInt::from(n.clone())) {
+        return Ok(Some(if n.is_negative() { format!("(- {})", -n) } else { n.to_string() }))
+      }
+    }
+    let sort = match self.table.sorts.get(&s) { Some(s) => s.clone(), None => return Ok(None) };
+    let shape = e.unwrapped(|r| match r {
+      &LispKind::Atom(a) => match self.elab.data[a].decl {
+        Some(DeclKey::Term(t)) => Shape::App(t, vec![]),
+        _ => Shape::Var(a),
+      },
+      LispKind::List(es) if !es.is_empty() => match es[0].as_atom().and_then(|a| self.elab.data[a].decl) {
+        Some(DeclKey::Term(t)) => Shape::App(t, es[1..].to_vec()),
+        _ => Shape::Other,
+      },
+      _ => Shape::Other,
+    });
+    match shape {
+      Shape::Var(a) => {
+        let name = format!("v.{}", self.elab.data[a].name);
+        return Ok(Some(self.constant(name, &sort, e)))
+      }
+      Shape::App(t, es) if es.len() == self.elab.terms[t].args.len() => {
+        let sorts = self.elab.terms[t].args.iter().map(|(_, ty)| ty.sort()).collect::<Vec<_>>();
+        let mut args = Vec::with_capacity(es.len());
+        for (e, s) in es.iter().zip(sorts) {
+          match self.expr(e, s)? { Some(a) => args.push(a), None => break }
+        }
+        if args.len() == es.len() {
+          if let Some(r) = self.app(t, &args) { return Ok(Some(r)) }
+        }
+      }
+      _ => {}
+    }
+    let i = self.opaque.iter().position(|e2| e2 == e).unwrap_or_else(|| {
+      self.opaque.push(e.clone());
+      self.opaque.len() - 1
+    });
+    Ok(Some(self.constant(format!("x.{}", i), &sort, e)))
+  }
+
+  /// Translate a node of a theorem statement, returning the translation and its sort.
+  fn node(&mut self, heap: &[Option<(String, SortId)>], e: &ExprNode) -> Option<(String, SortId)> {
+    match *e {
+      ExprNode::Ref(i) => heap[i].clone(),
+      ExprNode::Dummy(..) => None,
+      ExprNode::App(t, ref es) => {
+        let args = es.iter().map(|e| self.node(heap, e).map(|p| p.0)).collect::<Option<Vec<_>>>()?;
+        Some((self.app(t, &args)?, self.elab.terms[t].ret.0))
+      }
+    }
+  }
+
+  /// Translate the statement of theorem `t` as a universally quantified formula.
+  fn lemma(&mut self, t: ThmId) -> Option<String> {
+    let td = &self.elab.thms[t];
+    let (args, heap, hyps, ret) = (td.args.clone(), td.heap.clone(), td.hyps.clone(), td.ret.clone());
+    let mut vars = Vec::with_capacity(heap.len());
+    let mut binders = String::new();
+    for (i, (_, ty)) in args.iter().enumerate() {
+      vars.push(self.table.sorts.get(&ty.sort()).map(|s| {
+        write!(binders, "(|b.{}| {})", i, s).expect("writing to a string");
+        (format!("|b.{}|", i), ty.sort())
+      }))
+    }
+    for e in &heap[args.len()..] {
+      let e = self.node(&vars, e);
+      vars.push(e)
+    }
+    let mut prop = |e: &ExprNode| self.node(&vars, e)
+      .and_then(|(e, s)| if s == self.table.bool_sort { Some(e) } else { None });
+    let hyps = hyps.iter().map(|(_, e)| prop(e)).collect::<Option<Vec<_>>>()?;
+    let mut stmt = prop(&ret)?;
+    if !hyps.is_empty() { stmt = format!("(=> (and true {}) {})", hyps.join(" "), stmt) }
+    if !binders.is_empty() { stmt = format!("(forall ({}) {})", binders, stmt) }
+    Some(stmt)
+  }
+}
+
+impl Elaborator {
+  /// Ask the SMT solver in `table` whether `goal` follows from `hyps`, where each hypothesis
+  /// `(p, e)` is a proof `p` of `e`, or whether the hypotheses are contradictory if `goal`
+  /// is `None`. See [`BuiltinProc::Smt`](super::lisp::BuiltinProc::Smt) for the result.
+  pub fn smt(&mut self, sp: Span, hyps: &[(LispVal, LispVal)], goal: Option<&LispVal>,
+    table: &SmtTable,
+  ) -> Result<LispVal> {
+    let cmd = crate::get_smt_cmd().ok_or_else(|| ElabError::new_e(sp,
+      "smt: running external solvers is disabled (use --allow-smt or --smt-cmd to enable it)"))?;
+    let cancel = self.cancel.clone();
+    let mut smt = Smt {
+      elab: self, sp, table,
+      decls: String::new(), names: HashMap::new(), funs: HashSet::new(), opaque: vec![],
+    };
+    let mut asserts = String::new();
+    let mut assert = |e: &str, name: &str| writeln!(asserts, "(assert (! {} :named |{}|))", e, name)
+      .expect("writing to a string");
+    // Hypotheses that cannot be translated are not sent, and are never in the unsat core.
+    for (i, (_, e)) in hyps.iter().enumerate() {
+      if let Some(e) = smt.expr(e, table.bool_sort)? { assert(&e, &format!("h.{}", i)) }
+    }
+    for (i, &t) in table.lemmas.iter().enumerate() {
+      if let Some(e) = smt.lemma(t) { assert(&e, &format!("l.{}", i)) }
+    }
+    if let Some(g) = goal {
+      match smt.expr(g, table.bool_sort)? {
+        Some(g) => assert(&format!("(not {})", g), "goal"),
+        None => return Err(ElabError::new_e(sp, format!("smt: cannot translate {}", smt.elab.print(g)))),
+      }
+    }
+    let script = format!("(set-option :produce-unsat-cores true)\n(set-option :produce-models true)\n\
+      (set-logic ALL)\n{}{}(check-sat)\n", smt.decls, asserts);
+    let answer = run_solver(&cmd, &script, table.timeout, &cancel)
+      .map_err(|e| ElabError::new_e(sp, e))?;
+    Ok(match answer {
+      Answer::Unsat(core) => {
+        let (mut hs, mut lems) = (vec![], vec![]);
+        for name in core {
+          if let Some(i) = name.strip_prefix("h.").and_then(|i| i.parse::<usize>().ok()) {
+            if let Some((p, e)) = hyps.get(i) { hs.push(LispVal::list(vec![p.clone(), e.clone()])) }
+          } else if let Some(i) = name.strip_prefix("l.").and_then(|i| i.parse::<usize>().ok()) {
+            if let Some(&t) = table.lemmas.get(i) { lems.push(LispVal::atom(smt.elab.thms[t].atom)) }
+          }
+        }
+        LispVal::list(vec![LispVal::atom(smt.elab.get_atom(b"unsat")), LispVal::list(hs), LispVal::list(lems)])
+      }
+      Answer::Sat(model) => {
+        let mut vals = vec![];
+        for def in model {
+          if let SmtExpr::List(es) = def {
+            if let [SmtExpr::Atom(df), SmtExpr::Atom(x), SmtExpr::List(args), _, v] = &*es {
+              if let (true, true, Some(e)) = (df == "define-fun", args.is_empty(), smt.names.get(x)) {
+                vals.push(LispVal::list(vec![e.clone(), v.to_lisp()]))
+              }
+            }
+          }
+        }
+        LispVal::list(vec![LispVal::atom(smt.elab.get_atom(b"sat")), LispVal::list(vals)])
+      }
+      Answer::Unknown => LispVal::list(vec![LispVal::atom(smt.elab.get_atom(b"unknown"))]),
+    })
+  }
+}
@@ -0,0 +1,218 @@
+//! Snapshots of the elaborator state, used for incremental elaboration.
+//!
+//! When the server elaborates a file again after an edit, the statements before the edit
+//! are unchanged, and we would like to skip them. The final [`Environment`] of the previous
+//! elaboration can't be used for this, because we would have to undo the later statements,
+//! and lisp code can change the global state in ways that are not tracked (for example by
+//! mutating a `ref!` that is reachable from an earlier definition). So instead, the
+//! elaborator takes a [`Snapshot`] of its state every so often, and the next elaboration
+//! restarts from the last snapshot before the first changed statement.
+//!
+//! The lisp data is deep copied when a snapshot is taken, and again when it is restored,
+//! so a snapshot is not affected by anything that happens after it was taken.
+//! Since taking a snapshot costs about as much as importing the file up to that point,
+//! snapshots are only taken if [`ElaborateBuilder::snapshots`](super::ElaborateBuilder::snapshots)
+//! is set, and they are spaced out so that the time spent taking them is a small fraction
+//! of the elaboration time.
+
+use std::mem;
+use std::time::{Duration, Instant};
+use crate::{EnvMergeIter, Environment, FrozenEnv, Remap, Remapper, Span};
+use super::{lisp::eval::EvalLimits, namespace::Scopes, warning::WarningConfig, ElabError,
+  Elaborator, ReportMode};
+
+/// The minimum number of statements between two snapshots.
+const MIN_INTERVAL: usize = 16;
+
+/// A snapshot is taken only once the time since the last snapshot is at least this many
+/// times the time it took to take the last snapshot.
+const COST_RATIO: u32 = 4;
+
+/// The settings of the [`Elaborator`] that can be changed by lisp code.
+#[derive(Copy, Clone, Debug)]
+struct Settings {
+  timeout: Option<Duration>,
+  limits: EvalLimits,
+  check_proofs: bool,
+  reporting: ReportMode,
+  backtrace: ReportMode,
+  warnings: WarningConfig,
+}
+crate::deep_size_0!(Settings);
+
+/// A copy of the state of the elaborator after the first `idx` statements of a file.
+#[derive(Clone, Debug, DeepSizeOf)]
+pub(crate) struct Snapshot {
+  /// The number of statements that were elaborated when the snapshot was taken.
+  pub(crate) idx: usize,
+  /// The environment. The spans are not included, since they can be recovered from
+  /// the final environment of the elaboration.
+  env: FrozenEnv,
+  /// The elaborator settings.
+  settings: Settings,
+  /// The open sections and namespaces.
+  scopes: Scopes,
+}
+
+/// The snapshots of an elaboration in progress.
+#[derive(Debug)]
+pub(crate) struct Snapshots {
+  /// The snapshots so far, including the ones reused from the previous elaboration.
+  snaps: Vec<Snapshot>,
+  /// The time at which the last snapshot was taken, or elaboration started.
+  since: Instant,
+  /// The time it took to take the last snapshot.
+  cost: Duration,
+}
+
+impl Snapshots {
+  /// Start taking snapshots, after the reused snapshots `snaps`.
+  pub(crate) fn new(snaps: &[Snapshot]) -> Self {
+    Snapshots {snaps: snaps.to_vec(), since: Instant::now(), cost: Duration::default()}
+  }
+
+  /// Take a snapshot of `elab` before statement `idx`, if one is due.
+  pub(crate) fn step(&mut self, elab: &mut Elaborator, idx: usize) {
+    let last = self.snaps.last().map_or(0, |s| s.idx);
+    if idx >= last + MIN_INTERVAL && self.since.elapsed() >= self.cost * COST_RATIO {
+      let start = Instant::now();
+      self.snaps.push(elab.snapshot(last, idx));
+      self.since = Instant::now();
+      self.cost = self.since - start;
+    }
+  }
+
+  /// Get the list of snapshots, to be stored in the final environment.
+  pub(crate) fn finish(self) -> Vec<Snapshot> { self.snaps }
+}
+
+impl FrozenEnv {
+  /// The snapshots taken while elaborating this environment, in order.
+  pub(crate) fn snapshots(&self) -> &[Snapshot] { &unsafe { self.thaw() }.snapshots }
+}
+
+impl Elaborator {
+  /// Replace the environment with a copy of `env`. The spans are not copied.
+  fn copy_env(&mut self, env: &FrozenEnv) {
+    self.env = Environment::new();
+    let mut errors = vec![];
+    let mut it = EnvMergeIter::new(&mut self.env, env, Span::default());
+    // The environment is initially empty, so the only merges that are requested are for
+    // globals that are defined more than once, and these all have the final value.
+    while let Ok(Some(mut merge)) = it.next(&mut self.env, &mut errors) {
+      merge.val = merge.new.val.clone();
+      merge.apply(&mut self.env)
+    }
+    debug_assert!(errors.is_empty());
+  }
+
+  /// Take a snapshot of the current state, after the first `idx` statements.
+  /// `last` is the position of the previous snapshot, or 0 if there is none.
+  fn snapshot(&mut self, last: usize, idx: usize) -> Snapshot {
+    // The local context can refer to lisp data that is moved into the snapshot.
+    self.lc.clear();
+    let mut spans = mem::take(&mut self.env.spans);
+    let env = FrozenEnv::new(mem::take(&mut self.env));
+    self.copy_env(&env);
+    // The spans of the statements since the last snapshot can share lisp data with
+    // the snapshot, so they are copied (the earlier ones were copied already).
+    let r = &mut Remapper::identity(&env);
+    for s in spans.iter_mut().skip(last) { *s = s.remap(r) }
+    self.env.spans = spans;
+    let settings = Settings {
+      timeout: self.timeout,
+      limits: self.limits,
+      check_proofs: self.check_proofs,
+      reporting: self.reporting,
+      backtrace: self.backtrace,
+      warnings: self.warnings,
+    };
+    Snapshot {idx, env, settings, scopes: self.scopes.clone()}
+  }
+
+  /// Restore the state of a previous elaboration of the same file, whose first `idx`
+  /// statements are unchanged. `env` and `errs` are the environment and errors produced by
+  /// the previous elaboration.
+  ///
+  /// Returns the number of statements that can be skipped, which is the position of the last
+  /// snapshot in `env` that is not after statement `idx` (or 0 if there is no such snapshot),
+  /// along with the snapshots up to this point.
+  pub(crate) fn restart<'a>(&mut self,
+    idx: usize, errs: Option<&[ElabError]>, env: &'a FrozenEnv
+  ) -> (usize, &'a [Snapshot]) {
+    let snaps = env.snapshots();
+    let i = if let Some(i) = snaps.iter().rposition(|s| s.idx <= idx) {i} else {return (0, &[])};
+    let Snapshot {idx, env: snap, settings, scopes} = &snaps[i];
+    self.copy_env(snap);
+    // The snapshot does not have spans, but they are the same as in the old environment.
+    let r = &mut Remapper::identity(env);
+    self.env.spans = env.spans().iter().take(*idx).map(|s| s.remap(r)).collect();
+    let Settings {timeout, limits, check_proofs, reporting, backtrace, warnings} = *settings;
+    self.timeout = timeout;
+    self.limits = limits;
+    self.check_proofs = check_proofs;
+    self.reporting = reporting;
+    self.backtrace = backtrace;
+    self.warnings = warnings;
+    self.scopes = scopes.clone();
+    // The errors reported by the skipped statements are the ones before the restart point.
+    let pos = self.ast.stmts.get(*idx).map_or(self.ast.source.len(), |s| s.span.start);
+    self.errors = errs.unwrap_or_default().iter().filter(|e| e.pos.start < pos).cloned().collect();
+    (*idx, &snaps[..=i])
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+  use std::sync::Arc;
+  use futures::executor::block_on;
+  use mm1_parser::ErrorLevel;
+  use crate::{CancelToken, FrozenEnv};
+  use super::ElabError;
+  use super::super::{ElabResult, ElaborateBuilder};
+
+  /// Elaborate `text` with snapshots. If `old` is the result of a previous elaboration of the
+  /// same text, elaboration restarts from the last snapshot.
+  fn elab(text: &str, old: Option<&(Vec<ElabError>, FrozenEnv)>) -> (Vec<ElabError>, FrozenEnv) {
+    let ast = Arc::new(mm1_parser::parse(Arc::new(text.to_owned().into()), None).1);
+    let (_, _, errors, env) = block_on(ElaborateBuilder {
+      ast: &ast,
+      path: PathBuf::from("test.mm1").into(),
+      mm0_mode: false,
+      check_proofs: true,
+      report_upstream_errors: false,
+      cancel: CancelToken::new(),
+      old: old.map(|(errs, env)| (ast.stmts.len(), Some(errs.clone().into()), env.clone())),
+      snapshots: true,
+      profile: false,
+      recv_dep: |_| -> Result<futures::channel::oneshot::Receiver<ElabResult<()>>, _> {
+        Err("imports are not supported".into())
+      },
+      recv_goal: None,
+      recv_goal_state: None,
+      recv_stmt: None,
+      debugger: None,
+    }.elab());
+    (errors, env)
+  }
+
+  fn levels(errors: &[ElabError]) -> Vec<ErrorLevel> { errors.iter().map(|e| e.level).collect() }
+
+  #[test]
+  fn restart_keeps_warning_levels() {
+    let text = format!("
+      provable sort wff;
+      term p: wff;
+      do {{ (set-warning 'shadowed-binder 'deny) }};
+      {}
+      axiom ax (p: wff): $ p $;", "do {};\n".repeat(20));
+    let first = elab(&text, None);
+    assert_eq!(levels(&first.0), [ErrorLevel::Error]);
+    // The restart skips the `set-warning` (statement 2), but not the axiom (statement 23)
+    let idx = first.1.snapshots().last().expect("no snapshots").idx;
+    assert!(2 < idx && idx < 23, "{}", idx);
+    let second = elab(&text, Some(&first));
+    assert_eq!(levels(&second.0), [ErrorLevel::Error]);
+  }
+}
@@ -4,6 +4,7 @@ use std::mem::MaybeUninit;
 use std::collections::BTreeMap;
 use crate::AtomId;
 use super::local_context::LocalContext;
+use super::{Remap, Remapper};
 use crate::Span;
 
 /// A `Spans<T>` object is created for each declaration, and maintains data on the
@@ -47,6 +48,21 @@ impl<T: fmt::Debug> fmt::Debug for Spans<T> {
   }
 }
 
+impl<T: Remap> Remap for Spans<T> {
+  type Target = Spans<T::Target>;
+  fn remap(&self, r: &mut Remapper) -> Self::Target {
+    Spans {
+      stmt: self.stmt,
+      // `decl` may be uninitialized, so it can't be remapped. This is fine because
+      // it is currently unused.
+      decl: self.decl,
+      lc: self.lc.remap(r),
+      data: self.data.iter().map(|(&i, v)|
+        (i, v.iter().map(|(sp, k)| (*sp, k.remap(r))).collect())).collect(),
+    }
+  }
+}
+
 impl<T> Default for Spans<T> {
   fn default() -> Self { Self::new() }
 }
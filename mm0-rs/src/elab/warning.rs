@@ -0,0 +1,108 @@
+//! Warning codes, which classify the warnings reported by the elaborator so that each kind
+//! of warning can be suppressed, or turned into an error, separately.
+//!
+//! The level of each warning code is `warn` by default, and can be set with the `--allow`,
+//! `--warn` and `--deny` options of `mm0-rs compile` and `mm0-rs server`, or inside a file
+//! with the `(set-warning code level)` lisp command, which applies to the rest of the file.
+
+use crate::{ElabError, ErrorLevel};
+
+macro_rules! make_warning_codes {
+  ($($(#[$attr:meta])* $x:ident: $e:expr,)*) => {
+    /// The kinds of warnings that can be configured individually.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    pub enum WarningCode { $($(#[$attr])* $x),* }
+    crate::deep_size_0!(WarningCode);
+
+    impl WarningCode {
+      /// All the warning codes.
+      pub const ALL: [WarningCode; [$(WarningCode::$x),*].len()] = [$(WarningCode::$x),*];
+
+      /// The name of the warning code, as used in the command line options and in
+      /// `(set-warning)`.
+      #[must_use] pub fn name(self) -> &'static str {
+        match self { $(WarningCode::$x => $e),* }
+      }
+
+      /// Get a warning code by name.
+      #[must_use] pub fn from_name(s: &str) -> Option<WarningCode> {
+        match s { $($e => Some(WarningCode::$x),)* _ => None }
+      }
+    }
+  }
+}
+
+make_warning_codes! {
+  /// A named hypothesis of a theorem which is not used in its proof. Hypotheses whose
+  /// name starts with `_` are not reported.
+  UnusedHypothesis: "unused-hypothesis",
+  /// A hypothesis with the same name as an earlier hypothesis of the declaration,
+  /// or a variable with the same name as a term, which can then no longer be used.
+  ShadowedBinder: "shadowed-binder",
+  /// A use of a declaration marked `@[deprecated]`, directly or through its notation.
+  Deprecated: "deprecated",
+  /// A lisp `match` branch after a branch whose pattern always matches.
+  UnreachableBranch: "unreachable-branch",
+  /// A dummy variable in a declaration without a value.
+  UselessDummy: "useless-dummy",
+  /// A local definition or theorem which is never used (see [`unused`](crate::unused)).
+  UnusedDeclaration: "unused-declaration",
+}
+
+/// What to do with the warnings of a given [`WarningCode`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WarningLevel {
+  /// Suppress the warning.
+  Allow,
+  /// Report the warning (the default).
+  Warn,
+  /// Report the warning as an error.
+  Deny,
+}
+
+impl WarningLevel {
+  /// Get a warning level by name: `allow`, `warn` or `deny`.
+  #[must_use] pub fn from_name(s: &str) -> Option<WarningLevel> {
+    match s {
+      "allow" => Some(WarningLevel::Allow),
+      "warn" => Some(WarningLevel::Warn),
+      "deny" => Some(WarningLevel::Deny),
+      _ => None,
+    }
+  }
+}
+
+/// The [`WarningLevel`] of each [`WarningCode`].
+#[derive(Copy, Clone, Debug)]
+pub struct WarningConfig([WarningLevel; WarningCode::ALL.len()]);
+crate::deep_size_0!(WarningConfig);
+
+impl Default for WarningConfig {
+  fn default() -> Self { WarningConfig([WarningLevel::Warn; WarningCode::ALL.len()]) }
+}
+
+impl WarningConfig {
+  /// Get the level of warning code `code`.
+  #[must_use] pub fn get(&self, code: WarningCode) -> WarningLevel { self.0[code as usize] }
+
+  /// Set the level of warning code `code`, or of all warning codes if `code` is `None`.
+  pub fn set(&mut self, code: Option<WarningCode>, lvl: WarningLevel) {
+    match code {
+      Some(code) => self.0[code as usize] = lvl,
+      None => self.0 = [lvl; WarningCode::ALL.len()],
+    }
+  }
+
+  /// Apply the configuration to an error: a warning with an allowed code is dropped,
+  /// and a warning with a denied code becomes an error.
+  #[must_use] pub fn apply(&self, mut e: ElabError) -> Option<ElabError> {
+    if let Some(code) = e.code {
+      match self.get(code) {
+        WarningLevel::Allow => return None,
+        WarningLevel::Warn => {}
+        WarningLevel::Deny => e.level = ErrorLevel::Error,
+      }
+    }
+    Some(e)
+  }
+}
@@ -0,0 +1,177 @@
+//! Coq exporter, which produces `.v` files from an [`Environment`](crate::Environment) object.
+//!
+//! The output starts with a prelude which declares each MM0 sort as a `Parameter`,
+//! followed by the declarations of the environment in order:
+//!
+//! * A sort `s` becomes `Parameter s : Type`. If it is not `strict` it also gets an inhabitant
+//!   `s'default`, which is used to instantiate dummy variables, and if it is `provable` it gets
+//!   a predicate `s'Prov : s -> Prop`, so that `$ foo $` becomes `s'Prov foo`.
+//! * Variables and expressions are translated as described in the [`hol`](super::hol) module.
+//! * A `term` becomes a `Parameter`, a `def` becomes a `Definition`, an `axiom` becomes an
+//!   `Axiom`, and a `theorem` becomes a `Theorem` whose proof is the term built from the MM0
+//!   proof, so that Coq rechecks it. A theorem without a proof is `Admitted`.
+//!
+//! Each declaration is preceded by its MM0 statement in a comment, printed with the
+//! notations of the MM0 file, because the curried applications are hard to read.
+//! Coq keywords get a `'` suffix, as do local variables whose name is also the name of
+//! a declaration.
+use std::io::{self, Write};
+use crate::{Expr, Proof, TermKind, ThmKind, ProofNode, StmtTrace, DeclKey, Modifiers,
+  FrozenEnv, LinedString};
+use super::hol::{self, Syntax, paren};
+
+/// The line width used when pretty-printing MM0 statements.
+const PP_WIDTH: usize = 100;
+
+/// The syntax of Coq.
+struct Coq;
+
+impl Syntax for Coq {
+  const KEYWORDS: &'static [&'static str] = &[
+    "Admitted", "Axiom", "Check", "Defined", "Definition", "End", "Export", "Fixpoint",
+    "Hypothesis", "IF", "Import", "Inductive", "Lemma", "Module", "Notation", "Parameter",
+    "Print", "Proof", "Prop", "Qed", "Require", "SProp", "Section", "Set", "Theorem", "Type",
+    "Variable", "as", "at", "cofix", "else", "end", "exists", "exists2", "fix", "for",
+    "forall", "fun", "if", "in", "let", "match", "mod", "return", "then", "using", "where",
+    "with",
+  ];
+  const ARROW: &'static str = "->";
+
+  fn escape_keyword(s: &str) -> String { format!("{}'", s) }
+
+  fn lambda(out: &mut String, vars: &[(&str, String)], body: &str) {
+    out.push_str("(fun");
+    for (v, _) in vars { out.push(' '); out.push_str(v) }
+    out.push_str(" => ");
+    out.push_str(body);
+    out.push(')');
+  }
+}
+
+type Printer<'a> = hol::Printer<'a, Coq>;
+
+/// Write `s` in a comment, starting with `open`. Comment delimiters and string quotes in
+/// the text are broken up, because Coq lexes them inside comments.
+fn write_comment(w: &mut impl Write, open: &str, s: &str) -> io::Result<()> {
+  let s = s.trim().replace("(*", "( *").replace("*)", "* )").replace('"', "''");
+  writeln!(w, "{} {} *)", open, s)
+}
+
+fn write_binders(w: &mut impl Write, p: &Printer<'_>) -> io::Result<()> {
+  for (x, ty) in p.binders() { write!(w, " ({} : {})", x, ty)? }
+  Ok(())
+}
+
+/// Write `let` bindings for the dummy variables, followed by `body`.
+fn write_body(env: &FrozenEnv, w: &mut impl Write, p: &mut Printer<'_>, body: &[u8]) -> io::Result<()> {
+  for (x, s) in p.take_dummies() {
+    let s = Printer::sort_name(env, s);
+    writeln!(w, "  let {} : {} := {}'default in", x, s, s)?
+  }
+  w.write_all(body)
+}
+
+impl FrozenEnv {
+  /// Write this environment into a Coq file.
+  pub fn export_coq(&self, mut w: impl Write) -> io::Result<()> {
+    let w = &mut w;
+    let source = LinedString::default();
+    // Safety: the environment is only read, and the `FormatEnv` does not outlive this call.
+    let fe = unsafe { self.format_env(&source) };
+    let mut stmt = String::new();
+    writeln!(w, "(* This file was generated by mm0-rs from an MM0 development. *)\n")?;
+    for sd in self.sorts().iter() {
+      let s = Printer::ident(sd.name.as_str());
+      if let Some(doc) = &sd.doc { write_comment(w, "(**", doc)? }
+      writeln!(w, "Parameter {} : Type.", s)?;
+      if !sd.mods.contains(Modifiers::STRICT) { writeln!(w, "Parameter {}'default : {}.", s, s)? }
+      if sd.mods.contains(Modifiers::PROVABLE) { writeln!(w, "Parameter {}'Prov : {} -> Prop.", s, s)? }
+    }
+    writeln!(w)?;
+    for s in self.stmts() {
+      let a = if let StmtTrace::Decl(a) = *s {a} else {continue};
+      stmt.clear();
+      match self.data()[a].decl().expect("expected a term/thm") {
+        DeclKey::Term(tid) => {
+          let td = self.term(tid);
+          if let Some(doc) = &td.doc { write_comment(w, "(**", doc)? }
+          fe.pretty(|pr| pr.term(tid, true).render_fmt(PP_WIDTH, &mut stmt))
+            .expect("writing to a string");
+          write_comment(w, "(*", &stmt)?;
+          let ret = Printer::sort_name(self, td.ret.0);
+          if let TermKind::Def(Some(Expr {heap, head})) = &td.kind {
+            let mut p = Printer::new(self, &td.args, heap);
+            write!(w, "Definition {}", Printer::name(self, a))?;
+            write_binders(w, &p)?;
+            writeln!(w, " : {} :=", ret)?;
+            let body = format!("  {}.\n\n", p.expr(head));
+            write_body(self, w, &mut p, body.as_bytes())?;
+          } else {
+            write!(w, "Parameter {} :", Printer::name(self, a))?;
+            let p = Printer::new(self, &td.args, &[]);
+            if !td.args.is_empty() {
+              write!(w, " forall")?;
+              write_binders(w, &p)?;
+              write!(w, ",")?;
+            }
+            writeln!(w, " {}.\n", ret)?;
+          }
+        }
+        DeclKey::Thm(tid) => {
+          let td = self.thm(tid);
+          if let Some(doc) = &td.doc { write_comment(w, "(**", doc)? }
+          fe.pretty(|pr| pr.thm(tid).render_fmt(PP_WIDTH, &mut stmt))
+            .expect("writing to a string");
+          write_comment(w, "(*", &stmt)?;
+          let mut p = Printer::new(self, &td.args, &td.heap);
+          p.set_hyps(&td.hyps);
+          let mut binders = vec![];
+          write_binders(&mut binders, &p)?;
+          for (i, (_, h)) in td.hyps.iter().enumerate() {
+            let sort = Printer::sort_name(self, hol::expr_sort(self, &td.args, &td.heap, h));
+            let h = paren(&p.expr(h));
+            write!(binders, " ({} : {}'Prov {})", p.hyps[i], sort, h)?
+          }
+          let sort = Printer::sort_name(self, hol::expr_sort(self, &td.args, &td.heap, &td.ret));
+          let ret = format!("{}'Prov {}", sort, paren(&p.expr(&td.ret)));
+          match &td.kind {
+            ThmKind::Axiom => {
+              write!(w, "Axiom {} :", Printer::name(self, a))?;
+              if !binders.is_empty() {
+                write!(w, " forall")?;
+                w.write_all(&binders)?;
+                write!(w, ",")?;
+              }
+              writeln!(w, " {}.\n", ret)?
+            }
+            ThmKind::Thm(pf) => {
+              write!(w, "Theorem {}", Printer::name(self, a))?;
+              w.write_all(&binders)?;
+              writeln!(w, " : {}.\nProof.", ret)?;
+              if let Some(Proof {heap, head, ..}) = pf {
+                p.reset_heap();
+                let mut body = vec![];
+                for (i, e) in heap.iter().enumerate().skip(td.args.len()) {
+                  let c = p.proof(e);
+                  if matches!(e, ProofNode::Thm {..} | ProofNode::Conv(_)) {
+                    let name = format!("s'{}", i);
+                    writeln!(body, "  let {} := {} in", name, c)?;
+                    p.push_heap(name)
+                  } else {
+                    p.push_heap(c)
+                  }
+                }
+                writeln!(body, "  {}).\nQed.\n", p.proof(head))?;
+                writeln!(w, "exact (")?;
+                write_body(self, w, &mut p, &body)?;
+              } else {
+                writeln!(w, "Admitted.\n")?
+              }
+            }
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,186 @@
+//! Dedukti exporter, which produces `.dk` files from an [`Environment`](crate::Environment)
+//! object, so that the proofs can be rechecked independently by Dedukti or lambdapi.
+//!
+//! The MM0 metatheory is encoded in the λΠ-calculus modulo rewriting as follows:
+//!
+//! * A sort `s` becomes a type `s : Type`. If it is not `strict` it also gets an inhabitant
+//!   `s'default`, which is used to instantiate dummy variables, and if it is `provable` it gets
+//!   a type family `s'Prov : s -> Type` of proofs, so that `$ foo $` becomes `s'Prov foo`.
+//! * Variables and expressions are translated as described in the [`hol`](super::hol) module,
+//!   with typed lambdas.
+//! * A `term` or `axiom` becomes a constant declaration, a `def` becomes a `def`, whose
+//!   unfolding is a rewrite rule, and a `theorem` becomes a `thm` whose body is the proof.
+//!   A theorem without a proof becomes a constant declaration.
+//! * Dummy variables and shared proof steps are bound by β-redexes, because Dedukti has no
+//!   `let`. Conversion proofs are dropped, because Dedukti checks them by rewriting.
+//!
+//! Dedukti keywords are escaped with `{| |}`, and local variables whose name is also the name
+//! of a declaration get a `'` suffix.
+use std::io::{self, Write};
+use crate::{Type, Expr, Proof, AtomId, SortId, TermKind, ThmKind, ProofNode, StmtTrace,
+  DeclKey, Modifiers, FrozenEnv, DocComment};
+use super::hol::{self, Syntax, paren};
+
+/// The syntax of Dedukti.
+struct Dedukti;
+
+impl Syntax for Dedukti {
+  const KEYWORDS: &'static [&'static str] = &[
+    "Type", "def", "defac", "defacu", "inj", "injective", "private", "thm",
+  ];
+  const ARROW: &'static str = "->";
+
+  fn escape_keyword(s: &str) -> String { format!("{{|{}|}}", s) }
+
+  fn lambda(out: &mut String, vars: &[(&str, String)], body: &str) {
+    out.push('(');
+    for (v, ty) in vars {
+      out.push_str(v);
+      out.push_str(" : ");
+      out.push_str(ty);
+      out.push_str(" => ");
+    }
+    out.push_str(body);
+    out.push(')');
+  }
+}
+
+type Printer<'a> = hol::Printer<'a, Dedukti>;
+
+fn write_doc(w: &mut impl Write, doc: Option<&DocComment>) -> io::Result<()> {
+  if let Some(doc) = doc {
+    writeln!(w, "(; {} ;)", doc.trim().replace(";)", "; )"))?
+  }
+  Ok(())
+}
+
+/// The sort of the expression `e` in a proof with arguments `args` and heap `heap`.
+fn node_sort(env: &FrozenEnv, args: &[(Option<AtomId>, Type)], heap: &[ProofNode], e: &ProofNode) -> SortId {
+  match *e {
+    ProofNode::Ref(i) if i < args.len() => args[i].1.sort(),
+    ProofNode::Ref(i) => node_sort(env, args, heap, &heap[i]),
+    ProofNode::Dummy(_, s) => s,
+    ProofNode::Term {term, ..} => env.term(term).ret.0,
+    _ => unreachable!("not an expression"),
+  }
+}
+
+/// Bind each of `lets`, which are triples of a variable, its type, and its value, in `body`
+/// using β-redexes.
+fn redexes(lets: &[(String, String, String)], body: &str) -> String {
+  let mut out = String::new();
+  for (x, ty, _) in lets {
+    out.push('(');
+    out.push_str(x);
+    out.push_str(" : ");
+    out.push_str(ty);
+    out.push_str(" =>\n  ");
+  }
+  out.push_str(body);
+  for (_, _, e) in lets.iter().rev() {
+    out.push_str(")\n  ");
+    out.push_str(&paren(e));
+  }
+  out
+}
+
+/// The bindings of the dummy variables used so far to the default value of their sort.
+fn dummies(env: &FrozenEnv, p: &mut Printer<'_>) -> Vec<(String, String, String)> {
+  p.take_dummies().into_iter().map(|(x, sort)| {
+    let sort = Printer::sort_name(env, sort);
+    let default = format!("{}'default", sort);
+    (x, sort, default)
+  }).collect()
+}
+
+impl FrozenEnv {
+  /// Write this environment into a Dedukti file.
+  pub fn export_dedukti(&self, mut w: impl Write) -> io::Result<()> {
+    let w = &mut w;
+    writeln!(w, "(; This file was generated by mm0-rs from an MM0 development. ;)\n")?;
+    for sd in self.sorts().iter() {
+      let s = Printer::ident(sd.name.as_str());
+      write_doc(w, sd.doc.as_ref())?;
+      writeln!(w, "{} : Type.", s)?;
+      if !sd.mods.contains(Modifiers::STRICT) { writeln!(w, "{}'default : {}.", s, s)? }
+      if sd.mods.contains(Modifiers::PROVABLE) { writeln!(w, "{}'Prov : {} -> Type.", s, s)? }
+    }
+    writeln!(w)?;
+    for s in self.stmts() {
+      let a = if let StmtTrace::Decl(a) = *s {a} else {continue};
+      match self.data()[a].decl().expect("expected a term/thm") {
+        DeclKey::Term(tid) => {
+          let td = self.term(tid);
+          write_doc(w, td.doc.as_ref())?;
+          let heap = if let TermKind::Def(Some(Expr {heap, ..})) = &td.kind {&**heap} else {&[]};
+          let mut p = Printer::new(self, &td.args, heap);
+          let binders = p.binders().into_iter()
+            .map(|(x, ty)| format!("{} : {}", x, paren(&ty))).collect::<Vec<_>>();
+          let ret = Printer::sort_name(self, td.ret.0);
+          if let TermKind::Def(Some(Expr {head, ..})) = &td.kind {
+            write!(w, "def {} :", Printer::name(self, a))?;
+            for b in &binders { write!(w, " {} ->", b)? }
+            write!(w, " {} :=\n  ", ret)?;
+            for b in &binders { write!(w, "{} => ", b)? }
+            let body = p.expr(head);
+            writeln!(w, "{}.\n", redexes(&dummies(self, &mut p), &body))?;
+          } else {
+            write!(w, "{} :", Printer::name(self, a))?;
+            for b in &binders { write!(w, " {} ->", b)? }
+            writeln!(w, " {}.\n", ret)?;
+          }
+        }
+        DeclKey::Thm(tid) => {
+          let td = self.thm(tid);
+          write_doc(w, td.doc.as_ref())?;
+          let mut p = Printer::new(self, &td.args, &td.heap);
+          p.set_hyps(&td.hyps);
+          let mut binders = p.binders().into_iter()
+            .map(|(x, ty)| format!("{} : {}", x, paren(&ty))).collect::<Vec<_>>();
+          for (i, (_, h)) in td.hyps.iter().enumerate() {
+            let sort = Printer::sort_name(self, hol::expr_sort(self, &td.args, &td.heap, h));
+            let h = paren(&p.expr(h));
+            binders.push(format!("{} : {}'Prov {}", p.hyps[i], sort, h))
+          }
+          let sort = Printer::sort_name(self, hol::expr_sort(self, &td.args, &td.heap, &td.ret));
+          let ret = format!("{}'Prov {}", sort, paren(&p.expr(&td.ret)));
+          if let ThmKind::Thm(Some(Proof {heap, head, ..})) = &td.kind {
+            write!(w, "thm {} :", Printer::name(self, a))?;
+            for b in &binders { write!(w, " {} ->", b)? }
+            write!(w, " {} :=\n  ", ret)?;
+            for b in &binders { write!(w, "{} => ", b)? }
+            p.reset_heap();
+            let mut steps = vec![];
+            for (i, e) in heap.iter().enumerate().skip(td.args.len()) {
+              let c = p.proof(e);
+              let concl = match e {
+                ProofNode::Thm {thm, res, ..} => {
+                  let td2 = self.thm(*thm);
+                  Some((hol::expr_sort(self, &td2.args, &td2.heap, &td2.ret), &**res))
+                }
+                ProofNode::Conv(c) => Some((node_sort(self, &td.args, heap, &c.0), &c.0)),
+                _ => None,
+              };
+              if let Some((sort, res)) = concl {
+                let name = format!("s'{}", i);
+                let ty = format!("{}'Prov {}", Printer::sort_name(self, sort), paren(&p.proof(res)));
+                steps.push((name.clone(), ty, c));
+                p.push_heap(name)
+              } else {
+                p.push_heap(c)
+              }
+            }
+            let body = redexes(&steps, &p.proof(head));
+            writeln!(w, "{}.\n", redexes(&dummies(self, &mut p), &body))?;
+          } else {
+            if matches!(td.kind, ThmKind::Thm(None)) { writeln!(w, "(; proof missing ;)")? }
+            write!(w, "{} :", Printer::name(self, a))?;
+            for b in &binders { write!(w, " {} ->", b)? }
+            writeln!(w, " {}.\n", ret)?;
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,36 @@
+//! Implements the `mm0-rs dump` subcommand, which writes the elaborated environment in a
+//! structured format for external tools.
+use std::{fs, io};
+use clap::ArgMatches;
+use crate::{FileRef, render::RenderFormat};
+
+/// Main entry point for `mm0-rs dump` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs dump [--format json|sexpr|latex|html] <in.mm1> [out.json]`, where:
+///
+/// - `in.mm1` is the MM1 (or MM0) file to elaborate
+/// - `out.json` is the file to write, or stdout if omitted
+/// - `--format` selects the output format. See [`FrozenEnv::dump_json`](crate::FrozenEnv::dump_json)
+///   for the JSON schema, [`export::sexpr`](super::sexpr) for the s-expression format, and
+///   [`render`](crate::render) for the LaTeX and HTML rendering of the statements.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let path = args.value_of("INPUT").expect("required arg");
+  let path: FileRef = fs::canonicalize(path)?.into();
+  if args.value_of("OUTPUT").is_none() { crate::compiler::set_quiet(true) }
+  let env = crate::compiler::elab_for_result(path)?.1.unwrap_or_else(|| std::process::exit(1));
+  let mut w: Box<dyn io::Write> = match args.value_of("OUTPUT") {
+    Some(out) => Box::new(io::BufWriter::new(fs::File::create(out)?)),
+    None => Box::new(io::stdout()),
+  };
+  match args.value_of("format") {
+    Some("json") => env.export_json(&mut w)?,
+    Some("sexpr") => env.export_sexpr(&mut w)?,
+    Some("latex") => env.export_rendered(RenderFormat::Latex, &mut w)?,
+    Some("html") => env.export_rendered(RenderFormat::Html, &mut w)?,
+    _ => unreachable!(),
+  }
+  writeln!(w)?;
+  w.flush()
+}
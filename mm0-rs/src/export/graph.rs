@@ -0,0 +1,162 @@
+//! Dependency graph exporter, which writes the graph of terms and theorems of an
+//! [`Environment`](crate::Environment) in the Graphviz DOT or `GraphML` format.
+//!
+//! There is a node for every term, def, axiom and theorem, and an edge from each declaration
+//! to the declarations it uses (see [`Thm::for_each_dep`](crate::Thm::for_each_dep)).
+//! The node kind is indicated by the node shape in DOT output, and by the `kind`
+//! attribute in `GraphML` output.
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::{fs, io::{self, Write}};
+use clap::ArgMatches;
+use crate::{DeclKey, FileRef, FrozenEnv, StmtTrace, TermKind, ThmKind};
+
+/// The output format of the graph.
+#[derive(Copy, Clone, Debug)]
+pub enum GraphFormat {
+  /// Graphviz DOT format
+  Dot,
+  /// `GraphML` format
+  GraphMl,
+}
+
+/// Options controlling which declarations are included in the graph.
+#[derive(Clone, Debug, Default)]
+pub struct GraphFilter<'a> {
+  /// If true, terms and defs are omitted.
+  pub thms_only: bool,
+  /// If set, only declarations whose names start with this prefix are included.
+  pub prefix: Option<&'a str>,
+  /// If set, only declarations reachable from this declaration are included, and the number
+  /// is the maximum length of a path from the root (unlimited if `None`).
+  pub root: Option<(DeclKey, Option<usize>)>,
+}
+
+impl FrozenEnv {
+  fn decl_name(&self, k: DeclKey) -> &str {
+    let a = match k {
+      DeclKey::Term(t) => self.term(t).atom,
+      DeclKey::Thm(t) => self.thm(t).atom,
+    };
+    self.data()[a].name().as_str()
+  }
+
+  /// Write the dependency graph of this environment, restricted to the declarations
+  /// selected by `filter`. The nodes are written in declaration order.
+  pub fn export_graph(&self, fmt: GraphFormat, filter: &GraphFilter<'_>,
+    mut w: impl Write
+  ) -> io::Result<()> {
+    let w = &mut w;
+    let mut deps = HashMap::new();
+    let mut nodes = vec![];
+    for s in self.stmts() {
+      let a = if let StmtTrace::Decl(a) = *s {a} else {continue};
+      let k = self.data()[a].decl().expect("expected a term/thm");
+      let mut ks = vec![];
+      self.for_each_dep(k, |k2| if !ks.contains(&k2) { ks.push(k2) });
+      deps.insert(k, ks);
+      nodes.push(k)
+    }
+    let reachable = filter.root.map(|(root, depth)| {
+      let mut seen = HashSet::new();
+      seen.insert(root);
+      let mut queue = VecDeque::from(vec![(root, 0)]);
+      while let Some((k, d)) = queue.pop_front() {
+        if depth.map_or(false, |depth| d >= depth) {continue}
+        for &k2 in &deps[&k] {
+          if seen.insert(k2) { queue.push_back((k2, d + 1)) }
+        }
+      }
+      seen
+    });
+    let included = |k: DeclKey|
+      !(filter.thms_only && matches!(k, DeclKey::Term(_))) &&
+      filter.prefix.map_or(true, |p| self.decl_name(k).starts_with(p)) &&
+      reachable.as_ref().map_or(true, |r| r.contains(&k));
+    nodes.retain(|&k| included(k));
+    match fmt {
+      GraphFormat::Dot => writeln!(w, "digraph mm0 {{")?,
+      GraphFormat::GraphMl => writeln!(w, "\
+        <?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+        \n<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\
+        \n  <key id=\"kind\" for=\"node\" attr.name=\"kind\" attr.type=\"string\"/>\
+        \n  <graph id=\"mm0\" edgedefault=\"directed\">")?,
+    }
+    for &k in &nodes {
+      let (kind, attrs) = match k {
+        DeclKey::Term(t) => match self.term(t).kind {
+          TermKind::Term => ("term", "shape=ellipse"),
+          TermKind::Def(_) => ("def", "shape=ellipse, style=dashed"),
+        },
+        DeclKey::Thm(t) => match self.thm(t).kind {
+          ThmKind::Axiom => ("axiom", "shape=box, style=bold"),
+          ThmKind::Thm(_) => ("theorem", "shape=box"),
+        },
+      };
+      let name = self.decl_name(k);
+      match fmt {
+        GraphFormat::Dot => writeln!(w, "  \"{}\" [{}];", name, attrs)?,
+        GraphFormat::GraphMl =>
+          writeln!(w, "    <node id=\"{}\"><data key=\"kind\">{}</data></node>", name, kind)?,
+      }
+    }
+    for &k in &nodes {
+      for &k2 in deps[&k].iter().filter(|&&k2| included(k2)) {
+        let (src, tgt) = (self.decl_name(k), self.decl_name(k2));
+        match fmt {
+          GraphFormat::Dot => writeln!(w, "  \"{}\" -> \"{}\";", src, tgt)?,
+          GraphFormat::GraphMl =>
+            writeln!(w, "    <edge source=\"{}\" target=\"{}\"/>", src, tgt)?,
+        }
+      }
+    }
+    match fmt {
+      GraphFormat::Dot => writeln!(w, "}}"),
+      GraphFormat::GraphMl => writeln!(w, "  </graph>\n</graphml>"),
+    }
+  }
+}
+
+/// Main entry point for `mm0-rs graph` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs graph [--format dot|graphml] [--thms] [--prefix P] [--root X [--depth N]]
+/// <in.mm1> [out.dot]`, where:
+///
+/// - `in.mm1` is the MM1 (or MM0) file to elaborate
+/// - `out.dot` is the file to write, or stdout if omitted
+/// - `--thms` omits terms and defs from the graph
+/// - `--prefix P` includes only declarations whose names start with `P`
+/// - `--root X` includes only declarations that `X` depends on, directly or indirectly,
+///   and `--depth N` limits this to paths of length at most `N` from `X`. (The paths may
+///   pass through declarations which are not included because of the other filters.)
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let path = args.value_of("INPUT").expect("required arg");
+  let path: FileRef = fs::canonicalize(path)?.into();
+  if args.value_of("OUTPUT").is_none() { crate::compiler::set_quiet(true) }
+  let env = crate::compiler::elab_for_result(path)?.1.unwrap_or_else(|| std::process::exit(1));
+  let fmt = match args.value_of("format") {
+    Some("dot") => GraphFormat::Dot,
+    Some("graphml") => GraphFormat::GraphMl,
+    _ => unreachable!(),
+  };
+  let depth = match args.value_of("depth").map(str::parse).transpose() {
+    Ok(depth) => depth,
+    Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
+  };
+  let root = match args.value_of("root") {
+    None => None,
+    Some(root) => match env.get_atom(root.as_bytes()).and_then(|a| env.data()[a].decl()) {
+      Some(k) => Some((k, depth)),
+      None => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+        format!("unknown declaration '{}'", root))),
+    }
+  };
+  let filter = GraphFilter { thms_only: args.is_present("thms"), prefix: args.value_of("prefix"), root };
+  let mut w: Box<dyn io::Write> = match args.value_of("OUTPUT") {
+    Some(out) => Box::new(io::BufWriter::new(fs::File::create(out)?)),
+    None => Box::new(io::stdout()),
+  };
+  env.export_graph(fmt, &filter, &mut w)?;
+  w.flush()
+}
@@ -0,0 +1,209 @@
+//! Translation of MM0 expressions and proofs into curried applications, which is shared by
+//! the exporters for higher order proof assistants.
+//!
+//! A bound variable `{x : s}` is a value of type `s`, and a regular variable `(ph : s x y)` is
+//! a function `s1 -> s2 -> s` of its dependencies, where an occurrence of `ph` is written
+//! `ph x y`. When a term or theorem is applied, each regular argument is abstracted over the
+//! bound variables it may depend on, so `all x ph` becomes `all x (fun x => ph x)`.
+//! Conversion proofs are dropped, because the target system checks them by unfolding
+//! definitions.
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use crate::{Type, AtomId, SortId, ExprNode, ProofNode, FrozenEnv};
+
+/// The concrete syntax of a target language.
+pub(super) trait Syntax {
+  /// Words which cannot be used as identifiers.
+  const KEYWORDS: &'static [&'static str];
+  /// The function type arrow.
+  const ARROW: &'static str;
+  /// Turn the keyword `s` into an identifier.
+  fn escape_keyword(s: &str) -> String;
+  /// Write the lambda abstraction of `body` over `vars`, which are pairs of a variable name
+  /// and its type, in parentheses.
+  fn lambda(out: &mut String, vars: &[(&str, String)], body: &str);
+}
+
+/// Parenthesize `s` if it is not a single identifier.
+pub(super) fn paren(s: &str) -> String {
+  if s.contains(' ') { format!("({})", s) } else { s.into() }
+}
+
+/// The indexes of the bound variables in `args` which are in the dependency set `vs`.
+pub(super) fn deps(args: &[(Option<AtomId>, Type)], mut vs: u64) -> impl Iterator<Item=usize> + '_ {
+  args.iter().enumerate().filter(|(_, (_, ty))| ty.bound()).map(|(i, _)| i)
+    .filter(move |_| {let old = vs; vs /= 2; old & 1 != 0})
+}
+
+/// The sort of expression `e` in a declaration with arguments `args` and heap `heap`.
+pub(super) fn expr_sort(env: &FrozenEnv,
+    args: &[(Option<AtomId>, Type)], heap: &[ExprNode], e: &ExprNode) -> SortId {
+  match *e {
+    ExprNode::Ref(i) if i < args.len() => args[i].1.sort(),
+    ExprNode::Ref(i) => expr_sort(env, args, heap, &heap[i]),
+    ExprNode::Dummy(_, s) => s,
+    ExprNode::App(t, _) => env.term(t).ret.0,
+  }
+}
+
+/// The printer for the expressions and proofs in a single declaration.
+pub(super) struct Printer<'a, S> {
+  env: &'a FrozenEnv,
+  args: &'a [(Option<AtomId>, Type)],
+  /// The name of each argument
+  names: Vec<String>,
+  /// The name of each hypothesis
+  pub(super) hyps: Vec<String>,
+  /// The dummy variables which have been used so far
+  dummies: HashMap<AtomId, SortId>,
+  /// The translation of each heap element
+  heap: Vec<String>,
+  syntax: PhantomData<S>,
+}
+
+impl<'a, S: Syntax> Printer<'a, S> {
+  /// Escape the name `s` if it is a keyword.
+  pub(super) fn ident(s: &str) -> String {
+    if S::KEYWORDS.contains(&s) { S::escape_keyword(s) } else { s.into() }
+  }
+
+  /// The name of a sort, term or theorem.
+  pub(super) fn name(env: &FrozenEnv, a: AtomId) -> String { Self::ident(env.data()[a].name().as_str()) }
+
+  /// The name of a sort.
+  pub(super) fn sort_name(env: &FrozenEnv, s: SortId) -> String { Self::ident(env.sort(s).name.as_str()) }
+
+  /// The name of a local variable, which gets a `'` suffix if it would shadow a declaration.
+  pub(super) fn local(env: &FrozenEnv, a: AtomId) -> String {
+    let ad = &env.data()[a];
+    if ad.sort().is_some() || ad.decl().is_some() {
+      let mut s = Self::ident(&format!("{}'", ad.name()));
+      // The declaration itself may have been escaped to the same name
+      if s == Self::name(env, a) { s.push('\'') }
+      s
+    } else {
+      Self::ident(ad.name().as_str())
+    }
+  }
+
+  /// Construct a printer for a declaration with binders `args`, whose heap starts with `heap`.
+  pub(super) fn new(env: &'a FrozenEnv, args: &'a [(Option<AtomId>, Type)], heap: &[ExprNode]) -> Self {
+    let names = args.iter().enumerate().map(|(i, &(a, _))|
+      a.map_or_else(|| format!("v'{}", i + 1), |a| Self::local(env, a))).collect();
+    let mut p = Self {
+      env, args, names, hyps: vec![], dummies: HashMap::new(), heap: vec![], syntax: PhantomData
+    };
+    p.reset_heap();
+    for e in heap.get(args.len()..).unwrap_or_default() {
+      let e = p.expr(e);
+      p.heap.push(e)
+    }
+    p
+  }
+
+  /// Name the hypotheses of a theorem, using `h'1`, `h'2`, ... for unnamed hypotheses.
+  pub(super) fn set_hyps(&mut self, hyps: &[(Option<AtomId>, ExprNode)]) {
+    self.hyps = hyps.iter().enumerate().map(|(i, &(h, _))|
+      h.map_or_else(|| format!("h'{}", i + 1), |h| Self::local(self.env, h))).collect()
+  }
+
+  /// The binders of the declaration, as pairs of a variable name and its type.
+  pub(super) fn binders(&self) -> Vec<(&str, String)> {
+    self.names.iter().zip(self.args).map(|(name, &(_, ty))| {
+      let mut out = String::new();
+      if let Type::Reg(_, vs) = ty {
+        for i in deps(self.args, vs) {
+          out.push_str(&Self::sort_name(self.env, self.args[i].1.sort()));
+          out.push(' ');
+          out.push_str(S::ARROW);
+          out.push(' ');
+        }
+      }
+      out.push_str(&Self::sort_name(self.env, ty.sort()));
+      (&**name, out)
+    }).collect()
+  }
+
+  /// Clear the heap, leaving only the variables.
+  pub(super) fn reset_heap(&mut self) {
+    self.heap.clear();
+    for (i, name) in self.names.iter().enumerate() {
+      let mut out = name.clone();
+      if let Type::Reg(_, vs) = self.args[i].1 {
+        for j in deps(self.args, vs) { out.push(' '); out.push_str(&self.names[j]) }
+      }
+      self.heap.push(out)
+    }
+  }
+
+  /// Add the translation of the next heap element.
+  pub(super) fn push_heap(&mut self, s: String) { self.heap.push(s) }
+
+  /// Apply the declaration `head` with binders `args` to the expressions `es`, followed by
+  /// the hypothesis proofs `hyps`.
+  fn app(&self, head: String, args: &[(Option<AtomId>, Type)], es: &[String], hyps: &[String]) -> String {
+    let mut out = head;
+    for (&(_, ty), e) in args.iter().zip(es) {
+      out.push(' ');
+      match ty {
+        Type::Reg(_, vs) if vs != 0 => {
+          let vars = deps(args, vs)
+            .map(|j| (&*es[j], Self::sort_name(self.env, args[j].1.sort()))).collect::<Vec<_>>();
+          S::lambda(&mut out, &vars, e)
+        }
+        _ => out.push_str(&paren(e)),
+      }
+    }
+    for h in hyps { out.push(' '); out.push_str(&paren(h)) }
+    out
+  }
+
+  /// Translate an expression.
+  pub(super) fn expr(&mut self, e: &ExprNode) -> String {
+    match *e {
+      ExprNode::Ref(i) => self.heap[i].clone(),
+      ExprNode::Dummy(a, s) => {
+        assert!(self.dummies.insert(a, s).map_or(true, |s2| s == s2));
+        Self::local(self.env, a)
+      }
+      ExprNode::App(t, ref es) => {
+        let td = self.env.term(t);
+        let es = es.iter().map(|e| self.expr(e)).collect::<Vec<_>>();
+        self.app(Self::name(self.env, td.atom), &td.args, &es, &[])
+      }
+    }
+  }
+
+  /// Translate a proof. Conversions translate to the empty string.
+  pub(super) fn proof(&mut self, e: &ProofNode) -> String {
+    match *e {
+      ProofNode::Ref(i) => self.heap[i].clone(),
+      ProofNode::Dummy(a, s) => {
+        assert!(self.dummies.insert(a, s).map_or(true, |s2| s == s2));
+        Self::local(self.env, a)
+      }
+      ProofNode::Term {term, ref args} => {
+        let td = self.env.term(term);
+        let es = args.iter().map(|e| self.proof(e)).collect::<Vec<_>>();
+        self.app(Self::name(self.env, td.atom), &td.args, &es, &[])
+      }
+      ProofNode::Hyp(i, _) => self.hyps[i].clone(),
+      ProofNode::Thm {thm, ref args, ..} => {
+        let td = self.env.thm(thm);
+        let es = args.iter().map(|e| self.proof(e)).collect::<Vec<_>>();
+        let (es, hyps) = es.split_at(td.args.len());
+        self.app(Self::name(self.env, td.atom), &td.args, es, hyps)
+      }
+      ProofNode::Conv(ref p) => self.proof(&p.2),
+      ProofNode::Refl(_) | ProofNode::Sym(_) |
+      ProofNode::Cong {..} | ProofNode::Unfold {..} => String::new(),
+    }
+  }
+
+  /// Take the dummy variables which have been used so far, sorted by name.
+  pub(super) fn take_dummies(&mut self) -> Vec<(String, SortId)> {
+    let mut dummies = std::mem::take(&mut self.dummies).into_iter().collect::<Vec<_>>();
+    dummies.sort_by_key(|&(a, _)| &**self.env.data()[a].name());
+    dummies.into_iter().map(|(a, s)| (Self::local(self.env, a), s)).collect()
+  }
+}
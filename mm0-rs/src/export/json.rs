@@ -0,0 +1,195 @@
+//! JSON exporter, which serializes the declarations of an [`Environment`](crate::Environment)
+//! object for external tools.
+//!
+//! The output is an object with the following fields:
+//!
+//! * `version`: the version of the schema, currently `1`.
+//! * `sorts`: a list of `{name, modifiers, doc}` objects.
+//! * `terms`: a list of `{name, kind, modifiers, doc, args, ret, dummies, value}` objects, where
+//!   `kind` is `"term"` or `"def"`, and `value` is the definition of the def, or `null`.
+//! * `theorems`: a list of `{name, kind, modifiers, doc, args, hyps, ret}` objects, where
+//!   `kind` is `"axiom"` or `"theorem"`.
+//! * `notations`: a list of `{term, token, infix, rassoc, literals}` objects, where each literal
+//!   is either `{const, prec}` or `{var, prec}`. For a prefix notation the literals are the ones
+//!   after the leading `token`.
+//! * `coercions`: a list of `{from, to, term}` objects.
+//!
+//! A binder is `{name, sort, bound, deps}`, where `deps` is the list of bound variables that a
+//! regular variable depends on, and a return type is `{sort, deps}`. Unnamed binders are
+//! called `_1`, `_2`, ... . An expression is either a string, which is a variable, or a list
+//! `[term, args...]`, which is a term application. Proofs are not included.
+//! The declarations are listed in the order they appear in the file.
+use std::io::{self, Write};
+use serde_json::{json, Value};
+use crate::{Type, AtomId, SortId, Expr, ExprNode, TermKind, ThmKind, StmtTrace, DeclKey,
+  Modifiers, Literal, Coe, FrozenEnv};
+
+/// The serializer for the expressions in a single declaration.
+struct Dumper<'a> {
+  env: &'a FrozenEnv,
+  /// The name of each argument
+  names: Vec<String>,
+  /// The translation of each heap element
+  heap: Vec<Value>,
+  /// The dummy variables which have been used so far
+  dummies: Vec<(AtomId, SortId)>,
+}
+
+impl<'a> Dumper<'a> {
+  fn new(env: &'a FrozenEnv, args: &[(Option<AtomId>, Type)], heap: &[ExprNode]) -> Self {
+    let names = args.iter().enumerate().map(|(i, &(a, _))|
+      a.map_or_else(|| format!("_{}", i + 1), |a| env.data()[a].name().to_string())).collect::<Vec<_>>();
+    let mut d = Self { env, heap: names.iter().map(|s| json!(s)).collect(), names, dummies: vec![] };
+    for e in heap.get(args.len()..).unwrap_or_default() {
+      let e = d.expr(e);
+      d.heap.push(e)
+    }
+    d
+  }
+
+  fn sort(&self, s: SortId) -> Value { json!(self.env.sort(s).name.as_str()) }
+
+  fn deps(&self, args: &[(Option<AtomId>, Type)], mut vs: u64) -> Value {
+    let mut deps = vec![];
+    for (i, (_, ty)) in args.iter().enumerate() {
+      if ty.bound() {
+        if vs & 1 != 0 { deps.push(json!(self.names[i])) }
+        vs /= 2;
+      }
+    }
+    Value::Array(deps)
+  }
+
+  fn binders(&self, args: &[(Option<AtomId>, Type)]) -> Value {
+    args.iter().zip(&self.names).map(|(&(_, ty), name)| json!({
+      "name": name,
+      "sort": self.sort(ty.sort()),
+      "bound": ty.bound(),
+      "deps": if let Type::Reg(_, vs) = ty { self.deps(args, vs) } else { json!([]) },
+    })).collect()
+  }
+
+  fn expr(&mut self, e: &ExprNode) -> Value {
+    match *e {
+      ExprNode::Ref(i) => self.heap[i].clone(),
+      ExprNode::Dummy(a, s) => {
+        if !self.dummies.iter().any(|&(b, _)| a == b) { self.dummies.push((a, s)) }
+        json!(self.env.data()[a].name().as_str())
+      }
+      ExprNode::App(t, ref es) => {
+        let mut out = vec![json!(self.env.data()[self.env.term(t).atom].name().as_str())];
+        for e in &**es { out.push(self.expr(e)) }
+        Value::Array(out)
+      }
+    }
+  }
+}
+
+fn modifiers(mods: Modifiers) -> Value {
+  mods.to_string().split_whitespace().collect()
+}
+
+fn literal(env: &FrozenEnv, lit: &Literal) -> Value {
+  match lit {
+    &Literal::Var(i, prec) => json!({"var": i, "prec": prec.to_string()}),
+    Literal::Const(c) => json!({
+      "const": c.as_str(),
+      "prec": env.pe().consts.get(c).map(|(_, prec)| prec.to_string()),
+    }),
+  }
+}
+
+impl FrozenEnv {
+  /// Serialize the declarations in this environment into a JSON value.
+  /// See the [module documentation](self) for the schema.
+  #[must_use] pub fn dump_json(&self) -> Value {
+    let name = |a: AtomId| json!(self.data()[a].name().as_str());
+    let mut sorts = vec![];
+    let mut terms = vec![];
+    let mut thms = vec![];
+    for s in self.stmts() {
+      match *s {
+        StmtTrace::Sort(a) => {
+          let sd = self.sort(self.data()[a].sort().expect("expected a sort"));
+          sorts.push(json!({
+            "name": name(a),
+            "modifiers": modifiers(sd.mods),
+            "doc": sd.doc.as_deref(),
+          }))
+        }
+        StmtTrace::Decl(a) => match self.data()[a].decl().expect("expected a term/thm") {
+          DeclKey::Term(tid) => {
+            let td = self.term(tid);
+            let heap = if let TermKind::Def(Some(Expr {heap, ..})) = &td.kind {&**heap} else {&[]};
+            let mut d = Dumper::new(self, &td.args, heap);
+            let value = if let TermKind::Def(Some(Expr {head, ..})) = &td.kind {
+              d.expr(head)
+            } else { Value::Null };
+            terms.push(json!({
+              "name": name(a),
+              "kind": if matches!(td.kind, TermKind::Term) {"term"} else {"def"},
+              "modifiers": modifiers(td.vis),
+              "doc": td.doc.as_deref(),
+              "args": d.binders(&td.args),
+              "ret": {"sort": d.sort(td.ret.0), "deps": d.deps(&td.args, td.ret.1)},
+              "dummies": d.dummies.iter().map(|&(a, s)| json!({"name": name(a), "sort": d.sort(s)}))
+                .collect::<Value>(),
+              "value": value,
+            }))
+          }
+          DeclKey::Thm(tid) => {
+            let td = self.thm(tid);
+            let mut d = Dumper::new(self, &td.args, &td.heap);
+            let hyps = td.hyps.iter().enumerate().map(|(i, (h, e))| json!({
+              "name": h.map_or_else(|| format!("_{}", i + 1), |h| self.data()[h].name().to_string()),
+              "expr": d.expr(e),
+            })).collect::<Value>();
+            thms.push(json!({
+              "name": name(a),
+              "kind": if matches!(td.kind, ThmKind::Axiom) {"axiom"} else {"theorem"},
+              "modifiers": modifiers(td.vis),
+              "doc": td.doc.as_deref(),
+              "args": d.binders(&td.args),
+              "hyps": hyps,
+              "ret": d.expr(&td.ret),
+            }))
+          }
+        },
+        StmtTrace::Global(_) | StmtTrace::OutputString(_) => {}
+      }
+    }
+    let pe = self.pe();
+    let mut notations = pe.prefixes.iter().map(|(tk, n)| (tk, false, n))
+      .chain(pe.infixes.iter().map(|(tk, n)| (tk, true, n))).collect::<Vec<_>>();
+    notations.sort_by_key(|&(tk, infix, n)| (n.term, infix, tk.as_str()));
+    let notations = notations.into_iter().map(|(tk, infix, n)| json!({
+      "term": name(self.term(n.term).atom),
+      "token": tk.as_str(),
+      "infix": infix,
+      "rassoc": n.rassoc,
+      "literals": n.lits.iter().map(|lit| literal(self, lit)).collect::<Value>(),
+    })).collect::<Value>();
+    let mut coes = pe.coes.iter().flat_map(|(&s1, m)| m.iter().filter_map(move |(&s2, c)|
+      if let Coe::One(_, t) = **c { Some((s1, s2, t)) } else { None })).collect::<Vec<_>>();
+    coes.sort_unstable();
+    let coes = coes.into_iter().map(|(s1, s2, t)| json!({
+      "from": self.sort(s1).name.as_str(),
+      "to": self.sort(s2).name.as_str(),
+      "term": name(self.term(t).atom),
+    })).collect::<Value>();
+    json!({
+      "version": 1,
+      "sorts": sorts,
+      "terms": terms,
+      "theorems": thms,
+      "notations": notations,
+      "coercions": coes,
+    })
+  }
+
+  /// Write the [JSON dump](Self::dump_json) of this environment.
+  pub fn export_json(&self, w: impl Write) -> io::Result<()> {
+    serde_json::to_writer_pretty(w, &self.dump_json())?;
+    Ok(())
+  }
+}
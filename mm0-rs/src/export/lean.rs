@@ -0,0 +1,153 @@
+//! Lean 4 exporter, which produces `.lean` files from an [`Environment`](crate::Environment)
+//! object.
+//!
+//! The output starts with a prelude which declares each MM0 sort as an opaque Lean type,
+//! followed by the declarations of the environment in order:
+//!
+//! * A sort `s` becomes `axiom s : Type`. If it is not `strict` it also gets an inhabitant
+//!   `s.default`, which is used to instantiate dummy variables, and if it is `provable` it gets
+//!   a predicate `s.Prov : s → Prop`, so that `$ foo $` becomes `s.Prov foo`.
+//! * A bound variable `{x : s}` becomes `(x : s)`, and a regular variable `(ph : s x y)`
+//!   becomes a function of its dependencies `(ph : s1 → s2 → s)`, as described in the
+//!   [`hol`](super::hol) module.
+//! * A `term` or `axiom` becomes an `axiom`, a `def` becomes a `def`, and a `theorem`
+//!   becomes a `theorem` whose proof is a term built from the MM0 proof. A theorem without a
+//!   proof is exported with `sorry`.
+//!
+//! Visibility modifiers are not translated, because most MM1 theorems are not `pub` but
+//! should still be usable from other Lean files.
+//! Lean keywords are escaped with `«»`, and local variables whose name is also the name of
+//! a declaration get a `'` suffix so that they do not shadow the declaration.
+use std::io::{self, Write};
+use crate::{Expr, Proof, TermKind, ThmKind,
+  ProofNode, StmtTrace, DeclKey, Modifiers, FrozenEnv, DocComment};
+use super::hol::{self, Syntax, paren};
+
+/// The syntax of Lean 4.
+struct Lean;
+
+impl Syntax for Lean {
+  const KEYWORDS: &'static [&'static str] = &[
+    "Prop", "Sort", "Type", "abbrev", "at", "attribute", "axiom", "break", "by", "calc",
+    "catch", "class", "continue", "decreasing_by", "def", "deriving", "do", "else", "end",
+    "example", "export", "extends", "finally", "for", "forall", "from", "fun", "have", "hiding",
+    "if", "import", "in", "include", "inductive", "infix", "infixl", "infixr", "instance",
+    "lemma", "let", "local", "macro", "macro_rules", "match", "mut", "mutual", "namespace",
+    "noncomputable", "nomatch", "nofun", "notation", "omit", "opaque", "open", "partial",
+    "postfix", "prefix", "private", "protected", "renaming", "return", "scoped", "section",
+    "set_option", "show", "sorry", "structure", "suffices", "syntax", "termination_by",
+    "then", "theorem", "this", "try", "universe", "unless", "unsafe", "variable", "where",
+    "with",
+  ];
+  const ARROW: &'static str = "→";
+
+  fn escape_keyword(s: &str) -> String { format!("«{}»", s) }
+
+  fn lambda(out: &mut String, vars: &[(&str, String)], body: &str) {
+    out.push_str("(fun");
+    for (v, _) in vars { out.push(' '); out.push_str(v) }
+    out.push_str(" => ");
+    out.push_str(body);
+    out.push(')');
+  }
+}
+
+type Printer<'a> = hol::Printer<'a, Lean>;
+
+fn write_doc(w: &mut impl Write, doc: Option<&DocComment>) -> io::Result<()> {
+  if let Some(doc) = doc {
+    writeln!(w, "/-- {} -/", doc.trim().replace("-/", "- /"))?
+  }
+  Ok(())
+}
+
+fn write_binders(w: &mut impl Write, p: &Printer<'_>) -> io::Result<()> {
+  for (x, ty) in p.binders() { write!(w, " ({} : {})", x, ty)? }
+  Ok(())
+}
+
+/// Write `let` bindings for the dummy variables, followed by `body`.
+fn write_body(env: &FrozenEnv, w: &mut impl Write, p: &mut Printer<'_>, body: &[u8]) -> io::Result<()> {
+  for (x, s) in p.take_dummies() {
+    let s = Printer::sort_name(env, s);
+    writeln!(w, "  let {} : {} := {}.default", x, s, s)?
+  }
+  w.write_all(body)?;
+  writeln!(w, "\n")
+}
+
+impl FrozenEnv {
+  /// Write this environment into a Lean 4 file.
+  pub fn export_lean(&self, mut w: impl Write) -> io::Result<()> {
+    let w = &mut w;
+    writeln!(w, "/-\nThis file was generated by mm0-rs from an MM0 development.\n-/\n")?;
+    writeln!(w, "set_option autoImplicit false\n\nnoncomputable section\n\nnamespace MM0\n")?;
+    for sd in self.sorts().iter() {
+      let s = Printer::ident(sd.name.as_str());
+      write_doc(w, sd.doc.as_ref())?;
+      writeln!(w, "axiom {} : Type", s)?;
+      if !sd.mods.contains(Modifiers::STRICT) { writeln!(w, "axiom {}.default : {}", s, s)? }
+      if sd.mods.contains(Modifiers::PROVABLE) { writeln!(w, "axiom {}.Prov : {} → Prop", s, s)? }
+    }
+    writeln!(w)?;
+    for s in self.stmts() {
+      let a = if let StmtTrace::Decl(a) = *s {a} else {continue};
+      match self.data()[a].decl().expect("expected a term/thm") {
+        DeclKey::Term(tid) => {
+          let td = self.term(tid);
+          write_doc(w, td.doc.as_ref())?;
+          let kw = if matches!(td.kind, TermKind::Def(Some(_))) {"def"} else {"axiom"};
+          write!(w, "{} {}", kw, Printer::name(self, a))?;
+          if let TermKind::Def(Some(Expr {heap, head})) = &td.kind {
+            let mut p = Printer::new(self, &td.args, heap);
+            write_binders(w, &p)?;
+            writeln!(w, " : {} :=", Printer::sort_name(self, td.ret.0))?;
+            let body = format!("  {}", p.expr(head));
+            write_body(self, w, &mut p, body.as_bytes())?;
+          } else {
+            write_binders(w, &Printer::new(self, &td.args, &[]))?;
+            writeln!(w, " : {}\n", Printer::sort_name(self, td.ret.0))?;
+          }
+        }
+        DeclKey::Thm(tid) => {
+          let td = self.thm(tid);
+          write_doc(w, td.doc.as_ref())?;
+          let kw = if matches!(td.kind, ThmKind::Axiom) {"axiom"} else {"theorem"};
+          write!(w, "{} {}", kw, Printer::name(self, a))?;
+          let mut p = Printer::new(self, &td.args, &td.heap);
+          p.set_hyps(&td.hyps);
+          write_binders(w, &p)?;
+          for (i, (_, h)) in td.hyps.iter().enumerate() {
+            let sort = Printer::sort_name(self, hol::expr_sort(self, &td.args, &td.heap, h));
+            let h = paren(&p.expr(h));
+            write!(w, " ({} : {}.Prov {})", p.hyps[i], sort, h)?
+          }
+          let sort = Printer::sort_name(self, hol::expr_sort(self, &td.args, &td.heap, &td.ret));
+          write!(w, " : {}.Prov {}", sort, paren(&p.expr(&td.ret)))?;
+          match &td.kind {
+            ThmKind::Axiom => writeln!(w, "\n")?,
+            ThmKind::Thm(None) => writeln!(w, " :=\n  sorry\n")?,
+            ThmKind::Thm(Some(Proof {heap, head, ..})) => {
+              writeln!(w, " :=")?;
+              p.reset_heap();
+              let mut body = vec![];
+              for (i, e) in heap.iter().enumerate().skip(td.args.len()) {
+                let c = p.proof(e);
+                if matches!(e, ProofNode::Thm {..} | ProofNode::Conv(_)) {
+                  let name = format!("s'{}", i);
+                  writeln!(body, "  have {} := {}", name, c)?;
+                  p.push_heap(name)
+                } else {
+                  p.push_heap(c)
+                }
+              }
+              write!(body, "  {}", p.proof(head))?;
+              write_body(self, w, &mut p, &body)?;
+            }
+          }
+        }
+      }
+    }
+    writeln!(w, "end MM0\n\nend")
+  }
+}
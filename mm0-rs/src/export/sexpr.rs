@@ -0,0 +1,185 @@
+//! S-expression exporter, which writes the declarations of an [`Environment`](crate::Environment)
+//! in the same form as they are returned by the lisp function `get-decl`, so that they can be
+//! processed by external lisp tools.
+//!
+//! Each declaration is written as one of:
+//!
+//! * `(term x bis ret)`
+//! * `(def x bis ret vis ds val)`
+//! * `(axiom x bis hyps ret)`
+//! * `(theorem x bis hyps ret vis (ds proof))`
+//!
+//! where `vis` is `()`, `pub`, `abstract` or `local`, `ds` is the list of dummy variables, and
+//! `proof` is an elaborated proof using `(:conv e c p)`, `(:sym c)` and `(:unfold t es c)` as in
+//! `get-decl`. A theorem with a missing proof has `:sorry` in place of `(ds proof)`.
+//! Since the proofs are printed as text, shared subproofs are bound using `(:let H-1 p1 p2)`
+//! like in MMU files, rather than being duplicated. (The names `H-n` cannot clash with MM0
+//! identifiers.)
+use std::collections::HashMap;
+use std::io::{self, Write};
+use crate::{Expr, Proof, AtomId, SortId, TermKind, ThmKind, ExprNode, ProofNode, StmtTrace,
+  DeclKey, Modifiers, FrozenEnv};
+
+fn vis(mods: Modifiers) -> &'static str {
+  match mods {
+    Modifiers::PUB => "pub",
+    Modifiers::ABSTRACT => "abstract",
+    Modifiers::LOCAL => "local",
+    _ => "()",
+  }
+}
+
+/// The writer for the proof of a single theorem.
+struct ProofWriter<'a> {
+  env: &'a FrozenEnv,
+  hyps: &'a [(Option<AtomId>, ExprNode)],
+  /// The translation of each heap element
+  heap: Vec<Vec<u8>>,
+  dummies: HashMap<AtomId, SortId>,
+}
+
+impl ProofWriter<'_> {
+  fn go(&mut self, w: &mut Vec<u8>, e: &ProofNode) -> io::Result<()> {
+    match *e {
+      ProofNode::Ref(i) => w.extend_from_slice(&self.heap[i]),
+      ProofNode::Dummy(a, s) => {
+        assert!(self.dummies.insert(a, s).map_or(true, |s2| s == s2));
+        w.extend_from_slice(self.env.data()[a].name())
+      }
+      ProofNode::Hyp(i, _) =>
+        w.extend_from_slice(self.hyps[i].0.map_or(b"_", |h| self.env.data()[h].name())),
+      ProofNode::Term {term, ref args} | ProofNode::Cong {term, ref args} =>
+        self.app(w, self.env.term(term).atom, args)?,
+      ProofNode::Thm {thm, ref args, ..} => self.app(w, self.env.thm(thm).atom, args)?,
+      ProofNode::Conv(ref p) => {
+        w.extend_from_slice(b"(:conv");
+        for e in [&p.0, &p.1, &p.2] { w.push(b' '); self.go(w, e)? }
+        w.push(b')')
+      }
+      ProofNode::Refl(ref p) => self.go(w, p)?,
+      ProofNode::Sym(ref p) => {
+        w.extend_from_slice(b"(:sym ");
+        self.go(w, p)?;
+        w.push(b')')
+      }
+      ProofNode::Unfold {term, ref args, ref res} => {
+        write!(w, "(:unfold {} (", self.env.data()[self.env.term(term).atom].name())?;
+        for (i, e) in args.iter().enumerate() {
+          if i != 0 { w.push(b' ') }
+          self.go(w, e)?
+        }
+        w.extend_from_slice(b") ");
+        self.go(w, &res.1)?;
+        w.push(b')')
+      }
+    }
+    Ok(())
+  }
+
+  fn app(&mut self, w: &mut Vec<u8>, head: AtomId, args: &[ProofNode]) -> io::Result<()> {
+    write!(w, "({}", self.env.data()[head].name())?;
+    for e in args { w.push(b' '); self.go(w, e)? }
+    w.push(b')');
+    Ok(())
+  }
+}
+
+impl FrozenEnv {
+  fn write_dummies(&self, w: &mut impl Write, dummies: HashMap<AtomId, SortId>) -> io::Result<()> {
+    let mut dummies = dummies.into_iter().collect::<Vec<_>>();
+    dummies.sort_by_key(|&(a, _)| &**self.data()[a].name());
+    write!(w, "(")?;
+    for (i, (a, s)) in dummies.into_iter().enumerate() {
+      if i != 0 { write!(w, " ")? }
+      write!(w, "({} {})", self.data()[a].name(), self.sort(s).name)?
+    }
+    write!(w, ")")
+  }
+
+  /// Write the declarations in this environment as s-expressions.
+  pub fn export_sexpr(&self, mut w: impl Write) -> io::Result<()> {
+    let w = &mut w;
+    for s in self.stmts() {
+      let a = if let StmtTrace::Decl(a) = *s {a} else {continue};
+      let ad = &self.data()[a];
+      match ad.decl().expect("expected a term/thm") {
+        DeclKey::Term(tid) => {
+          let td = self.term(tid);
+          write!(w, "({} {} ", if matches!(td.kind, TermKind::Term) {"term"} else {"def"}, ad.name())?;
+          let bvs = self.write_binders(w, &td.args)?;
+          write!(w, " ({} ", self.sort(td.ret.0).name)?;
+          self.write_deps(w, &bvs, td.ret.1)?;
+          write!(w, ")")?;
+          if let TermKind::Def(Some(Expr {heap, head})) = &td.kind {
+            let mut dummies = HashMap::new();
+            let mut strs: Vec<Vec<u8>> = td.args.iter().map(|&(a, _)|
+              Vec::from(a.map_or("_", |a| self.data()[a].name().as_str()))).collect();
+            for e in &heap[td.args.len()..] {
+              let c = self.write_expr_node(&mut dummies, &strs, e)?;
+              strs.push(c);
+            }
+            let ret = self.write_expr_node(&mut dummies, &strs, head)?;
+            write!(w, " {}\n  ", vis(td.vis))?;
+            self.write_dummies(w, dummies)?;
+            write!(w, "\n  ")?;
+            w.write_all(&ret)?;
+          }
+          writeln!(w, ")\n")?;
+        }
+        DeclKey::Thm(tid) => {
+          let td = self.thm(tid);
+          write!(w, "({} {} ", if matches!(td.kind, ThmKind::Axiom) {"axiom"} else {"theorem"}, ad.name())?;
+          self.write_binders(w, &td.args)?;
+          let mut dummies = HashMap::new();
+          let mut strs: Vec<Vec<u8>> = td.args.iter().map(|&(a, _)|
+            Vec::from(a.map_or("_", |a| self.data()[a].name().as_str()))).collect();
+          for e in &td.heap[td.args.len()..] {
+            let c = self.write_expr_node(&mut dummies, &strs, e)?;
+            strs.push(c);
+          }
+          write!(w, "\n  (")?;
+          for (i, (h, e)) in td.hyps.iter().enumerate() {
+            if i != 0 { write!(w, " ")? }
+            write!(w, "({} ", h.map_or("_", |h| self.data()[h].name().as_str()))?;
+            w.write_all(&self.write_expr_node(&mut dummies, &strs, e)?)?;
+            write!(w, ")")?;
+          }
+          write!(w, ")\n  ")?;
+          w.write_all(&self.write_expr_node(&mut dummies, &strs, &td.ret)?)?;
+          match &td.kind {
+            ThmKind::Axiom => {}
+            ThmKind::Thm(None) => write!(w, " {}\n  :sorry", vis(td.vis))?,
+            ThmKind::Thm(Some(Proof {heap, head, ..})) => {
+              write!(w, " {}\n  (", vis(td.vis))?;
+              strs.truncate(td.args.len());
+              let mut pw = ProofWriter {env: self, hyps: &td.hyps, heap: strs, dummies: HashMap::new()};
+              let mut lets = vec![];
+              let mut nlets = 0;
+              for e in &heap[td.args.len()..] {
+                let mut c = vec![];
+                pw.go(&mut c, e)?;
+                if matches!(e, ProofNode::Thm {..} | ProofNode::Conv(_)) {
+                  nlets += 1;
+                  write!(lets, "\n   (:let H-{} ", nlets)?;
+                  lets.extend_from_slice(&c);
+                  c = format!("H-{}", nlets).into_bytes();
+                }
+                pw.heap.push(c)
+              }
+              let mut pf = vec![];
+              pw.go(&mut pf, head)?;
+              self.write_dummies(w, pw.dummies)?;
+              w.write_all(&lets)?;
+              write!(w, "\n   ")?;
+              w.write_all(&pf)?;
+              w.write_all(&vec![b')'; nlets])?;
+              write!(w, ")")?;
+            }
+          }
+          writeln!(w, ")\n")?;
+        }
+      }
+    }
+    Ok(())
+  }
+}
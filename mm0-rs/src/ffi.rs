@@ -0,0 +1,158 @@
+//! A C interface to the verifier and the MMB exporter, for linking `mm0-rs` into programs
+//! written in other languages. This module is only available with the `ffi` feature.
+//!
+//! To build a C library and its header:
+//!
+//! ```text
+//! cargo rustc --release --lib --features ffi --crate-type cdylib   # or staticlib
+//! cbindgen --config cbindgen.toml --output mm0_rs.h
+//! ```
+//!
+//! All the functions return `0` on success and `1` on failure. On failure, if `error` is not
+//! null, `*error` is set to a newly allocated error message, which should be freed with
+//! [`mm0_free_string`]. Panics are caught and reported as failures.
+//!
+//! ```c
+//! char *err = NULL;
+//! if (mm0_verify(mmb, mmb_len, spec, &err)) {
+//!   fprintf(stderr, "%s\n", err);
+//!   mm0_free_string(err);
+//! }
+//! ```
+
+use std::ffi::{CStr, CString};
+use std::io::Cursor;
+use std::os::raw::{c_char, c_int};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
+use zerocopy::AsBytes;
+use mm0b_parser::BasicMmbFile;
+use crate::api::{self, Snapshot};
+use crate::mmb::export::{ExportOptions, Exporter};
+use crate::mmb::verify::{check_spec, verify_file};
+use crate::ErrorLevel;
+
+/// Run `f`, converting a failure or a panic into an error code and message.
+unsafe fn run(error: *mut *mut c_char, f: impl FnOnce() -> Result<(), String>) -> c_int {
+  let msg = match catch_unwind(AssertUnwindSafe(f)) {
+    Ok(Ok(())) => return 0,
+    Ok(Err(msg)) => msg,
+    Err(_) => "internal error (panic)".into(),
+  };
+  if !error.is_null() {
+    *error = CString::new(msg.replace('\0', " ")).expect("no nul bytes").into_raw()
+  }
+  1
+}
+
+/// Read a C string argument as UTF-8.
+unsafe fn to_str<'a>(s: *const c_char, name: &str) -> Result<&'a str, String> {
+  if s.is_null() { return Err(format!("{} is null", name)) }
+  CStr::from_ptr(s).to_str().map_err(|_| format!("{} is not valid UTF-8", name))
+}
+
+/// Describe the errors in an elaborated file, one per line.
+fn errors(snap: &Snapshot) -> String {
+  let mut s = String::new();
+  for d in snap.diagnostics.iter().filter(|d| d.level == ErrorLevel::Error) {
+    use std::fmt::Write;
+    if !s.is_empty() { s.push('\n') }
+    match d.range {
+      Some(r) => write!(s, "{}:{}:{}: {}", d.file, r.start.line + 1, r.start.character + 1, d.message),
+      None => write!(s, "{}: {}", d.file, d.message),
+    }.expect("writing to a string")
+  }
+  s
+}
+
+/// Check all the proofs in the MMB file `mmb` (of length `mmb_len`), and if `spec` is not
+/// null, check that the file matches the specification `spec`, which is the text of an
+/// MM0 file.
+///
+/// # Safety
+///
+/// `mmb` must point to `mmb_len` readable bytes, `spec` must be null or a nul-terminated
+/// string, and `error` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn mm0_verify(mmb: *const u8, mmb_len: usize,
+    spec: *const c_char, error: *mut *mut c_char) -> c_int {
+  run(error, || {
+    if mmb.is_null() { return Err("mmb is null".into()) }
+    // Copy the file into a `u64` buffer so that the tables are aligned
+    let mut buf = vec![0_u64; (mmb_len + 7) >> 3];
+    let bytes = &mut buf.as_bytes_mut()[..mmb_len];
+    bytes.copy_from_slice(std::slice::from_raw_parts(mmb, mmb_len));
+    let file = BasicMmbFile::parse(bytes).map_err(|e| e.to_string())?;
+    verify_file(&file).map_err(|e| e.to_string())?;
+    if !spec.is_null() {
+      let spec = to_str(spec, "spec")?.to_owned();
+      let snap = api::elaborate_text("<spec>.mm0", spec).map_err(|e| e.to_string())?;
+      if snap.has_errors() {
+        return Err(format!("the specification contains errors:\n{}", errors(&snap)))
+      }
+      check_spec(&file, &snap.env).map_err(|e| e.to_string())?
+    }
+    Ok(())
+  })
+}
+
+/// Elaborate the MM1 (or MM0) file at `path` and compile it to MMB.
+///
+/// A newly allocated buffer containing the MMB file is stored in `*out` and its length in
+/// `*out_len`. The buffer should be freed with [`mm0_free_bytes`]. If `src` is not null, it is
+/// used as the text of the file instead of reading it from disk; `path` is still used to
+/// resolve imports.
+///
+/// # Safety
+///
+/// `path` must be a nul-terminated string, `src` must be null or a nul-terminated string,
+/// `out` and `out_len` must be valid for writes, and `error` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn mm0_compile(path: *const c_char, src: *const c_char,
+    out: *mut *mut u8, out_len: *mut usize, error: *mut *mut c_char) -> c_int {
+  run(error, || {
+    if out.is_null() || out_len.is_null() { return Err("out is null".into()) }
+    let path = Path::new(to_str(path, "path")?);
+    let snap = if src.is_null() { api::elaborate(path) } else {
+      api::elaborate_text(path, to_str(src, "src")?.to_owned())
+    }.map_err(|e| format!("{}: {}", path.display(), e))?;
+    if snap.has_errors() { return Err(errors(&snap)) }
+    let mut msgs = vec![];
+    let mut report = |level: ErrorLevel, msg: &str|
+      if level == ErrorLevel::Error { msgs.push(msg.to_owned()) };
+    let mut w = Cursor::new(vec![]);
+    let res = Exporter::new(snap.path.clone(), snap.source.as_deref(), &snap.env, &mut report, &mut w)
+      .export(&ExportOptions::new());
+    if let Err(e) = res {
+      msgs.push(e.to_string());
+      return Err(msgs.join("\n"))
+    }
+    let buf = w.into_inner().into_boxed_slice();
+    *out_len = buf.len();
+    *out = Box::into_raw(buf).cast();
+    Ok(())
+  })
+}
+
+/// Free a buffer returned by [`mm0_compile`].
+///
+/// # Safety
+///
+/// `buf` must be null, or a buffer of length `len` returned by [`mm0_compile`]
+/// which has not yet been freed.
+#[no_mangle]
+pub unsafe extern "C" fn mm0_free_bytes(buf: *mut u8, len: usize) {
+  if !buf.is_null() {
+    drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(buf, len)))
+  }
+}
+
+/// Free an error message returned by one of the functions in this module.
+///
+/// # Safety
+///
+/// `s` must be null, or a string returned in `error` which has not yet been freed.
+#[no_mangle]
+pub unsafe extern "C" fn mm0_free_string(s: *mut c_char) {
+  if !s.is_null() { drop(CString::from_raw(s)) }
+}
@@ -0,0 +1,550 @@
+//! The `mm0-rs fmt` subcommand, which formats MM0 and MM1 files in a canonical style.
+//!
+//! The formatter reparses the file and prints each statement again from its [`Ast`]:
+//!
+//! * Every statement starts on its own line, there is at most one blank line between two
+//!   statements, and trailing whitespace is removed.
+//! * Whitespace inside the statement header is normalized: one space between words,
+//!   `$ ... $` around the contents of each formula (whose whitespace is collapsed to single
+//!   spaces), and the modifiers are written in the order `pure strict provable free` for sorts
+//!   and `pub abstract local` for declarations.
+//! * A statement longer than the line width (100 by default) is wrapped: binder groups
+//!   which do not fit are moved to the next line, indented by two spaces, and a proof or
+//!   definition which does not fit is moved to the next line. A formula which does not fit
+//!   on a line is broken after the infix operators of lowest precedence outside parentheses,
+//!   using the notations declared in the file and the files it imports.
+//! * Comments, doc comments and lisp code (`do` blocks, proofs, annotations) are kept as they
+//!   are. A statement which contains a comment inside its header is not reformatted.
+//!
+//! The language server uses the same formatter for the `textDocument/formatting` request.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Write};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use clap::ArgMatches;
+use mm1_parser::parse;
+use crate::ast::{Ast, Binder, Decl, DeclKind, Delimiter, Formula, GenNota, ImportFilter,
+  LocalKind, Literal, SExprKind, SimpleNota, SimpleNotaKind, Stmt, StmtKind, Type};
+use crate::{Delims, LinedString, Prec, Span};
+
+/// The default maximum line width.
+pub const DEFAULT_WIDTH: usize = 100;
+
+/// The notations which are used to tokenize formulas and to find where they can be broken.
+#[derive(Default, Debug)]
+struct Notations {
+  /// The left delimiters.
+  delims_l: Delims,
+  /// The right delimiters.
+  delims_r: Delims,
+  /// The infix constants, and the precedences of their notations.
+  infixes: HashMap<String, u32>,
+}
+
+impl Notations {
+  /// Add the notations declared in `ast`, and in the files it imports (found relative to `path`).
+  fn add_file(&mut self, path: &Path, ast: &Ast, visited: &mut HashSet<PathBuf>) {
+    let src: &str = &ast.source;
+    for s in ast.stmts_iter() {
+      match &s.k {
+        StmtKind::Delimiter(Delimiter::Both(cs)) =>
+          for &c in &**cs { self.delims_l.set(c); self.delims_r.set(c) },
+        StmtKind::Delimiter(Delimiter::LeftRight(ls, rs)) => {
+          for &c in &**ls { self.delims_l.set(c) }
+          for &c in &**rs { self.delims_r.set(c) }
+        }
+        &StmtKind::SimpleNota(SimpleNota {k: SimpleNotaKind::Infix {..}, c, prec: Prec::Prec(p), ..}) => {
+          self.infixes.insert(src[c.trim.start..c.trim.end].to_owned(), p);
+        }
+        StmtKind::Notation(GenNota {lits, prec: Some((Prec::Prec(p), _)), ..}) =>
+          if let [Literal::Var(_), Literal::Const(c, _), ..] = **lits {
+            self.infixes.insert(src[c.trim.start..c.trim.end].to_owned(), *p);
+          },
+        _ => {}
+      }
+    }
+    for (_, f) in &ast.imports {
+      let path = crate::resolve_import(path, &String::from_utf8_lossy(f));
+      if path.extension().map_or(true, |e| e != "mm0" && e != "mm1") { continue }
+      let path = if let Ok(p) = fs::canonicalize(path) {p} else {continue};
+      if !visited.insert(path.clone()) { continue }
+      if let Ok(text) = fs::read_to_string(&path) {
+        let ast = parse(Arc::new(text.into()), None).1;
+        self.add_file(&path, &ast, visited)
+      }
+    }
+  }
+}
+
+/// Returns true if `s` contains a `--` comment outside of formulas and strings.
+fn has_comment(s: &[u8]) -> bool {
+  let mut i = 0;
+  while i < s.len() {
+    match s[i] {
+      b'$' => { i += 1; while i < s.len() && s[i] != b'$' { i += 1 } }
+      b'"' => {
+        i += 1;
+        while i < s.len() && s[i] != b'"' { if s[i] == b'\\' { i += 1 } i += 1 }
+      }
+      b'-' if s.get(i + 1) == Some(&b'-') => return true,
+      _ => {}
+    }
+    i += 1;
+  }
+  false
+}
+
+/// A token of a formula, and whether it is preceded by whitespace.
+type Token<'a> = (bool, &'a str);
+
+/// Join the tokens `toks`, with a space wherever there was whitespace (but not before the first).
+fn join(toks: &[Token<'_>]) -> String {
+  let mut s = String::new();
+  for (i, &(space, tk)) in toks.iter().enumerate() {
+    if space && i != 0 { s.push(' ') }
+    s.push_str(tk)
+  }
+  s
+}
+
+fn len(s: &str) -> usize { s.chars().count() }
+
+struct Formatter<'a> {
+  /// The text of the file.
+  src: &'a str,
+  /// The notations of the file and the files it imports.
+  nota: &'a Notations,
+  /// The maximum line width.
+  width: usize,
+  /// The formatted text.
+  out: String,
+}
+
+impl<'a> Formatter<'a> {
+  fn put(&mut self, args: fmt::Arguments<'_>) {
+    self.out.write_fmt(args).expect("writing to a string")
+  }
+
+  fn text(&self, sp: Span) -> &'a str { &self.src[sp.start..sp.end] }
+
+  /// The column of the end of the output.
+  fn col(&self) -> usize { len(&self.out[self.out.rfind('\n').map_or(0, |i| i + 1)..]) }
+
+  /// Returns true if the current line of the output is empty, apart from the indentation.
+  fn fresh(&self) -> bool {
+    self.out[self.out.rfind('\n').map_or(0, |i| i + 1)..].bytes().all(|c| c == b' ')
+  }
+
+  fn newline(&mut self, indent: usize) {
+    self.out.push('\n');
+    self.out.push_str(&" ".repeat(indent))
+  }
+
+  /// Write the whitespace and comments between two statements, in `a..b`. If `eof` is
+  /// true, this is the end of the file.
+  fn gap(&mut self, a: usize, b: usize, eof: bool) {
+    let mut lines = self.src[a..b].split('\n').collect::<Vec<_>>();
+    if a != 0 {
+      // a comment at the end of the line of the previous statement
+      let first = lines.remove(0).trim();
+      if !first.is_empty() { self.out.push(' '); self.out.push_str(first) }
+    }
+    // the indentation of the next statement
+    if !eof { lines.pop(); }
+    if !self.out.is_empty() && !self.out.ends_with('\n') { self.out.push('\n') }
+    let mut blank = false;
+    for line in lines {
+      let line = line.trim_end();
+      if line.is_empty() { blank = true; continue }
+      if blank && !self.out.is_empty() { self.out.push('\n') }
+      blank = false;
+      self.out.push_str(line);
+      self.out.push('\n');
+    }
+    if blank && !eof && !self.out.is_empty() { self.out.push('\n') }
+  }
+
+  /// The tokens of the formula `f`, as the math parser reads them.
+  fn tokens(&self, f: Formula) -> Vec<Token<'a>> {
+    let src = self.src.as_bytes();
+    let Span {start: mut i, end} = f.inner();
+    let (mut toks, mut space) = (vec![], false);
+    while i < end {
+      if let b' ' | b'\n' = src[i] { space = true; i += 1; continue }
+      let start = i;
+      while i < end {
+        let c = src[i];
+        if self.nota.delims_r.get(c) && i != start { break }
+        if self.nota.delims_l.get(c) { i += 1; break }
+        if let b' ' | b'\n' = c { break }
+        i += 1;
+      }
+      toks.push((space, self.text((start..i).into())));
+      space = false;
+    }
+    toks
+  }
+
+  /// The formula `f` on one line.
+  fn formula_line(&self, f: Formula) -> String {
+    let toks = self.tokens(f);
+    if toks.is_empty() { "$ $".into() } else { format!("$ {} $", join(&toks)) }
+  }
+
+  /// Split `toks` after the infix operators of lowest precedence outside parentheses,
+  /// or return `None` if there are none.
+  fn split<'b>(&self, toks: &'b [Token<'a>]) -> Option<Vec<&'b [Token<'a>]>> {
+    let (mut depth, mut best, mut cuts) = (0_i32, None, vec![]);
+    for (i, &(_, tk)) in toks.iter().enumerate() {
+      match tk {
+        "(" => depth += 1,
+        ")" => depth -= 1,
+        _ if depth == 0 && i + 1 < toks.len() => if let Some(&p) = self.nota.infixes.get(tk) {
+          match best {
+            Some(q) if q < p => {}
+            Some(q) if q == p => cuts.push(i + 1),
+            _ => { best = Some(p); cuts = vec![i + 1] }
+          }
+        }
+        _ => {}
+      }
+    }
+    if cuts.is_empty() { return None }
+    let mut start = 0;
+    let mut segs = vec![];
+    for i in cuts { segs.push(&toks[start..i]); start = i }
+    segs.push(&toks[start..]);
+    Some(segs)
+  }
+
+  /// Write the formula tokens `toks`, breaking lines as necessary, with continuation lines
+  /// indented by `indent`. `trail` is the length of the text which will follow on the same line.
+  fn tokens_out(&mut self, toks: &[Token<'a>], indent: usize, trail: usize) {
+    let s = join(toks);
+    if self.col() + len(&s) + trail <= self.width { return self.out.push_str(&s) }
+    let segs = if let Some(segs) = self.split(toks) {segs} else { return self.group_out(toks, indent, trail) };
+    let n = segs.len();
+    for (i, seg) in segs.into_iter().enumerate() {
+      let trail = if i + 1 == n {trail} else {0};
+      if i != 0 {
+        let s = join(seg);
+        if self.col() + 1 + len(&s) + trail <= self.width {
+          if seg[0].0 { self.out.push(' ') }
+          self.out.push_str(&s);
+          continue
+        }
+        self.newline(indent)
+      }
+      self.tokens_out(seg, indent + 2, trail)
+    }
+  }
+
+  /// Write the formula tokens `toks`, which have no infix operator to break at outside
+  /// parentheses, by breaking the contents of the longest parenthesized group instead.
+  fn group_out(&mut self, toks: &[Token<'a>], indent: usize, trail: usize) {
+    let (mut depth, mut open, mut best) = (0_i32, 0, None);
+    for (i, &(_, tk)) in toks.iter().enumerate() {
+      match tk {
+        "(" => { if depth == 0 { open = i } depth += 1 }
+        ")" => {
+          depth -= 1;
+          if depth == 0 && best.map_or(true, |(a, b)| b - a < i - open) { best = Some((open, i)) }
+        }
+        _ => {}
+      }
+    }
+    match best {
+      Some((a, b)) if b > a + 1 => {
+        self.out.push_str(&join(&toks[..=a]));
+        if toks[a + 1].0 { self.out.push(' ') }
+        let rest = format!("{}{}", if toks[b].0 {" "} else {""}, join(&toks[b..]));
+        self.tokens_out(&toks[a + 1..b], indent, len(&rest) + trail);
+        self.out.push_str(&rest)
+      }
+      _ => self.out.push_str(&join(toks)),
+    }
+  }
+
+  /// Write the formula `f`, breaking it if necessary as in [`tokens_out`](Self::tokens_out).
+  fn formula(&mut self, f: Formula, indent: usize, trail: usize) {
+    let toks = self.tokens(f);
+    if toks.is_empty() { return self.out.push_str("$ $") }
+    self.out.push_str("$ ");
+    self.tokens_out(&toks, indent, trail + 2);
+    self.out.push_str(" $");
+  }
+
+  /// Write `prefix`, the formula `f` and `suffix` after a space, or on a new line indented by
+  /// `indent` if it does not fit. If it does not fit on a line by itself, the formula is broken.
+  /// `trail` is the length of the text which will follow on the same line.
+  fn piece(&mut self, prefix: &str, f: Option<Formula>, suffix: &str, trail: usize) {
+    let indent = 2;
+    let s = format!("{}{}{}", prefix, f.map_or_else(String::new, |f| self.formula_line(f)), suffix);
+    let n = len(&s) + trail;
+    if !self.fresh() {
+      if self.col() + 1 + n <= self.width { self.out.push(' ') } else { self.newline(indent) }
+    }
+    if self.col() + n <= self.width { return self.out.push_str(&s) }
+    self.out.push_str(prefix);
+    if let Some(f) = f { self.formula(f, indent + 2, len(suffix) + trail) }
+    self.out.push_str(suffix);
+  }
+
+  /// Write a word after a space, or on a new line if it does not fit.
+  fn word(&mut self, s: &str) { self.piece(s, None, "", 0) }
+
+  /// The text of a type, or the formula if it is a formula.
+  fn ty(&self, ty: &Type) -> (String, Option<Formula>) {
+    match ty {
+      Type::DepType(d) => {
+        let mut s = self.text(d.sort).to_owned();
+        for &sp in &*d.deps { s.push(' '); s.push_str(self.text(sp)) }
+        (s, None)
+      }
+      &Type::Formula(f) => (String::new(), Some(f)),
+    }
+  }
+
+  /// Write the binder groups in `bis`, then the arrow types and the return type `ret`,
+  /// followed by text of length `trail` on the same line.
+  fn binders(&mut self, bis: &[Binder], ret: Option<&Type>, trail: usize) {
+    let colon = usize::from(ret.is_some() || bis.iter().any(|bi| bi.local.is_none()));
+    let mut arrows = vec![];
+    let mut i = 0;
+    while i < bis.len() {
+      let bi = &bis[i];
+      if bi.local.is_none() { arrows.extend(&bi.ty); i += 1; continue }
+      let group = bis[i..].iter().take_while(|bi2| bi2.span == bi.span && bi2.local.is_some()).count();
+      let curly = self.src.as_bytes()[bi.span.start] == b'{';
+      let mut prefix = if curly {"{"} else {"("}.to_owned();
+      for (j, bi) in bis[i..i + group].iter().enumerate() {
+        if j != 0 { prefix.push(' ') }
+        if bi.kind == LocalKind::Dummy { prefix.push('.') }
+        prefix.push_str(self.text(bi.local.expect("checked")))
+      }
+      let close = if curly {"}"} else {")"};
+      i += group;
+      let trail = if bis[i..].iter().all(|bi| bi.local.is_none()) {colon} else {0};
+      match &bi.ty {
+        None => { prefix.push_str(close); self.piece(&prefix, None, "", trail) }
+        Some(ty) => {
+          let (s, f) = self.ty(ty);
+          self.piece(&format!("{}: {}", prefix, s), f, close, trail)
+        }
+      }
+    }
+    if arrows.is_empty() && ret.is_none() { return }
+    self.out.push(':');
+    for ty in arrows {
+      let (s, f) = self.ty(ty);
+      self.piece(&s, f, " >", 0)
+    }
+    if let Some(ty) = ret {
+      let (s, f) = self.ty(ty);
+      self.piece(&s, f, "", trail)
+    }
+  }
+
+  fn decl(&mut self, d: &Decl) {
+    let kind = match d.k {
+      DeclKind::Term => "term",
+      DeclKind::Axiom => "axiom",
+      DeclKind::Thm => "theorem",
+      DeclKind::Def => "def",
+    };
+    self.put(format_args!("{}{} {}", d.mods, kind, self.text(d.id)));
+    self.binders(&d.bis, d.ty.as_ref(), if d.val.is_some() {2} else {1});
+    match &d.val {
+      None => self.out.push(';'),
+      Some(e) => if let SExprKind::Formula(f) = e.k {
+        let s = self.formula_line(f);
+        if self.col() + 3 + len(&s) < self.width {
+          self.put(format_args!(" = {};", s))
+        } else {
+          self.out.push_str(" =");
+          self.newline(2);
+          self.formula(f, 4, 1);
+          self.out.push(';')
+        }
+      } else {
+        let s = self.text(e.span);
+        if !s.contains('\n') && self.col() + 3 + len(s) < self.width {
+          self.out.push_str(" = ")
+        } else {
+          self.out.push_str(" =\n")
+        }
+        self.out.push_str(s);
+        self.out.push(';')
+      }
+    }
+  }
+
+  fn notation(&mut self, n: &GenNota) {
+    self.put(format_args!("{}notation {}", if n.local {"local "} else {""}, self.text(n.id)));
+    self.binders(&n.bis, n.ty.as_ref(), 2);
+    self.out.push_str(" =");
+    for lit in &n.lits {
+      match *lit {
+        Literal::Const(c, p) => self.word(&format!("(${}$:{})", self.text(c.trim), p)),
+        Literal::Var(sp) => self.word(self.text(sp)),
+      }
+    }
+    if let Some((p, right)) = n.prec {
+      self.out.push(':');
+      self.word(&format!("{} {}", p, if right {"rassoc"} else {"lassoc"}))
+    }
+    self.out.push(';')
+  }
+
+  /// Write the statement `s`.
+  fn stmt(&mut self, s: &Stmt) {
+    let verbatim = match &s.k {
+      StmtKind::Do(_) => true,
+      StmtKind::DocComment(..) | StmtKind::Annot(..) | StmtKind::Attrs(..) => false,
+      StmtKind::Decl(Decl {val: Some(e), ..}) =>
+        has_comment(&self.src.as_bytes()[s.span.start..e.span.start]) ||
+        has_comment(&self.src.as_bytes()[e.span.end..s.span.end]),
+      _ => has_comment(&self.src.as_bytes()[s.span.start..s.span.end]),
+    };
+    if verbatim { return self.out.push_str(self.text(s.span)) }
+    match &s.k {
+      StmtKind::Sort(sp, mods) => self.put(format_args!("{}sort {};", mods, self.text(*sp))),
+      StmtKind::Decl(d) => self.decl(d),
+      StmtKind::Delimiter(d) => {
+        let chars = |cs: &[u8]| cs.iter().map(|&c| (c as char).to_string()).collect::<Vec<_>>().join(" ");
+        match d {
+          Delimiter::Both(cs) => self.put(format_args!("delimiter $ {} $;", chars(cs))),
+          Delimiter::LeftRight(ls, rs) =>
+            self.put(format_args!("delimiter $ {} $ $ {} $;", chars(ls), chars(rs))),
+        }
+      }
+      StmtKind::SimpleNota(n) => {
+        let k = match n.k {
+          SimpleNotaKind::Prefix => "prefix",
+          SimpleNotaKind::Infix {right: false} => "infixl",
+          SimpleNotaKind::Infix {right: true} => "infixr",
+        };
+        self.put(format_args!("{}{} {}: ${}$ prec {};", if n.local {"local "} else {""},
+          k, self.text(n.id), self.text(n.c.trim), n.prec))
+      }
+      &StmtKind::Coercion {id, from, to} => self.put(format_args!("coercion {}: {} > {};",
+        self.text(id), self.text(from), self.text(to))),
+      StmtKind::Notation(n) => self.notation(n),
+      StmtKind::Inout {out, k, hs} => {
+        self.put(format_args!("{} {}:", if *out {"output"} else {"input"}, self.text(*k)));
+        for e in hs { self.word(self.text(e.span)) }
+        self.out.push(';')
+      }
+      StmtKind::Annot(e, s2) => {
+        if has_comment(&self.src.as_bytes()[e.span.end..s2.span.start]) {
+          return self.out.push_str(self.text(s.span))
+        }
+        self.put(format_args!("@{} ", self.text(e.span)));
+        self.stmt(s2)
+      }
+      StmtKind::Attrs(attrs, s2) => {
+        if has_comment(&self.src.as_bytes()[s.span.start..s2.span.start]) {
+          return self.out.push_str(self.text(s.span))
+        }
+        let attrs = attrs.iter().map(|a| {
+          let mut s = self.text(a.name).to_owned();
+          for e in &a.args { s.push(' '); s.push_str(self.text(e.span)) }
+          s
+        }).collect::<Vec<_>>();
+        self.put(format_args!("@[{}] ", attrs.join(", ")));
+        self.stmt(s2)
+      }
+      StmtKind::DocComment(_, s2) => {
+        for line in self.src[s.span.start..s2.span.start].lines() {
+          let line = line.trim();
+          if !line.is_empty() { self.out.push_str(line); self.out.push('\n') }
+        }
+        self.stmt(s2)
+      }
+      StmtKind::Do(_) => unreachable!(),
+      StmtKind::Import(sp, _, filter) => {
+        self.put(format_args!("import {}", self.text(*sp)));
+        match filter {
+          None => {}
+          Some(ImportFilter::Only(names)) => self.put(format_args!(" ({})",
+            names.iter().map(|&sp| self.text(sp)).collect::<Vec<_>>().join(" "))),
+          Some(ImportFilter::As(sp)) => self.put(format_args!(" as {}", self.text(*sp))),
+        }
+        self.out.push(';')
+      }
+      StmtKind::Section(name) => match name {
+        Some(sp) => self.put(format_args!("section {};", self.text(*sp))),
+        None => self.out.push_str("section;"),
+      },
+      StmtKind::Namespace(sp) => self.put(format_args!("namespace {};", self.text(*sp))),
+      StmtKind::Open(sp) => self.put(format_args!("open {};", self.text(*sp))),
+      StmtKind::End(name) => match name {
+        Some(sp) => self.put(format_args!("end {};", self.text(*sp))),
+        None => self.out.push_str("end;"),
+      },
+    }
+  }
+}
+
+/// Format the MM0/MM1 file at `path` with contents `text`, with lines at most `width`
+/// characters long where possible. Returns an error message if the file has parse errors.
+pub fn format(path: &Path, text: &Arc<LinedString>, width: usize) -> Result<String, String> {
+  let ast = parse(text.clone(), None).1;
+  if let Some(e) = ast.errors.first() {
+    let pos = text.to_pos(e.pos.start);
+    return Err(format!("{}:{}:{}: cannot format a file with parse errors: {}",
+      path.display(), pos.line + 1, pos.character + 1, e.msg))
+  }
+  let mut nota = Notations::default();
+  nota.add_file(path, &ast, &mut HashSet::new());
+  let mut f = Formatter {src: text, nota: &nota, width, out: String::new()};
+  let mut end = 0;
+  for s in &ast.stmts {
+    f.gap(end, s.span.start, false);
+    f.stmt(s);
+    end = s.span.end;
+  }
+  f.gap(end, text.len(), true);
+  if !f.out.is_empty() && !f.out.ends_with('\n') { f.out.push('\n') }
+  // Check that the result parses to the same statements, in case of a bug in the formatter.
+  let ast2 = parse(Arc::new(f.out.clone().into()), None).1;
+  if !ast2.errors.is_empty() || ast2.stmts_iter().count() != ast.stmts_iter().count() {
+    return Err(format!("{}: internal error: the formatted file does not parse", path.display()))
+  }
+  Ok(f.out)
+}
+
+/// Main entry point for `mm0-rs fmt` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs fmt [--check] [--width N] FILE...`, where:
+///
+/// - `FILE` are the MM0/MM1 files to format, which are formatted in place.
+/// - `--check`: Don't change the files, but list the files which are not formatted,
+///   and fail if there are any.
+/// - `--width N`: The maximum line width, 100 by default.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let width = match args.value_of("width") {
+    Some(w) => w.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput,
+      format!("invalid width '{}'", w)))?,
+    None => DEFAULT_WIDTH,
+  };
+  let check = args.is_present("check");
+  let mut ok = true;
+  for file in args.values_of_os("INPUT").expect("required arg") {
+    let path = Path::new(file);
+    let text = Arc::new(LinedString::from(fs::read_to_string(path)?));
+    match format(path, &text, width) {
+      Ok(out) if out == **text => {}
+      Ok(_) if check => { println!("{}", path.display()); ok = false }
+      Ok(out) => fs::write(path, out)?,
+      Err(e) => { eprintln!("error: {}", e); ok = false }
+    }
+  }
+  if !ok { std::process::exit(1) }
+  Ok(())
+}
@@ -16,7 +16,6 @@
 use std::collections::HashSet;
 use std::fs;
 use std::io::{self, Write};
-use std::path::PathBuf;
 use std::sync::Arc;
 use clap::ArgMatches;
 use mm0_util::{FileRef, LinedString};
@@ -58,12 +57,10 @@ impl<W: Write> Joiner<W> {
     let (_, ast) = parse(src.clone(), None);
     let mut start = 0;
     for s in &ast.stmts {
-      if let StmtKind::Import(_, f) = &s.k {
+      if let StmtKind::Import(_, f, _) = &s.k {
         let f = std::str::from_utf8(f).map_err(|_|
           io::Error::new(io::ErrorKind::InvalidInput, "invalid utf8"))?;
-        let r: FileRef = path.path().parent()
-          .map_or_else(|| PathBuf::from(f), |p| p.join(f))
-          .canonicalize()?.into();
+        let r: FileRef = crate::resolve_import(path.path(), f).canonicalize()?.into();
         self.w.write_all(&src.as_bytes()[start..s.span.start])?;
         if self.working.insert(r.clone()) {
           self.write(r)?;
@@ -12,6 +12,7 @@
 //!     compile    Compile MM1 files into MMB
 //!     help       Prints this message or the help of the given subcommand(s)
 //!     join       Join MM1/MM0 files with imports by concatenation
+//!     link       Join several MMB files into one
 //!     server     MM1 LSP server
 //! ```
 //!
@@ -58,40 +59,157 @@ pub use mm0_deepsize::deep_size_0;
 
 #[cfg(feature = "server")]
 #[macro_use] pub mod server;
+pub mod api;
+pub mod axiom_use;
+pub mod search;
+pub mod cache;
 pub mod compiler;
+pub mod diagnostics;
+pub mod formatter;
 pub mod joiner;
 pub mod elab;
 #[cfg(feature = "doc")]
 pub mod doc;
+#[cfg(feature = "repl")]
+pub mod repl;
+#[cfg(feature = "dap")]
+pub mod dap;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 /// Import and export functionality for MMB binary proof format
 ///
 /// See [`mm0-c/verifier.c`] for information on the MMB format.
 ///
 /// [`mm0-c/verifier.c`]: https://github.com/digama0/mm0/blob/master/mm0-c/verifier.c
-pub mod mmb { pub mod export; pub mod import; }
+pub mod mmb {
+  pub mod diff; pub mod export; pub mod import; pub mod join; pub mod verify;
+  pub use verify::verify;
+}
 /// Import and export functionality for MMU ascii proof format
 ///
 /// See [The `.mmu` file format] for information on the MMU format.
 ///
 /// [The `.mmu` file format]: https://github.com/digama0/mm0/blob/master/mm0-hs/README.md#the-mmu-file-format
 pub mod mmu { pub mod import; pub mod export; }
+/// Import functionality for Metamath `.mm` databases
+///
+/// See [the Metamath book] for information on the `.mm` format.
+///
+/// [the Metamath book]: https://us.metamath.org/downloads/metamath.pdf
+pub mod mm { pub mod import; }
+/// Export functionality for other proof assistants and external tools
+pub mod export { mod hol; pub mod coq; pub mod dedukti; pub mod dump; pub mod graph; pub mod json; pub mod lean; pub mod sexpr; }
+pub mod render;
+pub mod project;
+pub mod unused;
 #[cfg(feature = "mmc")]
 pub mod mmc;
 
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 
 pub use elab::{environment::*,
   frozen::{FrozenAtomData, FrozenEnv, FrozenLispKind, FrozenLispVal},
-  lisp::{self, debug::EnvDebug, print::{EnvDisplay, FormatEnv}, LispKind, LispVal, Uncons},
+  lisp::{self, debug::EnvDebug, eval::EvalLimits, print::{EnvDisplay, FormatEnv},
+    LispKind, LispVal, Uncons},
   local_context::{try_get_span, LocalContext},
-  ElabError, Elaborator};
+  warning::{WarningCode, WarningConfig, WarningLevel},
+  CancelToken, ElabError, Elaborator};
 pub use mm0_util::*;
 pub use mm1_parser::{ast, DocComment, ErrorLevel};
 pub use mm0b_parser::MAX_BOUND_VARS;
 
+lazy_static! {
+  static ref EVAL_LIMITS: std::sync::Mutex<EvalLimits> = Default::default();
+  static ref WARNINGS: std::sync::Mutex<WarningConfig> = Default::default();
+  static ref INCLUDE_PATHS: std::sync::Mutex<Vec<PathBuf>> = Default::default();
+  static ref SCOPED_INCLUDE_PATHS: std::sync::Mutex<Vec<(PathBuf, Vec<PathBuf>)>> = Default::default();
+  static ref SMT_CMD: std::sync::Mutex<Option<Vec<String>>> = Default::default();
+}
+
 static CHECK_PROOFS: AtomicBool = AtomicBool::new(true);
 pub(crate) fn get_check_proofs() -> bool { CHECK_PROOFS.load(Ordering::Relaxed) }
 
 /// Set the initial proof checking behavior at the start of an MM1 file
 /// before a `(check-proofs)` command is found.
 pub fn set_check_proofs(b: bool) { CHECK_PROOFS.store(b, Ordering::Relaxed) }
+
+static ALLOW_IO: AtomicBool = AtomicBool::new(false);
+pub(crate) fn get_allow_io() -> bool { ALLOW_IO.load(Ordering::Relaxed) }
+
+/// Enable the lisp file I/O builtins `read-file`, `write-file` and `file-exists?`,
+/// which are disabled by default.
+pub fn set_allow_io(b: bool) { ALLOW_IO.store(b, Ordering::Relaxed) }
+
+/// The SMT solver command used by `--allow-smt` when no other command is given.
+pub const DEFAULT_SMT_CMD: &str = "z3 -in";
+
+pub(crate) fn get_smt_cmd() -> Option<Vec<String>> { SMT_CMD.ulock().clone() }
+
+/// Enable the `smt` lisp builtin, which is disabled by default, and set the command line
+/// (a program and its arguments, separated by whitespace) of the SMT solver it runs.
+/// The command is chosen by the user, never by the lisp code. `None` disables the builtin.
+pub fn set_smt_cmd(cmd: Option<&str>) {
+  *SMT_CMD.ulock() = cmd.map(|cmd| cmd.split_whitespace().map(Into::into).collect::<Vec<_>>())
+    .filter(|cmd| !cmd.is_empty())
+}
+
+static MINIMIZE_PROOFS: AtomicBool = AtomicBool::new(false);
+pub(crate) fn get_minimize_proofs() -> bool { MINIMIZE_PROOFS.load(Ordering::Relaxed) }
+
+/// Run the [proof minimization](elab::minimize) pass on every theorem after it is elaborated.
+pub fn set_minimize_proofs(b: bool) { MINIMIZE_PROOFS.store(b, Ordering::Relaxed) }
+
+static PROOF_SPANS: AtomicBool = AtomicBool::new(false);
+pub(crate) fn get_proof_spans() -> bool { PROOF_SPANS.load(Ordering::Relaxed) }
+
+/// Record the source spans of the nodes of each proof, which are needed to write the
+/// [source map](mmb::export::ExportOptions::source_map) of an MMB file.
+pub fn set_proof_spans(b: bool) { PROOF_SPANS.store(b, Ordering::Relaxed) }
+
+pub(crate) fn get_eval_limits() -> EvalLimits { *EVAL_LIMITS.ulock() }
+
+/// Set the initial limits of the lisp evaluator at the start of an MM1 file
+/// before a `(set-stack-limit)`, `(set-step-limit)` or `(set-alloc-limit)` command is found.
+pub fn set_eval_limits(limits: EvalLimits) { *EVAL_LIMITS.ulock() = limits }
+
+pub(crate) fn get_warning_config() -> WarningConfig { *WARNINGS.ulock() }
+
+/// Set the initial level of each warning code at the start of an MM1 file
+/// before a `(set-warning)` command is found.
+pub fn set_warning_config(config: WarningConfig) { *WARNINGS.ulock() = config }
+
+/// Get the directories which are searched for the files imported by the file at `base`:
+/// the scoped include paths (see [`set_scoped_include_paths`]) of the directories containing
+/// `base`, innermost first, followed by the include paths (see [`set_include_paths`]).
+pub(crate) fn include_paths(base: &Path) -> Vec<PathBuf> {
+  let mut scoped = SCOPED_INCLUDE_PATHS.ulock().iter()
+    .filter(|(dir, _)| base.starts_with(dir)).cloned().collect::<Vec<_>>();
+  scoped.sort_by_key(|(dir, _)| std::cmp::Reverse(dir.components().count()));
+  scoped.into_iter().flat_map(|(_, paths)| paths)
+    .chain(INCLUDE_PATHS.ulock().iter().cloned()).collect()
+}
+
+/// Get the path of the file `f` imported by the file at `base`. This is `f` relative to the
+/// directory containing `base` if that file exists, otherwise `f` relative to the first
+/// include path of `base` (see [`include_paths`]) where it exists.
+pub(crate) fn resolve_import(base: &Path, f: &str) -> PathBuf {
+  let path = base.parent().map_or_else(|| PathBuf::from(f), |p| p.join(f));
+  if path.exists() { return path }
+  include_paths(base).into_iter().map(|dir| dir.join(f)).find(|p| p.exists()).unwrap_or(path)
+}
+
+/// Get the include paths set by [`set_include_paths`].
+pub(crate) fn get_include_paths() -> Vec<PathBuf> { INCLUDE_PATHS.ulock().clone() }
+
+/// Set the directories which are searched for imported files which are not found
+/// relative to the importing file.
+pub fn set_include_paths(paths: Vec<PathBuf>) { *INCLUDE_PATHS.ulock() = paths }
+
+/// Set the directories which are searched for imported files in particular directories:
+/// the paths of an entry `(dir, paths)` are searched for the files in `dir` and its
+/// subdirectories, before the include paths set by [`set_include_paths`].
+/// The language server uses this for the projects of its workspace folders.
+pub fn set_scoped_include_paths(paths: Vec<(PathBuf, Vec<PathBuf>)>) {
+  *SCOPED_INCLUDE_PATHS.ulock() = paths
+}
@@ -1,5 +1,5 @@
 
-use clap::clap_app;
+use clap::{clap_app, value_t_or_exit};
 
 fn main() -> std::io::Result<()> {
   let app = clap_app!(mm0_rs =>
@@ -13,16 +13,95 @@ fn main() -> std::io::Result<()> {
     (@subcommand compile =>
       (about: "Compile MM1 files into MMB")
       (@arg no_proofs: -n --("no-proofs") "Disable proof checking until (check-proofs #t)")
+      (@arg cache: --cache [DIR] "Cache elaborated files in DIR, and reuse them while unchanged (not with --allow-io)")
+      (@arg stack_limit: --("stack-limit") [N] "Set the initial lisp stack limit (see set-stack-limit)")
+      (@arg step_limit: --("step-limit") [N] "Set the initial lisp step limit (see set-step-limit)")
+      (@arg alloc_limit: --("alloc-limit") [N] "Set the initial lisp allocation limit (see set-alloc-limit)")
+      (@arg allow_io: --("allow-io") "Allow lisp code to read and write files under the current directory")
+      (@arg allow_smt: --("allow-smt") "Allow lisp code to run the SMT solver 'z3 -in' (see smt)")
+      (@arg smt_cmd: --("smt-cmd") [CMD] "Allow lisp code to run the SMT solver with command line CMD (see smt)")
       (@arg quiet: -q --quiet "Hide diagnostic messages")
+      (@arg public: --public "Omit local defs and non-pub theorems from the MMB output")
+      (@arg stats: --stats "Print statistics about the size of the MMB output")
+      (@arg optimize: -O --optimize "Run the peephole optimizer on proof streams before writing the MMB")
+      (@arg minimize_proofs: --("minimize-proofs") "Shrink the proofs after elaboration, and report their size before and after")
+      (@arg source_map: --("source-map") "Include a map from proofs to source spans in the MMB index")
+      (@arg allow_sorry: --("allow-sorry") "Export admitted theorems (closed with sorry or an explicit _) to the MMB file")
+      (@arg warn_unused: --("warn-unused") "Warn about local defs and theorems that are never used")
+      (@arg include: -I --include [DIR] +multiple number_of_values(1) "Search DIR for imported files not found relative to the importing file")
+      (@arg allow: -A --allow [CODE] +multiple number_of_values(1) "Suppress the warnings with warning code CODE ('all' for every code)")
+      (@arg warn: -W --warn [CODE] +multiple number_of_values(1) "Report the warnings with warning code CODE (the default)")
+      (@arg deny: -D --deny [CODE] +multiple number_of_values(1) "Report the warnings with warning code CODE as errors")
+      (@arg watch: -w --watch "Compile again whenever the input file or a file it imports changes")
+      (@arg error_format: --("error-format") [FORMAT] possible_values(&["human", "json", "sarif"])
+         "Print diagnostics as annotated source (human), JSON objects, one per line (json), or a SARIF log (sarif)")
+      (@arg profile: --profile "Print the statements that took the longest to elaborate")
+      (@arg profile_json: --("profile-json") [FILE] "Write the elaboration time, proof size and lisp allocations of each statement to a JSON file")
+      (@arg mm0: --mm0 [FILE] "Also write the MM0 specification (axioms, public defs and theorem statements) to a file")
       (@arg output: -o --output [FILE] "Print 'output' commands to a file (use '-' to print to stdout)")
-      (@arg INPUT: +required "Sets the input file (.mm1 or .mm0)")
-      (@arg OUTPUT: "Sets the output file (.mmb or .mmu)"))
+      (@arg INPUT: +required "Sets the input file (.mm1 or .mm0), or - to read from stdin")
+      (@arg OUTPUT: "Sets the output file (.mmb, .mmu, .lean, .v or .dk), or - to write the .mmb file to stdout"))
+    (@subcommand build =>
+      (about: "Build a project described by an mm0.toml manifest")
+      (@arg manifest_path: --("manifest-path") [FILE] "Use the manifest FILE instead of searching for mm0.toml")
+      (@arg ROOT: ... "Build only these root files"))
+    (@subcommand fmt =>
+      (about: "Format MM0/MM1 files in place")
+      (@arg check: --check "Don't change the files, but list the files which are not formatted, and fail if there are any")
+      (@arg width: --width [N] "Break lines longer than N characters (default 100)")
+      (@arg INPUT: +required ... "Sets the files to format (.mm1 or .mm0)"))
     (@subcommand join =>
       (about: "Join MM1/MM0 files with imports by concatenation")
       (@arg no_header: -h --("no-header") "Skip top header")
       (@arg bare: -b --("bare") "Don't add any comments")
       (@arg INPUT: +required "Sets the input file (.mm1 or .mm0)")
       (@arg OUTPUT: "Sets the output file (.mm1 or .mm0), or stdin if omitted"))
+    (@subcommand link =>
+      (about: "Join several MMB files into one")
+      (@arg OUTPUT: -o --output <FILE> "Sets the output file (.mmb)")
+      (@arg INPUT: +required ... "Sets the input files (.mmb)"))
+    (@subcommand verify =>
+      (about: "Check the proofs in an MMB file, and optionally check it against a spec")
+      (@arg jobs: -j --jobs [N] "Check theorems in parallel on N threads, and report timing")
+      (@arg error_format: --("error-format") [FORMAT] possible_values(&["human", "json", "sarif"])
+         "Print diagnostics as annotated source (human), JSON objects, one per line (json), or a SARIF log (sarif)")
+      (@arg MMB: +required "Sets the input file (.mmb)")
+      (@arg MM0: "Sets the specification file (.mm0)"))
+    (@subcommand diff =>
+      (about: "Compare the declarations of two MMB files")
+      (@arg proofs: -p --proofs "Also report declarations whose proofs changed")
+      (@arg OLD: +required "Sets the old file (.mmb)")
+      (@arg NEW: +required "Sets the new file (.mmb)"))
+    (@subcommand dump =>
+      (about: "Dump the elaborated environment for external tools")
+      (@arg format: -f --format <FORMAT>
+         possible_values(&["json", "sexpr", "latex", "html"]) default_value("json")
+         "Output format")
+      (@arg INPUT: +required "Sets the input file (.mm1 or .mm0)")
+      (@arg OUTPUT: "Sets the output file, or stdout if omitted"))
+    (@subcommand graph =>
+      (about: "Write the dependency graph of terms and theorems")
+      (@arg format: -f --format <FORMAT>
+         possible_values(&["dot", "graphml"]) default_value("dot")
+         "Output format")
+      (@arg thms: --thms "Include only axioms and theorems")
+      (@arg prefix: --prefix [PREFIX] "Include only declarations whose names start with PREFIX")
+      (@arg root: --root [NAME] "Include only declarations used by NAME")
+      (@arg depth: --depth [N] requires("root") "Include only declarations at most N steps from the root")
+      (@arg INPUT: +required "Sets the input file (.mm1 or .mm0)")
+      (@arg OUTPUT: "Sets the output file, or stdout if omitted"))
+    (@subcommand axiom_use =>
+      (name: "axiom-use")
+      (about: "Report the axioms used by each theorem")
+      (@arg json: --json "Print the report as JSON")
+      (@arg expect: --expect [AXIOMS] "Report theorems using axioms other than AXIOMS (a comma separated list)")
+      (@arg INPUT: +required "Sets the input file (.mm1 or .mm0)"))
+    (@subcommand search =>
+      (about: "Find theorems whose statement matches a pattern")
+      (@arg unfold: -u --unfold [N] "Unfold up to N nested definitions to find a match (default 0)")
+      (@arg hyps: --hyps "Also find theorems with a hypothesis matching the pattern")
+      (@arg INPUT: +required "Sets the input file (.mm1 or .mm0)")
+      (@arg PATTERN: +required "The pattern, a math expression where _ matches anything"))
     (@subcommand doc =>
       (about: "Build documentation pages")
       (@arg INPUT: +required "Sets the input file (.mm1 or .mm0)")
@@ -33,11 +112,41 @@ fn main() -> std::io::Result<()> {
          "Proof tree traversal order")
       (@arg src: --src [URL] "Use URL as the base for source doc links (use - to disable)")));
 
+  #[cfg(feature = "repl")]
+  let app = clap_app!(@app (app)
+    (@subcommand repl =>
+      (about: "Interactive lisp REPL, optionally in the environment of an MM1 file")
+      (@arg no_proofs: -n --("no-proofs") "Disable proof checking until (check-proofs #t)")
+      (@arg stack_limit: --("stack-limit") [N] "Set the initial lisp stack limit (see set-stack-limit)")
+      (@arg step_limit: --("step-limit") [N] "Set the initial lisp step limit (see set-step-limit)")
+      (@arg alloc_limit: --("alloc-limit") [N] "Set the initial lisp allocation limit (see set-alloc-limit)")
+      (@arg allow_io: --("allow-io") "Allow lisp code to read and write files under the current directory")
+      (@arg allow_smt: --("allow-smt") "Allow lisp code to run the SMT solver 'z3 -in' (see smt)")
+      (@arg smt_cmd: --("smt-cmd") [CMD] "Allow lisp code to run the SMT solver with command line CMD (see smt)")
+      (@arg INPUT: "Sets the input file (.mm1 or .mm0) to elaborate before starting")));
+
+  #[cfg(feature = "dap")]
+  let app = clap_app!(@app (app)
+    (@subcommand dap =>
+      (about: "Debug adapter for lisp code in MM1 files, using the Debug Adapter Protocol")
+      (@arg port: -p --port [PORT] "Listen for the client on TCP port PORT (default 4711)")));
+
   #[cfg(feature = "server")]
   let app = clap_app!(@app (app)
     (@subcommand server =>
       (about: "MM1 LSP server")
       (@arg no_proofs: -n --("no-proofs") "Disable proof checking until (check-proofs #t)")
+      (@arg cache: --cache [DIR] "Cache elaborated files in DIR, and reuse them while unchanged (not with --allow-io)")
+      (@arg stack_limit: --("stack-limit") [N] "Set the initial lisp stack limit (see set-stack-limit)")
+      (@arg step_limit: --("step-limit") [N] "Set the initial lisp step limit (see set-step-limit)")
+      (@arg alloc_limit: --("alloc-limit") [N] "Set the initial lisp allocation limit (see set-alloc-limit)")
+      (@arg allow_io: --("allow-io") "Allow lisp code to read and write files under the current directory")
+      (@arg allow_smt: --("allow-smt") "Allow lisp code to run the SMT solver 'z3 -in' (see smt)")
+      (@arg smt_cmd: --("smt-cmd") [CMD] "Allow lisp code to run the SMT solver with command line CMD (see smt)")
+      (@arg include: -I --include [DIR] +multiple number_of_values(1) "Search DIR for imported files not found relative to the importing file")
+      (@arg allow: -A --allow [CODE] +multiple number_of_values(1) "Suppress the warnings with warning code CODE ('all' for every code)")
+      (@arg warn: -W --warn [CODE] +multiple number_of_values(1) "Report the warnings with warning code CODE (the default)")
+      (@arg deny: -D --deny [CODE] +multiple number_of_values(1) "Report the warnings with warning code CODE as errors")
       (@arg debug: -d --debug "Enable debug logging")
       (@arg no_log_errors: -q --quiet "Don't print errors in server output log")));
 
@@ -46,17 +155,116 @@ fn main() -> std::io::Result<()> {
   match m.subcommand() {
     ("compile", Some(m)) => {
       if m.is_present("no_proofs") { mm0_rs::set_check_proofs(false) }
+      if m.is_present("allow_io") { mm0_rs::set_allow_io(true) }
+      set_smt_cmd(m);
+      if m.is_present("minimize_proofs") { mm0_rs::set_minimize_proofs(true) }
+      if m.is_present("source_map") { mm0_rs::set_proof_spans(true) }
+      set_eval_limits(m);
+      set_warning_config(m);
+      set_include_paths(m);
+      set_error_format(m);
+      mm0_rs::cache::set_cache_dir(m.value_of_os("cache").map(Into::into));
       mm0_rs::compiler::main(m)?
     }
+    ("build", Some(m)) => mm0_rs::project::main(m)?,
+    ("fmt", Some(m)) => mm0_rs::formatter::main(m)?,
     ("join", Some(m)) => mm0_rs::joiner::main(m)?,
+    ("link", Some(m)) => mm0_rs::mmb::join::main(m)?,
+    ("verify", Some(m)) => {
+      set_error_format(m);
+      mm0_rs::mmb::verify::main(m)?
+    }
+    ("diff", Some(m)) => mm0_rs::mmb::diff::main(m)?,
+    ("dump", Some(m)) => mm0_rs::export::dump::main(m)?,
+    ("graph", Some(m)) => mm0_rs::export::graph::main(m)?,
+    ("axiom-use", Some(m)) => mm0_rs::axiom_use::main(m)?,
+    ("search", Some(m)) => mm0_rs::search::main(m)?,
     #[cfg(feature = "doc")]
     ("doc", Some(m)) => mm0_rs::doc::main(m)?,
+    #[cfg(feature = "repl")]
+    ("repl", Some(m)) => {
+      if m.is_present("no_proofs") { mm0_rs::set_check_proofs(false) }
+      if m.is_present("allow_io") { mm0_rs::set_allow_io(true) }
+      set_smt_cmd(m);
+      set_eval_limits(m);
+      mm0_rs::repl::main(m)?
+    }
+    #[cfg(feature = "dap")]
+    ("dap", Some(m)) => mm0_rs::dap::main(m)?,
     #[cfg(feature = "server")]
     ("server", Some(m)) => {
       if m.is_present("no_proofs") { mm0_rs::set_check_proofs(false) }
+      if m.is_present("allow_io") { mm0_rs::set_allow_io(true) }
+      set_smt_cmd(m);
+      set_eval_limits(m);
+      set_warning_config(m);
+      set_include_paths(m);
+      mm0_rs::cache::set_cache_dir(m.value_of_os("cache").map(Into::into));
       mm0_rs::server::main(m)
     }
     _ => unreachable!()
   }
   Ok(())
 }
+
+/// Set the initial lisp evaluator limits from the `--stack-limit`, `--step-limit` and
+/// `--alloc-limit` options. A step or allocation limit of 0 means no limit.
+/// Set the SMT solver command from the `--allow-smt` and `--smt-cmd` options.
+fn set_smt_cmd(m: &clap::ArgMatches<'_>) {
+  if let Some(cmd) = m.value_of("smt_cmd") {
+    mm0_rs::set_smt_cmd(Some(cmd))
+  } else if m.is_present("allow_smt") {
+    mm0_rs::set_smt_cmd(Some(mm0_rs::DEFAULT_SMT_CMD))
+  }
+}
+
+fn set_eval_limits(m: &clap::ArgMatches<'_>) {
+  let mut limits = mm0_rs::EvalLimits::default();
+  if m.is_present("stack_limit") {
+    limits.stack = value_t_or_exit!(m, "stack_limit", usize)
+  }
+  if m.is_present("step_limit") {
+    limits.steps = Some(value_t_or_exit!(m, "step_limit", u64)).filter(|&n| n != 0)
+  }
+  if m.is_present("alloc_limit") {
+    limits.allocs = Some(value_t_or_exit!(m, "alloc_limit", usize)).filter(|&n| n != 0)
+  }
+  mm0_rs::set_eval_limits(limits)
+}
+
+/// Set the initial level of each warning code from the `--allow`, `--warn` and `--deny`
+/// options. Later options override earlier ones, so `-A all -W deprecated` allows every
+/// warning except `deprecated`.
+fn set_warning_config(m: &clap::ArgMatches<'_>) {
+  use mm0_rs::{WarningCode, WarningLevel};
+  let mut opts = vec![];
+  for (name, lvl) in [("allow", WarningLevel::Allow), ("warn", WarningLevel::Warn), ("deny", WarningLevel::Deny)] {
+    if let (Some(idxs), Some(codes)) = (m.indices_of(name), m.values_of(name)) {
+      opts.extend(idxs.zip(codes).map(|(i, code)| (i, code, lvl)))
+    }
+  }
+  opts.sort_by_key(|&(i, _, _)| i);
+  let mut config = mm0_rs::WarningConfig::default();
+  for (_, code, lvl) in opts {
+    let code = if code == "all" {None} else {
+      Some(WarningCode::from_name(code).unwrap_or_else(|| clap::Error::with_description(
+        &format!("unknown warning code '{}'", code), clap::ErrorKind::InvalidValue).exit()))
+    };
+    config.set(code, lvl)
+  }
+  mm0_rs::set_warning_config(config)
+}
+
+/// Set the directories searched for imported files from the `--include` options.
+fn set_include_paths(m: &clap::ArgMatches<'_>) {
+  if let Some(dirs) = m.values_of_os("include") {
+    mm0_rs::set_include_paths(dirs.map(Into::into).collect())
+  }
+}
+
+/// Set the format of the diagnostics from the `--error-format` option.
+fn set_error_format(m: &clap::ArgMatches<'_>) {
+  if let Some(fmt) = m.value_of("error_format").and_then(mm0_rs::diagnostics::ErrorFormat::from_name) {
+    mm0_rs::diagnostics::set_error_format(fmt)
+  }
+}
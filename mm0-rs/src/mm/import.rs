@@ -0,0 +1,809 @@
+//! Metamath importer, which produces an [`Environment`] object from a `.mm` database.
+//!
+//! Statements are translated as follows:
+//!
+//! * Each typecode used by a `$f` hypothesis or a syntax axiom becomes a sort, according to
+//!   the [`TypecodeMap`]. Variables of a [`Bound`](Typecode::Bound) typecode become bound
+//!   variables, and all other variables are regular variables.
+//! * A `$a` statement whose typecode is not [`Provable`](Typecode::Provable) is a syntax
+//!   axiom, and becomes a `term`. Its statement is also added to the grammar which is used
+//!   to parse all later statements into expressions.
+//! * A `$a` statement with a provable typecode becomes an `axiom`, and a `$p` statement with
+//!   a provable typecode becomes a `pub theorem`. The `$e` hypotheses become hypotheses, and
+//!   a regular variable depends on every bound variable it is not disjoint from (by `$d`).
+//! * A `$p` statement with a typecode that is not provable (a syntax theorem) does not
+//!   produce a declaration. It is unfolded in the proofs that use it.
+//!
+//! Labels and variable names are turned into MM0 identifiers by replacing characters other
+//! than `[a-zA-Z0-9_]` with `_`. If this makes two names the same, the later one gets a
+//! suffix `_1`, `_2`, etc. so that it is distinct. Proofs are checked as they are translated, except for
+//! disjoint variable conditions, which are left to the verifier of the exported file.
+//! Incomplete proofs (containing `?`) are imported as `sorry`.
+//! File inclusion (`$[ $]`) is not supported.
+use std::rc::Rc;
+use std::collections::{HashMap, HashSet};
+use crate::{Term, Thm, TermKind, ThmKind, TermId, ThmId, SortId,
+  AtomId, Environment, Modifiers, Type, Proof, MAX_BOUND_VARS, Span, FileRef, FileSpan, DocComment};
+use crate::elab::{ElabError, Result,
+  proof::{Dedup, IDedup, ExprHash, ProofHash, build}};
+
+/// How a Metamath typecode is translated to MM0.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Typecode {
+  /// Expressions of this typecode are in the given sort, and its variables are
+  /// regular variables.
+  Sort(String),
+  /// Expressions of this typecode are in the given sort, and its variables are
+  /// bound variables.
+  Bound(String),
+  /// This is the typecode of assertions, like `|-`. The rest of the statement is parsed as
+  /// an expression of the given typecode, whose sort is marked `provable`.
+  Provable(String),
+}
+
+/// The mapping from Metamath typecodes to MM0 sorts used by the importer.
+/// Typecodes that are not in the map become sorts of the same name.
+#[derive(Clone, Debug)]
+pub struct TypecodeMap(HashMap<Box<[u8]>, Typecode>);
+
+impl Default for TypecodeMap {
+  /// The conventions of `set.mm`: `|-` is the provable typecode, whose statements are `wff`s,
+  /// and `setvar` variables are bound variables.
+  fn default() -> Self { Self::new().provable("|-", "wff").bound("setvar", "setvar") }
+}
+
+impl TypecodeMap {
+  /// A mapping in which every typecode is a sort of the same name, and nothing is provable.
+  #[must_use] pub fn new() -> Self { Self(HashMap::new()) }
+
+  /// Translate typecode `tc` to the sort `sort`.
+  #[must_use] pub fn sort(mut self, tc: &str, sort: &str) -> Self {
+    self.0.insert(tc.as_bytes().into(), Typecode::Sort(sort.into()));
+    self
+  }
+
+  /// Translate typecode `tc` to the sort `sort`, with bound variables.
+  #[must_use] pub fn bound(mut self, tc: &str, sort: &str) -> Self {
+    self.0.insert(tc.as_bytes().into(), Typecode::Bound(sort.into()));
+    self
+  }
+
+  /// Make `tc` a provable typecode, whose statements are expressions of typecode `expr`.
+  #[must_use] pub fn provable(mut self, tc: &str, expr: &str) -> Self {
+    self.0.insert(tc.as_bytes().into(), Typecode::Provable(expr.into()));
+    self
+  }
+}
+
+/// Convert a Metamath label or variable name into an MM0 identifier.
+fn mangle(s: &[u8]) -> Vec<u8> {
+  let mut out = Vec::with_capacity(s.len() + 1);
+  if s.first().map_or(true, u8::is_ascii_digit) { out.push(b'_') }
+  out.extend(s.iter().map(|&c| if c.is_ascii_alphanumeric() {c} else {b'_'}));
+  out
+}
+
+fn whitespace(c: u8) -> bool { matches!(c, b' ' | b'\t' | b'\n' | b'\r' | b'\x0c') }
+
+fn lossy(s: &[u8]) -> std::borrow::Cow<'_, str> { String::from_utf8_lossy(s) }
+
+/// A `$f` or `$e` hypothesis.
+enum Hyp<'a> {
+  /// `label $f tc x $.`
+  Float { var: &'a [u8], tc: &'a [u8] },
+  /// `label $e tc syms $.`
+  Ess { tc: &'a [u8], syms: Box<[&'a [u8]]> },
+}
+
+struct HypData<'a> {
+  label: &'a [u8],
+  /// True until the end of the enclosing scope
+  active: bool,
+  kind: Hyp<'a>,
+}
+
+/// A mandatory hypothesis of an assertion, in Metamath order.
+#[derive(Clone, Copy)]
+enum FrameHyp<'a> {
+  /// A `$f` hypothesis for the argument with the given index and typecode
+  Float(usize, &'a [u8]),
+  /// A `$e` hypothesis
+  Ess,
+}
+
+/// The information needed to apply an assertion in a proof.
+struct Frame<'a> {
+  /// The typecode of the statement
+  tc: &'a [u8],
+  /// The number of `$f` hypotheses
+  nargs: usize,
+  hyps: Box<[FrameHyp<'a>]>,
+}
+
+/// A parsed expression, where `Var(i)` is argument `i`.
+enum Tree {
+  Var(usize),
+  App(TermId, Box<[Rc<Tree>]>),
+}
+
+#[derive(Clone)]
+enum Label<'a> {
+  /// A hypothesis, as an index into [`Importer::hyps`]
+  Hyp(usize),
+  /// A syntax axiom
+  Term(TermId, Rc<Frame<'a>>),
+  /// A syntax theorem, which is unfolded to its statement
+  Syntax(Rc<Frame<'a>>, Rc<Tree>),
+  /// An axiom or theorem
+  Thm(ThmId, Rc<Frame<'a>>),
+}
+
+#[derive(Clone, Copy)]
+enum Sym<'a> {
+  Const(&'a [u8]),
+  /// A variable with the given typecode, which is the argument with the given index
+  Var(&'a [u8], usize),
+}
+
+/// A grammar rule, derived from a syntax axiom.
+struct Rule<'a> {
+  term: TermId,
+  nargs: usize,
+  pat: Box<[Sym<'a>]>,
+}
+
+/// The binders of an assertion, computed from its mandatory hypotheses.
+struct Binders<'a> {
+  args: Vec<(Option<AtomId>, Type)>,
+  /// The typecode and argument index of each mandatory variable
+  vars: HashMap<&'a [u8], (&'a [u8], usize)>,
+  /// The mandatory hypotheses, as indexes into [`Importer::hyps`]
+  mand: Vec<usize>,
+  /// The `$e` hypotheses, as indexes into [`Importer::hyps`]
+  ess: Vec<usize>,
+  /// The set of all bound variables
+  bound: u64,
+  frame: Frame<'a>,
+}
+
+/// A proof stack element.
+#[derive(Clone, Copy)]
+enum El<'a> {
+  /// An expression with the given typecode
+  Expr(usize, &'a [u8]),
+  /// A proof of the given expression
+  Proof(usize, usize),
+}
+
+impl El<'_> {
+  fn reuse(self, de: &mut Dedup<ProofHash>) -> Self {
+    match self { El::Expr(e, _) => de.reuse(e), El::Proof(p, _) => de.reuse(p) };
+    self
+  }
+}
+
+/// The end positions and parses of a typecode starting at some position.
+type Parses = Rc<[(usize, Rc<Tree>)]>;
+
+/// An expression parser for the grammar of syntax axioms. This is a backtracking parser
+/// which remembers every way to parse each typecode at each position, so it does not need
+/// the grammar to be unambiguous; the first parse found is used.
+struct Parser<'a, 'b> {
+  rules: &'b HashMap<&'a [u8], Vec<Rule<'a>>>,
+  vars: &'b HashMap<&'a [u8], (&'a [u8], usize)>,
+  toks: &'b [&'a [u8]],
+  /// The parses of each typecode starting at each position
+  memo: HashMap<(&'a [u8], usize), Parses>,
+}
+
+impl<'a> Parser<'a, '_> {
+  fn parse(&mut self, tc: &'a [u8], pos: usize) -> Parses {
+    if let Some(r) = self.memo.get(&(tc, pos)) { return r.clone() }
+    // Left recursive rules find nothing at the position they start from
+    self.memo.insert((tc, pos), Rc::new([]));
+    let mut out: Vec<(usize, Rc<Tree>)> = vec![];
+    if let Some(&(vtc, i)) = self.toks.get(pos).and_then(|x| self.vars.get(x)) {
+      if vtc == tc { out.push((pos + 1, Rc::new(Tree::Var(i)))) }
+    }
+    let rules = self.rules;
+    for rule in rules.get(tc).map_or(&[][..], |v| &**v) {
+      let mut states = vec![(pos, vec![None; rule.nargs])];
+      for &sym in &*rule.pat {
+        let mut next = vec![];
+        for (p, args) in states {
+          match sym {
+            Sym::Const(c) => if self.toks.get(p) == Some(&c) { next.push((p + 1, args)) }
+            Sym::Var(tc2, i) => for (q, t) in self.parse(tc2, p).iter() {
+              if next.iter().all(|s: &(usize, _)| s.0 != *q) {
+                let mut args = args.clone();
+                args[i] = Some(t.clone());
+                next.push((*q, args))
+              }
+            }
+          }
+        }
+        states = next;
+      }
+      for (p, args) in states {
+        if out.iter().all(|o| o.0 != p) {
+          let args = args.into_iter().map(|a| a.expect("every argument occurs in the pattern"));
+          out.push((p, Rc::new(Tree::App(rule.term, args.collect()))))
+        }
+      }
+    }
+    let out: Parses = out.into();
+    self.memo.insert((tc, pos), out.clone());
+    out
+  }
+}
+
+fn expr_hash(de: &mut Dedup<ExprHash>, t: &Tree) -> usize {
+  match t {
+    &Tree::Var(i) => de.reuse(i),
+    Tree::App(t, args) => {
+      let args = args.iter().map(|a| expr_hash(de, a)).collect();
+      de.add_direct(ExprHash::App(*t, args))
+    }
+  }
+}
+
+fn subst_tree(de: &mut Dedup<ProofHash>, t: &Tree, args: &[usize]) -> usize {
+  match t {
+    &Tree::Var(i) => de.reuse(args[i]),
+    Tree::App(t, es) => {
+      let es = es.iter().map(|e| subst_tree(de, e, args)).collect();
+      de.add_direct(ProofHash::Term(*t, es))
+    }
+  }
+}
+
+/// Pop the arguments of an application of `fr` off the stack, returning the expressions
+/// for the `$f` hypotheses in argument order, and the proofs of the `$e` hypotheses.
+#[allow(clippy::type_complexity)]
+fn pop_args<'a>(sp: Span, stack: &mut Vec<El<'a>>, fr: &Frame<'a>) ->
+    Result<(Vec<usize>, Vec<(usize, usize)>)> {
+  let mid = stack.len().checked_sub(fr.hyps.len())
+    .ok_or_else(|| ElabError::new_e(sp, "proof stack underflow"))?;
+  let mut args = vec![0; fr.nargs];
+  let mut pfs = vec![];
+  for (&h, el) in fr.hyps.iter().zip(stack.drain(mid..)) {
+    match (h, el) {
+      (FrameHyp::Float(i, tc), El::Expr(e, tc2)) if tc == tc2 => args[i] = e,
+      (FrameHyp::Ess, El::Proof(p, e)) => pfs.push((p, e)),
+      _ => return Err(ElabError::new_e(sp, "type mismatch in proof step"))
+    }
+  }
+  Ok((args, pfs))
+}
+
+/// A `${ $}` block.
+struct Scope<'a> {
+  /// The length of [`Importer::active`] at the start of the block
+  active: usize,
+  /// The length of [`Importer::dvs`] at the start of the block
+  dvs: usize,
+  /// The variables declared in the block
+  vars: Vec<&'a [u8]>,
+}
+
+/// The importer, which reads the input `.mm` file and builds an [`Environment`].
+struct Importer<'a> {
+  /// The input file name
+  file: &'a FileRef,
+  /// The input source text (as a byte slice)
+  source: &'a [u8],
+  /// The typecode mapping
+  tcs: &'a TypecodeMap,
+  /// The position in the input
+  idx: usize,
+  /// The last comment, which is the doc comment of the next statement
+  doc: Option<DocComment>,
+  consts: HashSet<&'a [u8]>,
+  /// The active variables
+  vars: HashSet<&'a [u8]>,
+  /// The active `$f` hypothesis for each variable
+  floats: HashMap<&'a [u8], usize>,
+  hyps: Vec<HypData<'a>>,
+  /// The active hypotheses, in order
+  active: Vec<usize>,
+  /// The active disjoint variable pairs
+  dvs: Vec<(&'a [u8], &'a [u8])>,
+  scopes: Vec<Scope<'a>>,
+  labels: HashMap<&'a [u8], Label<'a>>,
+  /// The sort of each typecode, and whether its variables are bound
+  sorts: HashMap<&'a [u8], (SortId, bool)>,
+  /// The grammar rules for each typecode
+  rules: HashMap<&'a [u8], Vec<Rule<'a>>>,
+  /// The MM0 identifier of each label, variable and sort name, see [`Importer::name`]
+  names: HashMap<&'a [u8], AtomId>,
+  /// The identifiers used in `names`
+  used: HashSet<AtomId>,
+  /// The environment under construction
+  env: Environment,
+}
+
+impl<'a> Importer<'a> {
+  fn span(&self, s: Span) -> &'a [u8] { &self.source[s.start..s.end] }
+
+  /// Get the MM0 identifier for the label, variable or sort name `s`, given by [`mangle`].
+  /// If another name already has this identifier, a suffix is added to keep them distinct.
+  fn name(&mut self, s: &'a [u8]) -> AtomId {
+    if let Some(&a) = self.names.get(s) { return a }
+    let base = mangle(s);
+    let (mut a, mut n) = (self.env.get_atom(&base), 0);
+    while !self.used.insert(a) {
+      n += 1;
+      a = self.env.get_atom(&[&*base, format!("_{}", n).as_bytes()].concat());
+    }
+    self.names.insert(s, a);
+    a
+  }
+
+  fn fspan(&self, s: Span) -> FileSpan {
+    FileSpan {file: self.file.clone(), span: s}
+  }
+
+  /// Read the next token, not skipping comments.
+  fn raw_tok(&mut self) -> Option<Span> {
+    while self.idx < self.source.len() && whitespace(self.source[self.idx]) { self.idx += 1 }
+    let start = self.idx;
+    while self.idx < self.source.len() && !whitespace(self.source[self.idx]) { self.idx += 1 }
+    if self.idx == start {None} else {Some((start..self.idx).into())}
+  }
+
+  /// Read the next token, skipping comments.
+  fn tok(&mut self) -> Result<Option<Span>> {
+    loop {
+      let sp = if let Some(sp) = self.raw_tok() {sp} else {return Ok(None)};
+      if self.span(sp) != b"$(" { return Ok(Some(sp)) }
+      loop {
+        let t = self.raw_tok().ok_or_else(|| ElabError::new_e(sp, "unclosed comment"))?;
+        if self.span(t) == b"$)" {
+          self.doc = Some(lossy(&self.source[sp.end..t.start]).trim().into());
+          break
+        }
+      }
+    }
+  }
+
+  /// Read the tokens of a statement up to the keyword `end`, returning the tokens
+  /// and the position after `end`.
+  fn stmt(&mut self, kw: Span, end: &[u8]) -> Result<(Vec<&'a [u8]>, usize)> {
+    let mut toks = vec![];
+    loop {
+      let t = self.tok()?.ok_or_else(|| ElabError::new_e(kw, "unterminated statement"))?;
+      let s = self.span(t);
+      if s == end { return Ok((toks, t.end)) }
+      if s.starts_with(b"$") {
+        return Err(ElabError::new_e(t, format!("unexpected keyword '{}'", lossy(s))))
+      }
+      toks.push(s)
+    }
+  }
+
+  fn run(&mut self) -> Result<()> {
+    while let Some(sp) = self.tok()? {
+      match self.span(sp) {
+        b"$c" => for c in self.stmt(sp, b"$.")?.0 {
+          if self.vars.contains(c) || !self.consts.insert(c) {
+            return Err(ElabError::new_e(sp, format!("'{}' is already declared", lossy(c))))
+          }
+        }
+        b"$v" => for v in self.stmt(sp, b"$.")?.0 {
+          if self.consts.contains(v) || !self.vars.insert(v) {
+            return Err(ElabError::new_e(sp, format!("'{}' is already declared", lossy(v))))
+          }
+          if let Some(sc) = self.scopes.last_mut() { sc.vars.push(v) }
+        }
+        b"$d" => {
+          let vs = self.stmt(sp, b"$.")?.0;
+          if let Some(v) = vs.iter().find(|v| !self.vars.contains(*v)) {
+            return Err(ElabError::new_e(sp, format!("'{}' is not an active variable", lossy(v))))
+          }
+          for (i, &x) in vs.iter().enumerate() {
+            for &y in &vs[i+1..] { self.dvs.push((x, y)) }
+          }
+        }
+        b"${" => self.scopes.push(Scope {active: self.active.len(), dvs: self.dvs.len(), vars: vec![]}),
+        b"$}" => {
+          let sc = self.scopes.pop().ok_or_else(|| ElabError::new_e(sp, "unmatched '$}'"))?;
+          for h in self.active.drain(sc.active..) {
+            let hd = &mut self.hyps[h];
+            hd.active = false;
+            if let Hyp::Float {var, ..} = hd.kind { self.floats.remove(var); }
+          }
+          self.dvs.truncate(sc.dvs);
+          for v in sc.vars { self.vars.remove(v); }
+        }
+        b"$[" => return Err(ElabError::new_e(sp, "file inclusion is not supported")),
+        s if s.starts_with(b"$") =>
+          return Err(ElabError::new_e(sp, format!("unexpected keyword '{}'", lossy(s)))),
+        _ => self.labeled(sp)?,
+      }
+      self.doc = None;
+    }
+    if !self.scopes.is_empty() {
+      return Err(ElabError::new_e(self.idx..self.idx, "unclosed '${'"))
+    }
+    Ok(())
+  }
+
+  fn labeled(&mut self, label: Span) -> Result<()> {
+    let lbl = self.span(label);
+    if self.labels.contains_key(lbl) {
+      return Err(ElabError::new_e(label, format!("duplicate label '{}'", lossy(lbl))))
+    }
+    let doc = self.doc.take();
+    let kw = self.tok()?.ok_or_else(|| ElabError::new_e(label, "expecting keyword"))?;
+    let kw_s = self.span(kw);
+    let (toks, end) = self.stmt(kw, if kw_s == b"$p" {b"$="} else {b"$."})?;
+    let (&tc, syms) = toks.split_first()
+      .ok_or_else(|| ElabError::new_e(kw, "expecting typecode"))?;
+    match kw_s {
+      b"$f" => {
+        let var = match *syms { [v] if self.vars.contains(v) => v,
+          _ => return Err(ElabError::new_e(kw, "expecting a variable"))
+        };
+        self.sort(tc, kw)?;
+        let h = self.hyps.len();
+        if self.floats.insert(var, h).is_some() {
+          return Err(ElabError::new_e(kw, format!("variable '{}' already has a type", lossy(var))))
+        }
+        self.hyps.push(HypData {label: lbl, active: true, kind: Hyp::Float {var, tc}});
+        self.active.push(h);
+        self.labels.insert(lbl, Label::Hyp(h));
+      }
+      b"$e" => {
+        self.check_syms(kw, syms)?;
+        let h = self.hyps.len();
+        self.hyps.push(HypData {label: lbl, active: true, kind: Hyp::Ess {tc, syms: syms.into()}});
+        self.active.push(h);
+        self.labels.insert(lbl, Label::Hyp(h));
+      }
+      b"$a" | b"$p" => {
+        let syms = syms.to_vec();
+        let (proof, end) = if kw_s == b"$p" {
+          let (pf, end) = self.stmt(kw, b"$.")?;
+          (Some(pf), end)
+        } else { (None, end) };
+        let full = (label.start..end).into();
+        let tcs = self.tcs;
+        match tcs.0.get(tc) {
+          Some(Typecode::Provable(etc)) =>
+            self.assertion(label, full, doc, etc.as_bytes(), &syms, proof.as_deref())?,
+          _ if proof.is_some() => self.syntax_thm(label, tc, &syms)?,
+          _ => self.syntax_axiom(label, full, doc, tc, &syms)?,
+        }
+      }
+      _ => return Err(ElabError::new_e(kw, format!("unexpected keyword '{}'", lossy(kw_s))))
+    }
+    Ok(())
+  }
+
+  /// Get the sort for typecode `tc`, adding it to the environment if this is the first use.
+  fn sort(&mut self, tc: &'a [u8], sp: Span) -> Result<(SortId, bool)> {
+    if let Some(&r) = self.sorts.get(tc) { return Ok(r) }
+    if !self.consts.contains(tc) {
+      return Err(ElabError::new_e(sp, format!("typecode '{}' is not a constant", lossy(tc))))
+    }
+    let tcs = self.tcs;
+    let (name, bound) = match tcs.0.get(tc) {
+      Some(Typecode::Provable(_)) => return Err(ElabError::new_e(sp,
+        format!("typecode '{}' is provable, and cannot be used for expressions", lossy(tc)))),
+      Some(Typecode::Sort(s)) => (s.as_bytes(), false),
+      Some(Typecode::Bound(s)) => (s.as_bytes(), true),
+      None => (tc, false),
+    };
+    let mods = if tcs.0.values().any(|t| matches!(t, Typecode::Provable(e) if e.as_bytes() == tc)) {
+      Modifiers::PROVABLE
+    } else { Modifiers::empty() };
+    let a = self.name(name);
+    let s = self.env.add_sort(a, self.fspan(sp), sp, mods, None)
+      .map_err(|e| e.into_elab_error(sp))?;
+    self.sorts.insert(tc, (s, bound));
+    Ok((s, bound))
+  }
+
+  /// Check that every symbol is a constant or a variable with a type.
+  fn check_syms(&self, sp: Span, syms: &[&'a [u8]]) -> Result<()> {
+    for &s in syms {
+      if self.vars.contains(s) {
+        if !self.floats.contains_key(s) {
+          return Err(ElabError::new_e(sp, format!("variable '{}' has no type", lossy(s))))
+        }
+      } else if !self.consts.contains(s) {
+        return Err(ElabError::new_e(sp, format!("unknown symbol '{}'", lossy(s))))
+      }
+    }
+    Ok(())
+  }
+
+  /// The mandatory hypotheses of an assertion with statement `syms`: the active `$e`
+  /// hypotheses, and the `$f` hypotheses of the variables in them and in `syms`.
+  fn mandatory(&self, sp: Span, syms: &[&'a [u8]]) -> Result<Vec<usize>> {
+    self.check_syms(sp, syms)?;
+    let mut used = HashSet::new();
+    let mut mark = |syms: &[&'a [u8]]| for &s in syms {
+      if self.vars.contains(s) { used.insert(s); }
+    };
+    for &h in &self.active {
+      if let Hyp::Ess {syms, ..} = &self.hyps[h].kind { mark(syms) }
+    }
+    mark(syms);
+    Ok(self.active.iter().copied().filter(|&h| match self.hyps[h].kind {
+      Hyp::Float {var, ..} => used.contains(var),
+      Hyp::Ess {..} => true,
+    }).collect())
+  }
+
+  /// Compute the binders of an assertion with mandatory hypotheses `mand`.
+  /// Bound variables come first, since the dependencies of a regular variable can only
+  /// refer to earlier bound variables. Regular variables depend on all bound variables in a
+  /// `term`, and otherwise on all bound variables they are not disjoint from.
+  fn binders(&mut self, sp: Span, mand: Vec<usize>, tc: &'a [u8], term: bool) -> Result<Binders<'a>> {
+    let mut floats = vec![];
+    let mut ess = vec![];
+    for &h in &mand {
+      match self.hyps[h].kind {
+        Hyp::Float {var, tc} => floats.push((var, tc)),
+        Hyp::Ess {..} => ess.push(h),
+      }
+    }
+    let mut sorts = vec![];
+    for &(_, tc) in &floats { sorts.push(self.sort(tc, sp)?) }
+    let order = (0..floats.len()).filter(|&k| sorts[k].1)
+      .chain((0..floats.len()).filter(|&k| !sorts[k].1)).collect::<Vec<_>>();
+    let nbound = sorts.iter().filter(|s| s.1).count();
+    if nbound > MAX_BOUND_VARS {
+      return Err(ElabError::new_e(sp, format!("too many bound variables (max {})", MAX_BOUND_VARS)))
+    }
+    let dv = if term {HashSet::new()} else {
+      self.dvs.iter().flat_map(|&(x, y)| [(x, y), (y, x)]).collect()
+    };
+    let mut args = Vec::with_capacity(floats.len());
+    let mut vars = HashMap::new();
+    let mut arg_of = vec![0; floats.len()];
+    for (i, &k) in order.iter().enumerate() {
+      let (var, tc) = floats[k];
+      let (s, bound) = sorts[k];
+      arg_of[k] = i;
+      vars.insert(var, (tc, i));
+      let ty = if bound { Type::Bound(s) } else {
+        Type::Reg(s, (0..nbound).filter(|&j| !dv.contains(&(floats[order[j]].0, var)))
+          .fold(0, |deps, j| deps | 1 << j))
+      };
+      args.push((Some(self.name(var)), ty))
+    }
+    let mut floats = arg_of.into_iter().zip(floats);
+    let hyps = mand.iter().map(|&h| match self.hyps[h].kind {
+      Hyp::Float {..} => {
+        let (i, (_, tc)) = floats.next().expect("impossible");
+        FrameHyp::Float(i, tc)
+      }
+      Hyp::Ess {..} => FrameHyp::Ess,
+    }).collect();
+    Ok(Binders {
+      frame: Frame {tc, nargs: args.len(), hyps},
+      bound: (1 << nbound) - 1,
+      args, vars, mand, ess,
+    })
+  }
+
+  /// Parse `syms` as an expression of typecode `tc`.
+  fn parse(&self, sp: Span, b: &Binders<'a>, tc: &'a [u8], syms: &[&'a [u8]]) -> Result<Rc<Tree>> {
+    let mut p = Parser {rules: &self.rules, vars: &b.vars, toks: syms, memo: HashMap::new()};
+    p.parse(tc, 0).iter().find(|r| r.0 == syms.len()).map(|r| r.1.clone())
+      .ok_or_else(|| ElabError::new_e(sp, format!("cannot parse statement as '{}'", lossy(tc))))
+  }
+
+  /// Add a syntax axiom as a `term`, and add it to the grammar.
+  fn syntax_axiom(&mut self, label: Span, full: Span, doc: Option<DocComment>,
+      tc: &'a [u8], syms: &[&'a [u8]]) -> Result<()> {
+    let mand = self.mandatory(label, syms)?;
+    let b = self.binders(label, mand, tc, true)?;
+    if !b.ess.is_empty() {
+      return Err(ElabError::new_e(label, "syntax axioms cannot have hypotheses"))
+    }
+    let (s, _) = self.sort(tc, label)?;
+    let pat = syms.iter().map(|&x| match b.vars.get(x) {
+      Some(&(tc, i)) => Sym::Var(tc, i),
+      None => Sym::Const(x),
+    }).collect();
+    let lbl = self.span(label);
+    let atom = self.name(lbl);
+    let t = self.env.add_term(Term {
+      atom,
+      span: self.fspan(label),
+      vis: Modifiers::empty(),
+      full, doc,
+      args: b.args.into(),
+      ret: (s, b.bound),
+      kind: TermKind::Term,
+    }).map_err(|e| e.into_elab_error(label))?;
+    self.rules.entry(tc).or_default().push(Rule {term: t, nargs: b.frame.nargs, pat});
+    self.labels.insert(lbl, Label::Term(t, Rc::new(b.frame)));
+    Ok(())
+  }
+
+  /// Record a syntax theorem, which is unfolded in the proofs that use it.
+  fn syntax_thm(&mut self, label: Span, tc: &'a [u8], syms: &[&'a [u8]]) -> Result<()> {
+    let mand = self.mandatory(label, syms)?;
+    let b = self.binders(label, mand, tc, true)?;
+    if !b.ess.is_empty() {
+      return Err(ElabError::new_e(label, "syntax theorems cannot have hypotheses"))
+    }
+    let t = self.parse(label, &b, tc, syms)?;
+    self.labels.insert(self.span(label), Label::Syntax(Rc::new(b.frame), t));
+    Ok(())
+  }
+
+  /// Add an axiom, or a theorem if `proof` is provided. `tc` is the typecode of the
+  /// expressions in the statement and hypotheses.
+  fn assertion(&mut self, label: Span, full: Span, doc: Option<DocComment>,
+      tc: &'a [u8], syms: &[&'a [u8]], proof: Option<&[&'a [u8]]>) -> Result<()> {
+    let tcs = self.tcs;
+    let mand = self.mandatory(label, syms)?;
+    let b = self.binders(label, mand, tc, false)?;
+    let mut de = Dedup::new(&b.args);
+    let mut is = vec![];
+    for &h in &b.ess {
+      let hd = &self.hyps[h];
+      let (htc, hsyms) = if let Hyp::Ess {tc, syms} = &hd.kind {(*tc, &**syms)} else {unreachable!()};
+      let etc = match tcs.0.get(htc) {
+        Some(Typecode::Provable(etc)) => etc.as_bytes(),
+        _ => return Err(ElabError::new_e(label, format!(
+          "hypothesis '{}' does not have a provable typecode", lossy(hd.label))))
+      };
+      is.push((hd.label, expr_hash(&mut de, &*self.parse(label, &b, etc, hsyms)?)))
+    }
+    let ir = expr_hash(&mut de, &*self.parse(label, &b, tc, syms)?);
+    let kind = if let Some(steps) = proof {
+      let mut de = de.map_proof();
+      let hyps = is.iter().enumerate()
+        .map(|(i, &(_, e))| (de.add_direct(ProofHash::Hyp(i, e)), e)).collect::<Vec<_>>();
+      match self.proof(label, &b, &mut de, &hyps, ir, steps)? {
+        None => ThmKind::Thm(None),
+        Some(ip) => {
+          let (mut ids, heap) = build(&de);
+          let hyps = hyps.iter().map(|&(i, _)| ids[i].take()).collect();
+          ThmKind::Thm(Some(Proof {heap, hyps, head: ids[ip].take(), spans: Box::new([])}))
+        }
+      }
+    } else { ThmKind::Axiom };
+    let (mut ids, heap) = build(&de);
+    let hyps = is.into_iter()
+      .map(|(a, i)| (Some(self.name(a)), ids[i].take())).collect();
+    let ret = ids[ir].take();
+    let lbl = self.span(label);
+    let atom = self.name(lbl);
+    let t = self.env.add_thm(Thm {
+      atom,
+      span: self.fspan(label),
+      vis: if proof.is_some() {Modifiers::PUB} else {Modifiers::empty()},
+      full, doc,
+      args: b.args.into(), heap, hyps, ret, kind
+    }).map_err(|e| e.into_elab_error(label))?;
+    self.labels.insert(lbl, Label::Thm(t, Rc::new(b.frame)));
+    Ok(())
+  }
+
+  /// Translate the proof `steps` of the assertion `label`, where `hyps` are the proofs and
+  /// statements of the hypotheses and `ret` is the statement. Returns `None` if the proof
+  /// is incomplete.
+  fn proof(&mut self, label: Span, b: &Binders<'a>, de: &mut Dedup<ProofHash>,
+      hyps: &[(usize, usize)], ret: usize, steps: &[&'a [u8]]) -> Result<Option<usize>> {
+    let mut stack = vec![];
+    if steps.first() == Some(&&b"("[..]) {
+      let close = steps.iter().position(|&s| s == b")")
+        .ok_or_else(|| ElabError::new_e(label, "unclosed label list in compressed proof"))?;
+      let labels = &steps[1..close];
+      let mand = b.mand.iter().map(|&h| self.hyps[h].label).collect::<Vec<_>>();
+      let mut saved = vec![];
+      let mut n = 0_usize;
+      for &c in steps[close+1..].iter().flat_map(|s| s.iter()) {
+        match c {
+          b'A'..=b'T' => {
+            n = n * 20 + usize::from(c - b'A') + 1;
+            if n <= mand.len() {
+              self.step(label, b, de, hyps, &mut stack, mand[n - 1])?
+            } else if n <= mand.len() + labels.len() {
+              self.step(label, b, de, hyps, &mut stack, labels[n - mand.len() - 1])?
+            } else {
+              let el = saved.get(n - mand.len() - labels.len() - 1).copied()
+                .ok_or_else(|| ElabError::new_e(label, "proof step out of range"))?;
+              stack.push(El::reuse(el, de))
+            }
+            n = 0
+          }
+          b'U'..=b'Y' => n = n * 5 + usize::from(c - b'U') + 1,
+          b'Z' => saved.push(*stack.last()
+            .ok_or_else(|| ElabError::new_e(label, "proof stack underflow"))?),
+          b'?' => return Ok(None),
+          _ => return Err(ElabError::new_e(label,
+            format!("invalid character '{}' in compressed proof", c as char)))
+        }
+      }
+    } else {
+      for &s in steps {
+        if s == b"?" { return Ok(None) }
+        self.step(label, b, de, hyps, &mut stack, s)?
+      }
+    }
+    match *stack {
+      [El::Proof(p, e)] if e == ret => Ok(Some(p)),
+      [El::Proof(..)] => Err(ElabError::new_e(label, "proof does not prove the statement")),
+      _ => Err(ElabError::new_e(label, "proof should leave exactly one proof on the stack")),
+    }
+  }
+
+  /// Apply the proof step `lbl`.
+  fn step(&mut self, sp: Span, b: &Binders<'a>, de: &mut Dedup<ProofHash>,
+      hyps: &[(usize, usize)], stack: &mut Vec<El<'a>>, lbl: &'a [u8]) -> Result<()> {
+    let el = match self.labels.get(lbl).cloned() {
+      None => return Err(ElabError::new_e(sp, format!("unknown label '{}'", lossy(lbl)))),
+      Some(Label::Hyp(h)) => {
+        if !self.hyps[h].active {
+          return Err(ElabError::new_e(sp, format!("hypothesis '{}' is not active", lossy(lbl))))
+        }
+        match self.hyps[h].kind {
+          Hyp::Float {var, tc} => if let Some(&(_, i)) = b.vars.get(var) {
+            El::Expr(de.reuse(i), tc)
+          } else {
+            let (s, bound) = self.sort(tc, sp)?;
+            if !bound {
+              return Err(ElabError::new_e(sp, format!(
+                "dummy variable '{}' does not have a bound typecode", lossy(var))))
+            }
+            El::Expr(de.add_direct(ProofHash::Dummy(self.name(var), s)), tc)
+          },
+          Hyp::Ess {..} => {
+            let n = b.ess.iter().position(|&e| e == h).expect("active hypotheses are mandatory");
+            let (pf, e) = hyps[n];
+            El::Proof(de.reuse(pf), e)
+          }
+        }
+      }
+      Some(Label::Term(t, fr)) => {
+        let (args, _) = pop_args(sp, stack, &fr)?;
+        El::Expr(de.add_direct(ProofHash::Term(t, args.into())), fr.tc)
+      }
+      Some(Label::Syntax(fr, tree)) => {
+        let (args, _) = pop_args(sp, stack, &fr)?;
+        El::Expr(subst_tree(de, &tree, &args), fr.tc)
+      }
+      Some(Label::Thm(t, fr)) => {
+        let (args, pfs) = pop_args(sp, stack, &fr)?;
+        let td = &self.env.thms[t];
+        let mut heap = vec![None; td.heap.len()];
+        for (i, &e) in args.iter().enumerate() { heap[i] = Some(e) }
+        for (&(_, e), (_, h)) in pfs.iter().zip(&*td.hyps) {
+          if ProofHash::subst(de, &td.heap, &mut heap, h) != e {
+            return Err(ElabError::new_e(sp, format!("hypothesis mismatch in '{}'", lossy(lbl))))
+          }
+        }
+        let rhs = ProofHash::subst(de, &td.heap, &mut heap, &td.ret);
+        let args = args.into_iter().chain(pfs.into_iter().map(|p| p.0)).collect();
+        El::Proof(de.add_direct(ProofHash::Thm(t, args, rhs)), rhs)
+      }
+    };
+    stack.push(el);
+    Ok(())
+  }
+}
+
+/// Construct an [`Environment`] from a `.mm` file, using the default [`TypecodeMap`].
+pub fn elab(file: &FileRef, source: &[u8]) -> (Result<()>, Environment) {
+  elab_with(file, source, &TypecodeMap::default())
+}
+
+/// Construct an [`Environment`] from a `.mm` file, using the typecode mapping `tcs`.
+pub fn elab_with(file: &FileRef, source: &[u8], tcs: &TypecodeMap) -> (Result<()>, Environment) {
+  let mut p = Importer {
+    file, source, tcs, idx: 0, doc: None,
+    consts: HashSet::new(), vars: HashSet::new(), floats: HashMap::new(),
+    hyps: vec![], active: vec![], dvs: vec![], scopes: vec![],
+    labels: HashMap::new(), sorts: HashMap::new(), rules: HashMap::new(),
+    names: HashMap::new(), used: HashSet::new(), env: Environment::new(),
+  };
+  (p.run(), p.env)
+}
@@ -0,0 +1,315 @@
+//! MMB diff tool, which compares the declarations of two `.mmb` files.
+//!
+//! This is intended for reviewing what changed between two builds of a compiled library.
+//! Declarations are matched up by name when the files have a name index, and otherwise by
+//! statement, so an unnamed declaration is only matched with one that has the same statement.
+//! Statements (and proofs, if requested) are compared after translating all sort, term and
+//! theorem references into names, so renumbering the declarations (for example by inserting
+//! a new theorem in the middle of the file) does not count as a change.
+//!
+//! A declaration that is not in the index is referred to by the name it gets in the old
+//! file, like `t12` for term 12, and the unnamed declarations of the new file are renamed to
+//! the name of the declaration they match in the old file. Declarations which are only in the
+//! new file get their name in the new file with a prime, like `t13'`.
+//!
+//! ```text
+//! mm0-rs diff [--proofs] old.mmb new.mmb
+//! ```
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::{fmt, io};
+use clap::ArgMatches;
+use mm0b_parser::{u32_as_usize, Arg, BasicMmbFile, NumdStmtCmd, ParseError, ProofCmd, ProofIter,
+  UnifyCmd, UnifyIter};
+use crate::{Modifiers, SortId, TermId, ThmId};
+use crate::compiler::FileContents;
+
+/// The kind of a declaration.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DeclKind {
+  /// A `sort`
+  Sort,
+  /// A `term`
+  Term,
+  /// A `def` or `local def`
+  Def {
+    /// True for `local def`
+    local: bool,
+  },
+  /// An `axiom`
+  Axiom,
+  /// A `theorem` or `pub theorem`
+  Thm {
+    /// True for a theorem that is not `pub`
+    local: bool,
+  },
+}
+
+impl fmt::Display for DeclKind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DeclKind::Sort => write!(f, "sort"),
+      DeclKind::Term => write!(f, "term"),
+      DeclKind::Def {local: false} => write!(f, "def"),
+      DeclKind::Def {local: true} => write!(f, "local def"),
+      DeclKind::Axiom => write!(f, "axiom"),
+      DeclKind::Thm {local: false} => write!(f, "pub theorem"),
+      DeclKind::Thm {local: true} => write!(f, "theorem"),
+    }
+  }
+}
+
+/// A declaration in one of the files, with its statement and proof rendered in a form that
+/// does not depend on the numbering of declarations in the file.
+#[derive(Debug)]
+struct Decl {
+  kind: DeclKind,
+  name: Option<String>,
+  stmt: String,
+  /// The proof stream, or the empty string if proofs are not being compared.
+  proof: String,
+}
+
+impl Decl {
+  /// The key used to match this declaration with a declaration in the other file.
+  fn key(&self) -> String {
+    match &self.name {
+      Some(name) => name.clone(),
+      None => format!("{}:{}", self.kind, self.stmt),
+    }
+  }
+
+  /// The name to show in the report.
+  fn display_name(&self) -> String {
+    match &self.name {
+      Some(name) => name.clone(),
+      None => format!("<unnamed>{}", self.stmt),
+    }
+  }
+}
+
+/// The difference found for one declaration.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+  /// The declaration is only in the new file.
+  Added,
+  /// The declaration is only in the old file.
+  Removed,
+  /// The declaration has a different kind in the old file, given here.
+  Kind(DeclKind),
+  /// The declaration has a different statement.
+  Statement,
+  /// The declaration has the same statement but a different proof.
+  Proof,
+}
+
+/// An entry in the result of [`diff`].
+#[derive(Clone, Debug)]
+pub struct DiffEntry {
+  /// The kind of the declaration (in the new file, unless it was removed).
+  pub kind: DeclKind,
+  /// The name of the declaration, or its statement if it is unnamed.
+  pub name: String,
+  /// What changed.
+  pub change: Change,
+}
+
+impl fmt::Display for DiffEntry {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.change {
+      Change::Added => write!(f, "+ {} {}", self.kind, self.name),
+      Change::Removed => write!(f, "- {} {}", self.kind, self.name),
+      Change::Kind(old) => write!(f, "~ {} {}: was {}", self.kind, self.name, old),
+      Change::Statement => write!(f, "~ {} {}: statement changed", self.kind, self.name),
+      Change::Proof => write!(f, "~ {} {}: proof changed", self.kind, self.name),
+    }
+  }
+}
+
+/// The names of the declarations of one file, used to render references to them.
+/// Declarations that are not in the name index are given names by [`decls`].
+struct Names<'a> {
+  file: &'a BasicMmbFile<'a>,
+  sorts: Vec<String>,
+  terms: Vec<String>,
+  thms: Vec<String>,
+}
+
+/// Get the name of declaration `i` in `names`, or the default name from the file.
+fn get_name<'a>(names: &'a [String], i: usize, default: impl FnOnce() -> Cow<'a, str>) -> Cow<'a, str> {
+  names.get(i).map_or_else(default, |s| Cow::Borrowed(s))
+}
+
+impl Names<'_> {
+  fn sort(&self, n: SortId) -> Cow<'_, str> {
+    get_name(&self.sorts, n.0.into(), || self.file.sort_name(n))
+  }
+  fn term(&self, n: TermId) -> Cow<'_, str> {
+    get_name(&self.terms, u32_as_usize(n.0), || self.file.term_name(n))
+  }
+  fn thm(&self, n: ThmId) -> Cow<'_, str> {
+    get_name(&self.thms, u32_as_usize(n.0), || self.file.thm_name(n))
+  }
+}
+
+fn binders(names: &Names<'_>, args: &[Arg], out: &mut Vec<String>) {
+  for arg in args {
+    let s = names.sort(arg.sort());
+    out.push(if arg.bound() {format!("{{{}}}", s)} else {format!("({} {:#x})", s, arg.deps_unchecked())})
+  }
+}
+
+fn unify(names: &Names<'_>, it: UnifyIter<'_>, out: &mut Vec<String>) -> Result<(), ParseError> {
+  for cmd in it {
+    out.push(match cmd? {
+      UnifyCmd::Term {tid, save} => format!("{}{}", names.term(tid), if save {"!"} else {""}),
+      UnifyCmd::Ref(i) => format!("#{}", i),
+      UnifyCmd::Dummy(s) => format!(".{}", names.sort(s)),
+      UnifyCmd::Hyp => "|-".into(),
+    })
+  }
+  Ok(())
+}
+
+fn proof(names: &Names<'_>, it: ProofIter<'_>) -> Result<String, ParseError> {
+  let mut out = vec![];
+  for cmd in it {
+    out.push(match cmd? {
+      ProofCmd::Term {tid, save} => format!("{}{}", names.term(tid), if save {"!"} else {""}),
+      ProofCmd::Thm {tid, save} => format!("@{}{}", names.thm(tid), if save {"!"} else {""}),
+      ProofCmd::Ref(i) => format!("#{}", i),
+      ProofCmd::Dummy(s) => format!(".{}", names.sort(s)),
+      cmd => format!("{:?}", cmd),
+    })
+  }
+  Ok(out.join(" "))
+}
+
+/// Collect the declarations of `file`, in order.
+///
+/// The declarations which are not in the name index are named using `unnamed`, which maps
+/// the key of such a declaration (and the number of earlier declarations with the same key)
+/// to its name. The names are added to `unnamed` when reading the old file, and the new
+/// file (for which `new` is true) uses the names of the declarations it has in common
+/// with the old file, so that the comparison does not depend on the numbering.
+fn decls(file: &BasicMmbFile<'_>, proofs: bool,
+  unnamed: &mut HashMap<(String, usize), String>, new: bool,
+) -> Result<Vec<Decl>, ParseError> {
+  let mut names = Names { file, sorts: vec![], terms: vec![], thms: vec![] };
+  let mut counts = HashMap::<String, usize>::new();
+  let mut out = vec![];
+  let mut it = file.proof();
+  loop {
+    let pos = it.pos;
+    let (stmt, pf) = if let Some(decl) = it.next() {decl?} else {break};
+    let name = file.stmt_index(stmt).and_then(|e| e.value()).map(str::to_owned);
+    let mut toks = vec![];
+    let kind = match stmt {
+      NumdStmtCmd::Sort {sort_id} => {
+        let sd = file.sort(sort_id).ok_or(ParseError::StrError("sort out of range", pos))?;
+        toks.push(Modifiers::new(sd.0).to_string());
+        DeclKind::Sort
+      }
+      NumdStmtCmd::TermDef {term_id, local} => {
+        let td = file.term(term_id).ok_or(ParseError::StrError("term out of range", pos))?;
+        binders(&names, td.args(), &mut toks);
+        toks.push(":".into());
+        binders(&names, &[td.ret()], &mut toks);
+        if td.def() {
+          toks.push("=".into());
+          unify(&names, td.unify(), &mut toks)?;
+          DeclKind::Def {local}
+        } else {
+          DeclKind::Term
+        }
+      }
+      NumdStmtCmd::Axiom {thm_id} | NumdStmtCmd::Thm {thm_id, ..} => {
+        let td = file.thm(thm_id).ok_or(ParseError::StrError("theorem out of range", pos))?;
+        binders(&names, td.args(), &mut toks);
+        toks.push(":".into());
+        unify(&names, td.unify(), &mut toks)?;
+        if let NumdStmtCmd::Thm {local, ..} = stmt { DeclKind::Thm {local} } else { DeclKind::Axiom }
+      }
+    };
+    let proof = if proofs && !pf.is_null() { proof(&names, pf)? } else { String::new() };
+    let d = Decl {kind, name, stmt: toks.join(" "), proof};
+    let (list, default) = match stmt {
+      NumdStmtCmd::Sort {sort_id} => (&mut names.sorts, file.sort_name(sort_id)),
+      NumdStmtCmd::TermDef {term_id, ..} => (&mut names.terms, file.term_name(term_id)),
+      NumdStmtCmd::Axiom {thm_id} | NumdStmtCmd::Thm {thm_id, ..} =>
+        (&mut names.thms, file.thm_name(thm_id)),
+    };
+    list.push(if let Some(name) = &d.name { name.clone() } else {
+      let key = d.key();
+      let count = counts.entry(key.clone()).or_default();
+      *count += 1;
+      let default = if new { format!("{}'", default) } else { default.into_owned() };
+      unnamed.entry((key, *count)).or_insert(default).clone()
+    });
+    out.push(d)
+  }
+  Ok(out)
+}
+
+/// Compare the declarations in `old` and `new`. The result lists the added and changed
+/// declarations in the order of `new`, followed by the removed declarations in the order
+/// of `old`. If `proofs` is true, declarations with the same statement and a different proof
+/// are also reported.
+pub fn diff(old: &BasicMmbFile<'_>, new: &BasicMmbFile<'_>, proofs: bool) -> Result<Vec<DiffEntry>, ParseError> {
+  let mut unnamed = HashMap::new();
+  let old = decls(old, proofs, &mut unnamed, false)?;
+  let new = decls(new, proofs, &mut unnamed, true)?;
+  // Unnamed declarations can have the same key, so each key maps to a stack of declarations
+  let mut unmatched = HashMap::<String, Vec<usize>>::new();
+  for (i, d) in old.iter().enumerate().rev() { unmatched.entry(d.key()).or_default().push(i) }
+  let mut matched = vec![false; old.len()];
+  let mut out = vec![];
+  for d in &new {
+    let change = match unmatched.get_mut(&d.key()).and_then(Vec::pop) {
+      None => Change::Added,
+      Some(i) => {
+        matched[i] = true;
+        let o = &old[i];
+        if o.kind != d.kind { Change::Kind(o.kind) }
+        else if o.stmt != d.stmt { Change::Statement }
+        else if o.proof != d.proof { Change::Proof }
+        else { continue }
+      }
+    };
+    out.push(DiffEntry {kind: d.kind, name: d.display_name(), change})
+  }
+  for (d, _) in old.iter().zip(matched).filter(|p| !p.1) {
+    out.push(DiffEntry {kind: d.kind, name: d.display_name(), change: Change::Removed})
+  }
+  Ok(out)
+}
+
+/// Main entry point for `mm0-rs diff` subcommand.
+///
+/// See the [module documentation](self) for the purpose of this command.
+///
+/// # Arguments
+///
+/// `mm0-rs diff [--proofs] <old.mmb> <new.mmb>`, where:
+///
+/// - `old.mmb` and `new.mmb` are the files to compare
+/// - `--proofs` also reports declarations whose proofs changed
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let parse_err = |e: ParseError| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+  let old = FileContents::new_bin_from_file(args.value_of_os("OLD").expect("required arg").as_ref())?;
+  let new = FileContents::new_bin_from_file(args.value_of_os("NEW").expect("required arg").as_ref())?;
+  let old = BasicMmbFile::parse(&old).map_err(parse_err)?;
+  let new = BasicMmbFile::parse(&new).map_err(parse_err)?;
+  let entries = diff(&old, &new, args.is_present("proofs")).map_err(parse_err)?;
+  let (mut added, mut removed, mut changed) = (0, 0, 0);
+  for e in &entries {
+    println!("{}", e);
+    match e.change {
+      Change::Added => added += 1,
+      Change::Removed => removed += 1,
+      _ => changed += 1,
+    }
+  }
+  println!("{} added, {} removed, {} changed", added, removed, changed);
+  Ok(())
+}
@@ -1,16 +1,65 @@
 //! MMB exporter, which produces `.mmb` binary proof files from an
 //! [`Environment`](crate::Environment) object.
-use std::mem;
+use std::{fmt, mem};
+use std::cmp::Reverse;
+use std::collections::{HashMap, hash_map::Entry};
 use std::io::{self, Write, Seek, SeekFrom};
 use byteorder::{LE, ByteOrder, WriteBytesExt};
 use zerocopy::{AsBytes, U32, U64};
 use crate::{
-  Type, Expr, Proof, SortId, AtomId, AtomVec, TermKind, ThmKind,
-  TermVec, ExprNode, ProofNode, StmtTrace, DeclKey, Modifiers,
-  FrozenEnv, FileRef, LinedString, ErrorLevel};
+  Type, Expr, Proof, SortId, AtomId, AtomVec, TermKind, ThmKind, Thm,
+  TermId, ThmId, TermVec, ThmVec, ExprNode, ProofNode, StmtTrace, DeclKey, Modifiers,
+  FrozenEnv, FileRef, LinedString, ErrorLevel, ArcString, Span};
 
 #[allow(clippy::wildcard_imports)]
-use mm0b_parser::{ProofCmd, UnifyCmd, cmd::*, write_cmd_bytes};
+use mm0b_parser::{ProofCmd, UnifyCmd, cmd::*, write_cmd_bytes, try_next_cmd, version_for_sorts,
+  optimize_proof};
+
+pub mod mm0;
+
+/// The number of entries in [`ExportStats::largest_proofs`].
+const NUM_LARGEST_PROOFS: usize = 10;
+
+/// Statistics about a generated MMB file, returned by [`Exporter::export`].
+/// This is intended to help library authors see what dominates the size of the output.
+#[derive(Clone, Debug, Default)]
+pub struct ExportStats {
+  /// The size of the file header and sort table, in bytes.
+  pub header: u64,
+  /// The size of the term table, including binders and unify streams, in bytes.
+  pub terms: u64,
+  /// The size of the theorem table, including binders and unify streams, in bytes.
+  pub thms: u64,
+  /// The size of the declaration stream (which contains the proofs), in bytes.
+  pub proofs: u64,
+  /// The size of the debugging index, in bytes, or 0 if there is no index.
+  pub index: u64,
+  /// The declarations with the largest proof streams, in decreasing order of size (in bytes).
+  pub largest_proofs: Vec<(ArcString, u64)>,
+  /// The number of heap entries created in proof streams (saved terms, theorems and
+  /// conversions, as well as dummy variables).
+  pub saves: u64,
+  /// The number of backreferences to heap entries in proof streams. If this is
+  /// small compared to [`saves`](Self::saves), then many subterms are saved
+  /// without being reused.
+  pub refs: u64,
+}
+
+impl fmt::Display for ExportStats {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(f, "header:        {:>12} bytes", self.header)?;
+    writeln!(f, "term table:    {:>12} bytes", self.terms)?;
+    writeln!(f, "theorem table: {:>12} bytes", self.thms)?;
+    writeln!(f, "proofs:        {:>12} bytes", self.proofs)?;
+    writeln!(f, "index:         {:>12} bytes", self.index)?;
+    writeln!(f, "backreferences: {} to {} heap entries", self.refs, self.saves)?;
+    writeln!(f, "largest proofs:")?;
+    for (name, size) in &self.largest_proofs {
+      writeln!(f, "  {:>12} bytes: {}", size, name)?
+    }
+    Ok(())
+  }
+}
 
 #[derive(Debug)]
 struct Reorder<T=u32> {
@@ -30,6 +79,122 @@ impl<T> Reorder<T> {
   }
 }
 
+/// The mapping from term and theorem IDs in the environment to IDs in the output file.
+/// This is the identity map unless some declarations are omitted from the output
+/// (see [`ExportSubset::Public`]).
+#[derive(Debug, Default)]
+struct IdMap {
+  /// The new ID of each term, or `None` if the term is omitted.
+  terms: TermVec<Option<TermId>>,
+  /// The new ID of each theorem, or `None` if the theorem is omitted.
+  thms: ThmVec<Option<ThmId>>,
+}
+
+impl IdMap {
+  fn term(&self, t: TermId) -> TermId { self.terms[t].expect("reference to omitted term") }
+  fn thm(&self, t: ThmId) -> ThmId { self.thms[t].expect("reference to omitted theorem") }
+  fn contains(&self, k: DeclKey) -> bool {
+    match k {
+      DeclKey::Term(t) => self.terms[t].is_some(),
+      DeclKey::Thm(t) => self.thms[t].is_some(),
+    }
+  }
+}
+
+/// Returns true if this is a `theorem` that is not marked `pub`.
+fn is_local_thm(td: &Thm) -> bool {
+  matches!(td.kind, ThmKind::Thm(_)) && td.vis != Modifiers::PUB
+}
+
+/// The set of declarations written by [`Exporter::export`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportSubset {
+  /// Write every declaration in the environment.
+  All,
+  /// Omit `local def`s and non-`pub` theorems from the output entirely, so that the result
+  /// contains only the public interface of the file. Exporting fails if any public
+  /// declaration depends on a local one.
+  Public,
+}
+
+/// Options controlling the output of [`Exporter::export`]. This is constructed using
+/// [`ExportOptions::new`] followed by the builder methods for any non-default settings.
+///
+/// The output is a deterministic function of the environment and these options.
+#[derive(Clone, Copy, Debug)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct ExportOptions {
+  index: bool,
+  source_map: bool,
+  subset: ExportSubset,
+  optimize: bool,
+  stats: bool,
+  allow_sorry: bool,
+  version: Option<u8>,
+}
+
+impl Default for ExportOptions {
+  fn default() -> Self {
+    Self {
+      index: true,
+      source_map: false,
+      subset: ExportSubset::All,
+      optimize: false,
+      stats: false,
+      allow_sorry: false,
+      version: None,
+    }
+  }
+}
+
+impl ExportOptions {
+  /// The default options: write all declarations with a debugging index, without
+  /// optimization or statistics, using the lowest MMB version that can represent the file.
+  #[must_use] pub fn new() -> Self { Self::default() }
+
+  /// Output the (optional) debugging index to the file. (default: on)
+  #[must_use] pub fn index(mut self, index: bool) -> Self { self.index = index; self }
+
+  /// Include a source map in the index, which maps the proof stream of each declaration
+  /// back to its span in the source text. Each declaration has an entry, and inside a theorem
+  /// there is a nested entry for each subproof and subterm that was written in the source
+  /// (so that a failure at a given position in the proof stream can be traced to the
+  /// innermost source expression that generated it). The nested entries are omitted for
+  /// theorems whose proofs were minimized or came from a non-MM1 file, and when the proof
+  /// streams are [optimized](Self::optimize), since that changes the layout of the stream.
+  /// This has no effect unless the index is also enabled. (default: off)
+  #[must_use] pub fn source_map(mut self, source_map: bool) -> Self {
+    self.source_map = source_map;
+    self
+  }
+
+  /// Select which declarations are written (see [`ExportSubset`]). (default: all)
+  #[must_use] pub fn subset(mut self, subset: ExportSubset) -> Self { self.subset = subset; self }
+
+  /// Run the peephole optimizer on proof streams before writing them. This drops
+  /// conversions that are closed by reflexivity, and heap saves that are never referenced
+  /// (see [`optimize_proof`]), which produces smaller proof streams for the same proofs.
+  /// (default: off)
+  #[must_use] pub fn optimize(mut self, optimize: bool) -> Self { self.optimize = optimize; self }
+
+  /// Collect statistics about the size of the output, which are returned by
+  /// [`Exporter::export`]. (default: off)
+  #[must_use] pub fn stats(mut self, stats: bool) -> Self { self.stats = stats; self }
+
+  /// Write the admitted theorems, whose proofs are missing because they were closed with
+  /// `sorry` or left open, with a `sorry` proof and a warning. Otherwise exporting fails
+  /// on the first such theorem. (default: off)
+  #[must_use] pub fn allow_sorry(mut self, allow_sorry: bool) -> Self {
+    self.allow_sorry = allow_sorry;
+    self
+  }
+
+  /// Write the given MMB format version, rather than the lowest version that can represent
+  /// the file. Exporting fails if the version is unsupported or too old for the number
+  /// of sorts in the environment.
+  #[must_use] pub fn version(mut self, version: u8) -> Self { self.version = Some(version); self }
+}
+
 /// The main exporter structure. This keeps track of the underlying writer,
 /// as well as tracking values that are written out of order.
 pub struct Exporter<'a, W> {
@@ -49,6 +214,15 @@ pub struct Exporter<'a, W> {
   pos: u64,
   /// The calculated reorder maps for terms encountered so far (see [`Reorder`]).
   term_reord: TermVec<Option<Reorder>>,
+  /// The renumbering of terms and theorems in the output (see [`IdMap`]).
+  ids: IdMap,
+  /// The statistics collected so far.
+  stats: ExportStats,
+  /// The options for the current export.
+  opts: ExportOptions,
+  /// The size of the proof stream of each declaration, used to compute
+  /// [`ExportStats::largest_proofs`].
+  proof_sizes: Vec<(u64, AtomId)>,
   /// A list of "fixups", which are writes that have to occur in places other
   /// than the current writer location. We buffer these to avoid too many seeks
   /// of the underlying writer.
@@ -64,6 +238,9 @@ impl<'a, W: std::fmt::Debug> std::fmt::Debug for Exporter<'a, W> {
       .field("w", &self.w)
       .field("pos", &self.pos)
       .field("term_reord", &self.term_reord)
+      .field("ids", &self.ids)
+      .field("stats", &self.stats)
+      .field("opts", &self.opts)
       .field("fixups", &self.fixups)
       .finish()
   }
@@ -154,6 +331,7 @@ impl<W: Write + Seek> Write for Exporter<'_, W> {
 }
 
 fn write_expr_proof(w: &mut impl Write,
+  ids: &IdMap,
   heap: &[ExprNode],
   reorder: &mut Reorder,
   vars: &mut Option<&mut Vec<AtomId>>,
@@ -163,7 +341,7 @@ fn write_expr_proof(w: &mut impl Write,
   Ok(match *node {
     ExprNode::Ref(i) => match reorder.map[i] {
       None => {
-        let n = write_expr_proof(w, heap, reorder, vars, &heap[i], true)?;
+        let n = write_expr_proof(w, ids, heap, reorder, vars, &heap[i], true)?;
         reorder.map[i] = Some(n);
         n
       }
@@ -175,13 +353,27 @@ fn write_expr_proof(w: &mut impl Write,
       (reorder.idx, reorder.idx += 1).0
     }
     ExprNode::App(tid, ref es) => {
-      for e in &**es {write_expr_proof(w, heap, reorder, vars, e, false)?;}
-      ProofCmd::Term {tid, save}.write_to(w)?;
+      for e in &**es {write_expr_proof(w, ids, heap, reorder, vars, e, false)?;}
+      ProofCmd::Term {tid: ids.term(tid), save}.write_to(w)?;
       if save {(reorder.idx, reorder.idx += 1).0} else {0}
     }
   })
 }
 
+/// Run the proof optimizer (see [`optimize_proof`]) on the 0-terminated proof stream `buf`
+/// of a declaration with `nargs` arguments, replacing it with the optimized stream.
+fn optimize_stream(buf: &mut Vec<u8>, nargs: u32) -> io::Result<()> {
+  let mut cmds = vec![];
+  let mut pos = 0;
+  while let Some((cmd, data, next)) = try_next_cmd(buf, pos).expect("malformed proof stream") {
+    cmds.push(ProofCmd::try_from((cmd, data)).expect("malformed proof stream"));
+    pos = next;
+  }
+  buf.clear();
+  for cmd in optimize_proof(nargs, &cmds) { cmd.write_to(buf)? }
+  buf.write_u8(0)
+}
+
 /// A wrapper around a writer that implements [`Write`]` + `[`Seek`] by internally buffering
 /// all writes, writing to the underlying writer only once on [`Drop`].
 #[derive(Debug)]
@@ -199,6 +391,9 @@ impl<W: Write> BigBuffer<W> {
   pub fn finish(mut self) -> io::Result<()> {
     self.w.write_all(&mem::take(self.buffer.get_mut()))
   }
+  /// Drops the buffered data without writing it to the underlying writer, for example
+  /// because the export failed.
+  pub fn discard(mut self) { self.buffer.get_mut().clear() }
 }
 
 impl<W: Write> Write for BigBuffer<W> {
@@ -227,11 +422,68 @@ struct VarData {
   vars: Vec<AtomId>,
 }
 
+/// A range of the proof stream, generated by the source text `span` in the declaration `decl`.
+struct SourceData {
+  p_start: u64,
+  p_end: u64,
+  decl: DeclKey,
+  span: Span,
+}
+
+/// The source spans of the nodes of the proof being written, and the ranges of the
+/// proof stream generated by them. This is only populated when making a source map.
+#[derive(Default)]
+struct NodeSpans {
+  /// The source span of each node of the proof that has one, by address.
+  spans: HashMap<*const ProofNode, Span>,
+  /// The ranges `(start, end, span)` of the proof stream (as positions in the buffer)
+  /// generated by the nodes in `spans`.
+  ranges: Vec<(usize, usize, Span)>,
+}
+
+impl NodeSpans {
+  /// Set up the spans for writing the proof `pf` (see [`Proof::spans`]).
+  fn new(pf: &Proof) -> Self {
+    let mut spans = HashMap::new();
+    let (mut n, mut it) = (0, pf.spans.iter().peekable());
+    pf.for_each_node(|e| {
+      if let Some(&(_, sp)) = it.next_if(|(k, _)| *k == n) { spans.insert(e as *const _, sp); }
+      n += 1;
+    });
+    NodeSpans { spans, ranges: vec![] }
+  }
+
+  /// Record that the buffer range `start..end` was generated by `node`.
+  fn record(&mut self, node: &ProofNode, start: usize, end: usize) {
+    if start == end { return }
+    if let Some(&sp) = self.spans.get(&(node as *const _)) { self.ranges.push((start, end, sp)) }
+  }
+}
+
+/// A proof stream being written, together with the [`NodeSpans`] of the proof,
+/// which records the ranges of the stream generated by each node.
+struct ProofBuf<'b> {
+  buf: &'b mut Vec<u8>,
+  sm: &'b mut NodeSpans,
+}
+
+impl ProofBuf<'_> {
+  /// Record that the buffer range from `start` to the current position was generated by `node`.
+  fn record(&mut self, node: &ProofNode, start: usize) { self.sm.record(node, start, self.buf.len()) }
+}
+
+impl Write for ProofBuf<'_> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.buf.write(buf) }
+  fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
 struct IndexTemp {
   sort_names: Vec<NameData>,
   term_names: Vec<(NameData, VarData)>,
   /// The second `VarData` is the list of hypotheses
   thm_names: Vec<((NameData, VarData), VarData)>,
+  /// The source map, if requested
+  source_map: Option<Vec<SourceData>>,
 }
 
 impl<'a, W: Write + Seek> Exporter<'a, W> {
@@ -246,6 +498,10 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
   ) -> Self {
     Self {
       term_reord: TermVec(Vec::with_capacity(env.terms().len())),
+      ids: IdMap::default(),
+      stats: ExportStats::default(),
+      opts: ExportOptions::default(),
+      proof_sizes: vec![],
       file, source, env, report, w, pos: 0, fixups: vec![]
     }
   }
@@ -292,13 +548,15 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
 
   #[inline]
   fn write_sort_deps(&mut self, bound: bool, sort: SortId, deps: u64) -> io::Result<()> {
-    self.write_u64(u64::from(bound) << 63 | u64::from(sort.0) << 56 | deps)
+    self.write_u64(u64::from(bound) << 63 |
+      u64::from(sort.0 & 0x7F) << 56 | u64::from(sort.0 >> 7) << 55 | deps)
   }
 
   #[inline]
   fn write_term_header(header: &mut [u8], nargs: u16, sort: SortId, has_def: bool, p_term: u32) {
     LE::write_u16(&mut header[0..], nargs);
-    header[2] = sort.0 | if has_def {0x80} else {0};
+    header[2] = sort.0 & 0x7F | if has_def {0x80} else {0};
+    header[3] = sort.0 >> 7;
     LE::write_u32(&mut header[4..], p_term);
   }
 
@@ -342,6 +600,7 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
         UnifyCmd::Dummy(s).write_to(self)?
       }
       ExprNode::App(tid, ref es) => {
+        let tid = self.ids.term(tid);
         if save.is_empty() {
           UnifyCmd::Term {tid, save: false}.write_to(self)?
         } else {
@@ -356,14 +615,15 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
     Ok(())
   }
 
-  fn write_proof(&self, w: &mut impl Write,
+  fn write_proof(&self, w: &mut ProofBuf<'_>,
     heap: &[ProofNode],
     reorder: &mut Reorder,
     hyps: &[u32],
     node: &ProofNode,
     save: bool
   ) -> io::Result<u32> {
-    Ok(match node {
+    let start = w.buf.len();
+    let n = match node {
       &ProofNode::Ref(i) => match reorder.map[i] {
         None => {
           let n = self.write_proof(w, heap, reorder, hyps, &heap[i], true)?;
@@ -378,7 +638,7 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
       }
       &ProofNode::Term {term, ref args} => {
         for e in &**args {self.write_proof(w, heap, reorder, hyps, e, false)?;}
-        ProofCmd::Term {tid: term, save}.write_to(w)?;
+        ProofCmd::Term {tid: self.ids.term(term), save}.write_to(w)?;
         if save {(reorder.idx, reorder.idx += 1).0} else {0}
       }
       &ProofNode::Hyp(n, _) => {
@@ -390,11 +650,15 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
         for e in hs {self.write_proof(w, heap, reorder, hyps, e, false)?;}
         for e in args {self.write_proof(w, heap, reorder, hyps, e, false)?;}
         self.write_proof(w, heap, reorder, hyps, res, false)?;
-        ProofCmd::Thm {tid: thm, save}.write_to(w)?;
+        ProofCmd::Thm {tid: self.ids.thm(thm), save}.write_to(w)?;
         if save {(reorder.idx, reorder.idx += 1).0} else {0}
       }
       ProofNode::Conv(p) => {
         let (e1, c, p) = &**p;
+        if self.opts.optimize && matches!(c.deref(heap), ProofNode::Refl(_)) {
+          // `e1` and the type of `p` are identical, so the conversion is a no-op
+          return self.write_proof(w, heap, reorder, hyps, p, save)
+        }
         self.write_proof(w, heap, reorder, hyps, e1, false)?;
         self.write_proof(w, heap, reorder, hyps, p, false)?;
         ProofCmd::Conv.write_to(w)?;
@@ -408,15 +672,18 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
       ProofNode::Sym(_) |
       ProofNode::Cong {..} |
       ProofNode::Unfold {..} => unreachable!(),
-    })
+    };
+    w.record(node, start);
+    Ok(n)
   }
 
-  fn write_conv(&self, w: &mut impl Write,
+  fn write_conv(&self, w: &mut ProofBuf<'_>,
     heap: &[ProofNode],
     reorder: &mut Reorder,
     hyps: &[u32],
     node: &ProofNode,
   ) -> io::Result<()> {
+    let start = w.buf.len();
     match node {
       &ProofNode::Ref(i) => match reorder.map[i] {
         None => {
@@ -456,6 +723,27 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
         self.write_conv(w, heap, reorder, hyps, c)?;
       }
     }
+    w.record(node, start);
+    Ok(())
+  }
+
+  /// Write a declaration with command `cmd` and proof stream `buf` for the atom `a`,
+  /// and record statistics about it (if requested).
+  fn write_decl(&mut self, a: AtomId, cmd: u8, buf: &[u8]) -> io::Result<()> {
+    let start = self.pos;
+    write_cmd_bytes(self, cmd, buf)?;
+    if !self.opts.stats { return Ok(()) }
+    self.proof_sizes.push((self.pos - start, a));
+    let mut pos = 0;
+    while let Ok(Some((cmd, _, next))) = try_next_cmd(buf, pos) {
+      match cmd {
+        PROOF_REF => self.stats.refs += 1,
+        PROOF_TERM_SAVE | PROOF_THM_SAVE | PROOF_SAVE | PROOF_CONV_SAVE | PROOF_DUMMY =>
+          self.stats.saves += 1,
+        _ => {}
+      }
+      pos = next;
+    }
     Ok(())
   }
 
@@ -465,22 +753,103 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
     LE::write_u32(&mut header[4..], p_thm);
   }
 
-  /// Perform the actual export. If `index` is true, also output the
-  /// (optional) debugging table to the file.
+  /// Determine which terms and theorems will be written to the output, and their new IDs.
+  /// If `public_only` is true, `local def`s and non-`pub` theorems are omitted, and
+  /// it is an error for any remaining declaration to refer to an omitted one, either in
+  /// its statement or in its proof. (All such errors are reported before failing.)
+  fn make_id_map(&mut self, public_only: bool) -> io::Result<IdMap> {
+    let env = self.env;
+    let mut n = 0;
+    let terms = env.terms().0.iter().map(|td|
+      (!public_only || td.vis != Modifiers::LOCAL).then(|| (TermId(n), n += 1).0)).collect();
+    let mut n = 0;
+    let thms = env.thms().0.iter().map(|td|
+      (!public_only || !is_local_thm(td)).then(|| (ThmId(n), n += 1).0)).collect();
+    let ids = IdMap {terms, thms};
+    if !public_only { return Ok(ids) }
+    let mut failed = false;
+    let mut deps = vec![];
+    for s in env.stmts() {
+      let a = if let StmtTrace::Decl(a) = *s {a} else {continue};
+      let k = env.data()[a].decl().expect("expected a term/thm");
+      if !ids.contains(k) {continue}
+      let mut add_term = |t| if ids.terms[t].is_none() {deps.push(env.term(t).atom)};
+      match k {
+        DeclKey::Term(t) => if let TermKind::Def(Some(Expr {heap, head})) = &env.term(t).kind {
+          for e in heap.iter().chain(Some(head)) { e.for_each_term(&mut add_term) }
+        }
+        DeclKey::Thm(t) => {
+          let td = env.thm(t);
+          for e in td.heap.iter().chain(td.hyps.iter().map(|h| &h.1)).chain(Some(&td.ret)) {
+            e.for_each_term(&mut add_term)
+          }
+          if let ThmKind::Thm(Some(Proof {heap, hyps, head, ..})) = &td.kind {
+            for e in heap.iter().chain(&**hyps).chain(Some(head)) {
+              e.for_each_decl(&mut |k| if !ids.contains(k) {
+                deps.push(match k {
+                  DeclKey::Term(t) => env.term(t).atom,
+                  DeclKey::Thm(t) => env.thm(t).atom,
+                })
+              })
+            }
+          }
+        }
+      }
+      if !deps.is_empty() {
+        deps.sort_unstable();
+        deps.dedup();
+        let names = deps.drain(..).map(|a| env.data()[a].name().to_string()).collect::<Vec<_>>();
+        (self.report)(ErrorLevel::Error, &format!(
+          "{} refers to local declarations, which are omitted from the output: {}",
+          env.data()[a].name(), names.join(", ")));
+        failed = true;
+      }
+    }
+    if failed {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput,
+        "public declarations depend on local declarations"))
+    }
+    Ok(ids)
+  }
+
+  /// Write the MMB file with the given options, and flush the writer.
+  /// Consumes self since we're done. If [`ExportOptions::stats`] is set,
+  /// returns statistics about the generated file.
+  pub fn export(mut self, opts: &ExportOptions) -> io::Result<Option<ExportStats>> {
+    self.opts = *opts;
+    self.run()?;
+    self.finish()
+  }
+
+  /// Perform the actual export, with the options in `self.opts`.
   ///
   /// This does not finalize all writes. [`finish`] should be called after this
   /// to write the outstanding fixups.
   ///
   /// [`finish`]: Self::finish
-  pub fn run(&mut self, index: bool) -> io::Result<()> {
+  fn run(&mut self) -> io::Result<()> {
+    let ExportOptions {index, source_map, subset, optimize, stats: _, allow_sorry, version} = self.opts;
+    self.ids = self.make_id_map(subset == ExportSubset::Public)?;
+    let env = self.env;
+    let terms = env.terms().enum_iter()
+      .filter(|&(t, _)| self.ids.terms[t].is_some()).map(|p| p.1).collect::<Vec<_>>();
+    let thms = env.thms().enum_iter()
+      .filter(|&(t, _)| self.ids.thms[t].is_some()).map(|p| p.1).collect::<Vec<_>>();
     self.write_all(&MM0B_MAGIC)?; // magic
     let num_sorts = self.env.sorts().len();
-    assert!(num_sorts <= 128, "too many sorts (max 128)");
-    #[allow(clippy::cast_possible_truncation)]
-    self.write_all(&[MM0B_VERSION, num_sorts as u8, 0, 0])?; // two bytes reserved
-    let num_terms = self.env.terms().len();
+    let min_version = version_for_sorts(num_sorts).ok_or_else(||
+      io::Error::new(io::ErrorKind::InvalidInput, "too many sorts (max 256)"))?;
+    let version = match version {
+      None => min_version,
+      Some(v) if (min_version..=MM0B_VERSION).contains(&v) => v,
+      Some(v) => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+        "cannot write MMB version {} for a file with {} sorts", v, num_sorts)))
+    };
+    let [sorts_lo, sorts_hi] = u16::try_from(num_sorts).expect("impossible").to_le_bytes();
+    self.write_all(&[version, sorts_lo, sorts_hi, 0])?; // one byte reserved
+    let num_terms = terms.len();
     self.write_u32(num_terms.try_into().expect("too many terms"))?; // num_terms
-    let num_thms = self.env.thms().len();
+    let num_thms = thms.len();
     self.write_u32(num_thms.try_into().expect("too many thms"))?; // num_thms
     let p_terms = self.fixup32()?;
     let p_thms = self.fixup32()?;
@@ -493,8 +862,9 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
 
     // term header
     self.align_to(8)?; p_terms.commit(self);
+    self.stats.header = self.pos;
     let mut term_header = self.fixup_large(num_terms * 8)?;
-    for (head, t) in term_header.chunks_exact_mut(8).zip(&self.env.terms().0) {
+    for (head, t) in term_header.chunks_exact_mut(8).zip(terms) {
       let nargs: u16 = t.args.len().try_into().expect("term has more than 65536 args");
       Self::write_term_header(head, nargs, t.ret.0,
         matches!(t.kind, TermKind::Def(_)),
@@ -515,8 +885,10 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
 
     // theorem header
     self.align_to(8)?; p_thms.commit(self);
+    let start = self.pos;
+    self.stats.terms = start - self.stats.header;
     let mut thm_header = self.fixup_large(num_thms * 8)?;
-    for (head, t) in thm_header.chunks_exact_mut(8).zip(&self.env.thms().0) {
+    for (head, t) in thm_header.chunks_exact_mut(8).zip(thms) {
       let nargs = t.args.len().try_into().expect("theorem has more than 65536 args");
       Self::write_thm_header(head, nargs,
         self.align_to(8)?.try_into().expect("address too large"));
@@ -534,12 +906,15 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
 
     // main body (proofs of theorems)
     p_proof.commit(self);
+    self.stats.thms = self.pos - start;
+    let start = self.pos;
     let vec = &mut vec![];
     let mut index_temp = if index {
       Some(IndexTemp {
         sort_names: Vec::with_capacity(num_sorts),
         term_names: Vec::with_capacity(num_terms),
         thm_names: Vec::with_capacity(num_thms),
+        source_map: source_map.then(Vec::new),
       })
     } else { None };
     for s in self.env.stmts() {
@@ -551,7 +926,11 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
           write_cmd_bytes(self, STMT_SORT, &[])?
         }
         StmtTrace::Decl(a) => {
-          match self.env.data()[a].decl().expect("expected a term/thm") {
+          let k = self.env.data()[a].decl().expect("expected a term/thm");
+          if !self.ids.contains(k) {continue}
+          let p_start = self.pos;
+          let (mut sm, mut p_buf) = (NodeSpans::default(), 0);
+          match k {
             DeclKey::Term(t) => {
               let td = self.env.term(t);
               let vars = &mut index_temp.as_mut().map(|temp| {
@@ -569,10 +948,11 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
                   #[allow(clippy::cast_possible_truncation)] // no truncation
                   let nargs = td.args.len() as u32;
                   let mut reorder = Reorder::new(nargs, heap.len(), |i| i);
-                  write_expr_proof(vec, heap, &mut reorder, vars, head, false)?;
+                  write_expr_proof(vec, &self.ids, heap, &mut reorder, vars, head, false)?;
                   vec.write_u8(0)?;
+                  if optimize { optimize_stream(vec, nargs)? }
                   let cmd = STMT_DEF | if td.vis == Modifiers::LOCAL {STMT_LOCAL} else {0};
-                  write_cmd_bytes(self, cmd, vec)?;
+                  self.write_decl(a, cmd, vec)?;
                   vec.clear();
                 }
               }
@@ -595,56 +975,86 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
                 ThmKind::Axiom | ThmKind::Thm(None) => {
                   let mut reorder = Reorder::new(nargs, td.heap.len(), |i| i);
                   for (_, h) in &*td.hyps {
-                    write_expr_proof(vec, &td.heap, &mut reorder, vars, h, false)?;
+                    write_expr_proof(vec, &self.ids, &td.heap, &mut reorder, vars, h, false)?;
                     ProofCmd::Hyp.write_to(vec)?;
                   }
-                  write_expr_proof(vec, &td.heap, &mut reorder, vars, &td.ret, false)?;
+                  write_expr_proof(vec, &self.ids, &td.heap, &mut reorder, vars, &td.ret, false)?;
                   if let ThmKind::Axiom = td.kind {
                     STMT_AXIOM
                   } else {
+                    if !allow_sorry {
+                      return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                        "theorem {} contains sorry (use --allow-sorry to export it)",
+                        self.env.data()[td.atom].name())))
+                    }
                     ProofCmd::Sorry.write_to(vec)?;
                     (self.report)(ErrorLevel::Warning, &format!(
                       "theorem {} contains sorry", self.env.data()[td.atom].name()));
                     STMT_THM | if td.vis == Modifiers::PUB {0} else {STMT_LOCAL}
                   }
                 }
-                ThmKind::Thm(Some(Proof {heap, hyps, head})) => {
+                ThmKind::Thm(Some(pf @ Proof {heap, hyps, head, ..})) => {
+                  if index_temp.as_ref().map_or(false, |temp| temp.source_map.is_some()) &&
+                    !optimize { sm = NodeSpans::new(pf) }
                   let mut reorder = Reorder::new(nargs, heap.len(), |i| i);
                   let mut ehyps = Vec::with_capacity(hyps.len());
+                  let w = &mut ProofBuf {buf: vec, sm: &mut sm};
                   for h in &**hyps {
                     let e = match h.deref(heap) {
                       ProofNode::Hyp(_, ref e) => &**e,
                       _ => unreachable!()
                     };
-                    self.write_proof(vec, heap, &mut reorder, &ehyps, e, false)?;
-                    ProofCmd::Hyp.write_to(vec)?;
+                    self.write_proof(w, heap, &mut reorder, &ehyps, e, false)?;
+                    ProofCmd::Hyp.write_to(w)?;
                     ehyps.push(reorder.idx);
                     reorder.idx += 1;
                   }
-                  self.write_proof(vec, heap, &mut reorder, &ehyps, head, false)?;
+                  self.write_proof(w, heap, &mut reorder, &ehyps, head, false)?;
                   STMT_THM | if td.vis == Modifiers::PUB {0} else {STMT_LOCAL}
                 }
               };
               vec.write_u8(0)?;
-              write_cmd_bytes(self, cmd, vec)?;
+              if optimize { optimize_stream(vec, nargs)? }
+              self.write_decl(a, cmd, vec)?;
+              // the buffer is at the end of the declaration just written
+              p_buf = self.pos - vec.len() as u64;
               vec.clear();
             }
           }
+          if let Some(source_map) = index_temp.as_mut().and_then(|temp| temp.source_map.as_mut()) {
+            let span = match k {
+              DeclKey::Term(t) => self.env.term(t).full,
+              DeclKey::Thm(t) => self.env.thm(t).full,
+            };
+            source_map.push(SourceData {p_start, p_end: self.pos, decl: k, span});
+            // Sort the nested entries by start position, with the outer ranges first.
+            // If several nodes generated the same range, keep the outermost one.
+            sm.ranges.reverse();
+            sm.ranges.sort_by_key(|&(start, end, _)| (start, Reverse(end)));
+            sm.ranges.dedup_by_key(|&mut (start, end, _)| (start, end));
+            source_map.extend(sm.ranges.drain(..).map(|(start, end, span)| SourceData {
+              p_start: p_buf + start as u64, p_end: p_buf + end as u64, decl: k, span
+            }))
+          }
         }
         StmtTrace::Global(_) |
         StmtTrace::OutputString(_) => {}
       }
     }
     self.write_u8(0)?;
+    self.stats.proofs = self.pos - start;
 
     // debugging index
-    if let Some(IndexTemp { mut sort_names, mut term_names, mut thm_names }) = index_temp {
+    if let Some(IndexTemp {
+      mut sort_names, mut term_names, mut thm_names, source_map
+    }) = index_temp {
       assert_eq!(sort_names.len(), num_sorts);
       assert_eq!(term_names.len(), num_terms);
       assert_eq!(thm_names.len(), num_thms);
+      let start = self.pos;
 
       let mut atom_pos = AtomVec(self.env.data().enum_iter().map(|(_, ad)| -> io::Result<_> {
-        if ad.sort().is_some() || ad.decl().is_some() {
+        if ad.sort().is_some() || ad.decl().map_or(false, |k| self.ids.contains(k)) {
           let pos = self.pos;
           self.write_str(ad.name())?;
           Ok(pos)
@@ -683,14 +1093,41 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
       let p_hyps = self.pos;
       for (_, hs) in &thm_names { self.write_u64(hs.p_vars)? }
 
+      let mut index =
+        vec![(INDEX_NAME, p_names), (INDEX_VAR_NAME, p_vars), (INDEX_HYP_NAME, p_hyps)];
+      if let Some(source_map) = source_map {
+        let env = self.env;
+        let spans = source_map.iter().map(|sd| match sd.decl {
+          DeclKey::Term(t) => (&env.term(t).span.file, sd.span),
+          DeclKey::Thm(t) => (&env.thm(t).span.file, sd.span),
+        }).collect::<Vec<_>>();
+        let mut file_pos = HashMap::new();
+        for &(file, _) in &spans {
+          if let Entry::Vacant(e) = file_pos.entry(file) {
+            e.insert(self.pos);
+            self.write_str(file.rel().as_bytes())?;
+          }
+        }
+        self.align_to(8)?;
+        index.push((INDEX_SOURCE_MAP, self.pos));
+        self.write_u64(source_map.len() as u64)?;
+        for (sd, (file, span)) in source_map.iter().zip(spans) {
+          self.write_u64(sd.p_start)?;
+          self.write_u64(sd.p_end)?;
+          self.write_u64(file_pos[file])?;
+          self.write_u64(span.start as u64)?;
+          self.write_u64(span.end as u64)?;
+        }
+      }
+
       p_index.commit(self);
-      let index = [(INDEX_NAME, p_names), (INDEX_VAR_NAME, p_vars), (INDEX_HYP_NAME, p_hyps)];
       self.write_u64(index.len() as u64)?;
       for (name, ptr) in &index {
         self.write_all(name)?;
         self.write_u32(0)?;
         self.write_u64(*ptr)?;
       }
+      self.stats.index = self.pos - start;
     } else {
       p_index.cancel();
       self.write_u32(0)?; // padding
@@ -699,8 +1136,9 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
   }
 
   /// Finalize the outstanding fixups, and flush the writer. Consumes self since we're done.
-  pub fn finish(self) -> io::Result<()> {
-    let Self {mut w, fixups, ..} = self;
+  /// Returns statistics about the generated file, if they were requested.
+  fn finish(self) -> io::Result<Option<ExportStats>> {
+    let Self {mut w, fixups, env, mut stats, mut proof_sizes, opts, ..} = self;
     for (pos, f) in fixups {
       w.seek(SeekFrom::Start(pos))?;
       match f {
@@ -709,6 +1147,11 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
         Value::Box(buf) => w.write_all(&buf)?,
       }
     }
-    w.flush()
+    w.flush()?;
+    if !opts.stats { return Ok(None) }
+    proof_sizes.sort_unstable_by_key(|p| std::cmp::Reverse(p.0));
+    stats.largest_proofs = proof_sizes.into_iter().take(NUM_LARGEST_PROOFS)
+      .map(|(size, a)| (env.data()[a].name().clone(), size)).collect();
+    Ok(Some(stats))
   }
 }
\ No newline at end of file
@@ -0,0 +1,268 @@
+//! MM0 exporter, which produces the `.mm0` specification file corresponding to an
+//! [`Environment`](crate::Environment) object.
+//!
+//! The output contains only the parts of the environment that belong in a specification:
+//! sorts, term constructors, non-local definitions (without bodies if they are `abstract`
+//! or refer to local definitions),
+//! axioms, and the statements of `pub` theorems, along with the notations and coercions
+//! that are needed to read them. Math expressions are printed using the same notation-aware
+//! pretty printer as the rest of the system, so the result parses back to the same terms.
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use crate::{Type, Expr, AtomId, SortId, TermId, TermKind, ThmKind, ExprNode, StmtTrace,
+  DeclKey, Modifiers, Literal, Prec, FrozenEnv, LinedString, LispVal, FormatEnv};
+use super::is_local_thm;
+
+/// The line width used when pretty-printing math expressions.
+const PP_WIDTH: usize = 100;
+
+fn invalid(msg: String) -> io::Error { io::Error::new(io::ErrorKind::InvalidInput, msg) }
+
+/// Get the name of `a`, which is an error if it is a qualified name like `foo.x`, declared
+/// in a namespace, since these are not valid MM0 identifiers.
+fn mm0_name(env: &FrozenEnv, a: AtomId) -> io::Result<&str> {
+  let name = env.data()[a].name().as_str();
+  if name.contains('.') {
+    return Err(invalid(format!("{}: qualified names cannot be written in MM0", name)))
+  }
+  Ok(name)
+}
+
+fn write_deps(w: &mut impl Write, bvars: &[&str], vs: u64) -> io::Result<()> {
+  for (i, x) in bvars.iter().enumerate() {
+    if vs & (1 << i) != 0 { write!(w, " {}", x)? }
+  }
+  Ok(())
+}
+
+/// Get the names of the binders `args` of a term or theorem. Unnamed binders are given fresh
+/// names like `x1`, which are not the name of anything else in the environment, so that
+/// notations and the dependencies of later binders can refer to them.
+fn binder_names<'a>(env: &'a FrozenEnv, args: &[(Option<AtomId>, Type)]) -> Vec<Cow<'a, str>> {
+  let mut n = 0;
+  args.iter().map(|&(a, _)| match a {
+    Some(a) => env.data()[a].name().as_str().into(),
+    None => loop {
+      n += 1;
+      let x = format!("x{}", n);
+      if env.get_atom(x.as_bytes()).is_none() { break x.into() }
+    }
+  }).collect()
+}
+
+/// Write the binder list of a term or theorem, like ` {x: set} (ph: wff x)`, where the
+/// binders are named `names`. Returns the names of the bound variables, for use in the
+/// return type.
+fn write_binders<'a>(env: &FrozenEnv, w: &mut impl Write,
+    args: &[(Option<AtomId>, Type)], names: &'a [Cow<'_, str>]) -> io::Result<Vec<&'a str>> {
+  let mut bvars = vec![];
+  for (&(_, ty), x) in args.iter().zip(names) {
+    let x = &**x;
+    match ty {
+      Type::Bound(s) => {
+        bvars.push(x);
+        write!(w, " {{{}: {}}}", x, env.sort(s).name)?
+      }
+      Type::Reg(s, vs) => {
+        write!(w, " ({}: {}", x, env.sort(s).name)?;
+        write_deps(w, &bvars, vs)?;
+        write!(w, ")")?
+      }
+    }
+  }
+  Ok(bvars)
+}
+
+/// Collect the dummy variables of an expression, sorted by name.
+fn dummies(env: &FrozenEnv, heap: &[ExprNode], head: &ExprNode) -> Vec<(AtomId, SortId)> {
+  fn go(e: &ExprNode, ds: &mut HashMap<AtomId, SortId>) {
+    match *e {
+      ExprNode::Ref(_) => {}
+      ExprNode::Dummy(a, s) => { ds.insert(a, s); }
+      ExprNode::App(_, ref es) => for e in &**es { go(e, ds) }
+    }
+  }
+  let mut ds = HashMap::new();
+  for e in heap.iter().chain(Some(head)) { go(e, &mut ds) }
+  let mut ds = ds.into_iter().collect::<Vec<_>>();
+  ds.sort_by_key(|&(a, _)| &**env.data()[a].name());
+  ds
+}
+
+/// Get the names of the `local def`s that the expressions `es` refer to.
+fn local_refs<'b>(env: &FrozenEnv, es: impl Iterator<Item=&'b ExprNode>) -> Vec<String> {
+  let mut deps = vec![];
+  for e in es {
+    e.for_each_term(&mut |t| if env.term(t).vis == Modifiers::LOCAL {
+      deps.push(env.data()[env.term(t).atom].name().to_string())
+    })
+  }
+  deps
+}
+
+/// Check that the expressions `es` in the statement of `a` do not refer to any `local def`.
+fn check_refs<'b>(env: &FrozenEnv, a: AtomId, es: impl Iterator<Item=&'b ExprNode>) -> io::Result<()> {
+  let mut deps = local_refs(env, es);
+  if deps.is_empty() { return Ok(()) }
+  deps.sort_unstable();
+  deps.dedup();
+  Err(invalid(format!("{} refers to local definitions, which are omitted from the spec: {}",
+    env.data()[a].name(), deps.join(", "))))
+}
+
+/// Pretty-print the expressions `es` (which live in the context of `heap`),
+/// each as a math string `$ e $`, separated by `sep`.
+fn write_exprs<'b>(fe: FormatEnv<'_>, w: &mut impl Write, args: &[(Option<AtomId>, Type)],
+    heap: &[ExprNode], es: impl Iterator<Item=&'b ExprNode>, sep: &str) -> io::Result<()> {
+  let mut vals: Vec<LispVal> = vec![];
+  fe.binders(args, &mut vals, &mut vec![]);
+  for e in &heap[args.len()..] {
+    let e = fe.expr_node(&vals, &mut None, e);
+    vals.push(e)
+  }
+  for (i, e) in es.enumerate() {
+    if i != 0 { write!(w, "{}", sep)? }
+    let e = fe.expr_node(&vals, &mut None, e);
+    fe.pretty(|p| p.expr(&e).render(PP_WIDTH, w))?
+  }
+  Ok(())
+}
+
+impl FrozenEnv {
+  /// Write the notations and coercions associated to term `t`, which has just been written.
+  /// This fails if the term has a generalized infix notation or a left associative
+  /// notation, because these cannot be expressed in MM0.
+  fn write_mm0_notations(&self, w: &mut impl Write, t: TermId) -> io::Result<()> {
+    let pe = self.pe();
+    let (coe, fix) = if let Some(n) = pe.decl_nota.get(&t) {n} else {return Ok(())};
+    let td = self.term(t);
+    let name = self.data()[td.atom].name();
+    for (tk, infx) in fix {
+      let prec = pe.consts[tk].1;
+      if *infx {
+        let info = &pe.infixes[tk];
+        match (&*info.lits, info.rassoc) {
+          ([Literal::Var(0, _), Literal::Const(c), Literal::Var(1, _)], Some(r)) if c == tk =>
+            writeln!(w, "{} {}: ${}$ prec {};", if r {"infixr"} else {"infixl"}, name, tk, prec)?,
+          _ => return Err(invalid(format!(
+            "{}: generalized infix notations cannot be written in MM0", name)))
+        }
+        continue
+      }
+      let info = &pe.prefixes[tk];
+      let simple = info.lits.len() == info.nargs &&
+        info.lits.iter().enumerate().all(|(i, lit)| matches!(*lit,
+          Literal::Var(j, q) if i == j && q == if i + 1 == info.nargs {prec} else {Prec::Max}));
+      if simple {
+        writeln!(w, "prefix {}: ${}$ prec {};", name, tk, prec)?;
+        continue
+      }
+      if info.rassoc == Some(false) {
+        return Err(invalid(format!(
+          "{}: left associative notations cannot be written in MM0", name)))
+      }
+      write!(w, "notation {}", name)?;
+      let names = binder_names(self, &td.args);
+      let bvars = write_binders(self, w, &td.args, &names)?;
+      write!(w, ": {}", self.sort(td.ret.0).name)?;
+      write_deps(w, &bvars, td.ret.1)?;
+      write!(w, " = (${}$:{})", tk, prec)?;
+      for lit in &info.lits {
+        match lit {
+          &Literal::Var(i, _) => write!(w, " {}", names[i])?,
+          Literal::Const(c) => write!(w, " (${}$:{})", c, pe.consts[c].1)?,
+        }
+      }
+      writeln!(w, ";")?;
+    }
+    if *coe {
+      let s1 = match td.args[0].1 { Type::Bound(s) | Type::Reg(s, _) => s };
+      writeln!(w, "coercion {}: {} > {};", name, self.sort(s1).name, self.sort(td.ret.0).name)?;
+    }
+    Ok(())
+  }
+
+  /// Write the MM0 specification of this environment to `w`. This includes sorts,
+  /// term constructors, non-`local` definitions, axioms, and the statements of `pub` theorems,
+  /// in declaration order, with the notations for each term following its declaration.
+  /// Definitions whose body refers to a `local def` are written without the body.
+  ///
+  /// It is an error for an exported statement to refer to a `local def`, since it
+  /// would not be available to the reader of the specification, and for an exported
+  /// declaration to have a qualified name (declared in a `namespace`).
+  pub fn export_mm0(&self, mut w: impl Write) -> io::Result<()> {
+    let w = &mut w;
+    let source = LinedString::default();
+    // Safety: the environment is only read, and the `FormatEnv` does not outlive this call.
+    let fe = unsafe { self.format_env(&source) };
+    let pe = self.pe();
+    let (mut both, mut left, mut right) = (vec![], vec![], vec![]);
+    for c in 0..=u8::MAX {
+      match (pe.delims_l.get(c), pe.delims_r.get(c)) {
+        (true, true) => both.push(c),
+        (true, false) => left.push(c),
+        (false, true) => right.push(c),
+        (false, false) => {}
+      }
+    }
+    let delims = |cs: Vec<u8>| cs.into_iter().map(|c| format!(" {}", c as char)).collect::<String>();
+    if !both.is_empty() { writeln!(w, "delimiter ${} $;", delims(both))? }
+    if !left.is_empty() || !right.is_empty() {
+      writeln!(w, "delimiter ${} $ ${} $;", delims(left), delims(right))?
+    }
+    for s in self.stmts() {
+      match *s {
+        StmtTrace::Sort(a) => {
+          let ad = &self.data()[a];
+          let mods = self.sort(ad.sort().expect("expected a sort")).mods;
+          writeln!(w, "\n{}sort {};", mods, ad.name())?;
+        }
+        StmtTrace::Decl(a) => match self.data()[a].decl().expect("expected a term/thm") {
+          DeclKey::Term(tid) => {
+            let td = self.term(tid);
+            if td.vis == Modifiers::LOCAL { continue }
+            write!(w, "\n{} {}",
+              if matches!(td.kind, TermKind::Term) {"term"} else {"def"}, mm0_name(self, a)?)?;
+            let names = binder_names(self, &td.args);
+            let bvars = write_binders(self, w, &td.args, &names)?;
+            let body = match &td.kind {
+              // A definition which refers to local definitions is written without its body,
+              // so the proof file is free to define it.
+              TermKind::Def(Some(Expr {heap, head})) if !td.vis.contains(Modifiers::ABSTRACT) &&
+                  local_refs(self, heap.iter().chain(Some(head))).is_empty() => {
+                for (x, s) in dummies(self, heap, head) {
+                  write!(w, " {{.{}: {}}}", self.data()[x].name(), self.sort(s).name)?
+                }
+                Some((heap, head))
+              }
+              _ => None
+            };
+            write!(w, ": {}", self.sort(td.ret.0).name)?;
+            write_deps(w, &bvars, td.ret.1)?;
+            if let Some((heap, head)) = body {
+              write!(w, " =\n  ")?;
+              write_exprs(fe, w, &td.args, heap, Some(head).into_iter(), "")?;
+            }
+            writeln!(w, ";")?;
+            self.write_mm0_notations(w, tid)?;
+          }
+          DeclKey::Thm(tid) => {
+            let td = self.thm(tid);
+            if is_local_thm(td) { continue }
+            check_refs(self, a, td.heap.iter().chain(td.hyps.iter().map(|h| &h.1)).chain(Some(&td.ret)))?;
+            write!(w, "\n{} {}",
+              if matches!(td.kind, ThmKind::Axiom) {"axiom"} else {"theorem"}, mm0_name(self, a)?)?;
+            write_binders(self, w, &td.args, &binder_names(self, &td.args))?;
+            write!(w, ":\n  ")?;
+            write_exprs(fe, w, &td.args, &td.heap,
+              td.hyps.iter().map(|h| &h.1).chain(Some(&td.ret)), " >\n  ")?;
+            writeln!(w, ";")?;
+          }
+        }
+        StmtTrace::Global(_) | StmtTrace::OutputString(_) => {}
+      }
+    }
+    Ok(())
+  }
+}
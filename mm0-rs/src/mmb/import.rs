@@ -362,7 +362,7 @@ fn parse_proof(
   }.as_proof(pos)?.0;
   let (mut ids, heap) = build(&st.de);
   let hyps = st.hyps.into_iter().map(|i| ids[i].take()).collect();
-  Ok(Proof {heap, hyps, head: ids[ret].take()})
+  Ok(Proof {heap, hyps, head: ids[ret].take(), spans: Box::new([])})
 }
 
 fn parse(fref: &FileRef, buf: &[u8], env: &mut Environment) -> Result<()> {
@@ -0,0 +1,280 @@
+//! MMB linker, which merges several `.mmb` files into one.
+//!
+//! The input files are expected to be the result of separately compiling components of a
+//! library, so they will usually share a common prefix of sorts, terms and theorems
+//! (the shared dependencies of the components). Declarations are matched up by name,
+//! so every input must contain the name index. A declaration that appears in more than one
+//! input is only written once, and it is an error if the two copies have different
+//! statements. (If two theorems have the same statement but different proofs,
+//! the first proof is used.) All other declarations are appended to the output in order,
+//! with [`SortId`]s, [`TermId`]s and [`ThmId`]s renumbered to match the output file.
+//!
+//! ```text
+//! mm0-rs link -o out.mmb a.mmb b.mmb
+//! ```
+use std::collections::{HashMap, hash_map::Entry};
+use std::{fmt, fs, io};
+use std::io::Write;
+use clap::ArgMatches;
+use zerocopy::AsBytes;
+use crate::{SortId, SortVec, TermId, TermVec, ThmId, ThmVec};
+use crate::compiler::FileContents;
+use mm0b_parser::{Arg, BasicMmbFile, HasSymbolNames, Mm0Writer, NumdStmtCmd, ParseError,
+  ProofCmd, ProofIter, SortData, UnifyCmd, UnifyIter, exhausted};
+
+/// An error produced while joining MMB files. Input files are identified by their
+/// position in the list of inputs.
+#[derive(Debug)]
+pub enum JoinError {
+  /// An input file is malformed.
+  Parse(usize, ParseError),
+  /// An input file does not have a name for some declaration, so it cannot be matched
+  /// against the declarations in other files.
+  NoIndex(usize),
+  /// A declaration with the given name was already added from a previous file,
+  /// with a different statement.
+  Conflict(usize, String),
+  /// An IO error while writing the output.
+  Io(io::Error),
+}
+
+impl fmt::Display for JoinError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      JoinError::Parse(i, e) => write!(f, "input {}: {}", i, e),
+      JoinError::NoIndex(i) => write!(f, "input {}: missing name index", i),
+      JoinError::Conflict(i, name) =>
+        write!(f, "input {}: declaration '{}' conflicts with a previous declaration", i, name),
+      JoinError::Io(e) => write!(f, "{}", e),
+    }
+  }
+}
+
+impl From<io::Error> for JoinError {
+  fn from(e: io::Error) -> Self { Self::Io(e) }
+}
+
+impl From<JoinError> for io::Error {
+  fn from(e: JoinError) -> Self {
+    match e {
+      JoinError::Io(e) => e,
+      e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+    }
+  }
+}
+
+/// A declaration in the output file.
+#[derive(Copy, Clone, Debug)]
+enum Decl {
+  Term(TermId),
+  Thm(ThmId),
+}
+
+/// The renumbering from the IDs in one of the input files to the IDs in the output file.
+#[derive(Default, Debug)]
+struct Remap {
+  sorts: SortVec<SortId>,
+  terms: TermVec<TermId>,
+  thms: ThmVec<ThmId>,
+}
+
+impl Remap {
+  fn sort(&self, s: SortId, pos: usize) -> Result<SortId, ParseError> {
+    self.sorts.get(s).copied().ok_or(ParseError::StrError("unknown sort", pos))
+  }
+
+  fn term(&self, t: TermId, pos: usize) -> Result<TermId, ParseError> {
+    self.terms.get(t).copied().ok_or(ParseError::StrError("unknown term", pos))
+  }
+
+  fn thm(&self, t: ThmId, pos: usize) -> Result<ThmId, ParseError> {
+    self.thms.get(t).copied().ok_or(ParseError::StrError("unknown theorem", pos))
+  }
+
+  /// Renumber the sort in a binder.
+  fn arg(&self, mut a: Arg, pos: usize) -> Result<Arg, ParseError> {
+    a.add_sort(self.sort(a.sort(), pos)?);
+    Ok(a)
+  }
+
+  /// Renumber a unify stream, writing the result to `w` (without the final `END` command).
+  fn unify(&self, mut it: UnifyIter<'_>, w: &mut impl Write) -> Result<(), ParseError> {
+    let mut pos = it.pos;
+    while let Some(cmd) = it.next() {
+      match cmd? {
+        UnifyCmd::Term {tid, save} => UnifyCmd::Term {tid: self.term(tid, pos)?, save},
+        UnifyCmd::Dummy(s) => UnifyCmd::Dummy(self.sort(s, pos)?),
+        cmd @ (UnifyCmd::Ref(_) | UnifyCmd::Hyp) => cmd,
+      }.write_to(w)?;
+      pos = it.pos;
+    }
+    Ok(())
+  }
+
+  /// Renumber a proof stream, writing the result to `w` (without the final `END` command).
+  fn proof(&self, mut it: ProofIter<'_>, w: &mut impl Write) -> Result<(), ParseError> {
+    if it.is_null() { return Ok(()) }
+    let mut pos = it.pos;
+    while let Some(cmd) = it.next() {
+      match cmd? {
+        ProofCmd::Term {tid, save} => ProofCmd::Term {tid: self.term(tid, pos)?, save},
+        ProofCmd::Thm {tid, save} => ProofCmd::Thm {tid: self.thm(tid, pos)?, save},
+        ProofCmd::Dummy(s) => ProofCmd::Dummy(self.sort(s, pos)?),
+        cmd => cmd,
+      }.write_to(w)?;
+      pos = it.pos;
+    }
+    Ok(())
+  }
+}
+
+/// Running data for the MMB join process.
+#[derive(Debug)]
+struct Joiner {
+  /// The output file under construction.
+  out: Mm0Writer<Vec<u8>>,
+  /// The sorts that have been added so far, by name.
+  sorts: HashMap<String, (SortId, u8)>,
+  /// The terms and theorems that have been added so far, by name, along with a
+  /// "signature" that determines whether two declarations are compatible:
+  /// the declaration command, the binders, and the renumbered unify stream.
+  decls: HashMap<String, (Decl, Vec<u8>)>,
+}
+
+impl Joiner {
+  fn new() -> Self {
+    Self { out: Mm0Writer::new(vec![]), sorts: HashMap::new(), decls: HashMap::new() }
+  }
+
+  /// Add all the declarations from file `i` (with contents `file`) to the output.
+  fn add_file(&mut self, i: usize, file: &BasicMmbFile<'_>) -> Result<(), JoinError> {
+    let parse = |e| JoinError::Parse(i, e);
+    if file.index.get_symbol_names().is_none() { return Err(JoinError::NoIndex(i)) }
+    let mut remap = Remap::default();
+    let mut it = file.proof();
+    let mut pos = it.pos;
+    while let Some(decl) = it.next() {
+      let (stmt, pf) = decl.map_err(parse)?;
+      match stmt {
+        NumdStmtCmd::Sort {sort_id} => {
+          let name = file.try_sort_name(sort_id).ok_or(JoinError::NoIndex(i))?;
+          let data = file.sort(sort_id).ok_or_else(|| parse(exhausted!()))?.0;
+          let s = match self.sorts.entry(name.into()) {
+            Entry::Occupied(e) => {
+              let &(s, data2) = e.get();
+              if data != data2 { return Err(JoinError::Conflict(i, name.into())) }
+              s
+            }
+            Entry::Vacant(e) => e.insert((self.out.add_sort(Some(name), SortData(data))?, data)).0,
+          };
+          remap.sorts.push(s);
+        }
+        NumdStmtCmd::TermDef {term_id, local} => {
+          let name = file.try_term_name(term_id).ok_or(JoinError::NoIndex(i))?;
+          let td = file.term(term_id).ok_or_else(|| parse(ParseError::StrError("unknown term", pos)))?;
+          let args = td.args().iter().map(|&a| remap.arg(a, pos)).collect::<Result<Vec<_>, _>>()
+            .map_err(parse)?;
+          let ret = remap.arg(td.ret(), pos).map_err(parse)?;
+          let mut unify = vec![];
+          if td.def() { remap.unify(td.unify(), &mut unify).map_err(parse)? }
+          let sig = signature(u8::from(td.def()) << 1 | u8::from(local), &args, Some(ret), &unify);
+          let t = match self.decls.entry(name.into()) {
+            Entry::Occupied(e) => match *e.get() {
+              (Decl::Term(t), ref sig2) if *sig2 == sig => t,
+              _ => return Err(JoinError::Conflict(i, name.into())),
+            },
+            Entry::Vacant(e) => {
+              let t = if td.def() {
+                let mut b = self.out.add_def(local, Some(name), &args, ret);
+                b.unify().write_all(&unify)?;
+                remap.proof(pf, b.proof()).map_err(parse)?;
+                b.finish()?
+              } else {
+                self.out.add_term(Some(name), &args, ret)?
+              };
+              e.insert((Decl::Term(t), sig));
+              t
+            }
+          };
+          remap.terms.push(t);
+        }
+        NumdStmtCmd::Axiom {thm_id} | NumdStmtCmd::Thm {thm_id, ..} => {
+          let name = file.try_thm_name(thm_id).ok_or(JoinError::NoIndex(i))?;
+          let td = file.thm(thm_id).ok_or_else(|| parse(ParseError::StrError("unknown theorem", pos)))?;
+          let args = td.args().iter().map(|&a| remap.arg(a, pos)).collect::<Result<Vec<_>, _>>()
+            .map_err(parse)?;
+          let mut unify = vec![];
+          remap.unify(td.unify(), &mut unify).map_err(parse)?;
+          let kind = match stmt {
+            NumdStmtCmd::Thm {local, ..} => 4 | u8::from(local),
+            _ => 8,
+          };
+          let sig = signature(kind, &args, None, &unify);
+          let t = match self.decls.entry(name.into()) {
+            Entry::Occupied(e) => match *e.get() {
+              (Decl::Thm(t), ref sig2) if *sig2 == sig => t,
+              _ => return Err(JoinError::Conflict(i, name.into())),
+            },
+            Entry::Vacant(e) => {
+              let mut b = match stmt {
+                NumdStmtCmd::Thm {local, ..} => self.out.add_thm(local, Some(name), &args),
+                _ => self.out.add_axiom(Some(name), &args),
+              };
+              b.unify().write_all(&unify)?;
+              remap.proof(pf, b.proof()).map_err(parse)?;
+              let t = b.finish()?;
+              e.insert((Decl::Thm(t), sig));
+              t
+            }
+          };
+          remap.thms.push(t);
+        }
+      }
+      pos = it.pos;
+    }
+    Ok(())
+  }
+}
+
+/// Construct the signature of a declaration, used to test whether two declarations
+/// with the same name are the same.
+fn signature(kind: u8, args: &[Arg], ret: Option<Arg>, unify: &[u8]) -> Vec<u8> {
+  let mut sig = vec![kind];
+  sig.extend_from_slice(args.as_bytes());
+  if let Some(ret) = ret { sig.extend_from_slice(ret.as_bytes()) }
+  sig.extend_from_slice(unify);
+  sig
+}
+
+/// Join the MMB files `files` into a single MMB file, which is written to `w`.
+/// See the [module documentation](self) for details.
+///
+/// The output contains only the name index; variable and hypothesis names are not preserved.
+pub fn join(files: &[BasicMmbFile<'_>], w: &mut impl Write) -> Result<(), JoinError> {
+  let mut joiner = Joiner::new();
+  for (i, file) in files.iter().enumerate() { joiner.add_file(i, file)? }
+  Ok(joiner.out.finish(w)?)
+}
+
+/// Main entry point for `mm0-rs link` subcommand.
+///
+/// See the [module documentation](self) for the purpose of this command.
+///
+/// # Arguments
+///
+/// `mm0-rs link -o <out.mmb> <in.mmb>...`, where:
+///
+/// - `out.mmb` is the output location
+/// - `in.mmb` are the files to join, in order
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let contents = args.values_of_os("INPUT").expect("required arg")
+    .map(|path| FileContents::new_bin_from_file(path.as_ref()))
+    .collect::<io::Result<Vec<_>>>()?;
+  let files = contents.iter().enumerate()
+    .map(|(i, fc)| BasicMmbFile::parse(fc).map_err(|e| JoinError::Parse(i, e).into()))
+    .collect::<io::Result<Vec<_>>>()?;
+  let out = args.value_of_os("OUTPUT").expect("required arg");
+  let mut w = io::BufWriter::new(fs::File::create(out)?);
+  join(&files, &mut w)?;
+  w.flush()
+}
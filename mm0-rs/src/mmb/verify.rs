@@ -0,0 +1,956 @@
+//! MMB verifier, which checks all the proofs in a `.mmb` file, and optionally checks that
+//! the file matches a `.mm0` specification.
+//!
+//! The proof checker is a port of the `mm0-c` verifier. It works directly on the binary file,
+//! and does not use the elaborator or the [`Environment`](crate::Environment), so it can be
+//! used to double check the output of `mm0-rs compile`. Expressions are stored without
+//! sharing, and two expressions are considered equal only if they are the same allocation,
+//! exactly as in `mm0-c`, so the proof stream is responsible for all sharing.
+//!
+//! If a specification is given, it is elaborated as usual, and then its statements are
+//! matched in order against the non-local statements of the binary file: sorts must have
+//! the same modifiers, terms and definitions the same binders, return type and
+//! (unless the specification omits it) definition body, and axioms and theorems the same
+//! binders, hypotheses and conclusion. If the file has a name index, the names must
+//! match as well.
+//!
+//! ```text
+//! mm0-rs verify file.mmb [file.mm0]
+//! ```
+use std::collections::HashMap;
+use std::{fmt, fs, io};
+use std::io::Read;
+use std::mem::size_of;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use clap::ArgMatches;
+use zerocopy::{AsBytes, U64};
+use mm0b_parser::{Arg, BasicMmbFile, Header, NumdStmtCmd, ParseError, ProofCmd, ProofIter,
+  StmtCmd, UnifyCmd, UnifyIter, TYPE_BOUND_MASK, TYPE_DEPS_MASK, cmd::{DATA_16, DATA_8, DATA_MASK},
+  parse_cmd, u32_as_usize};
+use crate::{AtomId, DeclKey, ErrorLevel, Expr, ExprNode, FileRef, FrozenEnv, Modifiers, SortId, SortVec,
+  StmtTrace, TermId, TermKind, TermVec, ThmId, ThmKind, Type};
+use crate::compiler::{FileContents, elab_for_result};
+use crate::diagnostics::{self, Diagnostic, ErrorFormat, Region};
+
+/// An error produced while verifying an MMB file.
+#[derive(Debug)]
+pub enum VerifyError {
+  /// The file is malformed.
+  Parse(ParseError),
+  /// A declaration failed to check. This contains the position of the declaration in the
+  /// file, its name if the file has a name index, and the reason for the failure.
+  Decl(usize, Option<String>, String),
+  /// The file ended before all the entries in the sort, term or theorem table were declared.
+  Incomplete(&'static str),
+  /// The file checks, but some theorems (listed here) use `sorry`.
+  Sorry(Vec<String>),
+  /// The file checks, but does not match the specification.
+  Spec(String),
+  /// An IO error while reading the file.
+  Io(io::Error),
+}
+
+impl fmt::Display for VerifyError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      VerifyError::Parse(e) => write!(f, "{}", e),
+      VerifyError::Decl(pos, Some(name), msg) => write!(f, "at {:#x}: '{}': {}", pos, name, msg),
+      VerifyError::Decl(pos, None, msg) => write!(f, "at {:#x}: {}", pos, msg),
+      VerifyError::Incomplete(what) => write!(f, "not all {} were declared", what),
+      VerifyError::Sorry(names) => write!(f, "some theorems used sorry: {}", names.join(", ")),
+      VerifyError::Spec(msg) => write!(f, "specification mismatch: {}", msg),
+      VerifyError::Io(e) => write!(f, "{}", e),
+    }
+  }
+}
+
+impl From<ParseError> for VerifyError {
+  fn from(e: ParseError) -> Self { Self::Parse(e) }
+}
+
+impl From<io::Error> for VerifyError {
+  fn from(e: io::Error) -> Self { Self::Io(e) }
+}
+
+impl From<VerifyError> for io::Error {
+  fn from(e: VerifyError) -> Self {
+    match e {
+      VerifyError::Io(e) => e,
+      e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+    }
+  }
+}
+
+/// Return early with a formatted error message if the condition is false.
+macro_rules! ensure {
+  ($cond:expr, $($e:tt)*) => { if !$cond { return Err(format!($($e)*)) } }
+}
+
+/// An expression in the store: either a variable or a term applied to arguments.
+#[derive(Debug)]
+struct StoreExpr {
+  /// The type of the expression, in the same format as an [`Arg`]. For terms,
+  /// the dependencies are the variables that (may) occur in the expression.
+  ty: u64,
+  /// The head of the expression, or `None` for a variable.
+  app: Option<(TermId, Box<[usize]>)>,
+}
+
+/// An element of the main stack or heap. The `usize` values are indices into the store.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum StackEl {
+  /// An expression `e`
+  Expr(usize),
+  /// A proof of `|- e`
+  Proof(usize),
+  /// A proof of `e1 = e2`
+  Conv(usize, usize),
+  /// A convertibility obligation `e1 =?= e2`
+  CoConv(usize, usize),
+}
+
+/// The kind of proof stream being checked.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ProofMode {
+  /// A definition body
+  Def,
+  /// A theorem proof, or an axiom statement
+  Thm,
+}
+
+/// The kind of unify stream being checked.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum UnifyMode {
+  /// A definition body, either in the declaration or in an `Unfold` step
+  Def,
+  /// A theorem application
+  Thm,
+  /// A theorem statement, in the declaration
+  ThmEnd,
+}
+
+/// Returns true if an expression of type `from` can be substituted for a variable of type `to`.
+fn sorts_compatible(from: u64, to: u64) -> bool {
+  let diff = from ^ to;
+  diff & !TYPE_DEPS_MASK == 0 ||
+  (diff & !TYPE_BOUND_MASK & !TYPE_DEPS_MASK == 0 && from & TYPE_BOUND_MASK != 0)
+}
+
+/// The state of the proof checker.
+struct Verifier<'a> {
+  file: &'a BasicMmbFile<'a>,
+  /// The number of sorts declared so far.
+  num_sorts: usize,
+  /// The number of terms declared so far.
+  num_terms: u32,
+  /// The number of theorems declared so far.
+  num_thms: u32,
+  /// The expressions allocated in the current declaration.
+  store: Vec<StoreExpr>,
+  heap: Vec<StackEl>,
+  stack: Vec<StackEl>,
+  /// The hypotheses of the current theorem.
+  hstack: Vec<usize>,
+  uheap: Vec<usize>,
+  ustack: Vec<usize>,
+  /// The bit for the next bound variable.
+  next_bv: u64,
+  /// True if the current declaration uses `sorry`.
+  uses_sorry: bool,
+  /// The names (or positions) of the declarations that use `sorry`.
+  sorry: Vec<String>,
+}
+
+impl<'a> Verifier<'a> {
+  fn new(file: &'a BasicMmbFile<'a>) -> Self {
+    Self {
+      file, num_sorts: 0, num_terms: 0, num_thms: 0,
+      store: vec![], heap: vec![], stack: vec![], hstack: vec![], uheap: vec![], ustack: vec![],
+      next_bv: 1, uses_sorry: false, sorry: vec![],
+    }
+  }
+
+  fn sort_mods(&self, s: SortId) -> Result<Modifiers, String> {
+    ensure!(usize::from(s.0) < self.num_sorts, "bad sort {}", s.0);
+    Ok(Modifiers::new(self.file.sorts[usize::from(s.0)].0))
+  }
+
+  fn alloc(&mut self, ty: u64, app: Option<(TermId, Box<[usize]>)>) -> usize {
+    self.store.push(StoreExpr {ty, app});
+    self.store.len() - 1
+  }
+
+  fn pop(&mut self) -> Result<StackEl, String> {
+    self.stack.pop().ok_or_else(|| "stack underflow".into())
+  }
+
+  fn pop_expr(&mut self) -> Result<usize, String> {
+    match self.pop()? {
+      StackEl::Expr(e) => Ok(e),
+      el => Err(format!("expected an expression, got {:?}", el)),
+    }
+  }
+
+  fn pop_proof(&mut self) -> Result<usize, String> {
+    match self.pop()? {
+      StackEl::Proof(e) => Ok(e),
+      el => Err(format!("expected a proof, got {:?}", el)),
+    }
+  }
+
+  fn pop_conv(&mut self) -> Result<(usize, usize), String> {
+    match self.pop()? {
+      StackEl::Conv(e1, e2) => Ok((e1, e2)),
+      el => Err(format!("expected a conversion proof, got {:?}", el)),
+    }
+  }
+
+  fn pop_co_conv(&mut self) -> Result<(usize, usize), String> {
+    match self.pop()? {
+      StackEl::CoConv(e1, e2) => Ok((e1, e2)),
+      el => Err(format!("expected a convertibility obligation, got {:?}", el)),
+    }
+  }
+
+  /// Pop `n` expressions from the stack, in the order they were pushed.
+  fn pop_args(&mut self, n: usize) -> Result<Vec<usize>, String> {
+    ensure!(self.stack.len() >= n, "stack underflow");
+    self.stack.drain(self.stack.len() - n..).map(|el| match el {
+      StackEl::Expr(e) => Ok(e),
+      el => Err(format!("expected an expression, got {:?}", el)),
+    }).collect()
+  }
+
+  /// Reset the state for a new declaration, and put the arguments on the heap.
+  fn load_args(&mut self, args: &[Arg]) -> Result<(), String> {
+    self.store.clear();
+    self.heap.clear();
+    self.stack.clear();
+    self.hstack.clear();
+    self.next_bv = 1;
+    for &ty in args {
+      let mods = self.sort_mods(ty.sort())?;
+      let deps = ty.deps_unchecked();
+      if ty.bound() {
+        ensure!(!mods.contains(Modifiers::STRICT), "bound variable in strict sort");
+        ensure!(deps == self.next_bv, "bad binder deps");
+        self.next_bv <<= 1;
+      } else {
+        ensure!(deps & !(self.next_bv - 1) == 0, "bad binder deps");
+      }
+      let e = self.alloc(ty.into_inner(), None);
+      self.heap.push(StackEl::Expr(e))
+    }
+    Ok(())
+  }
+
+  /// Set the unify heap to the arguments of the current declaration.
+  fn load_uheap(&mut self, nargs: usize) {
+    self.uheap.clear();
+    self.uheap.extend(self.heap[..nargs].iter().map(|el| match *el {
+      StackEl::Expr(e) => e,
+      _ => unreachable!("arguments are expressions"),
+    }))
+  }
+
+  /// Run the unify stream `it` against the target expression `tgt`.
+  /// The unify heap should already contain the substitution.
+  fn run_unify(&mut self, mode: UnifyMode, it: UnifyIter<'_>, tgt: usize) -> Result<(), String> {
+    self.ustack.clear();
+    self.ustack.push(tgt);
+    for cmd in it {
+      match cmd.map_err(|e| e.to_string())? {
+        UnifyCmd::Ref(i) => {
+          let e = *self.uheap.get(u32_as_usize(i)).ok_or("bad ref step")?;
+          ensure!(self.ustack.pop() == Some(e), "unify failure at ref");
+        }
+        UnifyCmd::Term {tid, save} => {
+          let p = self.ustack.pop().ok_or("unify stack underflow")?;
+          match &self.store[p].app {
+            Some((t, args)) if *t == tid => self.ustack.extend(args.iter().rev()),
+            _ => return Err("unify failure at term".into())
+          }
+          if save { self.uheap.push(p) }
+        }
+        UnifyCmd::Dummy(s) => {
+          ensure!(mode == UnifyMode::Def, "dummy command not allowed in theorem statements");
+          let p = self.ustack.pop().ok_or("unify stack underflow")?;
+          let ty = Arg::from(self.store[p].ty);
+          ensure!(ty.bound() && ty.sort() == s, "unify failure at dummy");
+          let deps = ty.deps_unchecked();
+          ensure!(self.uheap.iter().all(|&e| self.store[e].ty & deps == 0),
+            "dummy disjoint variable violation");
+          self.uheap.push(p)
+        }
+        UnifyCmd::Hyp => match mode {
+          UnifyMode::Thm => {
+            let e = self.pop_proof()?;
+            self.ustack.push(e)
+          }
+          UnifyMode::ThmEnd => {
+            ensure!(self.ustack.is_empty(), "unfinished unify stack");
+            let e = self.hstack.pop().ok_or("hypothesis stack underflow")?;
+            self.ustack.push(e)
+          }
+          UnifyMode::Def => return Err("hyp command not allowed in definition statements".into())
+        }
+      }
+    }
+    if mode == UnifyMode::ThmEnd { ensure!(self.hstack.is_empty(), "unfinished hypothesis stack") }
+    ensure!(self.ustack.is_empty(), "unfinished unify stack");
+    Ok(())
+  }
+
+  /// Run the proof stream `it`, leaving the result on the stack.
+  fn run_proof(&mut self, mode: ProofMode, it: ProofIter<'_>) -> Result<(), String> {
+    for cmd in it {
+      match cmd.map_err(|e| e.to_string())? {
+        ProofCmd::Ref(i) => match *self.heap.get(u32_as_usize(i)).ok_or("bad ref step")? {
+          StackEl::Conv(e1, e2) => {
+            let (c1, c2) = self.pop_co_conv()?;
+            ensure!(e1 == c1 && e2 == c2, "ConvRef unify error");
+          }
+          el => self.stack.push(el)
+        }
+        ProofCmd::Dummy(s) => {
+          ensure!(!self.sort_mods(s)?.contains(Modifiers::STRICT), "dummy variable in strict sort");
+          ensure!(self.next_bv & !TYPE_DEPS_MASK == 0, "too many bound variables");
+          let mut ty = Arg::new(true);
+          ty.add_sort(s);
+          let e = self.alloc(ty.into_inner() | self.next_bv, None);
+          self.next_bv <<= 1;
+          self.stack.push(StackEl::Expr(e));
+          self.heap.push(StackEl::Expr(e))
+        }
+        ProofCmd::Term {tid, save} => {
+          ensure!(tid.0 < self.num_terms, "term out of range");
+          let td = self.file.term(tid).ok_or("term out of range")?;
+          let args = self.pop_args(td.args().len())?;
+          let mut bound = vec![];
+          let mut accum = 0;
+          for (&e, &target) in args.iter().zip(td.args()) {
+            let (ty, target) = (self.store[e].ty, target.into_inner());
+            ensure!(sorts_compatible(ty, target), "type mismatch");
+            let mut deps = ty & TYPE_DEPS_MASK;
+            if target & TYPE_BOUND_MASK != 0 {
+              bound.push(deps)
+            } else {
+              if mode == ProofMode::Def {
+                for (j, &d) in bound.iter().enumerate() {
+                  if target & (1 << j) != 0 { deps &= !d }
+                }
+              }
+              accum |= deps
+            }
+          }
+          if mode == ProofMode::Def {
+            let target = td.ret().deps_unchecked();
+            for (j, &d) in bound.iter().enumerate() {
+              if target & (1 << j) != 0 { accum |= d }
+            }
+          }
+          let mut ty = Arg::new(false);
+          ty.add_sort(td.sort());
+          let e = self.alloc(ty.into_inner() | accum, Some((tid, args.into())));
+          self.stack.push(StackEl::Expr(e));
+          if save { self.heap.push(StackEl::Expr(e)) }
+        }
+        ProofCmd::Thm {tid, save} => {
+          ensure!(mode == ProofMode::Thm, "invalid opcode in def");
+          ensure!(tid.0 < self.num_thms, "theorem out of range");
+          let td = self.file.thm(tid).ok_or("theorem out of range")?;
+          let e = self.pop_expr()?;
+          let args = self.pop_args(td.args().len())?;
+          let mut bound = vec![];
+          for (i, (&arg, &target)) in args.iter().zip(td.args()).enumerate() {
+            let (ty, target) = (self.store[arg].ty, target.into_inner());
+            ensure!(sorts_compatible(ty, target), "type mismatch");
+            let deps = ty & TYPE_DEPS_MASK;
+            if target & TYPE_BOUND_MASK != 0 {
+              bound.push(deps);
+              ensure!(args[..i].iter().all(|&a| self.store[a].ty & deps == 0),
+                "disjoint variable violation");
+            } else {
+              ensure!(bound.iter().enumerate().all(|(j, &d)| target & (1 << j) != 0 || d & deps == 0),
+                "disjoint variable violation");
+            }
+          }
+          self.uheap = args;
+          self.run_unify(UnifyMode::Thm, td.unify(), e)?;
+          self.stack.push(StackEl::Proof(e));
+          if save { self.heap.push(StackEl::Proof(e)) }
+        }
+        ProofCmd::Hyp => {
+          ensure!(mode == ProofMode::Thm, "invalid opcode in def");
+          let e = self.pop_expr()?;
+          ensure!(self.sort_mods(Arg::from(self.store[e].ty).sort())?.contains(Modifiers::PROVABLE),
+            "hypothesis should have provable sort");
+          self.hstack.push(e);
+          self.heap.push(StackEl::Proof(e))
+        }
+        ProofCmd::Conv => {
+          let e2 = self.pop_proof()?;
+          let e1 = self.pop_expr()?;
+          self.stack.push(StackEl::Proof(e1));
+          self.stack.push(StackEl::CoConv(e1, e2))
+        }
+        ProofCmd::Refl => {
+          let (e1, e2) = self.pop_co_conv()?;
+          ensure!(e1 == e2, "Refl unify failure");
+        }
+        ProofCmd::Sym => {
+          let (e1, e2) = self.pop_co_conv()?;
+          self.stack.push(StackEl::CoConv(e2, e1))
+        }
+        ProofCmd::Cong => {
+          let (e1, e2) = self.pop_co_conv()?;
+          match (&self.store[e1].app, &self.store[e2].app) {
+            (Some((t1, args1)), Some((t2, args2))) if t1 == t2 =>
+              for (&a1, &a2) in args1.iter().zip(&**args2).rev() {
+                self.stack.push(StackEl::CoConv(a1, a2))
+              },
+            _ => return Err("Cong unify error".into())
+          }
+        }
+        ProofCmd::Unfold => {
+          let e = self.pop_expr()?;
+          let (e1, e2) = self.pop_co_conv()?;
+          let (tid, args) = self.store[e1].app.as_ref().ok_or("Unfold: not a definition")?;
+          let td = self.file.term(*tid).ok_or("term out of range")?;
+          ensure!(td.def(), "Unfold: not a definition");
+          self.uheap.clear();
+          self.uheap.extend_from_slice(args);
+          self.run_unify(UnifyMode::Def, td.unify(), e)?;
+          self.stack.push(StackEl::CoConv(e, e2))
+        }
+        ProofCmd::ConvCut => {
+          let (e1, e2) = self.pop_co_conv()?;
+          self.stack.push(StackEl::Conv(e1, e2));
+          self.stack.push(StackEl::CoConv(e1, e2))
+        }
+        ProofCmd::ConvSave => {
+          let (e1, e2) = self.pop_conv()?;
+          self.heap.push(StackEl::Conv(e1, e2))
+        }
+        ProofCmd::Save => match *self.stack.last().ok_or("stack underflow")? {
+          StackEl::CoConv(..) => return Err("can't save proof obligation".into()),
+          el => self.heap.push(el)
+        }
+        ProofCmd::Sorry => {
+          ensure!(mode == ProofMode::Thm, "invalid opcode in def");
+          self.uses_sorry = true;
+          match self.pop()? {
+            StackEl::Expr(e) => self.stack.push(StackEl::Proof(e)),
+            StackEl::CoConv(..) => {}
+            el => return Err(format!("expected an expression or obligation, got {:?}", el)),
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Check one declaration in the proof stream.
+  fn verify_decl(&mut self, stmt: NumdStmtCmd, proof: ProofIter<'_>) -> Result<(), String> {
+    match stmt {
+      NumdStmtCmd::Sort {..} => {
+        ensure!(proof.is_null(), "next statement incorrect");
+        let sd = *self.file.sorts.get(self.num_sorts).ok_or("step sort overflow")?;
+        ensure!(Modifiers::try_from(sd).is_ok(), "bad sort modifiers");
+        self.num_sorts += 1;
+      }
+      NumdStmtCmd::TermDef {term_id, ..} => {
+        let td = self.file.term(term_id).ok_or("step term overflow")?;
+        ensure!(!self.sort_mods(td.sort())?.contains(Modifiers::PURE), "term in pure sort");
+        self.load_args(td.args_and_ret())?;
+        let ret = td.ret();
+        ensure!(!ret.bound() && ret.sort() == td.sort(), "bad return type");
+        self.heap.pop();
+        if td.def() {
+          ensure!(!proof.is_null(), "definition has no value");
+          self.run_proof(ProofMode::Def, proof)?;
+          let e = match self.stack[..] {
+            [StackEl::Expr(e)] => e,
+            _ => return Err("stack should have exactly one expression".into())
+          };
+          let (ty, ret) = (self.store[e].ty, ret.into_inner());
+          ensure!(sorts_compatible(ty, ret), "type mismatch");
+          ensure!(ty & TYPE_DEPS_MASK & !ret == 0, "type has unaccounted dependencies");
+          self.load_uheap(td.args().len());
+          self.run_unify(UnifyMode::Def, td.unify(), e)?;
+        } else {
+          ensure!(proof.is_null(), "next statement incorrect");
+        }
+        self.num_terms += 1;
+      }
+      NumdStmtCmd::Axiom {thm_id} | NumdStmtCmd::Thm {thm_id, ..} => {
+        let td = self.file.thm(thm_id).ok_or("step theorem overflow")?;
+        self.load_args(td.args())?;
+        self.run_proof(ProofMode::Thm, proof)?;
+        let e = match (&self.stack[..], stmt) {
+          (&[StackEl::Expr(e)], NumdStmtCmd::Axiom {..}) |
+          (&[StackEl::Proof(e)], NumdStmtCmd::Thm {..}) => e,
+          _ => return Err("stack should have exactly one element, of the right kind".into())
+        };
+        ensure!(self.sort_mods(Arg::from(self.store[e].ty).sort())?.contains(Modifiers::PROVABLE),
+          "conclusion should have provable sort");
+        self.load_uheap(td.args().len());
+        self.run_unify(UnifyMode::ThmEnd, td.unify(), e)?;
+        self.num_thms += 1;
+      }
+    }
+    Ok(())
+  }
+
+  /// Check one declaration at position `pos` in the file, recording it if it uses `sorry`.
+  fn check_decl(&mut self, pos: usize, stmt: NumdStmtCmd, proof: ProofIter<'_>) -> Result<(), VerifyError> {
+    self.uses_sorry = false;
+    self.verify_decl(stmt, proof)
+      .map_err(|msg| VerifyError::Decl(pos, stmt_name(self.file, stmt), msg))?;
+    if self.uses_sorry {
+      self.sorry.push(stmt_name(self.file, stmt).unwrap_or_else(|| format!("at {:#x}", pos)))
+    }
+    Ok(())
+  }
+
+  /// Number a declaration according to the declarations checked so far.
+  fn number(&self, stmt: StmtCmd) -> NumdStmtCmd {
+    match stmt {
+      // If there are too many sorts, this is caught by `verify_decl`
+      StmtCmd::Sort => NumdStmtCmd::Sort {sort_id: SortId(u8::try_from(self.num_sorts).unwrap_or(u8::MAX))},
+      StmtCmd::Axiom => NumdStmtCmd::Axiom {thm_id: ThmId(self.num_thms)},
+      StmtCmd::TermDef {local} => NumdStmtCmd::TermDef {term_id: TermId(self.num_terms), local},
+      StmtCmd::Thm {local} => NumdStmtCmd::Thm {thm_id: ThmId(self.num_thms), local},
+    }
+  }
+
+  /// Check that the whole file has been declared, after the end of the proof stream.
+  fn finish(self) -> Result<(), VerifyError> {
+    if self.num_sorts != self.file.sorts.len() { return Err(VerifyError::Incomplete("sorts")) }
+    if u32_as_usize(self.num_terms) != self.file.terms.len() { return Err(VerifyError::Incomplete("terms")) }
+    if u32_as_usize(self.num_thms) != self.file.thms.len() { return Err(VerifyError::Incomplete("theorems")) }
+    if !self.sorry.is_empty() { return Err(VerifyError::Sorry(self.sorry)) }
+    Ok(())
+  }
+}
+
+/// The name of a declaration, if the file has a name index.
+fn stmt_name(file: &BasicMmbFile<'_>, stmt: NumdStmtCmd) -> Option<String> {
+  Some(file.stmt_index(stmt)?.value()?.to_owned())
+}
+
+/// Check all the proofs in `file`. This fails if any proof is incorrect or uses `sorry`,
+/// but it does not check the file against a specification, see [`check_spec`].
+///
+/// The whole file must be in memory (or memory mapped); see [`verify`] for a version that
+/// reads the file incrementally.
+pub fn verify_file(file: &BasicMmbFile<'_>) -> Result<(), VerifyError> {
+  let mut v = Verifier::new(file);
+  let mut it = file.proof();
+  loop {
+    let pos = it.pos;
+    let (stmt, proof) = if let Some(decl) = it.next() {decl?} else {break};
+    v.check_decl(pos, stmt, proof)?;
+  }
+  v.finish()
+}
+
+/// Check all the proofs in the MMB file read from `r`, like [`verify_file`].
+///
+/// Only the part of the file before the proof stream (the header and the term and theorem
+/// tables) is kept in memory. The proof stream is then read and checked one declaration
+/// at a time, and the state of the checker is reset between declarations, so the memory
+/// use is bounded by the size of the largest single proof, regardless of the size of the file.
+/// The index, which comes after the proof stream, is not read, so errors are reported
+/// by file position only.
+pub fn verify<R: Read>(mut r: R) -> Result<(), VerifyError> {
+  let mut header = Header::default();
+  r.read_exact(header.as_bytes_mut())?;
+  let p_proof = u32_as_usize(header.p_proof.get());
+  if p_proof < size_of::<Header>() { return Err(ParseError::SuspectHeader.into()) }
+  header.p_index = U64::new(0);
+  // Use a `u64` buffer so that the tables are aligned
+  let mut buf = vec![0_u64; (p_proof + 7) >> 3];
+  let prefix = &mut buf.as_bytes_mut()[..p_proof];
+  prefix[..size_of::<Header>()].copy_from_slice(header.as_bytes());
+  r.read_exact(&mut prefix[size_of::<Header>()..])?;
+  let file = BasicMmbFile::parse(prefix)?;
+  let mut v = Verifier::new(&file);
+  let (mut pos, mut proof) = (p_proof, vec![]);
+  loop {
+    let mut cmd = [0_u8; 5];
+    r.read_exact(&mut cmd[..1])?;
+    let len = match cmd[0] & DATA_MASK { 0 => 1, DATA_8 => 2, DATA_16 => 3, _ => 5 };
+    r.read_exact(&mut cmd[1..len])?;
+    let (stmt, data, _) = parse_cmd(&cmd[..len], 0)?;
+    if stmt == 0 { break }
+    let data = u32_as_usize(data);
+    if data < len { return Err(ParseError::BadProofLen(pos).into()) }
+    proof.clear();
+    proof.resize(data - len, 0);
+    r.read_exact(&mut proof)?;
+    let stmt = v.number(StmtCmd::try_from(stmt)?);
+    v.check_decl(pos, stmt, ProofIter::new(&proof, 0, proof.len()))?;
+    pos += data;
+  }
+  v.finish()
+}
+
+/// An axiom or theorem to be checked by [`verify_par`], along with the number of
+/// sorts and terms that were declared before it.
+#[derive(Clone, Copy, Debug)]
+struct ThmJob {
+  pos: usize,
+  stmt: NumdStmtCmd,
+  thm_id: ThmId,
+  /// The start and end of the proof stream.
+  proof: (usize, usize),
+  num_sorts: usize,
+  num_terms: u32,
+}
+
+/// The number of theorems listed in the [`VerifyTiming`] report.
+const SLOWEST: usize = 20;
+
+/// Timing information produced by [`verify_par`].
+#[derive(Debug, Default)]
+pub struct VerifyTiming {
+  /// The elapsed time for the whole check.
+  pub total: Duration,
+  /// The name, or position if the file has no index, of each axiom and theorem,
+  /// and the time taken to check it, in declaration order.
+  pub thms: Vec<(ThmId, String, Duration)>,
+}
+
+impl fmt::Display for VerifyTiming {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let sum = self.thms.iter().map(|t| t.2).sum::<Duration>();
+    writeln!(f, "checked {} theorems in {:?} ({:?} across all threads)",
+      self.thms.len(), self.total, sum)?;
+    let mut thms = self.thms.iter().collect::<Vec<_>>();
+    thms.sort_by_key(|t| std::cmp::Reverse(t.2));
+    for (_, name, time) in thms.into_iter().take(SLOWEST) {
+      writeln!(f, "  {:>12} {}", format!("{:?}", time), name)?
+    }
+    Ok(())
+  }
+}
+
+/// Check all the proofs in `buf` like [`verify_file`], using `jobs` threads.
+///
+/// Sorts, terms and definitions are checked in order on the calling thread, since the checks
+/// for later declarations rely on them. Axioms and theorems only depend on the statements
+/// of earlier declarations, so they are put in a shared queue, and each worker thread
+/// repeatedly takes the next unchecked theorem from the queue until it is empty.
+/// If more than one declaration fails, the error for the first failing sort, term or
+/// definition is returned, or else the error for the first failing theorem.
+pub fn verify_par<B: Deref<Target = [u8]> + Send + Sync + 'static>(
+  buf: &Arc<B>, jobs: usize
+) -> Result<VerifyTiming, VerifyError> {
+  let start = Instant::now();
+  let file = BasicMmbFile::parse(buf)?;
+  let mut v = Verifier::new(&file);
+  let mut queue = vec![];
+  let mut it = file.proof();
+  loop {
+    let pos = it.pos;
+    let (stmt, proof) = if let Some(decl) = it.next() {decl?} else {break};
+    match stmt {
+      NumdStmtCmd::Axiom {thm_id} | NumdStmtCmd::Thm {thm_id, ..} => {
+        queue.push(ThmJob {
+          pos, stmt, thm_id, proof: (proof.pos, proof.ends_at),
+          num_sorts: v.num_sorts, num_terms: v.num_terms,
+        });
+        v.num_thms += 1;
+      }
+      _ => v.check_decl(pos, stmt, proof)?
+    }
+  }
+  let queue = Arc::new(queue);
+  let next = Arc::new(AtomicUsize::new(0));
+  let workers = (0..jobs.max(1)).map(|_| {
+    let (buf, queue, next) = (buf.clone(), queue.clone(), next.clone());
+    std::thread::spawn(move || -> Result<_, ParseError> {
+      let file = BasicMmbFile::parse(&buf)?;
+      let mut v = Verifier::new(&file);
+      let mut results = vec![];
+      loop {
+        let i = next.fetch_add(1, Ordering::Relaxed);
+        let job = if let Some(job) = queue.get(i) {job} else {break};
+        v.num_sorts = job.num_sorts;
+        v.num_terms = job.num_terms;
+        v.num_thms = job.thm_id.0;
+        let start = Instant::now();
+        let res = v.check_decl(job.pos, job.stmt, ProofIter::new(&buf, job.proof.0, job.proof.1));
+        results.push((i, start.elapsed(), res.map(|()| v.uses_sorry)));
+      }
+      Ok(results)
+    })
+  }).collect::<Vec<_>>();
+  let mut results = vec![];
+  for w in workers { results.extend(w.join().expect("verifier thread panicked")?) }
+  results.sort_by_key(|r| r.0);
+  let mut timing = VerifyTiming::default();
+  for (i, time, res) in results {
+    let job = &queue[i];
+    let uses_sorry = res?;
+    let name = stmt_name(&file, job.stmt).unwrap_or_else(|| format!("at {:#x}", job.pos));
+    if uses_sorry { v.sorry.push(name.clone()) }
+    timing.thms.push((job.thm_id, name, time));
+  }
+  v.finish()?;
+  timing.total = start.elapsed();
+  Ok(timing)
+}
+
+/// An expression decoded from a unify stream. Variables are numbered by their position
+/// in the unify heap: the arguments come first, followed by the dummies in order.
+#[derive(Clone, Debug)]
+enum UExpr {
+  Var(usize),
+  App(TermId, Box<[UExpr]>),
+}
+
+/// A decoder for the unify stream of a declaration.
+struct Decoder<'a> {
+  file: &'a BasicMmbFile<'a>,
+  it: UnifyIter<'a>,
+  nargs: usize,
+  heap: Vec<Option<UExpr>>,
+  /// The sorts of the dummy variables.
+  dummies: Vec<SortId>,
+}
+
+impl<'a> Decoder<'a> {
+  fn new(file: &'a BasicMmbFile<'a>, it: UnifyIter<'a>, nargs: usize) -> Self {
+    Self { file, it, nargs, heap: (0..nargs).map(|i| Some(UExpr::Var(i))).collect(), dummies: vec![] }
+  }
+
+  fn next(&mut self) -> Result<Option<UnifyCmd>, String> {
+    self.it.next().transpose().map_err(|e| e.to_string())
+  }
+
+  fn expr(&mut self) -> Result<UExpr, String> {
+    match self.next()?.ok_or("unexpected end of unify stream")? {
+      UnifyCmd::Term {tid, save} => {
+        let n = self.file.term(tid).ok_or("term out of range")?.args().len();
+        let slot = save.then(|| { self.heap.push(None); self.heap.len() - 1 });
+        let args = (0..n).map(|_| self.expr()).collect::<Result<Box<[_]>, _>>()?;
+        let e = UExpr::App(tid, args);
+        if let Some(i) = slot { self.heap[i] = Some(e.clone()) }
+        Ok(e)
+      }
+      UnifyCmd::Ref(i) => self.heap.get(u32_as_usize(i)).cloned().flatten()
+        .ok_or_else(|| "bad ref step".into()),
+      UnifyCmd::Dummy(s) => {
+        let e = UExpr::Var(self.nargs + self.dummies.len());
+        self.dummies.push(s);
+        self.heap.push(Some(e.clone()));
+        Ok(e)
+      }
+      UnifyCmd::Hyp => Err("unexpected hyp command".into())
+    }
+  }
+}
+
+/// Matches the statements of a specification against an MMB file.
+struct SpecChecker<'a> {
+  file: &'a BasicMmbFile<'a>,
+  env: &'a FrozenEnv,
+  /// The MMB sort corresponding to each sort in the specification.
+  sorts: SortVec<SortId>,
+  /// The MMB term corresponding to each term in the specification.
+  terms: TermVec<TermId>,
+}
+
+/// The context for matching an expression in the specification against a decoded expression.
+struct Matcher<'a> {
+  nargs: usize,
+  heap: &'a [ExprNode],
+  dummies: &'a [SortId],
+  /// The MMB dummy variable assigned to each dummy in the specification.
+  dmap: HashMap<AtomId, usize>,
+}
+
+impl SpecChecker<'_> {
+  /// The MMB encoding of a binder type. `bv` is the number of bound variables so far.
+  fn mmb_type(&self, ty: Type, bv: &mut u64) -> u64 {
+    let mut arg = match ty {
+      Type::Bound(_) => { let arg = Arg::from(TYPE_BOUND_MASK | 1 << *bv); *bv += 1; arg }
+      Type::Reg(_, deps) => Arg::from(deps),
+    };
+    arg.add_sort(self.sorts[ty.sort()]);
+    arg.into_inner()
+  }
+
+  fn binders_match(&self, spec: &[(Option<AtomId>, Type)], mmb: &[Arg]) -> bool {
+    let mut bv = 0;
+    spec.len() == mmb.len() &&
+    spec.iter().zip(mmb).all(|(&(_, ty), arg)| self.mmb_type(ty, &mut bv) == arg.into_inner())
+  }
+
+  fn expr_match(&self, mat: &mut Matcher<'_>, e: &ExprNode, ue: &UExpr) -> bool {
+    match (e, ue) {
+      (&ExprNode::Ref(i), _) if i < mat.nargs => matches!(*ue, UExpr::Var(j) if i == j),
+      (&ExprNode::Ref(i), _) => { let heap = mat.heap; self.expr_match(mat, &heap[i], ue) }
+      (&ExprNode::Dummy(a, sort), &UExpr::Var(j)) => {
+        if j < mat.nargs || mat.dummies[j - mat.nargs] != self.sorts[sort] { return false }
+        if let Some(&k) = mat.dmap.get(&a) { return k == j }
+        if mat.dmap.values().any(|&k| k == j) { return false }
+        mat.dmap.insert(a, j);
+        true
+      }
+      (ExprNode::App(t, es), UExpr::App(t2, us)) =>
+        self.terms[*t] == *t2 && es.len() == us.len() &&
+        es.iter().zip(&**us).all(|(e, ue)| self.expr_match(mat, e, ue)),
+      _ => false
+    }
+  }
+
+  fn check_name(mmb: Option<&str>, name: &str) -> Result<(), String> {
+    match mmb {
+      Some(n) if n != name => Err(format!("{}: declared as '{}' in the MMB file", name, n)),
+      _ => Ok(())
+    }
+  }
+
+  fn check_stmt(&mut self, stmt: NumdStmtCmd, atom: AtomId) -> Result<(), String> {
+    let ad = &self.env.data()[atom];
+    let name = ad.name().as_str();
+    match (stmt, ad.sort(), ad.decl()) {
+      (NumdStmtCmd::Sort {sort_id}, Some(sort), _) => {
+        Self::check_name(self.file.try_sort_name(sort_id), name)?;
+        let sd = self.file.sort(sort_id).ok_or("sort out of range")?;
+        ensure!(Modifiers::new(sd.0) == self.env.sort(sort).mods, "{}: sort modifiers do not match", name);
+        self.sorts.push(sort_id);
+      }
+      (NumdStmtCmd::TermDef {term_id, ..}, _, Some(DeclKey::Term(t))) => {
+        Self::check_name(self.file.try_term_name(term_id), name)?;
+        let td = self.env.term(t);
+        let tr = self.file.term(term_id).ok_or("term out of range")?;
+        ensure!(tr.def() == matches!(td.kind, TermKind::Def(_)), "{}: term/def mismatch", name);
+        let ret = self.mmb_type(Type::Reg(td.ret.0, td.ret.1), &mut 0);
+        ensure!(self.binders_match(&td.args, tr.args()) && ret == tr.ret().into_inner(),
+          "{}: type does not match", name);
+        if let TermKind::Def(Some(Expr {heap, head})) = &td.kind {
+          let mut dec = Decoder::new(self.file, tr.unify(), td.args.len());
+          let ue = dec.expr().map_err(|e| format!("{}: {}", name, e))?;
+          let mut mat = Matcher {nargs: td.args.len(), heap, dummies: &dec.dummies, dmap: HashMap::new()};
+          ensure!(self.expr_match(&mut mat, head, &ue), "{}: definition does not match", name);
+        }
+        self.terms.push(term_id);
+      }
+      (NumdStmtCmd::Axiom {thm_id} | NumdStmtCmd::Thm {thm_id, ..}, _, Some(DeclKey::Thm(t))) => {
+        Self::check_name(self.file.try_thm_name(thm_id), name)?;
+        let td = self.env.thm(t);
+        let tr = self.file.thm(thm_id).ok_or("theorem out of range")?;
+        ensure!(matches!(stmt, NumdStmtCmd::Axiom {..}) == matches!(td.kind, ThmKind::Axiom),
+          "{}: axiom/theorem mismatch", name);
+        ensure!(self.binders_match(&td.args, tr.args()), "{}: binders do not match", name);
+        let mut dec = Decoder::new(self.file, tr.unify(), td.args.len());
+        let decode = |dec: &mut Decoder<'_>| -> Result<_, String> {
+          let ret = dec.expr()?;
+          let mut hyps = vec![];
+          while let Some(cmd) = dec.next()? {
+            ensure!(matches!(cmd, UnifyCmd::Hyp), "unexpected unify command");
+            hyps.push(dec.expr()?)
+          }
+          hyps.reverse();
+          Ok((ret, hyps))
+        };
+        let (ret, hyps) = decode(&mut dec).map_err(|e| format!("{}: {}", name, e))?;
+        let mut mat = Matcher {nargs: td.args.len(), heap: &td.heap, dummies: &dec.dummies, dmap: HashMap::new()};
+        ensure!(hyps.len() == td.hyps.len() &&
+          td.hyps.iter().zip(&hyps).all(|(h, ue)| self.expr_match(&mut mat, &h.1, ue)) &&
+          self.expr_match(&mut mat, &td.ret, &ret), "{}: statement does not match", name);
+      }
+      _ => return Err(format!("{}: declaration kind does not match", name))
+    }
+    Ok(())
+  }
+}
+
+/// Check that the non-local statements of `file` match the statements of the
+/// specification `env`, in order. This does not check the proofs, see [`verify_file`].
+pub fn check_spec(file: &BasicMmbFile<'_>, env: &FrozenEnv) -> Result<(), VerifyError> {
+  let mut ch = SpecChecker {file, env, sorts: Default::default(), terms: Default::default()};
+  let mut spec = env.stmts().iter().filter_map(|s| match *s {
+    StmtTrace::Sort(a) | StmtTrace::Decl(a) => Some(a),
+    StmtTrace::Global(_) | StmtTrace::OutputString(_) => None,
+  });
+  for decl in file.proof() {
+    let (stmt, _) = decl?;
+    if stmt.is_local() { continue }
+    let a = spec.next().ok_or_else(|| VerifyError::Spec(format!(
+      "{} is not in the specification",
+      stmt_name(file, stmt).unwrap_or_else(|| format!("{:?}", stmt)))))?;
+    ch.check_stmt(stmt, a).map_err(VerifyError::Spec)?;
+  }
+  if let Some(a) = spec.next() {
+    return Err(VerifyError::Spec(format!("{} is missing from the MMB file", env.data()[a].name())))
+  }
+  Ok(())
+}
+
+/// Main entry point for `mm0-rs verify` subcommand.
+///
+/// See the [module documentation](self) for the purpose of this command.
+///
+/// # Arguments
+///
+/// `mm0-rs verify <file.mmb> [file.mm0]`, where:
+///
+/// - `file.mmb` is the binary proof file to check
+/// - `file.mm0` is the specification to check it against. If this argument is omitted,
+///   only the proofs are checked.
+///
+/// With `--jobs <N>`, theorems are checked in parallel on `N` threads (see [`verify_par`]),
+/// and a report of the time taken by the slowest theorems is printed (except in the `json`
+/// and `sarif` error formats, whose output contains only the diagnostics).
+///
+/// With `--error-format json` or `--error-format sarif`, a verification failure is reported
+/// as a [diagnostic](crate::diagnostics) about the MMB file.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let path: FileRef = fs::canonicalize(args.value_of_os("MMB").expect("required arg"))?.into();
+  match run(args, &path) {
+    Ok(()) => {}
+    Err(e) if diagnostics::error_format() != ErrorFormat::Human => {
+      let region = if let VerifyError::Decl(pos, _, _) = e {Region::Bytes((pos..pos).into())} else {Region::None};
+      diagnostics::emit(&Diagnostic {region, ..Diagnostic::message(path, ErrorLevel::Error, e.to_string())});
+      diagnostics::finish();
+      std::process::exit(1)
+    }
+    Err(e) => return Err(e.into()),
+  }
+  diagnostics::finish();
+  Ok(())
+}
+
+/// Check the MMB file at `path`, and the specification given by `args` if any,
+/// as described in [`main`].
+fn run(args: &ArgMatches<'_>, path: &FileRef) -> Result<(), VerifyError> {
+  let contents = Arc::new(FileContents::new_bin_from_file(path.path())?);
+  let file = BasicMmbFile::parse(&contents)?;
+  if let Some(jobs) = args.value_of("jobs") {
+    let jobs = jobs.parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let timing = verify_par(&contents, jobs)?;
+    if diagnostics::error_format() == ErrorFormat::Human {
+      diagnostics::print(format_args!("{}", timing))
+    }
+  } else {
+    verify_file(&file)?
+  }
+  if let Some(spec) = args.value_of("MM0") {
+    let spec: FileRef = fs::canonicalize(spec)?.into();
+    let (_, env) = elab_for_result(spec)?;
+    let env = if let Some(env) = env {env} else {
+      diagnostics::finish();
+      std::process::exit(1)
+    };
+    check_spec(&file, &env)?;
+  }
+  Ok(())
+}
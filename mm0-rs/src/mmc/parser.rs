@@ -2,10 +2,11 @@
 use std::mem;
 use std::convert::TryInto;
 use std::collections::{HashMap, hash_map::Entry};
+use num::ToPrimitive;
 use crate::{Elaborator, EnvDisplay};
 use crate::{FileSpan, AtomId, Type as EType, elab::Result, Environment, ElabError,
   LispKind, LispVal, Uncons, FormatEnv, try_get_span};
-use crate::elab::lisp::Syntax;
+use crate::elab::lisp::{Int, Syntax};
 use mm0_util::{BoxError, TermId, u32_as_usize};
 use mmcc::{Idx, init_dense_symbol_map};
 use mmcc::build_ast::{BadBinding, BuildAst, BuildMatch, Incomplete, Pattern, PatternBuilder,
@@ -638,15 +639,15 @@ impl<'a, C> Parser<'a, C> {
         }
       }
       &LispKind::Bool(b) => ExprKind::Bool(b),
-      LispKind::Number(n) => ExprKind::Int(n.clone()),
+      LispKind::Number(n) => ExprKind::Int(n.clone().into()),
       LispKind::DottedList(es, r) if !r.is_list() && es.len() == 1 => {
         let head = Box::new(self.parse_expr(&span, es[0].clone())?);
         if let Some(a) = r.as_atom() {
           ExprKind::Proj(head, spanned(&span, r, FieldName::Named(self.as_symbol(a))))
         } else {
-          match r.as_int(|n| n.try_into()) {
-            Some(Ok(i)) => ExprKind::Proj(head, spanned(&span, r, FieldName::Number(i))),
-            Some(Err(_)) => return Err(ElabError::new_e(&span, "field access: index out of range")),
+          match r.as_int(Int::to_u32) {
+            Some(Some(i)) => ExprKind::Proj(head, spanned(&span, r, FieldName::Number(i))),
+            Some(None) => return Err(ElabError::new_e(&span, "field access: index out of range")),
             None => return Err(ElabError::new_e(&span, "field access syntax error")),
           }
         }
@@ -1154,7 +1155,7 @@ impl<'a, C> Parser<'a, C> {
         _ => return Err(ElabError::new_e(try_get_span(base, e), "pattern syntax error"))
       }
       LispKind::Number(n) =>
-        pb.const_(&span, Spanned {span: span.clone(), k: ExprKind::Int(n.clone())}),
+        pb.const_(&span, Spanned {span: span.clone(), k: ExprKind::Int(n.clone().into())}),
       _ => return Err(ElabError::new_e(try_get_span(base, e), "pattern syntax error"))
     })
   }
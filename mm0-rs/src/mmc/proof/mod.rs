@@ -223,7 +223,7 @@ impl ProofDedup<'_> {
     Thm {
       atom, span, full, doc: None, vis,
       args: Box::new([]), hyps: Box::new([]), heap: eheap, ret,
-      kind: ThmKind::Thm(Some(Proof { heap, hyps: Box::new([]), head })),
+      kind: ThmKind::Thm(Some(Proof { heap, hyps: Box::new([]), head, spans: Box::new([]) })),
     }
   }
 }
@@ -48,12 +48,12 @@ fn build_unfold_map<'a>(env: &FrozenEnv, m: &mut HashMap<AtomId, &'a ProofNode>,
 type Line = (usize, Vec<u8>);
 
 impl FrozenEnv {
-  fn write_deps(&self, w: &mut impl Write, bvars: &[Option<AtomId>], mut vs: u64) -> io::Result<()> {
+  pub(crate) fn write_deps(&self, w: &mut impl Write, bvars: &[Option<AtomId>], mut vs: u64) -> io::Result<()> {
     list(w, bvars.iter().filter(|_| {let old = vs; vs /= 2; old & 1 != 0}),
       |w, &a| write!(w, "{}", self.data()[a.expect("you can only depend on variables with names")].name()))
   }
 
-  fn write_binders(&self, w: &mut impl Write, bis: &[(Option<AtomId>, Type)]) -> io::Result<Vec<Option<AtomId>>> {
+  pub(crate) fn write_binders(&self, w: &mut impl Write, bis: &[(Option<AtomId>, Type)]) -> io::Result<Vec<Option<AtomId>>> {
     let mut bvars = vec![];
     list(w, bis.iter(), |w, &(a, ty)| {
       write!(w, "({} ", a.map_or("_", |a| self.data()[a].name().as_str()))?;
@@ -72,7 +72,7 @@ impl FrozenEnv {
     Ok(bvars)
   }
 
-  fn write_expr_node(&self,
+  pub(crate) fn write_expr_node(&self,
     dummies: &mut HashMap<AtomId, SortId>,
     heap: &[Vec<u8>],
     node: &ExprNode,
@@ -320,7 +320,9 @@ impl FrozenEnv {
               w.write_all(&self.write_expr_node(&mut dummies, &strs, &td.ret)?)?;
               match &td.kind {
                 ThmKind::Axiom => {},
-                ThmKind::Thm(None) => panic!("proof {} missing", self.data()[td.atom].name()),
+                ThmKind::Thm(None) => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                  format!("theorem {} contains sorry, which MMU cannot represent",
+                    self.data()[td.atom].name()))),
                 ThmKind::Thm(Some(Proof {heap, head, ..})) => {
                   fn write_lines(w: &mut impl Write, (mut ls, nv): (Vec<Line>, Line)) -> io::Result<()> {
                     ls.push(nv);
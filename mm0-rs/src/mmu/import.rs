@@ -353,7 +353,7 @@ impl<'a> Importer<'a> {
           let ip = self.proof(&mut de, &vars, &mut proofs, ProofKind::Proof)?;
           let (mut ids, heap) = build(&de);
           let hyps = is2.into_iter().map(|i| ids[i].take()).collect();
-          ThmKind::Thm(Some(Proof {heap, hyps, head: ids[ip].take()}))
+          ThmKind::Thm(Some(Proof {heap, hyps, head: ids[ip].take(), spans: Box::new([])}))
         };
         let end = self.close_err()?;
         self.env.add_thm(Thm {
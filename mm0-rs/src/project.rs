@@ -0,0 +1,252 @@
+//! Project manifests, and the `mm0-rs build` subcommand which builds a project.
+//!
+//! A project is described by a manifest file named `mm0.toml` in its root directory:
+//!
+//! ```toml
+//! [project]
+//! name = "peano"
+//! roots = ["peano.mm1", "peano_hex.mm1"] # the files to build
+//! include = ["lib"]                      # directories to search for imported files
+//!
+//! [output]
+//! dir = "build"  # the output directory (default: the project directory)
+//! mmb = true     # write build/peano.mmb for the root peano.mm1
+//! mm0 = true     # write build/peano.mm0
+//! doc = true     # write documentation pages to build/doc/peano/
+//! public = false # omit local defs and non-pub theorems from the MMB output
+//!
+//! [flags]
+//! check-proofs = true
+//! allow-io = false
+//! allow-smt = false            # allow lisp to run the SMT solver `z3 -in`
+//! smt-cmd = "cvc5 --lang smt2"  # or run this solver instead (implies allow-smt)
+//! minimize-proofs = false
+//! warn-unused = false
+//! cache = ".cache"
+//!
+//! [warnings]
+//! all = "warn"
+//! deprecated = "deny"
+//! ```
+//!
+//! All the sections and fields are optional, and all paths are relative to the directory
+//! containing the manifest. The `[warnings]` section sets the level of each
+//! [warning code](crate::elab::warning), where `all` is applied before the others.
+//!
+//! `mm0-rs build` looks for `mm0.toml` in the current directory and its parents (or uses the
+//! manifest given by `--manifest-path`), applies its flags, and builds every root file, or
+//! only the root files given on the command line. The language server uses the include paths
+//! of the manifest of each workspace folder for the files in that project, and applies the
+//! flags of the manifest of the first workspace folder.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use clap::ArgMatches;
+use serde::Deserialize;
+use crate::{FileRef, WarningCode, WarningConfig, WarningLevel};
+use crate::compiler::{elab_for_result, print_unused_warnings, write_mmb};
+use crate::mmb::export::{ExportOptions, ExportSubset};
+
+/// The file name of a project manifest.
+pub const MANIFEST_NAME: &str = "mm0.toml";
+
+/// The `[project]` section of a manifest.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Project {
+  /// The name of the project.
+  pub name: Option<String>,
+  /// The files built by `mm0-rs build`.
+  pub roots: Vec<PathBuf>,
+  /// The directories which are searched for imported files which are not found
+  /// relative to the importing file.
+  pub include: Vec<PathBuf>,
+}
+
+/// The `[output]` section of a manifest.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+#[allow(clippy::struct_excessive_bools)]
+pub struct Output {
+  /// The directory containing the outputs. The default is the project directory.
+  pub dir: Option<PathBuf>,
+  /// Write an MMB file `<root>.mmb` for each root file.
+  pub mmb: bool,
+  /// Write the MM0 specification `<root>.mm0` of each root file.
+  pub mm0: bool,
+  /// Write the documentation pages of each root file to `doc/<root>/`.
+  pub doc: bool,
+  /// Omit local defs and non-pub theorems from the MMB output.
+  pub public: bool,
+}
+
+/// The `[flags]` section of a manifest, which corresponds to the options of `mm0-rs compile`.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+#[allow(clippy::struct_excessive_bools)]
+pub struct Flags {
+  /// Check proofs (see [`set_check_proofs`](crate::set_check_proofs)).
+  pub check_proofs: bool,
+  /// Allow lisp file I/O (see [`set_allow_io`](crate::set_allow_io)).
+  pub allow_io: bool,
+  /// Allow lisp to run the default SMT solver (see [`set_smt_cmd`](crate::set_smt_cmd)).
+  pub allow_smt: bool,
+  /// The command line of the SMT solver. This implies `allow_smt`.
+  pub smt_cmd: Option<String>,
+  /// Minimize proofs (see [`set_minimize_proofs`](crate::set_minimize_proofs)).
+  pub minimize_proofs: bool,
+  /// Warn about unused local defs and theorems after building each root file.
+  pub warn_unused: bool,
+  /// The [`cache`](crate::cache) directory.
+  pub cache: Option<PathBuf>,
+}
+
+impl Default for Flags {
+  fn default() -> Self {
+    Self {
+      check_proofs: true,
+      allow_io: false,
+      allow_smt: false,
+      smt_cmd: None,
+      minimize_proofs: false,
+      warn_unused: false,
+      cache: None,
+    }
+  }
+}
+
+/// A project manifest, as described in the [module documentation](self).
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Manifest {
+  /// The directory containing the manifest, relative to which all paths are resolved.
+  #[serde(skip)]
+  pub dir: PathBuf,
+  /// The `[project]` section.
+  pub project: Project,
+  /// The `[output]` section.
+  pub output: Output,
+  /// The `[flags]` section.
+  pub flags: Flags,
+  /// The `[warnings]` section, mapping warning codes (or `all`) to warning levels.
+  pub warnings: BTreeMap<String, String>,
+}
+
+fn invalid_data(msg: String) -> io::Error { io::Error::new(io::ErrorKind::InvalidData, msg) }
+
+impl Manifest {
+  /// Find the manifest of the project containing the directory `dir`, by looking for
+  /// `mm0.toml` in `dir` and its parents.
+  #[must_use] pub fn find(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors().map(|d| d.join(MANIFEST_NAME)).find(|p| p.is_file())
+  }
+
+  /// Read the manifest at `path`.
+  pub fn load(path: &Path) -> io::Result<Manifest> {
+    let path = fs::canonicalize(path)?;
+    let mut m: Manifest = toml::from_str(&fs::read_to_string(&path)?)
+      .map_err(|e| invalid_data(format!("{}: {}", path.display(), e)))?;
+    m.dir = path.parent().map_or_else(PathBuf::new, Path::to_owned);
+    Ok(m)
+  }
+
+  /// The warning configuration given by the `[warnings]` section.
+  pub fn warning_config(&self) -> io::Result<WarningConfig> {
+    let mut config = WarningConfig::default();
+    let level = |lvl: &str| WarningLevel::from_name(lvl)
+      .ok_or_else(|| invalid_data(format!("unknown warning level '{}'", lvl)));
+    if let Some(lvl) = self.warnings.get("all") { config.set(None, level(lvl)?) }
+    for (code, lvl) in self.warnings.iter().filter(|(code, _)| *code != "all") {
+      let code = WarningCode::from_name(code)
+        .ok_or_else(|| invalid_data(format!("unknown warning code '{}'", code)))?;
+      config.set(Some(code), level(lvl)?)
+    }
+    Ok(config)
+  }
+
+  /// Apply the flags, include paths and warning levels of the manifest to the global options.
+  pub fn apply(&self) -> io::Result<()> {
+    let Flags {check_proofs, allow_io, allow_smt, ref smt_cmd, minimize_proofs, warn_unused: _, ref cache} =
+      self.flags;
+    crate::set_check_proofs(check_proofs);
+    crate::set_allow_io(allow_io);
+    let default_smt = if allow_smt { Some(crate::DEFAULT_SMT_CMD) } else { None };
+    crate::set_smt_cmd(smt_cmd.as_deref().or(default_smt));
+    crate::set_minimize_proofs(minimize_proofs);
+    crate::set_warning_config(self.warning_config()?);
+    crate::set_include_paths(self.project.include.iter().map(|p| self.dir.join(p)).collect());
+    crate::cache::set_cache_dir(cache.as_ref().map(|p| self.dir.join(p)));
+    Ok(())
+  }
+
+  /// Elaborate the root file `root`, and write its outputs.
+  /// Returns false if the file has errors.
+  fn build(&self, root: &Path) -> io::Result<bool> {
+    let path: FileRef = fs::canonicalize(self.dir.join(root))?.into();
+    let (file, env) = elab_for_result(path.clone())?;
+    let env = if let Some(env) = env {env} else { return Ok(false) };
+    if self.flags.warn_unused && print_unused_warnings(&env)? { return Ok(false) }
+    let out = self.output.dir.as_ref().map_or_else(|| self.dir.clone(), |d| self.dir.join(d));
+    let stem = root.file_stem().unwrap_or(root.as_os_str());
+    if self.output.mmb || self.output.mm0 { fs::create_dir_all(&out)? }
+    if self.output.mmb {
+      let w = BufWriter::new(File::create(out.join(stem).with_extension("mmb"))?);
+      let opts = ExportOptions::new()
+        .subset(if self.output.public { ExportSubset::Public } else { ExportSubset::All });
+      write_mmb(&path, &file, &env, w, opts)?
+    }
+    if self.output.mm0 {
+      let mut w = vec![];
+      env.export_mm0(&mut w)?;
+      fs::write(out.join(stem).with_extension("mm0"), w)?;
+    }
+    if self.output.doc {
+      #[cfg(feature = "doc")]
+      crate::doc::write_docs(&path, file.ascii(), &env, out.join("doc").join(stem),
+        crate::doc::ProofOrder::Post, None, None)?;
+      #[cfg(not(feature = "doc"))]
+      return Err(invalid_data("documentation output requires the 'doc' feature".into()))
+    }
+    Ok(true)
+  }
+}
+
+/// Main entry point for `mm0-rs build` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs build [--manifest-path mm0.toml] [ROOT...]`, where:
+///
+/// - `--manifest-path` is the manifest of the project to build. By default, it is the
+///   `mm0.toml` file in the current directory or the nearest parent directory.
+/// - `ROOT` are the root files to build. By default, these are the `roots` of the manifest.
+///
+/// The build fails if one of the root files has errors or its outputs cannot be written,
+/// but the remaining root files are still built.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let path = match args.value_of_os("manifest_path") {
+    Some(p) => PathBuf::from(p),
+    None => Manifest::find(&std::env::current_dir()?).ok_or_else(|| io::Error::new(
+      io::ErrorKind::NotFound, format!("could not find {} in this directory or its parents", MANIFEST_NAME)))?,
+  };
+  let manifest = Manifest::load(&path)?;
+  manifest.apply()?;
+  let roots: Vec<PathBuf> = match args.values_of_os("ROOT") {
+    Some(roots) => roots.map(fs::canonicalize).collect::<io::Result<_>>()?,
+    None => manifest.project.roots.clone(),
+  };
+  if roots.is_empty() {
+    return Err(invalid_data(format!("{}: no root files to build", path.display())))
+  }
+  let mut ok = true;
+  for root in &roots {
+    match manifest.build(root) {
+      Ok(b) => ok &= b,
+      Err(e) => { eprintln!("error: {}: {}", root.display(), e); ok = false }
+    }
+  }
+  if !ok { std::process::exit(1) }
+  Ok(())
+}
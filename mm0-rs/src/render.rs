@@ -0,0 +1,267 @@
+//! Rendering of term and theorem statements to LaTeX and HTML, for inclusion in papers and
+//! generated documentation.
+//!
+//! Expressions are printed using the notation system, like the pretty printer, but each term
+//! can be given a template for each output format, declared in MM1 with
+//! `(set-render-template 'latex 'im "#1 \\to #2")`. In a template, `#1` through `#9` are
+//! replaced by the rendered arguments of the term, and `##` is a literal `#`. The rest of the
+//! template is copied to the output verbatim. When the term has a notation, arguments are
+//! parenthesized according to the precedences of the notation, and otherwise they are never
+//! parenthesized.
+//!
+//! Terms without a template are printed using their notation, with the tokens escaped for the
+//! output format, or as a function application if they have no notation.
+use std::{fmt::Write, io};
+use crate::{AtomId, ArcString, Type, Prec, Literal, ExprNode, TermKind, TermId, ThmId,
+  StmtTrace, DeclKey, FrozenEnv, APP_PREC};
+
+/// An output format of the renderer.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum RenderFormat {
+  /// LaTeX math mode.
+  Latex,
+  /// HTML.
+  Html,
+}
+crate::deep_size_0!(RenderFormat);
+
+impl RenderFormat {
+  /// Parse the name of a format, as it is used in `set-render-template`.
+  #[must_use] pub fn from_name(s: &str) -> Option<Self> {
+    match s {
+      "latex" => Some(Self::Latex),
+      "html" => Some(Self::Html),
+      _ => None,
+    }
+  }
+
+  /// Escape text for this format.
+  fn escape(self, out: &mut String, s: &str) {
+    for c in s.chars() {
+      match (self, c) {
+        (Self::Latex, '\\') => out.push_str("\\textbackslash{}"),
+        (Self::Latex, '~') => out.push_str("\\textasciitilde{}"),
+        (Self::Latex, '^') => out.push_str("\\textasciicircum{}"),
+        (Self::Latex, '{' | '}' | '$' | '&' | '#' | '_' | '%') => {out.push('\\'); out.push(c)}
+        (Self::Html, '&') => out.push_str("&amp;"),
+        (Self::Html, '<') => out.push_str("&lt;"),
+        (Self::Html, '>') => out.push_str("&gt;"),
+        (Self::Html, '"') => out.push_str("&quot;"),
+        _ => out.push(c),
+      }
+    }
+  }
+
+  /// Write `s` in the style of `style`, which is a LaTeX command (without backslash) and an
+  /// HTML element name.
+  fn styled(self, out: &mut String, style: (&str, &str), s: &str) {
+    match self {
+      Self::Latex => {
+        write!(out, "\\{}{{", style.0).expect("write to str");
+        self.escape(out, s);
+        out.push('}')
+      }
+      Self::Html => {
+        write!(out, "<{}>", style.1).expect("write to str");
+        self.escape(out, s);
+        write!(out, "</{}>", style.1).expect("write to str")
+      }
+    }
+  }
+
+  fn var(self, out: &mut String, s: &str) { self.styled(out, ("mathit", "i"), s) }
+  fn token(self, out: &mut String, s: &str) { self.styled(out, ("mathtt", "code"), s) }
+  fn name(self, out: &mut String, s: &str) { self.styled(out, ("mathrm", "span"), s) }
+}
+
+/// Check that `tpl` is a valid template for a term with `nargs` arguments.
+pub fn check_template(tpl: &str, nargs: usize) -> Result<(), String> {
+  let mut it = tpl.chars();
+  while let Some(c) = it.next() {
+    if c == '#' {
+      match it.next() {
+        Some('#') => {}
+        Some(c @ '1'..='9') => {
+          let i = c.to_digit(10).expect("is a digit") as usize;
+          if i > nargs {
+            return Err(format!("template refers to argument #{}, but the term has {} arguments", i, nargs))
+          }
+        }
+        _ => return Err("expected '#1' to '#9' or '##' after '#'".into()),
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Write the rendered expression `e`, which has precedence `q`, parenthesized if the
+/// context requires precedence `p`.
+fn paren(out: &mut String, (q, e): &(Prec, String), p: Prec) {
+  if p > *q {
+    out.push('(');
+    out.push_str(e);
+    out.push(')')
+  } else {
+    out.push_str(e)
+  }
+}
+
+/// Instantiate the template `tpl` with the rendered arguments `args`, which should have at
+/// least the precedences `precs`.
+fn template(tpl: &ArcString, args: &[(Prec, String)], precs: &[Prec]) -> String {
+  let mut out = String::new();
+  let mut it = tpl.as_str().chars();
+  while let Some(c) = it.next() {
+    match (c, if c == '#' { it.next() } else { None }) {
+      ('#', Some(c @ '1'..='9')) => {
+        let i = c.to_digit(10).expect("is a digit") as usize - 1;
+        paren(&mut out, &args[i], precs[i])
+      }
+      // `##` is a literal `#`
+      _ => out.push(c),
+    }
+  }
+  out
+}
+
+/// The renderer for the expressions in a single declaration.
+struct Renderer<'a> {
+  env: &'a FrozenEnv,
+  fmt: RenderFormat,
+  /// The rendering and precedence of each heap element
+  heap: Vec<(Prec, String)>,
+}
+
+impl<'a> Renderer<'a> {
+  fn new(env: &'a FrozenEnv, fmt: RenderFormat, args: &[(Option<AtomId>, Type)], heap: &[ExprNode]) -> Self {
+    let mut r = Self { env, fmt, heap: vec![] };
+    for &(a, _) in args {
+      let mut out = String::new();
+      fmt.var(&mut out, a.map_or("_", |a| r.env.data()[a].name().as_str()));
+      r.heap.push((Prec::Max, out))
+    }
+    for e in heap.get(args.len()..).unwrap_or_default() {
+      let e = r.expr(e);
+      r.heap.push(e)
+    }
+    r
+  }
+
+  fn app(&mut self, t: TermId, es: &[ExprNode]) -> (Prec, String) {
+    let env = self.env;
+    let pe = env.pe();
+    let args = es.iter().map(|e| self.expr(e)).collect::<Vec<_>>();
+    let nota = match pe.decl_nota.get(&t) {
+      Some(&(true, _)) => return args.into_iter().next().expect("coercion has one argument"),
+      Some((false, fix)) => fix.first().map(|(tk, infix)|
+        (tk, if *infix {&pe.infixes[tk]} else {&pe.prefixes[tk]}, *infix)),
+      None => None,
+    };
+    let mut precs = vec![Prec::Prec(0); args.len()];
+    if let Some((_, info, _)) = nota {
+      for lit in &info.lits {
+        if let Literal::Var(i, p) = *lit { precs[i] = p }
+      }
+    }
+    if let Some(tpl) = pe.templates.get(&(t, self.fmt)) {
+      let prec = nota.map_or(Prec::Max, |(tk, _, _)| pe.consts[tk].1);
+      return (prec, template(tpl, &args, &precs))
+    }
+    let mut out = String::new();
+    if let Some((tk, info, infix)) = nota {
+      if !infix { self.fmt.token(&mut out, tk.as_str()) }
+      for lit in &info.lits {
+        if !out.is_empty() { out.push(' ') }
+        match *lit {
+          Literal::Var(i, p) => paren(&mut out, &args[i], p),
+          Literal::Const(ref c) => self.fmt.token(&mut out, c.as_str()),
+        }
+      }
+      return (pe.consts[tk].1, out)
+    }
+    self.fmt.name(&mut out, env.data()[env.term(t).atom].name().as_str());
+    if args.is_empty() { return (Prec::Max, out) }
+    for e in &args {
+      out.push(' ');
+      paren(&mut out, e, Prec::Max)
+    }
+    (APP_PREC, out)
+  }
+
+  fn expr(&mut self, e: &ExprNode) -> (Prec, String) {
+    match *e {
+      ExprNode::Ref(i) => self.heap[i].clone(),
+      ExprNode::Dummy(a, _) => {
+        let mut out = String::new();
+        self.fmt.var(&mut out, self.env.data()[a].name().as_str());
+        (Prec::Max, out)
+      }
+      ExprNode::App(t, ref es) => self.app(t, es),
+    }
+  }
+}
+
+impl FrozenEnv {
+  /// Render the statement of a term or def. A term is rendered as `t x y : s`, and a def as
+  /// `t x y := e`, using the template or notation of `t`.
+  #[must_use] pub fn render_term(&self, fmt: RenderFormat, t: TermId) -> String {
+    let td = self.term(t);
+    let heap = if let TermKind::Def(Some(e)) = &td.kind {&*e.heap} else {&[]};
+    let mut r = Renderer::new(self, fmt, &td.args, heap);
+    let lhs = (0..td.args.len()).map(ExprNode::Ref).collect::<Vec<_>>();
+    let mut out = r.app(t, &lhs).1;
+    if let TermKind::Def(Some(e)) = &td.kind {
+      out.push_str(" := ");
+      out.push_str(&r.expr(&e.head).1)
+    } else {
+      out.push_str(" : ");
+      fmt.name(&mut out, self.sort(td.ret.0).name.as_str())
+    }
+    out
+  }
+
+  /// Render the statement of an axiom or theorem, as `h1, h2 ⊢ c`.
+  #[must_use] pub fn render_thm(&self, fmt: RenderFormat, t: ThmId) -> String {
+    let td = self.thm(t);
+    let mut r = Renderer::new(self, fmt, &td.args, &td.heap);
+    let (sep, vdash) = match fmt {
+      RenderFormat::Latex => (",\\ ", "\\vdash "),
+      RenderFormat::Html => (", ", "⊢ "),
+    };
+    let mut out = String::new();
+    for (i, (_, h)) in td.hyps.iter().enumerate() {
+      if i != 0 { out.push_str(sep) }
+      out.push_str(&r.expr(h).1)
+    }
+    if !td.hyps.is_empty() { out.push(' ') }
+    out.push_str(vdash);
+    out.push_str(&r.expr(&td.ret).1);
+    out
+  }
+  /// Write the statements of all terms and theorems in this environment, as a LaTeX
+  /// `description` environment or an HTML `dl` element.
+  pub fn export_rendered(&self, fmt: RenderFormat, mut w: impl io::Write) -> io::Result<()> {
+    let w = &mut w;
+    match fmt {
+      RenderFormat::Latex => writeln!(w, "\\begin{{description}}")?,
+      RenderFormat::Html => writeln!(w, "<dl class=\"mm0\">")?,
+    }
+    for s in self.stmts() {
+      let a = if let StmtTrace::Decl(a) = *s {a} else {continue};
+      let stmt = match self.data()[a].decl().expect("expected a term/thm") {
+        DeclKey::Term(t) => self.render_term(fmt, t),
+        DeclKey::Thm(t) => self.render_thm(fmt, t),
+      };
+      let mut name = String::new();
+      fmt.escape(&mut name, self.data()[a].name().as_str());
+      match fmt {
+        RenderFormat::Latex => writeln!(w, "\\item[\\texttt{{{}}}] ${}$", name, stmt)?,
+        RenderFormat::Html => writeln!(w, "<dt><code>{}</code></dt><dd>{}</dd>", name, stmt)?,
+      }
+    }
+    match fmt {
+      RenderFormat::Latex => writeln!(w, "\\end{{description}}"),
+      RenderFormat::Html => writeln!(w, "</dl>"),
+    }
+  }
+}
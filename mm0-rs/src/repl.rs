@@ -0,0 +1,109 @@
+//! An interactive lisp REPL.
+//!
+//! The `mm0-rs repl [file.mm1]` subcommand elaborates `file.mm1` (if given), and then reads
+//! lisp expressions from the terminal and evaluates them in the resulting environment, as if
+//! they were in a `do` block at the end of the file. The (non-`#undef`) results are
+//! pretty printed. Input continues onto the next line as long as there are unclosed brackets,
+//! strings or formulas, and the input history is saved in `~/.mm0-rs-history`.
+//! Type `:quit` or end the input (`Ctrl-D`) to exit, or use `Ctrl-C` to discard the
+//! current input.
+use std::{fs, io};
+use std::path::PathBuf;
+use std::sync::Arc;
+use clap::ArgMatches;
+use rustyline::{Editor, error::ReadlineError};
+use mm1_parser::parse;
+use crate::compiler::{elab_for_result, print_errors, set_virtual_file};
+use crate::elab::{CancelToken, Elaborator};
+use crate::{FileRef, Span};
+
+/// The line width used for pretty printing results.
+const WIDTH: usize = 100;
+
+/// Returns true if `s` has no unclosed brackets, strings or formulas, so that it can be
+/// evaluated. Otherwise the REPL asks for another line of input.
+fn is_complete(s: &str) -> bool {
+  let mut depth = 0_i32;
+  let mut it = s.bytes();
+  while let Some(c) = it.next() {
+    match c {
+      b'(' | b'[' | b'{' => depth += 1,
+      b')' | b']' | b'}' => depth -= 1,
+      b'"' => loop {
+        match it.next() {
+          None => return false,
+          Some(b'"') => break,
+          Some(b'\\') => { it.next(); }
+          Some(_) => {}
+        }
+      },
+      b'$' if !it.any(|c| c == b'$') => return false,
+      b'-' if it.clone().next() == Some(b'-') => { it.find(|&c| c == b'\n'); }
+      _ => {}
+    }
+  }
+  depth <= 0
+}
+
+/// Main entry point for `mm0-rs repl` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs repl [file.mm1]`, where:
+///
+/// - `file.mm1` (optional) is the file to elaborate before starting the REPL.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let env = match args.value_of("INPUT") {
+    Some(path) => {
+      let path: FileRef = fs::canonicalize(path)?.into();
+      Some(elab_for_result(path)?.1.unwrap_or_else(|| std::process::exit(1)))
+    }
+    None => None
+  };
+  // The REPL input is accumulated into a virtual file, so that values defined
+  // in the REPL can point to their definitions in error messages.
+  let path: FileRef = std::env::current_dir()?.join("<repl>").into();
+  let mut text = String::new();
+  let ast = parse(set_virtual_file(path.clone(), text.clone()), None).1;
+  let mut elab = Elaborator::new(Arc::new(ast), path.clone(),
+    false, crate::get_check_proofs(), CancelToken::new(), None);
+  if let Some(env) = &env {
+    elab.with_arena(|elab| elab.import_env(Span::default(), env, true))
+  }
+  let mut rl = Editor::<()>::new();
+  let history = std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".mm0-rs-history"));
+  if let Some(h) = &history { drop(rl.load_history(h)) }
+  let mut input = String::new();
+  loop {
+    match rl.readline(if input.is_empty() {"> "} else {". "}) {
+      Ok(line) => {
+        if input.is_empty() && matches!(line.trim(), ":q" | ":quit") { break }
+        input.push_str(&line);
+        input.push('\n');
+        if !is_complete(&input) { continue }
+      }
+      Err(ReadlineError::Interrupted) => { input.clear(); continue }
+      Err(ReadlineError::Eof) => break,
+      Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+    }
+    let input = std::mem::take(&mut input);
+    if input.trim().is_empty() { continue }
+    rl.add_history_entry(input.trim_end());
+    let start = text.len();
+    text.push_str("do {\n");
+    text.push_str(&input);
+    text.push_str("};\n");
+    let (_, ast) = parse(set_virtual_file(path.clone(), text.clone()), None);
+    let src = ast.source.clone();
+    if !ast.errors.is_empty() {
+      print_errors(&path, &src, &ast.errors, &[]);
+      text.truncate(start);
+      continue
+    }
+    let (vals, errors) = elab.with_arena(|elab| elab.eval_repl(&Arc::new(ast)));
+    print_errors(&path, &src, &[], &errors);
+    for v in &vals { println!("{}", elab.format_env().pp(v, WIDTH)) }
+  }
+  if let Some(h) = &history { drop(rl.save_history(h)) }
+  Ok(())
+}
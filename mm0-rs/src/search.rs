@@ -0,0 +1,189 @@
+//! Theorem search, which finds the theorems whose statement matches a pattern.
+//!
+//! A pattern is a term s-expression, usually written as a formula like `$ _ -> (a -> _) $`,
+//! where `_` matches any subterm, and an atom which is not a term constructor is a pattern
+//! variable, which matches any subterm but must match the same subterm at each occurrence.
+//! Definitions in the theorem statement can be unfolded up to a given depth to find a match,
+//! so that with depth 1 the pattern `$ _ -> _ $` also finds theorems about `$ ~ a $`, if `~`
+//! is a definition in terms of `->`.
+//!
+//! The search is available as the `(search)` lisp builtin, and the `mm0-rs search`
+//! subcommand runs a search on the environment of a file.
+use std::{fs, io};
+use std::rc::Rc;
+use std::sync::Arc;
+use clap::{value_t_or_exit, ArgMatches};
+use mm1_parser::{ast::Formula, parse};
+use crate::elab::{CancelToken, Elaborator};
+use crate::{AtomId, DeclKey, ExprNode, FileRef, LispKind, LispVal, Span, TermId, TermKind,
+  ThmId};
+
+/// A search pattern, compiled from a lisp term s-expression by [`Elaborator::search`].
+#[derive(Debug)]
+enum Pattern {
+  /// `_`, which matches anything.
+  Any,
+  /// A pattern variable, which matches anything, and must match the same term at each
+  /// occurrence. The index is the position of the variable in the variable list.
+  Var(usize),
+  /// An application of a term constructor to a list of argument patterns.
+  App(TermId, Box<[Pattern]>),
+}
+
+/// A theorem statement or unfolded definition, which is matched against a [`Pattern`].
+/// Unlike [`ExprNode`], this does not refer to a heap, which makes it easy to substitute
+/// the arguments into a definition when unfolding it.
+#[derive(Debug, PartialEq, Eq)]
+enum Tree {
+  /// The variable with the given index in the theorem binders.
+  Var(usize),
+  /// A dummy variable in an unfolded definition.
+  Dummy(AtomId),
+  /// An application of a term constructor.
+  App(TermId, Box<[Rc<Tree>]>),
+}
+
+impl Tree {
+  /// Convert an [`ExprNode`] into a [`Tree`], where `heap` contains the terms for the
+  /// heap references in `e`.
+  fn from_node(heap: &[Rc<Tree>], e: &ExprNode) -> Rc<Tree> {
+    match *e {
+      ExprNode::Ref(i) => heap[i].clone(),
+      ExprNode::Dummy(a, _) => Rc::new(Tree::Dummy(a)),
+      ExprNode::App(t, ref es) =>
+        Rc::new(Tree::App(t, es.iter().map(|e| Tree::from_node(heap, e)).collect())),
+    }
+  }
+}
+
+impl Elaborator {
+  fn search_pattern(&self, vars: &mut Vec<AtomId>, e: &LispVal) -> Result<Pattern, String> {
+    let app = |t: TermId, args: Vec<Pattern>| {
+      let td = &self.terms[t];
+      if td.args.len() == args.len() { Ok(Pattern::App(t, args.into())) } else {
+        Err(format!("search: '{}' expects {} arguments", self.data[td.atom].name, td.args.len()))
+      }
+    };
+    e.unwrapped(|r| match r {
+      &LispKind::Atom(AtomId::UNDER) => Ok(Pattern::Any),
+      &LispKind::Atom(a) => match self.data[a].decl {
+        Some(DeclKey::Term(t)) => app(t, vec![]),
+        _ => Ok(Pattern::Var(vars.iter().position(|&b| a == b).unwrap_or_else(|| {
+          vars.push(a);
+          vars.len() - 1
+        }))),
+      },
+      LispKind::List(es) if !es.is_empty() => match es[0].as_atom().and_then(|a| self.data[a].decl) {
+        Some(DeclKey::Term(t)) => app(t, es[1..].iter()
+          .map(|e| self.search_pattern(vars, e)).collect::<Result<_, _>>()?),
+        _ => Err(format!("search: expected a term constructor, got {}", self.format_env().pp(&es[0], 80))),
+      },
+      _ => Err(format!("search: invalid pattern {}", self.format_env().pp(e, 80))),
+    })
+  }
+
+  /// Unfold the definition application `t`, if it is one, returning the body of the
+  /// definition with the arguments substituted.
+  fn search_unfold(&self, t: &Tree) -> Option<Rc<Tree>> {
+    let (val, args) = match t {
+      Tree::App(t, args) => match &self.terms[*t].kind {
+        TermKind::Def(Some(val)) => (val, args),
+        _ => return None,
+      },
+      _ => return None,
+    };
+    let mut heap = args.to_vec();
+    for e in &val.heap[args.len()..] {
+      let e = Tree::from_node(&heap, e);
+      heap.push(e)
+    }
+    Some(Tree::from_node(&heap, &val.head))
+  }
+
+  /// Returns true if `e` matches pattern `p`, unfolding up to `depth` nested definitions.
+  /// `vars` contains the assignments of the pattern variables, which are restored
+  /// if the match fails.
+  fn search_match(&self, p: &Pattern, e: &Rc<Tree>, depth: u32, vars: &mut [Option<Rc<Tree>>]) -> bool {
+    match p {
+      Pattern::Any => true,
+      Pattern::Var(i) => if let Some(e2) = &vars[*i] { e == e2 } else {
+        vars[*i] = Some(e.clone());
+        true
+      },
+      Pattern::App(t, ps) => {
+        if let Tree::App(u, es) = &**e {
+          if t == u {
+            let old = vars.to_vec();
+            if ps.iter().zip(&**es).all(|(p, e)| self.search_match(p, e, depth, vars)) { return true }
+            vars.clone_from_slice(&old);
+            return false
+          }
+        }
+        depth != 0 && self.search_unfold(e).map_or(false, |e| self.search_match(p, &e, depth - 1, vars))
+      }
+    }
+  }
+
+  /// Find the theorems and axioms whose conclusion (or one of whose hypotheses, if `hyps`
+  /// is true) matches the pattern `pat`, unfolding up to `depth` nested definitions in the
+  /// statement. See the [module documentation](self) for the pattern syntax.
+  pub fn search(&self, pat: &LispVal, depth: u32, hyps: bool) -> Result<Vec<ThmId>, String> {
+    let mut vars = vec![];
+    let pat = self.search_pattern(&mut vars, pat)?;
+    let mut res = vec![];
+    for (tid, td) in self.thms.enum_iter() {
+      let mut heap: Vec<_> = (0..td.args.len()).map(|i| Rc::new(Tree::Var(i))).collect();
+      for e in &td.heap[td.args.len()..] {
+        let e = Tree::from_node(&heap, e);
+        heap.push(e)
+      }
+      let test = |e| {
+        let mut assn = vec![None; vars.len()];
+        self.search_match(&pat, &Tree::from_node(&heap, e), depth, &mut assn)
+      };
+      if test(&td.ret) || hyps && td.hyps.iter().any(|(_, e)| test(e)) { res.push(tid) }
+    }
+    Ok(res)
+  }
+}
+
+/// Main entry point for `mm0-rs search` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs search [--unfold N] [--hyps] <in.mm1> <pattern>`, where:
+///
+/// - `in.mm1` is the MM1 (or MM0) file to elaborate.
+/// - `pattern` is a math expression in the notation of `in.mm1`, like `_ -> (a -> _)`.
+///   See the [module documentation](self) for the pattern syntax.
+/// - `--unfold` sets the number of nested definitions that may be unfolded (default 0).
+/// - `--hyps` also finds theorems with a hypothesis matching the pattern.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let path = args.value_of("INPUT").expect("required arg");
+  let path: FileRef = fs::canonicalize(path)?.into();
+  crate::compiler::set_quiet(true);
+  let env = crate::compiler::elab_for_result(path)?.1.unwrap_or_else(|| std::process::exit(1));
+  let depth = if args.is_present("unfold") { value_t_or_exit!(args, "unfold", u32) } else { 0 };
+  // The pattern is parsed as a formula in a virtual file, using the notations of the input file.
+  let text = format!("${}$", args.value_of("PATTERN").expect("required arg"));
+  let pat_path: FileRef = std::env::current_dir()?.join("<search>").into();
+  let len = text.len();
+  let ast = parse(crate::compiler::set_virtual_file(pat_path.clone(), text), None).1;
+  let mut elab = Elaborator::new(Arc::new(ast), pat_path, false, false, CancelToken::new(), None);
+  elab.import_env(Span::default(), &env, true);
+  let res = elab.parse_formula(Formula((0..len).into()))
+    .and_then(|q| elab.eval_qexpr(q))
+    .map_err(|e| e.kind.msg())
+    .and_then(|pat| elab.search(&pat, depth, args.is_present("hyps")));
+  let thms = res.unwrap_or_else(|e| {
+    eprintln!("error: {}", e);
+    std::process::exit(1)
+  });
+  let fe = elab.format_env();
+  for t in thms {
+    let mut s = String::new();
+    fe.pretty(|pr| pr.thm(t).render_fmt(100, &mut s)).expect("writing to a string");
+    println!("{}", s)
+  }
+  Ok(())
+}
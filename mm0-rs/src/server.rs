@@ -5,9 +5,9 @@ use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}, Condvar};
 use std::collections::{VecDeque, HashMap, HashSet, hash_map::{Entry, DefaultHasher}};
 use std::hash::{Hash, Hasher};
 use std::thread::{ThreadId, self};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use futures::{FutureExt, future::BoxFuture};
-use futures::channel::oneshot::{Sender as FSender, channel};
+use futures::channel::oneshot::{Sender as FSender, Receiver as FReceiver, channel};
 use futures::executor::ThreadPool;
 use futures::lock::Mutex as FMutex;
 use lsp_server::{Connection, ErrorCode, Message, Notification, ProtocolError,
@@ -19,17 +19,21 @@ use serde::Deserialize;
 #[allow(clippy::wildcard_imports)] use lsp_types::*;
 use crossbeam::channel::{SendError, RecvError};
 use clap::ArgMatches;
-use crate::{ArcList, ArcString, BoxError, FileRef, FileSpan, Span,
+use crate::{cache, ArcList, ArcString, BoxError, ErrorLevel, FileRef, FileSpan, Span,
   MutexExt, CondvarExt};
-use mm1_parser::{Ast, parse};
+use mm1_parser::{Ast, parse, ast::{self, Atom, Decl, DeclKind, LocalKind, SExpr, SExprKind, Stmt, StmtKind}};
 use crate::mmb::import::elab as mmb_elab;
+use mm0b_parser::{BasicIndex, MmbFile, SourceMap};
+use crate::mm::import::elab as mm_elab;
 use crate::mmu::import::elab as mmu_elab;
 use crate::compiler::FileContents;
-use crate::{ObjectKind, DeclKey, StmtTrace, AtomId, SortId, TermId, ThmId, LinedString, FrozenEnv,
-  FrozenLispKind, FrozenAtomData};
-use crate::elab::{ElabResult, ElaborateBuilder, GoalListener,
-  local_context::InferSort, proof::Subst,
-  lisp::{print::FormatEnv, pretty::Pretty, LispKind, Proc, BuiltinProc},
+use crate::project::{Manifest, MANIFEST_NAME};
+use crate::{ObjectKind, DeclKey, StmtTrace, TermKind, AtomId, SortId, TermId, ThmId, LinedString, FrozenEnv,
+  FrozenLispKind, FrozenLispVal, FrozenAtomData, Deprecation, DocComment, ParserEnv, Type,
+  Environment, ExprNode, LispVal, Uncons, near_misses};
+use crate::elab::{CancelToken, ElabError, ElabResult, ElaborateBuilder, GoalListener, GoalStateListener,
+  StmtListener, environment::Literal, local_context::InferSort, proof::Subst,
+  lisp::{print::FormatEnv, pretty::Pretty, LispKind, Proc, ProcSpec, BuiltinProc, Syntax},
   spans::Spans};
 
 // Disabled because vscode doesn't handle them properly
@@ -147,16 +151,135 @@ macro_rules! log {
   ($($es:tt)*) => {crate::server::log(format!($($es)*))}
 }
 
+/// The time after which a running elaboration starts to report its progress.
+const PROGRESS_DELAY: Duration = Duration::from_millis(500);
+
+/// The time between two publications of the diagnostics found so far by a running elaboration.
+const PARTIAL_DIAGNOSTICS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The progress of an elaboration of a file. Once the elaboration has taken longer than
+/// [`PROGRESS_DELAY`], the statement being elaborated is reported with `$/progress`, and
+/// the diagnostics found so far are published every [`PARTIAL_DIAGNOSTICS_INTERVAL`],
+/// so that errors near the start of a long file are shown before it finishes.
+struct ElabProgress {
+  path: FileRef,
+  version: Option<i32>,
+  source: Arc<LinedString>,
+  cancel: CancelToken,
+  start: Instant,
+  /// The `$/progress` token, once it has been requested from the client.
+  token: Option<RequestId>,
+  /// True if the `begin` notification has been sent.
+  begun: bool,
+  /// The last percentage reported.
+  percentage: u32,
+  /// The time at which the diagnostics were last published.
+  last_diag: Instant,
+}
+
+impl ElabProgress {
+  fn new(path: FileRef, version: Option<i32>, source: Arc<LinedString>, cancel: CancelToken) -> Self {
+    let start = Instant::now();
+    ElabProgress {path, version, source, cancel, start, token: None, begun: false, percentage: 0, last_diag: start}
+  }
+
+  fn notify(token: &RequestId, value: WorkDoneProgress) -> Result<()> {
+    send_message(Notification {
+      method: "$/progress".to_owned(),
+      params: to_value(ProgressParams {
+        token: NumberOrString::String(token.to_string()),
+        value: ProgressParamsValue::WorkDone(value),
+      })?
+    })
+  }
+
+  /// Called by the [`StmtListener`] before the statement `idx` of `ast` is elaborated.
+  fn step(&mut self, ast: &Ast, errors: &[ElabError], idx: usize) -> Result<()> {
+    if self.cancel.is_canceled() || self.start.elapsed() < PROGRESS_DELAY { return Ok(()) }
+    if SERVER.caps.ulock().work_done_progress {
+      #[allow(clippy::cast_possible_truncation, clippy::integer_division)] // rounded down, and at most 100
+      let percentage = (idx * 100 / ast.stmts.len().max(1)) as u32;
+      let message = || {
+        let s = &ast.stmts[idx];
+        let line = ast.source.to_pos(s.span.start).line + 1;
+        Some(match find_decl(s) {
+          Some(d) => format!("{} (line {})", String::from_utf8_lossy(ast.span(d.id)), line),
+          None => format!("line {}", line),
+        })
+      };
+      match &self.token {
+        None => {
+          // The token can only be used once the client has created it, which is
+          // recorded in `Server::progress` when the response arrives.
+          static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+          let token = RequestId::from(format!("elab/{}", NEXT.fetch_add(1, Ordering::Relaxed)));
+          SERVER.progress.ulock().insert(token.clone(), false);
+          send_message(Request {
+            id: token.clone(),
+            method: "window/workDoneProgress/create".to_owned(),
+            params: to_value(WorkDoneProgressCreateParams {token: NumberOrString::String(token.to_string())})?
+          })?;
+          self.token = Some(token);
+        }
+        Some(token) if !self.begun => if SERVER.progress.ulock().get(token) == Some(&true) {
+          Self::notify(token, WorkDoneProgress::Begin(WorkDoneProgressBegin {
+            title: format!("Elaborating {}", self.path.rel()),
+            cancellable: Some(false),
+            message: message(),
+            percentage: Some(percentage),
+          }))?;
+          self.begun = true;
+          self.percentage = percentage;
+        },
+        Some(token) => if percentage != self.percentage {
+          Self::notify(token, WorkDoneProgress::Report(WorkDoneProgressReport {
+            cancellable: Some(false),
+            message: message(),
+            percentage: Some(percentage),
+          }))?;
+          self.percentage = percentage;
+        },
+      }
+    }
+    if self.last_diag.elapsed() >= PARTIAL_DIAGNOSTICS_INTERVAL {
+      let mut to_loc = |fsp: &FileSpan| match SERVER.vfs.get(&fsp.file) {
+        Some(file) if !fsp.file.ptr_eq(&self.path) => match file.text.ulock().1.try_ascii() {
+          Some(text) => text.to_loc(fsp),
+          None => Location {uri: fsp.file.url().clone(), range: Range::default()},
+        },
+        _ => self.source.to_loc(fsp),
+      };
+      let diags = ast.errors.iter().map(|e| e.to_diag(&self.source))
+        .chain(errors.iter().map(|e| e.to_diag(&self.source, &mut to_loc)))
+        .filter(|e| !e.message.is_empty()).collect();
+      send_diagnostics(self.path.url().clone(), self.version, diags)?;
+      self.last_diag = Instant::now();
+    }
+    Ok(())
+  }
+
+  /// End the progress report, if it was started.
+  fn finish(&mut self) -> Result<()> {
+    if let Some(token) = self.token.take() {
+      SERVER.progress.ulock().remove(&token);
+      if self.begun {
+        Self::notify(&token, WorkDoneProgress::End(WorkDoneProgressEnd {message: None}))?
+      }
+    }
+    Ok(())
+  }
+}
+
 async fn elaborate(path: FileRef, start: Option<Position>,
-    cancel: Arc<AtomicBool>, rd: ArcList<FileRef>) -> Result<ElabResult<u64>> {
+    cancel: CancelToken, rd: ArcList<FileRef>) -> Result<ElabResult<u64>> {
   let vfs = &SERVER.vfs;
   debug_assert!(!rd.contains(&path));
   let (path, file) = vfs.get_or_insert(path)?;
   let v = file.text.ulock().0;
   let (old_ast, old_env, old_deps) = {
     let mut g = file.parsed.lock().await;
-    let (old, res, senders) = match &mut *g {
-      None => (None, (None, None, vec![]), vec![]),
+    let (old, last, res, senders) = match &mut *g {
+      None => (None, None, (None, None, vec![]), vec![]),
       &mut Some(FileCache::InProgress {version, ref cancel, ref mut senders, ..}) => {
         if v == version {
           let (send, recv) = channel();
@@ -164,9 +287,15 @@ async fn elaborate(path: FileRef, start: Option<Position>,
           drop(g);
           return Ok(recv.await.unwrap_or(ElabResult::Canceled))
         }
-        cancel.store(true, Ordering::SeqCst);
-        let_unchecked!(Some(FileCache::InProgress {old, senders, ..}) = g.take(), {
-          (old, (None, None, vec![]), senders)
+        cancel.cancel();
+        let_unchecked!(Some(FileCache::InProgress {old, last, senders, ..}) = g.take(), {
+          let res = match (start, &last) {
+            (Some(s), Some((source, ast, errors, env))) => (
+              Some((s, source.clone(), Some(ast.clone()))),
+              Some((errors.clone(), env.clone())), vec![]),
+            _ => (None, None, vec![]),
+          };
+          (old, last, res, senders)
         })
       }
       &mut Some(FileCache::Ready {hash, ref deps, ref res, ..}) => {
@@ -189,46 +318,73 @@ async fn elaborate(path: FileRef, start: Option<Position>,
         }
         let_unchecked!(Some(FileCache::Ready {ast, source, deps, res, ..}) = g.take(), {
           if let ElabResult::Ok(_, errors, env) = res {
-            (Some((source.clone(), env.clone())),
+            let last = ast.clone().map(|ast| (source.clone(), ast, errors.clone(), env.clone()));
+            (Some((source.clone(), env.clone())), last,
               (start.map(|s| (s, source, ast)), Some((errors, env)), deps), vec![])
           } else {
-            (None, (None, None, vec![]), vec![])
+            (None, None, (None, None, vec![]), vec![])
           }
         })
       }
     };
-    *g = Some(FileCache::InProgress {old, version: v, cancel: cancel.clone(), senders});
+    *g = Some(FileCache::InProgress {old, last, version: v, cancel: cancel.clone(), senders});
     drop(g);
     res
   };
   let (version, text) = file.text.ulock().clone();
-  let old_ast = old_ast.and_then(|(s, old_text, ast)|
-    if text.ptr_eq(&old_text) {Some((s, ast?))} else {None});
+  // `s` is the start of the latest edit, but there may have been other edits since the
+  // last elaboration, so the texts are compared as well.
+  let old_ast = old_ast.and_then(|(s, old_text, ast)| {
+    let (old, new) = (old_text.try_ascii()?, text.try_ascii()?);
+    let diff = old.as_bytes().iter().zip(new.as_bytes()).position(|(a, b)| a != b)
+      .unwrap_or_else(|| old.len().min(new.len()));
+    Some((new.to_pos(diff.min(new.to_idx(s)?)), ast?))
+  });
   let mut hasher = DefaultHasher::new();
   version.hash(&mut hasher);
   let source = text.clone();
 
   let mut deps = Vec::new();
+  // Files which are not open in the editor can be loaded from the cache.
+  let cached = if version.is_some() {None} else {
+    cache::load(&path, &text, |p|
+      Some(cache::content_hash(&vfs.get_or_insert(p.clone()).ok()?.1.text.ulock().1)))
+  };
+  let mut trans = vec![];
   let (ast, (cyc, toks, errors, env)) = if path.has_extension("mmb") {
     let (error, env) = mmb_elab(&path, &text);
     let errors = if let Err(e) = error {vec![e]} else {vec![]};
-    (None, (None, vec![], errors, FrozenEnv::new(env)))
+    let env = FrozenEnv::new(env);
+    *file.mmb_listing.ulock() = Some(Arc::new(MmbListing::new(&text, &env, &errors)));
+    (None, (None, vec![], errors, env))
   } else if path.has_extension("mmu") {
     let (error, env) = mmu_elab(&path, &text);
     let errors = if let Err(e) = error {vec![e]} else {vec![]};
     (None, (None, vec![], errors, FrozenEnv::new(env)))
+  } else if path.has_extension("mm") {
+    let (error, env) = mm_elab(&path, &text);
+    let errors = if let Err(e) = error {vec![e]} else {vec![]};
+    (None, (None, vec![], errors, FrozenEnv::new(env)))
+  } else if let Some(entry) = cached {
+    deps = entry.deps;
+    trans = entry.trans;
+    (None, (None, vec![cache::content_hash(&text)], vec![], entry.env))
   } else {
     let (idx, ast) = parse(text.ascii().clone(), old_ast);
     let ast = Arc::new(ast);
     let rd = rd.push(path.clone());
+    let progress = Arc::new(Mutex::new(
+      ElabProgress::new(path.clone(), version, text.ascii().clone(), cancel.clone())));
     let elab = ElaborateBuilder {
       ast: &ast,
       path: path.clone(),
       mm0_mode: path.has_extension("mm0"),
       check_proofs: crate::get_check_proofs(),
-      report_upstream_errors: SERVER.options.ulock().report_upstream_errors.unwrap_or(true),
+      report_upstream_errors: SERVER.options_for(&path).report_upstream_errors.unwrap_or(true),
       cancel: cancel.clone(),
       old: old_env.map(|(errs, e)| (idx, errs, e)),
+      snapshots: true,
+      profile: false,
       recv_dep: |p| {
         let (p, dep) = vfs.get_or_insert(p)?;
         let (send, recv) = channel();
@@ -245,6 +401,14 @@ async fn elaborate(path: FileRef, start: Option<Position>,
         }
         Ok(recv)
       },
+      recv_goal_state: None,
+      recv_stmt: Some({
+        let (progress, ast) = (progress.clone(), ast.clone());
+        StmtListener::new(move |elab: &crate::elab::Elaborator, idx| {
+          let res = progress.ulock().step(&ast, elab.errors(), idx);
+          if let Err(e) = res { log!("failed to report progress: {:?}", e) }
+        })
+      }),
       recv_goal: start.filter(|_| SERVER.caps.ulock().goal_view)
         .and_then(|start| ast.source.to_idx(start))
         .filter(|&pos| pos != 0)
@@ -255,14 +419,30 @@ async fn elaborate(path: FileRef, start: Option<Position>,
             }
           })
         }),
+      debugger: None,
     }.elab();
-    (Some(ast.clone()), elab.await)
+    let res = elab.await;
+    progress.ulock().finish()?;
+    (Some(ast.clone()), res)
   };
   for tok in toks {tok.hash(&mut hasher)}
   let hash = hasher.finish();
-  let is_canceled = cancel.load(Ordering::SeqCst);
+  let is_canceled = cancel.is_canceled();
   log!("elabbed {:?}{}", path, if is_canceled {" (canceled)"} else {""});
   let no_change_since_elab = file.text.ulock().0 == version;
+  if !is_canceled && ast.is_some() && cache::cache_dir().is_some() {
+    trans = transitive_deps(&deps).await;
+    if version.is_none() && cyc.is_none() && errors.is_empty() &&
+      ast.as_ref().map_or(false, |ast| ast.errors.is_empty())
+    {
+      let hashes = trans.iter().map(|p| Ok((p.clone(),
+        cache::content_hash(&vfs.get_or_insert(p.clone())?.1.text.ulock().1))))
+        .collect::<io::Result<Vec<_>>>()?;
+      if let Err(e) = cache::store(&path, &source, &deps, &hashes, &env) {
+        log!("could not write cache for {:?}: {}", path, e)
+      }
+    }
+  }
   if !is_canceled && no_change_since_elab {
     let mut srcs = HashMap::new();
     let mut to_loc = |fsp: &FileSpan| -> Location {
@@ -284,8 +464,16 @@ async fn elaborate(path: FileRef, start: Option<Position>,
     if let Some(ast) = &ast {
       use std::fmt::Write;
       let (mut n_errs, mut n_warns, mut n_infos, mut n_hints) = (0, 0, 0, 0);
+      // Unused declaration warnings are not added to `errors`, because they should not
+      // be reported upstream when this file is imported.
+      let unused = if SERVER.options_for(&path).warn_unused.unwrap_or(false) {
+        let config = crate::get_warning_config();
+        env.unused_decl_warnings(Some(&path)).into_iter()
+          .filter_map(|(file, e)| Some((file, config.apply(e)?))).collect()
+      } else { vec![] };
       let errs: Vec<_> = ast.errors.iter().map(|e| e.to_diag(source.ascii()))
-        .chain(errors.iter().map(|e| e.to_diag(source.ascii(), &mut to_loc)))
+        .chain(errors.iter().chain(unused.iter().map(|(_, e)| e))
+          .map(|e| e.to_diag(source.ascii(), &mut to_loc)))
         .filter(|e| !e.message.is_empty())
         .inspect(|err| match err.severity {
           None => {}
@@ -301,7 +489,7 @@ async fn elaborate(path: FileRef, start: Option<Position>,
       if n_warns != 0 { write!(&mut log_msg, ", {} warnings", n_warns).unwrap() }
       if n_infos != 0 { write!(&mut log_msg, ", {} infos", n_infos).unwrap() }
       if n_hints != 0 { write!(&mut log_msg, ", {} hints", n_hints).unwrap() }
-      if SERVER.options.ulock().log_errors.unwrap_or(true) {
+      if SERVER.options_for(&path).log_errors.unwrap_or(true) {
         for e in &errors {
           let Position {line, character: col} = source.ascii().to_pos(e.pos.start);
           write!(&mut log_msg, "\n\n{}: {}:{}:{}:\n{}",
@@ -309,6 +497,11 @@ async fn elaborate(path: FileRef, start: Option<Position>,
         }
       }
       log(log_msg);
+    } else if version.is_some() {
+      if let Some(listing) = file.mmb_listing.ulock().clone() {
+        let errs = listing.errors.iter().map(|e| e.to_diag(&listing.text, &mut to_loc)).collect();
+        send_diagnostics(path.url().clone(), version, errs)?;
+      }
     }
   }
 
@@ -328,7 +521,11 @@ async fn elaborate(path: FileRef, start: Option<Position>,
     }
   }
   if !is_canceled {
-    *g = Some(FileCache::Ready {hash, source, ast, res: res.clone(), deps});
+    let refs = Arc::new(match &res {
+      ElabResult::Ok(_, _, env) => RefIndex::new(env),
+      _ => RefIndex::default(),
+    });
+    *g = Some(FileCache::Ready {hash, source, ast, res: res.clone(), deps, trans, refs});
     drop(g);
     for d in file.downstream.ulock().iter() {
       log!("{:?} affects {:?}", path, d);
@@ -338,7 +535,23 @@ async fn elaborate(path: FileRef, start: Option<Position>,
   Ok(res)
 }
 
-async fn elaborate_and_report(path: FileRef, start: Option<Position>, cancel: Arc<AtomicBool>) {
+/// Get the files imported by the files `deps`, directly or indirectly (including `deps`
+/// themselves). The files in `deps` should have already been elaborated.
+async fn transitive_deps(deps: &[FileRef]) -> Vec<FileRef> {
+  let mut trans = vec![];
+  for p in deps {
+    if let Some(file) = SERVER.vfs.get(p) {
+      if let Some(FileCache::Ready {trans: ps, ..}) = &*file.parsed.lock().await {
+        for p in ps.iter().chain(Some(p)) {
+          if !trans.contains(p) { trans.push(p.clone()) }
+        }
+      }
+    }
+  }
+  trans
+}
+
+async fn elaborate_and_report(path: FileRef, start: Option<Position>, cancel: CancelToken) {
   if let Err(e) =
     std::panic::AssertUnwindSafe(elaborate(path, start, cancel, Default::default()))
       .catch_unwind().await
@@ -348,7 +561,7 @@ async fn elaborate_and_report(path: FileRef, start: Option<Position>, cancel: Ar
 }
 
 fn elaborate_and_send(path: FileRef,
-  cancel: Arc<AtomicBool>,
+  cancel: CancelToken,
   send: FSender<ElabResult<u64>>,
   rd: ArcList<FileRef>
 ) -> BoxFuture<'static, ()> {
@@ -359,7 +572,7 @@ fn elaborate_and_send(path: FileRef,
   }.boxed()
 }
 
-fn dep_change(path: FileRef, cancel: Arc<AtomicBool>) -> BoxFuture<'static, ()> {
+fn dep_change(path: FileRef, cancel: CancelToken) -> BoxFuture<'static, ()> {
   elaborate_and_report(path, None, cancel).boxed()
 }
 
@@ -367,8 +580,12 @@ fn dep_change(path: FileRef, cancel: Arc<AtomicBool>) -> BoxFuture<'static, ()>
 enum FileCache {
   InProgress {
     old: Option<(FileContents, FrozenEnv)>,
+    /// The source, parse, errors and environment of the last completed elaboration,
+    /// which is used for incremental elaboration if this one is canceled by an edit.
+    #[allow(clippy::type_complexity)]
+    last: Option<(FileContents, Arc<Ast>, Option<Arc<[ElabError]>>, FrozenEnv)>,
     version: Option<i32>,
-    cancel: Arc<AtomicBool>,
+    cancel: CancelToken,
     senders: Vec<FSender<ElabResult<u64>>>,
   },
   Ready {
@@ -377,6 +594,10 @@ enum FileCache {
     ast: Option<Arc<Ast>>,
     res: ElabResult<u64>,
     deps: Vec<FileRef>,
+    /// The files imported directly or indirectly, if the [`cache`] is enabled.
+    trans: Vec<FileRef>,
+    /// The uses of the declarations in the file.
+    refs: Arc<RefIndex>,
   }
 }
 
@@ -388,6 +609,11 @@ struct VirtualFile {
   parsed: FMutex<Option<FileCache>>,
   /// Files that depend on this one
   downstream: Mutex<HashSet<FileRef>>,
+  /// The results of the [code lens commands](LensCommand) on the statements of this file,
+  /// keyed by the start of the statement, and the version of the file they were run on.
+  lens_results: Mutex<(Option<i32>, HashMap<(usize, LensCommand), String>)>,
+  /// The textual listing of an MMB file, computed when it is elaborated.
+  mmb_listing: Mutex<Option<Arc<MmbListing>>>,
 }
 
 impl VirtualFile {
@@ -395,11 +621,132 @@ impl VirtualFile {
     VirtualFile {
       text: Mutex::new((version, text)),
       parsed: FMutex::new(None),
-      downstream: Mutex::new(HashSet::new())
+      downstream: Mutex::new(HashSet::new()),
+      lens_results: Default::default(),
+      mmb_listing: Default::default(),
     }
   }
 }
 
+/// A declaration in an [`MmbListing`].
+#[derive(DeepSizeOf)]
+struct MmbEntry {
+  /// The span of the declaration in the listing.
+  span: Span,
+  /// The statement of the declaration, without the proof.
+  stmt: String,
+  /// The part of the proof stream of the MMB file containing the declaration.
+  bin: Span,
+  /// The source file (as recorded by the compiler) and the span of the declaration in it,
+  /// if the file was compiled with `--source-map`.
+  source: Option<(String, Span)>,
+}
+
+/// Since MMB files are binary, the editor shows a textual listing of one instead, with the
+/// declarations of the file in order, the theorems with their proofs, and comments giving the
+/// locations of the declarations in the proof stream. Positions in requests on an MMB file
+/// refer to its listing.
+#[derive(DeepSizeOf)]
+struct MmbListing {
+  /// The text of the listing.
+  text: Arc<LinedString>,
+  /// The declarations in the listing, in order.
+  entries: Vec<MmbEntry>,
+  /// The errors found in the MMB file, located in the listing.
+  errors: Vec<ElabError>,
+}
+
+impl MmbListing {
+  /// Build the listing of the MMB file `buf`, which elaborated to `env` with errors `errors`.
+  fn new(buf: &[u8], env: &FrozenEnv, errors: &[ElabError]) -> MmbListing {
+    use std::fmt::Write;
+    let file = MmbFile::<(BasicIndex<'_>, Option<SourceMap<'_>>)>::parse(buf).ok();
+    let env = unsafe { env.thaw() };
+    let empty = LinedString::default();
+    let fe = FormatEnv { source: &empty, env };
+    let mut text = String::new();
+    let mut entries = vec![];
+    for s in &env.stmts {
+      let (stmt, proof, bin) = match *s {
+        StmtTrace::Sort(a) => match env.data[a].sort {
+          Some(s) => (env.sorts[s].to_string(), None, env.sorts[s].full),
+          None => continue,
+        },
+        StmtTrace::Decl(a) => match env.data[a].decl {
+          Some(DeclKey::Term(t)) => (fe.to(&env.terms[t]).to_string(), None, env.terms[t].full),
+          Some(DeclKey::Thm(t)) => (fe.to(&env.thms[t]).to_string(),
+            fe.pretty(|p| p.refine_proof(t).map(|doc| doc.pretty(100).to_string())), env.thms[t].full),
+          None => continue,
+        },
+        StmtTrace::Global(_) | StmtTrace::OutputString(_) => continue,
+      };
+      let source = file.as_ref().and_then(|f| f.source_span(bin.start))
+        .map(|s| (s.file.to_owned(), s.span.into()));
+      write!(text, "-- proof stream {:#x}..{:#x}", bin.start, bin.end).expect("impossible");
+      if let Some((f, _)) = &source { write!(text, ", from {}", f).expect("impossible") }
+      text.push('\n');
+      let start = text.len();
+      match proof {
+        Some(pf) => write!(text, "{} =\n'{};", stmt.strip_suffix(';').unwrap_or(&stmt), pf)
+          .expect("impossible"),
+        None => text.push_str(&stmt),
+      }
+      entries.push(MmbEntry {span: (start..text.len()).into(), stmt, bin, source});
+      text.push_str("\n\n");
+    }
+    // Errors are reported on the declaration containing them, or else on a comment at the end.
+    let errors = errors.iter().map(|e| {
+      let pos = entries.iter().find(|en| en.bin.start <= e.pos.start && e.pos.start < en.bin.end)
+        .map_or_else(|| {
+          let start = text.len();
+          write!(text, "-- error at {:#x}: {}", e.pos.start, e.kind.msg()).expect("impossible");
+          let pos = (start..text.len()).into();
+          text.push('\n');
+          pos
+        }, |en| en.span);
+      ElabError {pos, ..e.clone()}
+    }).collect();
+    MmbListing {text: Arc::new(text.into()), entries, errors}
+  }
+
+  /// The entry whose declaration contains the position `idx` in the listing.
+  fn entry_at(&self, idx: usize) -> Option<&MmbEntry> {
+    self.entries.iter().find(|e| e.span.start <= idx && idx <= e.span.end)
+  }
+}
+
+impl MmbEntry {
+  /// Find the source file of this declaration, which is looked up relative to the directories
+  /// containing the MMB file `mmb`, and return it with its text and the span of the declaration.
+  fn source_file(&self, mmb: &FileRef) -> Option<(FileRef, Arc<LinedString>, Span)> {
+    let (file, span) = self.source.as_ref()?;
+    let path = mmb.path().parent()?.ancestors()
+      .find_map(|dir| fs::canonicalize(dir.join(file)).ok())?;
+    let (path, vf) = SERVER.vfs.get_or_insert(FileRef::from(path)).ok()?;
+    let text = vf.text.ulock().1.try_ascii()?.clone();
+    Some((path, text, *span))
+  }
+}
+
+/// The parameters of the `mm0/mmbListing` request, which returns the [`MmbListing`] of an MMB file.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MmbListingParams {
+  text_document: TextDocumentIdentifier,
+}
+
+/// Get the listing of the MMB file `path`, elaborating it if necessary.
+async fn mmb_listing(path: FileRef) -> Result<Option<Arc<MmbListing>>, ResponseError> {
+  // The editor requests the listing before opening it, so the file may need to be loaded.
+  let (path, file) = SERVER.vfs.get_or_insert(path)
+    .map_err(|e| response_err(ErrorCode::InvalidRequest, format!("{:?}", e)))?;
+  if elaborate(path, Some(Position::default()), Default::default(), Default::default())
+    .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?
+    .into_response_error()?.is_none() { return Ok(None) }
+  let listing = file.mmb_listing.ulock().clone();
+  Ok(listing)
+}
+
 #[derive(DeepSizeOf)]
 struct Vfs(Mutex<HashMap<FileRef, Arc<VirtualFile>>>);
 
@@ -424,20 +771,23 @@ impl Vfs {
     }
   }
 
-  fn source(&self, file: &FileRef) -> Arc<LinedString> {
-    self.0.ulock().get(file).unwrap().text.ulock().1.ascii().clone()
+  /// The text of the file `file`, if it is open and is a text file.
+  fn source(&self, file: &FileRef) -> Option<Arc<LinedString>> {
+    self.0.ulock().get(file)?.text.ulock().1.try_ascii().cloned()
   }
 
-  fn open_virt(&self, path: FileRef, version: i32, text: String) -> Arc<VirtualFile> {
-    let file = Arc::new(VirtualFile::new(Some(version), FileContents::new(text)));
+  fn open_virt(&self, path: FileRef, version: i32, text: FileContents) -> Arc<VirtualFile> {
     let file = match self.0.ulock().entry(path.clone()) {
       Entry::Occupied(entry) => {
+        // The file was loaded from disk because another file imported it, or (for MMB files)
+        // because its listing was requested before it was opened.
+        *entry.get().text.ulock() = (Some(version), text);
         for dep in entry.get().downstream.ulock().iter() {
           Job::DepChange(path.clone(), dep.clone(), DepChangeReason::Open).spawn();
         }
-        file
+        entry.get().clone()
       }
-      Entry::Vacant(entry) => entry.insert(file).clone()
+      Entry::Vacant(entry) => entry.insert(Arc::new(VirtualFile::new(Some(version), text))).clone()
     };
     Job::Elaborate(path, ElabReason::Open).spawn();
     file
@@ -485,6 +835,24 @@ enum RequestType {
   DocumentSymbol(DocumentSymbolParams),
   References(ReferenceParams),
   DocumentHighlight(DocumentHighlightParams),
+  Holes(HolesParams),
+  MmbListing(MmbListingParams),
+  GoalState(TextDocumentPositionParams),
+  CodeAction(CodeActionParams),
+  Formatting(DocumentFormattingParams),
+  WorkspaceSymbol(WorkspaceSymbolParams),
+  PrepareRename(TextDocumentPositionParams),
+  Rename(RenameParams),
+  SemanticTokens(SemanticTokensParams),
+  SignatureHelp(SignatureHelpParams),
+  InlayHint(InlayHintParams),
+  CodeLens(CodeLensParams),
+  ExecuteCommand(ExecuteCommandParams),
+  PrepareCallHierarchy(CallHierarchyPrepareParams),
+  IncomingCalls(CallHierarchyIncomingCallsParams),
+  OutgoingCalls(CallHierarchyOutgoingCallsParams),
+  FoldingRange(FoldingRangeParams),
+  SelectionRange(SelectionRangeParams),
 }
 
 fn parse_request(Request {id, method, params}: Request) -> Result<Option<(RequestId, RequestType)>> {
@@ -496,6 +864,24 @@ fn parse_request(Request {id, method, params}: Request) -> Result<Option<(Reques
     "textDocument/documentSymbol"    => Some((id, RequestType::DocumentSymbol(from_value(params)?))),
     "textDocument/references"        => Some((id, RequestType::References(from_value(params)?))),
     "textDocument/documentHighlight" => Some((id, RequestType::DocumentHighlight(from_value(params)?))),
+    "mm0/holes"                      => Some((id, RequestType::Holes(from_value(params)?))),
+    "mm0/mmbListing"                 => Some((id, RequestType::MmbListing(from_value(params)?))),
+    "$/goalState"                    => Some((id, RequestType::GoalState(from_value(params)?))),
+    "textDocument/codeAction"        => Some((id, RequestType::CodeAction(from_value(params)?))),
+    "textDocument/formatting"        => Some((id, RequestType::Formatting(from_value(params)?))),
+    "workspace/symbol"               => Some((id, RequestType::WorkspaceSymbol(from_value(params)?))),
+    "textDocument/prepareRename"     => Some((id, RequestType::PrepareRename(from_value(params)?))),
+    "textDocument/rename"            => Some((id, RequestType::Rename(from_value(params)?))),
+    "textDocument/semanticTokens/full" => Some((id, RequestType::SemanticTokens(from_value(params)?))),
+    "textDocument/signatureHelp"     => Some((id, RequestType::SignatureHelp(from_value(params)?))),
+    "textDocument/inlayHint"         => Some((id, RequestType::InlayHint(from_value(params)?))),
+    "textDocument/codeLens"          => Some((id, RequestType::CodeLens(from_value(params)?))),
+    "workspace/executeCommand"       => Some((id, RequestType::ExecuteCommand(from_value(params)?))),
+    "textDocument/prepareCallHierarchy" => Some((id, RequestType::PrepareCallHierarchy(from_value(params)?))),
+    "callHierarchy/incomingCalls"    => Some((id, RequestType::IncomingCalls(from_value(params)?))),
+    "callHierarchy/outgoingCalls"    => Some((id, RequestType::OutgoingCalls(from_value(params)?))),
+    "textDocument/foldingRange"      => Some((id, RequestType::FoldingRange(from_value(params)?))),
+    "textDocument/selectionRange"    => Some((id, RequestType::SelectionRange(from_value(params)?))),
     _ => None
   })
 }
@@ -536,12 +922,12 @@ fn send_diagnostics(uri: Url, version: Option<i32>, diagnostics: Vec<Diagnostic>
   })
 }
 
-type OpenRequests = Mutex<HashMap<RequestId, Arc<AtomicBool>>>;
+type OpenRequests = Mutex<HashMap<RequestId, CancelToken>>;
 
 struct RequestHandler {
   id: RequestId,
   #[allow(unused)]
-  cancel: Arc<AtomicBool>,
+  cancel: CancelToken,
 }
 
 impl RequestHandler {
@@ -574,15 +960,52 @@ impl RequestHandler {
       RequestType::CompletionResolve(ci) =>
         self.finish(completion_resolve(*ci).await),
       RequestType::References(ReferenceParams {text_document_position: doc, context, ..}) => {
-        let file: FileRef = doc.text_document.uri.into();
-        self.finish(references(file.clone(), doc.position, context.include_declaration,
-          |range| Location { uri: file.url().clone(), range }).await)
+        let res = references(doc.text_document.uri.into(), doc.position, context.include_declaration).await;
+        self.finish(res.map(|refs| refs.into_iter()
+          .map(|(file, range)| Location { uri: file.url().clone(), range }).collect::<Vec<_>>()))
       }
       RequestType::DocumentHighlight(DocumentHighlightParams {text_document_position_params: doc, ..}) => {
         let file: FileRef = doc.text_document.uri.into();
-        self.finish(references(file.clone(), doc.position, true,
-          |range| DocumentHighlight { range, kind: None }).await)
+        let res = references(file.clone(), doc.position, true).await;
+        self.finish(res.map(|refs| refs.into_iter().filter(|(file2, _)| *file2 == file)
+          .map(|(_, range)| DocumentHighlight { range, kind: None }).collect::<Vec<_>>()))
       }
+      RequestType::Holes(HolesParams {text_document: doc}) =>
+        self.finish(holes(doc.uri.into()).await),
+      RequestType::MmbListing(MmbListingParams {text_document: doc}) =>
+        self.finish(mmb_listing(doc.uri.into()).await.map(|l| l.map(|l| l.text.as_str().to_owned()))),
+      RequestType::GoalState(TextDocumentPositionParams {text_document: doc, position}) =>
+        self.finish(goal_state(doc.uri.into(), position).await),
+      RequestType::CodeAction(CodeActionParams {text_document: doc, range, ..}) =>
+        self.finish(code_action(doc.uri.into(), range).await),
+      RequestType::Formatting(DocumentFormattingParams {text_document: doc, ..}) =>
+        self.finish(formatting(doc.uri.into())),
+      RequestType::WorkspaceSymbol(WorkspaceSymbolParams {query, ..}) =>
+        self.finish(workspace_symbol(query).await),
+      RequestType::PrepareRename(TextDocumentPositionParams {text_document: doc, position}) =>
+        self.finish(prepare_rename(doc.uri.into(), position).await),
+      RequestType::Rename(RenameParams {text_document_position: doc, new_name, ..}) =>
+        self.finish(rename(doc.text_document.uri.into(), doc.position, new_name).await),
+      RequestType::SemanticTokens(SemanticTokensParams {text_document: doc, ..}) =>
+        self.finish(semantic_tokens(doc.uri.into()).await),
+      RequestType::SignatureHelp(SignatureHelpParams {text_document_position_params: doc, ..}) =>
+        self.finish(signature_help(doc.text_document.uri.into(), doc.position).await),
+      RequestType::InlayHint(InlayHintParams {text_document: doc, range}) =>
+        self.finish(inlay_hints(doc.uri.into(), range).await),
+      RequestType::CodeLens(CodeLensParams {text_document: doc, ..}) =>
+        self.finish(code_lens(doc.uri.into()).await),
+      RequestType::ExecuteCommand(ExecuteCommandParams {command, arguments, ..}) =>
+        self.finish(execute_command(&command, arguments).await),
+      RequestType::PrepareCallHierarchy(CallHierarchyPrepareParams {text_document_position_params: doc, ..}) =>
+        self.finish(prepare_call_hierarchy(doc.text_document.uri.into(), doc.position).await),
+      RequestType::IncomingCalls(CallHierarchyIncomingCallsParams {item, ..}) =>
+        self.finish(incoming_calls(&item).await),
+      RequestType::OutgoingCalls(CallHierarchyOutgoingCallsParams {item, ..}) =>
+        self.finish(outgoing_calls(&item).await),
+      RequestType::FoldingRange(FoldingRangeParams {text_document: doc, ..}) =>
+        self.finish(folding_range(doc.uri.into()).await),
+      RequestType::SelectionRange(SelectionRangeParams {text_document: doc, positions, ..}) =>
+        self.finish(selection_range(doc.uri.into(), &positions).await),
     }
   }
 
@@ -660,6 +1083,67 @@ fn try_old(file: &Arc<VirtualFile>)  -> Option<(FileContents, FrozenEnv)> {
   }))
 }
 
+/// Unfold the definitions in the expression `e`, and then the definitions in their values,
+/// up to `depth` levels of definitions. The dummy variables of a definition keep their names.
+fn unfold_defs(env: &Environment, e: &LispVal, depth: usize) -> LispVal {
+  fn node(env: &Environment, heap: &[LispVal], e: &ExprNode, depth: usize) -> LispVal {
+    match *e {
+      ExprNode::Ref(i) => heap[i].clone(),
+      ExprNode::Dummy(a, _) => LispVal::atom(a),
+      ExprNode::App(t, ref es) =>
+        app(env, t, es.iter().map(|e| node(env, heap, e, depth)).collect(), depth),
+    }
+  }
+  fn app(env: &Environment, t: TermId, args: Vec<LispVal>, depth: usize) -> LispVal {
+    match &env.terms[t].kind {
+      TermKind::Def(Some(val)) if depth != 0 => {
+        let mut heap = args;
+        for e in &val.heap[heap.len()..] {
+          let e = node(env, &heap, e, depth - 1);
+          heap.push(e)
+        }
+        node(env, &heap, &val.head, depth - 1)
+      }
+      _ => LispVal::list(std::iter::once(LispVal::atom(env.terms[t].atom)).chain(args).collect::<Vec<_>>()),
+    }
+  }
+  let mut u = Uncons::from(e.clone());
+  match u.next().and_then(|a| a.as_atom()).and_then(|a| env.data[a].decl) {
+    Some(DeclKey::Term(t)) => app(env, t, u.map(|e| unfold_defs(env, &e, depth)).collect(), depth),
+    _ => e.clone(),
+  }
+}
+
+/// Hover over a declaration in the [listing](MmbListing) of an MMB file, to show its statement,
+/// its location in the proof stream, and where it was declared if the file has a source map.
+async fn mmb_hover(path: FileRef, pos: Position) -> Result<Option<Hover>, ResponseError> {
+  use std::fmt::Write;
+  let listing = if let Some(listing) = mmb_listing(path.clone()).await? {listing} else {return Ok(None)};
+  let entry = match listing.text.to_idx(pos).and_then(|idx| listing.entry_at(idx)) {
+    Some(entry) => entry,
+    None => return Ok(None),
+  };
+  let MmbEntry {span, ref stmt, bin, ref source} = *entry;
+  let mut info = format!("proof stream `{:#x}..{:#x}` ({} bytes)", bin.start, bin.end, bin.end - bin.start);
+  if let Some((file, text, sp)) = entry.source_file(&path) {
+    let Position {line, character} = text.to_pos(sp.start);
+    write!(info, ", declared at `{}:{}:{}`", file.rel(), line + 1, character + 1).expect("impossible")
+  } else if let Some((file, _)) = source {
+    write!(info, ", declared in `{}`", file).expect("impossible")
+  }
+  Ok(Some(Hover {
+    range: Some(listing.text.to_range(span)),
+    contents: HoverContents::Array(vec![
+      MarkedString::LanguageString(LanguageString { language: "metamath-zero".into(), value: stmt.clone() }),
+      MarkedString::String(info),
+    ])
+  }))
+}
+
+/// Hover over an object to show its declaration or type and its documentation. Statements and
+/// expressions are also shown without notation (unless the `hoverDesugared` option is false),
+/// and expressions and definitions with the definitions in them unfolded, to the depth given by
+/// the `hoverUnfoldDepth` option.
 async fn hover(path: FileRef, pos: Position) -> Result<Option<Hover>, ResponseError> {
   macro_rules! or {($ret:expr, $e:expr)  => {match $e {
     Some(x) => x,
@@ -673,10 +1157,15 @@ async fn hover(path: FileRef, pos: Position) -> Result<Option<Hover>, ResponseEr
     MarkedString::String(trim_margin(doc))
   }
 
+  if path.has_extension("mmb") { return mmb_hover(path, pos).await }
   let file = SERVER.vfs.get(&path).ok_or_else(||
     response_err(ErrorCode::InvalidRequest, "hover nonexistent file"))?;
   let text = file.text.ulock().1.ascii().clone();
   let idx = or!(Ok(None), text.to_idx(pos));
+  let options = SERVER.options_for(&path);
+  let syntax_docs = options.syntax_docs.unwrap_or(false);
+  let desugar = options.hover_desugared.unwrap_or(true);
+  let depth = options.hover_unfold_depth.unwrap_or(1);
   let env = elaborate(path, Some(Position::default()), Default::default(), Default::default())
     .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
   let env = or!(Ok(None), env.into_response_error()?).1;
@@ -686,6 +1175,8 @@ async fn hover(path: FileRef, pos: Position) -> Result<Option<Hover>, ResponseEr
 
   let mut out: Vec<(Span, MarkedString)> = vec![];
   for &(sp, ref k) in spans.find_pos(idx) {
+    // The other renderings of the object, shown after the main one.
+    let mut extra = vec![];
     if let Some((r, doc)) = (|| Some(match k {
       &ObjectKind::Sort(s) => {
         let sd = &env.sorts[s];
@@ -693,11 +1184,33 @@ async fn hover(path: FileRef, pos: Position) -> Result<Option<Hover>, ResponseEr
       }
       &ObjectKind::Term(t, sp1) => {
         let td = &env.terms[t];
-        ((sp1, mk_mm0(format!("{}", fe.to(td)))), td.doc.clone())
+        let main = format!("{}", fe.to(td));
+        if desugar {
+          let s = fe.pretty_desugared(|p| p.term(t, true).pretty(80).to_string());
+          if s != main { extra.push(("without notation", s)) }
+        }
+        if let (TermKind::Def(Some(val)), true) = (&td.kind, depth != 0) {
+          let (mut heap, mut bvars) = (vec![], vec![]);
+          env.binders(&td.args, &mut heap, &mut bvars);
+          for e in &val.heap[heap.len()..] {
+            let e = env.expr_node(&heap, &mut None, e);
+            heap.push(e)
+          }
+          let e = env.expr_node(&heap, &mut None, &val.head);
+          let (s1, s2) = fe.pretty(|p| (p.expr(&e).pretty(80).to_string(),
+            p.expr(&unfold_defs(env, &e, depth)).pretty(80).to_string()));
+          if s1 != s2 { extra.push(("unfolded", s2)) }
+        }
+        ((sp1, mk_mm0(main)), td.doc.clone())
       }
       &ObjectKind::Thm(t) => {
         let td = &env.thms[t];
-        ((sp, mk_mm0(format!("{}", fe.to(td)))), td.doc.clone())
+        let main = format!("{}", fe.to(td));
+        if desugar {
+          let s = fe.pretty_desugared(|p| p.thm(t).pretty(80).to_string());
+          if s != main { extra.push(("without notation", s)) }
+        }
+        ((sp, mk_mm0(main)), td.doc.clone())
       }
       &ObjectKind::Var(x) => ((sp, mk_mm0(match spans.lc.as_ref().and_then(|lc| lc.vars.get(&x)) {
         Some((_, InferSort::Bound(sort))) => format!("{{{}: {}}}", fe.to(&x), fe.to(sort)),
@@ -722,8 +1235,17 @@ async fn hover(path: FileRef, pos: Position) -> Result<Option<Hover>, ResponseEr
         } else {
           (spans.lc.as_ref()?.vars.get(&a)?.1.sort()?, None)
         };
+        let e = unsafe {e.thaw()};
         let mut out = String::new();
-        fe.pretty(|p| p.expr(unsafe {e.thaw()}).render_fmt(80, &mut out).expect("impossible"));
+        fe.pretty(|p| p.expr(e).render_fmt(80, &mut out).expect("impossible"));
+        if desugar {
+          let s = fe.pretty_desugared(|p| p.expr(e).pretty(80).to_string());
+          if s != out { extra.push(("without notation", s)) }
+        }
+        if depth != 0 {
+          let s = fe.pretty(|p| p.expr(&unfold_defs(env, e, depth)).pretty(80).to_string());
+          if s != out { extra.push(("unfolded", s)) }
+        }
         { use std::fmt::Write; write!(out, ": {}", fe.to(&s)).expect("impossible"); }
         ((sp1, mk_mm0(out)), doc)
       }
@@ -750,20 +1272,25 @@ async fn hover(path: FileRef, pos: Position) -> Result<Option<Hover>, ResponseEr
             let mut subst = Subst::new(env, &td.heap, args);
             let mut out = String::new();
             let ret = subst.subst(&td.ret);
-            fe.pretty(|p| p.hyps_and_ret(Pretty::nil(),
-              td.hyps.iter().map(|(_, h)| subst.subst(h)),
-              &ret).render_fmt(80, &mut out).expect("impossible"));
+            let hyps = td.hyps.iter().map(|(_, h)| subst.subst(h)).collect::<Vec<_>>();
+            fe.pretty(|p| p.hyps_and_ret(Pretty::nil(), hyps.iter().cloned(), &ret)
+              .render_fmt(80, &mut out).expect("impossible"));
+            if desugar {
+              let s = fe.pretty_desugared(|p|
+                p.hyps_and_ret(Pretty::nil(), hyps.iter().cloned(), &ret).pretty(80).to_string());
+              if s != out { extra.push(("without notation", s)) }
+            }
             ((sp1, mk_mm0(out)), td.doc.clone())
           } else {return None}
         }
       }
       ObjectKind::Syntax(stx) => {
-        if SERVER.options.ulock().syntax_docs.unwrap_or(false) {
+        if syntax_docs {
           ((sp, mk_doc(stx.doc())), None)
         } else { return None }
       }
       ObjectKind::RefineSyntax(stx) => {
-        if SERVER.options.ulock().syntax_docs.unwrap_or(false) {
+        if syntax_docs {
           ((sp, mk_doc(stx.doc())), None)
         } else { return None }
       }
@@ -772,17 +1299,21 @@ async fn hover(path: FileRef, pos: Position) -> Result<Option<Hover>, ResponseEr
         if let Some(doc) = &ld.doc {
           ((sp, mk_doc(doc)), None)
         } else {
-          let bp = ld.unwrapped(|e| match *e {
-            LispKind::Proc(Proc::Builtin(p)) => Some(p),
+          let doc = ld.unwrapped(|e| match e {
+            LispKind::Proc(p) => p.doc(),
             _ => None
           })?;
-          ((sp, mk_doc(bp.doc())), None)
+          ((sp, mk_doc(&doc)), None)
         }
       }
+      ObjectKind::LispVar(_) |
       ObjectKind::Import(_) => return None,
     }))() {
       let sp = r.0;
       out.push(r);
+      for (label, s) in extra {
+        out.push((sp, mk_mm0(format!("-- {}\n{}", label, s))))
+      }
       if let Some(doc) = doc {
         out.push((sp, mk_doc(&doc)))
       }
@@ -803,6 +1334,13 @@ async fn definition<T>(path: FileRef, pos: Position,
     Some(x) => x,
     None => return Ok(vec![])
   }}}
+  // In the listing of an MMB file, go to the source of the declaration.
+  if path.has_extension("mmb") {
+    let listing = or_none!(mmb_listing(path.clone()).await?);
+    let entry = or_none!(listing.text.to_idx(pos).and_then(|idx| listing.entry_at(idx)));
+    let (file, text2, span) = or_none!(entry.source_file(&path));
+    return Ok(vec![f(&listing.text, &text2, entry.span, &FileSpan {file, span}, span)])
+  }
   let file = vfs.get(&path).ok_or_else(||
     response_err(ErrorCode::InvalidRequest, "goto definition nonexistent file"))?;
   let text = match &file.text.ulock().1 {
@@ -819,8 +1357,13 @@ async fn definition<T>(path: FileRef, pos: Position,
     let g = |fsp: &FileSpan, full|
       if fsp.file.ptr_eq(&path) {
         f(&text, &text, sp, fsp, full)
+      } else if let Some(text2) = vfs.source(&fsp.file) {
+        f(&text, &text2, sp, fsp, full)
       } else {
-        f(&text, &vfs.source(&fsp.file), sp, fsp, full)
+        // The declaration is in a binary file, like an imported MMB file,
+        // so the best we can do is to go to the start of the file.
+        let fsp = FileSpan {file: fsp.file.clone(), span: 0.into()};
+        f(&text, &LinedString::default(), sp, &fsp, 0.into())
       };
     let sort = |s| {
       let sd = env.sort(s);
@@ -839,6 +1382,7 @@ async fn definition<T>(path: FileRef, pos: Position,
       &ObjectKind::Term(t, _) => res.push(term(t)),
       &ObjectKind::Thm(t) => res.push(thm(t)),
       ObjectKind::Var(_) |
+      ObjectKind::LispVar(_) |
       ObjectKind::Syntax(_) |
       ObjectKind::RefineSyntax(_) => {}
       ObjectKind::Expr(e) => {
@@ -878,8 +1422,9 @@ async fn definition<T>(path: FileRef, pos: Position,
 async fn document_symbol(path: FileRef) -> Result<DocumentSymbolResponse, ResponseError> {
   let file = SERVER.vfs.get(&path).ok_or_else(||
     response_err(ErrorCode::InvalidRequest, "document symbol nonexistent file"))?;
+  if path.has_extension("mmb") { return Ok(DocumentSymbolResponse::Nested(vec![])) }
 
-  let maybe_old = if SERVER.elab_on().unwrap_or_default() == ElabOn::Save { try_old(&file) } else { None };
+  let maybe_old = if SERVER.elab_on(&path).unwrap_or_default() == ElabOn::Save { try_old(&file) } else { None };
   let (text, env) = if let Some((contents, frozen)) = maybe_old {
     (contents.ascii().clone(), frozen)
   } else {
@@ -890,8 +1435,8 @@ async fn document_symbol(path: FileRef) -> Result<DocumentSymbolResponse, Respon
       Some((_, env)) => (file.text.ulock().1.ascii().clone(), env)
     }
   };
-  let fe = unsafe { env.format_env(&text) };
-  let f = |sp, name: &ArcString, desc, full, kind| DocumentSymbol {
+  let mut res = vec![];
+  file_symbols(&path, &text, &env, |_| true, |name, desc, sp, full, kind, deprecated| res.push(DocumentSymbol {
     name: String::from_utf8_lossy(name).into(),
     detail: Some(desc),
     kind,
@@ -899,52 +1444,65 @@ async fn document_symbol(path: FileRef) -> Result<DocumentSymbolResponse, Respon
     range: text.to_range(full),
     selection_range: text.to_range(sp),
     children: None,
-    tags: None,
-  };
-  let mut res = vec![];
-  macro_rules! push {($fsp:expr, $($e:expr),*) => {
-    if $fsp.file == path { res.push(f($fsp.span, $($e),*)) }
+    tags: if deprecated {Some(vec![SymbolTag::Deprecated])} else {None},
+  }));
+  Ok(DocumentSymbolResponse::Nested(res))
+}
+
+/// List the sorts, terms, theorems and lisp globals declared in the file `path` with
+/// contents `text` and environment `env`, whose names satisfy `filter`. The function `f` is
+/// called with the name, the description, the spans of the name and of the whole declaration,
+/// the symbol kind, and whether the declaration is deprecated.
+fn file_symbols(path: &FileRef, text: &LinedString, env: &FrozenEnv, filter: impl Fn(&[u8]) -> bool,
+    mut f: impl FnMut(&ArcString, String, Span, Span, SymbolKind, bool)) {
+  let fe = unsafe { env.format_env(text) };
+  macro_rules! push {($a:expr, $fsp:expr, $desc:expr, $full:expr, $kind:expr) => {
+    if $fsp.file == *path {
+      f(env.data()[$a].name(), $desc, $fsp.span, $full, $kind, env.deprecated().contains_key(&$a))
+    }
   }}
   for s in env.stmts() {
     match *s {
-      StmtTrace::Sort(a) => {
-        let ad = &env.data()[a];
-        let s = ad.sort().expect("env well formed");
+      StmtTrace::Sort(a) if filter(env.data()[a].name()) => {
+        let s = env.data()[a].sort().expect("env well formed");
         let sd = env.sort(s);
-        push!(sd.span, ad.name(), format!("{}", sd), sd.full, SymbolKind::Class)
+        push!(a, sd.span, format!("{}", sd), sd.full, SymbolKind::Class)
       }
-      StmtTrace::Decl(a) => {
-        let ad = &env.data()[a];
-        match ad.decl().expect("env well formed") {
+      StmtTrace::Decl(a) if filter(env.data()[a].name()) => {
+        match env.data()[a].decl().expect("env well formed") {
           DeclKey::Term(t) => {
             let td = env.term(t);
-            push!(td.span, ad.name(), format!("{}", fe.to(td)), td.full, SymbolKind::Constructor)
+            push!(a, td.span, format!("{}", fe.to(td)), td.full, SymbolKind::Constructor)
           }
           DeclKey::Thm(t) => {
             let td = env.thm(t);
-            push!(td.span, ad.name(), format!("{}", fe.to(td)), td.full, SymbolKind::Method)
+            push!(a, td.span, format!("{}", fe.to(td)), td.full, SymbolKind::Method)
           }
         }
       }
-      StmtTrace::Global(a) => {
+      StmtTrace::Global(a) if filter(env.data()[a].name()) => {
         let ad = &env.data()[a];
         if let Some(ld) = ad.lisp() {
           if let Some((ref fsp, full)) = *ld.src() {
             let e = &**ld;
-            push!(fsp, ad.name(), format!("{}", fe.to(unsafe { e.thaw() })), full,
+            push!(a, fsp, format!("{}", fe.to(unsafe { e.thaw() })), full,
               match (|| Some(match e.unwrap() {
                 FrozenLispKind::Atom(_) |
                 FrozenLispKind::MVar(_, _) |
                 FrozenLispKind::Goal(_) => SymbolKind::Constant,
                 r @ (FrozenLispKind::List(_) | FrozenLispKind::DottedList(_, _)) =>
                   if r.is_list() {SymbolKind::Array} else {SymbolKind::Object},
-                FrozenLispKind::Number(_) => SymbolKind::Number,
+                FrozenLispKind::Number(_) |
+                FrozenLispKind::Rational(_) => SymbolKind::Number,
                 FrozenLispKind::String(_) => SymbolKind::String,
                 FrozenLispKind::Bool(_) => SymbolKind::Boolean,
                 FrozenLispKind::Syntax(_) => SymbolKind::Event,
                 FrozenLispKind::Undef => return None,
                 FrozenLispKind::Proc(_) => SymbolKind::Function,
                 FrozenLispKind::AtomMap(_) |
+                FrozenLispKind::HashTable(_) |
+                FrozenLispKind::Vector(_) |
+                FrozenLispKind::StringPort(_) |
                 FrozenLispKind::Annot(_, _) |
                 FrozenLispKind::Ref(_) => SymbolKind::Object,
               }))() {
@@ -954,218 +1512,2279 @@ async fn document_symbol(path: FileRef) -> Result<DocumentSymbolResponse, Respon
           }
         }
       }
-      StmtTrace::OutputString(_) => {}
+      StmtTrace::Sort(_) | StmtTrace::Decl(_) | StmtTrace::Global(_) | StmtTrace::OutputString(_) => {}
     }
   }
-  Ok(DocumentSymbolResponse::Nested(res))
 }
 
-#[derive(Serialize_repr, Deserialize_repr)]
-#[repr(u8)]
-enum TraceKind {Sort, Decl, Global}
-
-fn make_completion_item(path: &FileRef, fe: FormatEnv<'_>, ad: &FrozenAtomData, detail: bool, tk: TraceKind) -> Option<CompletionItem> {
-  use CompletionItemKind::{Class, Constructor, Method};
-  macro_rules! done {($desc:expr, $kind:expr, $doc:expr) => {
-    CompletionItem {
-      label: String::from_utf8_lossy(ad.name()).into(),
-      detail: if detail {Some($desc)} else {None},
-      kind: Some($kind),
-      documentation: $doc.as_ref().map(|doc| {
-        Documentation::MarkupContent(MarkupContent {
-          kind: MarkupKind::Markdown,
-          value: doc.to_string(),
-        })
-      }),
-      data: Some(to_value((path.url(), tk)).unwrap()),
-      ..Default::default()
-    }
-  }}
-  match tk {
-    TraceKind::Sort => ad.sort().map(|s| {
-      let sd = &fe.sorts[s];
-      done!(format!("{}", sd), Class, sd.doc)
-    }),
-    TraceKind::Decl => ad.decl().map(|dk| match dk {
-      DeclKey::Term(t) => {let td = &fe.terms[t]; done!(format!("{}", fe.to(td)), Constructor, td.doc)}
-      DeclKey::Thm(t) => {let td = &fe.thms[t]; done!(format!("{}", fe.to(td)), Method, td.doc)}
-    }),
-    TraceKind::Global => {
-      let e = ad.lisp().as_ref()?;
-      Some(done!(format!("{}", fe.to(unsafe {e.thaw()})), match *e.unwrap() {
-        FrozenLispKind::Atom(_) |
-        FrozenLispKind::MVar(_, _) |
-        FrozenLispKind::Goal(_) => CompletionItemKind::Constant,
-        FrozenLispKind::List(_) |
-        FrozenLispKind::DottedList(_, _) |
-        FrozenLispKind::Undef |
-        FrozenLispKind::Number(_) |
-        FrozenLispKind::String(_) |
-        FrozenLispKind::Bool(_) |
-        FrozenLispKind::AtomMap(_) |
-        FrozenLispKind::Annot(_, _) |
-        FrozenLispKind::Ref(_) => CompletionItemKind::Value,
-        FrozenLispKind::Syntax(_) => CompletionItemKind::Event,
-        FrozenLispKind::Proc(ref p) if
-          match *unsafe {p.thaw()} {
-            Proc::Builtin(p) => p.to_str() == ad.name().as_str(),
-            _ => false,
-          } => return None,
-        FrozenLispKind::Proc(_) => CompletionItemKind::Function
-      }, e.doc()))
-    }
-  }
+/// Returns true if the characters of `query` appear in order in `name`, ignoring case,
+/// which is how editors match symbol names.
+fn fuzzy_match(query: &[u8], name: &[u8]) -> bool {
+  let mut it = name.iter();
+  query.iter().all(|c| it.any(|d| d.eq_ignore_ascii_case(c)))
 }
 
-async fn completion(path: FileRef, _pos: Position) -> Result<CompletionResponse, ResponseError> {
-  let file = SERVER.vfs.get(&path).ok_or_else(||
-    response_err(ErrorCode::InvalidRequest, "document symbol nonexistent file"))?;
-  let (text, env) = if let Some(old) = try_old(&file) { old } else {
-    let env = elaborate(path.clone(), Some(Position::default()), Default::default(), Default::default())
+/// Search for the sorts, terms, theorems and lisp globals matching `query` in the files which
+/// have been elaborated. The root files of the projects of the workspace folders, from their
+/// `mm0.toml` manifests, are elaborated first, so that this covers the whole workspace.
+#[allow(deprecated)] // workaround rust#60681
+async fn workspace_symbol(query: String) -> Result<Vec<SymbolInformation>, ResponseError> {
+  let mut roots = vec![];
+  for root in SERVER.folders.ulock().iter().flat_map(Folder::roots) {
+    if !roots.contains(&root) { roots.push(root) }
+  }
+  for root in roots {
+    elaborate(root, None, Default::default(), Default::default())
       .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
-    match env.into_response_error()? {
-      None => return Ok(CompletionResponse::Array(vec![])),
-      Some((_, env)) => (file.text.ulock().1.clone(), env)
-    }
-  };
-  let text = text.ascii().clone();
-  let fe = unsafe { env.format_env(&text) };
+  }
+  let files: Vec<_> = SERVER.vfs.0.ulock().iter().map(|(p, f)| (p.clone(), f.clone())).collect();
   let mut res = vec![];
-  BuiltinProc::for_each(|_, s| {
-    res.push(CompletionItem {
-      label: s.into(),
-      documentation: None,
-      kind: Some(CompletionItemKind::Keyword),
-      ..Default::default()
-    })
-  });
-  for ad in env.data().iter() {
-    if let Some(ci) = make_completion_item(&path, fe, ad, false, TraceKind::Sort) {res.push(ci)}
-    if let Some(ci) = make_completion_item(&path, fe, ad, false, TraceKind::Decl) {res.push(ci)}
-    if let Some(ci) = make_completion_item(&path, fe, ad, false, TraceKind::Global) {res.push(ci)}
+  for (path, file) in files {
+    let (text, env) = match try_old(&file) {
+      Some((FileContents::Ascii(text), env)) => (text, env),
+      _ => continue,
+    };
+    file_symbols(&path, &text, &env, |name| fuzzy_match(query.as_bytes(), name),
+      |name, _, sp, _, kind, deprecated| res.push(SymbolInformation {
+        name: String::from_utf8_lossy(name).into(),
+        kind,
+        tags: if deprecated {Some(vec![SymbolTag::Deprecated])} else {None},
+        #[allow(deprecated)] deprecated: None,
+        location: Location {uri: path.url().clone(), range: text.to_range(sp)},
+        container_name: Some(path.rel().into()),
+      }))
   }
-  Ok(CompletionResponse::Array(res))
+  Ok(res)
 }
 
-async fn completion_resolve(ci: CompletionItem) -> Result<CompletionItem, ResponseError> {
-  let data = if let Some(data) = ci.data {data} else {
-    let p = BuiltinProc::from_str(&ci.label)
-      .ok_or_else(|| response_err(ErrorCode::InvalidRequest, "missing data"))?;
-    return Ok(CompletionItem {
-      label: ci.label,
-      documentation: Some(Documentation::MarkupContent(MarkupContent {
-        kind: MarkupKind::Markdown,
-        value: p.doc().into(),
-      })),
-      kind: Some(CompletionItemKind::Keyword),
-      ..Default::default()
-    })
-  };
-  let (uri, tk): (Url, TraceKind) = from_value(data).map_err(|e|
-    response_err(ErrorCode::InvalidRequest, format!("bad JSON {:?}", e)))?;
-  let path = uri.into();
-  let file = SERVER.vfs.get(&path).ok_or_else(||
-    response_err(ErrorCode::InvalidRequest, "document symbol nonexistent file"))?;
+/// The role of an identifier, which determines its semantic token type. The legend
+/// sent to the client lists the token types in the order of this enum.
+#[derive(Copy, Clone)]
+enum TokenType {
+  /// A sort, shown as a `type`.
+  Sort,
+  /// A term constructor, shown as a `function`.
+  Term,
+  /// A definition, shown as a `macro`.
+  Def,
+  /// A theorem or axiom, shown as a `method`.
+  Thm,
+  /// A hypothesis, shown as a `property`.
+  Hyp,
+  /// A regular variable, shown as a `parameter`.
+  Var,
+  /// A bound variable, or a local lisp variable, shown as a `variable`.
+  BoundVar,
+  /// A lisp syntax form or refine keyword, shown as a `keyword`.
+  Keyword,
+  /// A global lisp definition, shown as a `function`, with the `defaultLibrary`
+  /// modifier if it is a builtin.
+  Global,
+}
 
-  let maybe_old = if SERVER.elab_on().unwrap_or_default() == ElabOn::Save { try_old(&file) } else { None };
-  let (text, env) = if let Some((contents, frozen)) = maybe_old {
-    (contents.ascii().clone(), frozen)
-  } else {
-    let text = file.text.ulock().1.ascii().clone();
-    let env = elaborate(path.clone(), Some(Position::default()), Default::default(), Default::default())
-      .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?
-      .into_response_error()?
-      .ok_or_else(|| response_err(ErrorCode::InternalError, "import cycle"))?.1;
-    (text, env)
-  };
+/// The semantic token modifiers, as bits of the modifier set.
+const TOKEN_DECLARATION: u32 = 1;
+const TOKEN_DEPRECATED: u32 = 2;
+const TOKEN_BUILTIN: u32 = 4;
 
-  let fe = unsafe { env.format_env(&text) };
-  env.get_atom(ci.label.as_bytes()).and_then(|a| make_completion_item(&path, fe, &env.data()[a], true, tk))
-    .ok_or_else(|| response_err(ErrorCode::ContentModified, "completion missing"))
+fn semantic_tokens_legend() -> SemanticTokensLegend {
+  SemanticTokensLegend {
+    token_types: vec![
+      SemanticTokenType::TYPE,
+      SemanticTokenType::FUNCTION,
+      SemanticTokenType::MACRO,
+      SemanticTokenType::METHOD,
+      SemanticTokenType::PROPERTY,
+      SemanticTokenType::PARAMETER,
+      SemanticTokenType::VARIABLE,
+      SemanticTokenType::KEYWORD,
+    ],
+    token_modifiers: vec![
+      SemanticTokenModifier::DECLARATION,
+      SemanticTokenModifier::DEPRECATED,
+      SemanticTokenModifier::DEFAULT_LIBRARY,
+    ],
+  }
 }
 
-async fn references<T>(
-  path: FileRef, pos: Position, include_self: bool, f: impl Fn(Range) -> T + Send
-) -> Result<Vec<T>, ResponseError> {
-  macro_rules! or_none {($e:expr)  => {match $e {
-    Some(x) => x,
-    None => return Ok(vec![])
-  }}}
-  #[derive(Copy, Clone, PartialEq, Eq)]
-  enum Key {
-    Var(AtomId),
-    Sort(SortId),
-    Term(TermId),
-    Thm(ThmId),
-    Global(AtomId),
+impl TokenType {
+  /// The index of the token type in the legend.
+  fn index(self) -> u32 {
+    match self {
+      TokenType::Sort => 0,
+      TokenType::Term | TokenType::Global => 1,
+      TokenType::Def => 2,
+      TokenType::Thm => 3,
+      TokenType::Hyp => 4,
+      TokenType::Var => 5,
+      TokenType::BoundVar => 6,
+      TokenType::Keyword => 7,
+    }
   }
 
-  let file = SERVER.vfs.get(&path).ok_or_else(||
-    response_err(ErrorCode::InvalidRequest, "references: nonexistent file"))?;
-  let text = file.text.ulock().1.ascii().clone();
-  let idx = or_none!(text.to_idx(pos));
-  let env = elaborate(path, Some(Position::default()), Default::default(), Default::default())
-    .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
-  let env = or_none!(env.into_response_error()?).1;
-  let spans = or_none!(env.find(idx));
-
-  let to_key = |k: &ObjectKind| match *k {
-    ObjectKind::Expr(ref e) => {
+  /// Classify the object `k` at `sp` in the file `path`, returning the token type and the
+  /// modifiers of the token, or `None` if it should not be highlighted.
+  fn classify(env: &FrozenEnv, spans: &Spans<ObjectKind>, path: &FileRef,
+      sp: Span, k: &ObjectKind) -> Option<(TokenType, u32)> {
+    let decl = |fsp: &FileSpan, a: AtomId| {
+      let mut mods = 0;
+      if fsp.span == sp && fsp.file == *path { mods |= TOKEN_DECLARATION }
+      if env.deprecated().contains_key(&a) { mods |= TOKEN_DEPRECATED }
+      mods
+    };
+    let term = |t| {
+      let td = env.term(t);
+      let ty = if let TermKind::Def(_) = td.kind {TokenType::Def} else {TokenType::Term};
+      (ty, decl(&td.span, td.atom))
+    };
+    let thm = |t| {
+      let td = env.thm(t);
+      (TokenType::Thm, decl(&td.span, td.atom))
+    };
+    let var = |x| match spans.lc.as_ref()?.vars.get(&x)? {
+      (_, InferSort::Bound(_)) => Some((TokenType::BoundVar, 0)),
+      _ => Some((TokenType::Var, 0)),
+    };
+    // Expressions and proofs are only highlighted if `sp` is the name at their head,
+    // as in the applications of a `refine` proof.
+    let head = |e: &FrozenLispVal| {
       let a = e.uncons().next().unwrap_or(e).as_atom()?;
-      if let Some(DeclKey::Term(t)) = env.data()[a].decl() {
-        Some(Key::Term(t))
-      } else {
-        Some(Key::Var(a))
+      if env.data()[a].name().len() == sp.len() { head_atom(env, e) } else { None }
+    };
+    Some(match *k {
+      ObjectKind::Sort(s) => {
+        let sd = env.sort(s);
+        (TokenType::Sort, decl(&sd.span, sd.atom))
       }
-    }
-    ObjectKind::Proof(ref p) => {
-      let a = p.uncons().next().unwrap_or(p).as_atom()?;
-      if let Some(DeclKey::Thm(t)) = env.data()[a].decl() {
-        Some(Key::Thm(t))
-      } else {
-        Some(Key::Var(a))
+      ObjectKind::Term(t, _) => term(t),
+      ObjectKind::Thm(t) => thm(t),
+      ObjectKind::Var(x) => var(x).unwrap_or((TokenType::Hyp, 0)),
+      ObjectKind::Expr(ref e) => {
+        let a = head(e)?;
+        match env.data()[a].decl() {
+          Some(DeclKey::Term(t)) => term(t),
+          Some(DeclKey::Thm(_)) => return None,
+          None => var(a)?,
+        }
       }
-    }
-    ObjectKind::Import(_) |
-    ObjectKind::Syntax(_) |
-    ObjectKind::RefineSyntax(_) => None,
-    ObjectKind::Var(a) => Some(Key::Var(a)),
-    ObjectKind::Sort(a) => Some(Key::Sort(a)),
-    ObjectKind::Term(a, _) => Some(Key::Term(a)),
-    ObjectKind::Thm(a) => Some(Key::Thm(a)),
-    ObjectKind::Global(a) => Some(Key::Global(a)),
-  };
-
-  let mut res = vec![];
-  for &(sp, ref k) in spans.find_pos(idx) {
-    let key = match to_key(k) {Some(k) => k, None => continue};
-    match key {
-      Key::Global(a) if BuiltinProc::from_bytes(env.data()[a].name()).is_some() => continue,
-      _ => {}
-    }
-    let mut cont = |&(sp2, ref k2)| {
-      let eq = match *k2 {
-        ObjectKind::Expr(_) if !matches!(key, Key::Term(_) | Key::Var(_)) => false,
-        ObjectKind::Proof(_) if !matches!(key, Key::Thm(_) | Key::Var(_)) => false,
-        _ => Some(key) == to_key(k2),
-      };
-      if eq && (include_self || sp != sp2) {
-        let sp2 = if let ObjectKind::Term(_, sp2) = *k2 {sp2} else {sp2};
-        res.push(f(text.to_range(sp2)))
+      ObjectKind::Proof(ref p) => {
+        let a = head(p)?;
+        match env.data()[a].decl() {
+          _ if spans.lc.as_ref()?.proofs.contains_key(&a) => (TokenType::Hyp, 0),
+          Some(DeclKey::Thm(t)) => thm(t),
+          _ => return None,
+        }
       }
-    };
-    if let Key::Var(_) = key {
-      spans.into_iter().for_each(&mut cont);
-    } else {
-      for spans2 in env.spans() {
-        spans2.into_iter().for_each(&mut cont);
+      ObjectKind::Global(a) => {
+        let ad = &env.data()[a];
+        let mut mods = 0;
+        if let Some((fsp, _)) = ad.lisp().as_ref().and_then(|ld| ld.src().as_ref()) {
+          mods = decl(fsp, a)
+        } else if BuiltinProc::from_bytes(ad.name()).is_some() {
+          mods = TOKEN_BUILTIN
+        }
+        (TokenType::Global, mods)
       }
-    }
+      ObjectKind::LispVar(_) => (TokenType::BoundVar, 0),
+      ObjectKind::Syntax(_) | ObjectKind::RefineSyntax(_) => (TokenType::Keyword, 0),
+      ObjectKind::Import(_) => return None,
+    })
   }
-  Ok(res)
 }
 
-struct Server {
+/// Classify the identifiers of the file by their role, for semantic highlighting.
+async fn semantic_tokens(path: FileRef) -> Result<Option<SemanticTokensResult>, ResponseError> {
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "semantic tokens nonexistent file"))?;
+  if path.has_extension("mmb") { return Ok(None) }
+  let maybe_old = if SERVER.elab_on(&path).unwrap_or_default() == ElabOn::Save { try_old(&file) } else { None };
+  let (text, env) = if let Some((contents, frozen)) = maybe_old {
+    (contents.ascii().clone(), frozen)
+  } else {
+    let env = elaborate(path.clone(), Some(Position::default()), Default::default(), Default::default())
+      .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
+    match env.into_response_error()? {
+      None => return Ok(None),
+      Some((_, env)) => (file.text.ulock().1.ascii().clone(), env)
+    }
+  };
+  let mut tokens = vec![];
+  for spans in env.spans() {
+    for &(sp, ref k) in spans {
+      if let Some((ty, mods)) = TokenType::classify(&env, spans, &path, sp, k) {
+        tokens.push((sp, ty, mods))
+      }
+    }
+  }
+  tokens.sort_by_key(|&(sp, _, _)| (sp.start, sp.end));
+  let (mut data, mut last, mut end) = (vec![], Position::default(), 0);
+  for (sp, ty, mods) in tokens {
+    // Tokens may not overlap or span multiple lines.
+    if sp.start < end { continue }
+    let Range {start, end: stop} = text.to_range(sp);
+    if start.line != stop.line || start == stop { continue }
+    data.push(SemanticToken {
+      delta_line: start.line - last.line,
+      delta_start: if start.line == last.line { start.character - last.character } else { start.character },
+      length: stop.character - start.character,
+      token_type: ty.index(),
+      token_modifiers_bitset: mods,
+    });
+    last = start;
+    end = sp.end;
+  }
+  Ok(Some(SemanticTokensResult::Tokens(SemanticTokens {result_id: None, data})))
+}
+
+/// The parse of the current text of `file`, which is the one from the last elaboration
+/// if the file has not been changed since.
+async fn current_ast(file: &VirtualFile) -> Option<Arc<Ast>> {
+  let text = file.text.ulock().1.clone();
+  if let Some(FileCache::Ready {source, ast: Some(ast), ..}) = &*file.parsed.lock().await {
+    if text.ptr_eq(source) { return Some(ast.clone()) }
+  }
+  Some(Arc::new(parse(text.try_ascii()?.clone(), None).1))
+}
+
+/// Fold the proofs and `do` blocks, the lists in them which span several lines, and the
+/// binder lists which span several lines.
+async fn folding_range(path: FileRef) -> Result<Option<Vec<FoldingRange>>, ResponseError> {
+  /// Push a fold for each list in `e` which spans several lines.
+  fn fold_lists(ast: &Ast, e: &SExpr, out: &mut Vec<(u32, u32)>) {
+    match &e.k {
+      SExprKind::List(es) => {
+        push_fold(ast, e.span, out);
+        for e in es { fold_lists(ast, e, out) }
+      }
+      SExprKind::DottedList(es, r) => {
+        push_fold(ast, e.span, out);
+        for e in es.iter().chain(Some(&**r)) { fold_lists(ast, e, out) }
+      }
+      SExprKind::DocComment(_, e) => fold_lists(ast, e, out),
+      _ => {}
+    }
+  }
+  fn push_fold(ast: &Ast, sp: Span, out: &mut Vec<(u32, u32)>) {
+    let Range {start, end} = ast.source.to_range(sp);
+    if start.line < end.line { out.push((start.line, end.line)) }
+  }
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "folding range nonexistent file"))?;
+  let ast = if let Some(ast) = current_ast(&file).await {ast} else { return Ok(None) };
+  let mut folds = vec![];
+  for s in &ast.stmts {
+    let mut s = s;
+    while let StmtKind::Annot(_, s2) | StmtKind::Attrs(_, s2) | StmtKind::DocComment(_, s2) = &s.k { s = s2 }
+    match &s.k {
+      StmtKind::Decl(d) => {
+        if let (Some(first), Some(last)) = (d.bis.first(), d.bis.last()) {
+          push_fold(&ast, (first.span.start..last.span.end).into(), &mut folds)
+        }
+        if let Some(val) = &d.val {
+          push_fold(&ast, val.span, &mut folds);
+          fold_lists(&ast, val, &mut folds)
+        }
+      }
+      StmtKind::Do(es) => {
+        push_fold(&ast, s.span, &mut folds);
+        for e in es { fold_lists(&ast, e, &mut folds) }
+      }
+      _ => {}
+    }
+  }
+  // Editors only use one fold starting on each line, so we keep the largest.
+  folds.sort_by_key(|&(start, end)| (start, std::cmp::Reverse(end)));
+  folds.dedup_by_key(|&mut (start, _)| start);
+  Ok(Some(folds.into_iter().map(|(start_line, end_line)| FoldingRange {
+    start_line, start_character: None, end_line, end_character: None, kind: None
+  }).collect()))
+}
+
+/// Push the spans of the statement `s` and the nodes in it containing `idx` to `out`,
+/// outermost first.
+fn stmt_path(s: &Stmt, idx: usize, out: &mut Vec<Span>) {
+  let inside = |sp: Span| sp.start <= idx && idx <= sp.end;
+  out.push(s.span);
+  match &s.k {
+    StmtKind::Annot(e, _) if inside(e.span) => sexpr_path(e, idx, out),
+    StmtKind::Annot(_, s) | StmtKind::Attrs(_, s) | StmtKind::DocComment(_, s) if inside(s.span) =>
+      stmt_path(s, idx, out),
+    StmtKind::Decl(d) => {
+      let type_path = |ty: &ast::Type, out: &mut Vec<Span>| {
+        out.push(ty.span());
+        match ty {
+          ast::Type::DepType(dt) => if let Some(&sp) = dt.deps.iter().find(|&&sp| inside(sp)) { out.push(sp) },
+          ast::Type::Formula(f) => out.push(f.inner()),
+        }
+      };
+      if inside(d.id) {
+        out.push(d.id)
+      } else if let Some(bi) = d.bis.iter().find(|bi| inside(bi.span)) {
+        out.push(bi.span);
+        match (bi.local, &bi.ty) {
+          (Some(sp), _) if inside(sp) => out.push(sp),
+          (_, Some(ty)) if inside(ty.span()) => type_path(ty, out),
+          _ => {}
+        }
+      } else if let Some(ty) = d.ty.as_ref().filter(|ty| inside(ty.span())) {
+        type_path(ty, out)
+      } else if let Some(val) = d.val.as_ref().filter(|val| inside(val.span)) {
+        sexpr_path(val, idx, out)
+      }
+    }
+    StmtKind::Do(es) | StmtKind::Inout {hs: es, ..} =>
+      if let Some(e) = es.iter().find(|e| inside(e.span)) { sexpr_path(e, idx, out) },
+    _ => {}
+  }
+}
+
+/// Push the spans of `e` and the expressions in it containing `idx` to `out`, outermost first.
+fn sexpr_path(e: &SExpr, idx: usize, out: &mut Vec<Span>) {
+  out.push(e.span);
+  let inside = |e: &&SExpr| e.span.start <= idx && idx <= e.span.end;
+  let child = match &e.k {
+    SExprKind::List(es) => es.iter().find(inside),
+    SExprKind::DottedList(es, r) => es.iter().chain(Some(&**r)).find(inside),
+    SExprKind::DocComment(_, e) => Some(&**e).filter(inside),
+    SExprKind::Formula(f) => { out.push(f.inner()); None }
+    _ => None
+  };
+  if let Some(e) = child { sexpr_path(e, idx, out) }
+}
+
+/// Expand the selection at each position following the structure of the file: the identifier
+/// at the position, the expressions and binders containing it, and the statement.
+async fn selection_range(path: FileRef, positions: &[Position]) -> Result<Option<Vec<SelectionRange>>, ResponseError> {
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "selection range nonexistent file"))?;
+  let ast = if let Some(ast) = current_ast(&file).await {ast} else { return Ok(None) };
+  let text = ast.source.as_bytes();
+  Ok(Some(positions.iter().map(|&pos| {
+    let mut path = vec![];
+    if let Some(idx) = ast.source.to_idx(pos) {
+      if let Some(s) = ast.stmts.iter().find(|s| s.span.start <= idx && idx <= s.span.end) {
+        stmt_path(s, idx, &mut path)
+      }
+      let start = text[..idx].iter().rposition(|&c| !mm1_parser::ident_rest(c)).map_or(0, |i| i + 1);
+      let end = text[idx..].iter().position(|&c| !mm1_parser::ident_rest(c)).map_or(text.len(), |i| idx + i);
+      if start < end { path.push((start..end).into()) }
+    }
+    // Formulas may contain whitespace at the ends, which is not selected.
+    let trim = |sp: Span| {
+      let s = &text[sp.start..sp.end];
+      let start = s.iter().position(|c| !c.is_ascii_whitespace()).unwrap_or(s.len());
+      let end = s.iter().rposition(|c| !c.is_ascii_whitespace()).map_or(start, |i| i + 1);
+      Span {start: sp.start + start, end: sp.start + end.max(start)}
+    };
+    let mut res: Option<SelectionRange> = None;
+    let mut last = None;
+    for sp in path.into_iter().map(trim) {
+      // Each range must contain the next one.
+      if last.map_or(false, |last: Span| sp == last || sp.start < last.start || last.end < sp.end) { continue }
+      last = Some(sp);
+      res = Some(SelectionRange {range: ast.source.to_range(sp), parent: res.map(Box::new)});
+    }
+    res.unwrap_or(SelectionRange {range: Range {start: pos, end: pos}, parent: None})
+  }).collect()))
+}
+
+/// The parameters of the `mm0/holes` request, which lists the holes in a file.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HolesParams {
+  text_document: TextDocumentIdentifier,
+}
+
+/// A hole in a proof, returned by the `mm0/holes` request.
+#[derive(serde::Serialize)]
+struct Hole {
+  /// The location of the hole.
+  range: Range,
+  /// The statement that was admitted, as `|- e`.
+  goal: String,
+}
+
+/// List the holes of the proofs in the file, which are the goals that were admitted
+/// with `(sorry)` or left unproved in an explicit `_`.
+async fn holes(path: FileRef) -> Result<Vec<Hole>, ResponseError> {
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "holes nonexistent file"))?;
+  let text = file.text.ulock().1.ascii().clone();
+  let env = elaborate(path, Some(Position::default()), Default::default(), Default::default())
+    .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
+  let env = match env.into_response_error()? {
+    None => return Ok(vec![]),
+    Some((_, env)) => env,
+  };
+  let env = unsafe { env.thaw() };
+  let fe = FormatEnv { source: &text, env };
+  Ok(env.spans.iter().filter_map(|spans| spans.lc.as_ref()).flat_map(|lc| &lc.holes)
+    .map(|(sp, e)| Hole { range: text.to_range(*sp), goal: format!("|- {}", fe.pp(e, 80)) })
+    .collect())
+}
+
+/// The proof state at a position in a tactic block, returned by the `$/goalState` request.
+#[derive(serde::Serialize)]
+struct ProofState {
+  /// The hypotheses, in order.
+  hyps: Vec<ProofHyp>,
+  /// The goals, starting with the main goal.
+  goals: Vec<ProofGoal>,
+  /// The unassigned metavariables.
+  mvars: Vec<ProofMVar>,
+}
+
+/// A hypothesis in a [`ProofState`].
+#[derive(serde::Serialize)]
+struct ProofHyp {
+  name: String,
+  #[serde(rename = "type")]
+  ty: String,
+}
+
+/// A goal in a [`ProofState`].
+#[derive(serde::Serialize)]
+struct ProofGoal {
+  /// The statement to prove.
+  #[serde(rename = "type")]
+  ty: String,
+  /// The location of the proof term that created the goal, if it is in this file.
+  range: Option<Range>,
+}
+
+/// A metavariable in a [`ProofState`].
+#[derive(serde::Serialize)]
+struct ProofMVar {
+  /// The name of the metavariable, like `?a`.
+  name: String,
+  /// The sort of the metavariable, `{s}` if it is a bound variable of sort `s`,
+  /// or `provable` or `?` if the sort is not known.
+  target: String,
+  /// The location of the hole that created the metavariable, if it is in this file.
+  range: Option<Range>,
+}
+
+/// Find the innermost `focus` block in `e` that contains the position `idx`, and return
+/// the span of the `focus` keyword and the number of tactics that do not end before `idx`.
+fn find_focus(ast: &Ast, e: &SExpr, idx: usize) -> Option<(Span, usize)> {
+  if !e.span.contains(&idx) {return None}
+  let es = if let SExprKind::List(es) = &e.k {es} else {return None};
+  if let Some(r) = es.iter().find_map(|e| find_focus(ast, e, idx)) {return Some(r)}
+  match es.split_first() {
+    Some((head, tacs)) if matches!(head.k, SExprKind::Atom(Atom::Ident)) &&
+      ast.span(head.span) == b"focus" =>
+      Some((head.span, tacs.iter().filter(|e| e.span.end > idx).count())),
+    _ => None
+  }
+}
+
+/// Find the innermost `focus` block in the statement `s` that contains the position `idx`,
+/// see [`find_focus`].
+fn find_focus_stmt(ast: &Ast, s: &Stmt, idx: usize) -> Option<(Span, usize)> {
+  match &s.k {
+    StmtKind::Decl(d) => find_focus(ast, d.val.as_ref()?, idx),
+    StmtKind::Do(es) => es.iter().find_map(|e| find_focus(ast, e, idx)),
+    StmtKind::Annot(_, s) | StmtKind::Attrs(_, s) | StmtKind::DocComment(_, s) => find_focus_stmt(ast, s, idx),
+    _ => None
+  }
+}
+
+/// Get the proof state at a position in a `focus` block, which is the state after the last
+/// tactic that ends before the position. The statement containing the position is
+/// elaborated again, starting from the last snapshot, with a listener that records the
+/// proof state, and the elaboration is stopped once it passes the position. Returns `None`
+/// if the position is not in a `focus` block.
+async fn goal_state(path: FileRef, pos: Position) -> Result<Option<ProofState>, ResponseError> {
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "goal state nonexistent file"))?;
+  elaborate(path.clone(), Some(Position::default()), Default::default(), Default::default())
+    .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
+  let (ast, env) = match &*file.parsed.lock().await {
+    Some(FileCache::Ready {ast: Some(ast), res: ElabResult::Ok(_, _, env), ..}) => (ast.clone(), env.clone()),
+    _ => return Ok(None),
+  };
+  let idx = match ast.source.to_idx(pos) {Some(idx) => idx, None => return Ok(None)};
+  let stmt = match ast.stmts.iter().position(|s| s.span.contains(&idx)) {
+    Some(stmt) => stmt,
+    None => return Ok(None),
+  };
+  let (block, left) = match find_focus_stmt(&ast, &ast.stmts[stmt], idx) {
+    Some(r) => r,
+    None => return Ok(None),
+  };
+  let state = Arc::new(Mutex::new(None));
+  let cancel = CancelToken::default();
+  let listener = {
+    let (state, cancel) = (state.clone(), cancel.clone());
+    GoalStateListener::new(move |elab: &crate::elab::Elaborator, sp, n| {
+      if sp == block && n >= left {
+        *state.ulock() = Some(elab.goal_state())
+      } else if sp == block || elab.spans.stmt().start > idx {
+        cancel.cancel()
+      }
+    })
+  };
+  ElaborateBuilder {
+    ast: &ast,
+    path: path.clone(),
+    mm0_mode: path.has_extension("mm0"),
+    check_proofs: crate::get_check_proofs(),
+    report_upstream_errors: false,
+    cancel,
+    old: Some((stmt, None, env)),
+    snapshots: false,
+    profile: false,
+    recv_dep: |p| recv_dep(&path, p),
+    recv_goal: None,
+    recv_goal_state: Some(listener),
+    recv_stmt: None,
+    debugger: None,
+  }.elab().await;
+  let state = state.ulock().take();
+  Ok(state.map(|state| {
+    let range = |sp: Option<Span>| sp.map(|sp| ast.source.to_range(sp));
+    ProofState {
+      hyps: state.hyps.into_iter().map(|(name, ty)| ProofHyp {name, ty}).collect(),
+      goals: state.goals.into_iter().map(|(sp, ty)| ProofGoal {ty, range: range(sp)}).collect(),
+      mvars: state.mvars.into_iter()
+        .map(|(sp, name, target)| ProofMVar {name, target, range: range(sp)}).collect(),
+    }
+  }))
+}
+
+/// Get the result of elaborating the dependency `p` of `path`, for an elaboration of `path`
+/// which is not stored in the file cache.
+fn recv_dep(path: &FileRef, p: FileRef) -> Result<FReceiver<ElabResult<u64>>, BoxError> {
+  let (p, dep) = SERVER.vfs.get_or_insert(p)?;
+  let (send, recv) = channel();
+  if let Some(Some(FileCache::Ready {res, ..})) = dep.parsed.try_lock().as_deref() {
+    send.send(res.clone()).expect("failed to send");
+  } else {
+    Job::ElaborateDep(p, path.clone(), Some((send, ArcList::default().push(path.clone())))).spawn();
+  }
+  Ok(recv)
+}
+
+/// Find the declaration in the statement `s`, looking through annotations.
+fn find_decl(s: &Stmt) -> Option<&Decl> {
+  match &s.k {
+    StmtKind::Decl(d) => Some(d),
+    StmtKind::Annot(_, s) | StmtKind::Attrs(_, s) | StmtKind::DocComment(_, s) => find_decl(s),
+    _ => None
+  }
+}
+
+/// Generate a name following the naming convention `template`, in which `{head}` stands for
+/// `head` and `{n}` for a number, which is left out if the name is not `used`, and otherwise
+/// counts up from 1 until it is not. A template without `{n}` gets the number at the end.
+/// Returns `None` if the template does not make an identifier.
+fn fresh_name(template: &str, head: &str, used: impl Fn(&str) -> bool) -> Option<String> {
+  let template = template.replace("{head}", head);
+  let name = |n: &str| if template.contains("{n}") {
+    template.replace("{n}", n)
+  } else {
+    format!("{}{}", template, n)
+  };
+  let x = std::iter::once(name("")).chain((1..).map(|i| name(&i.to_string()))).find(|x| !used(x))?;
+  let s = x.as_bytes();
+  if s.is_empty() || !mm1_parser::ident_start(s[0]) || !s[1..].iter().all(|&c| mm1_parser::ident_rest(c)) {
+    return None
+  }
+  Some(x)
+}
+
+/// Suggest a name for the theorem `t` following the `theoremNameTemplate` convention, where the
+/// head is the head term of its conclusion, which is not the name of another declaration.
+/// Returns `None` if the theorem already has the suggested name.
+fn suggest_thm_name(path: &FileRef, env: &FrozenEnv, t: ThmId) -> Option<String> {
+  let td = env.thm(t);
+  let mut e = &td.ret;
+  let head = loop {
+    match *e {
+      ExprNode::Ref(i) if i >= td.args.len() => e = &td.heap[i],
+      ExprNode::App(t, _) => break String::from_utf8_lossy(env.data()[env.term(t).atom].name()).into_owned(),
+      _ => break "thm".into(),
+    }
+  };
+  let template = SERVER.options_for(path).theorem_name_template.unwrap_or_else(|| "{head}{n}".into());
+  let used = |x: &str| env.get_atom(x.as_bytes()).map_or(false, |a|
+    a != td.atom && (env.data()[a].decl().is_some() || env.aliases().contains_key(&a)));
+  fresh_name(&template, &head, used).filter(|x| *x.as_bytes() != **env.data()[td.atom].name())
+}
+
+/// The path of `target` as it would be written in an `import` statement of `base`. This is
+/// relative to the directory containing `base`, unless that has to go up a directory and
+/// `target` can be imported relative to one of the include paths of `base` instead.
+fn import_path(base: &std::path::Path, target: &std::path::Path) -> Option<String> {
+  let (mut dir, mut rel) = (base.parent()?.components().peekable(), target.components().peekable());
+  while let (Some(x), Some(y)) = (dir.peek(), rel.peek()) {
+    if x != y {break}
+    dir.next();
+    rel.next();
+  }
+  let mut res = std::path::PathBuf::new();
+  for _ in dir { res.push("..") }
+  res.extend(rel);
+  if res.starts_with("..") {
+    for inc in crate::include_paths(base) {
+      let file = fs::canonicalize(inc).ok().and_then(|inc| Some(target.strip_prefix(inc).ok()?
+        .to_str()?.replace('\\', "/")));
+      if let Some(file) = file {
+        if fs::canonicalize(crate::resolve_import(base, &file)).map_or(false, |p| p == target) {
+          return Some(file)
+        }
+      }
+    }
+  }
+  Some(res.to_str()?.replace('\\', "/"))
+}
+
+/// The files which import `path`, directly or indirectly, and so cannot be imported by it.
+fn downstream_files(path: &FileRef) -> HashSet<FileRef> {
+  let mut res = HashSet::new();
+  let mut todo = vec![path.clone()];
+  while let Some(path) = todo.pop() {
+    if let Some(file) = SERVER.vfs.get(&path) {
+      for p in &*file.downstream.ulock() {
+        if res.insert(p.clone()) { todo.push(p.clone()) }
+      }
+    }
+  }
+  res
+}
+
+/// Get the code actions at a range:
+///
+/// * For an unknown name, qualify it with a namespace which declares it, or import a file
+///   of the workspace which declares it.
+/// * For a use of a deprecated declaration, replace it by its suggested replacement.
+/// * For a variable whose type was inferred, add its binder to the declaration.
+/// * For an anonymous hypothesis `(_: $ foo $)`, give it a name.
+/// * In a theorem, replace the proof by the explicit proof term it elaborated to, which
+///   does not need to run the tactics again when the file is checked.
+async fn code_action(path: FileRef, range: Range) -> Result<Vec<CodeActionOrCommand>, ResponseError> {
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "code action nonexistent file"))?;
+  elaborate(path.clone(), Some(Position::default()), Default::default(), Default::default())
+    .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
+  let (ast, errors, env) = match &*file.parsed.lock().await {
+    Some(FileCache::Ready {ast: Some(ast), res: ElabResult::Ok(_, errors, env), ..}) =>
+      (ast.clone(), errors.clone(), env.clone()),
+    _ => return Ok(vec![]),
+  };
+  let idx = match ast.source.to_idx(range.start) {Some(idx) => idx, None => return Ok(vec![])};
+  let end = ast.source.to_idx(range.end).unwrap_or(idx);
+  let action = |title: String, kind, edits: Vec<TextEdit>| CodeActionOrCommand::CodeAction(CodeAction {
+    title,
+    kind: Some(kind),
+    edit: Some(WorkspaceEdit {
+      changes: Some(std::iter::once((path.url().clone(), edits)).collect()),
+      ..Default::default()
+    }),
+    ..Default::default()
+  });
+  let mut res = vec![];
+  // An error on an identifier which is not declared is an unknown name.
+  let unknown = errors.iter().flat_map(|errs| errs.iter())
+    .filter(|e| e.level == ErrorLevel::Error && e.pos.start <= end && idx <= e.pos.end)
+    .map(|e| e.pos)
+    .filter(|&sp| {
+      let x = ast.span(sp);
+      x.first().map_or(false, |&c| mm1_parser::ident_start(c)) &&
+      x.iter().all(|&c| mm1_parser::ident_rest(c) || c == b'.') &&
+      env.get_atom(x).map_or(true, |a| env.data()[a].decl().is_none() && !env.aliases().contains_key(&a))
+    }).collect::<Vec<_>>();
+  let mut importable = vec![];
+  for &sp in &unknown {
+    let x = ast.span(sp);
+    for (_, ad) in env.data().enum_iter() {
+      let name = ad.name();
+      if ad.decl().is_some() && name.len() > x.len() + 1 &&
+          name.ends_with(x) && name[..name.len() - x.len()].ends_with(b".") {
+        let new_text = String::from_utf8_lossy(name).into_owned();
+        res.push(action(format!("Qualify as '{}'", new_text),
+          CodeActionKind::QUICKFIX, vec![TextEdit {range: ast.source.to_range(sp), new_text}]));
+      }
+    }
+    // Offer to correct a typo, to a declared name or a variable or hypothesis in scope.
+    let lc = env.find(sp.start).and_then(|spans| spans.lc.as_ref());
+    let cands = env.data().enum_iter().filter(|&(a, ad)| ad.decl().is_some() || ad.sort().is_some() ||
+      lc.map_or(false, |lc| lc.vars.contains_key(&a) || lc.proofs.contains_key(&a)));
+    for name in near_misses(x, cands.map(|(_, ad)| (ad.name(), &**ad.name()))) {
+      let new_text = String::from_utf8_lossy(name).into_owned();
+      res.push(action(format!("Change to '{}'", new_text),
+        CodeActionKind::QUICKFIX, vec![TextEdit {range: ast.source.to_range(sp), new_text}]));
+    }
+    importable.push(x);
+  }
+  if !importable.is_empty() {
+    // Offer to import the other elaborated files which declare the name, except the files
+    // which import this one, because that would be an import cycle.
+    let downstream = downstream_files(&path);
+    let files: Vec<_> = SERVER.vfs.0.ulock().iter().map(|(p, f)| (p.clone(), f.clone())).collect();
+    let pos = ast.stmts.iter().rev().find(|s| matches!(s.k, StmtKind::Import(..)))
+      .map_or_else(Position::default, |s| ast.source.to_pos(s.span.end));
+    for (p, f) in files {
+      if p == path || downstream.contains(&p) { continue }
+      let env2 = if let Some((_, env2)) = try_old(&f) {env2} else {continue};
+      for &x in &importable {
+        let declared = env2.get_atom(x).and_then(|a| env2.data()[a].decl()).map_or(false, |dk| match dk {
+          DeclKey::Term(t) => env2.term(t).span.file == p,
+          DeclKey::Thm(t) => env2.thm(t).span.file == p,
+        });
+        let file = if let Some(file) = import_path(path.path(), p.path()) {file} else {continue};
+        if declared {
+          let new_text = if pos == Position::default() {
+            format!("import \"{}\";\n", file)
+          } else {
+            format!("\nimport \"{}\";", file)
+          };
+          res.push(action(format!("Import '{}' from \"{}\"", String::from_utf8_lossy(x), file),
+            CodeActionKind::QUICKFIX, vec![TextEdit {range: Range {start: pos, end: pos}, new_text}]));
+        }
+      }
+    }
+  }
+  // Offer to replace a use of a deprecated declaration by its replacement.
+  for &(sp, ref k) in env.find(idx).into_iter().flat_map(|spans| spans.find_pos(idx)) {
+    let a = match *k {
+      ObjectKind::Term(t, _) => env.term(t).atom,
+      ObjectKind::Thm(t) => env.thm(t).atom,
+      _ => continue,
+    };
+    let b = match env.deprecated().get(&a) {Some(&Deprecation {replacement: Some(b), ..}) => b, _ => continue};
+    let (name, text) = (env.data()[a].name(), ast.span(sp));
+    // Only replace the span if it is the (possibly unqualified) name of the declaration.
+    if !(name.ends_with(text) && (name.len() == text.len() || name[..name.len() - text.len()].ends_with(b"."))) {
+      continue
+    }
+    let new_text = String::from_utf8_lossy(env.data()[b].name()).into_owned();
+    res.push(action(format!("Replace '{}' by '{}'", String::from_utf8_lossy(name), new_text),
+      CodeActionKind::QUICKFIX, vec![TextEdit {range: ast.source.to_range(sp), new_text}]));
+  }
+  let stmt = ast.stmts.iter().find(|s| s.span.contains(&idx));
+  let decl = stmt.and_then(find_decl);
+  if let Some(d) = decl {
+    let declared = |a: AtomId| d.bis.iter().any(|bi| bi.local.map_or(false, |sp| env.get_atom(ast.span(sp)) == Some(a)));
+    // The arguments of the declaration, including the variables whose type was inferred.
+    let args = env.find(d.id.start).into_iter().flat_map(|spans| spans.find_pos(d.id.start))
+      .find_map(|(_, k)| match *k {
+        ObjectKind::Term(t, _) => Some(&env.term(t).args),
+        ObjectKind::Thm(t) => Some(&env.thm(t).args),
+        _ => None
+      });
+    // Offer to add the binder of a variable whose type was inferred.
+    for (_, k) in env.find(idx).into_iter().flat_map(|spans| spans.find_pos(idx)) {
+      let x = if let ObjectKind::Var(x) = *k {x} else {continue};
+      if declared(x) { continue }
+      let name = String::from_utf8_lossy(env.data()[x].name());
+      // Bound variables go before the first regular binder, and regular variables after
+      // the last variable binder, so that they are not after any binder that uses them.
+      let (new_text, pos) = match args.and_then(|args| args.iter().find(|arg| arg.0 == Some(x))) {
+        Some(&(_, Type::Bound(s))) => {
+          let text = format!("{{{}: {}}}", name, env.sort(s).name);
+          match d.bis.iter().find(|bi| bi.kind != LocalKind::Bound) {
+            Some(bi) => (format!("{} ", text), bi.span.start),
+            None => (format!(" {}", text), d.bis.last().map_or(d.id.end, |bi| bi.span.end)),
+          }
+        }
+        Some(&(_, Type::Reg(s, _))) => (format!(" ({}: {})", name, env.sort(s).name),
+          d.bis.iter().rev().find(|bi| !matches!(bi.ty, Some(ast::Type::Formula(_))))
+            .map_or(d.id.end, |bi| bi.span.end)),
+        _ => continue,
+      };
+      let pos = ast.source.to_pos(pos);
+      res.push(action(format!("Add binder {}", new_text.trim()),
+        CodeActionKind::QUICKFIX, vec![TextEdit {range: Range {start: pos, end: pos}, new_text}]));
+    }
+    // Offer to name an anonymous hypothesis, or all of them, following the
+    // `hypothesisNameTemplate` convention.
+    let anon = d.bis.iter().filter(|bi| bi.kind == LocalKind::Anon &&
+      matches!(bi.ty, Some(ast::Type::Formula(_)))).filter_map(|bi| Some((bi.span, bi.local?))).collect::<Vec<_>>();
+    if let Some(&(_, x)) = anon.iter().find(|(sp, _)| sp.contains(&idx)) {
+      let template = SERVER.options_for(&path).hypothesis_name_template.unwrap_or_else(|| "h{n}".into());
+      let declared = |h: &str| d.bis.iter().any(|bi| bi.local.map_or(false, |sp| ast.span(sp) == h.as_bytes()));
+      if let Some(new_text) = fresh_name(&template, "", declared) {
+        res.push(action(format!("Name the hypothesis '{}'", new_text),
+          CodeActionKind::REFACTOR_REWRITE, vec![TextEdit {range: ast.source.to_range(x), new_text}]));
+      }
+      let mut names: Vec<String> = vec![];
+      let mut edits = vec![];
+      for &(_, x) in &anon {
+        let new_text = match fresh_name(&template, "", |h| declared(h) || names.iter().any(|n| n == h)) {
+          Some(h) => h,
+          None => break,
+        };
+        names.push(new_text.clone());
+        edits.push(TextEdit {range: ast.source.to_range(x), new_text});
+      }
+      if edits.len() > 1 && edits.len() == anon.len() {
+        res.push(action(format!("Name the hypotheses {}", names.join(", ")),
+          CodeActionKind::REFACTOR_REWRITE, edits));
+      }
+    }
+    // Offer to rename a theorem following the `theoremNameTemplate` convention.
+    if matches!(d.k, DeclKind::Thm | DeclKind::Axiom) && d.id.start <= idx && idx <= d.id.end {
+      if let Some(DeclKey::Thm(t)) = env.get_atom(ast.span(d.id)).and_then(|a| env.data()[a].decl()) {
+        if let Some(name) = suggest_thm_name(&path, &env, t) {
+          if let Ok(Some(edit)) = rename(path.clone(), ast.source.to_pos(d.id.start), name.clone()).await {
+            res.push(CodeActionOrCommand::CodeAction(CodeAction {
+              title: format!("Rename to '{}'", name),
+              kind: Some(CodeActionKind::REFACTOR_REWRITE),
+              edit: Some(edit),
+              ..Default::default()
+            }))
+          }
+        }
+      }
+    }
+  }
+  if let (Some(stmt), Some(d)) = (stmt, decl.filter(|d| d.k == DeclKind::Thm)) {
+    if idx < end {
+      if let Some((title, edits)) = extract_lemma(&ast, &env, stmt, d, (idx..end).into()) {
+        res.push(action(title, CodeActionKind::REFACTOR_EXTRACT, edits))
+      }
+    }
+  }
+  if let Some(Decl {id, val: Some(val), ..}) = decl.filter(|d| d.k == DeclKind::Thm) {
+    if let Some(DeclKey::Thm(t)) = env.get_atom(ast.span(*id)).and_then(|a| env.data()[a].decl()) {
+      let env = unsafe { env.thaw() };
+      let fe = FormatEnv { source: &ast.source, env };
+      match fe.pretty(|p| p.refine_proof(t).map(|doc| format!("'{}", doc.pretty(100)))) {
+        Some(new_text) if new_text.as_bytes() != ast.span(val.span) =>
+          res.push(action("Replace the proof by an explicit proof term".into(),
+            CodeActionKind::REFACTOR_REWRITE, vec![TextEdit {range: ast.source.to_range(val.span), new_text}])),
+        _ => {}
+      }
+    }
+  }
+  Ok(res)
+}
+
+/// Extract the subproof selected by `sel` in the proof of the theorem `d`, declared by the
+/// statement `stmt`, into a new theorem above it. The new theorem takes the variables and
+/// hypotheses of `d` used by the subproof, and its proof is the text of the subproof.
+/// Returns the title of the code action, and the edits inserting the new theorem and
+/// replacing the subproof by an application of it.
+fn extract_lemma(ast: &Ast, env: &FrozenEnv, stmt: &Stmt, d: &Decl, sel: Span) -> Option<(String, Vec<TextEdit>)> {
+  use std::fmt::Write;
+  /// Push the lists in `e` which contain `sel` to `out`, outermost first.
+  fn enclosing<'a>(e: &'a SExpr, sel: Span, out: &mut Vec<&'a SExpr>) {
+    if e.span.start <= sel.start && sel.end <= e.span.end {
+      if let SExprKind::List(es) = &e.k {
+        out.push(e);
+        for e in es { enclosing(e, sel, out) }
+      }
+    }
+  }
+  /// Call `f` on the atoms in `e`.
+  fn atoms(e: &FrozenLispKind, f: &mut impl FnMut(AtomId)) {
+    match e.unwrap() {
+      &FrozenLispKind::Atom(a) => f(a),
+      FrozenLispKind::List(es) => for e in &**es { atoms(e, f) },
+      FrozenLispKind::DottedList(es, r) => {
+        for e in &**es { atoms(e, f) }
+        atoms(r, f)
+      }
+      _ => {}
+    }
+  }
+  let proof_at = |sp: Span| env.find(sp.start)?.find_pos(sp.start).find_map(|(sp2, k)| match k {
+    ObjectKind::Proof(p) if *sp2 == sp => Some(p),
+    _ => None
+  });
+  let outer = env.find(d.id.start)?.find_pos(d.id.start).find_map(|(_, k)|
+    if let ObjectKind::Thm(t) = *k {Some(env.thm(t))} else {None})?;
+  // The innermost list containing the selection which is an application of a theorem.
+  let mut lists = vec![];
+  enclosing(d.val.as_ref()?, sel, &mut lists);
+  let (sub, proof, td) = lists.into_iter().rev().find_map(|e| {
+    let mut it = if let SExprKind::List(es) = &e.k {es.iter()} else {unreachable!()};
+    let mut head = it.next()?;
+    if matches!(ast.span(head.span), b"!" | b"!!") { head = it.next()? }
+    let p = proof_at(head.span)?;
+    match p.uncons().next()?.as_atom().and_then(|a| env.data()[a].decl()) {
+      Some(DeclKey::Thm(t)) => Some((e, p, env.thm(t))),
+      _ => None
+    }
+  })?;
+  let mut it = proof.uncons();
+  it.next();
+  let args = (0..td.args.len()).map(|_| it.next().map(|e| unsafe { e.thaw() }.clone()))
+    .collect::<Option<Vec<_>>>()?;
+  let ret = Subst::new(unsafe { env.thaw() }, &td.heap, args).subst(&td.ret);
+  let mut used = HashSet::new();
+  atoms(proof, &mut |a| { used.insert(a); });
+
+  // The variables used by the subproof, and the bound variables they depend on.
+  let mut need = outer.args.iter().map(|(a, _)| a.map_or(false, |a| used.contains(&a))).collect::<Vec<_>>();
+  let deps = outer.args.iter().zip(&need).fold(0, |deps, (arg, &n)| match arg.1 {
+    Type::Reg(_, ds) if n => deps | ds,
+    _ => deps
+  });
+  let bvars = outer.args.iter().filter(|(_, ty)| ty.bound()).map(|(a, _)| a).collect::<Vec<_>>();
+  let mut bv = 0;
+  for (n, (_, ty)) in need.iter_mut().zip(&*outer.args) {
+    if ty.bound() {
+      if deps & (1 << bv) != 0 { *n = true }
+      bv += 1
+    }
+  }
+  let name = |a: AtomId| String::from_utf8_lossy(env.data()[a].name());
+  let (mut bis, mut app) = (String::new(), vec![]);
+  for (&(a, ty), _) in outer.args.iter().zip(&need).filter(|p| *p.1) {
+    let a = a?;
+    match ty {
+      Type::Bound(s) => write!(bis, " {{{}: {}}}", name(a), env.sort(s).name).expect("impossible"),
+      Type::Reg(s, ds) => {
+        write!(bis, " ({}: {}", name(a), env.sort(s).name).expect("impossible");
+        Signature::deps(env, &bvars, ds, &mut bis);
+        bis.push(')')
+      }
+    }
+    app.push(name(a))
+  }
+  for h in outer.hyps.iter().filter_map(|h| h.0).filter(|h| used.contains(h)) {
+    let f = d.bis.iter().find_map(|bi| match bi.ty {
+      Some(ast::Type::Formula(f)) if bi.local.map_or(false, |sp| ast.span(sp) == &**env.data()[h].name()) => Some(f),
+      _ => None
+    })?;
+    write!(bis, " ({}: {})", name(h), String::from_utf8_lossy(ast.span(f.0))).expect("impossible");
+    app.push(name(h))
+  }
+  let fe = unsafe { env.format_env(&ast.source) };
+  let mut concl = String::new();
+  fe.pretty(|p| p.expr(&ret).render_fmt(usize::MAX, &mut concl)).expect("writing to a string");
+
+  // The new theorem is `foo_lem` (or `foo_lem1`, ...) for the theorem `foo`, in the same
+  // namespace. There are fewer declarations than candidate names.
+  let id = ast.span(d.id);
+  let full = env.data()[outer.atom].name();
+  let ns = if full.ends_with(id) {&full[..full.len() - id.len()]} else {b""};
+  let lemma = (0..=env.terms().len() + env.thms().len())
+    .map(|i| if i == 0 {format!("{}_lem", String::from_utf8_lossy(id))} else {format!("{}_lem{}", String::from_utf8_lossy(id), i)})
+    .find(|x| env.get_atom(&[ns, x.as_bytes()].concat()).and_then(|a| env.data()[a].decl()).is_none())
+    .expect("impossible");
+  // An application `@ foo x` ends with the closing parenthesis of the enclosing list.
+  let (sp, proof) = if ast.span(sub.span).first() == Some(&b'@') {
+    let sp = Span {start: sub.span.start, end: sub.span.end - 1};
+    (sp, format!("({})", String::from_utf8_lossy(&ast.span(sp)[1..]).trim()))
+  } else {
+    (sub.span, String::from_utf8_lossy(ast.span(sub.span)).into_owned())
+  };
+  let new_text = format!("theorem {}{}: {} =\n'{};\n\n", lemma, bis, concl, proof);
+  let pos = ast.source.to_pos(stmt.span.start);
+  let app = if app.is_empty() {lemma.clone()} else {format!("(! {} {})", lemma, app.join(" "))};
+  Some((format!("Extract the subproof into a new theorem '{}'", lemma), vec![
+    TextEdit {range: Range {start: pos, end: pos}, new_text},
+    TextEdit {range: ast.source.to_range(sp), new_text: app},
+  ]))
+}
+
+/// The parameters of the `textDocument/inlayHint` request, which is not yet in `lsp_types`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InlayHintParams {
+  text_document: TextDocumentIdentifier,
+  /// The range in which hints are requested.
+  range: Range,
+}
+
+/// The kind of an [`InlayHint`].
+#[derive(Copy, Clone, Serialize_repr)]
+#[repr(u8)]
+enum InlayHintKind {
+  /// A hint for the type of a variable.
+  Type = 1,
+  /// A hint for the arguments of an application.
+  Parameter = 2,
+}
+
+/// An inlay hint, returned by the `textDocument/inlayHint` request.
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct InlayHint {
+  /// The location of the hint.
+  position: Position,
+  /// The text of the hint.
+  label: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  kind: Option<InlayHintKind>,
+  padding_left: bool,
+  padding_right: bool,
+}
+
+/// Show the sorts of binders whose type was inferred, the arguments inferred for theorem
+/// applications not written with `!`, and the grouping of notations without parentheses,
+/// in the given range of the file.
+async fn inlay_hints(path: FileRef, range: Range) -> Result<Vec<InlayHint>, ResponseError> {
+  use std::fmt::Write;
+  if !SERVER.options_for(&path).inlay_hints.unwrap_or(true) { return Ok(vec![]) }
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "inlay hint nonexistent file"))?;
+  elaborate(path.clone(), Some(Position::default()), Default::default(), Default::default())
+    .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
+  let (ast, env) = match &*file.parsed.lock().await {
+    Some(FileCache::Ready {ast: Some(ast), res: ElabResult::Ok(_, _, env), ..}) => (ast.clone(), env.clone()),
+    _ => return Ok(vec![]),
+  };
+  let start = ast.source.to_idx(range.start).unwrap_or(0);
+  let end = ast.source.to_idx(range.end).unwrap_or_else(|| ast.source.len());
+  let text: &str = &ast.source;
+  let mut res = vec![];
+  let mut hint = |idx: usize, label: String, kind, padding_left| res.push(InlayHint {
+    position: ast.source.to_pos(idx), label, kind, padding_left, padding_right: false
+  });
+  let name = |a: AtomId| String::from_utf8_lossy(env.data()[a].name());
+
+  // The sorts of the variables of a declaration which were not given a type.
+  for d in ast.stmts.iter().filter(|s| s.span.start <= end && start <= s.span.end).filter_map(find_decl) {
+    let args = match env.find(d.id.start).into_iter().flat_map(|spans| spans.find_pos(d.id.start))
+      .find_map(|(_, k)| match *k {
+        ObjectKind::Term(t, _) => Some(&env.term(t).args),
+        ObjectKind::Thm(t) => Some(&env.thm(t).args),
+        _ => None
+      }) {
+      Some(args) => args,
+      None => continue
+    };
+    let mut inferred = String::new();
+    for (a, ty) in &**args {
+      let a = if let Some(a) = *a {a} else {continue};
+      let s = &env.sort(ty.sort()).name;
+      match d.bis.iter().find(|bi| bi.local.map_or(false, |sp| ast.span(sp) == &**env.data()[a].name())) {
+        Some(bi) => if let (None, Some(sp)) = (&bi.ty, bi.local) {
+          hint(sp.end, format!(": {}", s), Some(InlayHintKind::Type), false)
+        }
+        None => {
+          if ty.bound() { write!(inferred, " {{{}: {}}}", name(a), s) } else { write!(inferred, " ({}: {})", name(a), s) }
+            .expect("impossible")
+        }
+      }
+    }
+    if !inferred.is_empty() {
+      hint(d.bis.last().map_or(d.id.end, |bi| bi.span.end), inferred.trim().into(), Some(InlayHintKind::Type), true)
+    }
+  }
+
+  let fe = unsafe { env.format_env(&ast.source) };
+  let pe = env.pe();
+  let mut apps = vec![];
+  for spans in env.spans() {
+    for &(sp, ref k) in spans {
+      if sp.end < start || end < sp.start { continue }
+      match k {
+        // The arguments of a theorem application, unless they were given explicitly with `!`.
+        ObjectKind::Proof(p) => {
+          let mut it = p.uncons();
+          let td = match it.next().and_then(|e| e.as_atom()).and_then(|a| env.data()[a].decl()) {
+            Some(DeclKey::Thm(t)) => env.thm(t),
+            _ => continue
+          };
+          if td.args.is_empty() || text[..sp.start].trim_end().ends_with('!') { continue }
+          let mut label = vec![];
+          for ((a, _), e) in td.args.iter().zip(it) {
+            let mut s = String::new();
+            fe.pretty(|p| p.expr(unsafe { e.thaw() }).render_fmt(usize::MAX, &mut s)).expect("writing to a string");
+            let s = s.strip_prefix("$ ").and_then(|s| s.strip_suffix(" $")).unwrap_or(&s);
+            let s = if s.chars().count() > 30 {format!("{}…", s.chars().take(29).collect::<String>())} else {s.into()};
+            label.push(format!("{} := {}", a.map_or("_".into(), name), s))
+          }
+          hint(sp.end, label.join(", "), Some(InlayHintKind::Parameter), true)
+        }
+        // The application of a notation at one of its constants.
+        &ObjectKind::Term(t, full) if full != sp => {
+          let tok = ast.span(sp);
+          let (info, infix) = match (pe.infixes.get(tok), pe.prefixes.get(tok)) {
+            (Some(info), _) => (info, true),
+            (None, Some(info)) => (info, false),
+            (None, None) => continue,
+          };
+          if info.term != t { continue }
+          // A notation which ends with a constant is delimited on the right, and a prefix
+          // notation on the left, so only the other notations can be ambiguous.
+          apps.push((full, infix || matches!(info.lits.last(), Some(Literal::Var(..)))))
+        }
+        _ => {}
+      }
+    }
+  }
+  apps.sort_by_key(|&(sp, _)| (sp.start, sp.end));
+  apps.dedup_by_key(|&mut (sp, _)| sp);
+  for &(sp, open) in &apps {
+    if !open || text[..sp.start].trim_end().ends_with('(') && text[sp.end..].trim_start().starts_with(')') { continue }
+    if apps.iter().any(|&(sp2, _)| sp2 != sp && sp2.start <= sp.start && sp.end <= sp2.end) {
+      hint(sp.start, "(".into(), None, false);
+      hint(sp.end, ")".into(), None, false);
+    }
+  }
+  res.sort_by_key(|h| (h.position.line, h.position.character));
+  Ok(res)
+}
+
+/// The commands of the code lenses shown above each theorem. Each of them elaborates the
+/// theorem again, and the result replaces the title of the lens.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+enum LensCommand {
+  /// Check the proof of the theorem, and report the number of errors.
+  Verify,
+  /// Report the time and lisp allocations it took to elaborate the theorem.
+  Profile,
+  /// Report the number of theorem applications in the proof.
+  ProofSize,
+}
+crate::deep_size_0!(LensCommand);
+
+impl LensCommand {
+  const ALL: [Self; 3] = [Self::Verify, Self::Profile, Self::ProofSize];
+
+  /// The name of the command in `workspace/executeCommand`.
+  fn name(self) -> &'static str {
+    match self {
+      Self::Verify => "mm0.verify",
+      Self::Profile => "mm0.profile",
+      Self::ProofSize => "mm0.proofSize",
+    }
+  }
+
+  /// The title of the lens before the command has been run.
+  fn title(self) -> &'static str {
+    match self {
+      Self::Verify => "verify",
+      Self::Profile => "profile",
+      Self::ProofSize => "show proof size",
+    }
+  }
+}
+
+/// Show the [lens commands](LensCommand) above each theorem, or their results if they have
+/// been run on the current version of the file.
+async fn code_lens(path: FileRef) -> Result<Vec<CodeLens>, ResponseError> {
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "code lens nonexistent file"))?;
+  elaborate(path.clone(), Some(Position::default()), Default::default(), Default::default())
+    .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
+  let ast = match &*file.parsed.lock().await {
+    Some(FileCache::Ready {ast: Some(ast), ..}) => ast.clone(),
+    _ => return Ok(vec![]),
+  };
+  let version = file.text.ulock().0;
+  let results = file.lens_results.ulock();
+  let mut res = vec![];
+  for s in &ast.stmts {
+    let d = match find_decl(s) {Some(d) if d.k == DeclKind::Thm => d, _ => continue};
+    let range = ast.source.to_range(d.id);
+    for cmd in LensCommand::ALL {
+      let title = results.1.get(&(s.span.start, cmd)).filter(|_| results.0 == version)
+        .map_or_else(|| cmd.title(), String::as_str);
+      res.push(CodeLens {
+        range,
+        command: Some(Command {
+          title: title.into(),
+          command: cmd.name().into(),
+          arguments: Some(vec![to_value(path.url()).expect("impossible"), to_value(range.start).expect("impossible")]),
+        }),
+        data: None,
+      })
+    }
+  }
+  Ok(res)
+}
+
+/// Run the [lens command](LensCommand) `command` with arguments `[uri, position]`,
+/// on the theorem at the position. The result is shown in the title of the lens, and
+/// also returned.
+async fn execute_command(command: &str, args: Vec<serde_json::Value>) -> Result<Option<String>, ResponseError> {
+  let cmd = LensCommand::ALL.iter().copied().find(|cmd| cmd.name() == command)
+    .ok_or_else(|| response_err(ErrorCode::InvalidParams, format!("unknown command {}", command)))?;
+  let (uri, pos): (Url, Position) = from_value(args.into())
+    .map_err(|e| response_err(ErrorCode::InvalidParams, format!("{}", e)))?;
+  let path = FileRef::from(uri);
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "execute command nonexistent file"))?;
+  let version = file.text.ulock().0;
+  elaborate(path.clone(), Some(Position::default()), Default::default(), Default::default())
+    .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
+  let (ast, env) = match &*file.parsed.lock().await {
+    Some(FileCache::Ready {ast: Some(ast), res: ElabResult::Ok(_, _, env), ..}) => (ast.clone(), env.clone()),
+    _ => return Ok(None),
+  };
+  let idx = match ast.source.to_idx(pos) {Some(idx) => idx, None => return Ok(None)};
+  let i = match ast.stmts.iter().position(|s| s.span.contains(&idx)) {Some(i) => i, None => return Ok(None)};
+  let span = ast.stmts[i].span;
+  // Elaborate the file up to the end of the statement, restarting from the last snapshot
+  // before it, with the proofs checked and the statements profiled.
+  let trunc = Arc::new(Ast {
+    source: ast.source.clone(),
+    imports: ast.imports.iter().filter(|(sp, _)| sp.start < span.end).cloned().collect(),
+    stmts: ast.stmts[..=i].to_vec(),
+    errors: vec![],
+  });
+  let (_, _, errors, env) = ElaborateBuilder {
+    ast: &trunc,
+    path: path.clone(),
+    mm0_mode: path.has_extension("mm0"),
+    check_proofs: true,
+    report_upstream_errors: false,
+    cancel: CancelToken::default(),
+    old: Some((i, None, env)),
+    snapshots: false,
+    profile: true,
+    recv_dep: |p| recv_dep(&path, p),
+    recv_goal: None,
+    recv_goal_state: None,
+    recv_stmt: None,
+    debugger: None,
+  }.elab().await;
+  let prof = env.profile().iter().rev().find(|prof| prof.span == span)
+    .ok_or_else(|| response_err(ErrorCode::InternalError, "statement was not elaborated"))?;
+  let result = match cmd {
+    LensCommand::Verify => match errors.iter()
+      .filter(|e| e.level == ErrorLevel::Error && span.start <= e.pos.start && e.pos.end <= span.end).count() {
+      0 => "✓ verified".into(),
+      1 => "✗ 1 error".into(),
+      n => format!("✗ {} errors", n),
+    },
+    LensCommand::Profile => format!("elaborated in {:.3?}, {} lisp allocations", prof.time, prof.lisp_allocs),
+    LensCommand::ProofSize => match prof.proof_size {
+      Some(n) => format!("proof size: {}", n),
+      None => "no proof".into(),
+    },
+  };
+  {
+    let mut results = file.lens_results.ulock();
+    if results.0 != version { *results = (version, HashMap::new()) }
+    results.1.insert((span.start, cmd), result.clone());
+  }
+  if SERVER.caps.ulock().code_lens_refresh {
+    send_message(Request {
+      id: String::from("code_lens_refresh").into(),
+      method: "workspace/codeLens/refresh".to_owned(),
+      params: serde_json::Value::Null,
+    }).map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?
+  }
+  Ok(Some(result))
+}
+
+/// Format the file with the [`formatter`](crate::formatter). This returns a single edit
+/// replacing the whole file, or no edits if the file is already formatted.
+fn formatting(path: FileRef) -> Result<Vec<TextEdit>, ResponseError> {
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "formatting nonexistent file"))?;
+  let text = match file.text.ulock().1.try_ascii() {
+    Some(text) => text.clone(),
+    None => return Ok(vec![]),
+  };
+  let new_text = crate::formatter::format(path.path(), &text, crate::formatter::DEFAULT_WIDTH)
+    .map_err(|e| response_err(ErrorCode::InvalidRequest, e))?;
+  if new_text == **text { return Ok(vec![]) }
+  Ok(vec![TextEdit {range: Range {start: Position::default(), end: text.to_pos(text.len())}, new_text}])
+}
+
+#[derive(Copy, Clone, Serialize_repr, Deserialize_repr)]
+#[repr(u8)]
+enum TraceKind {Sort, Decl, Global}
+
+fn make_completion_item(path: &FileRef, fe: FormatEnv<'_>, ad: &FrozenAtomData, detail: bool, tk: TraceKind) -> Option<CompletionItem> {
+  use CompletionItemKind::{Class, Constructor, Method};
+  macro_rules! done {($desc:expr, $kind:expr, $doc:expr) => {
+    CompletionItem {
+      label: String::from_utf8_lossy(ad.name()).into(),
+      detail: if detail {Some($desc)} else {None},
+      kind: Some($kind),
+      documentation: $doc.as_ref().map(|doc| {
+        Documentation::MarkupContent(MarkupContent {
+          kind: MarkupKind::Markdown,
+          value: doc.to_string(),
+        })
+      }),
+      data: Some(to_value((path.url(), tk)).unwrap()),
+      ..Default::default()
+    }
+  }}
+  match tk {
+    TraceKind::Sort => ad.sort().map(|s| {
+      let sd = &fe.sorts[s];
+      done!(format!("{}", sd), Class, sd.doc)
+    }),
+    TraceKind::Decl => ad.decl().map(|dk| match dk {
+      DeclKey::Term(t) => {let td = &fe.terms[t]; done!(format!("{}", fe.to(td)), Constructor, td.doc)}
+      DeclKey::Thm(t) => {let td = &fe.thms[t]; done!(format!("{}", fe.to(td)), Method, td.doc)}
+    }),
+    TraceKind::Global => {
+      let e = ad.lisp().as_ref()?;
+      Some(done!(format!("{}", fe.to(unsafe {e.thaw()})), match *e.unwrap() {
+        FrozenLispKind::Atom(_) |
+        FrozenLispKind::MVar(_, _) |
+        FrozenLispKind::Goal(_) => CompletionItemKind::Constant,
+        FrozenLispKind::List(_) |
+        FrozenLispKind::DottedList(_, _) |
+        FrozenLispKind::Undef |
+        FrozenLispKind::Number(_) |
+        FrozenLispKind::Rational(_) |
+        FrozenLispKind::String(_) |
+        FrozenLispKind::Bool(_) |
+        FrozenLispKind::AtomMap(_) |
+        FrozenLispKind::HashTable(_) |
+        FrozenLispKind::Vector(_) |
+        FrozenLispKind::StringPort(_) |
+        FrozenLispKind::Annot(_, _) |
+        FrozenLispKind::Ref(_) => CompletionItemKind::Value,
+        FrozenLispKind::Syntax(_) => CompletionItemKind::Event,
+        FrozenLispKind::Proc(ref p) if
+          match *unsafe {p.thaw()} {
+            Proc::Builtin(p) => p.to_str() == ad.name().as_str(),
+            _ => false,
+          } => return None,
+        FrozenLispKind::Proc(_) => CompletionItemKind::Function
+      }, e.doc()))
+    }
+  }
+}
+
+/// The kind of name expected at the cursor, which determines the completion candidates.
+enum CompletionContext {
+  /// Anything, at the top level of a statement.
+  Any,
+  /// Sorts, in the binders of a declaration.
+  Sort,
+  /// Terms and variables, in a formula. If the term or notation whose argument is being
+  /// typed is known, this is the type of the argument.
+  Math(Option<Type>),
+  /// Lisp builtins and globals, in a `do` block, or also theorems and hypotheses if the flag
+  /// is set, in a proof.
+  Lisp(bool),
+}
+
+impl CompletionContext {
+  fn new(env: &FrozenEnv, text: &str, idx: usize) -> Self {
+    let (stack, region) = scan(text, idx, env.pe());
+    match stack.last().map(|f| f.group) {
+      Some(Group::Math | Group::MathParen) => Self::Math(expected_type(env, text, &stack, idx)),
+      Some(Group::Binder) => Self::Sort,
+      Some(Group::Lisp(_) | Group::Block) => Self::Lisp(region == Region::Value),
+      None => match region {
+        Region::Stmt => Self::Any,
+        Region::Do => Self::Lisp(false),
+        Region::Value => Self::Lisp(true),
+      }
+    }
+  }
+}
+
+/// Find the type expected at the index `idx` of a formula, if it is an argument of a term
+/// application `foo x y` or directly follows a constant of a notation. `stack` is the result
+/// of [`scan`] at `idx`.
+fn expected_type(env: &FrozenEnv, text: &str, stack: &[Frame], idx: usize) -> Option<Type> {
+  let pe = env.pe();
+  for (i, f) in stack.iter().rev().enumerate() {
+    let mut items = &*f.items;
+    // The element containing the cursor is not one of the preceding arguments.
+    if let Some((last, rest)) = items.split_last() {
+      if i != 0 || last.end == idx { items = rest }
+    }
+    let tok = |sp: &Span| &text.as_bytes()[sp.start..sp.end];
+    let last = match items.last() {
+      Some(last) => tok(last),
+      None if f.group == Group::MathParen => continue,
+      None => return None,
+    };
+    // The leading constant of a prefix notation is not in its literals.
+    let lit = if let Some(info) = pe.prefixes.get(last) {
+      Some((info, info.lits.first()))
+    } else if let Some(info) = pe.infixes.get(last) {
+      let mut lits = info.lits.iter().skip_while(|lit| !matches!(lit, Literal::Const(c) if **c == *last));
+      Some((info, lits.nth(1)))
+    } else { None };
+    if let Some((info, lit)) = lit {
+      if let Some(&Literal::Var(i, _)) = lit { return Some(env.term(info.term).args[i].1) }
+    } else if let Some(DeclKey::Term(t)) = env.get_atom(tok(&items[0])).and_then(|a| env.data()[a].decl()) {
+      return env.term(t).args.get(items.len() - 1).map(|arg| arg.1)
+    }
+    return None
+  }
+  None
+}
+
+async fn completion(path: FileRef, pos: Position) -> Result<CompletionResponse, ResponseError> {
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "document symbol nonexistent file"))?;
+  let (text, env) = if let Some(old) = try_old(&file) { old } else {
+    let env = elaborate(path.clone(), Some(Position::default()), Default::default(), Default::default())
+      .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
+    match env.into_response_error()? {
+      None => return Ok(CompletionResponse::Array(vec![])),
+      Some((_, env)) => (file.text.ulock().1.clone(), env)
+    }
+  };
+  let text = text.ascii().clone();
+  // At the name of a theorem, suggest a name following the `theoremNameTemplate` convention.
+  if let Some(ast) = current_ast(&file).await {
+    let d = ast.source.to_idx(pos).and_then(|idx| ast.stmts.iter().find(|s| s.span.contains(&idx))
+      .and_then(find_decl).filter(|d| matches!(d.k, DeclKind::Thm | DeclKind::Axiom) &&
+        d.id.start <= idx && idx <= d.id.end));
+    if let Some(d) = d {
+      if let Some(DeclKey::Thm(t)) = env.get_atom(ast.span(d.id)).and_then(|a| env.data()[a].decl()) {
+        if let Some(label) = suggest_thm_name(&path, &env, t) {
+          return Ok(CompletionResponse::Array(vec![CompletionItem {
+            detail: Some("suggested name".into()),
+            kind: Some(CompletionItemKind::Method),
+            // The suggestion replaces the whole name, whatever has been typed.
+            filter_text: Some(String::from_utf8_lossy(ast.span(d.id)).into_owned()),
+            text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+              range: ast.source.to_range(d.id), new_text: label.clone()
+            })),
+            label,
+            ..Default::default()
+          }]))
+        }
+      }
+    }
+  }
+  let ctx = {
+    let cur = file.text.ulock().1.ascii().clone();
+    cur.to_idx(pos).map_or(CompletionContext::Any, |idx| CompletionContext::new(&env, &cur, idx))
+  };
+  let lc = text.to_idx(pos).and_then(|idx| env.find(idx)).and_then(|spans| spans.lc.as_ref());
+  let fe = unsafe { env.format_env(&text) };
+  let mut res = vec![];
+  // Candidates are ordered by rank, so that the best matches come first.
+  // In a proof, the lisp builtins and globals come after the theorems.
+  let lisp_rank = if let CompletionContext::Lisp(true) = ctx {2} else {1};
+  let mut push = |ci: Option<CompletionItem>, rank: u8| if let Some(mut ci) = ci {
+    ci.sort_text = Some(format!("{}{}", rank, ci.label));
+    res.push(ci)
+  };
+  let coe = |s1: SortId, s2: SortId| s1 == s2 || env.pe().coes.get(&s1).map_or(false, |m| m.contains_key(&s2));
+  let decl_rank = |dk| match (&ctx, dk) {
+    (CompletionContext::Any, _) |
+    (CompletionContext::Math(None), DeclKey::Term(_)) |
+    (CompletionContext::Lisp(true), DeclKey::Thm(_)) => Some(1),
+    (&CompletionContext::Math(Some(Type::Reg(s, _))), DeclKey::Term(t)) => match env.term(t).ret.0 {
+      s2 if s2 == s => Some(1),
+      s2 if coe(s2, s) => Some(2),
+      _ => None
+    },
+    _ => None
+  };
+  let local = |label: String, detail: Option<String>| Some(CompletionItem {
+    label, detail, kind: Some(CompletionItemKind::Variable), ..Default::default()
+  });
+  let name = |a: AtomId| String::from_utf8_lossy(env.data()[a].name()).into_owned();
+  match (&ctx, lc) {
+    (&CompletionContext::Math(ty), Some(lc)) => for (&a, (_, is)) in &lc.vars {
+      let ok = match (ty, is) {
+        (Some(Type::Bound(s)), &InferSort::Bound(s2)) => s == s2,
+        (Some(Type::Bound(_)), _) => false,
+        (Some(Type::Reg(s, _)), is) => is.sort().map_or(true, |s2| coe(s2, s)),
+        (None, _) => true,
+      };
+      if ok { push(local(name(a), is.sort().map(|s| env.sort(s).name.to_string())), 0) }
+    }
+    (CompletionContext::Lisp(true), Some(lc)) => for &a in lc.proofs.keys() { push(local(name(a), None), 0) }
+    _ => {}
+  }
+  if let CompletionContext::Any | CompletionContext::Lisp(_) = ctx {
+    let keyword = |s: &str| Some(CompletionItem {
+      label: s.into(), kind: Some(CompletionItemKind::Keyword), ..Default::default()
+    });
+    BuiltinProc::for_each(|_, s| push(keyword(s), lisp_rank));
+    Syntax::for_each(|_, s| push(keyword(s), lisp_rank));
+  }
+  for (a, ad) in env.data().enum_iter() {
+    if let CompletionContext::Any | CompletionContext::Sort = ctx {
+      push(make_completion_item(&path, fe, ad, false, TraceKind::Sort), 1)
+    }
+    if !env.hidden().contains(&a) {
+      if let Some(rank) = ad.decl().and_then(decl_rank) {
+        push(make_completion_item(&path, fe, ad, false, TraceKind::Decl), rank)
+      }
+    }
+    if let CompletionContext::Any | CompletionContext::Lisp(_) = ctx {
+      push(make_completion_item(&path, fe, ad, false, TraceKind::Global), lisp_rank)
+    }
+  }
+  for (&a, &b) in env.aliases() {
+    if let Some(rank) = env.data()[b].decl().and_then(decl_rank) {
+      if let Some(mut ci) = make_completion_item(&path, fe, &env.data()[b], false, TraceKind::Decl) {
+        ci.label = name(a);
+        push(Some(ci), rank)
+      }
+    }
+  }
+  Ok(CompletionResponse::Array(res))
+}
+
+async fn completion_resolve(ci: CompletionItem) -> Result<CompletionItem, ResponseError> {
+  let data = if let Some(data) = ci.data {data} else {
+    // Keywords are documented by their builtin, and local variables have no documentation.
+    let doc = if ci.kind == Some(CompletionItemKind::Keyword) {
+      BuiltinProc::from_str(&ci.label).map(BuiltinProc::doc)
+        .or_else(|| Syntax::from_str(&ci.label).map(Syntax::doc))
+    } else { None };
+    return Ok(CompletionItem {
+      documentation: doc.map(|doc| Documentation::MarkupContent(MarkupContent {
+        kind: MarkupKind::Markdown,
+        value: doc.into(),
+      })),
+      ..ci
+    })
+  };
+  let (uri, tk): (Url, TraceKind) = from_value(data).map_err(|e|
+    response_err(ErrorCode::InvalidRequest, format!("bad JSON {:?}", e)))?;
+  let path = uri.into();
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "document symbol nonexistent file"))?;
+
+  let maybe_old = if SERVER.elab_on(&path).unwrap_or_default() == ElabOn::Save { try_old(&file) } else { None };
+  let (text, env) = if let Some((contents, frozen)) = maybe_old {
+    (contents.ascii().clone(), frozen)
+  } else {
+    let text = file.text.ulock().1.ascii().clone();
+    let env = elaborate(path.clone(), Some(Position::default()), Default::default(), Default::default())
+      .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?
+      .into_response_error()?
+      .ok_or_else(|| response_err(ErrorCode::InternalError, "import cycle"))?.1;
+    (text, env)
+  };
+
+  let fe = unsafe { env.format_env(&text) };
+  env.get_atom(ci.label.as_bytes()).and_then(|a| {
+    let ci2 = make_completion_item(&path, fe, &env.data()[a], true, tk);
+    match (ci2, tk, env.aliases().get(&a)) {
+      (None, TraceKind::Decl, Some(&b)) =>
+        make_completion_item(&path, fe, &env.data()[b], true, tk).map(|ci2| CompletionItem {label: ci.label, ..ci2}),
+      (ci2, _, _) => ci2,
+    }
+  }).ok_or_else(|| response_err(ErrorCode::ContentModified, "completion missing"))
+}
+
+/// The kind of an open bracket, in the scan done by [`scan`].
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Group {
+  /// A binder group `(x y: foo)` or `{x: foo}` of a declaration.
+  Binder,
+  /// A lisp list `(f x y)` or `[f x y]`, or an implicit list `@ f x y` if the flag is set.
+  Lisp(bool),
+  /// A curly list `{x + y}` or a `do` block, which are not applications.
+  Block,
+  /// A math formula `$ foo x y $`.
+  Math,
+  /// A parenthesized math expression `(foo x y)`.
+  MathParen,
+}
+
+/// An open bracket, and the spans of the elements inside it up to the cursor.
+struct Frame {
+  group: Group,
+  items: Vec<Span>,
+}
+
+impl Frame {
+  /// Start a new element of the innermost group at `start`, unless it is the target of a
+  /// quote `'x`, which already started the element.
+  fn push(stack: &mut [Frame], quote: &mut bool, start: usize) {
+    if let Some(f) = stack.last_mut() {
+      if !std::mem::take(quote) { f.items.push(start.into()) }
+    }
+  }
+
+  /// End the last element of the innermost group at `end`.
+  fn end(stack: &mut [Frame], end: usize) {
+    if let Some(sp) = stack.last_mut().and_then(|f| f.items.last_mut()) { sp.end = end }
+  }
+}
+
+/// The kind of top level text containing the cursor, in the scan done by [`scan`].
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Region {
+  /// A statement, outside any lisp code.
+  Stmt,
+  /// The body of a `do` block.
+  Do,
+  /// The value of a definition or theorem, following `=`.
+  Value,
+}
+
+/// Scan `text` up to the index `idx`, and return the brackets which are open at `idx`
+/// together with the kind of top level text containing it. This uses the text
+/// instead of the parsed file, because the code around the cursor is usually incomplete
+/// while the user is typing it.
+fn scan(text: &str, idx: usize, pe: &ParserEnv) -> (Vec<Frame>, Region) {
+  let s = &text.as_bytes()[..idx];
+  let (mut stack, mut region, mut quote, mut i) = (Vec::<Frame>::new(), Region::Stmt, false, 0);
+  let delim = |c: u8| pe.delims_l.get(c) || pe.delims_r.get(c);
+  let open = |stack: &mut Vec<Frame>, quote: &mut bool, i, group| {
+    Frame::push(stack, quote, i);
+    stack.push(Frame {group, items: vec![]})
+  };
+  let close = |stack: &mut Vec<Frame>, i| {
+    stack.pop();
+    Frame::end(stack, i)
+  };
+  while i < s.len() {
+    let c = s[i];
+    if let Some(Group::Math | Group::MathParen) = stack.last().map(|f| f.group) {
+      match c {
+        b'$' => {
+          while let Some(f) = stack.pop() { if f.group == Group::Math {break} }
+          Frame::end(&mut stack, i + 1)
+        }
+        b'(' => open(&mut stack, &mut quote, i, Group::MathParen),
+        b')' => if stack.last().map(|f| f.group) == Some(Group::MathParen) { close(&mut stack, i + 1) },
+        _ if c.is_ascii_whitespace() => {}
+        _ => {
+          Frame::push(&mut stack, &mut quote, i);
+          if !delim(c) {
+            while i + 1 < s.len() && !matches!(s[i + 1], b'$' | b'(' | b')') &&
+              !s[i + 1].is_ascii_whitespace() && !delim(s[i + 1]) { i += 1 }
+          }
+          Frame::end(&mut stack, i + 1)
+        }
+      }
+    } else {
+      match c {
+        b'-' if s.get(i + 1) == Some(&b'-') =>
+          while i + 1 < s.len() && s[i + 1] != b'\n' { i += 1 },
+        b'"' => {
+          Frame::push(&mut stack, &mut quote, i);
+          i += 1;
+          while i < s.len() && s[i] != b'"' { i += if s[i] == b'\\' {2} else {1} }
+          Frame::end(&mut stack, (i + 1).min(s.len()))
+        }
+        b'$' => open(&mut stack, &mut quote, i, Group::Math),
+        b'(' | b'[' | b'{' if stack.is_empty() && region == Region::Stmt => open(&mut stack, &mut quote, i, Group::Binder),
+        b'(' | b'[' => open(&mut stack, &mut quote, i, Group::Lisp(false)),
+        b'{' => open(&mut stack, &mut quote, i, Group::Block),
+        b')' | b']' | b'}' => {
+          while stack.last().map(|f| f.group) == Some(Group::Lisp(true)) { close(&mut stack, i) }
+          close(&mut stack, i + 1)
+        }
+        b'@' if !stack.is_empty() => open(&mut stack, &mut quote, i, Group::Lisp(true)),
+        b'\'' | b',' | b'`' if !stack.is_empty() => { Frame::push(&mut stack, &mut quote, i); quote = true }
+        b';' if stack.is_empty() => region = Region::Stmt,
+        _ if c.is_ascii_whitespace() => {}
+        _ => {
+          let start = i;
+          while i + 1 < s.len() && !b"()[]{}\"$;".contains(&s[i + 1]) && !s[i + 1].is_ascii_whitespace() { i += 1 }
+          if stack.is_empty() {
+            match &s[start..=i] {
+              b"=" => region = Region::Value,
+              b"do" => region = Region::Do,
+              _ => {}
+            }
+          } else {
+            Frame::push(&mut stack, &mut quote, start);
+            Frame::end(&mut stack, i + 1)
+          }
+        }
+      }
+    }
+    i += 1
+  }
+  (stack, region)
+}
+
+/// An application containing the cursor, found by [`enclosing_call`].
+struct Call<'a> {
+  /// True for a lisp application `(f x y)`, false for a term application in a formula.
+  lisp: bool,
+  /// True for an application `(! foo x y)` of a theorem whose variables are explicit.
+  explicit: bool,
+  /// The name of the function being applied.
+  head: &'a str,
+  /// The index of the argument containing the cursor, or following the cursor.
+  active: usize,
+}
+
+/// Find the application containing the index `idx` of `text`.
+fn enclosing_call<'a>(text: &'a str, idx: usize, pe: &ParserEnv) -> Option<Call<'a>> {
+  let (stack, _) = scan(text, idx, pe);
+  let f = stack.last()?;
+  let lisp = match f.group {
+    Group::Lisp(_) => true,
+    Group::Math | Group::MathParen => false,
+    Group::Binder | Group::Block => return None,
+  };
+  let mut items = &*f.items;
+  let explicit = lisp && matches!(items.first().map(|sp| &text[sp.start..sp.end]), Some("!" | "!!"));
+  if explicit { items = &items[1..] }
+  let (head, args) = items.split_first()?;
+  if args.is_empty() && head.end == idx { return None }
+  let active = match args.last() {
+    Some(sp) if sp.end == idx => args.len() - 1,
+    _ => args.len(),
+  };
+  Some(Call {lisp, explicit, head: &text[head.start..head.end], active})
+}
+
+/// A signature for `textDocument/signatureHelp`, with the parameters marked by their
+/// offsets in the label.
+#[derive(Default)]
+struct Signature {
+  label: String,
+  params: Vec<ParameterInformation>,
+}
+
+impl Signature {
+  /// Append a parameter to the label, preceded by a space.
+  fn param(&mut self, s: &str) {
+    self.label.push(' ');
+    let start = self.label.encode_utf16().count();
+    self.label.push_str(s);
+    #[allow(clippy::cast_possible_truncation)]
+    let offsets = [start as u32, (start + s.encode_utf16().count()) as u32];
+    self.params.push(ParameterInformation {label: ParameterLabel::LabelOffsets(offsets), documentation: None})
+  }
+
+  /// Append the binders `bis` of a term or theorem to the label, one parameter per variable.
+  fn binders(&mut self, env: &FrozenEnv, bis: &[(Option<AtomId>, Type)]) {
+    let name = |a: &Option<AtomId>| a.map_or("_".into(), |a| String::from_utf8_lossy(env.data()[a].name()));
+    let mut bvars = vec![];
+    for (a, ty) in bis {
+      match *ty {
+        Type::Bound(s) => {
+          self.param(&format!("{{{}: {}}}", name(a), env.sort(s).name));
+          bvars.push(a)
+        }
+        Type::Reg(s, ds) => {
+          let mut out = format!("({}: {}", name(a), env.sort(s).name);
+          Self::deps(env, &bvars, ds, &mut out);
+          out.push(')');
+          self.param(&out)
+        }
+      }
+    }
+  }
+
+  /// Write the bound variables `bvars` selected by the dependency set `ds` to `out`,
+  /// each preceded by a space.
+  fn deps(env: &FrozenEnv, bvars: &[&Option<AtomId>], ds: u64, out: &mut String) {
+    for (i, a) in bvars.iter().enumerate() {
+      if ds & (1 << i) != 0 {
+        out.push(' ');
+        out.push_str(&a.map_or("_".into(), |a| String::from_utf8_lossy(env.data()[a].name())))
+      }
+    }
+  }
+
+  fn finish(self, doc: Option<DocComment>, active: usize) -> SignatureHelp {
+    #[allow(clippy::cast_possible_truncation)]
+    let active = Some(active as u32);
+    SignatureHelp {
+      signatures: vec![SignatureInformation {
+        label: self.label,
+        documentation: doc.map(|doc| Documentation::MarkupContent(MarkupContent {
+          kind: MarkupKind::Markdown,
+          value: doc.to_string(),
+        })),
+        parameters: Some(self.params),
+        active_parameter: active,
+      }],
+      active_signature: Some(0),
+      active_parameter: active,
+    }
+  }
+}
+
+/// Show the binders of the term or theorem applied at the cursor, or the number of arguments
+/// of the lisp procedure applied at the cursor.
+async fn signature_help(path: FileRef, pos: Position) -> Result<Option<SignatureHelp>, ResponseError> {
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "signature help nonexistent file"))?;
+  let env = if let Some((_, env)) = try_old(&file) { env } else {
+    let env = elaborate(path.clone(), Some(Position::default()), Default::default(), Default::default())
+      .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
+    match env.into_response_error()? {
+      None => return Ok(None),
+      Some((_, env)) => env
+    }
+  };
+  let text = file.text.ulock().1.ascii().clone();
+  let idx = if let Some(idx) = text.to_idx(pos) {idx} else {return Ok(None)};
+  let call = if let Some(call) = enclosing_call(&text, idx, env.pe()) {call} else {return Ok(None)};
+  // Builtin procedures which are not used in the file have no atom.
+  let a = env.get_atom(call.head.as_bytes()).map(|a| match env.aliases().get(&a) {
+    Some(&b) if env.data()[a].decl().is_none() => b,
+    _ => a,
+  });
+  let fe = unsafe { env.format_env(&text) };
+  let mut sig = Signature {label: call.head.into(), params: vec![]};
+  Ok(match a.and_then(|a| env.data()[a].decl()) {
+    Some(DeclKey::Term(t)) => {
+      let td = env.term(t);
+      sig.binders(&env, &td.args);
+      sig.label += ": ";
+      sig.label += env.sort(td.ret.0).name.as_str();
+      let bvars = td.args.iter().filter(|(_, ty)| ty.bound()).map(|(a, _)| a).collect::<Vec<_>>();
+      Signature::deps(&env, &bvars, td.ret.1, &mut sig.label);
+      Some(sig.finish(td.doc.clone(), call.active))
+    }
+    Some(DeclKey::Thm(t)) if call.lisp => {
+      let td = env.thm(t);
+      sig.binders(&env, &td.args);
+      let (mut heap, mut bvars) = (vec![], vec![]);
+      fe.binders(&td.args, &mut heap, &mut bvars);
+      for e in &td.heap[heap.len()..] {
+        let e = fe.expr_node(&heap, &mut None, e);
+        heap.push(e)
+      }
+      let expr = |e| {
+        let mut out = String::new();
+        fe.pretty(|p| p.expr(&fe.expr_node(&heap, &mut None, e)).render_fmt(usize::MAX, &mut out))
+          .expect("writing to a string");
+        out
+      };
+      for (h, e) in &td.hyps {
+        let h = h.map_or("_".into(), |h| String::from_utf8_lossy(env.data()[h].name()));
+        sig.param(&format!("({}: {})", h, expr(e)))
+      }
+      { use std::fmt::Write; write!(sig.label, ": {}", expr(&td.ret)).expect("impossible"); }
+      let active = if call.explicit { call.active } else { td.args.len() + call.active };
+      Some(sig.finish(td.doc.clone(), active))
+    }
+    None if call.lisp => {
+      let (spec, doc) = match a.and_then(|a| env.data()[a].lisp().as_ref()) {
+        Some(ld) => match ld.unwrap() {
+          FrozenLispKind::Proc(p) => {
+            let p = unsafe { p.thaw() };
+            (p.spec(), ld.doc().clone().or_else(|| p.doc()))
+          }
+          _ => return Ok(None)
+        },
+        None => match BuiltinProc::from_bytes(call.head.as_bytes()) {
+          Some(p) => (p.spec(), Some(p.doc().into())),
+          None => return Ok(None)
+        }
+      };
+      sig.label.insert(0, '(');
+      let n = match spec { ProcSpec::Exact(n) | ProcSpec::AtLeast(n) => n };
+      for i in 1..=n { sig.param(&format!("arg{}", i)) }
+      if let ProcSpec::AtLeast(_) = spec { sig.param("...") }
+      sig.label.push(')');
+      let active = if let ProcSpec::AtLeast(n) = spec { call.active.min(n) } else { call.active };
+      Some(sig.finish(doc, active))
+    }
+    _ => None
+  })
+}
+
+/// The identity of a sort, term, theorem or lisp global which is the same in every file that
+/// imports it: the file and the span of the name in its declaration.
+type DeclLoc = (FileRef, Span);
+
+/// A sort, term, theorem or lisp global used in a file.
+#[derive(Copy, Clone)]
+enum DeclTarget {
+  Sort(SortId),
+  Term(TermId),
+  Thm(ThmId),
+  Global(AtomId),
+}
+
+impl DeclTarget {
+  /// The declaration used by the object `k` in `env`, if any.
+  fn new(env: &FrozenEnv, k: &ObjectKind) -> Option<Self> {
+    match *k {
+      ObjectKind::Sort(s) => Some(Self::Sort(s)),
+      ObjectKind::Term(t, _) => Some(Self::Term(t)),
+      ObjectKind::Thm(t) => Some(Self::Thm(t)),
+      ObjectKind::Expr(ref e) => match env.data()[head_atom(env, e)?].decl()? {
+        DeclKey::Term(t) => Some(Self::Term(t)),
+        DeclKey::Thm(_) => None,
+      },
+      ObjectKind::Proof(ref p) => match env.data()[head_atom(env, p)?].decl()? {
+        DeclKey::Thm(t) => Some(Self::Thm(t)),
+        DeclKey::Term(_) => None,
+      },
+      ObjectKind::Global(a) if BuiltinProc::from_bytes(env.data()[a].name()).is_none() =>
+        Some(Self::Global(a)),
+      ObjectKind::Global(_) |
+      ObjectKind::Var(_) |
+      ObjectKind::LispVar(_) |
+      ObjectKind::Import(_) |
+      ObjectKind::Syntax(_) |
+      ObjectKind::RefineSyntax(_) => None,
+    }
+  }
+
+  /// The atom naming the declaration.
+  fn atom(self, env: &FrozenEnv) -> AtomId {
+    match self {
+      Self::Sort(s) => env.sort(s).atom,
+      Self::Term(t) => env.term(t).atom,
+      Self::Thm(t) => env.thm(t).atom,
+      Self::Global(a) => a,
+    }
+  }
+
+  /// The location of the declaration.
+  fn loc(self, env: &FrozenEnv) -> Option<DeclLoc> {
+    let FileSpan {file, span} = match self {
+      Self::Sort(s) => &env.sort(s).span,
+      Self::Term(t) => &env.term(t).span,
+      Self::Thm(t) => &env.thm(t).span,
+      Self::Global(a) => &env.data()[a].lisp().as_ref()?.src().as_ref()?.0,
+    };
+    Some((file.clone(), *span))
+  }
+
+  /// Returns true if `a` names a declaration of the same kind as this one.
+  fn is_declared(self, env: &FrozenEnv, a: AtomId) -> bool {
+    let ad = &env.data()[a];
+    match self {
+      Self::Sort(_) => ad.sort().is_some(),
+      Self::Term(_) | Self::Thm(_) => ad.decl().is_some(),
+      Self::Global(_) => ad.lisp().is_some(),
+    }
+  }
+}
+
+/// The head atom of the expression or proof `e`, resolving a qualified name
+/// introduced by `import "file" as F;`.
+fn head_atom(env: &FrozenEnv, e: &FrozenLispVal) -> Option<AtomId> {
+  let a = e.uncons().next().unwrap_or(e).as_atom()?;
+  match env.aliases().get(&a) {
+    Some(&b) if env.data()[a].decl().is_none() => Some(b),
+    _ => Some(a),
+  }
+}
+
+/// The local variable or hypothesis used by the object `k` in `env`, if any.
+fn local_var(env: &FrozenEnv, k: &ObjectKind) -> Option<AtomId> {
+  match *k {
+    ObjectKind::Var(a) => Some(a),
+    ObjectKind::Expr(ref e) | ObjectKind::Proof(ref e) => {
+      let a = head_atom(env, e)?;
+      if env.data()[a].decl().is_some() {None} else {Some(a)}
+    }
+    _ => None,
+  }
+}
+
+/// The reverse reference index of a file, which maps each sort, term, theorem and lisp global
+/// to its uses in the file. It is built when the file is elaborated, so that the uses of a
+/// declaration in all the files that import it can be found without searching their spans.
+#[derive(Default)]
+struct RefIndex(HashMap<DeclLoc, Vec<(Span, Span)>>);
+
+impl RefIndex {
+  /// Build the index for the environment `env`. Each use is recorded as the span of the
+  /// token, and the span to show as the reference, which is the whole notation for a
+  /// notation token.
+  fn new(env: &FrozenEnv) -> Self {
+    let mut refs = HashMap::<_, Vec<_>>::new();
+    for spans in env.spans() {
+      for &(sp, ref k) in spans {
+        if let Some(loc) = DeclTarget::new(env, k).and_then(|d| d.loc(env)) {
+          let full = if let ObjectKind::Term(_, full) = *k {full} else {sp};
+          refs.entry(loc).or_default().push((sp, full))
+        }
+      }
+    }
+    for uses in refs.values_mut() {
+      uses.sort_by_key(|&(sp, _)| (sp.start, sp.end));
+      uses.dedup_by_key(|&mut (sp, _)| sp);
+    }
+    Self(refs)
+  }
+}
+
+/// Find the uses of the declaration at `loc` in the files which have been elaborated, using
+/// their [`RefIndex`]. Files which have been changed since they were last elaborated are skipped.
+async fn find_uses(loc: &DeclLoc) -> Vec<(FileRef, Arc<LinedString>, Vec<(Span, Span)>)> {
+  let files: Vec<_> = SERVER.vfs.0.ulock().iter().map(|(p, f)| (p.clone(), f.clone())).collect();
+  let mut res = vec![];
+  for (path, file) in files {
+    if let Some(FileCache::Ready {source, refs, ..}) = &*file.parsed.lock().await {
+      if !file.text.ulock().1.ptr_eq(source) { continue }
+      if let (Some(text), Some(uses)) = (source.try_ascii(), refs.0.get(loc)) {
+        res.push((path, text.clone(), uses.clone()))
+      }
+    }
+  }
+  res
+}
+
+/// The symbol at a position in a file, found by [`symbol_at`].
+enum Symbol {
+  /// A local variable or hypothesis, with its uses in the statement (the token spans).
+  Var(AtomId, Vec<Span>),
+  /// A declaration.
+  Decl(DeclTarget, DeclLoc),
+}
+
+/// Elaborate the file and find the symbols at `pos`, and the span of the token there.
+async fn symbol_at(path: &FileRef, pos: Position) ->
+    Result<Option<(Arc<LinedString>, FrozenEnv, Vec<(Span, Symbol)>)>, ResponseError> {
+  let file = SERVER.vfs.get(path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "references: nonexistent file"))?;
+  let text = match file.text.ulock().1.try_ascii() {
+    Some(text) => text.clone(),
+    None => return Ok(None),
+  };
+  let idx = if let Some(idx) = text.to_idx(pos) {idx} else { return Ok(None) };
+  let env = elaborate(path.clone(), Some(Position::default()), Default::default(), Default::default())
+    .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
+  let env = if let Some((_, env)) = env.into_response_error()? {env} else { return Ok(None) };
+  let spans = if let Some(spans) = env.find(idx) {spans} else { return Ok(None) };
+  let syms = spans.find_pos(idx).filter_map(|&(sp, ref k)| {
+    if let Some(a) = local_var(&env, k) {
+      let uses = spans.into_iter().filter(|(_, k2)| local_var(&env, k2) == Some(a))
+        .map(|&(sp2, _)| sp2).collect();
+      Some((sp, Symbol::Var(a, uses)))
+    } else {
+      let d = DeclTarget::new(&env, k)?;
+      Some((sp, Symbol::Decl(d, d.loc(&env)?)))
+    }
+  }).collect();
+  Ok(Some((text, env, syms)))
+}
+
+/// Find the references to the symbol at `pos`: the uses of a local variable in its statement,
+/// or the uses of a declaration in all the files which have been elaborated. If `include_decl`
+/// is false, the declaration (or for a local variable, the use at `pos`) is omitted.
+async fn references(path: FileRef, pos: Position, include_decl: bool) ->
+    Result<Vec<(FileRef, Range)>, ResponseError> {
+  let (text, _, syms) = if let Some(res) = symbol_at(&path, pos).await? {res} else { return Ok(vec![]) };
+  let mut res = vec![];
+  for (sp, sym) in syms {
+    match sym {
+      Symbol::Var(_, uses) => for sp2 in uses {
+        if include_decl || sp != sp2 { res.push((path.clone(), text.to_range(sp2))) }
+      },
+      Symbol::Decl(_, loc) => for (file, text2, uses) in find_uses(&loc).await {
+        for (sp2, full) in uses {
+          if include_decl || file != loc.0 || sp2 != loc.1 { res.push((file.clone(), text2.to_range(full))) }
+        }
+      },
+    }
+  }
+  Ok(res)
+}
+
+/// The text and environment of the last elaboration of `path`, if the file has not been
+/// changed since.
+async fn ready_file(path: &FileRef) -> Option<(Arc<LinedString>, FrozenEnv)> {
+  let file = SERVER.vfs.get(path)?;
+  let g = file.parsed.lock().await;
+  if let Some(FileCache::Ready {source, res: ElabResult::Ok(_, _, env), ..}) = &*g {
+    if !file.text.ulock().1.ptr_eq(source) { return None }
+    Some((source.try_ascii()?.clone(), env.clone()))
+  } else { None }
+}
+
+/// The term or theorem declared by the statement with spans `spans` in the file `path`.
+fn stmt_decl(env: &FrozenEnv, path: &FileRef, spans: &Spans<ObjectKind>) -> Option<(DeclTarget, DeclLoc)> {
+  spans.into_iter().find_map(|&(sp, ref k)| match DeclTarget::new(env, k)? {
+    d @ (DeclTarget::Term(_) | DeclTarget::Thm(_)) => {
+      let loc = d.loc(env)?;
+      if loc.0 == *path && loc.1 == sp {Some((d, loc))} else {None}
+    }
+    _ => None
+  })
+}
+
+/// The call hierarchy item for the term or theorem `d`.
+fn call_item(env: &FrozenEnv, d: DeclTarget) -> Option<CallHierarchyItem> {
+  let file = match d {
+    DeclTarget::Term(t) => &env.term(t).span.file,
+    DeclTarget::Thm(t) => &env.thm(t).span.file,
+    DeclTarget::Sort(_) | DeclTarget::Global(_) => return None,
+  };
+  let text = SERVER.vfs.get(file)?.text.ulock().1.try_ascii()?.clone();
+  let fe = unsafe { env.format_env(&text) };
+  let (fsp, full, detail, kind) = match d {
+    DeclTarget::Term(t) => {
+      let td = env.term(t);
+      (&td.span, td.full, format!("{}", fe.to(td)), SymbolKind::Constructor)
+    }
+    DeclTarget::Thm(t) => {
+      let td = env.thm(t);
+      (&td.span, td.full, format!("{}", fe.to(td)), SymbolKind::Method)
+    }
+    DeclTarget::Sort(_) | DeclTarget::Global(_) => return None,
+  };
+  let a = d.atom(env);
+  Some(CallHierarchyItem {
+    name: String::from_utf8_lossy(env.data()[a].name()).into(),
+    kind,
+    tags: if env.deprecated().contains_key(&a) {Some(vec![SymbolTag::Deprecated])} else {None},
+    detail: Some(detail),
+    uri: fsp.file.url().clone(),
+    range: text.to_range(full),
+    selection_range: text.to_range(fsp.span),
+    data: None,
+  })
+}
+
+/// The declaration of the call hierarchy item `item`.
+async fn item_decl(item: &CallHierarchyItem) -> Option<(Arc<LinedString>, FrozenEnv, DeclTarget, DeclLoc)> {
+  let path = FileRef::from(item.uri.clone());
+  let (text, env) = ready_file(&path).await?;
+  let start = text.to_idx(item.selection_range.start)?;
+  let (d, loc) = stmt_decl(&env, &path, env.find(start)?)?;
+  if loc.1.start != start { return None }
+  Some((text, env, d, loc))
+}
+
+/// Find the term or theorem at `pos`, to show the declarations using it and used by it.
+async fn prepare_call_hierarchy(path: FileRef, pos: Position) ->
+    Result<Option<Vec<CallHierarchyItem>>, ResponseError> {
+  let (_, env, syms) = if let Some(res) = symbol_at(&path, pos).await? {res} else { return Ok(None) };
+  let items = syms.into_iter().filter_map(|(_, sym)| match sym {
+    Symbol::Decl(d, _) => call_item(&env, d),
+    Symbol::Var(..) => None,
+  }).collect::<Vec<_>>();
+  Ok(if items.is_empty() {None} else {Some(items)})
+}
+
+/// Find the terms and theorems whose declarations use the item, in the files which have been
+/// elaborated, using their [`RefIndex`].
+async fn incoming_calls(item: &CallHierarchyItem) ->
+    Result<Option<Vec<CallHierarchyIncomingCall>>, ResponseError> {
+  let loc = if let Some((_, _, _, loc)) = item_decl(item).await {loc} else { return Ok(None) };
+  let mut res = vec![];
+  for (path, text, uses) in find_uses(&loc).await {
+    let env = if let Some((_, env)) = ready_file(&path).await {env} else {continue};
+    let mut callers: Vec<(DeclLoc, DeclTarget, Vec<Range>)> = vec![];
+    for (sp, full) in uses {
+      let (d, caller) = match env.find(sp.start).and_then(|spans| stmt_decl(&env, &path, spans)) {
+        Some(r) => r,
+        None => continue,
+      };
+      if caller == loc { continue }
+      match callers.iter_mut().find(|c| c.0 == caller) {
+        Some(c) => c.2.push(text.to_range(full)),
+        None => callers.push((caller, d, vec![text.to_range(full)])),
+      }
+    }
+    for (_, d, from_ranges) in callers {
+      if let Some(from) = call_item(&env, d) {
+        res.push(CallHierarchyIncomingCall {from, from_ranges})
+      }
+    }
+  }
+  Ok(Some(res))
+}
+
+/// Find the declarations used by the item: the theorems used in the proof of a theorem, or
+/// the terms used by a term or definition.
+async fn outgoing_calls(item: &CallHierarchyItem) ->
+    Result<Option<Vec<CallHierarchyOutgoingCall>>, ResponseError> {
+  let (text, env, this, loc) = if let Some(r) = item_decl(item).await {r} else { return Ok(None) };
+  let spans = if let Some(spans) = env.find(loc.1.start) {spans} else { return Ok(None) };
+  let mut callees: Vec<(DeclLoc, DeclTarget, Vec<Range>)> = vec![];
+  for &(sp, ref k) in spans {
+    let d = match (this, DeclTarget::new(&env, k)) {
+      (DeclTarget::Thm(_), Some(d @ DeclTarget::Thm(_))) |
+      (DeclTarget::Term(_), Some(d @ DeclTarget::Term(_))) => d,
+      _ => continue
+    };
+    let callee = if let Some(l) = d.loc(&env) {l} else {continue};
+    if callee == loc { continue }
+    let range = text.to_range(if let ObjectKind::Term(_, full) = *k {full} else {sp});
+    match callees.iter_mut().find(|c| c.0 == callee) {
+      Some(c) => if !c.2.contains(&range) { c.2.push(range) },
+      None => callees.push((callee, d, vec![range])),
+    }
+  }
+  Ok(Some(callees.into_iter().filter_map(|(_, d, from_ranges)|
+    Some(CallHierarchyOutgoingCall {to: call_item(&env, d)?, from_ranges})).collect()))
+}
+
+/// The occurrences of the name of a symbol, which are changed by [`rename`].
+struct Renaming {
+  /// The name of the symbol, as written in its declaration.
+  name: String,
+  /// The range of the name at the requested position.
+  range: Range,
+  /// The ranges of the name in each file.
+  edits: Vec<(FileRef, Range)>,
+}
+
+/// If the token `tk` at `sp` is `name` or a qualified name ending with `.name`, the span of
+/// `name` in it.
+fn name_span(tk: &[u8], sp: Span, name: &[u8]) -> Option<Span> {
+  if tk == name || tk.ends_with(name) && tk[..tk.len() - name.len()].ends_with(b".") {
+    Some((sp.end - name.len()..sp.end).into())
+  } else { None }
+}
+
+/// Find the occurrences of the name of the symbol at `pos`. Unlike [`references`], this only
+/// returns the uses where the name is written, and not notations for a term. If `new_name` is
+/// given, this fails if the symbol cannot be renamed to it because the name is already used.
+async fn renaming(path: FileRef, pos: Position, new_name: Option<&str>) ->
+    Result<Option<Renaming>, ResponseError> {
+  let (text, env, syms) = if let Some(res) = symbol_at(&path, pos).await? {res} else { return Ok(None) };
+  let (sp, sym) = if let Some(s) = syms.into_iter().next() {s} else { return Ok(None) };
+  let (name, edits) = match sym {
+    Symbol::Var(a, uses) => {
+      let name = env.data()[a].name().to_vec();
+      if let Some(new) = new_name {
+        if env.find(sp.start).and_then(|spans| spans.lc.as_ref())
+            .map_or(false, |lc| lc.vars.keys().chain(lc.proofs.keys())
+              .any(|&b| env.data()[b].name().as_str() == new)) {
+          return Err(response_err(ErrorCode::InvalidParams, format!("'{}' is already used", new)))
+        }
+      }
+      let edits = uses.into_iter().filter_map(|sp2| name_span(&text[sp2], sp2, &name))
+        .map(|sp2| (path.clone(), text.to_range(sp2))).collect();
+      (name, edits)
+    }
+    Symbol::Decl(d, loc) => {
+      let uses = find_uses(&loc).await;
+      let name = match uses.iter().find(|(file, _, _)| *file == loc.0) {
+        Some((_, text2, _)) => text2[loc.1].to_vec(),
+        None => return Err(response_err(ErrorCode::InvalidRequest,
+          format!("cannot rename a declaration in {}", loc.0))),
+      };
+      if let Some(new) = new_name {
+        let full = env.data()[d.atom(&env)].name();
+        let mut new_full = full[..full.len() - name.len()].to_vec();
+        new_full.extend_from_slice(new.as_bytes());
+        if env.get_atom(&new_full).map_or(false, |b| d.is_declared(&env, b)) {
+          return Err(response_err(ErrorCode::InvalidParams,
+            format!("'{}' is already declared", String::from_utf8_lossy(&new_full))))
+        }
+      }
+      let edits = uses.into_iter().flat_map(|(file, text2, uses)| {
+        let name = &name;
+        uses.into_iter().filter_map(move |(sp2, _)| name_span(&text2[sp2], sp2, name)
+          .map(|sp2| (file.clone(), text2.to_range(sp2))))
+      }).collect();
+      (name, edits)
+    }
+  };
+  let range = if let Some(sp) = name_span(&text[sp], sp, &name) {text.to_range(sp)} else { return Ok(None) };
+  Ok(Some(Renaming {name: String::from_utf8_lossy(&name).into_owned(), range, edits}))
+}
+
+/// Check that the symbol at `pos` can be renamed, and return the range of its name there.
+async fn prepare_rename(path: FileRef, pos: Position) -> Result<Option<PrepareRenameResponse>, ResponseError> {
+  Ok(renaming(path, pos, None).await?.map(|Renaming {name, range, ..}|
+    PrepareRenameResponse::RangeWithPlaceholder {range, placeholder: name}))
+}
+
+/// Rename the symbol at `pos` to `new_name`, in the statement for a local variable, or in
+/// all the files which have been elaborated for a declaration. Only the tokens which refer
+/// to the symbol are changed, so other uses of the same name are not affected.
+async fn rename(path: FileRef, pos: Position, new_name: String) -> Result<Option<WorkspaceEdit>, ResponseError> {
+  let new = new_name.as_bytes();
+  if new.is_empty() || !mm1_parser::ident_start(new[0]) || !new[1..].iter().all(|&c| mm1_parser::ident_rest(c)) {
+    return Err(response_err(ErrorCode::InvalidParams, format!("'{}' is not a valid identifier", new_name)))
+  }
+  let Renaming {edits, ..} = if let Some(r) = renaming(path, pos, Some(&new_name)).await? {r} else {
+    return Err(response_err(ErrorCode::InvalidRequest, "there is nothing to rename here"))
+  };
+  let mut changes = HashMap::<_, Vec<_>>::new();
+  for (file, range) in edits {
+    changes.entry(file.url().clone()).or_default().push(TextEdit {range, new_text: new_name.clone()})
+  }
+  Ok(Some(WorkspaceEdit {changes: Some(changes), ..Default::default()}))
+}
+
+struct Server {
   conn: Connection,
   #[allow(unused)]
   caps: Mutex<ClientCapabilities>,
@@ -1173,8 +3792,16 @@ struct Server {
   vfs: Vfs,
   pool: ThreadPool,
   #[allow(clippy::type_complexity)]
-  threads: Arc<(Mutex<VecDeque<(Job, Arc<AtomicBool>)>>, Condvar)>,
+  threads: Arc<(Mutex<VecDeque<(Job, CancelToken)>>, Condvar)>,
   options: Mutex<ServerOptions>,
+  /// The workspace folders open in the client.
+  folders: Mutex<Vec<Folder>>,
+  /// The include paths given on the command line, which are searched after the include
+  /// paths of the workspace folders.
+  include_paths: Vec<std::path::PathBuf>,
+  /// The `$/progress` tokens requested by [`ElabProgress`], and whether the client has
+  /// created them.
+  progress: Mutex<HashMap<RequestId, bool>>,
 }
 
 
@@ -1200,6 +3827,8 @@ struct ClientCapabilities {
   reg_id: Option<RequestId>,
   definition_location_links: Option<bool>,
   goal_view: bool,
+  code_lens_refresh: bool,
+  work_done_progress: bool,
 }
 
 impl ClientCapabilities {
@@ -1213,7 +3842,13 @@ impl ClientCapabilities {
     let goal_view = params.initialization_options
       .and_then(|o| from_value(o).ok()).and_then(|o: InitOptions| o.extra_capabilities)
       .and_then(|c| c.goal_view).unwrap_or(false);
-    ClientCapabilities { reg_id: None, definition_location_links: dll, goal_view }
+    let code_lens_refresh = params.capabilities.workspace.as_ref()
+      .and_then(|w| w.code_lens.as_ref()).and_then(|c| c.refresh_support).unwrap_or(false);
+    let work_done_progress = params.capabilities.window.as_ref()
+      .and_then(|w| w.work_done_progress).unwrap_or(false);
+    ClientCapabilities {
+      reg_id: None, definition_location_links: dll, goal_view, code_lens_refresh, work_done_progress
+    }
   }
 
   fn register(&mut self) -> Result<()> {
@@ -1306,15 +3941,14 @@ impl std::fmt::Display for Job {
 }
 
 impl Job {
-  fn spawn_core<F>(self, cancel: Arc<AtomicBool>, fut: F)
+  fn spawn_core<F>(self, cancel: CancelToken, fut: F)
   where F: std::future::Future<Output=()> + Send + 'static {
     SERVER.threads.0.ulock().push_back((self, cancel.clone()));
     SERVER.pool.spawn_ok(async move {
       fut.await;
       let (m, cvar) = &*SERVER.threads;
       let mut vec = m.ulock();
-      let a: *const AtomicBool = &*cancel;
-      let i = vec.iter().enumerate().find(|&(_, (_, b))| a == &**b).expect("my job is missing").0;
+      let i = vec.iter().enumerate().find(|&(_, (_, b))| cancel.ptr_eq(b)).expect("my job is missing").0;
       vec.swap_remove_front(i);
       drop(vec);
       cvar.notify_all();
@@ -1322,7 +3956,7 @@ impl Job {
   }
 
   fn spawn(mut self) {
-    let cancel: Arc<AtomicBool> = Default::default();
+    let cancel = CancelToken::new();
     match &mut self {
       Job::RequestHandler(id, req) => {
         SERVER.reqs.ulock().insert(id.clone(), cancel.clone());
@@ -1356,7 +3990,7 @@ impl Job {
 /// be useful to tell whether a certain option has been set by the user or left
 /// as the default. If they were just T, `T::Default` could mean that the user selected
 /// a value that's the same as the default, or it could mean that it was untouched.
-#[derive(Debug, Eq, PartialEq, Deserialize)]
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct ServerOptions {
   elab_on: Option<ElabOn>,
@@ -1365,6 +3999,13 @@ struct ServerOptions {
   syntax_docs: Option<bool>,
   log_errors: Option<bool>,
   report_upstream_errors: Option<bool>,
+  warn_unused: Option<bool>,
+  inlay_hints: Option<bool>,
+  hover_desugared: Option<bool>,
+  hover_unfold_depth: Option<usize>,
+  theorem_name_template: Option<String>,
+  hypothesis_name_template: Option<String>,
+  include_paths: Option<Vec<std::path::PathBuf>>,
 }
 
 impl std::default::Default for ServerOptions {
@@ -1376,6 +4017,13 @@ impl std::default::Default for ServerOptions {
       syntax_docs: None,
       log_errors: None,
       report_upstream_errors: None,
+      warn_unused: None,
+      inlay_hints: None,
+      hover_desugared: None,
+      hover_unfold_depth: None,
+      theorem_name_template: None,
+      hypothesis_name_template: None,
+      include_paths: None,
     }
   }
 }
@@ -1395,26 +4043,78 @@ impl std::default::Default for ElabOn {
   fn default() -> Self { Self::Change }
 }
 
+/// A workspace folder open in the client.
+struct Folder {
+  uri: Url,
+  /// The canonical path of the folder.
+  dir: std::path::PathBuf,
+  /// The manifest of the project containing the folder, if there is one.
+  manifest: Option<Manifest>,
+  /// The configuration of the folder, which is used instead of [`Server::options`]
+  /// for the files in the folder once the client has sent it.
+  options: Option<ServerOptions>,
+}
+
+impl Folder {
+  fn new(uri: Url) -> Option<Folder> {
+    let dir = fs::canonicalize(uri.to_file_path().ok()?).ok()?;
+    let manifest = Manifest::find(&dir).and_then(|path| match Manifest::load(&path) {
+      Ok(m) => Some(m),
+      Err(e) => { eprintln!("warning: could not load {}: {}", path.display(), e); None }
+    });
+    Some(Folder {uri, dir, manifest, options: None})
+  }
+
+  /// The id of the configuration request for this folder.
+  fn config_id(&self) -> RequestId { format!("get_config:{}", self.uri).into() }
+
+  /// The root files of the project containing the folder, from its `mm0.toml` manifest.
+  fn roots(&self) -> impl Iterator<Item=FileRef> + '_ {
+    self.manifest.iter().flat_map(|m| m.project.roots.iter()
+      .filter_map(move |r| fs::canonicalize(m.dir.join(r)).ok()).map(FileRef::from))
+  }
+}
+
 fn send_config_request() -> Result<()> {
   use lsp_types::request::{WorkspaceConfiguration, Request};
-  let params = lsp_types::ConfigurationParams {
-    items: vec![lsp_types::ConfigurationItem {
-        scope_uri: None,
-        section: Some("metamath-zero".to_string()),
-    }],
+  let send = |id: RequestId, scope_uri: Option<Url>| {
+    let params = lsp_types::ConfigurationParams {
+      items: vec![lsp_types::ConfigurationItem {
+          scope_uri,
+          section: Some("metamath-zero".to_string()),
+      }],
+    };
+    send_message(lsp_server::Request::new(id, WorkspaceConfiguration::METHOD.to_string(), params))
   };
-  let req = lsp_server::Request::new(
-    RequestId::from("get_config".to_string()),
-    WorkspaceConfiguration::METHOD.to_string(),
-    params
-  );
-  send_message(req)
+  send(RequestId::from("get_config".to_string()), None)?;
+  for folder in &*SERVER.folders.ulock() {
+    send(folder.config_id(), Some(folder.uri.clone()))?
+  }
+  Ok(())
+}
+
+/// Set the include paths used to resolve imports. The files in each workspace folder search
+/// the `includePaths` setting of the folder (relative to the folder) and the include paths of
+/// its project manifest, and then the include paths `cli` given on the command line and the
+/// workspace folders themselves, so that files can import files in the other folders.
+fn set_include_paths(cli: &[std::path::PathBuf], folders: &[Folder], options: &ServerOptions) {
+  let mut scoped = vec![];
+  for f in folders {
+    if let Some(paths) = &f.options.as_ref().unwrap_or(options).include_paths {
+      scoped.push((f.dir.clone(), paths.iter().map(|p| f.dir.join(p)).collect()))
+    }
+    if let Some(m) = &f.manifest {
+      scoped.push((m.dir.clone(), m.project.include.iter().map(|p| m.dir.join(p)).collect()))
+    }
+  }
+  crate::set_scoped_include_paths(scoped);
+  crate::set_include_paths(cli.iter().cloned().chain(folders.iter().map(|f| f.dir.clone())).collect());
 }
 
 impl Server {
   fn new() -> Result<Server> {
     let (conn, _iot) = Connection::stdio();
-    let params = from_value(conn.initialize(
+    let params: InitializeParams = from_value(conn.initialize(
       to_value(ServerCapabilities {
         text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::Incremental)),
         hover_provider: Some(true.into()),
@@ -1423,12 +4123,60 @@ impl Server {
           ..Default::default()
         }),
         definition_provider: Some(OneOf::Left(true)),
+        signature_help_provider: Some(SignatureHelpOptions {
+          trigger_characters: Some(vec!["(".into(), " ".into()]),
+          retrigger_characters: None,
+          work_done_progress_options: Default::default(),
+        }),
         document_symbol_provider: Some(OneOf::Left(true)),
+        workspace_symbol_provider: Some(OneOf::Left(true)),
         references_provider: Some(OneOf::Left(true)),
         document_highlight_provider: Some(OneOf::Left(true)),
+        code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
+        document_formatting_provider: Some(OneOf::Left(true)),
+        rename_provider: Some(OneOf::Right(RenameOptions {
+          prepare_provider: Some(true),
+          work_done_progress_options: Default::default(),
+        })),
+        call_hierarchy_provider: Some(CallHierarchyServerCapability::Simple(true)),
+        folding_range_provider: Some(FoldingRangeProviderCapability::Simple(true)),
+        selection_range_provider: Some(SelectionRangeProviderCapability::Simple(true)),
+        code_lens_provider: Some(CodeLensOptions {resolve_provider: Some(false)}),
+        execute_command_provider: Some(ExecuteCommandOptions {
+          commands: LensCommand::ALL.iter().map(|cmd| cmd.name().into()).collect(),
+          work_done_progress_options: Default::default(),
+        }),
+        workspace: Some(WorkspaceServerCapabilities {
+          workspace_folders: Some(WorkspaceFoldersServerCapabilities {
+            supported: Some(true),
+            change_notifications: Some(OneOf::Left(true)),
+          }),
+          file_operations: None,
+        }),
+        semantic_tokens_provider: Some(SemanticTokensOptions {
+          legend: semantic_tokens_legend(),
+          full: Some(SemanticTokensFullOptions::Bool(true)),
+          ..Default::default()
+        }.into()),
         ..Default::default()
+      }).map(|mut caps| {
+        // `lsp_types` does not have the inlay hint capability yet.
+        caps["inlayHintProvider"] = true.into();
+        caps
       })?
     )?)?;
+    let include_paths = crate::get_include_paths();
+    let folders: Vec<_> = match &params.workspace_folders {
+      Some(folders) => folders.iter().filter_map(|f| Folder::new(f.uri.clone())).collect(),
+      None => params.root_uri.clone().and_then(Folder::new).into_iter().collect(),
+    };
+    // The flags of the project of the first folder apply to all files.
+    if let Some(m) = folders.first().and_then(|f| f.manifest.as_ref()) {
+      if let Err(e) = m.apply() {
+        eprintln!("warning: could not load {}: {}", m.dir.join(MANIFEST_NAME).display(), e)
+      }
+    }
+    set_include_paths(&include_paths, &folders, &ServerOptions::default());
     Ok(Server {
       caps: Mutex::new(ClientCapabilities::new(params)),
       conn,
@@ -1437,11 +4185,23 @@ impl Server {
       pool: ThreadPool::new()?,
       threads: Default::default(),
       options: Mutex::new(ServerOptions::default()),
+      folders: Mutex::new(folders),
+      include_paths,
+      progress: Default::default(),
     })
   }
 
-  fn elab_on(&self) -> Option<ElabOn> {
-    self.options.ulock().elab_on
+  /// The options for the file `path`: the configuration of the innermost workspace folder
+  /// containing it, or the global configuration.
+  fn options_for(&self, path: &FileRef) -> ServerOptions {
+    let folders = self.folders.ulock();
+    folders.iter().filter(|f| f.options.is_some() && path.path().starts_with(&f.dir))
+      .max_by_key(|f| f.dir.components().count())
+      .and_then(|f| f.options.clone()).unwrap_or_else(|| self.options.ulock().clone())
+  }
+
+  fn elab_on(&self, path: &FileRef) -> Option<ElabOn> {
+    self.options_for(path).elab_on
   }
 
   fn run(&self) {
@@ -1458,7 +4218,7 @@ impl Server {
 
     loop {
       match (|| -> Result<bool> {
-        let Server {conn, caps, reqs, vfs, options, ..} = &*SERVER;
+        let Server {conn, caps, reqs, vfs, options, folders, include_paths, ..} = &*SERVER;
         match conn.receiver.recv() {
           Err(RecvError) => return Ok(true),
           Ok(Message::Request(req)) => {
@@ -1470,15 +4230,26 @@ impl Server {
             }
           }
           Ok(Message::Response(resp)) => {
+            let folder = folders.ulock().iter().position(|f| f.config_id() == resp.id);
             if resp.id == get_config_id {
               if let Some(val) = resp.result {
                 let [config]: [ServerOptions; 1] = from_value(val)?;
+                set_include_paths(include_paths, &folders.ulock(), &config);
                 *self.options.ulock() = config;
               }
+            } else if let Some(i) = folder {
+              let mut folders = folders.ulock();
+              folders[i].options = resp.result.and_then(|val| from_value(val).ok())
+                .map(|[config]: [ServerOptions; 1]| config);
+              set_include_paths(include_paths, &folders, &options.ulock());
             } else {
               let mut caps = caps.ulock();
               if caps.reg_id.as_ref().map_or(false, |rid| rid == &resp.id) {
                 caps.finish_register(&resp);
+              } else if resp.id == RequestId::from(String::from("code_lens_refresh")) {
+                // The client has refreshed the code lenses, nothing to do.
+              } else if let Some(created) = self.progress.ulock().get_mut(&resp.id) {
+                *created = resp.error.is_none();
               } else {
                 log!("response to unknown request {}", resp.id)
               }
@@ -1489,20 +4260,25 @@ impl Server {
             match notif.method.as_str() {
               Cancel::METHOD => {
                 let CancelParams {id} = from_value(notif.params)?;
-                if let Some(cancel) = reqs.ulock().get(&nos_id(id)) {
-                  cancel.store(true, Ordering::Relaxed);
-                }
+                if let Some(cancel) = reqs.ulock().get(&nos_id(id)) { cancel.cancel() }
               }
               DidOpenTextDocument::METHOD => {
                 let DidOpenTextDocumentParams {text_document: doc} = from_value(notif.params)?;
-                let path = doc.uri.into();
+                let path: FileRef = doc.uri.into();
                 log!("open {:?}", path);
-                vfs.open_virt(path, doc.version, doc.text);
+                // The editor shows the listing of an MMB file, so the file is read from disk.
+                let text = if path.has_extension("mmb") {
+                  FileContents::new_bin_from_file(path.path())?
+                } else {
+                  FileContents::new(doc.text)
+                };
+                vfs.open_virt(path, doc.version, text);
               }
               DidChangeTextDocument::METHOD => {
                 let DidChangeTextDocumentParams {text_document: doc, content_changes} = from_value(notif.params)?;
-                if !content_changes.is_empty() {
-                  let path = doc.uri.into();
+                let path: FileRef = doc.uri.into();
+                // MMB files are read-only, and edits to their listings are ignored.
+                if !content_changes.is_empty() && !path.has_extension("mmb") {
                   log!("change {:?}", path);
                   let start = {
                     let file = vfs.get(&path).ok_or("changed nonexistent file")?;
@@ -1512,7 +4288,7 @@ impl Server {
                     *text = FileContents::Ascii(Arc::new(s));
                     start
                   };
-                  if options.ulock().elab_on.unwrap_or_default() == ElabOn::Change {
+                  if SERVER.elab_on(&path).unwrap_or_default() == ElabOn::Change {
                     Job::Elaborate(path, ElabReason::Change(start)).spawn();
                   }
                 }
@@ -1527,11 +4303,22 @@ impl Server {
                 let DidSaveTextDocumentParams {text_document: doc, ..} = from_value(notif.params)?;
                 let path = FileRef::from(doc.uri);
                 log!("save {:?}", path);
-                if options.ulock().elab_on.unwrap_or_default() == ElabOn::Save {
+                if SERVER.elab_on(&path).unwrap_or_default() == ElabOn::Save {
                   Job::Elaborate(path, ElabReason::Save).spawn();
                 }
               }
               DidChangeConfiguration::METHOD => send_config_request()?,
+              DidChangeWorkspaceFolders::METHOD => {
+                let DidChangeWorkspaceFoldersParams {event} = from_value(notif.params)?;
+                log!("workspace folders +{} -{}", event.added.len(), event.removed.len());
+                {
+                  let mut folders = folders.ulock();
+                  folders.retain(|f| !event.removed.iter().any(|r| r.uri == f.uri));
+                  folders.extend(event.added.into_iter().filter_map(|f| Folder::new(f.uri)));
+                  set_include_paths(include_paths, &folders, &options.ulock());
+                }
+                send_config_request()?
+              }
               _ => {}
             }
           }
@@ -1546,7 +4333,7 @@ impl Server {
     logger.stop();
     let (mutex, cvar) = &*self.threads;
     let mut g = mutex.ulock();
-    g.iter().for_each(|(_, c)| c.store(true, Ordering::Relaxed));
+    g.iter().for_each(|(_, c)| c.cancel());
     while !g.is_empty() {
       // use itertools::Itertools;
       // eprintln!("waiting on threads:\n  {}", g.iter().map(|(s, _)| s).format("\n  "));
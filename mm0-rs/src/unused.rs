@@ -0,0 +1,47 @@
+//! Detection of unused local declarations.
+//!
+//! A `local def` or a theorem that is not marked `pub` is not part of the public interface
+//! of a file, so if no other declaration refers to it, it is dead code. This is reported by
+//! `mm0-rs compile --warn-unused`, and by the language server if the `warnUnused` option
+//! is set.
+//!
+//! Because an environment only contains the declarations of a file and its imports,
+//! a declaration that is only used in a file which imports it will still be reported
+//! as unused when checking the imported file by itself.
+use crate::{DeclKey, ElabError, FileRef, FrozenEnv, Modifiers, StmtTrace, ThmKind, WarningCode};
+
+impl FrozenEnv {
+  /// Returns true if `k` is a `local def` or a theorem which is not `pub`.
+  #[must_use] pub fn is_local_decl(&self, k: DeclKey) -> bool {
+    match k {
+      DeclKey::Term(t) => self.term(t).vis == Modifiers::LOCAL,
+      DeclKey::Thm(t) => {
+        let td = self.thm(t);
+        matches!(td.kind, ThmKind::Thm(_)) && td.vis != Modifiers::PUB
+      }
+    }
+  }
+
+  /// Returns the local declarations (see [`is_local_decl`](Self::is_local_decl)) which
+  /// are not used by any other declaration in the environment, in declaration order.
+  #[must_use] pub fn unused_decls(&self) -> Vec<DeclKey> {
+    self.stmts().iter().filter_map(|s|
+      if let StmtTrace::Decl(a) = *s { self.data()[a].decl() } else { None })
+      .filter(|&k| self.is_local_decl(k) && self.uses_of(k).next().is_none())
+      .collect()
+  }
+
+  /// Returns a warning for each unused local declaration in `file`
+  /// (or every file, if `file` is `None`), along with the file containing it.
+  #[must_use] pub fn unused_decl_warnings(&self, file: Option<&FileRef>) -> Vec<(FileRef, ElabError)> {
+    self.unused_decls().into_iter().filter_map(|k| {
+      let (kind, atom, fsp) = match k {
+        DeclKey::Term(t) => ("def", self.term(t).atom, &self.term(t).span),
+        DeclKey::Thm(t) => ("theorem", self.thm(t).atom, &self.thm(t).span),
+      };
+      if file.map_or(false, |file| *file != fsp.file) { return None }
+      Some((fsp.file.clone(), ElabError::lint(WarningCode::UnusedDeclaration, fsp.span,
+        format!("{} '{}' is never used", kind, self.data()[atom].name()))))
+    }).collect()
+  }
+}
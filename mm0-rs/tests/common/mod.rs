@@ -0,0 +1,57 @@
+//! Helpers shared by the integration tests.
+// Each test crate uses only some of the helpers.
+#![allow(dead_code)]
+
+use std::io::Cursor;
+use mm0_rs::api::{elaborate_text, Snapshot};
+use mm0_rs::mmb::export::{ExportOptions, Exporter};
+use mm0_rs::ErrorLevel;
+use mm0b_parser::BasicMmbFile;
+use zerocopy::AsBytes;
+
+/// Get the messages of the errors in `snap`.
+pub fn errors(snap: &Snapshot) -> Vec<&str> {
+  snap.diagnostics.iter()
+    .filter(|d| d.level == ErrorLevel::Error)
+    .map(|d| &*d.message).collect()
+}
+
+/// Elaborate `text` as the file `name.mm1`, and check that there are no errors.
+pub fn elab_ok(name: &str, text: &str) -> Snapshot {
+  let snap = elaborate_text(format!("{}.mm1", name), text.into()).unwrap();
+  let errs = errors(&snap);
+  assert!(errs.is_empty(), "{:?}", errs);
+  snap
+}
+
+/// Export `snap` as an MMB file with the options `opts`,
+/// checking that all the theorems are proved.
+pub fn export(snap: &Snapshot, opts: &ExportOptions) -> Aligned {
+  let mut report = |level: ErrorLevel, msg: &str|
+    assert!(level != ErrorLevel::Error, "{}", msg);
+  let mut w = Cursor::new(vec![]);
+  Exporter::new(snap.path.clone(), snap.source.as_deref(), &snap.env, &mut report, &mut w)
+    .export(opts).unwrap();
+  Aligned::new(&w.into_inner())
+}
+
+/// The contents of an MMB file, in a `u64` buffer so that the tables are aligned.
+pub struct Aligned {
+  buf: Vec<u64>,
+  len: usize,
+}
+
+impl Aligned {
+  /// Copy `bytes` into an aligned buffer.
+  pub fn new(bytes: &[u8]) -> Self {
+    let mut buf = vec![0_u64; (bytes.len() + 7) >> 3];
+    buf.as_bytes_mut()[..bytes.len()].copy_from_slice(bytes);
+    Aligned { buf, len: bytes.len() }
+  }
+
+  /// The contents of the file.
+  pub fn bytes(&self) -> &[u8] { &self.buf.as_bytes()[..self.len] }
+
+  /// Parse the file, which must be well formed.
+  pub fn parse(&self) -> BasicMmbFile<'_> { BasicMmbFile::parse(self.bytes()).unwrap() }
+}
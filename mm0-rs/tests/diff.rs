@@ -0,0 +1,59 @@
+//! Tests for `mm0-rs diff`, comparing MMB files exported with and without a name index.
+
+mod common;
+
+use mm0_rs::mmb::diff::diff;
+use mm0_rs::mmb::export::ExportOptions;
+
+/// Export `old` and `new` and compare them, returning the diff entries as strings.
+fn run(old: &str, new: &str, index: bool) -> Vec<String> {
+  let opts = ExportOptions::new().index(index);
+  let old = common::export(&common::elab_ok("old", old), &opts);
+  let new = common::export(&common::elab_ok("new", new), &opts);
+  diff(&old.parse(), &new.parse(), true).unwrap().iter().map(|e| e.to_string()).collect()
+}
+
+const OLD: &str = "
+delimiter $ ( ) $;
+provable sort wff;
+term imp (a b: wff): wff;
+axiom ax_1 (a b: wff): $ imp a (imp b a) $;
+axiom ax_mp (a b: wff): $ imp a b $ > $ a $ > $ b $;
+theorem a1i (a b: wff) (h: $ a $): $ imp b a $ = '(ax_mp ax_1 h);
+theorem a1ii (a b c: wff) (h: $ a $): $ imp c (imp b a) $ = '(a1i (a1i h));
+";
+
+/// `OLD` with a new term and axiom before the others, which renumbers all of them.
+const NEW: &str = "
+delimiter $ ( ) $;
+provable sort wff;
+term not (a: wff): wff;
+axiom notnot (a: wff): $ not (not a) $ > $ a $;
+term imp (a b: wff): wff;
+axiom ax_1 (a b: wff): $ imp a (imp b a) $;
+axiom ax_mp (a b: wff): $ imp a b $ > $ a $ > $ b $;
+theorem a1i (a b: wff) (h: $ a $): $ imp b a $ = '(ax_mp ax_1 h);
+theorem a1ii (a b c: wff) (h: $ a $): $ imp c (imp b a) $ = '(a1i (a1i h));
+";
+
+#[test]
+fn renumbering_with_index() {
+  assert_eq!(run(OLD, OLD, true), Vec::<String>::new());
+  assert_eq!(run(OLD, NEW, true), ["+ term not", "+ axiom notnot"]);
+}
+
+#[test]
+fn renumbering_without_index() {
+  assert_eq!(run(OLD, OLD, false), Vec::<String>::new());
+  let d = run(OLD, NEW, false);
+  assert_eq!(d.len(), 2, "{:?}", d);
+  assert!(d.iter().all(|s| s.starts_with('+')), "{:?}", d);
+}
+
+#[test]
+fn changed_proof_without_index() {
+  let new = OLD.replace("'(a1i (a1i h))", "'(a1i (ax_mp ax_1 h))");
+  let d = run(OLD, &new, false);
+  assert_eq!(d.len(), 1, "{:?}", d);
+  assert!(d[0].starts_with('~'), "{:?}", d);
+}
@@ -0,0 +1,27 @@
+mod common;
+
+use mm0_rs::api::{elaborate_text, Snapshot};
+use common::errors;
+
+const HEADER: &str = "
+delimiter $ ( ) ~ $;
+provable sort wff;
+term im (a b: wff): wff; infixr im: $->$ prec 25;
+term not (a: wff): wff; prefix not: $~$ prec 41;
+";
+
+fn elab(name: &str, text: &str) -> Snapshot {
+  elaborate_text(format!("{}.mm1", name), format!("{}{}", HEADER, text)).unwrap()
+}
+
+#[test]
+fn independent_subterm_errors() {
+  let snap = elab("subterm_errors", "
+    axiom ax1 (a: wff): $ foo a -> ~ bar a $;
+    axiom ax2 (a: wff): $ a -> baz a $;");
+  let errs = errors(&snap);
+  assert_eq!(errs.len(), 3, "{:?}", errs);
+  assert!(errs[0].contains("foo"), "{:?}", errs);
+  assert!(errs[1].contains("bar"), "{:?}", errs);
+  assert!(errs[2].contains("baz"), "{:?}", errs);
+}
@@ -0,0 +1,26 @@
+//! Tests for the MM0 exporter, which check that the exported specification of the example
+//! files can be read back.
+
+mod common;
+
+use mm0_rs::api::{elaborate, elaborate_text};
+
+/// Export the specification of the example file `name.mm1`, and check that it parses and
+/// elaborates to an environment with the same number of sorts and terms.
+fn roundtrip(name: &str) {
+  let snap = elaborate(format!("{}/../examples/{}.mm1", env!("CARGO_MANIFEST_DIR"), name)).unwrap();
+  assert!(!snap.has_errors(), "{:?}", common::errors(&snap));
+  let mut mm0 = vec![];
+  snap.env.export_mm0(&mut mm0).unwrap();
+  let spec = elaborate_text(format!("{}_spec.mm0", name), String::from_utf8(mm0).unwrap()).unwrap();
+  assert!(!spec.has_errors(), "{:?}", common::errors(&spec));
+  assert_eq!(spec.env.sorts().len(), snap.env.sorts().len());
+  assert!(spec.env.terms().len() <= snap.env.terms().len());
+  assert!(!spec.env.thms().is_empty());
+}
+
+#[test]
+fn export_hol() { roundtrip("hol") }
+
+#[test]
+fn export_peano() { roundtrip("peano") }
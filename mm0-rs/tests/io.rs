@@ -0,0 +1,82 @@
+//! Tests for the lisp file I/O builtins `read-file`, `write-file` and `file-exists?`, which
+//! must not access files outside the current directory.
+
+mod common;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use mm0_rs::api::elaborate_text;
+
+/// Create an empty directory `target/io-test/name` for a test, under the current directory.
+fn sandbox(name: &str) -> PathBuf {
+  let dir = std::env::current_dir().unwrap().join("target").join("io-test").join(name);
+  let _ = fs::remove_dir_all(&dir);
+  fs::create_dir_all(&dir).unwrap();
+  dir
+}
+
+/// Create a file outside the current directory, with contents `secret`.
+fn outside(name: &str) -> PathBuf {
+  let path = std::env::temp_dir().join(format!("mm0-io-test-{}.txt", name));
+  fs::write(&path, "secret").unwrap();
+  assert!(!path.starts_with(std::env::current_dir().unwrap()));
+  path
+}
+
+/// Evaluate the lisp expression `e` in a file in `dir`, and return the error messages.
+fn run(dir: &Path, e: &str) -> Vec<String> {
+  mm0_rs::set_allow_io(true);
+  let snap = elaborate_text(dir.join("io.mm1"), format!("do {{ {} }};", e)).unwrap();
+  common::errors(&snap).into_iter().map(String::from).collect()
+}
+
+/// Check that evaluating `e` fails because the path is outside the current directory.
+fn assert_outside(dir: &Path, e: &str) {
+  let errs = run(dir, e);
+  assert!(errs.len() == 1 && errs[0].contains("outside"), "{}: {:?}", e, errs);
+}
+
+#[test]
+fn inside() {
+  let dir = sandbox("inside");
+  fs::create_dir(dir.join("sub")).unwrap();
+  assert_eq!(run(&dir, r#"
+    (write-file "sub/a.txt" "hello")
+    (if (not (file-exists? "sub/../sub/a.txt")) (error "a.txt does not exist"))
+    (if (file-exists? "b.txt") (error "b.txt exists"))
+    (if (not (== (read-file "sub/a.txt") "hello")) (error "wrong contents"))"#),
+    Vec::<String>::new());
+  assert_eq!(fs::read_to_string(dir.join("sub/a.txt")).unwrap(), "hello");
+}
+
+#[test]
+fn absolute_and_parent_paths() {
+  let dir = sandbox("escape");
+  let out = outside("escape");
+  let abs = out.to_str().unwrap();
+  let rel = format!("{}{}", "../".repeat(dir.components().count()), abs.trim_start_matches('/'));
+  for path in [abs, &*rel] {
+    assert_outside(&dir, &format!("(read-file {:?})", path));
+    assert_outside(&dir, &format!("(file-exists? {:?})", path));
+    assert_outside(&dir, &format!("(write-file {:?} \"pwned\")", path));
+  }
+  assert_eq!(fs::read_to_string(&out).unwrap(), "secret");
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinks() {
+  use std::os::unix::fs::symlink;
+  let dir = sandbox("symlink");
+  let out = outside("symlink");
+  symlink(&out, dir.join("link")).unwrap();
+  assert_outside(&dir, r#"(read-file "link")"#);
+  assert_outside(&dir, r#"(file-exists? "link")"#);
+  assert_outside(&dir, r#"(write-file "link" "pwned")"#);
+  assert_eq!(fs::read_to_string(&out).unwrap(), "secret");
+  // A link to a file in the directory is fine
+  fs::write(dir.join("a.txt"), "hello").unwrap();
+  symlink(dir.join("a.txt"), dir.join("link2")).unwrap();
+  assert_eq!(run(&dir, r#"(if (not (== (read-file "link2") "hello")) (error "wrong contents"))"#),
+    Vec::<String>::new());
+}
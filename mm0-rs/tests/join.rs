@@ -0,0 +1,56 @@
+//! Tests for `mm0-rs link`, joining separately compiled MMB files.
+
+mod common;
+
+use mm0_rs::mmb::export::ExportOptions;
+use mm0_rs::mmb::join::{join, JoinError};
+use mm0_rs::mmb::verify::verify_file;
+use mm0_rs::{SortId, TermId, ThmId};
+use mm0b_parser::NumdStmtCmd;
+
+/// Export each of `texts`, and join the results.
+fn run(texts: &[&str]) -> Result<common::Aligned, JoinError> {
+  let files = texts.iter().enumerate()
+    .map(|(i, text)| common::export(&common::elab_ok(&format!("in{}", i), text), &ExportOptions::new()))
+    .collect::<Vec<_>>();
+  let mut out = vec![];
+  join(&files.iter().map(common::Aligned::parse).collect::<Vec<_>>(), &mut out)?;
+  Ok(common::Aligned::new(&out))
+}
+
+const PRELUDE: &str = "
+delimiter $ ( ) $;
+provable sort wff;
+term imp (a b: wff): wff;
+axiom ax_1 (a b: wff): $ imp a (imp b a) $;
+axiom ax_mp (a b: wff): $ imp a b $ > $ a $ > $ b $;
+";
+
+#[test]
+fn shared_prefix() {
+  let a = format!("{}{}", PRELUDE, "
+    theorem a1i (a b: wff) (h: $ a $): $ imp b a $ = '(ax_mp ax_1 h);");
+  let b = format!("{}{}", PRELUDE, "
+    term not (a: wff): wff;
+    axiom notnot (a: wff): $ not (not a) $ > $ a $;
+    theorem notnot2 (a b: wff) (h: $ not (not a) $): $ imp b a $ = '(ax_mp ax_1 (notnot h));");
+  let out = run(&[&a, &b]).unwrap();
+  let file = out.parse();
+  verify_file(&file).unwrap();
+  let stmts = file.proof().map(|r| r.unwrap().0).collect::<Vec<_>>();
+  assert_eq!(stmts.len(), 8, "{:?}", stmts);
+  assert_eq!(file.sort_name(SortId(0)), "wff");
+  let terms = (0..2).map(|i| file.term_name(TermId(i))).collect::<Vec<_>>();
+  assert_eq!(terms, ["imp", "not"]);
+  let thms = (0..5).map(|i| file.thm_name(ThmId(i))).collect::<Vec<_>>();
+  assert_eq!(thms, ["ax_1", "ax_mp", "a1i", "notnot", "notnot2"]);
+  assert!(file.thm(ThmId(5)).is_none());
+  assert!(matches!(stmts[7], NumdStmtCmd::Thm { thm_id: ThmId(4), local: true }));
+}
+
+#[test]
+fn conflict() {
+  let a = format!("{}{}", PRELUDE, "term not (a: wff): wff;");
+  let b = format!("{}{}", PRELUDE, "term not (a b: wff): wff;");
+  assert!(matches!(run(&[&a, &b]), Err(JoinError::Conflict(1, name)) if name == "not"));
+}
@@ -0,0 +1,44 @@
+//! Tests for the Metamath importer, which elaborates `.mm` files.
+
+mod common;
+
+use mm0_rs::{DeclKey, ThmKind};
+use mm0_rs::api::elaborate_text;
+use mm0_rs::mmb::export::ExportOptions;
+use mm0_rs::mmb::verify::verify_file;
+
+/// A small database in which the labels `ax-1` and `ax_1` both become `ax_1` in MM0.
+const TEXT: &str = "
+$c ( ) -> wff |- $.
+$v ph ps $.
+wph $f wff ph $.
+wps $f wff ps $.
+wi $a wff ( ph -> ps ) $.
+ax-1 $a |- ( ph -> ( ps -> ph ) ) $.
+${
+  min $e |- ph $.
+  maj $e |- ( ph -> ps ) $.
+  ax-mp $a |- ps $.
+$}
+${
+  a1i.1 $e |- ph $.
+  a1i $p |- ( ps -> ph ) $= wph wps wph wi a1i.1 wph wps ax-1 ax-mp $.
+$}
+ax_1 $p |- ( ph -> ( ps -> ph ) ) $= wph wps ax-1 $.
+";
+
+#[test]
+fn import_and_verify() {
+  let snap = elaborate_text("import.mm", TEXT.into()).unwrap();
+  assert!(common::errors(&snap).is_empty(), "{:?}", common::errors(&snap));
+  let env = &snap.env;
+  let thm = |name: &str| match env.get_atom(name.as_bytes()).and_then(|a| env.data()[a].decl()) {
+    Some(DeclKey::Thm(t)) => env.thm(t),
+    d => panic!("{} is {:?}", name, d),
+  };
+  assert!(matches!(thm("ax_1").kind, ThmKind::Axiom));
+  assert!(matches!(thm("ax_1_1").kind, ThmKind::Thm(Some(_))));
+  assert!(matches!(thm("ax_mp").kind, ThmKind::Axiom));
+  assert!(matches!(thm("a1i").kind, ThmKind::Thm(Some(_))));
+  verify_file(&common::export(&snap, &ExportOptions::new()).parse()).unwrap();
+}
@@ -0,0 +1,51 @@
+//! Tests for the source map written by `mm0-rs compile --source-map`.
+
+mod common;
+
+use mm0_rs::ThmKind;
+use mm0_rs::mmb::export::ExportOptions;
+use mm0b_parser::{BasicIndex, MmbFile, NumdStmtCmd, SourceMap};
+
+const TEXT: &str = "
+delimiter $ ( ) $;
+provable sort wff;
+term imp (a b: wff): wff;
+axiom ax_1 (a b: wff): $ imp a (imp b a) $;
+axiom ax_mp (a b: wff): $ imp a b $ > $ a $ > $ b $;
+theorem a1i (a b: wff) (h: $ a $): $ imp b a $ = '(ax_mp ax_1 h);
+";
+
+/// The number of source spans recorded in the proofs of `snap`.
+fn proof_spans(snap: &mm0_rs::api::Snapshot) -> usize {
+  snap.env.thms().iter().map(|td| match &td.kind {
+    ThmKind::Thm(Some(pf)) => pf.spans.len(),
+    _ => 0,
+  }).sum()
+}
+
+#[test]
+fn theorem_spans() {
+  // Both cases are in one test, because the setting is global.
+  mm0_rs::set_proof_spans(false);
+  assert_eq!(proof_spans(&common::elab_ok("no_source_map", TEXT)), 0);
+  mm0_rs::set_proof_spans(true);
+  let snap = common::elab_ok("source_map", TEXT);
+  assert_ne!(proof_spans(&snap), 0);
+
+  let out = common::export(&snap, &ExportOptions::new().source_map(true));
+  let file = MmbFile::<(BasicIndex<'_>, Option<SourceMap<'_>>)>::parse(out.bytes()).unwrap();
+  let (_, pf) = file.proof().map(Result::unwrap)
+    .find(|(stmt, _)| matches!(stmt, NumdStmtCmd::Thm {..})).unwrap();
+  // The whole declaration is the outermost entry.
+  let sp = file.source_span(pf.pos).unwrap();
+  assert_eq!(sp.file, "source_map.mm1");
+  assert!(TEXT[sp.span].starts_with("theorem a1i"));
+  // Some command of the proof is mapped to the application of `ax_mp` in the proof.
+  let (mut it, mut spans) = (pf, vec![]);
+  loop {
+    let pos = it.pos;
+    if it.next().is_none() { break }
+    spans.push(&TEXT[file.source_span(pos).unwrap().span]);
+  }
+  assert!(spans.contains(&"(ax_mp ax_1 h)"), "{:?}", spans);
+}
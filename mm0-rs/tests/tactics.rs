@@ -0,0 +1,275 @@
+//! Tests for the decision procedures. Each test elaborates a file which uses the procedure
+//! to prove some theorems, and then checks the proofs again with the MMB verifier.
+
+mod common;
+
+use mm0_rs::mmb::export::ExportOptions;
+use mm0_rs::mmb::verify::verify_file;
+
+/// Elaborate `text`, check that there are no errors and that all the theorems are proved,
+/// and verify the exported MMB file.
+fn check(name: &str, text: &str) {
+  let mmb = common::export(&common::elab_ok(name, text), &ExportOptions::new());
+  verify_file(&mmb.parse()).unwrap();
+}
+
+#[test]
+fn congruence_closure() {
+  check("cc", r#"
+delimiter $ ( ) $;
+provable sort wff;
+sort nat;
+term eq (a b: nat): wff; infixl eq: $=$ prec 50;
+term f (a: nat): nat;
+term g (a b: nat): nat;
+axiom refl (a: nat): $ a = a $;
+axiom symm (a b: nat): $ a = b $ > $ b = a $;
+axiom trans (a b c: nat): $ a = b $ > $ b = c $ > $ a = c $;
+axiom f_congr (a b: nat): $ a = b $ > $ f a = f b $;
+axiom g_congr (a b c d: nat): $ a = b $ > $ c = d $ > $ g a c = g b d $;
+
+do {
+  (def cc-fns (atom-map!))
+  (insert! cc-fns 'refl (fn (x) (list '! 'refl x)))
+  (insert! cc-fns 'symm (fn (p x y) (list '! 'symm x y p)))
+  (insert! cc-fns 'trans (fn (p q x y z) (list '! 'trans x y z p q)))
+  (insert! cc-fns 'congr (fn (t xs ys ps) (match t
+    ['f (list '! 'f_congr (hd xs) (hd ys) (hd ps))]
+    ['g (list '! 'g_congr (hd xs) (hd ys) (nth 1 xs) (nth 1 ys) (hd ps) (nth 1 ps))])))
+};
+
+theorem cc1 (a b c: nat) (h1: $ a = b $) (h2: $ f b = c $): $ g (f a) a = g c b $ =
+(congruence-closure (list (list 'h1 $ a $ $ b $) (list 'h2 $ f b $ $ c $))
+  $ g (f a) a $ $ g c b $ cc-fns);
+
+theorem cc2 (a b: nat) (h: $ f (f a) = a $) (h2: $ f (f (f a)) = a $): $ f a = a $ =
+(congruence-closure (list (list 'h $ f (f a) $ $ a $) (list 'h2 $ f (f (f a)) $ $ a $))
+  $ f a $ $ a $ cc-fns);
+
+do {
+  (if (def? (congruence-closure (list (list 'h $ f a $ $ b $)) $ a $ $ b $ cc-fns))
+    (error "a = b does not follow from f a = f b"))
+};
+"#);
+}
+
+#[test]
+fn simp() {
+  check("simp", r#"
+delimiter $ ( ) $;
+provable sort wff;
+sort nat;
+term eq (a b: nat): wff; infixl eq: $=$ prec 50;
+term zero: nat; prefix zero: $0$ prec max;
+term add (a b: nat): nat; infixl add: $+$ prec 64;
+axiom refl (a: nat): $ a = a $;
+axiom symm (a b: nat): $ a = b $ > $ b = a $;
+axiom trans (a b c: nat): $ a = b $ > $ b = c $ > $ a = c $;
+axiom add_congr (a b c d: nat): $ a = b $ > $ c = d $ > $ a + c = b + d $;
+@simp axiom add0 (a: nat): $ a + 0 = a $;
+@simp axiom zadd (a: nat): $ 0 + a = a $;
+axiom addcom (a b: nat): $ a + b = b + a $;
+axiom addass (a b c: nat): $ a + b + c = a + (b + c) $;
+do {
+  (add-simp! 'addcom 'addass)
+  (def simp-fns (atom-map!))
+  (insert! simp-fns 'refl (fn (x) (list '! 'refl x)))
+  (insert! simp-fns 'symm (fn (p x y) (list '! 'symm x y p)))
+  (insert! simp-fns 'trans (fn (p q x y z) (list '! 'trans x y z p q)))
+  (insert! simp-fns 'congr (fn (t xs ys ps)
+    (list '! 'add_congr (hd xs) (hd ys) (nth 1 xs) (nth 1 ys) (hd ps) (nth 1 ps))))
+  (def (norm e) (match (simp e simp-fns) [(e2 p) (list e2 p)] [_ (list e (list '! 'refl e))]))
+  -- Prove `x = y` by simplifying both sides to the same term.
+  (def (simp-eq x y) (match (list (norm x) (norm y))
+    [((x2 px) (y2 py))
+      (if (== x2 y2) () (error "simp-eq: different normal forms"))
+      (list '! 'trans x x2 y px (list '! 'symm y x2 py))]))
+};
+theorem s1 (a: nat): $ a + 0 + 0 = a $ = (simp-eq $ a + 0 + 0 $ $ a $);
+theorem s2 (a b c: nat): $ (c + 0) + (b + a) = a + (0 + b) + c $ =
+(simp-eq $ (c + 0) + (b + a) $ $ a + (0 + b) + c $);
+
+do {
+  (if (def? (simp $ 0 $ simp-fns)) (error "0 cannot be simplified"))
+};
+"#);
+}
+
+#[test]
+fn lia() {
+  check("lia", r#"
+delimiter $ ( ) $;
+provable sort wff;
+sort nat;
+term fal: wff;
+term eq (a b: nat): wff; infixl eq: $=$ prec 50;
+term le (a b: nat): wff; infixl le: $<=$ prec 50;
+term lt (a b: nat): wff; infixl lt: $<$ prec 50;
+term zero: nat; prefix zero: $0$ prec max;
+term s (a: nat): nat;
+term add (a b: nat): nat; infixl add: $+$ prec 64;
+term mul (a b: nat): nat; infixl mul: $*$ prec 70;
+
+axiom refl (a: nat): $ a = a $;
+axiom symm (a b: nat): $ a = b $ > $ b = a $;
+axiom trans (a b c: nat): $ a = b $ > $ b = c $ > $ a = c $;
+axiom s_congr (a b: nat): $ a = b $ > $ s a = s b $;
+axiom add_congr (a b c d: nat): $ a = b $ > $ c = d $ > $ a + c = b + d $;
+axiom mul_congr (a b c d: nat): $ a = b $ > $ c = d $ > $ a * c = b * d $;
+@simp axiom add0 (a: nat): $ a + 0 = a $;
+@simp axiom zadd (a: nat): $ 0 + a = a $;
+@simp axiom addS (a b: nat): $ a + s b = s (a + b) $;
+@simp axiom Sadd (a b: nat): $ s a + b = s (a + b) $;
+@simp axiom addass (a b c: nat): $ a + b + c = a + (b + c) $;
+@simp axiom addcom (a b: nat): $ a + b = b + a $;
+@simp axiom addlcom (a b c: nat): $ a + (b + c) = b + (a + c) $;
+@simp axiom zmul (a: nat): $ 0 * a = 0 $;
+@simp axiom Smul (a b: nat): $ s a * b = b + a * b $;
+
+axiom le0 (a: nat): $ 0 <= a $;
+axiom ltle (a b: nat): $ a < b $ > $ a + s 0 <= b $;
+axiom eqle (a b: nat): $ a = b $ > $ a <= b $;
+axiom eqge (a b: nat): $ a = b $ > $ b <= a $;
+axiom lemul (k a b: nat): $ a <= b $ > $ k * a <= k * b $;
+axiom leadd (a b c d: nat): $ a <= b $ > $ c <= d $ > $ a + c <= b + d $;
+axiom le_eq_l (a b c: nat): $ a = b $ > $ a <= c $ > $ b <= c $;
+axiom le_contra (a c: nat): $ a + s c <= a $ > $ fal $;
+
+do {
+  (def eq-fns (atom-map!))
+  (insert! eq-fns 'refl (fn (x) (list '! 'refl x)))
+  (insert! eq-fns 'symm (fn (p x y) (list '! 'symm x y p)))
+  (insert! eq-fns 'trans (fn (p q x y z) (list '! 'trans x y z p q)))
+  (insert! eq-fns 'congr (fn (t xs ys ps) (match t
+    ['s (list '! 's_congr (hd xs) (hd ys) (hd ps))]
+    [_ (list '! (match t ['add 'add_congr] ['mul 'mul_congr])
+      (hd xs) (hd ys) (nth 1 xs) (nth 1 ys) (hd ps) (nth 1 ps))])))
+  (def (norm e) (match (simp e eq-fns) [(e2 p) (list e2 p)] [_ (list e (list '! 'refl e))]))
+  -- Prove `x = y` by simplifying both sides to the same term.
+  (def (simp-eq x y) (match (list (norm x) (norm y))
+    [((x2 px) (y2 py))
+      (if (== x2 y2) () (error "simp-eq: different normal forms"))
+      (list '! 'trans x x2 y px (list '! 'symm y x2 py))]))
+
+  -- The numerals are `s (s ... 0)`.
+  (def (num e) (match e
+    [('zero) 0]
+    [('s e) (match (num e) [#undef #undef] [n (+ n 1)])]
+    [_ #undef]))
+  (def (numeral n) (if (= n 0) '(zero) (list 's (numeral (- n 1)))))
+  (def lia-table (atom-map!))
+  (insert! lia-table 'le 'le)
+  (insert! lia-table 'lt 'lt)
+  (insert! lia-table 'eq 'eq)
+  (insert! lia-table 'add 'add)
+  (insert! lia-table 'mul 'mul)
+  (insert! lia-table 'num num)
+  (insert! lia-table 'numeral numeral)
+  (insert! lia-table 'lt->le 'ltle)
+  (insert! lia-table 'eq->le 'eqle)
+  (insert! lia-table 'eq->ge 'eqge)
+  (insert! lia-table 'le-mul (fn (p k t u) (list '! 'lemul k t u p)))
+  (insert! lia-table 'le-add (fn (p q t u t2 u2) (list '! 'leadd t u t2 u2 p q)))
+  (insert! lia-table 'contra (fn (p t u c)
+    (def uc (list 'add u c))
+    (list '! 'le_contra u (nth 1 c) (list '! 'le_eq_l t uc u (simp-eq t uc) p))))
+  (insert! lia-table 'nonneg (fn (x) (list '! 'le0 x)))
+};
+
+theorem lia1 (x y w: nat) (h1: $ x < y $) (h2: $ y < z $) (h3: $ z < x + s 0 $): $ fal $ =
+(lia (list (list 'h1 $ x < y $) (list 'h2 $ y < z $) (list 'h3 $ z < x + s 0 $)) #undef lia-table);
+
+theorem lia2 (x y: nat) (h1: $ x + s 0 <= y $) (h2: $ y + y <= x $): $ fal $ =
+(lia (list (list 'h1 $ x + s 0 <= y $) (list 'h2 $ y + y <= x $)) #undef lia-table);
+
+do {
+  (if (def? (lia (list (list 'h $ s 0 <= s (s 0) $)) #undef lia-table))
+    (error "1 <= 2 is not a contradiction"))
+};
+"#);
+}
+
+#[test]
+fn sat_tauto() {
+  check("sat", r#"
+delimiter $ ( ) ~ $;
+provable sort wff;
+term im (a b: wff): wff; infixr im: $->$ prec 25;
+term not (a: wff): wff; prefix not: $~$ prec 41;
+term an (a b: wff): wff; infixl an: $/\$ prec 34;
+term or (a b: wff): wff; infixl or: $\/$ prec 30;
+term fal: wff;
+term p0: wff;
+
+axiom hyp (G a: wff): $ G /\ a -> a $;
+axiom weaken (G a b: wff): $ G -> b $ > $ G /\ a -> b $;
+axiom mpd (G a b: wff): $ G -> a -> b $ > $ G -> a $ > $ G -> b $;
+axiom cases (G a: wff): $ G /\ a -> fal $ > $ G /\ ~a -> fal $ > $ G -> fal $;
+axiom intro (G a b: wff): $ G /\ a -> b $ > $ G -> a -> b $;
+axiom bycontra (G a: wff): $ G /\ ~a -> fal $ > $ G -> a $;
+axiom not_1 (G a: wff): $ G -> ~a -> a -> fal $;
+axiom not_2 (G a: wff): $ G -> ~~a -> ~a -> fal $;
+axiom an_1 (G a b: wff): $ G -> a /\ b -> ~a -> fal $;
+axiom an_2 (G a b: wff): $ G -> a /\ b -> ~b -> fal $;
+axiom an_3 (G a b: wff): $ G -> ~(a /\ b) -> a -> b -> fal $;
+axiom or_1 (G a b: wff): $ G -> a \/ b -> ~a -> ~b -> fal $;
+axiom or_2 (G a b: wff): $ G -> ~(a \/ b) -> a -> fal $;
+axiom or_3 (G a b: wff): $ G -> ~(a \/ b) -> b -> fal $;
+axiom im_1 (G a b: wff): $ G -> (a -> b) -> a -> ~b -> fal $;
+axiom im_2 (G a b: wff): $ G -> ~(a -> b) -> ~a -> fal $;
+axiom im_3 (G a b: wff): $ G -> ~(a -> b) -> b -> fal $;
+
+do {
+  (def sat-table (atom-map!))
+  -- Each step or lemma is applied to `_` for the context `G`, then the terms, then the proofs.
+  (def (add-rule k thm) (insert! sat-table k (fn args (cons '! thm '_ args))))
+  (insert! sat-table 'hyp (fn (a) (list '! 'hyp '_ a)))
+  (insert! sat-table 'weaken (fn (p a) (list '! 'weaken '_ a '_ p)))
+  (insert! sat-table 'mp (fn (p q) (list '! 'mpd '_ '_ '_ p q)))
+  (insert! sat-table 'cases (fn (p q a) (list '! 'cases '_ a p q)))
+  (insert! sat-table 'intro (fn (p a) (list '! 'intro '_ a '_ p)))
+  (insert! sat-table 'by-contra (fn (p a) (list '! 'bycontra '_ a p)))
+  (insert! sat-table 'not 'not)
+  (insert! sat-table 'and 'an)
+  (insert! sat-table 'or 'or)
+  (insert! sat-table 'imp 'im)
+  (add-rule 'not-1 'not_1) (add-rule 'not-2 'not_2)
+  (add-rule 'and-1 'an_1) (add-rule 'and-2 'an_2) (add-rule 'and-3 'an_3)
+  (add-rule 'or-1 'or_1) (add-rule 'or-2 'or_2) (add-rule 'or-3 'or_3)
+  (add-rule 'imp-1 'im_1) (add-rule 'imp-2 'im_2) (add-rule 'imp-3 'im_3)
+};
+
+theorem sat1 (g a b: wff): $ g -> a /\ b -> b /\ a $ = (sat-tauto $ a /\ b -> b /\ a $ sat-table);
+theorem sat2 (g a b c: wff): $ g -> (a -> b) -> (b -> c) -> a -> c $ =
+(sat-tauto $ (a -> b) -> (b -> c) -> a -> c $ sat-table);
+theorem peirce (g a b: wff): $ g -> ((a -> b) -> a) -> a $ = (sat-tauto $ ((a -> b) -> a) -> a $ sat-table);
+theorem sat3 (g a b c: wff): $ g -> ~(a \/ b) /\ (c \/ a) -> ~~c $ =
+(sat-tauto $ ~(a \/ b) /\ (c \/ a) -> ~~c $ sat-table);
+
+do {
+  (if (not (def? (sat-tauto $ p0 \/ ~p0 $ sat-table))) (error "excluded middle is a tautology"))
+  (if (def? (sat-tauto $ p0 \/ p0 $ sat-table)) (error "p0 or p0 is not a tautology"))
+};
+"#);
+}
+
+#[test]
+fn auto() {
+  check("auto", r#"
+delimiter $ ( ) ~ $;
+provable sort wff;
+term im (a b: wff): wff; infixr im: $->$ prec 25;
+term an (a b: wff): wff; infixl an: $/\$ prec 34;
+axiom ax_1 (a b: wff): $ a -> b -> a $;
+axiom ax_2 (a b c: wff): $ (a -> b -> c) -> (a -> b) -> a -> c $;
+axiom ax_mp (a b: wff): $ a $ > $ a -> b $ > $ b $;
+axiom anl (a b: wff): $ a /\ b $ > $ a $;
+axiom anr (a b: wff): $ a /\ b $ > $ b $;
+axiom ani (a b: wff): $ a $ > $ b $ > $ a /\ b $;
+
+theorem a1i (a b: wff) (h: $ a $): $ b -> a $ = (focus (auto 2));
+theorem ancom (a b: wff) (h: $ a /\ b $): $ b /\ a $ = (focus (auto 2));
+theorem mp2 (a b c: wff) (h1: $ a $) (h2: $ a -> b $) (h3: $ b -> c $): $ c $ = (focus (auto 3));
+theorem anim (a b c: wff) (h1: $ a -> b $) (h2: $ a /\ c $): $ c /\ b $ = (focus (auto 3));
+"#);
+}
@@ -0,0 +1,82 @@
+//! Tests for the MMB verifier used by `mm0-rs verify`.
+
+mod common;
+
+use std::io::Cursor;
+use std::sync::Arc;
+use mm0_rs::api::elaborate_text;
+use mm0_rs::mmb::export::ExportOptions;
+use mm0_rs::mmb::verify::{check_spec, verify, verify_file, verify_par, VerifyError};
+use mm0_rs::ThmId;
+
+const TEXT: &str = "
+delimiter $ ( ) $;
+provable sort wff;
+term imp (a b: wff): wff;
+infixr imp: $->$ prec 25;
+axiom ax_1 (a b: wff): $ a -> b -> a $;
+axiom ax_mp (a b: wff): $ a -> b $ > $ a $ > $ b $;
+pub theorem a1i (a b: wff) (h: $ a $): $ b -> a $ = '(ax_mp ax_1 h);
+theorem a1ii (a b c: wff) (h: $ a $): $ c -> b -> a $ = '(a1i (a1i h));
+";
+
+/// The public statements of `TEXT`, which leaves out the local theorem `a1ii`.
+const SPEC: &str = "
+delimiter $ ( ) $;
+provable sort wff;
+term imp (a b: wff): wff;
+infixr imp: $->$ prec 25;
+axiom ax_1 (a b: wff): $ a -> b -> a $;
+axiom ax_mp (a b: wff): $ a -> b $ > $ a $ > $ b $;
+theorem a1i (a b: wff) (h: $ a $): $ b -> a $;
+";
+
+/// Elaborate `text` as the file `name.mm0`, and check that there are no errors.
+fn spec(name: &str, text: &str) -> mm0_rs::FrozenEnv {
+  let snap = elaborate_text(format!("{}.mm0", name), text.into()).unwrap();
+  assert!(common::errors(&snap).is_empty(), "{:?}", common::errors(&snap));
+  snap.env
+}
+
+#[test]
+fn verify_and_check_spec() {
+  let out = common::export(&common::elab_ok("verify", TEXT), &ExportOptions::new());
+  let file = out.parse();
+  verify_file(&file).unwrap();
+  check_spec(&file, &spec("spec", SPEC)).unwrap();
+  let bad = SPEC.replace("$ b -> a $;", "$ a -> b $;");
+  assert!(matches!(check_spec(&file, &spec("bad_spec", &bad)), Err(VerifyError::Spec(_))));
+  let short = SPEC.replace("theorem a1i (a b: wff) (h: $ a $): $ b -> a $;", "");
+  assert!(matches!(check_spec(&file, &spec("short_spec", &short)), Err(VerifyError::Spec(_))));
+}
+
+#[test]
+fn sorry() {
+  let text = TEXT.replace("'(a1i (a1i h))", "(focus (sorry))");
+  let out = common::export(&common::elab_ok("sorry", &text), &ExportOptions::new().allow_sorry(true));
+  assert!(matches!(verify_file(&out.parse()), Err(VerifyError::Sorry(names)) if names == ["a1ii"]));
+}
+
+#[test]
+fn streaming() {
+  let out = common::export(&common::elab_ok("streaming", TEXT), &ExportOptions::new());
+  verify(Cursor::new(out.bytes())).unwrap();
+  // The index is never read, so the file can end right after the proof stream.
+  let file = out.parse();
+  let end = file.proof().last().unwrap().unwrap().1.ends_at + 1;
+  verify(Cursor::new(&out.bytes()[..end])).unwrap();
+  // But the proof stream itself must be complete.
+  assert!(matches!(verify(Cursor::new(&out.bytes()[..end - 2])), Err(VerifyError::Io(_))));
+}
+
+#[test]
+fn parallel() {
+  let out = common::export(&common::elab_ok("parallel", TEXT), &ExportOptions::new());
+  let timing = verify_par(&Arc::new(out.bytes().to_vec()), 3).unwrap();
+  let thms = timing.thms.iter().map(|(id, name, _)| (*id, &**name)).collect::<Vec<_>>();
+  assert_eq!(thms, [(ThmId(0), "ax_1"), (ThmId(1), "ax_mp"), (ThmId(2), "a1i"), (ThmId(3), "a1ii")]);
+  let text = TEXT.replace("'(a1i (a1i h))", "(focus (sorry))");
+  let out = common::export(&common::elab_ok("parallel_sorry", &text), &ExportOptions::new().allow_sorry(true));
+  assert!(matches!(verify_par(&Arc::new(out.bytes().to_vec()), 3),
+    Err(VerifyError::Sorry(names)) if names == ["a1ii"]));
+}
@@ -1,4 +1,7 @@
-import { commands, window, workspace, ExtensionContext, TextDocument, EndOfLine } from 'vscode';
+import { commands, debug, languages, window, workspace, ExtensionContext, TextDocument, EndOfLine,
+	DebugAdapterDescriptor, DebugAdapterDescriptorFactory, DebugAdapterServer, DebugSession,
+	ProviderResult, TextDocumentContentProvider, Uri } from 'vscode';
+import { spawn } from 'child_process';
 
 import {
 	LanguageClient,
@@ -23,9 +26,20 @@ function startClient() {
 
 	// Options to control the language client
 	let clientOptions: LanguageClientOptions = {
-		// Register the server for MM0 files
-		documentSelector: [{ scheme: 'file', language: 'metamath-zero' }],
-		initializationOptions: { extraCapabilities: { goalView: true } }
+		// Register the server for MM0 files, and the listings of MMB files
+		documentSelector: [
+			{ scheme: 'file', language: 'metamath-zero' },
+			{ scheme: 'mmb', language: 'metamath-zero' }
+		],
+		initializationOptions: { extraCapabilities: { goalView: true } },
+		// The server knows MMB files by their file URIs, and positions in them refer to the listing
+		uriConverters: {
+			code2Protocol: uri => (uri.scheme === 'mmb' ? uri.with({ scheme: 'file' }) : uri).toString(),
+			protocol2Code: value => {
+				let uri = Uri.parse(value);
+				return uri.scheme === 'file' && uri.path.endsWith('.mmb') ? uri.with({ scheme: 'mmb' }) : uri;
+			}
+		}
 	};
 
 	// Create the language client and start the client.
@@ -36,6 +50,42 @@ function startClient() {
 	client.start();
 }
 
+// Starts `mm0-rs dap` for each debugging session, and connects to it once it is listening.
+class DebugAdapterFactory implements DebugAdapterDescriptorFactory {
+	createDebugAdapterDescriptor(session: DebugSession): ProviderResult<DebugAdapterDescriptor> {
+		let config = workspace.getConfiguration('metamath-zero');
+		let mm0Path: string = config.get('executablePath') || 'mm0-rs';
+		let port: number = session.configuration.port || 4711;
+		let proc = spawn(mm0Path, ['dap', '--port', String(port)]);
+		return new Promise((resolve, reject) => {
+			proc.on('error', reject);
+			// The adapter prints a line to stdout once it is listening
+			proc.stdout.once('data', () => resolve(new DebugAdapterServer(port)));
+		});
+	}
+}
+
+// Shows the textual listing of an MMB file, as decompiled by the server.
+class MmbListingProvider implements TextDocumentContentProvider {
+	provideTextDocumentContent(uri: Uri): ProviderResult<string> {
+		return client.onReady().then(() => client.sendRequest<string | null>('mm0/mmbListing',
+			{ textDocument: { uri: uri.with({ scheme: 'file' }).toString() } }))
+			.then(text => text || '');
+	}
+}
+
+// Opens the listing of an MMB file, which is the active file if it is not given.
+function showMmbListing(file?: Uri) {
+	if (!file && window.activeTextEditor) { file = window.activeTextEditor.document.uri; }
+	if (!file || !file.path.endsWith('.mmb')) {
+		window.showErrorMessage('Not an MMB file');
+		return;
+	}
+	workspace.openTextDocument(file.with({ scheme: 'mmb' }))
+		.then(doc => languages.setTextDocumentLanguage(doc, 'metamath-zero'))
+		.then(doc => window.showTextDocument(doc, { preview: false }));
+}
+
 export function activate(context: ExtensionContext) {
 	startClient();
 
@@ -56,7 +106,10 @@ export function activate(context: ExtensionContext) {
 		commands.registerCommand('metamath-zero.shutdownServer',
 		  () => client.stop().then(() => {}, () => {})),
 		commands.registerCommand('metamath-zero.restartServer',
-			() => client.stop().then(startClient, startClient))
+			() => client.stop().then(startClient, startClient)),
+		commands.registerCommand('metamath-zero.showMmbListing', showMmbListing),
+		workspace.registerTextDocumentContentProvider('mmb', new MmbListingProvider()),
+		debug.registerDebugAdapterDescriptorFactory('metamath-zero', new DebugAdapterFactory())
 	);
 }
 